@@ -1,89 +1,73 @@
 section .data
     buffer  times 32 db 0
+    digits  db "0123456789abcdef"
 
 section .text
     global print
+    global print_fmt
 
+; unsigned decimal
 print:
-    call ft_itoa
-    mov rdi, buffer
-    call cstrlen
-
-    mov rdi, 1                   ; fd
-    mov rsi, buffer              ; buffer
+    mov rsi, 10
     xor rdx, rdx
-    mov rdx, rax                 ; count
-    mov rax, 1                   ; write(2)
-    syscall
-
-    ret
-
-;======================================================
-
-; https://tuttlem.github.io/2013/01/08/strlen-implementation-in-nasm.html
-
-cstrlen:
-    push  rbx                 ; save any registers that
-    push  rcx                 ; we will trash in here
-
-    mov   rbx, rdi            ; rbx = rdi
-
-    xor   al, al              ; the byte that the scan will
-                                ; compare to is zero
-
-    mov   rcx, 0xffffffff     ; the maximum number of bytes
-
-    repne scasb               ; while [rdi] != al, keep scanning
-
-    sub   rdi, rbx            ; length = dist2 - dist1
-    mov   rax, rdi            ; rax now holds our length
-
-    pop   rcx                 ; restore the saved registers
-    pop   rbx
-
-    ret                       ; all done!
+    xor rcx, rcx
 
 ;======================================================
 
-; https://stackoverflow.com/a/54381686/12015230
-
-ft_itoa:
-    xor     rcx, rcx                    ;initialize counter
-    xor     r9, r9                      ;set neg flag to 0
-    mov     eax, edi                    ;move number in RAX for DIV instruction
-    push    rbx                         ;save RBX
-    mov     ebx, 10
-
-.check_negative:
-    and     edi, 0x80000000
-    mov     rdi, buffer
-    jz      .divide                     ;number is positive, proceed to main loop
-    not     eax                         ;else
-    inc     eax                         ;compute absolute value with binary complement
-    inc     r9                          ;set neg flag
+; rdi = value, rsi = radix, rdx = signed flag, rcx = minimum digit count
+print_fmt:
+    push rbx
+    mov rax, rdi
+    xor r9, r9                  ; neg flag
+
+    mov r11, 29                 ; clamp the width to what fits in the buffer
+    cmp rcx, r11
+    cmova rcx, r11
+
+    test rdx, rdx
+    jz .digits
+    test rax, rax
+    jns .digits
+    neg rax                     ; absolute value
+    inc r9
+
+.digits:
+    lea r8, [buffer + 31]       ; the string is built backwards from the newline
+    mov byte [r8], 10
+    mov rbx, rsi
+    xor r10, r10                ; digit count
 
 .divide:
-    xor     edx, edx
-    div     ebx
-    add     edx, 48                     ;convert int to char
-    push    rdx
-    inc     rcx
-    cmp     eax, 0
-    jnz     .divide
-
-.check_neg_flag:
-    cmp     r9, 1
-    jne     .buff_string
-    mov     byte[rdi], '-'
+    xor edx, edx
+    div rbx
+    mov dl, [digits + rdx]
+    dec r8
+    mov [r8], dl
+    inc r10
+    test rax, rax
+    jnz .divide
+
+.pad:
+    cmp r10, rcx
+    jae .sign
+    dec r8
+    mov byte [r8], '0'
+    inc r10
+    jmp .pad
+
+.sign:
+    test r9, r9
+    jz .write
+    dec r8
+    mov byte [r8], '-'
+
+.write:
+    mov rdi, 1                  ; fd
+    mov rsi, r8                 ; buffer
+    lea rdx, [buffer + 32]
+    sub rdx, r8                 ; count
+    mov rax, 1                  ; write(2)
+    syscall
 
-.buff_string:
-    pop     rdx
-    mov     byte[rdi + r9], dl
-    dec     rcx
-    inc     r9
-    cmp     rcx, 0
-    jnz     .buff_string
-    mov     byte[rdi + r9], 10
-    mov     byte[rdi + r9 + 1], 0
-    pop     rbx                         ;restore RBX
-    ret
\ No newline at end of file
+    pop rbx
+    ret
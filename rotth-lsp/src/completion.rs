@@ -1,31 +1,321 @@
-use rotth::ast::TopLevel;
+use rotth::{
+    ast::{AstKind, AstNode, Binding, TopLevel},
+    intrinsics::INTRINSICS,
+};
+use std::path::Path;
 
-#[derive(Debug)]
-pub enum CompleteCompletionItem {
-    Const(String),
-    Mem(String),
-    Proc(String),
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompletionKind {
+    Intrinsic,
+    Proc,
+    Const,
+    Mem,
+    Var,
+    Binding,
+    Struct,
+    Alias,
+    KeyWord,
 }
-/// return (need_to_continue_search, founded reference)
-pub fn completion(ast: &[TopLevel], ident_offset: usize) -> Vec<CompleteCompletionItem> {
+
+#[derive(Debug, Clone)]
+pub struct Completion {
+    pub label: String,
+    pub kind: CompletionKind,
+    /// Stack effect or type
+    pub detail: Option<String>,
+    pub doc: Option<String>,
+}
+
+impl Completion {
+    fn new(label: impl ToString, kind: CompletionKind, detail: Option<String>) -> Self {
+        Self {
+            label: label.to_string(),
+            kind,
+            detail,
+            doc: None,
+        }
+    }
+}
+
+/// Keywords that can start a top level item
+const ITEM_KEYWORDS: &[&str] = &[
+    "include", "proc", "const", "mem", "table", "var", "struct", "extern", "alias",
+];
+/// Keywords that can appear inside a body
+const BODY_KEYWORDS: &[&str] = &[
+    "if", "else", "cond", "while", "do", "bind", "var", "cast", "dip", "keep", "defer", "return",
+    "end",
+];
+/// Keywords that can only appear inside a `while` body
+const LOOP_KEYWORDS: &[&str] = &["break", "continue"];
+
+/// Completion candidates at `offset` in `file`, `ast` holds the items of `file` and its includes
+pub fn completion(ast: &[TopLevel], file: &Path, offset: usize) -> Vec<Completion> {
+    let body = ast
+        .iter()
+        .filter_map(|item| match item {
+            TopLevel::Proc(p) => Some(&p.body),
+            TopLevel::Const(c) => Some(&c.body),
+            TopLevel::Mem(m) => Some(&m.body),
+            TopLevel::Table(t) => Some(&t.body),
+            _ => None,
+        })
+        .find(|body| contains(body, file, offset));
+
     let mut res = Vec::default();
-    for item in ast.iter() {
-        if let TopLevel::Proc(p) = item {
-            if p.name.span.end < ident_offset {
-                let name = rotth::coerce_ast!(p.name => REF Word || unreachable!());
-                res.push(CompleteCompletionItem::Proc(name.clone()));
+    let body = match body {
+        Some(body) => body,
+        None => {
+            res.extend(
+                ITEM_KEYWORDS
+                    .iter()
+                    .map(|kw| Completion::new(kw, CompletionKind::KeyWord, None)),
+            );
+            return res;
+        }
+    };
+
+    res.extend(
+        BODY_KEYWORDS
+            .iter()
+            .map(|kw| Completion::new(kw, CompletionKind::KeyWord, None)),
+    );
+    res.extend(INTRINSICS.iter().map(|i| Completion {
+        doc: Some(i.doc.to_string()),
+        ..Completion::new(
+            i.word,
+            CompletionKind::Intrinsic,
+            Some(format!("( {} )", i.signature)),
+        )
+    }));
+    for item in ast {
+        let name = match item.name() {
+            Some(name) => name,
+            None => continue,
+        };
+        let completion = match item {
+            TopLevel::Proc(p) => {
+                Completion::new(name, CompletionKind::Proc, Some(p.signature.to_string()))
             }
-        } else if let TopLevel::Const(c) = item {
-            if c.name.span.end < ident_offset {
-                let name = rotth::coerce_ast!(c.name => REF Word || unreachable!());
-                res.push(CompleteCompletionItem::Proc(name.clone()));
+            TopLevel::Extern(e) => {
+                Completion::new(name, CompletionKind::Proc, Some(e.signature.to_string()))
             }
-        } else if let TopLevel::Mem(m) = item {
-            if m.name.span.end < ident_offset {
-                let name = rotth::coerce_ast!(m.name => REF Word || unreachable!());
-                res.push(CompleteCompletionItem::Proc(name.clone()));
+            TopLevel::Const(c) => {
+                let tys = match &c.signature.ast {
+                    AstKind::ConstSignature(s) => s.tys.iter().map(|t| t.to_string()).collect(),
+                    _ => Vec::new(),
+                };
+                Completion::new(name, CompletionKind::Const, Some(tys.join(" ")))
             }
-        }
+            TopLevel::Mem(_) => Completion::new(name, CompletionKind::Mem, None),
+            TopLevel::Table(t) => Completion::new(
+                name,
+                CompletionKind::Mem,
+                Some(format!("{} {}", t.ty, t.len)),
+            ),
+            TopLevel::Var(v) => Completion::new(name, CompletionKind::Var, Some(v.ty.to_string())),
+            TopLevel::Struct(_) => Completion::new(name, CompletionKind::Struct, None),
+            TopLevel::Alias(a) => Completion::new(
+                name,
+                CompletionKind::Alias,
+                Some(format!("= {}", a.target())),
+            ),
+            TopLevel::Include(_) => continue,
+        };
+        res.push(completion);
     }
+    scope_at(body, offset, &mut res);
     res
 }
+
+fn contains(node: &AstNode, file: &Path, offset: usize) -> bool {
+    node.span.file == file && node.span.start <= offset && offset <= node.span.end
+}
+
+fn covers(node: &AstNode, offset: usize) -> bool {
+    node.span.start <= offset && offset <= node.span.end
+}
+
+/// Pushes the bindings and local vars visible at `offset` within `node`,
+/// and the loop keywords if `offset` is in a loop body,
+/// only descending into the branch that contains `offset`
+fn scope_at(node: &AstNode, offset: usize, res: &mut Vec<Completion>) {
+    if !covers(node, offset) {
+        return;
+    }
+    match &node.ast {
+        AstKind::Body(body) => {
+            for node in body.iter().take_while(|n| n.span.start <= offset) {
+                if let AstKind::Var(v) = &node.ast {
+                    let name = rotth::coerce_ast!(v.name => REF Word || unreachable!());
+                    res.push(Completion::new(
+                        name,
+                        CompletionKind::Var,
+                        Some(v.ty.to_string()),
+                    ));
+                }
+                scope_at(node, offset, res);
+            }
+        }
+        AstKind::Bind(b) => {
+            if offset >= b.do_.span.end {
+                for binding in &b.bindings {
+                    push_binding(binding, res);
+                }
+            }
+            scope_at(&b.body, offset, res);
+        }
+        AstKind::Dip(c) | AstKind::Keep(c) => scope_at(&c.body, offset, res),
+        AstKind::Defer(c) => {
+            // a deferred body runs on the way out, it can't leave the loop around it
+            res.retain(|c| {
+                !(c.kind == CompletionKind::KeyWord && LOOP_KEYWORDS.contains(&&*c.label))
+            });
+            scope_at(&c.body, offset, res)
+        }
+        AstKind::While(w) => {
+            scope_at(&w.cond, offset, res);
+            if covers(&w.body, offset) {
+                res.extend(
+                    LOOP_KEYWORDS
+                        .iter()
+                        .map(|kw| Completion::new(kw, CompletionKind::KeyWord, None)),
+                );
+            }
+            scope_at(&w.body, offset, res);
+        }
+        AstKind::If(i) => {
+            scope_at(&i.truth, offset, res);
+            if let Some(e) = &i.lie {
+                scope_at(&e.body, offset, res);
+            }
+        }
+        AstKind::Cond(c) => {
+            scope_at(&c.body, offset, res);
+            for b in &c.branches {
+                scope_at(&b.body, offset, res);
+            }
+        }
+        _ => (),
+    }
+}
+
+fn push_binding(binding: &AstNode, res: &mut Vec<Completion>) {
+    match &binding.ast {
+        AstKind::Binding(Binding::Bind { name, ty, .. }) => res.push(Completion::new(
+            name,
+            CompletionKind::Binding,
+            Some(ty.to_string()),
+        )),
+        AstKind::Binding(Binding::Destructure { fields }) => res.extend(
+            fields
+                .iter()
+                .map(|f| Completion::new(f, CompletionKind::Binding, None)),
+        ),
+        _ => (),
+    }
+}
+
+#[test]
+fn test_completion() {
+    use rotth::{ast::parse_no_include, lexer::lex_string};
+
+    let src = "
+        proc helper u64 : u64 do end
+        proc main : u64 do
+            5 bind x : u64 do
+                var y : u64
+                x
+            end
+        end";
+    let file = Path::new("test.rh");
+    let ast = parse_no_include(lex_string(src.to_string(), file.into()).unwrap()).unwrap();
+    let labels = |offset: usize| {
+        completion(&ast, file, offset)
+            .into_iter()
+            .map(|c| (c.label, c.kind, c.detail))
+            .collect::<Vec<_>>()
+    };
+    let has = |labels: &[(String, CompletionKind, Option<String>)], label: &str| {
+        labels
+            .iter()
+            .find(|(l, ..)| l == label)
+            .map(|(_, k, d)| (*k, d.clone()))
+    };
+
+    // outside of any body only items can start
+    let top = labels(0);
+    assert!(top.iter().all(|(_, k, _)| *k == CompletionKind::KeyWord));
+    assert!(has(&top, "proc").is_some() && has(&top, "if").is_none());
+
+    let inner = labels(src.find("x\n").unwrap());
+    assert_eq!(
+        has(&inner, "helper"),
+        Some((CompletionKind::Proc, Some("u64 : u64".to_string())))
+    );
+    assert_eq!(
+        has(&inner, "x"),
+        Some((CompletionKind::Binding, Some("u64".to_string())))
+    );
+    assert_eq!(
+        has(&inner, "y"),
+        Some((CompletionKind::Var, Some("u64".to_string())))
+    );
+    assert_eq!(
+        has(&inner, "dup").map(|(k, _)| k),
+        Some(CompletionKind::Intrinsic)
+    );
+    assert_eq!(
+        has(&inner, "if").map(|(k, _)| k),
+        Some(CompletionKind::KeyWord)
+    );
+
+    // bindings are only visible after `do`, vars after their declaration
+    let before = labels(src.find("5 bind").unwrap());
+    assert!(has(&before, "x").is_none() && has(&before, "y").is_none());
+
+    // a var of one branch isn't visible in the other
+    let src = "
+        proc main : u64 do
+            true if
+                var a : u64
+                1
+            else
+                2
+            end
+        end";
+    let ast = parse_no_include(lex_string(src.to_string(), file.into()).unwrap()).unwrap();
+    let branch = |needle: &str| {
+        completion(&ast, file, src.find(needle).unwrap())
+            .into_iter()
+            .any(|c| c.label == "a")
+    };
+    assert!(branch("1\n"));
+    assert!(!branch("2\n"));
+
+    let src = "
+        proc main : u64 do
+            0 while dup 10 < do
+                defer 2 drop end
+                1 +
+            end
+        end";
+    let ast = parse_no_include(lex_string(src.to_string(), file.into()).unwrap()).unwrap();
+    let labels = |needle: &str| {
+        completion(&ast, file, src.find(needle).unwrap())
+            .into_iter()
+            .map(|c| (c.label, c.kind, c.detail))
+            .collect::<Vec<_>>()
+    };
+    // loop control is only offered where it can leave a while body
+    let loop_body = labels("1 +");
+    for kw in ["break", "continue"] {
+        assert_eq!(
+            has(&loop_body, kw).map(|(k, _)| k),
+            Some(CompletionKind::KeyWord)
+        );
+        assert!(has(&inner, kw).is_none());
+        assert!(has(&labels("0 while"), kw).is_none());
+        assert!(has(&labels("2 drop"), kw).is_none());
+    }
+}
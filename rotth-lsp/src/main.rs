@@ -2,7 +2,7 @@ use dashmap::DashMap;
 use ropey::Rope;
 use rotth::ast::{parse_no_include, TopLevel};
 use rotth::lexer::lex_string;
-use rotth_lsp::completion::{completion, CompleteCompletionItem};
+use rotth_lsp::completion::{completion, CompletionKind};
 use rotth_lsp::semantic_token::{semantic_token_from_ast, CompleteSemanticToken, LEGEND_TYPE};
 use somok::Somok;
 use std::collections::HashSet;
@@ -217,6 +217,57 @@ impl Backend {
         //     )
         //     .await;
     }
+
+    fn word_at(&self, params: &TextDocumentPositionParams) -> Option<String> {
+        let uri = &params.text_document.uri;
+        let rope = if let Some(r) = self.document_map.get(&uri.to_file_path().unwrap()) {
+            r
+        } else {
+            return None;
+        };
+
+        let position = params.position;
+        let line = if let Some(l) = rope.get_line(position.line as usize) {
+            l
+        } else {
+            return None;
+        };
+
+        let mut last_space = 0;
+        let mut word_start = None;
+        let mut word_end = None;
+        for (i, c) in line.chars().enumerate() {
+            if c.is_whitespace() {
+                if word_start.is_none() {
+                    last_space = i
+                } else {
+                    word_end = Some(i);
+                    break;
+                }
+            } else if i == position.character as usize {
+                word_start = Some(last_space + 1)
+            }
+        }
+        let word = if let (Some(start), Some(end)) = (word_start, word_end) {
+            if let Some(line) = line.as_str() {
+                line[start..end].to_string()
+            } else {
+                return None;
+            }
+        } else {
+            return None;
+        };
+        word.some()
+    }
+
+    fn find_item(&self, word: &str) -> Option<TopLevel> {
+        self.ast_map.iter().find_map(|r| {
+            r.value()
+                .iter()
+                .find(|item| item.name().as_deref() == Some(word))
+                .cloned()
+        })
+    }
 }
 
 #[tower_lsp::async_trait]
@@ -229,6 +280,7 @@ impl LanguageServer for Backend {
             }),
             capabilities: ServerCapabilities {
                 definition_provider: Some(OneOf::Left(true)),
+                hover_provider: Some(HoverProviderCapability::Simple(true)),
                 // references_provider: Some(OneOf::Left(true)),
                 // rename_provider: Some(OneOf::Left(true)),
                 text_document_sync: Some(TextDocumentSyncCapability::Kind(
@@ -292,31 +344,30 @@ impl LanguageServer for Backend {
 
             let char = rope.try_line_to_char(position.line as usize).ok()?;
             let offset = char + position.character as usize;
-            let completions = completion(&asts, offset);
-            let mut ret = Vec::with_capacity(completions.len());
-            for item in completions {
-                match item {
-                    CompleteCompletionItem::Const(name) | CompleteCompletionItem::Mem(name) => {
-                        ret.push(CompletionItem {
-                            label: name.clone(),
-                            insert_text: Some(name.clone()),
-                            kind: Some(CompletionItemKind::CONSTANT),
-                            detail: Some(name),
-                            ..Default::default()
-                        });
-                    }
-                    CompleteCompletionItem::Proc(name) => {
-                        ret.push(CompletionItem {
-                            label: name.clone(),
-                            kind: Some(CompletionItemKind::FUNCTION),
-                            detail: Some(name.clone()),
-                            insert_text: Some(name),
-                            insert_text_format: Some(InsertTextFormat::SNIPPET),
-                            ..Default::default()
-                        });
-                    }
-                }
-            }
+            let completions = completion(&asts, &path, offset);
+            let ret = completions
+                .into_iter()
+                .map(|item| CompletionItem {
+                    kind: Some(match item.kind {
+                        CompletionKind::Intrinsic | CompletionKind::KeyWord => {
+                            CompletionItemKind::KEYWORD
+                        }
+                        CompletionKind::Proc | CompletionKind::Alias => {
+                            CompletionItemKind::FUNCTION
+                        }
+                        CompletionKind::Const | CompletionKind::Mem => CompletionItemKind::CONSTANT,
+                        CompletionKind::Var | CompletionKind::Binding => {
+                            CompletionItemKind::VARIABLE
+                        }
+                        CompletionKind::Struct => CompletionItemKind::STRUCT,
+                    }),
+                    insert_text: Some(item.label.clone()),
+                    label: item.label,
+                    detail: item.detail,
+                    documentation: item.doc.map(Documentation::String),
+                    ..Default::default()
+                })
+                .collect::<Vec<_>>();
             Some(ret)
         }();
         Ok(completions.map(CompletionResponse::Array))
@@ -361,55 +412,13 @@ impl LanguageServer for Backend {
         &self,
         params: GotoDefinitionParams,
     ) -> Result<Option<GotoDefinitionResponse>> {
-        let uri = params.text_document_position_params.text_document.uri;
-        let rope = if let Some(r) = self.document_map.get(&uri.to_file_path().unwrap()) {
-            r
+        let word = if let Some(word) = self.word_at(&params.text_document_position_params) {
+            word
         } else {
             return Ok(None);
         };
 
-        let position = params.text_document_position_params.position;
-        let line = if let Some(l) = rope.get_line(position.line as usize) {
-            l
-        } else {
-            return Ok(None);
-        };
-
-        let mut last_space = 0;
-        let mut word_start = None;
-        let mut word_end = None;
-        for (i, c) in line.chars().enumerate() {
-            if c.is_whitespace() {
-                if word_start.is_none() {
-                    last_space = i
-                } else {
-                    word_end = Some(i);
-                    break;
-                }
-            } else if i == position.character as usize {
-                word_start = Some(last_space + 1)
-            }
-        }
-        let word = if let (Some(start), Some(end)) = (word_start, word_end) {
-            if let Some(line) = line.as_str() {
-                &line[start..end]
-            } else {
-                return Ok(None);
-            }
-        } else {
-            return Ok(None);
-        };
-
-        let item = self.ast_map.iter().find_map(|r| {
-            for item in r.value() {
-                if let Some(name) = item.name() {
-                    if name == word {
-                        return item.clone().some();
-                    }
-                }
-            }
-            None
-        });
+        let item = self.find_item(&word);
 
         let definition = item.and_then(|item| {
             let span = &item.span();
@@ -427,6 +436,49 @@ impl LanguageServer for Backend {
         Ok(definition)
     }
 
+    async fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
+        let word = if let Some(word) = self.word_at(&params.text_document_position_params) {
+            word
+        } else {
+            return Ok(None);
+        };
+
+        let hover = self.find_item(&word).and_then(|item| match item {
+            TopLevel::Proc(p) => {
+                let signature = rotth::coerce_ast!(p.signature => ProcSignature || unreachable!());
+                Hover {
+                    contents: HoverContents::Markup(MarkupContent {
+                        kind: MarkupKind::Markdown,
+                        value: format!("```rotth\nproc {word} {signature}\n```"),
+                    }),
+                    range: None,
+                }
+                .some()
+            }
+            TopLevel::Extern(e) => {
+                let signature = rotth::coerce_ast!(e.signature => ProcSignature || unreachable!());
+                Hover {
+                    contents: HoverContents::Markup(MarkupContent {
+                        kind: MarkupKind::Markdown,
+                        value: format!("```rotth\nextern host {word} {signature}\n```"),
+                    }),
+                    range: None,
+                }
+                .some()
+            }
+            TopLevel::Alias(a) => Hover {
+                contents: HoverContents::Markup(MarkupContent {
+                    kind: MarkupKind::Markdown,
+                    value: format!("```rotth\nalias {word} = {}\n```", a.target()),
+                }),
+                range: None,
+            }
+            .some(),
+            _ => None,
+        });
+        Ok(hover)
+    }
+
     async fn semantic_tokens_full(
         &self,
         params: SemanticTokensParams,
@@ -35,39 +35,49 @@ pub fn semantic_token_from_ast(ast: &[TopLevel]) -> Vec<CompleteSemanticToken> {
                 push_token(&i.path, &mut semantic_tokens, SemanticTokenType::STRING);
             }
             TopLevel::Proc(p) => {
+                for attr in &p.attrs {
+                    push_tokens_recursively(attr, &mut semantic_tokens);
+                }
                 push_token(&p.proc, &mut semantic_tokens, SemanticTokenType::KEYWORD);
                 push_token(&p.name, &mut semantic_tokens, SemanticTokenType::FUNCTION);
-                let signature =
-                    rotth::coerce_ast!(p.signature => REF ProcSignature || unreachable!());
-                for ty in &signature.ins {
-                    push_token(ty, &mut semantic_tokens, SemanticTokenType::TYPE)
-                }
-                if let Some(sep) = &signature.sep {
-                    push_token(sep, &mut semantic_tokens, SemanticTokenType::KEYWORD);
-                }
-                if let Some(outs) = &signature.outs {
-                    for ty in outs {
-                        push_token(ty, &mut semantic_tokens, SemanticTokenType::TYPE)
-                    }
-                }
+                push_proc_signature(&p.signature, &mut semantic_tokens);
                 push_token(&p.do_, &mut semantic_tokens, SemanticTokenType::KEYWORD);
                 push_tokens_recursively(&p.body, &mut semantic_tokens);
                 push_token(&p.end, &mut semantic_tokens, SemanticTokenType::KEYWORD);
             }
             TopLevel::Const(c) => {
+                for attr in &c.attrs {
+                    push_tokens_recursively(attr, &mut semantic_tokens);
+                }
                 push_token(&c.const_, &mut semantic_tokens, SemanticTokenType::KEYWORD);
                 push_token(&c.name, &mut semantic_tokens, SemanticTokenType::TYPE);
+                for name in &c.group {
+                    push_token(name, &mut semantic_tokens, SemanticTokenType::TYPE);
+                }
                 push_token(&c.do_, &mut semantic_tokens, SemanticTokenType::KEYWORD);
                 push_tokens_recursively(&c.body, &mut semantic_tokens);
                 push_token(&c.end, &mut semantic_tokens, SemanticTokenType::KEYWORD);
             }
             TopLevel::Mem(m) => {
+                if let Some(extern_) = &m.extern_ {
+                    push_token(extern_, &mut semantic_tokens, SemanticTokenType::KEYWORD);
+                }
                 push_token(&m.mem, &mut semantic_tokens, SemanticTokenType::KEYWORD);
                 push_token(&m.name, &mut semantic_tokens, SemanticTokenType::TYPE);
                 push_token(&m.do_, &mut semantic_tokens, SemanticTokenType::KEYWORD);
                 push_tokens_recursively(&m.body, &mut semantic_tokens);
                 push_token(&m.end, &mut semantic_tokens, SemanticTokenType::KEYWORD);
             }
+            TopLevel::Table(t) => {
+                push_token(&t.table, &mut semantic_tokens, SemanticTokenType::KEYWORD);
+                push_token(&t.name, &mut semantic_tokens, SemanticTokenType::TYPE);
+                push_token(&t.sep, &mut semantic_tokens, SemanticTokenType::KEYWORD);
+                push_token(&t.ty, &mut semantic_tokens, SemanticTokenType::TYPE);
+                push_token(&t.len, &mut semantic_tokens, SemanticTokenType::NUMBER);
+                push_token(&t.do_, &mut semantic_tokens, SemanticTokenType::KEYWORD);
+                push_tokens_recursively(&t.body, &mut semantic_tokens);
+                push_token(&t.end, &mut semantic_tokens, SemanticTokenType::KEYWORD);
+            }
             TopLevel::Var(v) => {
                 push_token(&v.var, &mut semantic_tokens, SemanticTokenType::KEYWORD);
                 push_token(&v.name, &mut semantic_tokens, SemanticTokenType::TYPE);
@@ -75,6 +85,9 @@ pub fn semantic_token_from_ast(ast: &[TopLevel]) -> Vec<CompleteSemanticToken> {
                 push_token(&v.ty, &mut semantic_tokens, SemanticTokenType::TYPE);
             }
             TopLevel::Struct(s) => {
+                for attr in &s.attrs {
+                    push_tokens_recursively(attr, &mut semantic_tokens);
+                }
                 push_token(&s.struct_, &mut semantic_tokens, SemanticTokenType::KEYWORD);
                 push_token(&s.name, &mut semantic_tokens, SemanticTokenType::TYPE);
                 push_token(&s.do_, &mut semantic_tokens, SemanticTokenType::KEYWORD);
@@ -83,6 +96,18 @@ pub fn semantic_token_from_ast(ast: &[TopLevel]) -> Vec<CompleteSemanticToken> {
                 }
                 push_token(&s.end, &mut semantic_tokens, SemanticTokenType::KEYWORD);
             }
+            TopLevel::Extern(e) => {
+                push_token(&e.extern_, &mut semantic_tokens, SemanticTokenType::KEYWORD);
+                push_token(&e.host, &mut semantic_tokens, SemanticTokenType::KEYWORD);
+                push_token(&e.name, &mut semantic_tokens, SemanticTokenType::FUNCTION);
+                push_proc_signature(&e.signature, &mut semantic_tokens);
+            }
+            TopLevel::Alias(a) => {
+                push_token(&a.alias, &mut semantic_tokens, SemanticTokenType::KEYWORD);
+                push_token(&a.name, &mut semantic_tokens, SemanticTokenType::FUNCTION);
+                push_token(&a.eq, &mut semantic_tokens, SemanticTokenType::KEYWORD);
+                push_token(&a.target, &mut semantic_tokens, SemanticTokenType::FUNCTION);
+            }
         }
     }
 
@@ -96,6 +121,11 @@ fn push_tokens_recursively(node: &AstNode, tokens: &mut Vec<CompleteSemanticToke
             push_token(&*f.sep, tokens, SemanticTokenType::KEYWORD);
             push_token(&*f.ty, tokens, SemanticTokenType::TYPE);
         }
+        AstKind::NamedType(n) => {
+            push_token(&*n.name, tokens, SemanticTokenType::PARAMETER);
+            push_token(&*n.sep, tokens, SemanticTokenType::KEYWORD);
+            push_token(&*n.ty, tokens, SemanticTokenType::TYPE);
+        }
         AstKind::KeyWord(_) => push_token(node, tokens, SemanticTokenType::KEYWORD),
         AstKind::Type(_) => push_token(node, tokens, SemanticTokenType::TYPE),
         AstKind::Separator => push_token(node, tokens, SemanticTokenType::KEYWORD),
@@ -108,14 +138,23 @@ fn push_tokens_recursively(node: &AstNode, tokens: &mut Vec<CompleteSemanticToke
             push_tokens_recursively(&b.body, tokens);
             push_token(&b.end, tokens, SemanticTokenType::KEYWORD);
         }
-        AstKind::Binding(b) => {
-            if let Binding::Bind { name, sep, ty } = b {
+        AstKind::Binding(b) => match b {
+            Binding::Bind { name, sep, ty } => {
                 push_token(name, tokens, SemanticTokenType::PARAMETER);
                 push_token(sep, tokens, SemanticTokenType::KEYWORD);
                 push_token(ty, tokens, SemanticTokenType::TYPE);
-            } else {
-                push_token(node, tokens, SemanticTokenType::PARAMETER);
             }
+            Binding::Destructure { fields } => {
+                for field in fields {
+                    push_token(field, tokens, SemanticTokenType::PARAMETER);
+                }
+            }
+            Binding::Ignore => push_token(node, tokens, SemanticTokenType::PARAMETER),
+        },
+        AstKind::Dip(c) | AstKind::Keep(c) | AstKind::Defer(c) => {
+            push_token(&c.kw, tokens, SemanticTokenType::KEYWORD);
+            push_tokens_recursively(&c.body, tokens);
+            push_token(&c.end, tokens, SemanticTokenType::KEYWORD);
         }
         AstKind::While(w) => {
             push_token(&w.while_, tokens, SemanticTokenType::KEYWORD);
@@ -155,6 +194,7 @@ fn push_tokens_recursively(node: &AstNode, tokens: &mut Vec<CompleteSemanticToke
         AstKind::Literal(l) => {
             let ty = match l {
                 IConst::Bool(_) => SemanticTokenType::NUMBER,
+                IConst::Int(_) => SemanticTokenType::NUMBER,
                 IConst::U64(_) => SemanticTokenType::NUMBER,
                 IConst::I64(_) => SemanticTokenType::NUMBER,
                 IConst::Char(_) => SemanticTokenType::STRING,
@@ -180,7 +220,7 @@ fn push_tokens_recursively(node: &AstNode, tokens: &mut Vec<CompleteSemanticToke
             push_token(&v.sep, tokens, SemanticTokenType::KEYWORD);
             push_token(&v.ty, tokens, SemanticTokenType::TYPE);
         }
-        AstKind::Accessor => {
+        AstKind::Accessor | AstKind::Attribute(_) => {
             push_token(node, tokens, SemanticTokenType::KEYWORD);
         }
         AstKind::FieldAccess(a) => {
@@ -190,6 +230,21 @@ fn push_tokens_recursively(node: &AstNode, tokens: &mut Vec<CompleteSemanticToke
     }
 }
 
+fn push_proc_signature(node: &AstNode, tokens: &mut Vec<CompleteSemanticToken>) {
+    let signature = rotth::coerce_ast!(node => REF ProcSignature || unreachable!());
+    for ty in &signature.ins {
+        push_token(ty, tokens, SemanticTokenType::TYPE)
+    }
+    if let Some(sep) = &signature.sep {
+        push_token(sep, tokens, SemanticTokenType::KEYWORD);
+    }
+    if let Some(outs) = &signature.outs {
+        for ty in outs {
+            push_tokens_recursively(ty, tokens)
+        }
+    }
+}
+
 fn push_token(node: &AstNode, tokens: &mut Vec<CompleteSemanticToken>, typ: SemanticTokenType) {
     tokens.push(CompleteSemanticToken {
         start: node.span.start,
@@ -8,8 +8,8 @@ use std::{
 
 use crate::{
     iconst::IConst,
-    lexer::{KeyWord, Token},
-    resolver::resolve_include,
+    lexer::{KeyWord, Token, RESERVED},
+    resolver::resolve_includes,
     span::Span,
     types::{self, Primitive, StructIndex, ValueType},
     Error, RedefinitionError,
@@ -23,8 +23,11 @@ pub enum TopLevel {
     Proc(Proc),
     Const(Const),
     Mem(Mem),
+    Table(Table),
     Var(ToplevelVar),
     Struct(Struct),
+    Extern(Extern),
+    Alias(Alias),
     Include(Include),
 }
 
@@ -34,8 +37,11 @@ impl TopLevel {
             TopLevel::Proc(i) => &i.name,
             TopLevel::Const(i) => &i.name,
             TopLevel::Mem(i) => &i.name,
+            TopLevel::Table(i) => &i.name,
             TopLevel::Var(i) => &i.name,
             TopLevel::Struct(i) => &i.name,
+            TopLevel::Extern(i) => &i.name,
+            TopLevel::Alias(i) => &i.name,
             TopLevel::Include(_) => return None,
         };
         match &name_node.ast {
@@ -49,17 +55,37 @@ impl TopLevel {
             TopLevel::Proc(i) => &i.name,
             TopLevel::Const(i) => &i.name,
             TopLevel::Mem(i) => &i.name,
+            TopLevel::Table(i) => &i.name,
             TopLevel::Var(i) => &i.name,
             TopLevel::Struct(i) => &i.name,
+            TopLevel::Extern(i) => &i.name,
+            TopLevel::Alias(i) => &i.name,
             TopLevel::Include(i) => &i.include,
         }
         .span
         .clone()
     }
+
+    /// Every name the item defines with its span, the first one being `name`
+    pub fn names(&self) -> Vec<(String, Span)> {
+        let group = match self {
+            TopLevel::Const(c) => &c.group[..],
+            _ => &[],
+        };
+        self.name()
+            .map(|name| (name, self.span()))
+            .into_iter()
+            .chain(group.iter().map(|n| match &n.ast {
+                AstKind::Word(w) => (w.clone(), n.span.clone()),
+                _ => unreachable!(),
+            }))
+            .collect()
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Proc {
+    pub attrs: Vec<AstNode>,
     pub proc: AstNode,
     pub name: AstNode,
     pub signature: AstNode,
@@ -68,8 +94,11 @@ pub struct Proc {
     pub end: AstNode,
 }
 
+/// `mem name do size end`, or `extern mem name : size end` for memory a linked object defines,
+/// whose `:` is then in `do_`
 #[derive(Debug, Clone)]
 pub struct Mem {
+    pub extern_: Option<AstNode>,
     pub mem: AstNode,
     pub name: AstNode,
     pub do_: AstNode,
@@ -77,6 +106,21 @@ pub struct Mem {
     pub end: AstNode,
 }
 
+/// `table name : type [len] do body end`, an array of `len` values of `type`, the body being
+/// evaluated at compile time for each of them with its index bound to `i`
+#[derive(Debug, Clone)]
+pub struct Table {
+    pub table: AstNode,
+    pub name: AstNode,
+    pub sep: AstNode,
+    pub ty: AstNode,
+    /// `[len]`, a single word to the lexer
+    pub len: AstNode,
+    pub do_: AstNode,
+    pub body: AstNode,
+    pub end: AstNode,
+}
+
 #[derive(Debug, Clone)]
 pub struct ToplevelVar {
     pub var: AstNode,
@@ -87,6 +131,7 @@ pub struct ToplevelVar {
 
 #[derive(Debug, Clone)]
 pub struct Struct {
+    pub attrs: Vec<AstNode>,
     pub struct_: AstNode,
     pub name: AstNode,
     pub do_: AstNode,
@@ -96,14 +141,45 @@ pub struct Struct {
 
 #[derive(Debug, Clone)]
 pub struct Const {
+    pub attrs: Vec<AstNode>,
     pub const_: AstNode,
     pub name: AstNode,
+    /// Names after the first of a group like `const W H : u64 u64 do 80 24 end`, which names
+    /// each value of the body
+    pub group: Vec<AstNode>,
     pub signature: AstNode,
     pub do_: AstNode,
     pub body: AstNode,
     pub end: AstNode,
 }
 
+/// `extern host name ins : outs`, a proc implemented by the embedder of the evaluator
+#[derive(Debug, Clone)]
+pub struct Extern {
+    pub extern_: AstNode,
+    pub host: AstNode,
+    pub name: AstNode,
+    pub signature: AstNode,
+}
+
+/// `alias name = word`, uses of `name` are replaced with `word` when lowering
+#[derive(Debug, Clone)]
+pub struct Alias {
+    pub alias: AstNode,
+    pub name: AstNode,
+    pub eq: AstNode,
+    pub target: AstNode,
+}
+
+impl Alias {
+    pub fn target(&self) -> &str {
+        match &self.target.ast {
+            AstKind::Word(w) => w,
+            _ => unreachable!(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Include {
     pub include: AstNode,
@@ -131,11 +207,15 @@ pub enum AstKind {
     Type(Type),
     Separator,
     Accessor,
+    Attribute(Attribute),
 
     Bind(Bind),
     Binding(Binding),
 
     While(While),
+    Dip(Combinator),
+    Keep(Combinator),
+    Defer(Combinator),
 
     If(If),
     Cond(Cond),
@@ -152,10 +232,18 @@ pub enum AstKind {
 
     Body(Vec<AstNode>),
     StructField(StructField),
+    NamedType(NamedType),
     Var(Box<Var>),
     FieldAccess(Box<FieldAccess>),
 }
 
+/// `#[name]` or `#[name(arg)]` preceding an item
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Attribute {
+    pub name: String,
+    pub arg: Option<String>,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct FieldAccess {
     pub access: AstNode,
@@ -220,6 +308,25 @@ impl Type {
             "i16" => ValueType::Primitive(Primitive::I16),
             "i8" => ValueType::Primitive(Primitive::I8),
             "()" => ValueType::Any,
+            "ptr(proc)" => ValueType::Proc(None),
+            n if n.starts_with("ptr(proc(") => {
+                let (ins, outs) = proc_ptr_signature(n)?;
+                let tys = |tys: Vec<&str>| {
+                    tys.into_iter()
+                        .map(|ty| {
+                            let name = ty.trim_start_matches("&>");
+                            Type {
+                                ptr_count: (ty.len() - name.len()) / 2,
+                                type_name: name.to_string(),
+                            }
+                            .to_type(structs)
+                        })
+                        .collect::<Option<Vec<_>>>()
+                };
+                let (ins, outs) = (tys(ins)?, tys(outs)?);
+                ValueType::Proc(structs.signature_id(&ins, &outs).some())
+            }
+            n if n.starts_with('\'') => ValueType::Var(n.chars().nth(1)?),
             n => ValueType::Struct(structs.name_to_id(n)?),
         };
         let ptr_depth = self.ptr_count;
@@ -231,6 +338,35 @@ impl Type {
     }
 }
 
+/// The inputs and outputs of a `ptr(proc(ins : outs))` type as the lexer spaces it, `None` if it
+/// has more than one `:`
+fn proc_ptr_signature(ty: &str) -> Option<(Vec<&str>, Vec<&str>)> {
+    let signature = ty.strip_prefix("ptr(proc(")?.strip_suffix("))")?;
+    // the types of nested proc pointers have spaces too
+    let mut words = Vec::new();
+    let (mut start, mut depth) = (0, 0);
+    for (i, c) in signature.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ' ' if depth == 0 => {
+                words.push(&signature[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    words.push(&signature[start..]);
+    words.retain(|w| !w.is_empty());
+    match words.iter().position(|w| *w == ":") {
+        Some(sep) if !words[sep + 1..].contains(&":") => {
+            (words[..sep].to_vec(), words[sep + 1..].to_vec()).some()
+        }
+        Some(_) => None,
+        None => (words, Vec::new()).some(),
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ConstSignature {
     pub sep: Box<AstNode>,
@@ -244,15 +380,63 @@ pub struct ProcSignature {
     pub outs: Option<Vec<AstNode>>,
 }
 
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", "&>".repeat(self.ptr_count), self.type_name)
+    }
+}
+
+impl std::fmt::Display for ProcSignature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut tys = self.ins.iter().map(|ty| ty.to_string()).collect::<Vec<_>>();
+        if let Some(outs) = &self.outs {
+            tys.push(":".to_string());
+            tys.extend(outs.iter().map(|ty| ty.to_string()));
+        }
+        write!(f, "{}", tys.join(" "))
+    }
+}
+
+impl std::fmt::Display for AstNode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.ast {
+            AstKind::Type(ty) => write!(f, "{ty}"),
+            AstKind::Word(w) => write!(f, "{w}"),
+            AstKind::NamedType(n) => write!(f, "{}: {}", n.name, n.ty),
+            AstKind::ProcSignature(s) => write!(f, "{s}"),
+            ast => write!(f, "{ast:?}"),
+        }
+    }
+}
+
+/// A proc output with an optional documenting name, e.g. `quotient: u64`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NamedType {
+    pub name: Box<AstNode>,
+    pub sep: Box<AstNode>,
+    pub ty: Box<AstNode>,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct StructField {
     pub name: Box<AstNode>,
     pub sep: Box<AstNode>,
     pub ty: Box<AstNode>,
+    /// Width of a bit-field, the literal after a second `:`
+    pub bits: Option<Box<AstNode>>,
+}
+
+/// `dip ... end`, `keep ... end` or `defer ... end`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Combinator {
+    pub kw: Box<AstNode>,
+    pub body: Box<AstNode>,
+    pub end: Box<AstNode>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct While {
+    pub attrs: Vec<AstNode>,
     pub while_: Box<AstNode>,
     pub cond: Box<AstNode>,
     pub do_: Box<AstNode>,
@@ -315,6 +499,10 @@ pub enum Binding {
         sep: Box<AstNode>,
         ty: Box<AstNode>,
     },
+    /// `{ptr len}`, binds fields of a struct pointer or slice by name
+    Destructure {
+        fields: Vec<AstNode>,
+    },
 }
 
 fn ty() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> {
@@ -329,14 +517,49 @@ fn ty() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> {
             }),
         })
 }
+/// A type of a proc signature or binding, which may also be a type variable like `'a` or `&>'a`
+fn sig_ty() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> {
+    let type_var = just(Token::Ptr)
+        .repeated()
+        .then(select! { Token::TypeVar(v) => v })
+        .map_with_span(|(ptr, v), span| AstNode {
+            span,
+            ast: AstKind::Type(Type {
+                ptr_count: ptr.len(),
+                type_name: format!("'{v}"),
+            }),
+        });
+    type_var.or(ty())
+}
 fn literal() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> {
     select! {
         Token::Bool(b), span => AstNode { span, ast: AstKind::Literal(IConst::Bool(b)) },
-        Token::Num(n), span => AstNode { span, ast: AstKind::Literal(IConst::U64(n.parse().unwrap())) },
+        Token::Num(n), span => AstNode { span, ast: AstKind::Literal(num_literal(&n)) },
         Token::Str(s), span => AstNode { span, ast: AstKind::Literal(IConst::Str(s)) },
         Token::Char(c), span => AstNode { span, ast: AstKind::Literal(IConst::Char(c)) },
     }
 }
+/// `42u` is a `u64`, `42i` an `i64` and `42p` a pointer, plain `42` is inferred. The digits are
+/// hexadecimal after `0x`, binary after `0b` and octal after `0o`, and can be grouped with `_`.
+fn num_literal(n: &str) -> IConst {
+    let (digits, suffix) = n.split_at(n.len() - n.ends_with(['u', 'i', 'p']) as usize);
+    let digits = digits.replace('_', "");
+    let (digits, radix) = match digits.get(..2) {
+        Some("0x") => (&digits[2..], 16),
+        Some("0b") => (&digits[2..], 2),
+        Some("0o") => (&digits[2..], 8),
+        _ => (&digits[..], 10),
+    };
+    let value = || u64::from_str_radix(digits, radix).unwrap();
+    match suffix {
+        "u" => IConst::U64(value()),
+        // other radixes spell out the bits, so `0xffffffffffffffffi` is -1
+        "i" if radix != 10 => IConst::I64(value() as i64),
+        "i" => IConst::I64(digits.parse().unwrap()),
+        "p" => IConst::Ptr(value()),
+        _ => IConst::Int(value()),
+    }
+}
 fn include_path() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> {
     select! {
         Token::Str(s), span => AstNode { span, ast: AstKind::Path(PathBuf::from(s)) },
@@ -387,11 +610,47 @@ fn kw_ret() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> {
         Token::KeyWord(kw @ KeyWord::Return), span => AstNode { span, ast: AstKind::KeyWord(kw) },
     }
 }
+/// `break` or `continue`
+fn kw_loop_jump() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> {
+    select! {
+        Token::KeyWord(kw @ (KeyWord::Break | KeyWord::Continue)), span => AstNode { span, ast: AstKind::KeyWord(kw) },
+    }
+}
 fn kw_cast() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> {
     select! {
         Token::KeyWord(kw @ KeyWord::Cast), span => AstNode { span, ast: AstKind::KeyWord(kw) },
     }
 }
+fn attribute() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> {
+    select! {
+        Token::Attribute(name, arg), span => AstNode { span, ast: AstKind::Attribute(Attribute { name, arg }) },
+    }
+}
+fn kw_dip() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> {
+    select! {
+        Token::KeyWord(kw @ KeyWord::Dip), span => AstNode { span, ast: AstKind::KeyWord(kw) },
+    }
+}
+fn kw_keep() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> {
+    select! {
+        Token::KeyWord(kw @ KeyWord::Keep), span => AstNode { span, ast: AstKind::KeyWord(kw) },
+    }
+}
+fn kw_defer() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> {
+    select! {
+        Token::KeyWord(kw @ KeyWord::Defer), span => AstNode { span, ast: AstKind::KeyWord(kw) },
+    }
+}
+fn kw_extern() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> {
+    select! {
+        Token::KeyWord(kw @ KeyWord::Extern), span => AstNode { span, ast: AstKind::KeyWord(kw) },
+    }
+}
+fn kw_alias() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> {
+    select! {
+        Token::KeyWord(kw @ KeyWord::Alias), span => AstNode { span, ast: AstKind::KeyWord(kw) },
+    }
+}
 fn kw_proc() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> {
     select! {
         Token::KeyWord(kw @ KeyWord::Proc), span => AstNode { span, ast: AstKind::KeyWord(kw) },
@@ -407,6 +666,11 @@ fn kw_mem() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> {
         Token::KeyWord(kw @ KeyWord::Mem), span => AstNode { span, ast: AstKind::KeyWord(kw) },
     }
 }
+fn kw_table() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> {
+    select! {
+        Token::KeyWord(kw @ KeyWord::Table), span => AstNode { span, ast: AstKind::KeyWord(kw) },
+    }
+}
 fn kw_var() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> {
     select! {
         Token::KeyWord(kw @ KeyWord::Var), span => AstNode { span, ast: AstKind::KeyWord(kw) },
@@ -423,6 +687,17 @@ fn word() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> {
         Token::Word(w), span => AstNode { span, ast: AstKind::Word(w) },
     }
 }
+/// A word naming something new, which can't be a reserved one
+fn name() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> {
+    word().try_map(|node, span| match &node.ast {
+        AstKind::Word(w) if RESERVED.contains(&w.as_str()) => Simple::custom(
+            span,
+            format!("`{}` is reserved for a future keyword, use another name", w),
+        )
+        .error(),
+        _ => node.okay(),
+    })
+}
 fn separator() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> {
     select! {
         Token::SigSep, span => AstNode { span, ast: AstKind::Separator },
@@ -434,25 +709,35 @@ fn ignore() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> {
     }
 }
 fn binding() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> {
-    let name_type = word()
-        .then(separator())
-        .then(ty())
-        .map_with_span(|((name, sep), ty), span| AstNode {
+    let name_type =
+        name()
+            .then(separator())
+            .then(sig_ty())
+            .map_with_span(|((name, sep), ty), span| AstNode {
+                span,
+                ast: AstKind::Binding(Binding::Bind {
+                    name: name.boxed(),
+                    sep: sep.boxed(),
+                    ty: ty.boxed(),
+                }),
+            });
+
+    let destructure = word()
+        .repeated()
+        .at_least(1)
+        .delimited_by(just(Token::OpenBrace), just(Token::CloseBrace))
+        .map_with_span(|fields, span| AstNode {
             span,
-            ast: AstKind::Binding(Binding::Bind {
-                name: name.boxed(),
-                sep: sep.boxed(),
-                ty: ty.boxed(),
-            }),
+            ast: AstKind::Binding(Binding::Destructure { fields }),
         });
 
-    choice((name_type, ignore()))
+    choice((name_type, destructure, ignore()))
 }
 
 fn var() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> {
     kw_var()
         .then(kw_ret().or_not())
-        .then(word())
+        .then(name())
         .then(separator())
         .then(ty())
         .map_with_span(|((((var, ret), name), sep), ty), span| AstNode {
@@ -500,21 +785,61 @@ fn body() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> + Clone {
                 span,
             });
 
-        let while_ = kw_while()
+        let while_ = attribute()
+            .repeated()
+            .then(kw_while())
             .then(body.clone())
             .then(kw_do())
             .then(body.clone())
             .then(kw_end())
-            .map_with_span(|((((while_, cond), do_), body), end), span| AstNode {
-                ast: AstKind::While(While {
-                    while_: box while_,
-                    cond: box cond,
-                    do_: box do_,
-                    body: box body,
-                    end: box end,
-                }),
+            .map_with_span(
+                |(((((attrs, while_), cond), do_), body), end), span| AstNode {
+                    ast: AstKind::While(While {
+                        attrs,
+                        while_: box while_,
+                        cond: box cond,
+                        do_: box do_,
+                        body: box body,
+                        end: box end,
+                    }),
+                    span,
+                },
+            );
+
+        let dip =
+            kw_dip()
+                .then(body.clone())
+                .then(kw_end())
+                .map_with_span(|((kw, body), end), span| AstNode {
+                    span,
+                    ast: AstKind::Dip(Combinator {
+                        kw: kw.boxed(),
+                        body: body.boxed(),
+                        end: end.boxed(),
+                    }),
+                });
+        let keep =
+            kw_keep()
+                .then(body.clone())
+                .then(kw_end())
+                .map_with_span(|((kw, body), end), span| AstNode {
+                    span,
+                    ast: AstKind::Keep(Combinator {
+                        kw: kw.boxed(),
+                        body: body.boxed(),
+                        end: end.boxed(),
+                    }),
+                });
+        let defer = kw_defer().then(body.clone()).then(kw_end()).map_with_span(
+            |((kw, body), end), span| AstNode {
                 span,
-            });
+                ast: AstKind::Defer(Combinator {
+                    kw: kw.boxed(),
+                    body: body.boxed(),
+                    end: end.boxed(),
+                }),
+            },
+        );
 
         let lie = kw_else().then(body.clone()).map(|(else_, body)| Else {
             else_: box else_,
@@ -581,10 +906,14 @@ fn body() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> + Clone {
             word(),
             bind,
             while_,
+            dip,
+            keep,
+            defer,
             if_,
             cond,
             cast,
             kw_ret(),
+            kw_loop_jump(),
         ))
         .repeated()
         .map_with_span(|body, span| AstNode {
@@ -594,9 +923,25 @@ fn body() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> + Clone {
     })
 }
 
+fn named_ty() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> {
+    name()
+        .then(separator())
+        .then(sig_ty())
+        .map_with_span(|((name, sep), ty), span| AstNode {
+            span,
+            ast: AstKind::NamedType(NamedType {
+                name: box name,
+                sep: box sep,
+                ty: box ty,
+            }),
+        })
+        .or(sig_ty())
+}
+
 fn proc_signature() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> {
-    ty().repeated()
-        .then(separator().then(ty().repeated().at_least(1)).or_not())
+    sig_ty()
+        .repeated()
+        .then(separator().then(named_ty().repeated().at_least(1)).or_not())
         .map_with_span(|(ins, maybe_outs), span| {
             let (sep, outs) = if let Some((sep, outs)) = maybe_outs {
                 (Some(box sep), Some(outs))
@@ -620,14 +965,17 @@ fn const_signature() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>>
 }
 
 fn proc() -> impl Parser<Token, TopLevel, Error = Simple<Token, Span>> {
-    kw_proc()
-        .then(word())
+    attribute()
+        .repeated()
+        .then(kw_proc())
+        .then(name())
         .then(proc_signature())
         .then(kw_do())
         .then(body())
         .then(kw_end())
-        .map(|(((((proc, name), signature), do_), body), end)| {
+        .map(|((((((attrs, proc), name), signature), do_), body), end)| {
             TopLevel::Proc(Proc {
+                attrs,
                 proc,
                 name,
                 signature,
@@ -639,32 +987,64 @@ fn proc() -> impl Parser<Token, TopLevel, Error = Simple<Token, Span>> {
 }
 
 fn const_() -> impl Parser<Token, TopLevel, Error = Simple<Token, Span>> {
-    kw_const()
-        .then(word())
+    attribute()
+        .repeated()
+        .then(kw_const())
+        .then(name())
+        .then(name().repeated())
         .then(const_signature())
         .then(kw_do())
         .then(body())
         .then(kw_end())
-        .map(|(((((const_, name), signature), do_), body), end)| {
-            TopLevel::Const(Const {
-                const_,
-                name,
-                signature,
-                do_,
-                body,
-                end,
-            })
-        })
+        .try_map(
+            |(((((((attrs, const_), name), group), signature), do_), body), end), span| {
+                let tys = coerce_ast!(signature => REF ConstSignature || unreachable!())
+                    .tys
+                    .len();
+                if !group.is_empty() && group.len() + 1 != tys {
+                    return Simple::custom(
+                        span,
+                        format!(
+                            "A const group names each of its values, but has {} names for {} \
+                             types",
+                            group.len() + 1,
+                            tys
+                        ),
+                    )
+                    .error();
+                }
+                TopLevel::Const(Const {
+                    attrs,
+                    const_,
+                    name,
+                    group,
+                    signature,
+                    do_,
+                    body,
+                    end,
+                })
+                .okay()
+            },
+        )
 }
 
 fn mem() -> impl Parser<Token, TopLevel, Error = Simple<Token, Span>> {
-    kw_mem()
-        .then(word())
+    let local = kw_mem()
+        .then(name())
         .then(kw_do())
+        .map(|((mem, name), do_)| (None, mem, name, do_));
+    let extern_ = kw_extern()
+        .then(kw_mem())
+        .then(name())
+        .then(separator())
+        .map(|(((extern_, mem), name), sep)| (Some(extern_), mem, name, sep));
+    local
+        .or(extern_)
         .then(body())
         .then(kw_end())
-        .map(|((((mem, name), do_), body), end)| {
+        .map(|(((extern_, mem, name, do_), body), end)| {
             TopLevel::Mem(Mem {
+                extern_,
                 mem,
                 name,
                 do_,
@@ -674,35 +1054,131 @@ fn mem() -> impl Parser<Token, TopLevel, Error = Simple<Token, Span>> {
         })
 }
 
+fn table() -> impl Parser<Token, TopLevel, Error = Simple<Token, Span>> {
+    let len = word().try_map(|node, span| {
+        let len = match &node.ast {
+            AstKind::Word(w) => w.strip_prefix('[').and_then(|w| w.strip_suffix(']')),
+            _ => None,
+        }
+        .and_then(|len| len.parse::<u64>().ok())
+        .filter(|&len| len > 0)
+        .ok_or_else(|| Simple::custom(span.clone(), "expected a table length, like `[256]`"))?;
+        AstNode {
+            span,
+            ast: AstKind::Literal(IConst::Int(len)),
+        }
+        .okay()
+    });
+    kw_table()
+        .then(name())
+        .then(separator())
+        .then(ty())
+        .then(len)
+        .then(kw_do())
+        .then(body())
+        .then(kw_end())
+        .try_map(
+            |(((((((table, name), sep), ty), len), do_), body), end), _| {
+                check_table_type(&ty).map_err(|e| Simple::custom(ty.span.clone(), e))?;
+                TopLevel::Table(Table {
+                    table,
+                    name,
+                    sep,
+                    ty,
+                    len,
+                    do_,
+                    body,
+                    end,
+                })
+                .okay()
+            },
+        )
+}
+/// Table elements are integers, bools or chars, whatever a body can compute at compile time
+fn check_table_type(ty: &AstNode) -> Result<(), String> {
+    let ty = coerce_ast!(ty => REF Type || unreachable!());
+    match ty.type_name.as_str() {
+        _ if ty.ptr_count > 0 => (),
+        "bool" | "char" | "u8" | "i8" | "u16" | "i16" | "u32" | "i32" | "u64" | "i64" => {
+            return ().okay()
+        }
+        _ => (),
+    }
+    format!(
+        "a table can't hold `{}{}`, only integers, bools or chars",
+        "&>".repeat(ty.ptr_count),
+        ty.type_name
+    )
+    .error()
+}
+
 fn toplevel_var() -> impl Parser<Token, TopLevel, Error = Simple<Token, Span>> {
     kw_var()
-        .then(word())
+        .then(name())
         .then(separator())
         .then(ty())
         .map(|(((var, name), sep), ty)| TopLevel::Var(ToplevelVar { var, name, sep, ty }))
 }
 
 fn struct_field() -> impl Parser<Token, AstNode, Error = Simple<Token, Span>> {
-    word()
+    name()
         .then(separator())
         .then(ty())
-        .map_with_span(|((name, sep), ty), span| AstNode {
-            span,
-            ast: AstKind::StructField(StructField {
-                name: box name,
-                sep: box sep,
-                ty: box ty,
-            }),
+        .then(separator().ignore_then(literal()).or_not())
+        .try_map(|(((name, sep), ty), bits), span| {
+            if let Some(bits) = &bits {
+                check_bit_width(&ty, bits).map_err(|e| Simple::custom(bits.span.clone(), e))?;
+            }
+            AstNode {
+                span,
+                ast: AstKind::StructField(StructField {
+                    name: box name,
+                    sep: box sep,
+                    ty: box ty,
+                    bits: bits.map(Box::new),
+                }),
+            }
+            .okay()
         })
 }
+/// Bit-fields are integers or bools, at least one bit and at most as wide as their type
+fn check_bit_width(ty: &AstNode, bits: &AstNode) -> Result<(), String> {
+    let ty = coerce_ast!(ty => REF Type || unreachable!());
+    let max = match ty.type_name.as_str() {
+        _ if ty.ptr_count > 0 => None,
+        "bool" | "u8" | "i8" => 8.some(),
+        "u16" | "i16" => 16.some(),
+        "u32" | "i32" => 32.some(),
+        "u64" | "i64" => 64.some(),
+        _ => None,
+    };
+    let max = max.ok_or_else(|| {
+        format!(
+            "a bit-field can't be a `{}{}`, only an integer or a bool",
+            "&>".repeat(ty.ptr_count),
+            ty.type_name
+        )
+    })?;
+    match &bits.ast {
+        AstKind::Literal(IConst::Int(width)) if *width > 0 && *width <= max => ().okay(),
+        _ => format!(
+            "the width of a `{}` bit-field must be 1 to {} bits",
+            ty.type_name, max
+        )
+        .error(),
+    }
+}
 fn struct_() -> impl Parser<Token, TopLevel, Error = Simple<Token, Span>> {
-    kw_struct()
-        .then(word())
+    attribute()
+        .repeated()
+        .then(kw_struct())
+        .then(name())
         .then(kw_do())
         .then(struct_field().repeated())
         .then(kw_end())
-        .map(|((((struct_, name), do_), body), end)| {
+        .map(|(((((attrs, struct_), name), do_), body), end)| {
             TopLevel::Struct(Struct {
+                attrs,
                 struct_,
                 name,
                 do_,
@@ -712,76 +1188,156 @@ fn struct_() -> impl Parser<Token, TopLevel, Error = Simple<Token, Span>> {
         })
 }
 
+fn extern_() -> impl Parser<Token, TopLevel, Error = Simple<Token, Span>> {
+    let host = word().try_map(|node, span| match &node.ast {
+        AstKind::Word(w) if w == "host" => node.okay(),
+        _ => Simple::custom(span, "Expected `host`").error(),
+    });
+    kw_extern()
+        .then(host)
+        .then(name())
+        .then(proc_signature())
+        .map(|(((extern_, host), name), signature)| {
+            TopLevel::Extern(Extern {
+                extern_,
+                host,
+                name,
+                signature,
+            })
+        })
+}
+
+fn alias() -> impl Parser<Token, TopLevel, Error = Simple<Token, Span>> {
+    let eq = word().try_map(|node, span| match &node.ast {
+        AstKind::Word(w) if w == "=" => node.okay(),
+        _ => Simple::custom(span, "Expected `=`").error(),
+    });
+    kw_alias()
+        .then(name())
+        .then(eq)
+        .then(word())
+        .map(|(((alias, name), eq), target)| {
+            TopLevel::Alias(Alias {
+                alias,
+                name,
+                eq,
+                target,
+            })
+        })
+}
+
 fn include() -> impl Parser<Token, TopLevel, Error = Simple<Token, Span>> {
     kw_include()
         .then(include_path())
         .map(|(include, path)| TopLevel::Include(Include { include, path }))
 }
 
+/// Items up to the end of input. A malformed item is reported and skipped a token at a time
+/// until the next item that parses, which is never a `var`, since procs have those too, or to
+/// the end of input if none does.
 fn toplevel() -> impl Parser<Token, Vec<TopLevel>, Error = Simple<Token, Span>> {
-    choice((
+    let item = choice((
         include(),
         proc(),
         const_(),
         mem(),
-        toplevel_var(),
+        table(),
         struct_(),
+        extern_(),
+        alias(),
+    ));
+    choice((
+        toplevel_var().map(Some),
+        item.map(Some)
+            .recover_with(skip_then_retry_until([]))
+            .recover_with(skip_until([], |_| None)),
     ))
     .repeated()
+    .flatten()
     .then_ignore(end())
 }
 
+/// The well-formed items of `tokens` and the errors of the malformed ones
+pub fn parse_recovering(tokens: Vec<(Token, Span)>) -> (Vec<TopLevel>, Vec<Simple<Token, Span>>) {
+    let (items, errors) = toplevel().parse_recovery(Stream::from_iter(
+        tokens.last().unwrap().1.clone(),
+        tokens.into_iter(),
+    ));
+    (items.unwrap_or_default(), errors)
+}
+
 pub fn parse_no_include(tokens: Vec<(Token, Span)>) -> Result<Vec<TopLevel>, Error> {
-    toplevel()
-        .parse(Stream::from_iter(
-            tokens.last().unwrap().1.clone(),
-            tokens.into_iter(),
-        ))
-        .map_err(Error::Parser)
+    match parse_recovering(tokens) {
+        (items, errors) if errors.is_empty() => items.okay(),
+        (_, errors) => Error::Parser(errors).error(),
+    }
 }
 
 pub fn parse(tokens: Vec<(Token, Span)>) -> Result<FnvHashMap<String, TopLevel>, Error> {
-    let items = match toplevel().parse(Stream::from_iter(
-        tokens.last().unwrap().1.clone(),
-        tokens.into_iter(),
-    )) {
-        Ok(items) => items,
-        Err(es) => return Error::Parser(es).error(),
-    };
-
-    let (includes, mut items) = items
-        .into_iter()
-        .partition::<Vec<_>, _>(|item| matches!(item, TopLevel::Include(_)));
+    parse_with_include_paths(tokens, &[])
+}
 
-    for include in includes {
-        if let TopLevel::Include(include) = include {
-            resolve_include(&include.path.span.file, include.path(), &mut items)?;
-        } else {
-            unreachable!();
-        }
-    }
+/// `parse`, looking for included files in `include_paths` too
+pub fn parse_with_include_paths(
+    tokens: Vec<(Token, Span)>,
+    include_paths: &[PathBuf],
+) -> Result<FnvHashMap<String, TopLevel>, Error> {
+    let file = tokens.last().unwrap().1.file.clone();
+    let items = resolve_includes(&file, parse_no_include(tokens)?, include_paths)?;
 
     let mut res = FnvHashMap::default();
+    let mut defined = FnvHashMap::<_, Span>::default();
     let mut errors = Vec::new();
 
     for item in items {
-        match res.entry(item.name().unwrap()) {
-            Entry::Occupied(it) => {
-                let redefined: &TopLevel = it.get();
-                errors.push(RedefinitionError {
-                    redefining_item: item.span(),
-                    redefined_item: redefined.span(),
-                });
-            }
-            Entry::Vacant(v) => {
-                v.insert(item);
+        for (name, span) in item.names() {
+            match defined.entry(name) {
+                Entry::Occupied(it) => errors.push(RedefinitionError {
+                    redefining_item: span,
+                    redefined_item: it.get().clone(),
+                }),
+                Entry::Vacant(v) => {
+                    v.insert(span);
+                }
             }
         }
+        res.entry(item.name().unwrap()).or_insert(item);
+    }
+
+    if !errors.is_empty() {
+        return Error::Redefinition(errors).error();
     }
+    resolve_aliases(res.values())?;
+    res.okay()
+}
 
-    if errors.is_empty() {
-        res.okay()
-    } else {
-        Error::Redefinition(errors).error()
+/// Follows alias chains to the word each alias finally stands for
+pub fn resolve_aliases<'a>(
+    items: impl IntoIterator<Item = &'a TopLevel>,
+) -> Result<FnvHashMap<String, String>, Error> {
+    let aliases = items
+        .into_iter()
+        .filter_map(|item| match item {
+            TopLevel::Alias(a) => (item.name().unwrap(), a).some(),
+            _ => None,
+        })
+        .collect::<FnvHashMap<_, _>>();
+    let mut res = FnvHashMap::default();
+    for name in aliases.keys() {
+        let mut chain = vec![name.as_str()];
+        let mut target = aliases[name].target();
+        while let Some(next) = aliases.get(target) {
+            if chain.contains(&target) {
+                let spans = chain
+                    .iter()
+                    .map(|a| aliases[*a].name.span.clone())
+                    .collect();
+                return Error::AliasCycle(spans).error();
+            }
+            chain.push(target);
+            target = next.target();
+        }
+        res.insert(name.clone(), target.to_string());
     }
+    res.okay()
 }
@@ -53,8 +53,10 @@ fn test_const() {
     assert_matches!(
         ast,
         Ok(TopLevel::Const(Const {
+            attrs: _,
             const_: _,
             name: _,
+            group: _,
             signature: _,
             do_: _,
             body: _,
@@ -79,6 +81,7 @@ fn test_mem() {
     assert_matches!(
         ast,
         Ok(TopLevel::Mem(Mem {
+            extern_: None,
             mem: _,
             name: _,
             do_: _,
@@ -128,6 +131,7 @@ fn test_proc() {
     assert_matches!(
         ast,
         Ok(TopLevel::Proc(Proc {
+            attrs: _,
             proc: _,
             name: _,
             signature: _,
@@ -138,6 +142,62 @@ fn test_proc() {
     )
 }
 #[test]
+fn test_proc_named_outs() {
+    let tokens = lex_string(
+        indoc::indoc! {r#"
+            u64 u64 : quotient: u64 u64
+        "#}
+        .into(),
+        "./".try_into().unwrap(),
+    )
+    .unwrap();
+    let ast = proc_signature().then_ignore(end()).parse(Stream::from_iter(
+        tokens.last().unwrap().1.clone(),
+        tokens.into_iter(),
+    ));
+    assert_matches!(
+        ast,
+        Ok(AstNode {
+            span: _,
+            ast: AstKind::ProcSignature(ProcSignature {
+                ins: _,
+                sep: Some(_),
+                outs: Some(outs),
+            })
+        }) if matches!(
+            &outs[..],
+            [
+                AstNode { ast: AstKind::NamedType(_), .. },
+                AstNode { ast: AstKind::Type(_), .. }
+            ]
+        )
+    )
+}
+#[test]
+fn test_proc_attributes() {
+    let tokens = lex_string(
+        indoc::indoc! {r#"
+            #[deprecated("use bar")]
+            proc foo do end
+        "#}
+        .into(),
+        "./".try_into().unwrap(),
+    )
+    .unwrap();
+    let ast = proc().then_ignore(end()).parse(Stream::from_iter(
+        tokens.last().unwrap().1.clone(),
+        tokens.into_iter(),
+    ));
+    assert_matches!(
+        ast,
+        Ok(TopLevel::Proc(Proc { attrs, .. })) if matches!(
+            &attrs[..],
+            [AstNode { ast: AstKind::Attribute(Attribute { name, arg: Some(arg) }), .. }]
+                if name == "deprecated" && arg == "use bar"
+        )
+    )
+}
+#[test]
 fn test_struct() {
     let tokens = lex_string(
         indoc::indoc! {r#"
@@ -157,6 +217,7 @@ fn test_struct() {
     assert_matches!(
         ast,
         Ok(TopLevel::Struct(Struct {
+            attrs: _,
             struct_: _,
             name: _,
             do_: _,
@@ -187,3 +248,125 @@ fn test_ty() {
         })
     )
 }
+#[test]
+fn test_extern_host() {
+    let tokens = lex_string(
+        indoc::indoc! {r#"
+            extern host clock u64 : u64
+        "#}
+        .into(),
+        "./".try_into().unwrap(),
+    )
+    .unwrap();
+    let ast = extern_().then_ignore(end()).parse(Stream::from_iter(
+        tokens.last().unwrap().1.clone(),
+        tokens.into_iter(),
+    ));
+    assert_matches!(
+        ast,
+        Ok(TopLevel::Extern(Extern {
+            name: AstNode { ast: AstKind::Word(name), .. },
+            signature: AstNode { ast: AstKind::ProcSignature(_), .. },
+            ..
+        })) if name == "clock"
+    )
+}
+
+#[test]
+fn test_alias() {
+    let tokens = lex_string(
+        indoc::indoc! {r#"
+            alias @8 = @u64
+            alias load = @8
+            alias a = b
+            alias b = a
+        "#}
+        .into(),
+        "./".try_into().unwrap(),
+    )
+    .unwrap();
+    let items = parse_no_include(tokens).unwrap();
+    assert_matches!(&items[0], TopLevel::Alias(a) if a.target() == "@u64");
+    let aliases = resolve_aliases(&items[..2]).unwrap();
+    assert_eq!(aliases["load"], "@u64");
+    assert_matches!(resolve_aliases(&items), Err(Error::AliasCycle(spans)) if spans.len() == 2);
+}
+
+#[test]
+fn test_num_suffixes() {
+    assert_eq!(num_literal("42"), IConst::Int(42));
+    assert_eq!(num_literal("42u"), IConst::U64(42));
+    assert_eq!(num_literal("42i"), IConst::I64(42));
+    assert_eq!(num_literal("42p"), IConst::Ptr(42));
+}
+
+#[test]
+fn test_reserved() {
+    let parse = |src: &str| parse_no_include(lex_string(src.into(), "./".into()).unwrap());
+    assert_matches!(parse("proc enums do end"), Ok(_));
+    for src in [
+        "proc enum do end",
+        "const union : u64 do 1 end",
+        "struct S do macro: u64 end",
+        "proc f do bind enum: u64 do end end",
+    ] {
+        match parse(src) {
+            Err(Error::Parser(es)) => assert!(
+                es.iter()
+                    .any(|e| format!("{:?}", e).contains("reserved for a future keyword")),
+                "{}: {:?}",
+                src,
+                es
+            ),
+            res => panic!("{}: {:?}", src, res),
+        }
+    }
+}
+
+#[test]
+fn test_num_literal() {
+    assert_eq!(num_literal("1_000_000"), IConst::Int(1_000_000));
+    assert_eq!(num_literal("0xFF"), IConst::Int(255));
+    assert_eq!(num_literal("0b1010u"), IConst::U64(10));
+    assert_eq!(num_literal("0o777p"), IConst::Ptr(0o777));
+    assert_eq!(num_literal("0xffff_ffff_ffff_ffffi"), IConst::I64(-1));
+    assert_eq!(num_literal("12i"), IConst::I64(12));
+}
+
+#[test]
+fn test_const_group() {
+    let lex = |src: &str| lex_string(src.into(), "./".into()).unwrap();
+    let items = parse_no_include(lex("const W H : u64 u64 do 80 24 end")).unwrap();
+    assert_matches!(&items[..], [item @ TopLevel::Const(Const { group, .. })] if group.len() == 1
+        && item.names().into_iter().map(|(n, _)| n).collect::<Vec<_>>() == ["W", "H"]);
+    assert_matches!(
+        parse_no_include(lex("const W H : u64 do 80 end")),
+        Err(Error::Parser(es)) if format!("{:?}", es).contains("has 2 names for 1 types")
+    );
+    assert_matches!(
+        parse(lex("const W H : u64 u64 do 80 24 end proc H do end")),
+        Err(Error::Redefinition(es)) if es.len() == 1
+    );
+}
+
+#[test]
+fn test_recovery() {
+    let tokens = lex_string(
+        indoc::indoc! {r#"
+            proc a : u64 do 1 end
+            proc b : u64 1 end
+            var x : u64
+            const C : u64 do 2 end
+            proc d : u64 do var y : u64 if end
+            proc e do end
+            mem m 8 end
+        "#}
+        .into(),
+        "./".try_into().unwrap(),
+    )
+    .unwrap();
+    let (items, errors) = parse_recovering(tokens);
+    let names = items.iter().filter_map(TopLevel::name).collect::<Vec<_>>();
+    assert_eq!(names, ["a", "C", "e"]);
+    assert_eq!(errors.len(), 3, "{:?}", errors);
+}
@@ -0,0 +1,215 @@
+//! Which features of a program the interpreter and each backend lack, so what keeps it from
+//! running on one of them is known before trying.
+
+use crate::{
+    emit, eval,
+    iconst::IConst,
+    lir::{Op, Program},
+    session::{x86_64_syscalls, Session, AARCH64_SYSCALLS},
+    span::{SourceMap, Span},
+};
+use somok::Somok;
+use std::fmt::{self, Write};
+
+pub use crate::session::Engine;
+
+/// Something a program uses that not everything running it has
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Feature {
+    /// A syscall by its x86-64 number
+    Syscall(u64),
+    /// A syscall whose number is only known when it is made
+    ComputedSyscall,
+    /// A call of an `extern host` proc
+    HostProc(String),
+    /// An `extern mem`, defined by an object linked in
+    ExternMem(String),
+    /// A piece of the runtime left out to link another one
+    OwnRuntime,
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Feature::Syscall(nr) => match x86_64_syscalls().find(|(_, n)| n == nr) {
+                Some((name, _)) => write!(f, "syscall `{}` ({})", name, nr),
+                None => write!(f, "syscall {}", nr),
+            },
+            Feature::ComputedSyscall => write!(f, "syscall with a computed number"),
+            Feature::HostProc(name) => write!(f, "host proc `{}`", name),
+            Feature::ExternMem(name) => write!(f, "extern mem `{}`", name),
+            Feature::OwnRuntime => write!(f, "own runtime"),
+        }
+    }
+}
+
+impl Engine {
+    /// Why this lacks `feature`, `None` if it has it
+    pub fn lacks(self, feature: &Feature) -> Option<&'static str> {
+        use Engine::*;
+        match (feature, self) {
+            (Feature::Syscall(nr), Interpreter) if !eval::HOST_SYSCALLS.contains(nr) => {
+                "The interpreter only does `read`, `write`, `open`, `close`, `brk` and `exit`, \
+                 others fail with ENOSYS"
+                    .some()
+            }
+            (Feature::Syscall(nr), Wasm) if !emit::wasm::SYSCALLS.contains(nr) => {
+                "Only syscalls with a WASI counterpart are mapped, others fail with ENOSYS".some()
+            }
+            (Feature::Syscall(nr), Aarch64)
+                if !AARCH64_SYSCALLS.iter().any(|(_, x86, _)| x86 == nr) =>
+            {
+                "AArch64 has no counterpart, it fails with ENOSYS".some()
+            }
+            (Feature::Syscall(_) | Feature::ComputedSyscall, Cranelift)
+                if !cfg!(all(target_arch = "x86_64", target_os = "linux")) =>
+            {
+                "The JIT only makes syscalls on an x86-64 Linux host".some()
+            }
+            (Feature::ComputedSyscall, Interpreter | Wasm | Aarch64) => {
+                "Only some syscalls are supported and this one is not known until it is made".some()
+            }
+            (Feature::HostProc(_), Interpreter) => None,
+            (Feature::HostProc(_), _) => {
+                "Host procs can only be called from the interpreter".some()
+            }
+            (Feature::ExternMem(_), Interpreter | Elf | Wasm | Cranelift) => {
+                "Nothing links in the object defining it, build through NASM or C".some()
+            }
+            (Feature::OwnRuntime, Elf) => {
+                "Executables written directly bundle the whole runtime, build through NASM".some()
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A feature a program uses, at its first use
+#[derive(Debug, Clone)]
+pub struct Use {
+    pub feature: Feature,
+    /// `None` without `Comments::Spans`, or for features of the whole program
+    pub span: Option<Span>,
+}
+
+/// Every feature `program` uses, once and in the order its ops first use it
+pub fn features(program: &Program, session: &Session) -> Vec<Use> {
+    let mut uses = Vec::<Use>::new();
+    let mut add = |feature: Feature, span: Option<&Span>| {
+        if uses.iter().all(|u| u.feature != feature) {
+            uses.push(Use {
+                feature,
+                span: span.cloned(),
+            })
+        }
+    };
+    let options = &session.options;
+    if !options.runtime.print || (options.checked && !options.runtime.traps) {
+        add(Feature::OwnRuntime, None);
+    }
+    for name in program.extern_mems.keys() {
+        let span = program.mem_spans.get(name);
+        add(Feature::ExternMem(name.clone()), span);
+    }
+
+    let (mut span, mut pushed) = (None, None);
+    for op in &program.ops {
+        match op {
+            Op::Loc(s) => span = Some(s),
+            Op::Syscall0
+            | Op::Syscall1
+            | Op::Syscall2
+            | Op::Syscall3
+            | Op::Syscall4
+            | Op::Syscall5
+            | Op::Syscall6 => match pushed {
+                Some(nr) => add(Feature::Syscall(nr), span),
+                None => add(Feature::ComputedSyscall, span),
+            },
+            Op::CallHost(name, _, _) => add(Feature::HostProc(name.clone()), span),
+            _ => (),
+        }
+        pushed = match op {
+            Op::Push(IConst::Int(nr) | IConst::U64(nr)) => Some(*nr),
+            Op::Loc(_) => pushed,
+            _ => None,
+        };
+    }
+    uses
+}
+
+/// The uses `engine` lacks, each with why
+pub fn gaps(uses: &[Use], engine: Engine) -> Vec<(&Use, &'static str)> {
+    uses.iter()
+        .filter_map(|u| Some((u, engine.lacks(&u.feature)?)))
+        .collect()
+}
+
+/// The gaps of every one of `engines`, with their locations in `sources`
+pub fn report(uses: &[Use], engines: &[Engine], sources: &SourceMap) -> String {
+    let mut res = String::new();
+    for &engine in engines {
+        let gaps = gaps(uses, engine);
+        if gaps.is_empty() {
+            writeln!(
+                res,
+                "{}: supports everything the program uses",
+                engine.name()
+            )
+            .unwrap();
+            continue;
+        }
+        writeln!(res, "{}: {} unsupported", engine.name(), gaps.len()).unwrap();
+        for (u, why) in gaps {
+            let location = u.span.as_ref().and_then(|s| sources.location(s));
+            match location {
+                Some(location) => writeln!(res, "  {} at {}", u.feature, location),
+                None => writeln!(res, "  {}", u.feature),
+            }
+            .unwrap();
+            writeln!(res, "    {}", why).unwrap();
+        }
+    }
+    res
+}
+
+#[test]
+fn test_features() {
+    use crate::lir::lower_source;
+    let (ops, _) = lower_source(
+        "
+        extern host clock u64 : u64
+        proc main : u64 do
+            0 0 0 57 syscall3 drop
+            0 0 0 argc syscall3 drop
+            1 clock drop
+            0 0 0 1 syscall3
+        end",
+    );
+    let program = Program {
+        ops,
+        ..Default::default()
+    };
+    let features = features(&program, &Session::default())
+        .into_iter()
+        .map(|u| u.feature)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        features,
+        [
+            Feature::Syscall(57),
+            Feature::ComputedSyscall,
+            Feature::HostProc("clock".to_string()),
+            Feature::Syscall(1),
+        ]
+    );
+    let lacking = |engine: Engine| {
+        features
+            .iter()
+            .filter(|f| engine.lacks(f).is_some())
+            .count()
+    };
+    assert_eq!(lacking(Engine::Interpreter), 2);
+    assert_eq!(lacking(Engine::Nasm), 1);
+    assert_eq!(lacking(Engine::Wasm), 3);
+}
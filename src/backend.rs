@@ -0,0 +1,108 @@
+use crate::{emit, emit_aarch64, lir::Program, session::Session, Result};
+use somok::Somok;
+use std::io::{BufWriter, Write};
+
+#[cfg(feature = "cranelift")]
+pub mod cranelift;
+
+/// Turns the lowered program into something that can run
+pub trait Backend {
+    type Output;
+
+    fn compile(self, program: Program, session: &Session) -> Result<Self::Output>;
+}
+
+/// Writes NASM assembly to a sink, see `emit`
+pub struct Nasm<S: Write> {
+    sink: BufWriter<S>,
+}
+
+impl<S: Write> Nasm<S> {
+    pub fn new(sink: BufWriter<S>) -> Self {
+        Self { sink }
+    }
+}
+
+impl<S: Write> Backend for Nasm<S> {
+    type Output = ();
+
+    fn compile(self, program: Program, session: &Session) -> Result<()> {
+        emit::compile(program, session, self.sink)?.okay()
+    }
+}
+
+/// Writes GNU assembly for AArch64, see `emit_aarch64`
+pub struct Aarch64<S: Write> {
+    sink: BufWriter<S>,
+}
+
+impl<S: Write> Aarch64<S> {
+    pub fn new(sink: BufWriter<S>) -> Self {
+        Self { sink }
+    }
+}
+
+impl<S: Write> Backend for Aarch64<S> {
+    type Output = ();
+
+    fn compile(self, program: Program, session: &Session) -> Result<()> {
+        emit_aarch64::compile(program, session, self.sink)?.okay()
+    }
+}
+
+/// Writes a C translation of the program, see `emit::c`
+pub struct C<S: Write> {
+    sink: BufWriter<S>,
+}
+
+impl<S: Write> C<S> {
+    pub fn new(sink: BufWriter<S>) -> Self {
+        Self { sink }
+    }
+}
+
+impl<S: Write> Backend for C<S> {
+    type Output = ();
+
+    fn compile(self, program: Program, session: &Session) -> Result<()> {
+        emit::c::compile(program, session, self.sink)?.okay()
+    }
+}
+
+/// Writes a WebAssembly text module for WASI, see `emit::wasm`
+pub struct Wasm<S: Write> {
+    sink: BufWriter<S>,
+}
+
+impl<S: Write> Wasm<S> {
+    pub fn new(sink: BufWriter<S>) -> Self {
+        Self { sink }
+    }
+}
+
+impl<S: Write> Backend for Wasm<S> {
+    type Output = ();
+
+    fn compile(self, program: Program, session: &Session) -> Result<()> {
+        emit::wasm::compile(program, session, self.sink)?.okay()
+    }
+}
+
+/// Writes a static x86-64 executable, see `emit::elf`
+pub struct Elf<S: Write> {
+    sink: BufWriter<S>,
+}
+
+impl<S: Write> Elf<S> {
+    pub fn new(sink: BufWriter<S>) -> Self {
+        Self { sink }
+    }
+}
+
+impl<S: Write> Backend for Elf<S> {
+    type Output = ();
+
+    fn compile(self, program: Program, session: &Session) -> Result<()> {
+        emit::elf::compile(program, session, self.sink)?.okay()
+    }
+}
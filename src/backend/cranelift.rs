@@ -0,0 +1,935 @@
+use super::Backend;
+use crate::{
+    hir::PrintFormat,
+    iconst::IConst,
+    intrinsics,
+    lir::{self, Cmp, Op},
+    session::Session,
+    Error, Result,
+};
+use cranelift_codegen::{
+    ir::{
+        condcodes::IntCC, types::I16, types::I32, types::I64, types::I8, types::I8X16, AbiParam,
+        Block, FuncRef, InstBuilder, MemFlags, Signature, Type, Value,
+    },
+    settings::{self, Configurable},
+};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
+use fnv::FnvHashMap;
+use somok::Somok;
+use std::ffi::CString;
+
+/// Words in the data stack, the native backend uses the process stack instead
+const DATA_STACK_WORDS: usize = 1 << 17;
+/// Name of the function holding the ops before the first proc
+const ENTRY: &str = "_start";
+/// Whether `rotth_syscall` can make the syscall the program asks for, whose numbers and
+/// arguments are the x86-64 Linux ones
+const NATIVE_SYSCALLS: bool = cfg!(all(target_arch = "x86_64", target_os = "linux"));
+
+/// Compiles the program to machine code in memory with Cranelift, so it runs without nasm or ld.
+/// Every proc becomes a function taking and returning the data stack pointer.
+/// Alignment traps and backtraces of checked builds are only emitted by the NASM backend.
+pub struct Cranelift;
+
+/// A JIT compiled program along with the memory it runs in
+pub struct Program {
+    module: Option<JITModule>,
+    entry: *const u8,
+    state: Box<State>,
+    data_stack: Vec<u64>,
+    ret_stack: Vec<u64>,
+    locals_stack: Vec<u64>,
+    escaping_stack: Vec<u64>,
+    mems: FnvHashMap<String, Vec<u64>>,
+    /// Bytes of each table, in words to align them
+    tables: FnvHashMap<String, Vec<u64>>,
+    strings: Vec<String>,
+}
+
+/// Globals the generated code reads and writes by address
+#[repr(C)]
+#[derive(Default)]
+struct State {
+    ret_sp: u64,
+    locals_sp: u64,
+    escaping_sp: u64,
+    argc: u64,
+    argv: u64,
+}
+
+impl Program {
+    /// Runs the program with `args` as its argv and returns its exit code
+    pub fn run(&mut self, args: &[String]) -> u64 {
+        let args = args
+            .iter()
+            .map(|a| CString::new(a.as_str()).unwrap_or_default())
+            .collect::<Vec<_>>();
+        let argv = args
+            .iter()
+            .map(|a| a.as_ptr())
+            .chain([std::ptr::null()])
+            .collect::<Vec<_>>();
+        self.state.argc = args.len() as u64;
+        self.state.argv = argv.as_ptr() as u64;
+        self.state.ret_sp = end(&self.ret_stack);
+        self.state.locals_sp = end(&self.locals_stack);
+        self.state.escaping_sp = end(&self.escaping_stack);
+        for mem in self.mems.values_mut() {
+            mem.fill(0)
+        }
+        // the last word stays zero, so exiting with an empty stack exits with 0
+        self.data_stack.fill(0);
+        let sp = end(&self.data_stack) - 8;
+
+        // Safety: the entry was compiled with the `(sp) -> exitcode` signature,
+        // and everything the code addresses is owned by `self`
+        let entry: extern "C" fn(u64) -> u64 = unsafe { std::mem::transmute(self.entry) };
+        entry(sp)
+    }
+
+    fn addresses(&self) -> Addresses {
+        Addresses {
+            strings: self
+                .strings
+                .iter()
+                .map(|s| (s.len() as u64, s.as_ptr() as u64))
+                .collect(),
+            mems: self
+                .mems
+                .iter()
+                .map(|(name, mem)| (name.clone(), mem.as_ptr() as u64))
+                .collect(),
+            tables: self
+                .tables
+                .iter()
+                .map(|(name, table)| (name.clone(), table.as_ptr() as u64))
+                .collect(),
+            ret_sp: &self.state.ret_sp as *const u64 as u64,
+            locals_sp: &self.state.locals_sp as *const u64 as u64,
+            escaping_sp: &self.state.escaping_sp as *const u64 as u64,
+            argc: &self.state.argc as *const u64 as u64,
+            argv: &self.state.argv as *const u64 as u64,
+        }
+    }
+}
+
+impl Drop for Program {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.take() {
+            // Safety: nothing can call into the module once the program is gone
+            unsafe { module.free_memory() }
+        }
+    }
+}
+
+fn end(stack: &[u64]) -> u64 {
+    stack.as_ptr() as u64 + 8 * stack.len() as u64
+}
+
+/// Where the program's memory lives, baked into the generated code
+struct Addresses {
+    /// Length and pointer of each literal
+    strings: Vec<(u64, u64)>,
+    mems: FnvHashMap<String, u64>,
+    tables: FnvHashMap<String, u64>,
+    ret_sp: u64,
+    locals_sp: u64,
+    escaping_sp: u64,
+    argc: u64,
+    argv: u64,
+}
+
+impl Backend for Cranelift {
+    type Output = Program;
+
+    fn compile(self, lowered: lir::Program, session: &Session) -> Result<Program> {
+        let ops = lowered.ops;
+        if let Some(name) = lowered.extern_mems.keys().next() {
+            return backend_error(format!("`{}` is an extern mem, which needs linking", name))
+                .error();
+        }
+        let syscalls = ops.iter().any(|op| {
+            matches!(
+                op,
+                Op::Syscall0
+                    | Op::Syscall1
+                    | Op::Syscall2
+                    | Op::Syscall3
+                    | Op::Syscall4
+                    | Op::Syscall5
+                    | Op::Syscall6
+            )
+        });
+        if syscalls && !NATIVE_SYSCALLS {
+            return backend_error("Syscalls are only supported on an x86-64 Linux host").error();
+        }
+        // the binding, locals and escaping stacks are as big as the native ones
+        let stack_words = session.options.stack_size as usize / 8;
+        let mut program = Program {
+            module: None,
+            entry: std::ptr::null(),
+            state: Default::default(),
+            data_stack: vec![0; DATA_STACK_WORDS],
+            ret_stack: vec![0; stack_words],
+            locals_stack: vec![0; stack_words],
+            escaping_stack: vec![0; stack_words],
+            mems: lowered
+                .mems
+                .iter()
+                .map(|(name, size)| (name.clone(), vec![0; size.div_ceil(8)]))
+                .collect(),
+            tables: lowered
+                .tables
+                .iter()
+                .map(|(name, bytes)| {
+                    let words = bytes.chunks(8).map(|chunk| {
+                        let mut word = [0; 8];
+                        word[..chunk.len()].copy_from_slice(chunk);
+                        u64::from_le_bytes(word)
+                    });
+                    (name.clone(), words.collect())
+                })
+                .collect(),
+            strings: session.strings().to_vec(),
+        };
+        let addresses = program.addresses();
+
+        let mut flags = settings::builder();
+        flags.set("opt_level", "speed").map_err(backend_error)?;
+        flags.set("is_pic", "false").map_err(backend_error)?;
+        let isa = cranelift_native::builder()
+            .map_err(backend_error)?
+            .finish(settings::Flags::new(flags))
+            .map_err(backend_error)?;
+        let mut builder = JITBuilder::with_isa(isa, default_libcall_names());
+        builder.symbol("rotth_print", rotth_print as *const u8);
+        builder.symbol("rotth_hash_str", rotth_hash_str as *const u8);
+        builder.symbol("rotth_find_byte", rotth_find_byte as *const u8);
+        builder.symbol("rotth_syscall", rotth_syscall as *const u8);
+        let mut module = JITModule::new(builder);
+        let hosts = Hosts::declare(&mut module)?;
+
+        let starts = ops
+            .iter()
+            .enumerate()
+            .filter(|(_, op)| matches!(op, Op::Proc(_)))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        let mut bodies = vec![(ENTRY, &ops[..starts.first().copied().unwrap_or(ops.len())])];
+        for (i, &start) in starts.iter().enumerate() {
+            let end = starts.get(i + 1).copied().unwrap_or(ops.len());
+            match &ops[start] {
+                Op::Proc(name) => bodies.push((name.as_str(), &ops[start + 1..end])),
+                _ => unreachable!(),
+            }
+        }
+
+        let signature = word_signature(&module, 1, 1);
+        let mut ids = FnvHashMap::default();
+        for (name, _) in &bodies {
+            let id = module
+                .declare_function(name, Linkage::Local, &signature)
+                .map_err(backend_error)?;
+            ids.insert(name.to_string(), id);
+        }
+
+        let mut ctx = module.make_context();
+        let mut builder_ctx = FunctionBuilderContext::new();
+        for (name, body) in bodies {
+            ctx.func.signature = signature.clone();
+            Lowering {
+                b: FunctionBuilder::new(&mut ctx.func, &mut builder_ctx),
+                module: &mut module,
+                ids: &ids,
+                hosts: &hosts,
+                addresses: &addresses,
+                refs: Default::default(),
+                labels: Default::default(),
+                sp: Variable::from_u32(0),
+                entry: name == ENTRY,
+                terminated: false,
+            }
+            .lower(body)?;
+            module
+                .define_function(ids[name], &mut ctx)
+                .map_err(backend_error)?;
+            module.clear_context(&mut ctx);
+        }
+        module.finalize_definitions().map_err(backend_error)?;
+
+        program.entry = module.get_finalized_function(ids[ENTRY]);
+        program.module = module.some();
+        program.okay()
+    }
+}
+
+fn backend_error(e: impl ToString) -> Error {
+    Error::Backend(e.to_string())
+}
+
+fn word_signature(module: &JITModule, ins: usize, outs: usize) -> Signature {
+    let mut signature = module.make_signature();
+    signature.params = vec![AbiParam::new(I64); ins];
+    signature.returns = vec![AbiParam::new(I64); outs];
+    signature
+}
+
+/// Runtime routines implemented in Rust
+struct Hosts {
+    print: FuncId,
+    hash_str: FuncId,
+    find_byte: FuncId,
+    syscall: FuncId,
+}
+
+impl Hosts {
+    fn declare(module: &mut JITModule) -> Result<Self> {
+        let mut host = |name, ins, outs| {
+            let signature = word_signature(module, ins, outs);
+            module
+                .declare_function(name, Linkage::Import, &signature)
+                .map_err(backend_error)
+        };
+        Self {
+            print: host("rotth_print", 4, 0)?,
+            hash_str: host("rotth_hash_str", 2, 1)?,
+            find_byte: host("rotth_find_byte", 3, 1)?,
+            syscall: host("rotth_syscall", 7, 1)?,
+        }
+        .okay()
+    }
+}
+
+extern "C" fn rotth_print(value: u64, radix: u64, signed: u64, width: u64) {
+    let format = match (radix, signed) {
+        (16, _) => PrintFormat::Hex,
+        (_, 0) => PrintFormat::Unsigned,
+        _ => PrintFormat::Signed,
+    };
+    println!("{}", format.format(value, width as usize));
+}
+
+extern "C" fn rotth_hash_str(len: u64, ptr: u64) -> u64 {
+    // Safety: the typechecker only lets `len ptr` pairs of strings through
+    intrinsics::hash_str(unsafe { std::slice::from_raw_parts(ptr as *const u8, len as usize) })
+}
+
+extern "C" fn rotth_find_byte(ptr: u64, len: u64, byte: u64) -> u64 {
+    // Safety: the caller vouches for `len` bytes at `ptr`, as for the native backends
+    let bytes = unsafe { std::slice::from_raw_parts(ptr as *const u8, len as usize) };
+    bytes
+        .iter()
+        .position(|&b| b == byte as u8)
+        .map_or(len, |i| i as u64)
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+extern "C" fn rotth_syscall(nr: u64, a: u64, b: u64, c: u64, d: u64, e: u64, f: u64) -> u64 {
+    let res;
+    // Safety: as safe as the syscalls the program makes when compiled natively
+    unsafe {
+        std::arch::asm!(
+            "syscall",
+            inlateout("rax") nr => res,
+            in("rdi") a,
+            in("rsi") b,
+            in("rdx") c,
+            in("r10") d,
+            in("r8") e,
+            in("r9") f,
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack),
+        )
+    }
+    res
+}
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+extern "C" fn rotth_syscall(_: u64, _: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+    unreachable!("Programs making syscalls are rejected by `Cranelift::compile` on this host")
+}
+
+/// Lowers the ops of one proc into the body of a Cranelift function
+struct Lowering<'f, 'm> {
+    b: FunctionBuilder<'f>,
+    module: &'m mut JITModule,
+    ids: &'m FnvHashMap<String, FuncId>,
+    hosts: &'m Hosts,
+    addresses: &'m Addresses,
+    refs: FnvHashMap<FuncId, FuncRef>,
+    labels: FnvHashMap<String, Block>,
+    /// Data stack pointer, the stack grows down
+    sp: Variable,
+    entry: bool,
+    /// The current block already ends in a jump or return
+    terminated: bool,
+}
+
+impl<'f, 'm> Lowering<'f, 'm> {
+    fn lower(mut self, body: &[Op]) -> Result<()> {
+        let start = self.b.create_block();
+        self.b.append_block_params_for_function_params(start);
+        self.b.switch_to_block(start);
+        self.b.declare_var(self.sp, I64);
+        let sp = self.b.block_params(start)[0];
+        self.b.def_var(self.sp, sp);
+        for op in body {
+            if let Op::Label(l) = op {
+                let block = self.b.create_block();
+                self.labels.insert(l.clone(), block);
+            }
+        }
+
+        for op in body {
+            self.lower_op(op)?;
+        }
+        if !self.terminated {
+            let sp = self.b.use_var(self.sp);
+            self.b.ins().return_(&[sp]);
+        }
+        self.b.seal_all_blocks();
+        self.b.finalize();
+        ().okay()
+    }
+
+    fn lower_op(&mut self, op: &Op) -> Result<()> {
+        if self.terminated && !matches!(op, Op::Label(_) | Op::Loc(_)) {
+            // code after a jump or return is unreachable, but still needs a block
+            let block = self.b.create_block();
+            self.b.switch_to_block(block);
+            self.terminated = false;
+        }
+        match op {
+            Op::Push(c) => {
+                let c = match c {
+                    IConst::Bool(b) => *b as u64,
+                    IConst::Char(c) => *c as u64,
+                    IConst::U64(u) => *u,
+                    IConst::I64(i) => *i as u64,
+                    IConst::Ptr(p) => *p,
+                    IConst::Int(_) | IConst::Str(_) => unreachable!(),
+                };
+                let c = self.konst(c);
+                self.push(c)
+            }
+            Op::PushStr(i) => {
+                let (len, ptr) = self.addresses.strings[*i];
+                let len = self.konst(len);
+                self.push(len);
+                let ptr = self.konst(ptr);
+                self.push(ptr)
+            }
+            Op::PushMem(name) => {
+                let mem = self.konst(self.addresses.mems[name]);
+                self.push(mem)
+            }
+            Op::PushTable(name) => {
+                let table = self.konst(self.addresses.tables[name]);
+                self.push(table)
+            }
+            Op::Drop => {
+                self.pop();
+            }
+            Op::Dup => {
+                let v = self.peek(0);
+                self.push(v)
+            }
+            Op::Swap => {
+                let a = self.pop();
+                let b = self.pop();
+                self.push(a);
+                self.push(b)
+            }
+            Op::Over => {
+                let v = self.peek(1);
+                self.push(v)
+            }
+            Op::Pick(depth) => {
+                let v = self.peek(*depth);
+                self.push(v)
+            }
+            Op::Roll(depth) => {
+                let rolled = self.peek(*depth);
+                for i in (0..*depth).rev() {
+                    let v = self.peek(i);
+                    self.poke(i + 1, v);
+                }
+                self.poke(0, rolled)
+            }
+
+            Op::Bind => {
+                let v = self.pop();
+                let rsp = self.bump(self.addresses.ret_sp, -8);
+                self.b.ins().store(MemFlags::trusted(), v, rsp, 0);
+            }
+            Op::UseBinding(offset) => {
+                let rsp = self.load_at(self.addresses.ret_sp);
+                let v = self
+                    .b
+                    .ins()
+                    .load(I64, MemFlags::trusted(), rsp, 8 * *offset as i32);
+                self.push(v)
+            }
+            Op::Unbind => {
+                self.bump(self.addresses.ret_sp, 8);
+            }
+
+            Op::ReadU64 => self.read(|b, a| b.ins().load(I64, MemFlags::new(), a, 0)),
+            Op::ReadU32 => self.read(|b, a| b.ins().uload32(MemFlags::new(), a, 0)),
+            Op::ReadU16 => self.read(|b, a| b.ins().uload16(I64, MemFlags::new(), a, 0)),
+            Op::ReadU8 => self.read(|b, a| b.ins().uload8(I64, MemFlags::new(), a, 0)),
+            Op::WriteU64 => self.write(|b, v, a| {
+                b.ins().store(MemFlags::new(), v, a, 0);
+            }),
+            Op::WriteU32 => self.write(|b, v, a| {
+                b.ins().istore32(MemFlags::new(), v, a, 0);
+            }),
+            Op::WriteU16 => self.write(|b, v, a| {
+                b.ins().istore16(MemFlags::new(), v, a, 0);
+            }),
+            Op::WriteU8 => self.write(|b, v, a| {
+                b.ins().istore8(MemFlags::new(), v, a, 0);
+            }),
+
+            Op::Bswap64 => {
+                let v = self.pop();
+                let v = self.b.ins().bswap(v);
+                self.push(v)
+            }
+            Op::Bswap32 => self.bswap_narrow(I32),
+            Op::Bswap16 => self.bswap_narrow(I16),
+            Op::ZeroExtend(bits) | Op::SignExtend(bits) => {
+                let ty = Type::int(*bits as u16).unwrap();
+                let v = self.pop();
+                let v = self.b.ins().ireduce(ty, v);
+                let v = match op {
+                    Op::ZeroExtend(_) => self.b.ins().uextend(I64, v),
+                    _ => self.b.ins().sextend(I64, v),
+                };
+                self.push(v)
+            }
+
+            Op::ReserveEscaping(n) => {
+                self.bump(self.addresses.escaping_sp, -(*n as i64));
+            }
+            Op::PushEscaping(n) => {
+                let esp = self.load_at(self.addresses.escaping_sp);
+                let v = self.b.ins().iadd_imm(esp, *n as i64);
+                self.push(v)
+            }
+            Op::ReserveLocals(n) => {
+                self.bump(self.addresses.locals_sp, -(*n as i64));
+            }
+            Op::FreeLocals(n) => {
+                self.bump(self.addresses.locals_sp, *n as i64);
+            }
+            Op::PushLvar(offset) => {
+                let lsp = self.load_at(self.addresses.locals_sp);
+                let v = self.b.ins().iadd_imm(lsp, *offset as i64);
+                self.push(v)
+            }
+
+            // like the native backend, dumping is left to the evaluator
+            Op::Dump => (),
+            Op::Print(format) => {
+                let v = self.pop();
+                let width = self.konst(0);
+                self.print(*format, v, width)
+            }
+            Op::PrintPadded(format) => {
+                let width = self.pop();
+                let v = self.pop();
+                self.print(*format, v, width)
+            }
+            Op::HashStr => {
+                let ptr = self.pop();
+                let len = self.pop();
+                let hash = self.call(self.hosts.hash_str, &[len, ptr]).unwrap();
+                self.push(hash)
+            }
+            Op::FindByte => {
+                let byte = self.pop();
+                let len = self.pop();
+                let ptr = self.pop();
+                let idx = self.call(self.hosts.find_byte, &[ptr, len, byte]).unwrap();
+                self.push(idx);
+                let found = self.b.ins().icmp(IntCC::UnsignedLessThan, idx, len);
+                let found = self.b.ins().uextend(I64, found);
+                self.push(found)
+            }
+            Op::Copy16 => self.write(|b, src, dst| {
+                let v = b.ins().load(I8X16, MemFlags::new(), src, 0);
+                b.ins().store(MemFlags::new(), v, dst, 0);
+            }),
+            Op::Cmp16 => self.binary(|b, x, y| {
+                let x = b.ins().load(I8X16, MemFlags::new(), x, 0);
+                let y = b.ins().load(I8X16, MemFlags::new(), y, 0);
+                let eq = b.ins().icmp(IntCC::Equal, x, y);
+                b.ins().vhigh_bits(I64, eq)
+            }),
+            Op::Splat16 => self.write(|b, byte, dst| {
+                let byte = b.ins().ireduce(I8, byte);
+                let v = b.ins().splat(I8X16, byte);
+                b.ins().store(MemFlags::new(), v, dst, 0);
+            }),
+
+            Op::Syscall0 => self.syscall(0),
+            Op::Syscall1 => self.syscall(1),
+            Op::Syscall2 => self.syscall(2),
+            Op::Syscall3 => self.syscall(3),
+            Op::Syscall4 => self.syscall(4),
+            Op::Syscall5 => self.syscall(5),
+            Op::Syscall6 => self.syscall(6),
+            Op::SplitErrno => {
+                let v = self.pop();
+                let failed = self
+                    .b
+                    .ins()
+                    .icmp_imm(IntCC::UnsignedGreaterThanOrEqual, v, -4095);
+                let errno = self.b.ins().ineg(v);
+                let v = self.b.ins().select(failed, errno, v);
+                self.push(v);
+                let failed = self.b.ins().uextend(I64, failed);
+                self.push(failed)
+            }
+
+            Op::Argc => {
+                let v = self.load_at(self.addresses.argc);
+                self.push(v)
+            }
+            Op::Argv => {
+                let v = self.load_at(self.addresses.argv);
+                self.push(v)
+            }
+
+            Op::Add => self.binary(|b, x, y| b.ins().iadd(x, y)),
+            Op::Sub => self.binary(|b, x, y| b.ins().isub(x, y)),
+            Op::Mul => self.binary(|b, x, y| b.ins().imul(x, y)),
+            Op::BitAnd => self.binary(|b, x, y| b.ins().band(x, y)),
+            Op::BitOr => self.binary(|b, x, y| b.ins().bor(x, y)),
+            Op::BitXor => self.binary(|b, x, y| b.ins().bxor(x, y)),
+            Op::BitNot => {
+                let x = self.pop();
+                let v = self.b.ins().bnot(x);
+                self.push(v)
+            }
+            // shift counts are taken modulo the width, like the other backends
+            Op::Shl => self.binary(|b, x, y| b.ins().ishl(x, y)),
+            Op::Shr => self.binary(|b, x, y| b.ins().ushr(x, y)),
+            Op::Sar => self.binary(|b, x, y| b.ins().sshr(x, y)),
+            Op::AddImm(k) => {
+                let x = self.pop();
+                let v = self.b.ins().iadd_imm(x, *k as i64);
+                self.push(v)
+            }
+            Op::SubImm(k) => {
+                let x = self.pop();
+                let v = self.b.ins().iadd_imm(x, (*k as i64).wrapping_neg());
+                self.push(v)
+            }
+            Op::Divmod => {
+                let divisor = self.pop();
+                let dividend = self.pop();
+                let quotient = self.b.ins().udiv(dividend, divisor);
+                let remainder = self.b.ins().urem(dividend, divisor);
+                self.push(quotient);
+                self.push(remainder)
+            }
+            Op::IDivmod => {
+                let divisor = self.pop();
+                let dividend = self.pop();
+                let quotient = self.b.ins().sdiv(dividend, divisor);
+                let remainder = self.b.ins().srem(dividend, divisor);
+                self.push(quotient);
+                self.push(remainder)
+            }
+
+            Op::Eq => self.compare(IntCC::Equal),
+            Op::Ne => self.compare(IntCC::NotEqual),
+            Op::Lt => self.compare(IntCC::SignedLessThan),
+            Op::Le => self.compare(IntCC::SignedLessThanOrEqual),
+            Op::Gt => self.compare(IntCC::SignedGreaterThan),
+            Op::Ge => self.compare(IntCC::SignedGreaterThanOrEqual),
+            Op::LtU => self.compare(IntCC::UnsignedLessThan),
+            Op::LeU => self.compare(IntCC::UnsignedLessThanOrEqual),
+            Op::GtU => self.compare(IntCC::UnsignedGreaterThan),
+            Op::GeU => self.compare(IntCC::UnsignedGreaterThanOrEqual),
+
+            Op::Proc(_) => unreachable!(),
+            Op::Label(l) => {
+                let block = self.label(l)?;
+                if !self.terminated {
+                    self.b.ins().jump(block, &[]);
+                }
+                self.b.switch_to_block(block);
+                self.terminated = false;
+            }
+            Op::Loc(_) => {}
+            Op::Jump(l) => {
+                let block = self.label(l)?;
+                self.b.ins().jump(block, &[]);
+                self.terminated = true;
+            }
+            Op::JumpF(l) | Op::JumpT(l) => {
+                let target = self.label(l)?;
+                let next = self.b.create_block();
+                let cond = self.pop();
+                if let Op::JumpT(_) = op {
+                    self.b.ins().brif(cond, target, &[], next, &[]);
+                } else {
+                    self.b.ins().brif(cond, next, &[], target, &[]);
+                }
+                self.b.switch_to_block(next);
+            }
+            Op::CmpImmBranch(cmp, k, l) => {
+                let target = self.label(l)?;
+                let next = self.b.create_block();
+                let x = self.pop();
+                let cc = match cmp {
+                    Cmp::Eq => IntCC::Equal,
+                    Cmp::Ne => IntCC::NotEqual,
+                    Cmp::Lt => IntCC::SignedLessThan,
+                    Cmp::Le => IntCC::SignedLessThanOrEqual,
+                    Cmp::Gt => IntCC::SignedGreaterThan,
+                    Cmp::Ge => IntCC::SignedGreaterThanOrEqual,
+                    Cmp::LtU => IntCC::UnsignedLessThan,
+                    Cmp::LeU => IntCC::UnsignedLessThanOrEqual,
+                    Cmp::GtU => IntCC::UnsignedGreaterThan,
+                    Cmp::GeU => IntCC::UnsignedGreaterThanOrEqual,
+                };
+                let cond = self.b.ins().icmp_imm(cc, x, *k as i64);
+                self.b.ins().brif(cond, target, &[], next, &[]);
+                self.b.switch_to_block(next);
+            }
+            Op::Call(p) => {
+                let id = *self
+                    .ids
+                    .get(p)
+                    .ok_or_else(|| backend_error(format!("Call to unknown proc `{}`", p)))?;
+                let sp = self.b.use_var(self.sp);
+                let sp = self.call(id, &[sp]).unwrap();
+                self.b.def_var(self.sp, sp);
+            }
+            Op::PushProc(p) => {
+                let id = *self
+                    .ids
+                    .get(p)
+                    .ok_or_else(|| backend_error(format!("Address of unknown proc `{}`", p)))?;
+                let callee = self.func_ref(id);
+                let addr = self.b.ins().func_addr(I64, callee);
+                self.push(addr)
+            }
+            Op::CallIndirect(..) => {
+                let addr = self.pop();
+                let signature = self.b.import_signature(word_signature(self.module, 1, 1));
+                let sp = self.b.use_var(self.sp);
+                let call = self.b.ins().call_indirect(signature, addr, &[sp]);
+                let sp = self.b.inst_results(call)[0];
+                self.b.def_var(self.sp, sp);
+            }
+            Op::CallHost(name, _, _) => {
+                return backend_error(format!(
+                    "Host proc `{}` can only be called from the evaluator",
+                    name
+                ))
+                .error()
+            }
+            Op::Return => {
+                let sp = self.b.use_var(self.sp);
+                self.b.ins().return_(&[sp]);
+                self.terminated = true;
+            }
+            Op::Exit if self.entry => {
+                let code = self.pop();
+                self.b.ins().return_(&[code]);
+                self.terminated = true;
+            }
+            Op::Exit => return backend_error("`Exit` outside of the entry point").error(),
+        }
+        ().okay()
+    }
+
+    fn label(&self, label: &str) -> Result<Block> {
+        self.labels
+            .get(label)
+            .copied()
+            .ok_or_else(|| backend_error(format!("Jump to `{}` outside of its proc", label)))
+    }
+
+    fn konst(&mut self, c: u64) -> Value {
+        self.b.ins().iconst(I64, c as i64)
+    }
+
+    fn push(&mut self, v: Value) {
+        let sp = self.b.use_var(self.sp);
+        let sp = self.b.ins().iadd_imm(sp, -8);
+        self.b.ins().store(MemFlags::trusted(), v, sp, 0);
+        self.b.def_var(self.sp, sp);
+    }
+
+    fn pop(&mut self) -> Value {
+        let sp = self.b.use_var(self.sp);
+        let v = self.b.ins().load(I64, MemFlags::trusted(), sp, 0);
+        let sp = self.b.ins().iadd_imm(sp, 8);
+        self.b.def_var(self.sp, sp);
+        v
+    }
+
+    /// The value `depth` words below the top of the stack
+    fn peek(&mut self, depth: usize) -> Value {
+        let sp = self.b.use_var(self.sp);
+        self.b
+            .ins()
+            .load(I64, MemFlags::trusted(), sp, 8 * depth as i32)
+    }
+
+    fn poke(&mut self, depth: usize, v: Value) {
+        let sp = self.b.use_var(self.sp);
+        self.b
+            .ins()
+            .store(MemFlags::trusted(), v, sp, 8 * depth as i32);
+    }
+
+    fn load_at(&mut self, address: u64) -> Value {
+        let address = self.konst(address);
+        self.b.ins().load(I64, MemFlags::trusted(), address, 0)
+    }
+
+    /// Adds `by` to the pointer stored at `address`, returning the new pointer
+    fn bump(&mut self, address: u64, by: i64) -> Value {
+        let address = self.konst(address);
+        let v = self.b.ins().load(I64, MemFlags::trusted(), address, 0);
+        let v = self.b.ins().iadd_imm(v, by);
+        self.b.ins().store(MemFlags::trusted(), v, address, 0);
+        v
+    }
+
+    fn read(&mut self, load: impl FnOnce(&mut FunctionBuilder, Value) -> Value) {
+        let address = self.pop();
+        let v = load(&mut self.b, address);
+        self.push(v)
+    }
+
+    fn write(&mut self, store: impl FnOnce(&mut FunctionBuilder, Value, Value)) {
+        let address = self.pop();
+        let v = self.pop();
+        store(&mut self.b, v, address)
+    }
+
+    fn bswap_narrow(&mut self, ty: Type) {
+        let v = self.pop();
+        let v = self.b.ins().ireduce(ty, v);
+        let v = self.b.ins().bswap(v);
+        let v = self.b.ins().uextend(I64, v);
+        self.push(v)
+    }
+
+    fn binary(&mut self, op: impl FnOnce(&mut FunctionBuilder, Value, Value) -> Value) {
+        let y = self.pop();
+        let x = self.pop();
+        let v = op(&mut self.b, x, y);
+        self.push(v)
+    }
+
+    fn compare(&mut self, cc: IntCC) {
+        let y = self.pop();
+        let x = self.pop();
+        let v = self.b.ins().icmp(cc, x, y);
+        let v = self.b.ins().uextend(I64, v);
+        self.push(v)
+    }
+
+    fn print(&mut self, format: PrintFormat, v: Value, width: Value) {
+        let radix = self.konst(format.radix());
+        let signed = self.konst(format.is_signed() as u64);
+        self.call(self.hosts.print, &[v, radix, signed, width]);
+    }
+
+    fn syscall(&mut self, args: usize) {
+        let mut regs = vec![self.pop()];
+        for _ in 0..args {
+            let v = self.pop();
+            regs.push(v)
+        }
+        while regs.len() < 7 {
+            let zero = self.konst(0);
+            regs.push(zero)
+        }
+        let res = self.call(self.hosts.syscall, &regs).unwrap();
+        self.push(res)
+    }
+
+    /// Calls a function of the module, returning its result if it has one
+    fn func_ref(&mut self, id: FuncId) -> FuncRef {
+        *self
+            .refs
+            .entry(id)
+            .or_insert_with(|| self.module.declare_func_in_func(id, self.b.func))
+    }
+
+    fn call(&mut self, id: FuncId, args: &[Value]) -> Option<Value> {
+        let callee = self.func_ref(id);
+        let call = self.b.ins().call(callee, args);
+        self.b.inst_results(call).first().copied()
+    }
+}
+
+#[test]
+fn test_jit() {
+    use Op::*;
+    let session = Session::new(Default::default(), Default::default());
+    let mems = [("mem_cell".to_string(), 8)].into_iter().collect();
+    // 5 fact 42 cell ! cell @ + 3 2 divmod +
+    let ops = vec![
+        Call("main".to_string()),
+        Exit,
+        Proc("fact".to_string()),
+        Push(IConst::U64(1)),
+        Swap,
+        Label("loop".to_string()),
+        Dup,
+        Push(IConst::U64(0)),
+        Gt,
+        JumpF("done".to_string()),
+        Swap,
+        Over,
+        Mul,
+        Swap,
+        Push(IConst::U64(1)),
+        Sub,
+        Jump("loop".to_string()),
+        Label("done".to_string()),
+        Drop,
+        Return,
+        Proc("main".to_string()),
+        Push(IConst::U64(5)),
+        Call("fact".to_string()),
+        Push(IConst::U64(42)),
+        PushMem("mem_cell".to_string()),
+        WriteU64,
+        PushMem("mem_cell".to_string()),
+        ReadU64,
+        Bind,
+        UseBinding(0),
+        Unbind,
+        Add,
+        Push(IConst::U64(7)),
+        Push(IConst::U64(2)),
+        Divmod,
+        Add,
+        Add,
+        Return,
+    ];
+    let lowered = lir::Program {
+        ops,
+        mems,
+        ..Default::default()
+    };
+    let mut program = Cranelift.compile(lowered, &session).unwrap();
+    assert_eq!(program.run(&[]), 120 + 42 + 3 + 1);
+    assert_eq!(program.run(&[]), 166);
+}
@@ -0,0 +1,249 @@
+use clap::{Args, Parser, Subcommand};
+use rotth::{
+    audit::{self, Engine},
+    backend::{Aarch64, Backend, Nasm},
+    cli::{CompileArgs, ReportArgs, RunArgs},
+    diagnostics::Renderer,
+    driver::{self, BuildOptions},
+    emit::symbols,
+    eval::{EvalError, HostSyscalls, Interpreter},
+    lir,
+    session::{Comments, Target},
+    span::SourceMap,
+    typecheck::TypecheckWarning,
+    Error, Result,
+};
+use somok::{Either, Somok};
+use std::{
+    io::{self, BufWriter},
+    path::PathBuf,
+    process::ExitCode,
+};
+
+/// Compiler driver for rotth programs
+#[derive(Parser)]
+#[clap(name = "rotthc")]
+struct Cli {
+    #[clap(flatten)]
+    report: ReportArgs,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Compile to an executable
+    Build {
+        #[clap(flatten)]
+        common: Common,
+        /// Where the executable goes, next to the source without its extension by default
+        #[clap(short = 'o', long)]
+        output: Option<PathBuf>,
+        #[clap(long)]
+        strip: bool,
+        /// Object to link in, defining `extern mem`s
+        #[clap(long, value_name = "OBJECT")]
+        link: Vec<PathBuf>,
+    },
+    /// Interpret the program, passing it the arguments after `--`
+    Run {
+        #[clap(flatten)]
+        common: Common,
+        #[clap(flatten)]
+        run: RunArgs,
+    },
+    /// Typecheck without compiling
+    Check {
+        #[clap(flatten)]
+        common: Common,
+    },
+    /// Print the procs and items after name resolution
+    DumpHir {
+        #[clap(flatten)]
+        common: Common,
+    },
+    /// Print the lowered ops, with the stack effect of each source node, after the mems, strings
+    /// and tables they use. Commands taking a source also take this output in a `.lir` file.
+    DumpLir {
+        #[clap(flatten)]
+        common: Common,
+        /// Only print the ops of this proc
+        #[clap(long)]
+        only_proc: Option<String>,
+    },
+    /// Print the assembly for the target
+    DumpAsm {
+        #[clap(flatten)]
+        common: Common,
+    },
+    /// Print the bytes each string, table and mem takes in the data, rodata and bss sections
+    DataSize {
+        #[clap(flatten)]
+        common: Common,
+    },
+    /// List what the program uses that the interpreter or each backend lacks
+    Audit {
+        #[clap(flatten)]
+        common: Common,
+        /// Only audit this one, failing if it lacks anything
+        #[clap(long, value_parser = ["interpreter", "nasm", "elf", "aarch64", "c", "wasm", "cranelift"])]
+        backend: Option<String>,
+    },
+}
+
+#[derive(Args)]
+struct Common {
+    source: PathBuf,
+    #[clap(flatten)]
+    compile: CompileArgs,
+}
+
+impl Common {
+    fn options(&self) -> Result<BuildOptions> {
+        BuildOptions {
+            options: self.compile.options()?,
+            ..Default::default()
+        }
+        .okay()
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let mut renderer = cli.report.renderer();
+    match rotthc(cli.command, &mut renderer) {
+        Ok(code) => code,
+        Err(e) => {
+            report(&e, &mut renderer);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn report(e: &Error, renderer: &mut Renderer) {
+    for diagnostic in e.diagnostics() {
+        renderer.emit(&diagnostic);
+    }
+}
+
+fn warn(warnings: &[TypecheckWarning], renderer: &mut Renderer) {
+    for w in warnings {
+        renderer.emit(&w.diagnostic());
+    }
+}
+
+fn rotthc(command: Command, renderer: &mut Renderer) -> Result<ExitCode> {
+    match command {
+        Command::Build {
+            common,
+            output,
+            strip,
+            link,
+        } => {
+            let mut opts = common.options()?;
+            opts.options.strip = strip;
+            opts.output = output;
+            opts.objects = link;
+            let exe = driver::build(&common.source, &opts)?;
+            eprintln!("Built {}", exe.display());
+        }
+        Command::Run { common, run } => {
+            let mut opts = common.options()?;
+            if run.check_uninit {
+                // the trap points at the read through the spans
+                opts.options.comments = opts.options.comments.max(Comments::Spans);
+            }
+            let lowered = driver::lower(&common.source, &opts)?;
+            warn(&lowered.warnings, renderer);
+            let program = common.source.display().to_string();
+            let mut interpreter = Interpreter::new();
+            interpreter
+                .mems(&lowered.program.mems)
+                .tables(&lowered.program.tables)
+                .args(std::iter::once(program).chain(run.args))
+                .syscalls(HostSyscalls::new());
+            if run.check_uninit {
+                interpreter.check_uninit(&lowered.program.mem_spans);
+            }
+            let run = interpreter.run(&lowered.program.ops, &lowered.session.strings());
+            return match run {
+                // like the kernel, only the low byte of the status gets to the parent
+                Ok(Either::Left(code)) => ExitCode::from(code as u8).okay(),
+                Ok(Either::Right(stack)) => {
+                    eprintln!("Program ended without exiting, stack: {:?}", stack);
+                    ExitCode::FAILURE.okay()
+                }
+                Err(e @ EvalError::Trap(_)) => {
+                    report(&Error::Eval(e), renderer);
+                    ExitCode::FAILURE.okay()
+                }
+                Err(e) => Error::Backend(e.to_string()).error(),
+            };
+        }
+        Command::Check { common } => {
+            let (_, _, warnings) = driver::check(&common.source, &common.options()?)?;
+            warn(&warnings, renderer);
+        }
+        Command::DumpHir { common } => {
+            let (_, hir) = driver::hir(&common.source, &common.options()?)?;
+            let mut items = hir.into_iter().collect::<Vec<_>>();
+            items.sort_by(|(a, _), (b, _)| a.cmp(b));
+            for (name, item) in items {
+                match item.as_proc() {
+                    Some(proc) => println!("proc {} {}", name, proc.signature()),
+                    None => println!("{}", name),
+                }
+                println!("{:#?}", item);
+            }
+        }
+        Command::DumpLir { common, only_proc } => {
+            let mut opts = common.options()?;
+            // the dump finds the nodes of ops by their spans
+            opts.options.comments = opts.options.comments.max(Comments::Spans);
+            let lowered = driver::lower(&common.source, &opts)?;
+            warn(&lowered.warnings, renderer);
+            let snapshots = &lowered.session.snapshots;
+            if only_proc.is_none() {
+                print!("{}", lir::dump_data(&lowered.program, &lowered.session));
+            }
+            print!(
+                "{}",
+                lir::dump(&lowered.program.ops, snapshots, only_proc.as_deref())
+            );
+        }
+        Command::DumpAsm { common } => {
+            let lowered = driver::lower(&common.source, &common.options()?)?;
+            warn(&lowered.warnings, renderer);
+            let sink = BufWriter::new(io::stdout().lock());
+            let (program, session) = (lowered.program, lowered.session);
+            match session.options.target {
+                Target::X86_64Linux => Nasm::new(sink).compile(program, &session)?,
+                Target::Aarch64Linux => Aarch64::new(sink).compile(program, &session)?,
+            }
+        }
+        Command::DataSize { common } => {
+            let lowered = driver::lower(&common.source, &common.options()?)?;
+            let layout = lowered.data_layout();
+            warn(&lowered.warnings, renderer);
+            print!("{}", symbols::data_report(&layout));
+        }
+        Command::Audit { common, backend } => {
+            let mut opts = common.options()?;
+            // uses are located by the spans before them
+            opts.options.comments = opts.options.comments.max(Comments::Spans);
+            let lowered = driver::lower(&common.source, &opts)?;
+            let uses = audit::features(&lowered.program, &lowered.session);
+            let engines = match &backend {
+                Some(name) => vec![Engine::from_name(name).unwrap()],
+                None => Engine::all(),
+            };
+            let mut sources = SourceMap::new();
+            sources.load_spans(uses.iter().filter_map(|u| u.span.as_ref()))?;
+            print!("{}", audit::report(&uses, &engines, &sources));
+            if backend.is_some() && !audit::gaps(&uses, engines[0]).is_empty() {
+                return ExitCode::FAILURE.okay();
+            }
+        }
+    }
+    ExitCode::SUCCESS.okay()
+}
@@ -0,0 +1,617 @@
+use crate::{
+    ast::{self, parse_with_include_paths},
+    backend::{Aarch64, Backend, Elf, Nasm},
+    emit::symbols::Manifest,
+    hir::{self, Walker},
+    include_graph::IncludeGraph,
+    lexer::lex,
+    lir::{self, demangle},
+    session::{Options, Session, Target},
+    typecheck::{TypecheckWarning, Typechecker},
+    types::define_structs,
+    Error, Result,
+};
+use fnv::FnvHashMap;
+use somok::Somok;
+use std::{
+    fs::File,
+    io::{BufWriter, ErrorKind},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Runtime linked into every executable, see `print.asm`
+const RUNTIME: &str = include_str!("../print.asm");
+
+/// Assembler and linker for one target
+#[derive(Debug, Clone)]
+pub struct Toolchain {
+    pub assembler: PathBuf,
+    pub linker: PathBuf,
+    /// Passed to the linker as `--sysroot`
+    pub sysroot: Option<PathBuf>,
+}
+
+impl Default for Toolchain {
+    /// `nasm` and `ld` from `PATH`
+    fn default() -> Self {
+        Self {
+            assembler: "nasm".into(),
+            linker: "ld".into(),
+            sysroot: None,
+        }
+    }
+}
+
+impl Toolchain {
+    /// GNU binutils for AArch64, the cross tools unless the host is AArch64 itself
+    pub fn aarch64() -> Self {
+        let prefix = if cfg!(target_arch = "aarch64") {
+            ""
+        } else {
+            "aarch64-linux-gnu-"
+        };
+        Self {
+            assembler: format!("{}as", prefix).into(),
+            linker: format!("{}ld", prefix).into(),
+            sysroot: None,
+        }
+    }
+}
+
+/// Builds executables, using the toolchains configured per target triple and the host tools otherwise
+#[derive(Debug, Clone, Default)]
+pub struct Builder {
+    toolchains: FnvHashMap<String, Toolchain>,
+    symbols: Option<PathBuf>,
+    objects: Vec<PathBuf>,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Uses `toolchain` when building for `triple`, e.g. `aarch64-linux-gnu`
+    pub fn toolchain(mut self, triple: impl Into<String>, toolchain: Toolchain) -> Self {
+        self.toolchains.insert(triple.into(), toolchain);
+        self
+    }
+
+    /// Links the object at `path` into the executable, to define the program's `extern mem`s
+    pub fn object(mut self, path: impl Into<PathBuf>) -> Self {
+        self.objects.push(path.into());
+        self
+    }
+
+    /// Writes the symbols manifest of the program to `path`, see `emit::symbols`
+    pub fn symbols(mut self, path: impl Into<PathBuf>) -> Self {
+        self.symbols = Some(path.into());
+        self
+    }
+
+    /// Compiles, assembles and links `source` into an executable for `triple` at `out_path`.
+    /// Intermediate files live in a temporary directory that is removed afterwards.
+    /// x86-64 executables bundling the whole runtime are written directly, without any tools,
+    /// unless a toolchain is configured for the target, objects are linked in or
+    /// `Options::placements` asks the linker to place symbols. The target of `options` is
+    /// replaced by the one `triple` names.
+    pub fn build(
+        &self,
+        source: &Path,
+        triple: &str,
+        out_path: &Path,
+        mut options: Options,
+    ) -> Result<Vec<TypecheckWarning>> {
+        let target = Target::from_triple(triple)
+            .ok_or_else(|| Error::UnsupportedTarget(triple.to_string()))?;
+        options.target = target;
+        let bundled = options.runtime.print && (options.runtime.traps || !options.checked);
+        let direct = bundled
+            && options.placements.is_empty()
+            && self.objects.is_empty()
+            && !self.toolchains.contains_key(triple);
+        if target == Target::X86_64Linux && direct {
+            let mut session = Session::new(Default::default(), options);
+            let (program, warnings) = compile_lir(source, &mut session)?;
+            self.write_symbols(&program, &session)?;
+            let sink = BufWriter::new(File::create(out_path)?);
+            Elf::new(sink).compile(program, &session)?;
+            make_executable(out_path)?;
+            return warnings.okay();
+        }
+        let emulation = match target {
+            Target::X86_64Linux => "elf_x86_64",
+            Target::Aarch64Linux => "aarch64linux",
+        };
+        let (toolchain, hint) = match (self.toolchains.get(triple), target) {
+            (Some(toolchain), _) => (
+                toolchain.clone(),
+                "check the toolchain configured for this target",
+            ),
+            (None, Target::X86_64Linux) => (
+                Toolchain::default(),
+                "install nasm and binutils with your package manager",
+            ),
+            (None, Target::Aarch64Linux) => (
+                Toolchain::aarch64(),
+                "install binutils for aarch64-linux-gnu with your package manager",
+            ),
+        };
+        find_tool(&toolchain.assembler, hint)?;
+        find_tool(&toolchain.linker, hint)?;
+
+        let tmp = TempDir::new()?;
+        let program = tmp.path.join("program.asm");
+        let runtime = tmp.path.join("runtime.asm");
+
+        let strip = options.strip;
+        let bundle_print = options.runtime.print;
+        let mut session = Session::new(Default::default(), options);
+        let (lowered, warnings) = compile_lir(source, &mut session)?;
+        let manifest = self.write_symbols(&lowered, &session)?;
+        compile_asm(lowered, &session, &program)?;
+        let placements = !session.options.placements.is_empty();
+
+        let mut objects = Vec::new();
+        let sources = match target {
+            Target::X86_64Linux if bundle_print => {
+                std::fs::write(&runtime, RUNTIME)?;
+                vec![program, runtime]
+            }
+            // the AArch64 runtime is emitted along with the program
+            _ => vec![program],
+        };
+        for asm in sources {
+            let object = asm.with_extension("o");
+            match target {
+                Target::X86_64Linux => assemble(&toolchain.assembler, "elf64", &asm, &object)?,
+                Target::Aarch64Linux => run(Command::new(&toolchain.assembler)
+                    .arg("-o")
+                    .arg(&object)
+                    .arg(&asm))?,
+            }
+            objects.push(object);
+        }
+        objects.extend(self.objects.iter().cloned());
+
+        // an explicit emulation makes a linker for another architecture fail loudly
+        let mut link = Command::new(&toolchain.linker);
+        link.args(["-m", emulation]);
+        if let Some(sysroot) = &toolchain.sysroot {
+            link.arg(format!("--sysroot={}", sysroot.display()));
+        }
+        if strip {
+            link.arg("-s");
+        }
+        if placements {
+            let script = tmp.path.join("layout.ld");
+            std::fs::write(&script, manifest.linker_script())?;
+            link.arg("-T").arg(script);
+        }
+        run(link.arg("-o").arg(out_path).args(&objects))?;
+
+        warnings.okay()
+    }
+
+    fn write_symbols(&self, program: &lir::Program, session: &Session) -> Result<Manifest> {
+        let manifest = Manifest::new(
+            &program.mems,
+            &session.strings(),
+            &program.tables,
+            &session.options.placements,
+        );
+        if let Some(path) = &self.symbols {
+            std::fs::write(path, manifest.to_string())?;
+        }
+        manifest.okay()
+    }
+}
+
+/// Builds `source` for `target` with the host tools
+pub fn build_executable(
+    source: &Path,
+    target: Target,
+    out_path: &Path,
+    options: Options,
+) -> Result<Vec<TypecheckWarning>> {
+    Builder::new().build(source, target.triple(), out_path, options)
+}
+
+/// The files `source` depends on, for watch mode and build caches to decide what is stale
+pub fn include_graph(source: &Path) -> Result<IncludeGraph> {
+    IncludeGraph::new(source)
+}
+
+/// Assembles `asm` into a throwaway object to surface backend bugs early.
+/// Does nothing if the assembler for `target` is not installed.
+pub fn check_asm(asm: &Path, target: Target) -> Result<()> {
+    let assembler = match target {
+        Target::X86_64Linux => Toolchain::default().assembler,
+        Target::Aarch64Linux => Toolchain::aarch64().assembler,
+    };
+    if find_tool(&assembler, "").is_err() {
+        return ().okay();
+    }
+    let tmp = TempDir::new()?;
+    let object = tmp.path.join("check.o");
+    match target {
+        Target::X86_64Linux => assemble(&assembler, "elf64", asm, &object),
+        Target::Aarch64Linux => run(Command::new(&assembler).arg("-o").arg(&object).arg(asm)),
+    }
+}
+
+/// An assembler error, traced back through the emitted comments to the op that produced it
+#[derive(Debug)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+    /// The op in the closest `; Op` comment above the line
+    pub op: Option<String>,
+    /// Demangled name of the proc containing the line
+    pub proc: Option<String>,
+}
+
+impl std::fmt::Display for AsmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)?;
+        if let Some(op) = &self.op {
+            write!(f, "\n  while emitting {}", op)?;
+        }
+        if let Some(proc) = &self.proc {
+            write!(f, "\n  in proc `{}`", proc)?;
+        }
+        Ok(())
+    }
+}
+
+fn assemble(assembler: &Path, format: &str, asm: &Path, object: &Path) -> Result<()> {
+    let mut command = Command::new(assembler);
+    command.args(["-f", format, "-o"]).arg(object).arg(asm);
+    let output = command.output()?;
+    if output.status.success() {
+        return ().okay();
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let source = std::fs::read_to_string(asm)?;
+    let lines = source.lines().collect::<Vec<_>>();
+    let prefix = format!("{}:", asm.display());
+    let errors = stderr
+        .lines()
+        .filter_map(|l| {
+            let (line, message) = l.strip_prefix(&prefix)?.split_once(':')?;
+            let line = line.parse::<usize>().ok()?;
+            let message = message.trim();
+            if message.starts_with("warning") {
+                return None;
+            }
+            trace_asm_line(&lines, line, message)
+        })
+        .collect::<Vec<_>>();
+    if errors.is_empty() {
+        Error::ToolFailed {
+            command: format!("{:?}", command),
+            stderr: stderr.into_owned(),
+        }
+        .error()
+    } else {
+        Error::Assembler(errors).error()
+    }
+}
+
+/// Op comments are the `Debug` output of an op, so unlike the remarks
+/// written by hand in the templates they start with an uppercase letter
+fn trace_asm_line(lines: &[&str], line: usize, message: &str) -> Option<AsmError> {
+    let above = lines.get(..line.checked_sub(1)?)?;
+    let op = above
+        .iter()
+        .rev()
+        .filter_map(|l| l.strip_prefix("; "))
+        .find(|c| c.starts_with(|c: char| c.is_ascii_uppercase()))
+        .map(str::to_string);
+    let proc = above
+        .iter()
+        .rev()
+        .filter_map(|l| l.strip_suffix(':'))
+        .find(|l| !l.starts_with(|c: char| c.is_whitespace() || c == '.'))
+        .map(|l| demangle(l).to_string());
+    AsmError {
+        line,
+        message: message.to_string(),
+        op,
+        proc,
+    }
+    .some()
+}
+
+fn compile_asm(program: lir::Program, session: &Session, out: &Path) -> Result<()> {
+    let sink = BufWriter::new(File::create(out)?);
+    match session.options.target {
+        Target::X86_64Linux => Nasm::new(sink).compile(program, session),
+        Target::Aarch64Linux => Aarch64::new(sink).compile(program, session),
+    }
+}
+
+type Lowered = (lir::Program, Vec<TypecheckWarning>);
+
+/// Lexes, parses and walks `source`, defining its structs in `session`
+pub(crate) fn compile_hir(
+    source: &Path,
+    session: &mut Session,
+) -> Result<FnvHashMap<String, hir::TopLevel>> {
+    let tokens = lex(source.canonicalize()?)?;
+    let (structs, ast) = parse_with_include_paths(tokens, &session.options.include_paths)?
+        .into_iter()
+        .partition::<FnvHashMap<_, _>, _>(|(_, i)| matches!(i, ast::TopLevel::Struct(_)));
+    session.structs = define_structs(structs);
+    Walker::new(session).walk_ast(ast)
+}
+
+fn compile_lir(source: &Path, session: &mut Session) -> Result<Lowered> {
+    let hir = compile_hir(source, session)?;
+    let session = &*session;
+    let (procs, warnings) = Typechecker::typecheck_program(hir, session)?;
+    let program = lir::Compiler::new(session).compile(procs)?;
+    (program, warnings).okay()
+}
+
+#[cfg(unix)]
+fn make_executable(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?.okay()
+}
+
+#[cfg(not(unix))]
+fn make_executable(_: &Path) -> Result<()> {
+    ().okay()
+}
+
+fn find_tool(tool: &Path, hint: &'static str) -> Result<()> {
+    match Command::new(tool).arg("--version").output() {
+        Ok(_) => ().okay(),
+        Err(e) if e.kind() == ErrorKind::NotFound => Error::MissingTool {
+            name: tool.display().to_string(),
+            hint,
+        }
+        .error(),
+        Err(e) => Error::IO(e).error(),
+    }
+}
+
+fn run(command: &mut Command) -> Result<()> {
+    let output = command.output()?;
+    if output.status.success() {
+        ().okay()
+    } else {
+        Error::ToolFailed {
+            command: format!("{:?}", command),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        }
+        .error()
+    }
+}
+
+/// A directory removed along with its contents when dropped
+pub struct TempDir {
+    pub path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> std::io::Result<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "rotth-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&path)?;
+        Self { path }.okay()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+#[test]
+fn test_trace_asm_line() {
+    let asm = indoc::indoc! {"
+        proc0_foo:
+        ; save return address
+            pop rdi
+        ; Push(U64(1))
+            mov rax, 1
+        ; push the value
+            push rbx
+    "};
+    let lines = asm.lines().collect::<Vec<_>>();
+    let e = trace_asm_line(&lines, 7, "error: invalid operand").unwrap();
+    assert_eq!(e.op.as_deref(), Some("Push(U64(1))"));
+    assert_eq!(e.proc.as_deref(), Some("foo"));
+}
+
+#[test]
+fn test_build_executable() {
+    use std::assert_matches::assert_matches;
+    let tmp = TempDir::new().unwrap();
+    let source = tmp.path.join("exit.rh");
+    std::fs::write(&source, "proc main : u64 do 41 print 3 end").unwrap();
+    let exe = tmp.path.join("exit");
+    build_executable(&source, Target::default(), &exe, Options::default()).unwrap();
+    let output = Command::new(&exe).output().unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(output.stdout, b"41\n");
+
+    // nothing is written for a program that doesn't typecheck
+    std::fs::write(&source, "proc main : u64 do end").unwrap();
+    let exe = tmp.path.join("bad");
+    let res = build_executable(&source, Target::default(), &exe, Options::default());
+    assert_matches!(res, Err(Error::Typecheck(_)));
+    assert!(!exe.exists());
+}
+
+#[test]
+fn test_toolchains() {
+    use std::assert_matches::assert_matches;
+    let tmp = TempDir::new().unwrap();
+    let source = tmp.path.join("main.rh");
+    std::fs::write(&source, "proc main : u64 do 0 end").unwrap();
+    let exe = tmp.path.join("main");
+    let missing = Toolchain {
+        assembler: tmp.path.join("missing-as"),
+        linker: "ld".into(),
+        sysroot: None,
+    };
+    let builder = Builder::new().toolchain("x86_64-unknown-linux-gnu", missing);
+
+    let res = builder.build(
+        &source,
+        "x86_64-unknown-linux-gnu",
+        &exe,
+        Options::default(),
+    );
+    assert_matches!(
+        res,
+        Err(Error::MissingTool { name, hint })
+            if name.ends_with("missing-as") && hint.contains("configured")
+    );
+    // other triples of the same target don't pick it up
+    builder
+        .build(&source, "x86_64-linux", &exe, Options::default())
+        .unwrap();
+    assert!(exe.exists());
+    let res = builder.build(&source, "riscv64-linux-gnu", &exe, Options::default());
+    assert_matches!(res, Err(Error::UnsupportedTarget(t)) if t == "riscv64-linux-gnu");
+}
+
+#[test]
+fn test_no_runtime() {
+    use crate::{session::Runtime, typecheck::ErrorKind};
+    use std::assert_matches::assert_matches;
+    let tmp = TempDir::new().unwrap();
+    let source = tmp.path.join("main.rh");
+    std::fs::write(&source, "proc main : u64 do 0 end").unwrap();
+    let options = Options {
+        checked: true,
+        runtime: Runtime {
+            print: false,
+            traps: false,
+        },
+        ..Default::default()
+    };
+    let mut session = Session::new(Default::default(), options.clone());
+    let (program, _) = compile_lir(&source, &mut session).unwrap();
+    let asm = tmp.path.join("main.asm");
+    compile_asm(program, &session, &asm).unwrap();
+    let asm = std::fs::read_to_string(asm).unwrap();
+    let lines = asm.lines().map(str::trim).collect::<Vec<_>>();
+    // the checks still jump to the traps, the user's runtime object defines them
+    assert!(lines.contains(&"extern trap_stack_overflow"), "{}", asm);
+    assert!(lines.contains(&"global trace_stack"), "{}", asm);
+    assert!(!lines.contains(&"trap_stack_overflow:"), "{}", asm);
+    assert!(!lines.contains(&"extern print"), "{}", asm);
+
+    std::fs::write(&source, "proc main : u64 do 1 print 0 end").unwrap();
+    let mut session = Session::new(Default::default(), options);
+    assert_matches!(
+        compile_lir(&source, &mut session),
+        Err(Error::Typecheck(e)) if matches!(e.kind, ErrorKind::NoRuntime("print"))
+    );
+}
+
+#[test]
+fn test_hash_str_runtime() {
+    let tmp = TempDir::new().unwrap();
+    let source = tmp.path.join("hash.rh");
+    // `swap swap` keeps the literal from being folded
+    std::fs::write(
+        &source,
+        "proc main : u64 do \"hello, world\" swap swap hash-str print-hex 0 end",
+    )
+    .unwrap();
+    let exe = tmp.path.join("hash");
+    build_executable(&source, Target::default(), &exe, Options::default()).unwrap();
+    let output = Command::new(&exe).output().unwrap();
+    let folded = crate::intrinsics::hash_str(b"hello, world");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout).trim(),
+        format!("{:x}", folded)
+    );
+}
+
+#[test]
+fn test_find_byte_runtime() {
+    let tmp = TempDir::new().unwrap();
+    let source = tmp.path.join("find.rh");
+    // the `z` is past the first 16 bytes, the `q` past the 3 searched
+    std::fs::write(
+        &source,
+        "proc main : u64 do
+            \"the quick brown fox jumps over the lazy dog\" bind len : u64 str : &>char do
+                str len 'z' find-byte cast u64 print print
+                str len '!' find-byte cast u64 print print
+                str 3 'q' find-byte cast u64 print print
+            end
+            0
+        end",
+    )
+    .unwrap();
+    let exe = tmp.path.join("find");
+    build_executable(&source, Target::default(), &exe, Options::default()).unwrap();
+    let output = Command::new(&exe).output().unwrap();
+    let lines = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    assert_eq!(lines, ["1", "37", "0", "43", "0", "3"]);
+}
+
+#[test]
+fn test_misaligned_trap() {
+    let tmp = TempDir::new().unwrap();
+    let source = tmp.path.join("misaligned.rh");
+    // the alignment of a pointer made from an integer is only known at runtime
+    std::fs::write(
+        &source,
+        "mem buf do 16 end
+        proc main : u64 do buf cast u64 1 + cast &>u64 @u64 end",
+    )
+    .unwrap();
+    let exe = tmp.path.join("misaligned");
+    let options = Options {
+        checked: true,
+        strict_alignment: true,
+        ..Default::default()
+    };
+    build_executable(&source, Target::default(), &exe, options).unwrap();
+    let output = Command::new(&exe).output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("Misaligned memory access"));
+}
+
+#[test]
+fn test_signature_trap() {
+    let tmp = TempDir::new().unwrap();
+    let source = tmp.path.join("signature.rh");
+    // the proc a signature-less pointer points to is only known at runtime
+    std::fs::write(
+        &source,
+        "proc one : u64 do 1 end
+        proc two : u64 u64 do 1 2 end
+        proc main : u64 do &two cast ptr(proc) call-like one end",
+    )
+    .unwrap();
+    let exe = tmp.path.join("signature");
+    let options = Options {
+        checked: true,
+        ..Default::default()
+    };
+    build_executable(&source, Target::default(), &exe, options).unwrap();
+    let output = Command::new(&exe).output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.starts_with("Indirect call doesn't match its `call-like` signature\n  at main"));
+}
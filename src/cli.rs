@@ -0,0 +1,163 @@
+//! Flags the `rotth` and `rotthc` binaries share, so both take them the same way: what a
+//! compilation does differently, how errors and warnings are printed and how an interpreted
+//! program is run.
+
+use crate::{
+    diagnostics::{ColorChoice, ErrorFormat, Renderer},
+    session::{Comments, Options, OptionsBuilder, PathRemap, Runtime, Target, WarningLevel},
+    Error, Result,
+};
+use clap::Args;
+use somok::Somok;
+use std::path::PathBuf;
+
+// Flags making up the `Options` of a compilation, not a doc comment as clap would take it for
+// the about of the binaries flattening it
+#[derive(Debug, Clone, Args)]
+pub struct CompileArgs {
+    /// Target triple, defaults to the host
+    #[clap(long)]
+    pub target: Option<String>,
+    /// 0 lowers procs as they are, 1 goes through the MIR to fold constants and remove dead code,
+    /// 2 also inlines small procs, hoists loop invariants and caches the stack in registers
+    #[clap(short = 'O', long, value_parser = clap::value_parser!(u8).range(0..=2))]
+    pub opt_level: Option<u8>,
+    /// Lower procs through the MIR, which folds constants and removes dead code, like `-O1`
+    #[clap(long)]
+    pub mir: bool,
+    /// Print the passes the optimization level runs
+    #[clap(short = 'v', long)]
+    pub verbose: bool,
+    /// Emit runtime safety checks
+    #[clap(long)]
+    pub checked: bool,
+    #[clap(long)]
+    pub strict_alignment: bool,
+    #[clap(long)]
+    pub verify_lir: bool,
+    /// What the comments in emitted assembly show, `source` prints each source line above its code
+    #[clap(long, value_parser = ["none", "ops", "spans", "source"])]
+    pub comments: Option<String>,
+    /// Leave a runtime piece out, to link your own instead
+    #[clap(long, value_parser = ["all", "print", "traps"])]
+    pub no_runtime: Vec<String>,
+    /// Write source paths starting with FROM as starting with TO, for reproducible builds
+    #[clap(long, value_name = "FROM=TO")]
+    pub remap_path_prefix: Vec<PathRemap>,
+    /// Directory to look for included files in when they are not next to the including file
+    #[clap(short = 'I', long, value_name = "DIR")]
+    pub include_path: Vec<PathBuf>,
+    /// What to do with typecheck warnings, `deny` makes them fail the compilation
+    #[clap(long, value_parser = ["allow", "warn", "deny"])]
+    pub warnings: Option<String>,
+    /// Bytes in each of the return, locals and escaping stacks
+    #[clap(long, value_name = "BYTES")]
+    pub stack_size: Option<u64>,
+}
+
+impl CompileArgs {
+    /// The target of `--target`, the host by default
+    pub fn target(&self) -> Result<Target> {
+        match &self.target {
+            Some(triple) => {
+                Target::from_triple(triple).ok_or_else(|| Error::UnsupportedTarget(triple.clone()))
+            }
+            None => Target::default().okay(),
+        }
+    }
+
+    /// A builder set up with the flags, for options the binaries take differently
+    pub fn builder(&self) -> Result<OptionsBuilder> {
+        let mut builder = Options::builder()
+            .target(self.target()?)
+            .opt_level(self.opt_level.unwrap_or(self.mir as u8))
+            .checked(self.checked)
+            .strict_alignment(self.strict_alignment)
+            .verify_lir(self.verify_lir)
+            .comments(
+                self.comments
+                    .as_deref()
+                    .and_then(Comments::from_name)
+                    .unwrap_or_default(),
+            )
+            .runtime(Runtime {
+                print: !self.no_runtime.iter().any(|p| p == "print" || p == "all"),
+                traps: !self.no_runtime.iter().any(|p| p == "traps" || p == "all"),
+            })
+            .warnings(
+                self.warnings
+                    .as_deref()
+                    .and_then(WarningLevel::from_name)
+                    .unwrap_or_default(),
+            );
+        for remap in &self.remap_path_prefix {
+            builder = builder.remap_path_prefix(remap.clone());
+        }
+        for dir in &self.include_path {
+            builder = builder.include_path(dir);
+        }
+        if let Some(bytes) = self.stack_size {
+            builder = builder.stack_size(bytes);
+        }
+        builder.okay()
+    }
+
+    /// Builds the options of `builder`, printing the passes they run with `--verbose`
+    pub fn build(&self, builder: OptionsBuilder) -> Result<Options> {
+        let options = builder.build()?;
+        if self.verbose {
+            eprintln!(
+                "Passes at -O{}: {}",
+                options.opt_level,
+                options.pass_names().join(", ")
+            );
+        }
+        options.okay()
+    }
+
+    pub fn options(&self) -> Result<Options> {
+        self.build(self.builder()?)
+    }
+}
+
+// Flags picking how errors and warnings are printed
+#[derive(Debug, Clone, Args)]
+pub struct ReportArgs {
+    /// Whether errors and warnings are colored, `auto` colors them on a terminal
+    #[clap(long, global = true, value_parser = ["auto", "always", "never"])]
+    pub color: Option<String>,
+    /// Columns errors and warnings are cut at, the terminal's `COLUMNS` by default
+    #[clap(long, global = true)]
+    pub diagnostic_width: Option<usize>,
+    /// `short` prints every error and warning as a single `file:line:col` line, `json` as a
+    /// JSON object
+    #[clap(long, global = true, value_parser = ["human", "short", "json"])]
+    pub error_format: Option<String>,
+}
+
+impl ReportArgs {
+    pub fn renderer(&self) -> Renderer {
+        Renderer::new(
+            self.color
+                .as_deref()
+                .and_then(ColorChoice::from_name)
+                .unwrap_or_default(),
+            self.diagnostic_width,
+            self.error_format
+                .as_deref()
+                .and_then(ErrorFormat::from_name)
+                .unwrap_or_default(),
+        )
+    }
+}
+
+// Flags of a program run by the interpreter
+#[derive(Debug, Clone, Args)]
+pub struct RunArgs {
+    /// Trap on reading bytes of `mem`s, variables and locals that were never written
+    #[clap(long)]
+    pub check_uninit: bool,
+    /// Arguments of the program, after `--`
+    #[clap(last = true)]
+    pub args: Vec<String>,
+}
@@ -0,0 +1,742 @@
+//! What is reported about a program and how: every `Error` and warning as a `Diagnostic`,
+//! printed with or without colors, cut to the width of the terminal, as single lines for
+//! editors that parse plain output, or as JSON for those that don't want to.
+
+use crate::{
+    eval::EvalError,
+    rename::RenameError,
+    span::{SourceMap, Span},
+    typecheck::{ErrorKind, TypecheckError, TypecheckWarning},
+    types::Type,
+    Error,
+};
+use ariadne::{Cache, Color, FileCache, Fmt, Label, Report, ReportKind};
+use chumsky::error::{Simple, SimpleReason};
+use serde::{Serialize, Serializer};
+use somok::Somok;
+use std::{
+    fmt,
+    hash::Hash,
+    io::{IsTerminal, Write},
+    path::Path,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    pub fn name(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+/// Something to tell about a program, with the source it points at.
+/// Serialized, labels lose their colors and spans are byte offsets into their files.
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// `E` and a number for errors of the lexer, parser and typechecker, `W` and a number for
+    /// warnings, which stay the same between versions
+    pub code: Option<&'static str>,
+    pub message: String,
+    /// Spans with what is there and the color to underline them in.
+    /// The diagnostic is about the first one, errors that aren't about the source have none.
+    #[serde(serialize_with = "serialize_labels")]
+    pub labels: Vec<(Span, String, Color)>,
+    /// Shown below the source
+    pub note: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl ToString) -> Self {
+        Self {
+            severity: Severity::Error,
+            code: None,
+            message: message.to_string(),
+            labels: Vec::new(),
+            note: None,
+        }
+    }
+
+    pub fn warning(message: impl ToString) -> Self {
+        Self {
+            severity: Severity::Warning,
+            ..Self::error(message)
+        }
+    }
+
+    pub fn with_code(mut self, code: &'static str) -> Self {
+        self.code = code.some();
+        self
+    }
+
+    pub fn with_label(mut self, span: Span, message: impl ToString, color: Color) -> Self {
+        self.labels.push((span, message.to_string(), color));
+        self
+    }
+
+    pub fn with_note(mut self, note: impl ToString) -> Self {
+        self.note = note.to_string().some();
+        self
+    }
+
+    /// Where the diagnostic is about
+    pub fn span(&self) -> Option<&Span> {
+        self.labels.first().map(|(span, _, _)| span)
+    }
+
+    pub fn report(&self) -> Report<'_, Span> {
+        let kind = match self.severity {
+            Severity::Error => ReportKind::Error,
+            Severity::Warning => ReportKind::Warning,
+        };
+        let (source, offset) = match self.span() {
+            Some(span) => (span.file.as_path(), span.start),
+            None => (Path::new(""), 0),
+        };
+        let labels = self.labels.iter().map(|(span, message, color)| {
+            Label::new(span.clone())
+                .with_message(message)
+                .with_color(*color)
+        });
+        let report = Report::build(kind, source, offset)
+            .with_message(&self.message)
+            .with_labels(labels);
+        match &self.note {
+            Some(note) => report.with_note(note).finish(),
+            None => report.finish(),
+        }
+    }
+}
+
+impl Error {
+    /// What is reported about the error, warnings denied included
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        match self {
+            Error::Lexer(es) => es
+                .iter()
+                .map(|e| unexpected(e, "character", "E0001"))
+                .collect(),
+            Error::Parser(es) => es.iter().map(|e| unexpected(e, "token", "E0002")).collect(),
+            Error::Redefinition(es) => es
+                .iter()
+                .map(|e| {
+                    Diagnostic::error("Duplicate word definitions")
+                        .with_code("E0004")
+                        .with_label(e.redefining_item.clone(), "redefined here", Color::Yellow)
+                        .with_label(
+                            e.redefined_item.clone(),
+                            "Word originally defined here...",
+                            Color::Green,
+                        )
+                })
+                .collect(),
+            Error::AliasCycle(spans) => {
+                vec![cycle("Aliases refer to each other in a cycle", spans).with_code("E0005")]
+            }
+            Error::IncludeCycle(spans) => vec![cycle("Files include each other in a cycle", spans)
+                .with_code("E0006")
+                .with_note("A file can't include itself, even through other files")],
+            Error::Typecheck(e) => vec![typecheck(e)],
+            Error::DeniedWarnings(ws) => ws
+                .iter()
+                .map(TypecheckWarning::diagnostic)
+                .chain([Diagnostic::error(self)])
+                .collect(),
+            Error::Assembler(es) => {
+                let mut message =
+                    "Internal compiler error, the generated assembly is invalid:".to_string();
+                for e in es {
+                    message += &format!("\n{}", e);
+                }
+                vec![Diagnostic::error(message)]
+            }
+            Error::Rename(e) => vec![rename(e)],
+            Error::Eval(EvalError::Trap(trap)) if !trap.spans.is_empty() => {
+                let mut spans = trap.spans.iter();
+                let (span, label) = spans.next().unwrap();
+                let mut diagnostic = Diagnostic::error(&trap.message).with_label(
+                    span.clone(),
+                    label.fg(Color::Red),
+                    Color::Red,
+                );
+                for (span, label) in spans {
+                    diagnostic = diagnostic.with_label(span.clone(), label, Color::Yellow);
+                }
+                if !trap.backtrace.is_empty() {
+                    diagnostic = diagnostic
+                        .with_note(format!("in {}", trap.backtrace.join(", called from ")));
+                }
+                vec![diagnostic]
+            }
+            Error::ConstEval { span, trap } => {
+                let mut diagnostic = Diagnostic::error(&trap.message)
+                    .with_code("E0007")
+                    .with_label(
+                        span.clone(),
+                        "Evaluating this at compile time fails".fg(Color::Red),
+                        Color::Red,
+                    );
+                for (span, label) in &trap.spans {
+                    diagnostic = diagnostic.with_label(span.clone(), label, Color::Yellow);
+                }
+                if !trap.backtrace.is_empty() {
+                    diagnostic = diagnostic
+                        .with_note(format!("in {}", trap.backtrace.join(", called from ")));
+                }
+                vec![diagnostic]
+            }
+            Error::IO(_)
+            | Error::MissingTool { .. }
+            | Error::ToolFailed { .. }
+            | Error::UnsupportedTarget(_)
+            | Error::Backend(_)
+            | Error::Manifest(_)
+            | Error::Options(_)
+            | Error::Eval(_)
+            | Error::Lir(_) => vec![Diagnostic::error(self)],
+        }
+    }
+
+    /// The diagnostics of the error rendered with their source, which `cache` reads
+    pub fn report(&self, mut cache: impl Cache<Path>) -> String {
+        let mut out = Vec::new();
+        for diagnostic in self.diagnostics() {
+            diagnostic.report().write(&mut cache, &mut out).unwrap();
+        }
+        String::from_utf8_lossy(&out).into_owned()
+    }
+}
+
+impl TypecheckWarning {
+    pub fn diagnostic(&self) -> Diagnostic {
+        Diagnostic::warning(&self.message)
+            .with_code(self.code)
+            .with_label(self.span.clone(), self.label, Color::Yellow)
+    }
+}
+
+/// `diagnostics` as a JSON array, see `Diagnostic` for what is kept of them
+pub fn to_json(diagnostics: &[Diagnostic]) -> String {
+    serde_json::to_string_pretty(diagnostics).unwrap()
+}
+
+#[derive(Serialize)]
+struct SpanLabel<'a> {
+    span: &'a Span,
+    message: String,
+}
+
+fn serialize_labels<S: Serializer>(
+    labels: &[(Span, String, Color)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(labels.iter().map(|(span, message, _)| SpanLabel {
+        span,
+        message: plain(message),
+    }))
+}
+
+/// `text` without the escapes that color it
+fn plain(text: &str) -> String {
+    let mut res = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            chars.by_ref().take_while(|&c| c != 'm').for_each(drop);
+        } else {
+            res.push(c);
+        }
+    }
+    res
+}
+
+/// A lexer or parser error with `code`, `what` being what the input is made of
+fn unexpected<T: fmt::Display + Hash + Eq>(
+    e: &Simple<T, Span>,
+    what: &str,
+    code: &'static str,
+) -> Diagnostic {
+    let found = match e.found() {
+        Some(found) => format!("{} {}", what, found),
+        None => "end of file".to_string(),
+    };
+    match e.reason() {
+        SimpleReason::Unexpected => {
+            let expected = if e.expected().len() == 0 {
+                "something else".to_string()
+            } else {
+                e.expected()
+                    .map(|expected| match expected {
+                        Some(expected) => expected.to_string(),
+                        None => "end of input".to_string(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            let message = match e.found() {
+                Some(_) => format!("Unexpected {} in input, expected {}", what, expected),
+                None => format!("Unexpected end of input, expected {}", expected),
+            };
+            Diagnostic::error(message).with_code(code).with_label(
+                e.span(),
+                format!("Unexpected {}", found.fg(Color::Red)),
+                Color::Red,
+            )
+        }
+        SimpleReason::Unclosed { span, delimiter } => {
+            Diagnostic::error(format!("Unclosed delimiter {}", delimiter))
+                .with_code("E0003")
+                .with_label(
+                    e.span(),
+                    format!("Must be closed before {}", found.fg(Color::Red)),
+                    Color::Red,
+                )
+                .with_label(span.clone(), "Opened here", Color::Yellow)
+        }
+        SimpleReason::Custom(message) => Diagnostic::error(message).with_code(code).with_label(
+            e.span(),
+            message.fg(Color::Red),
+            Color::Red,
+        ),
+    }
+}
+
+fn cycle(message: &str, spans: &[Span]) -> Diagnostic {
+    spans
+        .iter()
+        .fold(Diagnostic::error(message), |diagnostic, span| {
+            diagnostic.with_label(span.clone(), "part of the cycle", Color::Red)
+        })
+}
+
+fn typecheck(e: &TypecheckError) -> Diagnostic {
+    let (label, note) = match &e.kind {
+        ErrorKind::TypeMismatch { expected, actual } => (
+            format!(
+                "Unexpected types: {} where {} expected",
+                format!("{:?}", actual).fg(Color::Green),
+                format!("{:?}", expected).fg(Color::Yellow)
+            ),
+            None,
+        ),
+        ErrorKind::NotEnoughData => ("Not enough data on the stack".to_string(), None),
+        ErrorKind::Undefined(w) => (format!("Unknown word `{}`", w.fg(Color::Yellow)), None),
+        ErrorKind::InvalidMain => (
+            format!("Invalid type signature for `{}`", "main".fg(Color::Yellow)),
+            None,
+        ),
+        ErrorKind::InvalidWhile { entry, .. } => (
+            format!(
+                "Loop is entered with {}",
+                format!("{:?}", entry).fg(Color::Yellow)
+            ),
+            None,
+        ),
+        ErrorKind::BranchMismatch { truth, lie } => (
+            format!(
+                "True branch leaves {} where the false one leaves {}",
+                format!("{:?}", truth.stack).fg(Color::Green),
+                format!("{:?}", lie.stack).fg(Color::Yellow)
+            ),
+            None,
+        ),
+        ErrorKind::InvalidCond => (
+            "Every value must be matched by exactly one branch".to_string(),
+            "A `cond` ends with a `_` branch unless it matches both `true` and `false`".some(),
+        ),
+        ErrorKind::InvalidDefer => (
+            "Deferred code must not change the stack or return".to_string(),
+            "A deferred body starts on an empty stack and must leave it empty".some(),
+        ),
+        ErrorKind::CompStop => ("Compilation stopped here".to_string(), None),
+        ErrorKind::Unexpected => ("Unexpected word".to_string(), None),
+        ErrorKind::NoRuntime(piece) => (
+            format!("Needs the {} runtime", piece.fg(Color::Yellow)),
+            None,
+        ),
+        ErrorKind::CallInConst => (
+            "Procedure call here".to_string(),
+            "Compile-time evaluation can't call procs or make syscalls".some(),
+        ),
+        ErrorKind::Impure => ("Not allowed in a pure proc".to_string(), None),
+        ErrorKind::Misaligned { required, offset } => (
+            format!(
+                "Pointer is {} bytes past a {} byte boundary",
+                offset.fg(Color::Yellow),
+                required.fg(Color::Yellow)
+            ),
+            None,
+        ),
+        ErrorKind::DynamicDepth(word) => (
+            format!("The depth of `{}` must be a literal right before it", word),
+            "Depths are fixed at compile time, consts and computed values can't be used".some(),
+        ),
+        ErrorKind::InvalidCast { from, to } => (
+            format!(
+                "{} can't be reinterpreted as {}",
+                format!("{:?}", from).fg(Color::Yellow),
+                format!("{:?}", to).fg(Color::Yellow)
+            ),
+            None,
+        ),
+        ErrorKind::UnboundTypeVar(v) => (
+            format!(
+                "No input has type `{}`",
+                format!("'{}", v).fg(Color::Yellow)
+            ),
+            "Every type variable of the outputs must also be an input".some(),
+        ),
+        ErrorKind::LiteralOutOfRange(ty) => (
+            format!("Used as {} here", format!("{:?}", ty).fg(Color::Yellow)),
+            "Unsuffixed literals take the type they are used as".some(),
+        ),
+    };
+    let mut diagnostic = Diagnostic::error(&e.message)
+        .with_code(typecheck_code(&e.kind))
+        .with_label(e.span.clone(), label.fg(Color::Red), Color::Red);
+    let (branches, diverges) = match &e.kind {
+        ErrorKind::InvalidWhile { entry, body } => {
+            (vec![(body, Color::Green)], divergence(entry, &body.stack))
+        }
+        ErrorKind::BranchMismatch { truth, lie } => (
+            vec![(truth, Color::Green), (lie, Color::Yellow)],
+            divergence(&lie.stack, &truth.stack),
+        ),
+        _ => (Vec::new(), None),
+    };
+    for (branch, color) in branches {
+        if let Some(span) = &branch.span {
+            let label = format!("This leaves {}", format!("{:?}", branch.stack).fg(color));
+            diagnostic = diagnostic.with_label(span.clone(), label, color);
+        }
+    }
+    match note.map(str::to_string).or(diverges) {
+        Some(note) => diagnostic.with_note(note),
+        None => diagnostic,
+    }
+}
+
+fn typecheck_code(kind: &ErrorKind) -> &'static str {
+    match kind {
+        ErrorKind::TypeMismatch { .. } => "E0100",
+        ErrorKind::NotEnoughData => "E0101",
+        ErrorKind::Undefined(_) => "E0102",
+        ErrorKind::InvalidMain => "E0103",
+        ErrorKind::InvalidWhile { .. } => "E0104",
+        ErrorKind::InvalidCond => "E0105",
+        ErrorKind::InvalidDefer => "E0106",
+        ErrorKind::CompStop => "E0107",
+        ErrorKind::Unexpected => "E0108",
+        ErrorKind::NoRuntime(_) => "E0109",
+        ErrorKind::CallInConst => "E0110",
+        ErrorKind::Impure => "E0111",
+        ErrorKind::Misaligned { .. } => "E0112",
+        ErrorKind::InvalidCast { .. } => "E0113",
+        ErrorKind::UnboundTypeVar(_) => "E0114",
+        ErrorKind::BranchMismatch { .. } => "E0115",
+        ErrorKind::DynamicDepth(_) => "E0116",
+        ErrorKind::LiteralOutOfRange(_) => "E0117",
+    }
+}
+
+/// How many more or fewer values `actual` has than `expected`, or else where it first differs,
+/// counting from the top of both. A literal matches any integer.
+fn divergence(expected: &[Type], actual: &[Type]) -> Option<String> {
+    if actual.len() != expected.len() {
+        return format!(
+            "{} values are left instead of {}",
+            actual.len(),
+            expected.len()
+        )
+        .some();
+    }
+    let same = |e: &Type, a: &Type| {
+        e == a
+            || e.is_integer()
+                && a.is_integer()
+                && (e.literal_var().is_some() || a.literal_var().is_some())
+    };
+    let depth = expected
+        .iter()
+        .rev()
+        .zip(actual.iter().rev())
+        .position(|(e, a)| !same(e, a))?;
+    let top = actual.len() - 1;
+    format!(
+        "Value {} from the top is {:?} where {:?} is expected",
+        depth + 1,
+        actual[top - depth],
+        expected[top - depth]
+    )
+    .some()
+}
+
+fn rename(e: &RenameError) -> Diagnostic {
+    let diagnostic = Diagnostic::error(format!("Cannot rename: {}", e));
+    match e {
+        RenameError::NotFound(span) => {
+            diagnostic.with_label(span.clone(), "Nothing to rename here", Color::Red)
+        }
+        RenameError::Collision { name, existing } => diagnostic.with_label(
+            existing.clone(),
+            format!("`{}` is defined here", name),
+            Color::Yellow,
+        ),
+        RenameError::Captured { name, at } => diagnostic.with_label(
+            at.clone(),
+            format!("This reference to `{}` would be captured", name),
+            Color::Red,
+        ),
+        RenameError::InvalidName(_) | RenameError::Intrinsic(_) | RenameError::Reserved(_) => {
+            diagnostic
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Colors when printing to a terminal, unless `NO_COLOR` is set or the terminal is dumb
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "auto" => ColorChoice::Auto.some(),
+            "always" => ColorChoice::Always.some(),
+            "never" => ColorChoice::Never.some(),
+            _ => None,
+        }
+    }
+
+    fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => {
+                std::env::var_os("NO_COLOR").is_none()
+                    && std::env::var("TERM").as_deref() != Ok("dumb")
+                    && std::io::stderr().is_terminal()
+            }
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorFormat {
+    /// The source around each report, with its labels
+    #[default]
+    Human,
+    /// `file:line:col: error: message`, one line per report
+    Short,
+    /// A JSON object per line, as `to_json` writes them
+    Json,
+}
+
+impl ErrorFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "human" => ErrorFormat::Human.some(),
+            "short" => ErrorFormat::Short.some(),
+            "json" => ErrorFormat::Json.some(),
+            _ => None,
+        }
+    }
+}
+
+/// Prints reports to stderr, keeping the sources they show
+pub struct Renderer {
+    color: bool,
+    /// Columns longer lines are cut at
+    width: Option<usize>,
+    format: ErrorFormat,
+    files: FileCache,
+    sources: SourceMap,
+}
+
+impl Renderer {
+    /// Without a width, that of the terminal is taken from `COLUMNS` when it is set
+    pub fn new(color: ColorChoice, width: Option<usize>, format: ErrorFormat) -> Self {
+        let width = width.or_else(|| std::env::var("COLUMNS").ok().and_then(|c| c.parse().ok()));
+        Self {
+            color: color.enabled(),
+            width,
+            format,
+            files: FileCache::default(),
+            sources: SourceMap::new(),
+        }
+    }
+
+    /// Prints `diagnostic`. Its severity and message are all the short format keeps of it,
+    /// along with where its span starts.
+    pub fn emit(&mut self, diagnostic: &Diagnostic) {
+        let text = self.render(diagnostic);
+        std::io::stderr().write_all(text.as_bytes()).unwrap();
+    }
+
+    fn render(&mut self, diagnostic: &Diagnostic) -> String {
+        let severity = diagnostic.severity.name();
+        let text = match (self.format, diagnostic.span()) {
+            (ErrorFormat::Human, _) => {
+                let mut out = Vec::new();
+                diagnostic
+                    .report()
+                    .write(&mut self.files, &mut out)
+                    .unwrap();
+                String::from_utf8_lossy(&out).into_owned()
+            }
+            (ErrorFormat::Short, Some(span)) => {
+                let _ = self.sources.load(&span.file);
+                let location = self
+                    .sources
+                    .location(span)
+                    .unwrap_or_else(|| span.file.display().to_string());
+                format!("{}: {}: {}\n", location, severity, diagnostic.message)
+            }
+            (ErrorFormat::Short, None) => format!("{}: {}\n", severity, diagnostic.message),
+            // cutting it to the width would make it invalid
+            (ErrorFormat::Json, _) => return serde_json::to_string(diagnostic).unwrap() + "\n",
+        };
+        text.lines().map(|line| self.fit(line) + "\n").collect()
+    }
+
+    /// `line` without its colors if they are off, and cut to the width
+    fn fit(&self, line: &str) -> String {
+        let (mut parts, mut plain) = (Vec::new(), 0);
+        let mut chars = line.chars();
+        while let Some(c) = chars.next() {
+            if c == '\x1b' {
+                // `ESC [ ... m`, which takes no room on the terminal
+                let escape = std::iter::once(c)
+                    .chain(chars.by_ref().take_while(|&c| c != 'm'))
+                    .chain(std::iter::once('m'))
+                    .collect::<String>();
+                parts.push(Err(escape));
+            } else {
+                parts.push(Ok(c));
+                plain += 1;
+            }
+        }
+        // the last column that fits goes to the ellipsis
+        let cut = self
+            .width
+            .filter(|&width| plain > width)
+            .map(|width| width - 1);
+        let (mut out, mut columns) = (String::new(), 0);
+        for part in parts {
+            match part {
+                Ok(_) if Some(columns) == cut => {
+                    out.push('…');
+                    if self.color {
+                        out.push_str("\x1b[0m");
+                    }
+                    break;
+                }
+                Ok(c) => {
+                    out.push(c);
+                    columns += 1;
+                }
+                Err(escape) if self.color => out.push_str(&escape),
+                Err(_) => (),
+            }
+        }
+        out
+    }
+}
+
+#[test]
+fn test_render() {
+    let tmp = crate::build::TempDir::new().unwrap();
+    let file = tmp.path.join("bad.rh");
+    std::fs::write(&file, "proc main : u64 do\n  true\nend").unwrap();
+    let span = Span::new(&file, 21, 25);
+    let diagnostic = Diagnostic::error("Wrong types").with_label(span, "here", Color::Red);
+
+    let mut short = Renderer::new(ColorChoice::Never, None, ErrorFormat::Short);
+    let text = short.render(&diagnostic);
+    assert_eq!(
+        text,
+        format!("{}:2:3: error: Wrong types\n", file.display())
+    );
+    let text = short.render(&Diagnostic::error("No backend"));
+    assert_eq!(text, "error: No backend\n");
+
+    let mut human = Renderer::new(ColorChoice::Never, Some(20), ErrorFormat::Human);
+    let text = human.render(&diagnostic);
+    assert!(text.contains("true") && !text.contains('\x1b'));
+    assert!(text.lines().all(|line| line.chars().count() <= 20));
+
+    let colored = Renderer::new(ColorChoice::Always, Some(4), ErrorFormat::Human);
+    assert_eq!(colored.fit("\x1b[31mab\x1b[0m"), "\x1b[31mab\x1b[0m");
+    assert_eq!(colored.fit("\x1b[31mabcdef"), "\x1b[31mabc…\x1b[0m");
+}
+
+#[test]
+fn test_error_report() {
+    use crate::driver::{self, BuildOptions};
+
+    let tmp = crate::build::TempDir::new().unwrap();
+    let file = tmp.path.join("bad.rh");
+    std::fs::write(&file, "proc main : u64 do\n  1 true +\nend").unwrap();
+    let e = match driver::check(&file, &BuildOptions::default()) {
+        Err(e) => e,
+        Ok(_) => panic!("`1 true +` typechecked"),
+    };
+    let diagnostics = e.diagnostics();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].severity, Severity::Error);
+    assert_eq!(diagnostics[0].span().unwrap().file, file);
+    let text = e.report(FileCache::default());
+    assert!(
+        text.contains("bad.rh:2:") && text.contains("Wrong types"),
+        "{}",
+        text
+    );
+
+    let e = Error::UnsupportedTarget("x".to_string());
+    let diagnostics = e.diagnostics();
+    assert!(diagnostics[0].labels.is_empty());
+    assert!(e
+        .report(FileCache::default())
+        .contains("No backend for target `x`"));
+}
+
+#[test]
+fn test_to_json() {
+    let source = "proc main : u64 do\n  1 ` +\nend";
+    let diagnostics = match crate::lexer::lex_string(source.into(), "bad.rh".into()) {
+        Err(e) => e.diagnostics(),
+        Ok(_) => panic!("` lexed"),
+    };
+    let json = to_json(&diagnostics);
+    assert!(json.contains("\"severity\": \"error\""), "{}", json);
+    assert!(json.contains("\"code\": \"E0001\""), "{}", json);
+    assert!(json.contains("\"file\": \"bad.rh\""), "{}", json);
+    assert!(json.contains("\"start\": 23"), "{}", json);
+    assert!(!json.contains('\x1b'), "{}", json);
+
+    let warning = TypecheckWarning {
+        span: Span::new("a.rh", 0, 4),
+        message: "proc `f` is never used".to_string(),
+        label: "Defined here",
+        code: "W0002",
+    };
+    let mut renderer = Renderer::new(ColorChoice::Never, Some(4), ErrorFormat::Json);
+    assert_eq!(
+        renderer.render(&warning.diagnostic()),
+        "{\"severity\":\"warning\",\"code\":\"W0002\",\"message\":\"proc `f` is never used\",\
+         \"labels\":[{\"span\":{\"file\":\"a.rh\",\"start\":0,\"end\":4},\
+         \"message\":\"Defined here\"}],\"note\":null}\n"
+    );
+}
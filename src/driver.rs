@@ -0,0 +1,286 @@
+use crate::{
+    build::{compile_hir, Builder, Toolchain},
+    emit::symbols::{data_layout, DataItem},
+    eval::{HostSyscalls, Interpreter},
+    hir::TopLevel,
+    lir::{self, extend, LirPass},
+    session::{Options, Session},
+    typecheck::{TypecheckWarning, Typechecker},
+    types::Type,
+    Result,
+};
+use fnv::FnvHashMap;
+use somok::Somok;
+use std::path::{Path, PathBuf};
+
+/// What `build` makes and how
+#[derive(Debug, Clone, Default)]
+pub struct BuildOptions {
+    pub options: Options,
+    /// Assembler and linker to use instead of the default ones for the target
+    pub toolchain: Option<Toolchain>,
+    /// Where the executable goes, next to the source without its extension by default
+    pub output: Option<PathBuf>,
+    /// Objects linked into the executable, defining the `extern mem`s of the program
+    pub objects: Vec<PathBuf>,
+}
+
+impl BuildOptions {
+    /// Runs `pass` on the LIR of every program built with these options, in the order of its phase
+    pub fn pass(&mut self, pass: impl LirPass + 'static) -> &mut Self {
+        self.options.passes.register(pass);
+        self
+    }
+}
+
+/// Lexes, parses, typechecks, lowers and emits `path`, then assembles and links the result into
+/// an executable and returns where it is. Typecheck warnings are dropped, `Builder` returns them.
+pub fn build(path: &Path, opts: &BuildOptions) -> Result<PathBuf> {
+    let output = opts
+        .output
+        .clone()
+        .unwrap_or_else(|| path.with_extension(""));
+    let triple = opts.options.target.triple();
+    let mut builder = Builder::new();
+    if let Some(toolchain) = &opts.toolchain {
+        builder = builder.toolchain(triple, toolchain.clone());
+    }
+    for object in &opts.objects {
+        builder = builder.object(object);
+    }
+    builder.build(path, triple, &output, opts.options.clone())?;
+    output.okay()
+}
+
+/// A program lowered to LIR, ready for a backend or the interpreter
+pub struct Lowered {
+    pub session: Session,
+    pub program: lir::Program,
+    pub warnings: Vec<TypecheckWarning>,
+}
+
+impl Lowered {
+    /// Where the backends put the strings, tables and mems of the program
+    pub fn data_layout(&self) -> Vec<DataItem> {
+        let placements = &self.session.options.placements;
+        let program = &self.program;
+        data_layout(
+            &program.mems,
+            &self.session.strings(),
+            &program.tables,
+            placements,
+        )
+    }
+}
+
+fn session(opts: &BuildOptions) -> Session {
+    Session::new(Default::default(), opts.options.clone())
+}
+
+/// Lexes, parses and walks `path` into HIR, without typechecking it
+pub fn hir(path: &Path, opts: &BuildOptions) -> Result<(Session, FnvHashMap<String, TopLevel>)> {
+    let mut session = session(opts);
+    let hir = compile_hir(path, &mut session)?;
+    (session, hir).okay()
+}
+
+/// Takes `path` through the typechecker, returning the procs reachable from `main`
+pub fn check(
+    path: &Path,
+    opts: &BuildOptions,
+) -> Result<(Session, FnvHashMap<String, TopLevel>, Vec<TypecheckWarning>)> {
+    let (session, hir) = hir(path, opts)?;
+    let (procs, warnings) = Typechecker::typecheck_program(hir, &session)?;
+    (session, procs, warnings).okay()
+}
+
+/// Typechecks `path` and lowers it to LIR, or reads the LIR of a `.lir` file as `rotthc dump-lir`
+/// prints it
+pub fn lower(path: &Path, opts: &BuildOptions) -> Result<Lowered> {
+    if path.extension() == Some("lir".as_ref()) {
+        return read_lir(path, opts);
+    }
+    let (session, procs, warnings) = check(path, opts)?;
+    let program = lir::Compiler::new(&session).compile(procs)?;
+    Lowered {
+        session,
+        program,
+        warnings,
+    }
+    .okay()
+}
+
+fn read_lir(path: &Path, opts: &BuildOptions) -> Result<Lowered> {
+    let session = session(opts);
+    let text = std::fs::read_to_string(path)?;
+    let program = lir::parse(&text, path, &session)?;
+    let strings = session.strings().len();
+    lir::verify(&program.ops, &program.mems, strings, &Default::default())
+        .map_err(lir::TextError::Invalid)?;
+    // checked builds make room for the values of each proc as it's called
+    if session.options.checked {
+        let missing = program
+            .ops
+            .iter()
+            .filter_map(|op| match op {
+                lir::Op::Proc(l) if !program.depths.contains_key(l) => {
+                    format!("proc `{}` has no depth", l).some()
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            return Err(lir::TextError::Invalid(missing).into());
+        }
+    }
+    Lowered {
+        session,
+        program,
+        warnings: Vec::new(),
+    }
+    .okay()
+}
+
+/// A program whose `main` is `snippet`, to write to a file for `eval`
+pub fn snippet_program(snippet: &str) -> String {
+    format!("proc main do\n{}\nend\n", snippet)
+}
+
+/// What a snippet left
+pub struct Evaluated {
+    pub session: Session,
+    /// Values on the stack with their types, the top last
+    pub stack: Vec<(Type, u64)>,
+    /// Exit code, if the snippet exited instead
+    pub exit_code: Option<u64>,
+    pub warnings: Vec<TypecheckWarning>,
+}
+
+impl Evaluated {
+    /// Each value on the stack as `value : type`, the top last
+    pub fn describe(&self) -> Vec<String> {
+        self.stack
+            .iter()
+            .map(|(ty, value)| {
+                let value = if ty.is_ptr() || ty.is_proc() {
+                    format!("{:#x}", value)
+                } else if *ty == Type::BOOL {
+                    (*value != 0).to_string()
+                } else if *ty == Type::CHAR {
+                    format!("{:?}", *value as u8 as char)
+                } else if ty.is_signed() {
+                    let bits = ty.narrow_bits().unwrap_or(64);
+                    (extend(*value, bits, true) as i64).to_string()
+                } else {
+                    value.to_string()
+                };
+                format!("{} : {}", value, ty.name(&self.session.structs))
+            })
+            .collect()
+    }
+}
+
+/// Interprets `path`, whose `main` takes nothing and leaves anything, like that of a
+/// `snippet_program`, returning what it leaves
+pub fn eval(path: &Path, opts: &BuildOptions) -> Result<Evaluated> {
+    let (session, hir) = hir(path, opts)?;
+    let (procs, warnings) = Typechecker::typecheck_snippet(hir, &session)?;
+    let outs = match &procs["main"] {
+        TopLevel::Proc(main) => main.outs.clone(),
+        _ => unreachable!(),
+    };
+    let program = lir::Compiler::new(&session).compile(procs)?;
+    let mut interpreter = Interpreter::new();
+    interpreter
+        .mems(&program.mems)
+        .tables(&program.tables)
+        .syscalls(HostSyscalls::new());
+    if session.options.checked {
+        interpreter.check_indirect();
+    }
+    let mut machine = interpreter.start(&session.strings());
+    let exit_code = interpreter.call(&mut machine, &program.ops, "main")?;
+    let stack = match exit_code {
+        Some(_) => Vec::new(),
+        None => outs.into_iter().zip(machine.stack().to_vec()).collect(),
+    };
+    Evaluated {
+        session,
+        stack,
+        exit_code,
+        warnings,
+    }
+    .okay()
+}
+
+#[test]
+fn test_build() {
+    let tmp = crate::build::TempDir::new().unwrap();
+    let source = tmp.path.join("answer.rh");
+    std::fs::write(&source, "proc main : u64 do 42 end").unwrap();
+    let exe = build(&source, &BuildOptions::default()).unwrap();
+    assert_eq!(exe, tmp.path.join("answer"));
+    let status = std::process::Command::new(&exe).status().unwrap();
+    assert_eq!(status.code(), Some(42));
+}
+
+#[test]
+fn test_eval() {
+    let tmp = crate::build::TempDir::new().unwrap();
+    let source = tmp.path.join("eval.rh");
+    let snippet = "1 2 + 'a' true 5 cast i8 0 cast i8 swap - &test_eval_helper";
+    let program = snippet_program(snippet) + "proc test_eval_helper do end\n";
+    std::fs::write(&source, program).unwrap();
+    let evaluated = eval(&source, &BuildOptions::default()).unwrap();
+    let lines = evaluated.describe();
+    assert_eq!(
+        lines[..4],
+        ["3 : u64", "'a' : char", "true : bool", "-5 : i8"]
+    );
+    assert!(lines[4].ends_with(" : ptr(proc(:))"), "{}", lines[4]);
+    assert_eq!(evaluated.exit_code, None);
+}
+
+#[test]
+fn test_cstr() {
+    let tmp = crate::build::TempDir::new().unwrap();
+    let source = tmp.path.join("cstr.rh");
+    // the copy of "hello" ends in a nul right after the heap gave the copy of "abc" its bytes
+    let snippet = "
+        \"abc\" swap to-cstr from-cstr
+        \"hello\" swap to-cstr
+        dup 5 ptr+ cast &>u8 @u8
+        swap cstrlen";
+    let program = format!("include \"std.rh\"\n{}", snippet_program(snippet));
+    std::fs::write(&source, program).unwrap();
+    let std = Path::new(env!("CARGO_MANIFEST_DIR")).join("rotth-src");
+    let opts = BuildOptions {
+        options: Options::builder().include_path(std).build().unwrap(),
+        ..Default::default()
+    };
+    let evaluated = eval(&source, &opts).unwrap();
+    let lines = evaluated.describe();
+    assert_eq!(lines[1..], ["3 : u64", "0 : u8", "5 : u64"]);
+}
+
+#[test]
+fn test_read_lir() {
+    let tmp = crate::build::TempDir::new().unwrap();
+    let path = tmp.path.join("answer.lir");
+    let ops = "Call(\"main\")\nExit\nProc(\"main\")\nPush(U64(42))\nReturn\n";
+    std::fs::write(&path, ops).unwrap();
+    let lowered = lower(&path, &BuildOptions::default()).unwrap();
+    assert_eq!(lowered.program.ops.len(), 5);
+
+    // checked builds can't tell how much room `main` needs without its depth
+    let opts = BuildOptions {
+        options: Options::builder().checked(true).build().unwrap(),
+        ..Default::default()
+    };
+    assert!(matches!(
+        lower(&path, &opts),
+        Err(crate::Error::Lir(lir::TextError::Invalid(e))) if e == ["proc `main` has no depth"]
+    ));
+    std::fs::write(&path, format!("depth main 1\n{}", ops)).unwrap();
+    assert_eq!(lower(&path, &opts).unwrap().program.depth("main"), 1);
+}
@@ -1,23 +1,120 @@
-use crate::{iconst::IConst, lir::Op};
-use fnv::FnvHashMap;
+use crate::{
+    emit::{
+        cache::StackCache,
+        symbols::{data_layout, Datum},
+    },
+    hir::PrintFormat,
+    iconst::IConst,
+    intrinsics::{HASH_OFFSET, HASH_PRIME},
+    lir::{demangle, Cmp, Op, Program},
+    session::{Comments, Options, PathRemap, Session},
+    span::{SourceMap, Span},
+};
 use indoc::indoc;
 use somok::Somok;
-use std::io::{BufWriter, Write};
+use std::{
+    io::{BufWriter, Write},
+    path::PathBuf,
+};
+
+pub mod c;
+mod cache;
+pub mod elf;
+pub mod symbols;
+pub mod wasm;
+
+/// Frames kept by the shadow call stack of checked builds, deeper calls share the last slot
+pub(crate) const TRACE_FRAMES: usize = 256;
+
+/// Bytes checked builds let the data stack grow below where it starts, the usual 8 MiB limit of
+/// the main thread's stack less room for the arguments and environment above it
+pub(crate) const DATA_STACK_BYTES: u64 = (8 << 20) - (256 << 10);
+
+/// `k` as an operand, an immediate if it fits in the sign-extended 32 bits x86 takes and
+/// otherwise loaded into `scratch` first, along with the code loading it
+fn imm_operand(k: u64, scratch: &str) -> (String, String) {
+    if i32::try_from(k as i64).is_ok() {
+        (String::new(), (k as i64).to_string())
+    } else {
+        (format!("    mov {}, {}\n", scratch, k), scratch.to_string())
+    }
+}
+
+/// Condition code suffix of `cmp`, as in `jcc` and `setcc`
+fn condition(cmp: Cmp) -> &'static str {
+    match cmp {
+        Cmp::Eq => "e",
+        Cmp::Ne => "ne",
+        Cmp::Lt => "l",
+        Cmp::Le => "le",
+        Cmp::Gt => "g",
+        Cmp::Ge => "ge",
+        Cmp::LtU => "b",
+        Cmp::LeU => "be",
+        Cmp::GtU => "a",
+        Cmp::GeU => "ae",
+    }
+}
 
 pub fn compile<S: Write>(
-    ops: Vec<Op>,
-    strings: &[String],
-    mems: &FnvHashMap<String, usize>,
+    mut program: Program,
+    session: &Session,
     mut sink: BufWriter<S>,
 ) -> std::io::Result<()> {
+    let ops = std::mem::take(&mut program.ops);
+    let mems = &program.mems;
     use Op::*;
+    let options = &session.options;
+    let strings = session.strings();
+    let check_alignment = options.checked && options.strict_alignment;
+    let proc_names = ops
+        .iter()
+        .filter_map(|op| match op {
+            Proc(l) => l.clone().some(),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let hashes = ops.iter().any(|op| matches!(op, HashStr));
+    let finds = ops.iter().any(|op| matches!(op, FindByte));
+    let runtime = options.runtime;
+    let traps = options.checked && runtime.traps;
     write!(
         sink,
         indoc! {"
             BITS 64
             section .text
             global _start
-            extern print
+        "}
+    )?;
+    if runtime.print {
+        write!(
+            sink,
+            indoc! {"
+                extern print
+                extern print_fmt
+            "}
+        )?;
+    }
+    if options.checked && !runtime.traps {
+        // the user's trap handler gets the shadow call stack to print a backtrace
+        write!(
+            sink,
+            indoc! {"
+                extern trap_misaligned
+                extern trap_stack_overflow
+                extern trap_signature
+                global trace_depth
+                global trace_stack
+            "}
+        )?;
+    }
+    for name in program.extern_mems.keys() {
+        // the object defining it names it without the prefix of mem labels
+        writeln!(sink, "extern {0}\n%define mem_{0} {0}", name)?;
+    }
+    write!(
+        sink,
+        indoc! {"
 
             _start:
                 mov QWORD [ret_stack_rsp], ret_stack_end
@@ -30,25 +127,54 @@ pub fn compile<S: Write>(
 
         "},
     )?;
+    if options.checked {
+        // procs check against this that the values they push fit
+        writeln!(
+            sink,
+            "    lea rax, [rsp - {}]\n    mov [data_stack_limit], rax\n",
+            DATA_STACK_BYTES
+        )?;
+    }
+    let mut annotator = Annotator::new(options, ";");
+    let mut cache = options.pipeline.register_cache.then(StackCache::default);
     for op in ops {
+        let comment = annotator.annotate(&op, &mut sink)?;
+        if let Some(cache) = &mut cache {
+            // checked indirect calls compare the stack pointer, so every value has to be on it
+            let checked_call = options.checked && matches!(op, CallIndirect(..));
+            if !checked_call
+                && cache.compile(&op, &comment, &strings, check_alignment, &mut sink)?
+            {
+                continue;
+            }
+            cache.flush(&mut sink)?;
+        }
         match &op {
             PushMem(nm) => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         push mem_{}
                     "},
-                op, nm
+                comment, nm
+            )?,
+            PushTable(nm) => write!(
+                sink,
+                indoc! {"
+                    {}
+                        push table_{}
+                    "},
+                comment, nm
             )?,
             PushStr(i) => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                     ;   mov rax, len
                         push {}
                         push str_{}
                     "},
-                op,
+                comment,
                 strings[*i].len(),
                 i
             )?,
@@ -56,267 +182,546 @@ pub fn compile<S: Write>(
                 IConst::Bool(b) => write!(
                     sink,
                     indoc! {"
-                        ; {:?}
+                        {}
                             mov rax, {}
                             push rax
                         "},
-                    op, *b as u64
+                    comment, *b as u64
                 )?,
                 IConst::Char(c) => write!(
                     sink,
                     indoc! {"
-                        ; {:?}
+                        {}
                             mov rax, {}
                             push rax
                         "},
-                    op, *c as u64
+                    comment, *c as u64
                 )?,
                 IConst::U64(u) => write!(
                     sink,
                     indoc! {"
-                        ; {:?}
+                        {}
                             mov rax, {}
                             push rax
                         "},
-                    op, u
+                    comment, u
                 )?,
                 IConst::I64(i) => write!(
                     sink,
                     indoc! {"
-                        ; {:?}
+                        {}
                             mov rax, {}
                             push rax
                         "},
-                    op, i
+                    comment, i
                 )?,
                 IConst::Ptr(p) => write!(
                     sink,
                     indoc! {"
-                        ; {:?}
+                        {}
                             mov rax, {}
                             push rax
                         "},
-                    op, p
+                    comment, p
                 )?,
-                IConst::Str(_s) => unreachable!(),
+                IConst::Int(_) | IConst::Str(_) => unreachable!(),
             },
             Dup => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         pop rax
                         push rax
                         push rax
                     "},
-                op
+                comment
             )?,
             Swap => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         pop rax
                         pop rbx
                         push rax
                         push rbx
                     "},
-                op
+                comment
             )?,
             Over => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         pop rax
                         pop rbx
                         push rbx
                         push rax
                         push rbx
                     "},
-                op
+                comment
+            )?,
+            Pick(depth) => write!(
+                sink,
+                indoc! {"
+                    {}
+                        push QWORD [rsp + 8 * {}]
+                    "},
+                comment, depth
             )?,
+            Roll(depth) => {
+                write!(
+                    sink,
+                    indoc! {"
+                        {}
+                            mov rax, [rsp + 8 * {}]
+                        "},
+                    comment, depth
+                )?;
+                for i in (0..*depth).rev() {
+                    writeln!(sink, "    mov rbx, [rsp + 8 * {}]", i)?;
+                    writeln!(sink, "    mov [rsp + 8 * {}], rbx", i + 1)?;
+                }
+                writeln!(sink, "    mov [rsp], rax")?;
+            }
             Drop => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         pop rax
                     "},
-                op
+                comment
             )?,
 
             ReserveEscaping(n) => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         mov rax, {}
                         sub [escaping_stack_sp], rax
                     "},
-                op, n
+                comment, n
             )?,
             PushEscaping(n) => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         mov rax, {}
                         mov rbx, [escaping_stack_sp]
                         add rbx, rax
                         push rbx
                     "},
-                op, n
+                comment, n
             )?,
 
             ReserveLocals(n) => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         mov rax, {}
                         sub [locals_stack_sp], rax
                     "},
-                op, n
+                comment, n
             )?,
             FreeLocals(n) => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         mov rax, {}
                         add [locals_stack_sp], rax
                     "},
-                op, n
+                comment, n
             )?,
 
             PushLvar(o) => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         mov rax, {}
                         mov rbx, [locals_stack_sp]
                         add rbx, rax
                         push rbx
                     "},
-                op, o
+                comment, o
             )?,
 
             Bind => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         pop rbx
                         mov rax, 8
                         sub [ret_stack_rsp], rax
                         mov QWORD rax, [ret_stack_rsp]
                         mov QWORD [rax], rbx
                     "},
-                op
+                comment
             )?,
             UseBinding(offset) => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         mov rax, 8 * {}
                         mov QWORD rbx, [ret_stack_rsp]
                         add rbx, rax
                         mov QWORD rax, [rbx]
                         push rax
                     "},
-                op, offset
+                comment, offset
             )?,
             Unbind => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         mov rax, 8
                         add [ret_stack_rsp], rax
                     "},
-                op
+                comment
             )?,
 
+            ReadU64 if check_alignment => write!(
+                sink,
+                indoc! {"
+                    {}
+                        pop rax
+                        test rax, 7
+                        jnz trap_misaligned
+                        mov rbx, [rax]
+                        push rbx
+                    "},
+                comment
+            )?,
             ReadU64 => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         pop rax
                         mov rbx, [rax]
                         push rbx
                     "},
-                op
+                comment
+            )?,
+            ReadU32 if check_alignment => write!(
+                sink,
+                indoc! {"
+                    {}
+                        pop rax
+                        test rax, 3
+                        jnz trap_misaligned
+                        mov ebx, [rax]
+                        push rbx
+                    "},
+                comment
+            )?,
+            ReadU32 => write!(
+                sink,
+                indoc! {"
+                    {}
+                        pop rax
+                        mov ebx, [rax]
+                        push rbx
+                    "},
+                comment
+            )?,
+            ReadU16 if check_alignment => write!(
+                sink,
+                indoc! {"
+                    {}
+                        pop rax
+                        test rax, 1
+                        jnz trap_misaligned
+                        movzx rbx, WORD [rax]
+                        push rbx
+                    "},
+                comment
+            )?,
+            ReadU16 => write!(
+                sink,
+                indoc! {"
+                    {}
+                        pop rax
+                        movzx rbx, WORD [rax]
+                        push rbx
+                    "},
+                comment
             )?,
             ReadU8 => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         pop rax
                         xor rbx, rbx
                         mov bl, [rax]
                         push rbx
                     "},
-                op
+                comment
+            )?,
+            WriteU64 if check_alignment => write!(
+                sink,
+                indoc! {"
+                    {}
+                        pop rax
+                        pop rbx
+                        test rax, 7
+                        jnz trap_misaligned
+                        mov [rax], rbx
+                    "},
+                comment
             )?,
             WriteU64 => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         pop rax
                         pop rbx
                         mov [rax], rbx
                     "},
-                op
+                comment
+            )?,
+            WriteU32 if check_alignment => write!(
+                sink,
+                indoc! {"
+                    {}
+                        pop rax
+                        pop rbx
+                        test rax, 3
+                        jnz trap_misaligned
+                        mov [rax], ebx
+                    "},
+                comment
+            )?,
+            WriteU32 => write!(
+                sink,
+                indoc! {"
+                    {}
+                        pop rax
+                        pop rbx
+                        mov [rax], ebx
+                    "},
+                comment
+            )?,
+            WriteU16 if check_alignment => write!(
+                sink,
+                indoc! {"
+                    {}
+                        pop rax
+                        pop rbx
+                        test rax, 1
+                        jnz trap_misaligned
+                        mov [rax], bx
+                    "},
+                comment
+            )?,
+            WriteU16 => write!(
+                sink,
+                indoc! {"
+                    {}
+                        pop rax
+                        pop rbx
+                        mov [rax], bx
+                    "},
+                comment
             )?,
             WriteU8 => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         pop rax
                         pop rbx
                         mov [rax], bl
                     "},
-                op
+                comment
             )?,
 
-            Print => write!(
+            Bswap64 => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
+                        pop rax
+                        bswap rax
+                        push rax
+                    "},
+                comment
+            )?,
+            Bswap32 => write!(
+                sink,
+                indoc! {"
+                    {}
+                        pop rax
+                        bswap eax
+                        push rax
+                    "},
+                comment
+            )?,
+            Bswap16 => write!(
+                sink,
+                indoc! {"
+                    {}
+                        pop rax
+                        xchg al, ah
+                        movzx rax, ax
+                        push rax
+                    "},
+                comment
+            )?,
+            ZeroExtend(bits) | SignExtend(bits) => write!(
+                sink,
+                indoc! {"
+                    {}
+                        pop rax
+                        {}
+                        push rax
+                    "},
+                comment,
+                match (&op, *bits) {
+                    (ZeroExtend(_), 32) => "mov eax, eax",
+                    (ZeroExtend(_), 16) => "movzx rax, ax",
+                    (ZeroExtend(_), _) => "movzx rax, al",
+                    (_, 32) => "movsxd rax, eax",
+                    (_, 16) => "movsx rax, ax",
+                    _ => "movsx rax, al",
+                }
+            )?,
+
+            Print(PrintFormat::Unsigned) => write!(
+                sink,
+                indoc! {"
+                    {}
                         pop rdi
                         call print
                     "},
-                op
+                comment
+            )?,
+            Print(format) => write!(
+                sink,
+                indoc! {"
+                    {}
+                        pop rdi
+                        mov rsi, {}
+                        mov rdx, {}
+                        xor rcx, rcx
+                        call print_fmt
+                    "},
+                comment,
+                format.radix(),
+                format.is_signed() as u64
+            )?,
+            PrintPadded(format) => write!(
+                sink,
+                indoc! {"
+                    {}
+                        pop rcx
+                        pop rdi
+                        mov rsi, {}
+                        mov rdx, {}
+                        call print_fmt
+                    "},
+                comment,
+                format.radix(),
+                format.is_signed() as u64
+            )?,
+
+            HashStr => write!(
+                sink,
+                indoc! {"
+                    {}
+                        pop rsi
+                        pop rcx
+                        call hash_str
+                        push rax
+                    "},
+                comment
+            )?,
+
+            Copy16 => write!(
+                sink,
+                indoc! {"
+                    {}
+                        pop rax
+                        pop rbx
+                        movdqu xmm0, [rbx]
+                        movdqu [rax], xmm0
+                    "},
+                comment
+            )?,
+            Cmp16 => write!(
+                sink,
+                indoc! {"
+                    {}
+                        pop rax
+                        pop rbx
+                        movdqu xmm0, [rbx]
+                        movdqu xmm1, [rax]
+                        pcmpeqb xmm0, xmm1
+                        pmovmskb eax, xmm0
+                        push rax
+                    "},
+                comment
+            )?,
+            Splat16 => write!(
+                sink,
+                indoc! {"
+                    {}
+                        pop rax
+                        pop rbx
+                        movzx ebx, bl
+                        mov rcx, 0x0101010101010101
+                        imul rbx, rcx
+                        movq xmm0, rbx
+                        punpcklqdq xmm0, xmm0
+                        movdqu [rax], xmm0
+                    "},
+                comment
+            )?,
+            FindByte => write!(
+                sink,
+                indoc! {"
+                    {}
+                        pop rax
+                        pop rcx
+                        pop rsi
+                        call find_byte
+                        push rax
+                        push rdx
+                    "},
+                comment
             )?,
 
             Syscall0 => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         pop rax
                         syscall
                         push rax
                     "},
-                op
+                comment
             )?,
             Syscall1 => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         pop rax
                         pop rdi
                         syscall
                         push rax
                     "},
-                op
+                comment
             )?,
             Syscall2 => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         pop rax
                         pop rdi
                         pop rsi
                         syscall
                         push rax
                     "},
-                op
+                comment
             )?,
             Syscall3 => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         pop rax
                         pop rdi
                         pop rsi
@@ -324,12 +729,12 @@ pub fn compile<S: Write>(
                         syscall
                         push rax
                     "},
-                op
+                comment
             )?,
             Syscall4 => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         pop rax
                         pop rdi
                         pop rsi
@@ -338,12 +743,12 @@ pub fn compile<S: Write>(
                         syscall
                         push rax
                     "},
-                op
+                comment
             )?,
             Syscall5 => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         pop rax
                         pop rdi
                         pop rsi
@@ -353,12 +758,12 @@ pub fn compile<S: Write>(
                         syscall
                         push rax
                     "},
-                op
+                comment
             )?,
             Syscall6 => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         pop rax
                         pop rdi
                         pop rsi
@@ -369,54 +774,124 @@ pub fn compile<S: Write>(
                         syscall
                         push rax
                     "},
-                op
+                comment
+            )?,
+            SplitErrno => write!(
+                sink,
+                indoc! {"
+                    {}
+                        pop rax
+                        mov rcx, rax
+                        neg rcx
+                        xor rbx, rbx
+                        cmp rax, -4095
+                        setae bl
+                        cmovae rax, rcx
+                        push rax
+                        push rbx
+                    "},
+                comment
             )?,
 
             Argc => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         mov rax, [argc]
                         push rax
                     "},
-                op
+                comment
             )?,
             Argv => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                     mov rax, [argv]
                     push rax
                     "},
-                op
+                comment
             )?,
 
             Sub => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         pop rax
                         pop rbx
                         sub rbx, rax
                         push rbx
                     "},
-                op
+                comment
             )?,
             Add => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         pop rax
                         pop rbx
                         add rbx, rax
                         push rbx
                     "},
-                op
+                comment
+            )?,
+            BitAnd | BitOr | BitXor => write!(
+                sink,
+                indoc! {"
+                    {}
+                        pop rax
+                        {} [rsp], rax
+                    "},
+                comment,
+                match op {
+                    BitAnd => "and",
+                    BitOr => "or",
+                    _ => "xor",
+                }
             )?,
+            BitNot => write!(
+                sink,
+                indoc! {"
+                    {}
+                        not qword [rsp]
+                    "},
+                comment
+            )?,
+            Shl | Shr | Sar => write!(
+                sink,
+                indoc! {"
+                    {}
+                        pop rcx
+                        {} qword [rsp], cl
+                    "},
+                comment,
+                match op {
+                    Shl => "shl",
+                    Shr => "shr",
+                    _ => "sar",
+                }
+            )?,
+            AddImm(k) | SubImm(k) => {
+                let (load, k) = imm_operand(*k, "rax");
+                write!(
+                    sink,
+                    indoc! {"
+                        {}
+                        {}    {} qword [rsp], {}
+                        "},
+                    comment,
+                    load,
+                    if matches!(op, AddImm(_)) {
+                        "add"
+                    } else {
+                        "sub"
+                    },
+                    k
+                )?
+            }
             Divmod => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         xor rdx, rdx
                         pop rbx
                         pop rax
@@ -424,24 +899,37 @@ pub fn compile<S: Write>(
                         push rax
                         push rdx
                     "},
-                op
+                comment
+            )?,
+            IDivmod => write!(
+                sink,
+                indoc! {"
+                    {}
+                        pop rbx
+                        pop rax
+                        cqo
+                        idiv rbx
+                        push rax
+                        push rdx
+                    "},
+                comment
             )?,
             Mul => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         pop rax
                         pop rbx
                         mul rbx
                         push rax
                     "},
-                op
+                comment
             )?,
 
             Ne => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         mov rcx, 0
                         mov rdx, 1
                         pop rbx
@@ -450,12 +938,12 @@ pub fn compile<S: Write>(
                         cmovne rcx, rdx
                         push rcx
                     "},
-                op
+                comment
             )?,
             Lt => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         mov rcx, 0
                         mov rdx, 1
                         pop rbx
@@ -464,12 +952,12 @@ pub fn compile<S: Write>(
                         cmovl rcx, rdx
                         push rcx
                     "},
-                op
+                comment
             )?,
             Ge => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         mov rcx, 0
                         mov rdx, 1
                         pop rbx
@@ -478,12 +966,12 @@ pub fn compile<S: Write>(
                         cmovge rcx, rdx
                         push rcx
                     "},
-                op
+                comment
             )?,
             Le => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         mov rcx, 0
                         mov rdx, 1
                         pop rbx
@@ -492,12 +980,12 @@ pub fn compile<S: Write>(
                         cmovle rcx, rdx
                         push rcx
                     "},
-                op
+                comment
             )?,
             Gt => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         mov rcx, 0
                         mov rdx, 1
                         pop rbx
@@ -506,12 +994,12 @@ pub fn compile<S: Write>(
                         cmovg rcx, rdx
                         push rcx
                     "},
-                op
+                comment
             )?,
             Eq => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         mov rcx, 0
                         mov rdx, 1
                         pop rbx
@@ -520,9 +1008,45 @@ pub fn compile<S: Write>(
                         cmove rcx, rdx
                         push rcx
                     "},
-                op
+                comment
+            )?,
+            LtU | LeU | GtU | GeU => write!(
+                sink,
+                indoc! {"
+                    {}
+                        mov rcx, 0
+                        mov rdx, 1
+                        pop rbx
+                        pop rax
+                        cmp rax, rbx
+                        cmov{} rcx, rdx
+                        push rcx
+                    "},
+                comment,
+                match op {
+                    LtU => "b",
+                    LeU => "be",
+                    GtU => "a",
+                    _ => "ae",
+                }
             )?,
 
+            Return if options.checked => write!(
+                sink,
+                indoc! {"
+                    ; pop trace frame
+                        dec QWORD [trace_depth]
+                    ; load return adderss
+                        mov QWORD rax, [ret_stack_rsp]
+                        mov QWORD rdi, [rax]
+                        mov rax, 8
+                        add [ret_stack_rsp], rax
+                        push rdi
+                    {}
+                        ret
+                    "},
+                comment
+            )?,
             Return => write!(
                 sink,
                 indoc! {"
@@ -532,28 +1056,105 @@ pub fn compile<S: Write>(
                         mov rax, 8
                         add [ret_stack_rsp], rax
                         push rdi
-                    ; {:?}
+                    {}
                         ret
                     "},
-                op
+                comment
             )?,
             Call(p) => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         call {}
                     "},
-                op, p
+                comment, p
             )?,
+            PushProc(p) => write!(
+                sink,
+                indoc! {"
+                    {}
+                        push {}
+                    "},
+                comment, p
+            )?,
+            CallIndirect(ins, outs) if options.checked => write!(
+                sink,
+                indoc! {"
+                    {}
+                        pop rax
+                    ; remember where the signature leaves the stack, if there is room
+                        lea rcx, [rsp + 8 * {}]
+                        mov rbx, 8
+                        sub [ret_stack_rsp], rbx
+                        mov QWORD rbx, [ret_stack_rsp]
+                        cmp rbx, ret_stack
+                        jb trap_stack_overflow
+                        mov QWORD [rbx], rcx
+                        call rax
+                    ; check the proc pointed to had that signature
+                        mov QWORD rbx, [ret_stack_rsp]
+                        cmp rsp, [rbx]
+                        jne trap_signature
+                        mov rbx, 8
+                        add [ret_stack_rsp], rbx
+                    "},
+                comment,
+                *ins as i64 - *outs as i64
+            )?,
+            CallIndirect(..) => write!(
+                sink,
+                indoc! {"
+                    {}
+                        pop rax
+                        call rax
+                    "},
+                comment
+            )?,
+            CallHost(name, _, _) => {
+                return std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    format!("Host proc `{}` can only be called from the evaluator", name),
+                )
+                .error()
+            }
             Exit => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         pop rdi
                         mov rax, 60
                         syscall
                     "},
-                op
+                comment
+            )?,
+            Proc(l) if options.checked => write!(
+                sink,
+                indoc! {"
+                    {}:
+                    ; save return address, if there is room
+                        pop rdi
+                        mov rax, 8
+                        sub [ret_stack_rsp], rax
+                        mov QWORD rax, [ret_stack_rsp]
+                        cmp rax, ret_stack
+                        jb trap_stack_overflow
+                        mov QWORD [rax], rdi
+                    ; push trace frame
+                        mov rax, [trace_depth]
+                        mov rbx, {}
+                        cmp rax, rbx
+                        cmova rax, rbx
+                        mov QWORD [trace_stack + 8 * rax], {}
+                        inc QWORD [trace_depth]
+                    ; check the values it pushes fit
+                        lea rax, [rsp - 8 * {}]
+                        cmp rax, [data_stack_limit]
+                        jb trap_stack_overflow
+                    "},
+                l,
+                TRACE_FRAMES - 1,
+                proc_names.iter().position(|p| p == l).unwrap(),
+                program.depth(l)
             )?,
             Proc(l) => write!(
                 sink,
@@ -578,41 +1179,262 @@ pub fn compile<S: Write>(
             JumpF(l) => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         pop rax
                         test rax, rax
                         jz {}
                     "},
-                op, l
+                comment, l
             )?,
+            JumpT(l) => write!(
+                sink,
+                indoc! {"
+                    {}
+                        pop rax
+                        test rax, rax
+                        jnz {}
+                    "},
+                comment, l
+            )?,
+            CmpImmBranch(cmp, k, l) => {
+                let (load, k) = imm_operand(*k, "rbx");
+                write!(
+                    sink,
+                    indoc! {"
+                        {}
+                            pop rax
+                        {}    cmp rax, {}
+                            j{} {}
+                        "},
+                    comment,
+                    load,
+                    k,
+                    condition(*cmp),
+                    l
+                )?
+            }
             Jump(l) => write!(
                 sink,
                 indoc! {"
-                    ; {:?}
+                    {}
                         jmp {}
                     "},
-                op, l
+                comment, l
             )?,
+            Loc(_) => {}
             Dump => {}
-            JumpT(_) => todo!("Jump if true"),
         }
     }
+    if traps {
+        write!(
+            sink,
+            indoc! {"
+                trap_misaligned:
+                    mov rsi, trap_misaligned_msg
+                    mov rdx, trap_misaligned_msg_len
+                    jmp trap
+                trap_stack_overflow:
+                    mov rsi, trap_stack_overflow_msg
+                    mov rdx, trap_stack_overflow_msg_len
+                    jmp trap
+                trap_signature:
+                    mov rsi, trap_signature_msg
+                    mov rdx, trap_signature_msg_len
+                trap:
+                    mov rax, 1
+                    mov rdi, 2
+                    syscall
+                ; print the shadow call stack, innermost frame first
+                    mov r12, [trace_depth]
+                    mov rbx, {}
+                    cmp r12, rbx
+                    cmova r12, rbx
+                trap_backtrace:
+                    test r12, r12
+                    jz trap_exit
+                    dec r12
+                    mov rax, [trace_stack + 8 * r12]
+                    mov rsi, [trace_names + 8 * rax]
+                    mov rdx, [trace_name_lens + 8 * rax]
+                    mov rax, 1
+                    mov rdi, 2
+                    syscall
+                    jmp trap_backtrace
+                trap_exit:
+                    mov rdi, 1
+                    mov rax, 60
+                    syscall
+            "},
+            TRACE_FRAMES
+        )?;
+    }
+    if hashes {
+        // must agree with `intrinsics::hash_str`, which folds literals
+        write!(
+            sink,
+            indoc! {"
+                hash_str:
+                    mov rax, {:#x}
+                    mov rdx, {:#x}
+                hash_str_loop:
+                    test rcx, rcx
+                    jz hash_str_done
+                    movzx rbx, BYTE [rsi]
+                    xor rax, rbx
+                    imul rax, rdx
+                    inc rsi
+                    dec rcx
+                    jmp hash_str_loop
+                hash_str_done:
+                    ret
+            "},
+            HASH_OFFSET, HASH_PRIME
+        )?;
+    }
+    if finds {
+        // 16 bytes at a time while there are that many left, then one at a time
+        write!(
+            sink,
+            indoc! {"
+                find_byte:
+                    movzx ebx, al
+                    mov rdx, 0x0101010101010101
+                    mov rax, rbx
+                    imul rax, rdx
+                    movq xmm1, rax
+                    punpcklqdq xmm1, xmm1
+                    mov rdi, rsi
+                find_byte_wide:
+                    cmp rcx, 16
+                    jb find_byte_tail
+                    movdqu xmm0, [rdi]
+                    pcmpeqb xmm0, xmm1
+                    pmovmskb eax, xmm0
+                    test rax, rax
+                    jnz find_byte_hit
+                    add rdi, 16
+                    sub rcx, 16
+                    jmp find_byte_wide
+                find_byte_hit:
+                    bsf eax, eax
+                    add rdi, rax
+                    jmp find_byte_found
+                find_byte_tail:
+                    test rcx, rcx
+                    jz find_byte_none
+                    cmp [rdi], bl
+                    je find_byte_found
+                    inc rdi
+                    dec rcx
+                    jmp find_byte_tail
+                find_byte_none:
+                    xor rdx, rdx
+                    jmp find_byte_done
+                find_byte_found:
+                    mov rdx, 1
+                find_byte_done:
+                    mov rax, rdi
+                    sub rax, rsi
+                    ret
+            "}
+        )?;
+    }
     write!(
         sink,
         indoc! {"
             section .data
         "}
     )?;
-    for (i, str) in strings.iter().enumerate() {
+    if traps {
         write!(
             sink,
             indoc! {"
+                trap_misaligned_msg:
+                    db \"Misaligned memory access\", 10
+                trap_misaligned_msg_len: equ $ - trap_misaligned_msg
+                trap_stack_overflow_msg:
+                    db \"Stack overflow\", 10
+                trap_stack_overflow_msg_len: equ $ - trap_stack_overflow_msg
+                trap_signature_msg:
+                    db \"Indirect call doesn't match its `call-like` signature\", 10
+                trap_signature_msg_len: equ $ - trap_signature_msg
+            "}
+        )?;
+        for (i, name) in proc_names.iter().enumerate() {
+            write!(
+                sink,
+                indoc! {"
+                    trace_name_{}:
+                        db \"  at \", {}, 10
+                    "},
+                i,
+                demangle(name)
+                    .bytes()
+                    .map(|b| b.to_string())
+                    .intersperse(",".to_string())
+                    .collect::<String>()
+            )?;
+        }
+        writeln!(sink, "trace_names:")?;
+        for i in 0..proc_names.len() {
+            writeln!(sink, "    dq trace_name_{}", i)?;
+        }
+        writeln!(sink, "trace_name_lens:")?;
+        for name in &proc_names {
+            writeln!(sink, "    dq {}", demangle(name).len() + 6)?;
+        }
+    }
+    let tables = &program.tables;
+    let layout = data_layout(mems, &strings, tables, &options.placements);
+    for item in &layout {
+        let i = match item.datum {
+            Datum::Str(i) => i,
+            _ => continue,
+        };
+        let placed = item.section != ".data";
+        if placed {
+            writeln!(sink, "section .data.str_{} progbits alloc noexec write", i)?;
+        }
+        write!(
+            sink,
+            indoc! {"
+                align {}, db 0
                 str_{}:
                     db {}
                 "},
+            item.align,
             i,
             {
-                str.bytes()
+                strings[i]
+                    .bytes()
+                    .map(|b| b.to_string())
+                    .intersperse(",".to_string())
+                    .collect::<String>()
+            }
+        )?;
+        if placed {
+            writeln!(sink, "section .data")?;
+        }
+    }
+    for item in &layout {
+        let name = match &item.datum {
+            Datum::Table(name) => name,
+            _ => continue,
+        };
+        write!(
+            sink,
+            indoc! {"
+                section {} progbits alloc noexec nowrite align=8
+                align {}, db 0
+                table_{}:
+                    db {}
+                "},
+            item.section,
+            item.align,
+            name,
+            {
+                tables[name]
+                    .iter()
                     .map(|b| b.to_string())
                     .intersperse(",".to_string())
                     .collect::<String>()
@@ -622,29 +1444,203 @@ pub fn compile<S: Write>(
     write!(
         sink,
         indoc! {"
-            section .bss
+            section .bss align=8
                 ret_stack_rsp: resq 1
-                ret_stack: resb 65536
+                ret_stack: resb {0}
                 ret_stack_end:
                 locals_stack_sp: resq 1
-                locals_stack: resb 65536
+                locals_stack: resb {0}
                 locals_stack_end:
                 escaping_stack_sp: resq 1
-                escaping_stack: resb 65536
+                escaping_stack: resb {0}
                 escaping_stack_end:
                 argc: resq 1
                 argv: resq 1
         "},
+        options.stack_size
     )?;
-    for (name, size) in mems {
+    if options.checked {
         write!(
             sink,
             indoc! {"
+                data_stack_limit: resq 1
+                trace_depth: resq 1
+                trace_stack: resq {}
+            "},
+            TRACE_FRAMES
+        )?;
+    }
+    // the runtime data before the mems keeps the section aligned to 8
+    for item in &layout {
+        let name = match &item.datum {
+            Datum::Mem(name) => name,
+            _ => continue,
+        };
+        let placed = item.section != ".bss";
+        if placed {
+            writeln!(
+                sink,
+                "section .bss.mem_{} nobits alloc noexec write align={}",
+                name, item.align
+            )?;
+        }
+        write!(
+            sink,
+            indoc! {"
+            alignb {}
             mem_{}:
                 resb {}
         "},
-            name, size
+            item.align, name, item.size
         )?;
+        if placed {
+            writeln!(sink, "section .bss")?;
+        }
     }
     ().okay()
 }
+
+/// Comments the instructions of each op, as verbose as `Options::comments` asks
+pub(crate) struct Annotator {
+    level: Comments,
+    /// Line comment marker of the assembler
+    marker: &'static str,
+    sources: SourceMap,
+    remaps: Vec<PathRemap>,
+    span: Option<Span>,
+    /// File and zero based number of the last source line written
+    line: Option<(PathBuf, usize)>,
+}
+
+impl Annotator {
+    pub fn new(options: &Options, marker: &'static str) -> Self {
+        Self {
+            level: options.comments,
+            marker,
+            sources: SourceMap::new(),
+            remaps: options.remap_path_prefix.clone(),
+            span: None,
+            line: None,
+        }
+    }
+
+    /// The comment line above the instructions of `op`, source lines are written right away
+    pub fn annotate(&mut self, op: &Op, sink: &mut impl Write) -> std::io::Result<String> {
+        if let Op::Loc(span) = op {
+            if self.level == Comments::Source {
+                self.source_line(span, sink)?;
+            }
+            self.span = span.clone().some();
+        }
+        match (self.level, &self.span) {
+            (Comments::None, _) => String::new(),
+            (Comments::Spans, Some(span)) => {
+                let _ = self.sources.load(&span.file);
+                let file = PathRemap::apply(&self.remaps, &span.file);
+                let location = match self.sources.line_col(span) {
+                    Some((line, col)) => format!("{}:{}:{}", file.display(), line, col),
+                    None => format!("{}[{}..{}]", file.display(), span.start, span.end),
+                };
+                format!("{} {:?} @ {}", self.marker, op, location)
+            }
+            _ => format!("{} {:?}", self.marker, op),
+        }
+        .okay()
+    }
+
+    fn source_line(&mut self, span: &Span, sink: &mut impl Write) -> std::io::Result<()> {
+        let source = match self.sources.load(&span.file) {
+            Ok(source) => source,
+            Err(_) => return ().okay(),
+        };
+        let (line, _) = source.line_col(span.start);
+        let at = (span.file.clone(), line);
+        if self.line.as_ref() == Some(&at) {
+            return ().okay();
+        }
+        writeln!(
+            sink,
+            "{} {}:{}: {}",
+            self.marker,
+            PathRemap::apply(&self.remaps, &span.file).display(),
+            line + 1,
+            source.line(line).trim()
+        )?;
+        self.line = at.some();
+        ().okay()
+    }
+}
+
+#[test]
+fn test_comments() {
+    let tmp = crate::build::TempDir::new().unwrap();
+    let file = tmp.path.join("main.rh");
+    std::fs::write(&file, "proc main : u64 do\n    1 2 +\nend\n").unwrap();
+    let ops = [
+        Op::Loc(Span::new(&file, 23, 24)),
+        Op::Push(IConst::U64(1)),
+        Op::Loc(Span::new(&file, 25, 26)),
+        Op::Push(IConst::U64(2)),
+        Op::Loc(Span::new(&file, 27, 28)),
+        Op::Add,
+    ];
+    let annotate = |comments| {
+        let options = Options {
+            comments,
+            ..Default::default()
+        };
+        let mut annotator = Annotator::new(&options, ";");
+        let mut sink = Vec::new();
+        for op in &ops {
+            let comment = annotator.annotate(op, &mut sink).unwrap();
+            writeln!(sink, "{}", comment).unwrap();
+        }
+        String::from_utf8(sink).unwrap()
+    };
+    assert!(annotate(Comments::None).trim().is_empty());
+    let written = annotate(Comments::Ops);
+    assert!(written.contains("; Push(U64(2))\n; Loc("), "{}", written);
+    assert!(!written.contains("main.rh:"), "{}", written);
+    // the line the three ops come from is written once, above the first
+    let written = annotate(Comments::Source);
+    assert_eq!(
+        written.matches("main.rh:2: 1 2 +\n").count(),
+        1,
+        "{}",
+        written
+    );
+    assert!(
+        written.find("main.rh:2:") < written.find("; Push(U64(1))"),
+        "{}",
+        written
+    );
+}
+
+#[test]
+fn test_remap_path_prefix() {
+    let tmp = crate::build::TempDir::new().unwrap();
+    let file = tmp.path.join("main.rh");
+    std::fs::write(&file, "proc main : u64 do\n    0\nend\n").unwrap();
+    let mut options = Options {
+        remap_path_prefix: vec![format!("{}=src", tmp.path.display()).parse().unwrap()],
+        ..Default::default()
+    };
+    let loc = Op::Loc(Span::new(&file, 23, 24));
+    let mut sink = Vec::new();
+    for (level, expected) in [
+        (Comments::Spans, "src/main.rh:2:5"),
+        (Comments::Source, "src/main.rh:2: 0"),
+    ] {
+        options.comments = level;
+        let comment = Annotator::new(&options, ";")
+            .annotate(&loc, &mut sink)
+            .unwrap();
+        let written = String::from_utf8(std::mem::take(&mut sink)).unwrap() + &comment;
+        assert!(written.contains(expected), "{}", written);
+        assert!(
+            !written.contains(&*tmp.path.to_string_lossy()),
+            "{}",
+            written
+        );
+    }
+}
@@ -0,0 +1,514 @@
+use crate::{
+    emit::{
+        symbols::{data_layout, Datum},
+        Annotator,
+    },
+    hir::PrintFormat,
+    iconst::IConst,
+    intrinsics::{HASH_OFFSET, HASH_PRIME},
+    lir::{demangle, Cmp, Op, Program},
+    session::Session,
+};
+use indoc::indoc;
+use somok::Somok;
+use std::io::{BufWriter, Write};
+
+/// Frames kept by the shadow call stack of checked builds, deeper calls share the last slot
+const TRACE_FRAMES: usize = 256;
+
+/// Stacks and the macros ops are written with
+const PRELUDE: &str = indoc! {r#"
+    #if defined(__linux__)
+    #define _GNU_SOURCE
+    #endif
+    #include <errno.h>
+    #include <stdint.h>
+    #include <stdio.h>
+    #include <stdlib.h>
+    #include <string.h>
+    #include <sys/stat.h>
+    #include <unistd.h>
+
+    typedef uint64_t u64;
+
+    static u64 stack[1 << 20];
+    static u64 *sp = stack;
+    static u64 bindings[STACK_SIZE / 8];
+    static u64 *bp = bindings;
+    static _Alignas(8) unsigned char locals[STACK_SIZE];
+    static unsigned char *locals_sp = locals + sizeof locals;
+    static _Alignas(8) unsigned char escaping[STACK_SIZE];
+    static unsigned char *escaping_sp = escaping + sizeof escaping;
+    static u64 argc, argv;
+
+    #define PUSH(x) do { u64 x_ = (u64)(x); *sp++ = x_; } while (0)
+    #define PUSH_PTR(p) PUSH((uintptr_t)(p))
+    #define POP() (*--sp)
+    #define TOP(depth) (sp[-1 - (depth)])
+    #define PTR(x) ((void *)(uintptr_t)(x))
+    #define READ(T) do { T v_; memcpy(&v_, PTR(TOP(0)), sizeof v_); TOP(0) = v_; } while (0)
+    #define WRITE(T) do { T v_ = (T)TOP(1); memcpy(PTR(TOP(0)), &v_, sizeof v_); sp -= 2; } while (0)
+    #define ALIGNED(T) do { if (TOP(0) % sizeof(T)) trap_misaligned(); } while (0)
+    #define BINARY(expr) do { u64 b = POP(), a = POP(); PUSH(expr); } while (0)
+    #define SIGNED(x) ((int64_t)(x))
+    #define SYSCALL(count) do { \
+        u64 n_ = POP(), a_[6] = {0}; \
+        for (int i_ = 0; i_ < (count); i_++) a_[i_] = POP(); \
+        PUSH(rh_syscall(n_, a_)); \
+    } while (0)
+
+    static inline u64 bswap(u64 v, int bytes)
+    {
+        u64 r = 0;
+        for (int i = 0; i < bytes; i++, v >>= 8)
+            r = r << 8 | (v & 0xff);
+        return r;
+    }
+
+    // libc reports errors through `errno`, rotth expects them the way the kernel does
+    static inline u64 rh_result(long r)
+    {
+        return r < 0 ? (u64)-(int64_t)errno : (u64)r;
+    }
+
+    // syscalls are numbered as on x86-64 Linux, the common ones go through libc
+    static inline u64 rh_syscall(u64 n, const u64 *a)
+    {
+        switch (n) {
+        case 0: return rh_result(read((int)a[0], PTR(a[1]), a[2]));
+        case 1: return rh_result(write((int)a[0], PTR(a[1]), a[2]));
+        case 3: return rh_result(close((int)a[0]));
+        case 8: return rh_result(lseek((int)a[0], (off_t)a[1], (int)a[2]));
+        case 32: return rh_result(dup((int)a[0]));
+        case 33: return rh_result(dup2((int)a[0], (int)a[1]));
+        case 39: return rh_result(getpid());
+        case 60:
+        case 231: exit((int)a[0]);
+        case 79: return getcwd(PTR(a[0]), a[1]) ? (u64)strlen(PTR(a[0])) + 1 : (u64)-(int64_t)errno;
+        case 80: return rh_result(chdir(PTR(a[0])));
+        case 82: return rh_result(rename(PTR(a[0]), PTR(a[1])));
+        case 83: return rh_result(mkdir(PTR(a[0]), (int)a[1]));
+        case 84: return rh_result(rmdir(PTR(a[0])));
+        case 87: return rh_result(unlink(PTR(a[0])));
+        default:
+    #if defined(__linux__) && defined(__x86_64__)
+            return rh_result(syscall((long)n, a[0], a[1], a[2], a[3], a[4], a[5]));
+    #else
+            return (u64)-ENOSYS;
+    #endif
+        }
+    }
+"#};
+
+/// `print` and `print_fmt`, the same routines the assembly runtimes provide
+const PRINT: &str = indoc! {r#"
+
+    void print_fmt(u64 value, u64 radix, u64 is_signed, u64 width)
+    {
+        static const char digits[] = "0123456789abcdef";
+        char buffer[32], *p = buffer + sizeof buffer;
+        int negative = is_signed && SIGNED(value) < 0;
+        u64 count = 0;
+        if (negative)
+            value = -value;
+        if (width > MAX_WIDTH)
+            width = MAX_WIDTH;
+        *--p = '\n';
+        do {
+            *--p = digits[value % radix];
+            value /= radix;
+            count++;
+        } while (value);
+        for (; count < width; count++)
+            *--p = '0';
+        if (negative)
+            *--p = '-';
+        write(1, p, buffer + sizeof buffer - p);
+    }
+
+    void print(u64 value)
+    {
+        print_fmt(value, 10, 0, 0);
+    }
+"#};
+
+/// Hash routine, must agree with `intrinsics::hash_str`, which folds literals
+const HASH_STR: &str = indoc! {r#"
+
+    static u64 hash_str(const unsigned char *s, u64 len)
+    {
+        u64 hash = HASH_OFFSET;
+        while (len--)
+            hash = (hash ^ *s++) * HASH_PRIME;
+        return hash;
+    }
+"#};
+
+/// Misalignment trap, printing the shadow call stack
+const TRAPS: &str = indoc! {r#"
+
+    void trap_misaligned(void)
+    {
+        static const char msg[] = "Misaligned memory access\n";
+        u64 depth = trace_depth < TRACE_FRAMES ? trace_depth : TRACE_FRAMES;
+        write(2, msg, sizeof msg - 1);
+        while (depth--) {
+            const char *name = trace_names[trace_stack[depth]];
+            write(2, "  at ", 5);
+            write(2, name, strlen(name));
+            write(2, "\n", 1);
+        }
+        exit(1);
+    }
+"#};
+
+/// Translates the program to a single C file, which needs nothing but libc
+pub fn compile<S: Write>(
+    program: Program,
+    session: &Session,
+    mut sink: BufWriter<S>,
+) -> std::io::Result<()> {
+    let ops = program.ops;
+    let mems = &program.mems;
+    use Op::*;
+    let options = &session.options;
+    let strings = session.strings();
+    let check_alignment = options.checked && options.strict_alignment;
+    let proc_names = ops
+        .iter()
+        .filter_map(|op| match op {
+            Proc(l) => l.clone().some(),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let hashes = ops.iter().any(|op| matches!(op, HashStr));
+    let runtime = options.runtime;
+    let traps = options.checked && runtime.traps;
+
+    writeln!(sink, "#define MAX_WIDTH {}", PrintFormat::MAX_WIDTH)?;
+    writeln!(sink, "#define TRACE_FRAMES {}", TRACE_FRAMES)?;
+    writeln!(sink, "#define STACK_SIZE {}", options.stack_size)?;
+    writeln!(sink, "#define HASH_OFFSET {:#x}u", HASH_OFFSET)?;
+    writeln!(sink, "#define HASH_PRIME {:#x}u", HASH_PRIME)?;
+    sink.write_all(PRELUDE.as_bytes())?;
+    writeln!(sink)?;
+    writeln!(sink, "void print(u64 value);")?;
+    writeln!(
+        sink,
+        "void print_fmt(u64 value, u64 radix, u64 is_signed, u64 width);"
+    )?;
+    if options.checked {
+        // the user's trap handler gets the shadow call stack to print a backtrace
+        let linkage = if runtime.traps { "static " } else { "" };
+        writeln!(sink, "void trap_misaligned(void);")?;
+        writeln!(sink, "{}u64 trace_depth;", linkage)?;
+        writeln!(sink, "{}u64 trace_stack[TRACE_FRAMES];", linkage)?;
+        writeln!(sink, "{}const char *const trace_names[] = {{", linkage)?;
+        for name in &proc_names {
+            writeln!(sink, "    {},", literal(demangle(name).as_bytes()))?;
+        }
+        writeln!(sink, "    0,\n}};")?;
+    }
+    for name in program.extern_mems.keys() {
+        writeln!(
+            sink,
+            "extern unsigned char {0}[];\n#define mem_{1} {0}",
+            name,
+            ident(name)
+        )?;
+    }
+    let tables = &program.tables;
+    for item in data_layout(mems, &strings, tables, &Default::default()) {
+        match &item.datum {
+            Datum::Str(i) => writeln!(
+                sink,
+                "static const char str_{}[] = {};",
+                i,
+                literal(strings[*i].as_bytes())
+            )?,
+            Datum::Table(name) => writeln!(
+                sink,
+                "static const _Alignas({}) unsigned char table_{}[] = {{{}}};",
+                item.align,
+                ident(name),
+                tables[name]
+                    .iter()
+                    .map(|b| b.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            )?,
+            Datum::Mem(name) => writeln!(
+                sink,
+                "static _Alignas({}) unsigned char mem_{}[{}];",
+                item.align,
+                ident(name),
+                item.size.max(1)
+            )?,
+        }
+    }
+    for name in &proc_names {
+        writeln!(sink, "static void {}(void);", proc(name))?;
+    }
+    if runtime.print {
+        sink.write_all(PRINT.as_bytes())?;
+    }
+    if hashes {
+        sink.write_all(HASH_STR.as_bytes())?;
+    }
+    if traps {
+        sink.write_all(TRAPS.as_bytes())?;
+    }
+
+    write!(
+        sink,
+        indoc! {"
+
+            int main(int c, char **v)
+            {{
+                argc = (u64)c;
+                argv = (u64)(uintptr_t)v;
+        "}
+    )?;
+    let mut annotator = Annotator::new(options, "//");
+    for op in ops {
+        let comment = annotator.annotate(&op, &mut sink)?;
+        match &op {
+            Proc(l) => {
+                writeln!(sink, "}}\n\nstatic void {}(void)\n{{", proc(l))?;
+                if options.checked {
+                    let i = proc_names.iter().position(|p| p == l).unwrap();
+                    writeln!(
+                        sink,
+                        "    trace_stack[trace_depth < TRACE_FRAMES ? trace_depth : TRACE_FRAMES - 1] = {};",
+                        i
+                    )?;
+                    writeln!(sink, "    trace_depth++;")?;
+                }
+                continue;
+            }
+            Label(l) => {
+                writeln!(sink, "{}:;", label(l))?;
+                continue;
+            }
+            Loc(_) | Dump => continue,
+            CallHost(name, _, _) => {
+                return std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    format!("Host proc `{}` can only be called from the evaluator", name),
+                )
+                .error()
+            }
+            _ => (),
+        }
+        let code = match &op {
+            Push(c) => match c {
+                IConst::Bool(b) => format!("PUSH({});", *b as u64),
+                IConst::Char(c) => format!("PUSH({});", *c as u64),
+                IConst::U64(u) => format!("PUSH({}u);", u),
+                IConst::I64(i) => format!("PUSH({}u);", *i as u64),
+                IConst::Ptr(p) => format!("PUSH({}u);", p),
+                IConst::Int(_) | IConst::Str(_) => unreachable!(),
+            },
+            PushStr(i) => format!("PUSH({}); PUSH_PTR(str_{});", strings[*i].len(), i),
+            PushMem(nm) => format!("PUSH_PTR(mem_{});", ident(nm)),
+            PushTable(nm) => format!("PUSH_PTR(table_{});", ident(nm)),
+            Drop => "sp--;".to_string(),
+            Dup => "PUSH(TOP(0));".to_string(),
+            Swap => "{ u64 a = POP(), b = POP(); PUSH(a); PUSH(b); }".to_string(),
+            Over => "PUSH(TOP(1));".to_string(),
+            Pick(depth) => format!("PUSH(TOP({}));", depth),
+            Roll(depth) => format!(
+                "{{ u64 v = TOP({0}); memmove(sp - 1 - {0}, sp - {0}, {0} * sizeof *sp); TOP(0) = v; }}",
+                depth
+            ),
+
+            Bind => "*bp++ = POP();".to_string(),
+            UseBinding(offset) => format!("PUSH(bp[-1 - {}]);", offset),
+            Unbind => "bp--;".to_string(),
+
+            ReadU64 | ReadU32 | ReadU16 | ReadU8 | WriteU64 | WriteU32 | WriteU16 | WriteU8 => {
+                let (access, ty) = match &op {
+                    ReadU64 => ("READ", "uint64_t"),
+                    ReadU32 => ("READ", "uint32_t"),
+                    ReadU16 => ("READ", "uint16_t"),
+                    ReadU8 => ("READ", "uint8_t"),
+                    WriteU64 => ("WRITE", "uint64_t"),
+                    WriteU32 => ("WRITE", "uint32_t"),
+                    WriteU16 => ("WRITE", "uint16_t"),
+                    _ => ("WRITE", "uint8_t"),
+                };
+                if check_alignment && ty != "uint8_t" {
+                    format!("ALIGNED({1}); {0}({1});", access, ty)
+                } else {
+                    format!("{}({});", access, ty)
+                }
+            }
+
+            Bswap64 => "TOP(0) = bswap(TOP(0), 8);".to_string(),
+            Bswap32 => "TOP(0) = bswap(TOP(0), 4);".to_string(),
+            Bswap16 => "TOP(0) = bswap(TOP(0), 2);".to_string(),
+            ZeroExtend(bits) => format!("TOP(0) = (uint{}_t)TOP(0);", bits),
+            SignExtend(bits) => format!("TOP(0) = (uint64_t)(int64_t)(int{}_t)TOP(0);", bits),
+
+            ReserveEscaping(n) => format!("escaping_sp -= {};", n),
+            PushEscaping(n) => format!("PUSH_PTR(escaping_sp + {});", n),
+            ReserveLocals(n) => format!("locals_sp -= {};", n),
+            FreeLocals(n) => format!("locals_sp += {};", n),
+            PushLvar(o) => format!("PUSH_PTR(locals_sp + {});", o),
+
+            Print(PrintFormat::Unsigned) => "print(POP());".to_string(),
+            Print(format) => format!(
+                "print_fmt(POP(), {}, {}, 0);",
+                format.radix(),
+                format.is_signed() as u64
+            ),
+            PrintPadded(format) => format!(
+                "{{ u64 width = POP(); print_fmt(POP(), {}, {}, width); }}",
+                format.radix(),
+                format.is_signed() as u64
+            ),
+            HashStr => "{ u64 p = POP(); TOP(0) = hash_str(PTR(p), TOP(0)); }".to_string(),
+            Copy16 => "{ u64 dst = POP(); memmove(PTR(dst), PTR(POP()), 16); }".to_string(),
+            Cmp16 => concat!(
+                "{ const unsigned char *b = PTR(POP()), *a = PTR(TOP(0)); u64 mask = 0; ",
+                "for (int i = 0; i < 16; i++) mask |= (u64)(a[i] == b[i]) << i; TOP(0) = mask; }"
+            )
+            .to_string(),
+            Splat16 => "{ u64 dst = POP(); memset(PTR(dst), (unsigned char)POP(), 16); }".to_string(),
+            FindByte => concat!(
+                "{ u64 byte = POP(), len = TOP(0); const unsigned char *p = PTR(TOP(1)), ",
+                "*hit = memchr(p, (unsigned char)byte, len); ",
+                "TOP(1) = hit ? (u64)(hit - p) : len; TOP(0) = hit != NULL; }"
+            )
+            .to_string(),
+
+            Syscall0 => "SYSCALL(0);".to_string(),
+            Syscall1 => "SYSCALL(1);".to_string(),
+            Syscall2 => "SYSCALL(2);".to_string(),
+            Syscall3 => "SYSCALL(3);".to_string(),
+            Syscall4 => "SYSCALL(4);".to_string(),
+            Syscall5 => "SYSCALL(5);".to_string(),
+            Syscall6 => "SYSCALL(6);".to_string(),
+            // the kernel reports errors as -errno in the last page of the address space
+            SplitErrno => {
+                "{ u64 v = POP(); int e = v > (u64)-4096; PUSH(e ? -v : v); PUSH(e); }".to_string()
+            }
+
+            Argc => "PUSH(argc);".to_string(),
+            Argv => "PUSH(argv);".to_string(),
+
+            Add => "BINARY(a + b);".to_string(),
+            Sub => "BINARY(a - b);".to_string(),
+            Mul => "BINARY(a * b);".to_string(),
+            BitAnd => "BINARY(a & b);".to_string(),
+            BitOr => "BINARY(a | b);".to_string(),
+            BitXor => "BINARY(a ^ b);".to_string(),
+            BitNot => "TOP(0) = ~TOP(0);".to_string(),
+            // counts are taken modulo 64 like the hardware does, C leaves larger ones undefined
+            Shl => "BINARY(a << (b & 63));".to_string(),
+            Shr => "BINARY(a >> (b & 63));".to_string(),
+            Sar => "BINARY((u64)((int64_t)a >> (b & 63)));".to_string(),
+            AddImm(k) => format!("sp[-1] += {}u;", k),
+            SubImm(k) => format!("sp[-1] -= {}u;", k),
+            // division by zero faults like the `div` instruction would
+            Divmod => "{ u64 b = POP(), a = POP(); if (!b) abort(); PUSH(a / b); PUSH(a % b); }"
+                .to_string(),
+            // and dividing by -1 negates, which C leaves undefined for the smallest value
+            IDivmod => "{ u64 b = POP(), a = POP(); if (!b) abort(); \
+                if (SIGNED(b) == -1) { PUSH(-a); PUSH(0); } \
+                else { PUSH(SIGNED(a) / SIGNED(b)); PUSH(SIGNED(a) % SIGNED(b)); } }"
+                .to_string(),
+
+            Eq => "BINARY(a == b);".to_string(),
+            Ne => "BINARY(a != b);".to_string(),
+            Lt => "BINARY(SIGNED(a) < SIGNED(b));".to_string(),
+            Le => "BINARY(SIGNED(a) <= SIGNED(b));".to_string(),
+            Gt => "BINARY(SIGNED(a) > SIGNED(b));".to_string(),
+            Ge => "BINARY(SIGNED(a) >= SIGNED(b));".to_string(),
+            LtU => "BINARY(a < b);".to_string(),
+            LeU => "BINARY(a <= b);".to_string(),
+            GtU => "BINARY(a > b);".to_string(),
+            GeU => "BINARY(a >= b);".to_string(),
+
+            Jump(l) => format!("goto {};", label(l)),
+            JumpF(l) => format!("if (!POP()) goto {};", label(l)),
+            JumpT(l) => format!("if (POP()) goto {};", label(l)),
+            CmpImmBranch(cmp, k, l) => {
+                let (op, signed) = match cmp {
+                    Cmp::Eq => ("==", false),
+                    Cmp::Ne => ("!=", false),
+                    Cmp::Lt => ("<", true),
+                    Cmp::Le => ("<=", true),
+                    Cmp::Gt => (">", true),
+                    Cmp::Ge => (">=", true),
+                    Cmp::LtU => ("<", false),
+                    Cmp::LeU => ("<=", false),
+                    Cmp::GtU => (">", false),
+                    Cmp::GeU => (">=", false),
+                };
+                if signed {
+                    format!("if (SIGNED(POP()) {} SIGNED({}u)) goto {};", op, k, label(l))
+                } else {
+                    format!("if (POP() {} {}u) goto {};", op, k, label(l))
+                }
+            }
+            Call(p) => format!("{}();", proc(p)),
+            PushProc(p) => format!("PUSH_PTR(&{});", proc(p)),
+            CallIndirect(..) => "((void (*)(void))(uintptr_t)POP())();".to_string(),
+            Return if options.checked => "trace_depth--; return;".to_string(),
+            Return => "return;".to_string(),
+            Exit => "exit((int)POP());".to_string(),
+
+            Proc(_) | Label(_) | Loc(_) | Dump | CallHost(..) => unreachable!(),
+        };
+        if !comment.is_empty() {
+            writeln!(sink, "    {}", comment)?;
+        }
+        writeln!(sink, "    {}", code)?;
+    }
+    writeln!(sink, "}}")?;
+    ().okay()
+}
+
+/// Escapes what C does not allow in identifiers
+fn ident(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() || c == '_' => c.to_string(),
+            c => format!("_{:x}_", c as u32),
+        })
+        .collect()
+}
+
+fn proc(name: &str) -> String {
+    format!("rh_{}", ident(name))
+}
+
+fn label(name: &str) -> String {
+    format!("L{}", ident(name))
+}
+
+/// A C string literal holding exactly `bytes`
+fn literal(bytes: &[u8]) -> String {
+    let mut res = String::from("\"");
+    for &b in bytes {
+        match b {
+            b'"' | b'\\' => {
+                res.push('\\');
+                res.push(b as char)
+            }
+            // octal escapes always take three digits, so a digit after one stays a digit
+            b' '..=b'~' if b != b'?' => res.push(b as char),
+            b => res += &format!("\\{:03o}", b),
+        }
+    }
+    res.push('"');
+    res
+}
+
+#[test]
+fn test_c_literal() {
+    assert_eq!(literal(b"a\"b\\c\n1?"), r#""a\"b\\c\0121\077""#);
+    assert_eq!(ident("foo-bar"), "foo_2d_bar");
+    assert_eq!(label(".main_0"), "L_2e_main_0");
+}
@@ -0,0 +1,359 @@
+use super::{condition, imm_operand};
+use crate::{
+    iconst::IConst,
+    lir::{Cmp, Op},
+};
+use std::io::Write;
+
+const REGS: [&str; 2] = ["rax", "rbx"];
+
+/// The top of the data stack kept in rax and rbx instead of pushed, so that ops on it skip
+/// the memory round trip. The values are pushed before the ops that work on the stack in
+/// memory, and before every label and jump, so that code is entered with none of them cached.
+#[derive(Debug, Default)]
+pub struct StackCache {
+    /// Registers holding the topmost values, the topmost last
+    regs: Vec<&'static str>,
+}
+
+/// `reg` narrowed to `bits`
+fn sub_reg(reg: &str, bits: u32) -> &'static str {
+    match (reg, bits) {
+        ("rax", 32) => "eax",
+        ("rax", 16) => "ax",
+        ("rax", _) => "al",
+        (_, 32) => "ebx",
+        (_, 16) => "bx",
+        _ => "bl",
+    }
+}
+
+impl StackCache {
+    /// Pushes the cached values, leaving the whole stack in memory
+    pub fn flush(&mut self, sink: &mut impl Write) -> std::io::Result<()> {
+        for reg in self.regs.drain(..) {
+            writeln!(sink, "    push {}", reg)?;
+        }
+        Ok(())
+    }
+
+    /// Pops values from memory under the cached ones until `n` are cached
+    fn fill(&mut self, n: usize, sink: &mut impl Write) -> std::io::Result<()> {
+        while self.regs.len() < n {
+            let free = REGS.into_iter().find(|r| !self.regs.contains(r)).unwrap();
+            writeln!(sink, "    pop {}", free)?;
+            self.regs.insert(0, free);
+        }
+        Ok(())
+    }
+
+    /// Registers of the `N` topmost values, the topmost last, which are no longer cached
+    fn take<const N: usize>(
+        &mut self,
+        sink: &mut impl Write,
+    ) -> std::io::Result<[&'static str; N]> {
+        self.fill(N, sink)?;
+        let taken = self.regs.split_off(self.regs.len() - N);
+        Ok(taken.try_into().unwrap())
+    }
+
+    /// A register for a new value on top, the bottom cached value is pushed if there is none
+    fn alloc(&mut self, sink: &mut impl Write) -> std::io::Result<&'static str> {
+        if self.regs.len() == REGS.len() {
+            let spilled = self.regs.remove(0);
+            writeln!(sink, "    push {}", spilled)?;
+        }
+        let free = REGS.into_iter().find(|r| !self.regs.contains(r)).unwrap();
+        self.regs.push(free);
+        Ok(free)
+    }
+
+    /// Emits `op` working on the cached values, false if it can't and needs them flushed
+    pub fn compile(
+        &mut self,
+        op: &Op,
+        comment: &str,
+        strings: &[String],
+        check_alignment: bool,
+        sink: &mut impl Write,
+    ) -> std::io::Result<bool> {
+        use Op::*;
+        // every op below works on registers only, the rest go through the stack in memory
+        let handled = matches!(
+            op,
+            Push(_)
+                | PushMem(_)
+                | PushTable(_)
+                | PushStr(_)
+                | PushProc(_)
+                | PushLvar(_)
+                | PushEscaping(_)
+                | UseBinding(_)
+                | Argc
+                | Argv
+                | Dup
+                | Swap
+                | Over
+                | Drop
+                | Bind
+                | Unbind
+                | ReadU64
+                | ReadU32
+                | ReadU16
+                | ReadU8
+                | WriteU64
+                | WriteU32
+                | WriteU16
+                | WriteU8
+                | ZeroExtend(_)
+                | SignExtend(_)
+                | Add
+                | Sub
+                | Mul
+                | BitAnd
+                | BitOr
+                | BitXor
+                | BitNot
+                | Shl
+                | Shr
+                | Sar
+                | AddImm(_)
+                | SubImm(_)
+                | JumpF(_)
+                | JumpT(_)
+                | CmpImmBranch(..)
+                | CallIndirect(..)
+                | Loc(_)
+                | Dump
+        ) || Cmp::of(op).is_some();
+        if !handled {
+            return Ok(false);
+        }
+        if !matches!(op, Loc(_) | Dump) {
+            writeln!(sink, "{}", comment)?;
+        }
+        match op {
+            Push(c) => {
+                let value = match c {
+                    IConst::Bool(b) => *b as u64,
+                    IConst::Char(c) => *c as u64,
+                    IConst::U64(u) | IConst::Ptr(u) => *u,
+                    IConst::I64(i) => *i as u64,
+                    IConst::Int(_) | IConst::Str(_) => unreachable!(),
+                };
+                let r = self.alloc(sink)?;
+                writeln!(sink, "    mov {}, {}", r, value)?;
+            }
+            PushMem(nm) => {
+                let r = self.alloc(sink)?;
+                writeln!(sink, "    mov {}, mem_{}", r, nm)?;
+            }
+            PushTable(nm) => {
+                let r = self.alloc(sink)?;
+                writeln!(sink, "    mov {}, table_{}", r, nm)?;
+            }
+            PushStr(i) => {
+                let len = self.alloc(sink)?;
+                writeln!(sink, "    mov {}, {}", len, strings[*i].len())?;
+                let ptr = self.alloc(sink)?;
+                writeln!(sink, "    mov {}, str_{}", ptr, i)?;
+            }
+            PushProc(p) => {
+                let r = self.alloc(sink)?;
+                writeln!(sink, "    mov {}, {}", r, p)?;
+            }
+            PushLvar(o) | PushEscaping(o) => {
+                let sp = match op {
+                    PushLvar(_) => "locals_stack_sp",
+                    _ => "escaping_stack_sp",
+                };
+                let r = self.alloc(sink)?;
+                let (load, o) = imm_operand(*o as u64, "rcx");
+                write!(sink, "    mov {}, [{}]\n{}", r, sp, load)?;
+                writeln!(sink, "    add {}, {}", r, o)?;
+            }
+            UseBinding(offset) => {
+                let r = self.alloc(sink)?;
+                writeln!(sink, "    mov {}, [ret_stack_rsp]", r)?;
+                writeln!(sink, "    mov {0}, [{0} + 8 * {1}]", r, offset)?;
+            }
+            Argc | Argv => {
+                let r = self.alloc(sink)?;
+                let var = if matches!(op, Argc) { "argc" } else { "argv" };
+                writeln!(sink, "    mov {}, [{}]", r, var)?;
+            }
+            Dup => {
+                let [a] = self.take(sink)?;
+                self.regs.push(a);
+                let r = self.alloc(sink)?;
+                writeln!(sink, "    mov {}, {}", r, a)?;
+            }
+            Swap => {
+                self.fill(2, sink)?;
+                self.regs.swap(0, 1);
+            }
+            Over => {
+                // the value under the top is pushed, and stays in its register as the new top
+                self.fill(2, sink)?;
+                let under = self.regs.remove(0);
+                writeln!(sink, "    push {}", under)?;
+                self.regs.push(under);
+            }
+            Drop => {
+                if self.regs.pop().is_none() {
+                    writeln!(sink, "    add rsp, 8")?;
+                }
+            }
+            Bind => {
+                let [a] = self.take(sink)?;
+                writeln!(sink, "    sub QWORD [ret_stack_rsp], 8")?;
+                writeln!(sink, "    mov rcx, [ret_stack_rsp]")?;
+                writeln!(sink, "    mov [rcx], {}", a)?;
+            }
+            Unbind => writeln!(sink, "    add QWORD [ret_stack_rsp], 8")?,
+            ReadU64 | ReadU32 | ReadU16 | ReadU8 => {
+                let [a] = self.take(sink)?;
+                let align = match op {
+                    ReadU64 => 7,
+                    ReadU32 => 3,
+                    ReadU16 => 1,
+                    _ => 0,
+                };
+                if check_alignment && align != 0 {
+                    writeln!(sink, "    test {}, {}\n    jnz trap_misaligned", a, align)?;
+                }
+                match op {
+                    ReadU64 => writeln!(sink, "    mov {0}, [{0}]", a)?,
+                    ReadU32 => writeln!(sink, "    mov {}, [{}]", sub_reg(a, 32), a)?,
+                    ReadU16 => writeln!(sink, "    movzx {0}, WORD [{0}]", a)?,
+                    _ => writeln!(sink, "    movzx {0}, BYTE [{0}]", a)?,
+                }
+                self.regs.push(a);
+            }
+            WriteU64 | WriteU32 | WriteU16 | WriteU8 => {
+                let [value, ptr] = self.take(sink)?;
+                let (align, value) = match op {
+                    WriteU64 => (7, value),
+                    WriteU32 => (3, sub_reg(value, 32)),
+                    WriteU16 => (1, sub_reg(value, 16)),
+                    _ => (0, sub_reg(value, 8)),
+                };
+                if check_alignment && align != 0 {
+                    writeln!(sink, "    test {}, {}\n    jnz trap_misaligned", ptr, align)?;
+                }
+                writeln!(sink, "    mov [{}], {}", ptr, value)?;
+            }
+            ZeroExtend(bits) | SignExtend(bits) => {
+                let [a] = self.take(sink)?;
+                let narrow = sub_reg(a, *bits);
+                match (op, *bits) {
+                    (ZeroExtend(_), 32) => writeln!(sink, "    mov {0}, {0}", narrow)?,
+                    (ZeroExtend(_), _) => writeln!(sink, "    movzx {}, {}", a, narrow)?,
+                    (_, 32) => writeln!(sink, "    movsxd {}, {}", a, narrow)?,
+                    _ => writeln!(sink, "    movsx {}, {}", a, narrow)?,
+                }
+                self.regs.push(a);
+            }
+            Add | Sub | Mul | BitAnd | BitOr | BitXor => {
+                let [a, b] = self.take(sink)?;
+                let instr = match op {
+                    Add => "add",
+                    Sub => "sub",
+                    Mul => "imul",
+                    BitAnd => "and",
+                    BitOr => "or",
+                    _ => "xor",
+                };
+                writeln!(sink, "    {} {}, {}", instr, a, b)?;
+                self.regs.push(a);
+            }
+            BitNot => {
+                let [a] = self.take(sink)?;
+                writeln!(sink, "    not {}", a)?;
+                self.regs.push(a);
+            }
+            Shl | Shr | Sar => {
+                let [a, b] = self.take(sink)?;
+                let instr = match op {
+                    Shl => "shl",
+                    Shr => "shr",
+                    _ => "sar",
+                };
+                writeln!(sink, "    mov rcx, {}\n    {} {}, cl", b, instr, a)?;
+                self.regs.push(a);
+            }
+            AddImm(k) | SubImm(k) => {
+                let [a] = self.take(sink)?;
+                let (load, k) = imm_operand(*k, "rcx");
+                let instr = if matches!(op, AddImm(_)) {
+                    "add"
+                } else {
+                    "sub"
+                };
+                write!(sink, "{}", load)?;
+                writeln!(sink, "    {} {}, {}", instr, a, k)?;
+                self.regs.push(a);
+            }
+            JumpF(l) | JumpT(l) => {
+                let [a] = self.take(sink)?;
+                self.flush(sink)?;
+                let jump = if matches!(op, JumpF(_)) { "jz" } else { "jnz" };
+                writeln!(sink, "    test {0}, {0}\n    {1} {2}", a, jump, l)?;
+            }
+            CmpImmBranch(cmp, k, l) => {
+                let [a] = self.take(sink)?;
+                self.flush(sink)?;
+                let (load, k) = imm_operand(*k, "rcx");
+                write!(sink, "{}", load)?;
+                writeln!(sink, "    cmp {}, {}", a, k)?;
+                writeln!(sink, "    j{} {}", condition(*cmp), l)?;
+            }
+            CallIndirect(..) => {
+                let [a] = self.take(sink)?;
+                self.flush(sink)?;
+                writeln!(sink, "    call {}", a)?;
+            }
+            Loc(_) | Dump => (),
+            _ => {
+                let cmp = Cmp::of(op).unwrap();
+                let [a, b] = self.take(sink)?;
+                writeln!(sink, "    cmp {}, {}", a, b)?;
+                writeln!(sink, "    set{} cl", condition(cmp))?;
+                writeln!(sink, "    movzx {}, cl", a)?;
+                self.regs.push(a);
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[test]
+fn test_stack_cache() {
+    use crate::hir::PrintFormat;
+    use Op::*;
+    let ops = [
+        Push(IConst::U64(1)),
+        Push(IConst::U64(2)),
+        Add,
+        Dup,
+        Mul,
+        Push(IConst::U64(3)),
+        Swap,
+        Sub,
+        Push(IConst::U64(4)),
+        Push(IConst::U64(5)),
+    ];
+    let mut cache = StackCache::default();
+    let mut asm = Vec::new();
+    for op in &ops {
+        assert!(cache.compile(op, "", &[], false, &mut asm).unwrap());
+    }
+    cache.flush(&mut asm).unwrap();
+    let asm = String::from_utf8(asm).unwrap();
+    // only the bottom value of the last three spills, then the two left cached are pushed
+    assert_eq!(asm.matches("push").count(), 3, "{}", asm);
+    assert!(!asm.contains("pop"), "{}", asm);
+    let print = Print(PrintFormat::Unsigned);
+    let handled = cache.compile(&print, "", &[], false, &mut Vec::new());
+    assert!(!handled.unwrap());
+}
@@ -0,0 +1,1459 @@
+use crate::{
+    emit::{
+        symbols::{data_layout, Datum},
+        DATA_STACK_BYTES, TRACE_FRAMES,
+    },
+    hir::PrintFormat,
+    iconst::IConst,
+    intrinsics::{HASH_OFFSET, HASH_PRIME},
+    lir::{demangle, Cmp, Op, Program},
+    session::Session,
+};
+use fnv::{FnvHashMap, FnvHashSet};
+use somok::Somok;
+use std::io::{BufWriter, Error, ErrorKind, Write};
+
+/// Where the headers, literals and code are mapped
+const BASE: u64 = 0x40_0000;
+/// Where the stacks and mems are mapped, high enough for any program text below it
+const BSS: u64 = 0x4000_0000;
+/// Static addresses are sign-extended 32 bit immediates, so everything has to stay below this
+const ADDRESS_LIMIT: u64 = 0x8000_0000;
+const EHDR_SIZE: u64 = 64;
+const PHDR_SIZE: u64 = 56;
+const SHDR_SIZE: u64 = 64;
+const SYM_SIZE: u64 = 24;
+const PAGE: u64 = 0x1000;
+
+/// Assembles the program straight into a static x86-64 executable, so nothing but the compiler
+/// is needed to build it. The code is the one `emit` writes for NASM, with the bundled runtime,
+/// which is why this only works when no runtime piece is left out to be linked in later.
+/// Unless `Options::strip` is set the executable has a symbol table naming procs, labels and mems.
+pub fn compile<S: Write>(
+    mut program: Program,
+    session: &Session,
+    mut sink: BufWriter<S>,
+) -> std::io::Result<()> {
+    let ops = std::mem::take(&mut program.ops);
+    let mems = &program.mems;
+    use Op::*;
+    let options = &session.options;
+    let strings = session.strings();
+    let check_alignment = options.checked && options.strict_alignment;
+    if !options.runtime.print || (options.checked && !options.runtime.traps) {
+        return Error::new(
+            ErrorKind::Unsupported,
+            "Executables written directly bundle the whole runtime, assemble with NASM to link your own",
+        )
+        .error();
+    }
+    if let Some((name, _)) = program.extern_mems.iter().next() {
+        let message = format!(
+            "`{}` is an extern mem, assemble with NASM to link its object",
+            name
+        );
+        return Error::new(ErrorKind::Unsupported, message).error();
+    }
+    let proc_names = ops
+        .iter()
+        .filter_map(|op| match op {
+            Proc(l) => l.clone().some(),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let hashes = ops.iter().any(|op| matches!(op, HashStr));
+    let finds = ops.iter().any(|op| matches!(op, FindByte));
+    let traps = options.checked;
+
+    let mut rodata = Section::new(BASE + EHDR_SIZE + 2 * PHDR_SIZE);
+    let digits = rodata.put("digits", b"0123456789abcdef", 1);
+    let mut trap_msg = (0, 0);
+    let mut overflow_msg = (0, 0);
+    let mut signature_msg = (0, 0);
+    let mut trace_names = 0;
+    let mut trace_name_lens = 0;
+    if traps {
+        let msg = b"Misaligned memory access\n";
+        trap_msg = (rodata.put("trap_misaligned_msg", msg, 1), msg.len() as u64);
+        let msg = b"Stack overflow\n";
+        overflow_msg = (
+            rodata.put("trap_stack_overflow_msg", msg, 1),
+            msg.len() as u64,
+        );
+        let msg = b"Indirect call doesn't match its `call-like` signature\n";
+        signature_msg = (rodata.put("trap_signature_msg", msg, 1), msg.len() as u64);
+        let mut names = Vec::new();
+        let mut lens = Vec::new();
+        for (i, name) in proc_names.iter().enumerate() {
+            let text = format!("  at {}\n", demangle(name));
+            let addr = rodata.put(format!("trace_name_{}", i), text.as_bytes(), 1);
+            names.extend_from_slice(&addr.to_le_bytes());
+            lens.extend_from_slice(&(text.len() as u64).to_le_bytes());
+        }
+        trace_names = rodata.put("trace_names", &names, 8);
+        trace_name_lens = rodata.put("trace_name_lens", &lens, 8);
+    }
+    // never placed, placements go through the linker
+    let tables = &program.tables;
+    let layout = data_layout(mems, &strings, tables, &Default::default());
+    let mut literals = vec![0; strings.len()];
+    let mut table_addrs = FnvHashMap::default();
+    for item in &layout {
+        match &item.datum {
+            Datum::Str(i) => {
+                let bytes = strings[*i].as_bytes();
+                literals[*i] = rodata.put(item.name(), bytes, item.align as u64);
+            }
+            Datum::Table(name) => {
+                let addr = rodata.put(item.name(), &tables[name], item.align as u64);
+                table_addrs.insert(name.clone(), addr);
+            }
+            Datum::Mem(_) => (),
+        }
+    }
+
+    let mut bss = Section::new(BSS);
+    let stack = options.stack_size;
+    let ret_stack_rsp = bss.reserve("ret_stack_rsp", 8, 8);
+    let ret_stack = bss.reserve("ret_stack", stack, 8);
+    let ret_stack_end = ret_stack + stack;
+    let locals_stack_sp = bss.reserve("locals_stack_sp", 8, 8);
+    let locals_stack_end = bss.reserve("locals_stack", stack, 8) + stack;
+    let escaping_stack_sp = bss.reserve("escaping_stack_sp", 8, 8);
+    let escaping_stack_end = bss.reserve("escaping_stack", stack, 8) + stack;
+    let argc = bss.reserve("argc", 8, 8);
+    let argv = bss.reserve("argv", 8, 8);
+    let buffer = bss.reserve("buffer", 32, 8);
+    let mut data_stack_limit = 0;
+    let mut trace_depth = 0;
+    let mut trace_stack = 0;
+    if options.checked {
+        data_stack_limit = bss.reserve("data_stack_limit", 8, 8);
+        trace_depth = bss.reserve("trace_depth", 8, 8);
+        trace_stack = bss.reserve("trace_stack", 8 * TRACE_FRAMES as u64, 8);
+    }
+    let mem_addrs = layout
+        .iter()
+        .filter_map(|item| match &item.datum {
+            Datum::Mem(name) => {
+                let addr = bss.reserve(item.name(), item.size as u64, item.align as u64);
+                (name.clone(), addr).some()
+            }
+            _ => None,
+        })
+        .collect::<FnvHashMap<_, _>>();
+    if bss.end() > ADDRESS_LIMIT {
+        return Error::new(ErrorKind::InvalidInput, "Mems don't fit below 2 GiB").error();
+    }
+
+    let mut a = Asm::new(align(rodata.end(), 16));
+    a.label("_start");
+    a.mov_mem_imm(abs(ret_stack_rsp), ret_stack_end as i32);
+    a.mov_mem_imm(abs(locals_stack_sp), locals_stack_end as i32);
+    a.mov_mem_imm(abs(escaping_stack_sp), escaping_stack_end as i32);
+    a.pop(Rax);
+    a.store(abs(argc), Rax);
+    a.store(abs(argv), Rsp);
+    if options.checked {
+        a.mov(Rax, Rsp);
+        a.mov_imm(Rbx, DATA_STACK_BYTES);
+        a.alu(SUB, Rax, Rbx);
+        a.store(abs(data_stack_limit), Rax);
+    }
+
+    for op in ops {
+        match &op {
+            PushMem(nm) => a.push_imm(mem_addrs[nm] as i32),
+            PushTable(nm) => a.push_imm(table_addrs[nm] as i32),
+            PushStr(i) => {
+                a.push_imm(strings[*i].len() as i32);
+                a.push_imm(literals[*i] as i32);
+            }
+            Push(c) => {
+                let value = match c {
+                    IConst::Bool(b) => *b as u64,
+                    IConst::Char(c) => *c as u64,
+                    IConst::U64(u) => *u,
+                    IConst::I64(i) => *i as u64,
+                    IConst::Ptr(p) => *p,
+                    IConst::Int(_) | IConst::Str(_) => unreachable!(),
+                };
+                a.mov_imm(Rax, value);
+                a.push(Rax);
+            }
+            Dup => {
+                a.pop(Rax);
+                a.push(Rax);
+                a.push(Rax);
+            }
+            Swap => {
+                a.pop(Rax);
+                a.pop(Rbx);
+                a.push(Rax);
+                a.push(Rbx);
+            }
+            Over => {
+                a.pop(Rax);
+                a.pop(Rbx);
+                a.push(Rbx);
+                a.push(Rax);
+                a.push(Rbx);
+            }
+            Pick(depth) => a.push_mem(at(Rsp, 8 * *depth as i32)),
+            Roll(depth) => {
+                a.load(Rax, at(Rsp, 8 * *depth as i32));
+                for i in (0..*depth as i32).rev() {
+                    a.load(Rbx, at(Rsp, 8 * i));
+                    a.store(at(Rsp, 8 * (i + 1)), Rbx);
+                }
+                a.store(at(Rsp, 0), Rax);
+            }
+            Drop => a.pop(Rax),
+
+            ReserveEscaping(n) => {
+                a.mov_imm(Rax, *n as u64);
+                a.alu(SUB, abs(escaping_stack_sp), Rax);
+            }
+            PushEscaping(n) => {
+                a.mov_imm(Rax, *n as u64);
+                a.load(Rbx, abs(escaping_stack_sp));
+                a.alu(ADD, Rbx, Rax);
+                a.push(Rbx);
+            }
+
+            ReserveLocals(n) => {
+                a.mov_imm(Rax, *n as u64);
+                a.alu(SUB, abs(locals_stack_sp), Rax);
+            }
+            FreeLocals(n) => {
+                a.mov_imm(Rax, *n as u64);
+                a.alu(ADD, abs(locals_stack_sp), Rax);
+            }
+            PushLvar(o) => {
+                a.mov_imm(Rax, *o as u64);
+                a.load(Rbx, abs(locals_stack_sp));
+                a.alu(ADD, Rbx, Rax);
+                a.push(Rbx);
+            }
+
+            Bind => {
+                a.pop(Rbx);
+                a.mov_imm(Rax, 8);
+                a.alu(SUB, abs(ret_stack_rsp), Rax);
+                a.load(Rax, abs(ret_stack_rsp));
+                a.store(at(Rax, 0), Rbx);
+            }
+            UseBinding(offset) => {
+                a.mov_imm(Rax, 8 * *offset as u64);
+                a.load(Rbx, abs(ret_stack_rsp));
+                a.alu(ADD, Rbx, Rax);
+                a.load(Rax, at(Rbx, 0));
+                a.push(Rax);
+            }
+            Unbind => {
+                a.mov_imm(Rax, 8);
+                a.alu(ADD, abs(ret_stack_rsp), Rax);
+            }
+
+            ReadU64 | ReadU32 | ReadU16 | ReadU8 => {
+                a.pop(Rax);
+                let (size, mask) = match op {
+                    ReadU64 => (Size::Q, 7),
+                    ReadU32 => (Size::D, 3),
+                    ReadU16 => (Size::W, 1),
+                    _ => (Size::B, 0),
+                };
+                if check_alignment && mask != 0 {
+                    a.test_imm(Rax, mask);
+                    a.jcc(Cond::Ne, "trap_misaligned");
+                }
+                match size {
+                    Size::Q | Size::D => a.enc(size, &[0x8b], Rbx as u8, at(Rax, 0)),
+                    Size::W => a.enc(Size::Q, &[0x0f, 0xb7], Rbx as u8, at(Rax, 0)),
+                    Size::B => {
+                        a.alu(XOR, Rbx, Rbx);
+                        a.enc(Size::B, &[0x8a], Rbx as u8, at(Rax, 0));
+                    }
+                }
+                a.push(Rbx);
+            }
+            WriteU64 | WriteU32 | WriteU16 | WriteU8 => {
+                a.pop(Rax);
+                a.pop(Rbx);
+                let (size, mask) = match op {
+                    WriteU64 => (Size::Q, 7),
+                    WriteU32 => (Size::D, 3),
+                    WriteU16 => (Size::W, 1),
+                    _ => (Size::B, 0),
+                };
+                if check_alignment && mask != 0 {
+                    a.test_imm(Rax, mask);
+                    a.jcc(Cond::Ne, "trap_misaligned");
+                }
+                let opcode = if size == Size::B { 0x88 } else { 0x89 };
+                a.enc(size, &[opcode], Rbx as u8, at(Rax, 0));
+            }
+
+            Bswap64 => {
+                a.pop(Rax);
+                a.bytes(&[0x48, 0x0f, 0xc8]);
+                a.push(Rax);
+            }
+            Bswap32 => {
+                a.pop(Rax);
+                a.bytes(&[0x0f, 0xc8]);
+                a.push(Rax);
+            }
+            Bswap16 => {
+                a.pop(Rax);
+                // xchg al, ah
+                a.bytes(&[0x86, 0xe0]);
+                a.enc(Size::Q, &[0x0f, 0xb7], Rax as u8, Rax);
+                a.push(Rax);
+            }
+            ZeroExtend(bits) | SignExtend(bits) => {
+                a.pop(Rax);
+                match (&op, *bits) {
+                    // mov eax, eax
+                    (ZeroExtend(_), 32) => a.enc(Size::D, &[0x89], Rax as u8, Rax),
+                    (ZeroExtend(_), 16) => a.enc(Size::Q, &[0x0f, 0xb7], Rax as u8, Rax),
+                    (ZeroExtend(_), _) => a.enc(Size::Q, &[0x0f, 0xb6], Rax as u8, Rax),
+                    // movsxd rax, eax
+                    (_, 32) => a.enc(Size::Q, &[0x63], Rax as u8, Rax),
+                    (_, 16) => a.enc(Size::Q, &[0x0f, 0xbf], Rax as u8, Rax),
+                    _ => a.enc(Size::Q, &[0x0f, 0xbe], Rax as u8, Rax),
+                }
+                a.push(Rax);
+            }
+
+            Print(PrintFormat::Unsigned) => {
+                a.pop(Rdi);
+                a.call("print");
+            }
+            Print(format) => {
+                a.pop(Rdi);
+                a.mov_imm(Rsi, format.radix());
+                a.mov_imm(Rdx, format.is_signed() as u64);
+                a.alu(XOR, Rcx, Rcx);
+                a.call("print_fmt");
+            }
+            PrintPadded(format) => {
+                a.pop(Rcx);
+                a.pop(Rdi);
+                a.mov_imm(Rsi, format.radix());
+                a.mov_imm(Rdx, format.is_signed() as u64);
+                a.call("print_fmt");
+            }
+
+            HashStr => {
+                a.pop(Rsi);
+                a.pop(Rcx);
+                a.call("hash_str");
+                a.push(Rax);
+            }
+
+            Copy16 => {
+                a.pop(Rax);
+                a.pop(Rbx);
+                a.movdqu_load(0, at(Rbx, 0));
+                a.movdqu_store(at(Rax, 0), 0);
+            }
+            Cmp16 => {
+                a.pop(Rax);
+                a.pop(Rbx);
+                a.movdqu_load(0, at(Rbx, 0));
+                a.movdqu_load(1, at(Rax, 0));
+                // pcmpeqb xmm0, xmm1
+                a.bytes(&[0x66, 0x0f, 0x74, 0xc1]);
+                // pmovmskb eax, xmm0
+                a.bytes(&[0x66, 0x0f, 0xd7, 0xc0]);
+                a.push(Rax);
+            }
+            Splat16 => {
+                a.pop(Rax);
+                a.pop(Rbx);
+                a.enc(Size::D, &[0x0f, 0xb6], Rbx as u8, Rbx);
+                a.mov_imm(Rcx, 0x0101010101010101);
+                // imul rbx, rcx
+                a.enc(Size::Q, &[0x0f, 0xaf], Rbx as u8, Rcx);
+                // movq xmm0, rbx
+                a.bytes(&[0x66]);
+                a.enc(Size::Q, &[0x0f, 0x6e], 0, Rbx);
+                // punpcklqdq xmm0, xmm0
+                a.bytes(&[0x66, 0x0f, 0x6c, 0xc0]);
+                a.movdqu_store(at(Rax, 0), 0);
+            }
+            FindByte => {
+                a.pop(Rax);
+                a.pop(Rcx);
+                a.pop(Rsi);
+                a.call("find_byte");
+                a.push(Rax);
+                a.push(Rdx);
+            }
+
+            Syscall0 | Syscall1 | Syscall2 | Syscall3 | Syscall4 | Syscall5 | Syscall6 => {
+                let args = match op {
+                    Syscall0 => 0,
+                    Syscall1 => 1,
+                    Syscall2 => 2,
+                    Syscall3 => 3,
+                    Syscall4 => 4,
+                    Syscall5 => 5,
+                    _ => 6,
+                };
+                a.pop(Rax);
+                for reg in [Rdi, Rsi, Rdx, R10, R8, R9].into_iter().take(args) {
+                    a.pop(reg);
+                }
+                a.syscall();
+                a.push(Rax);
+            }
+            SplitErrno => {
+                a.pop(Rax);
+                a.mov(Rcx, Rax);
+                a.group(0xf7, 3, Rcx);
+                a.alu(XOR, Rbx, Rbx);
+                a.alu_imm(7, Rax, -4095);
+                a.setcc(Cond::Ae, Rbx);
+                a.cmov(Cond::Ae, Rax, Rcx);
+                a.push(Rax);
+                a.push(Rbx);
+            }
+
+            Argc => {
+                a.load(Rax, abs(argc));
+                a.push(Rax);
+            }
+            Argv => {
+                a.load(Rax, abs(argv));
+                a.push(Rax);
+            }
+
+            Sub | Add => {
+                a.pop(Rax);
+                a.pop(Rbx);
+                a.alu(if matches!(op, Sub) { SUB } else { ADD }, Rbx, Rax);
+                a.push(Rbx);
+            }
+            BitAnd | BitOr | BitXor => {
+                a.pop(Rax);
+                a.pop(Rbx);
+                let opcode = match op {
+                    BitAnd => AND,
+                    BitOr => OR,
+                    _ => XOR,
+                };
+                a.alu(opcode, Rbx, Rax);
+                a.push(Rbx);
+            }
+            BitNot => {
+                a.pop(Rax);
+                a.group(0xf7, 2, Rax);
+                a.push(Rax);
+            }
+            Shl | Shr | Sar => {
+                a.pop(Rcx);
+                a.pop(Rax);
+                // `d3 /4`, `/5` and `/7` shift by cl
+                let extension = match op {
+                    Shl => 4,
+                    Shr => 5,
+                    _ => 7,
+                };
+                a.group(0xd3, extension, Rax);
+                a.push(Rax);
+            }
+            AddImm(k) | SubImm(k) => {
+                a.pop(Rbx);
+                a.mov_imm(Rax, *k);
+                a.alu(if matches!(op, SubImm(_)) { SUB } else { ADD }, Rbx, Rax);
+                a.push(Rbx);
+            }
+            Divmod => {
+                a.alu(XOR, Rdx, Rdx);
+                a.pop(Rbx);
+                a.pop(Rax);
+                a.group(0xf7, 6, Rbx);
+                a.push(Rax);
+                a.push(Rdx);
+            }
+            IDivmod => {
+                a.pop(Rbx);
+                a.pop(Rax);
+                // cqo, then idiv
+                a.bytes(&[0x48, 0x99]);
+                a.group(0xf7, 7, Rbx);
+                a.push(Rax);
+                a.push(Rdx);
+            }
+            Mul => {
+                a.pop(Rax);
+                a.pop(Rbx);
+                a.group(0xf7, 4, Rbx);
+                a.push(Rax);
+            }
+
+            Eq | Ne | Lt | Le | Gt | Ge | LtU | LeU | GtU | GeU => {
+                let cond = Cond::of(Cmp::of(&op).unwrap());
+                a.mov_imm(Rcx, 0);
+                a.mov_imm(Rdx, 1);
+                a.pop(Rbx);
+                a.pop(Rax);
+                a.alu(CMP, Rax, Rbx);
+                a.cmov(cond, Rcx, Rdx);
+                a.push(Rcx);
+            }
+
+            Return => {
+                if options.checked {
+                    // pop trace frame
+                    a.group(0xff, 1, abs(trace_depth));
+                }
+                a.load(Rax, abs(ret_stack_rsp));
+                a.load(Rdi, at(Rax, 0));
+                a.mov_imm(Rax, 8);
+                a.alu(ADD, abs(ret_stack_rsp), Rax);
+                a.push(Rdi);
+                a.bytes(&[0xc3]);
+            }
+            Call(p) => a.call(p),
+            PushProc(p) => {
+                // lea rax, [rip + p]
+                a.rel32(&[0x48, 0x8d, 0x05], p);
+                a.push(Rax);
+            }
+            CallIndirect(ins, outs) => {
+                a.pop(Rax);
+                if options.checked {
+                    // remember where the signature leaves the stack, if there is room
+                    a.mov(Rcx, Rsp);
+                    a.mov_imm(Rbx, (8 * (*ins as i64 - *outs as i64)) as u64);
+                    a.alu(ADD, Rcx, Rbx);
+                    a.mov_imm(Rbx, 8);
+                    a.alu(SUB, abs(ret_stack_rsp), Rbx);
+                    a.load(Rbx, abs(ret_stack_rsp));
+                    a.mov_imm(Rdx, ret_stack);
+                    a.alu(CMP, Rbx, Rdx);
+                    a.jcc(Cond::B, "trap_stack_overflow");
+                    a.store(at(Rbx, 0), Rcx);
+                }
+                // call rax
+                a.bytes(&[0xff, 0xd0]);
+                if options.checked {
+                    // check the proc pointed to had that signature
+                    a.load(Rbx, abs(ret_stack_rsp));
+                    a.alu(CMP, at(Rbx, 0), Rsp);
+                    a.jcc(Cond::Ne, "trap_signature");
+                    a.mov_imm(Rbx, 8);
+                    a.alu(ADD, abs(ret_stack_rsp), Rbx);
+                }
+            }
+            CallHost(name, _, _) => {
+                return Error::new(
+                    ErrorKind::Unsupported,
+                    format!("Host proc `{}` can only be called from the evaluator", name),
+                )
+                .error()
+            }
+            Exit => {
+                a.pop(Rdi);
+                a.mov_imm(Rax, 60);
+                a.syscall();
+            }
+            Proc(l) => {
+                a.label(l);
+                // save return address, checked builds first see if there is room
+                a.pop(Rdi);
+                a.mov_imm(Rax, 8);
+                a.alu(SUB, abs(ret_stack_rsp), Rax);
+                a.load(Rax, abs(ret_stack_rsp));
+                if options.checked {
+                    a.mov_imm(Rbx, ret_stack);
+                    a.alu(CMP, Rax, Rbx);
+                    a.jcc(Cond::B, "trap_stack_overflow");
+                }
+                a.store(at(Rax, 0), Rdi);
+                if options.checked {
+                    // push trace frame
+                    a.load(Rax, abs(trace_depth));
+                    a.mov_imm(Rbx, TRACE_FRAMES as u64 - 1);
+                    a.alu(CMP, Rax, Rbx);
+                    a.cmov(Cond::A, Rax, Rbx);
+                    let index = proc_names.iter().position(|p| p == l).unwrap();
+                    a.mov_mem_imm(table(trace_stack, Rax, 8), index as i32);
+                    a.group(0xff, 0, abs(trace_depth));
+                    // check the values it pushes fit
+                    a.mov(Rax, Rsp);
+                    a.mov_imm(Rbx, 8 * program.depth(l) as u64);
+                    a.alu(SUB, Rax, Rbx);
+                    a.alu(CMP, abs(data_stack_limit), Rax);
+                    a.jcc(Cond::A, "trap_stack_overflow");
+                }
+            }
+            Label(l) => a.label(l),
+            JumpF(l) | JumpT(l) => {
+                a.pop(Rax);
+                a.alu(TEST, Rax, Rax);
+                a.jcc(
+                    if matches!(op, JumpF(_)) {
+                        Cond::E
+                    } else {
+                        Cond::Ne
+                    },
+                    l,
+                );
+            }
+            CmpImmBranch(cmp, k, l) => {
+                a.pop(Rax);
+                a.mov_imm(Rbx, *k);
+                a.alu(CMP, Rax, Rbx);
+                a.jcc(Cond::of(*cmp), l);
+            }
+            Jump(l) => a.jmp(l),
+            Loc(_) => {}
+            Dump => {}
+        }
+    }
+
+    if traps {
+        a.label("trap_misaligned");
+        a.mov_imm(Rsi, trap_msg.0);
+        a.mov_imm(Rdx, trap_msg.1);
+        a.jmp("trap");
+        a.label("trap_stack_overflow");
+        a.mov_imm(Rsi, overflow_msg.0);
+        a.mov_imm(Rdx, overflow_msg.1);
+        a.jmp("trap");
+        a.label("trap_signature");
+        a.mov_imm(Rsi, signature_msg.0);
+        a.mov_imm(Rdx, signature_msg.1);
+        a.label("trap");
+        a.mov_imm(Rax, 1);
+        a.mov_imm(Rdi, 2);
+        a.syscall();
+        // print the shadow call stack, innermost frame first
+        a.load(R12, abs(trace_depth));
+        a.mov_imm(Rbx, TRACE_FRAMES as u64);
+        a.alu(CMP, R12, Rbx);
+        a.cmov(Cond::A, R12, Rbx);
+        a.label("trap_backtrace");
+        a.alu(TEST, R12, R12);
+        a.jcc(Cond::E, "trap_exit");
+        a.group(0xff, 1, R12);
+        a.load(Rax, table(trace_stack, R12, 8));
+        a.load(Rsi, table(trace_names, Rax, 8));
+        a.load(Rdx, table(trace_name_lens, Rax, 8));
+        a.mov_imm(Rax, 1);
+        a.mov_imm(Rdi, 2);
+        a.syscall();
+        a.jmp("trap_backtrace");
+        a.label("trap_exit");
+        a.mov_imm(Rdi, 1);
+        a.mov_imm(Rax, 60);
+        a.syscall();
+    }
+    if hashes {
+        // must agree with `intrinsics::hash_str`, which folds literals
+        a.label("hash_str");
+        a.mov_imm(Rax, HASH_OFFSET);
+        a.mov_imm(Rdx, HASH_PRIME);
+        a.label("hash_str_loop");
+        a.alu(TEST, Rcx, Rcx);
+        a.jcc(Cond::E, "hash_str_done");
+        a.enc(Size::Q, &[0x0f, 0xb6], Rbx as u8, at(Rsi, 0));
+        a.alu(XOR, Rax, Rbx);
+        a.enc(Size::Q, &[0x0f, 0xaf], Rax as u8, Rdx);
+        a.group(0xff, 0, Rsi);
+        a.group(0xff, 1, Rcx);
+        a.jmp("hash_str_loop");
+        a.label("hash_str_done");
+        a.bytes(&[0xc3]);
+    }
+    if finds {
+        // 16 bytes at a time while there are that many left, then one at a time
+        a.label("find_byte");
+        a.enc(Size::D, &[0x0f, 0xb6], Rbx as u8, Rax);
+        a.mov_imm(Rdx, 0x0101010101010101);
+        a.mov(Rax, Rbx);
+        a.enc(Size::Q, &[0x0f, 0xaf], Rax as u8, Rdx);
+        // movq xmm1, rax
+        a.bytes(&[0x66]);
+        a.enc(Size::Q, &[0x0f, 0x6e], 1, Rax);
+        // punpcklqdq xmm1, xmm1
+        a.bytes(&[0x66, 0x0f, 0x6c, 0xc9]);
+        a.mov(Rdi, Rsi);
+        a.label("find_byte_wide");
+        a.alu_imm(7, Rcx, 16);
+        a.jcc(Cond::B, "find_byte_tail");
+        a.movdqu_load(0, at(Rdi, 0));
+        // pcmpeqb xmm0, xmm1
+        a.bytes(&[0x66, 0x0f, 0x74, 0xc1]);
+        // pmovmskb eax, xmm0
+        a.bytes(&[0x66, 0x0f, 0xd7, 0xc0]);
+        a.alu(TEST, Rax, Rax);
+        a.jcc(Cond::Ne, "find_byte_hit");
+        a.alu_imm(0, Rdi, 16);
+        a.alu_imm(5, Rcx, 16);
+        a.jmp("find_byte_wide");
+        a.label("find_byte_hit");
+        // bsf eax, eax
+        a.enc(Size::D, &[0x0f, 0xbc], Rax as u8, Rax);
+        a.alu(ADD, Rdi, Rax);
+        a.jmp("find_byte_found");
+        a.label("find_byte_tail");
+        a.alu(TEST, Rcx, Rcx);
+        a.jcc(Cond::E, "find_byte_none");
+        // cmp [rdi], bl
+        a.enc(Size::B, &[0x38], Rbx as u8, at(Rdi, 0));
+        a.jcc(Cond::E, "find_byte_found");
+        a.group(0xff, 0, Rdi);
+        a.group(0xff, 1, Rcx);
+        a.jmp("find_byte_tail");
+        a.label("find_byte_none");
+        a.alu(XOR, Rdx, Rdx);
+        a.jmp("find_byte_done");
+        a.label("find_byte_found");
+        a.mov_imm(Rdx, 1);
+        a.label("find_byte_done");
+        a.mov(Rax, Rdi);
+        a.alu(SUB, Rax, Rsi);
+        a.bytes(&[0xc3]);
+    }
+    print_runtime(&mut a, digits, buffer);
+
+    let text = a.finish()?;
+    if a.base + text.len() as u64 > BSS {
+        return Error::new(ErrorKind::InvalidInput, "Code doesn't fit below the mems").error();
+    }
+    let symbols = if options.strip {
+        None
+    } else {
+        let procs = proc_names.iter().collect::<FnvHashSet<_>>();
+        let mut symbols = a
+            .labels
+            .iter()
+            .map(|(name, offset)| {
+                let kind = if procs.contains(name) || name == "_start" {
+                    STT_FUNC
+                } else {
+                    STT_NOTYPE
+                };
+                (name.as_str(), a.base + *offset as u64, TEXT, kind)
+            })
+            .collect::<Vec<_>>();
+        symbols.extend(
+            rodata
+                .symbols
+                .iter()
+                .map(|(n, v)| (n.as_str(), *v, RODATA, STT_OBJECT)),
+        );
+        symbols.extend(
+            bss.symbols
+                .iter()
+                .map(|(n, v)| (n.as_str(), *v, BSS_SECTION, STT_OBJECT)),
+        );
+        symbols.some()
+    };
+    let image = Image {
+        rodata: &rodata,
+        text_addr: a.base,
+        text: &text,
+        bss: &bss,
+        entry: a.base,
+    };
+    sink.write_all(&image.write(symbols))?;
+    sink.flush()
+}
+
+/// `print.asm`, which `print` and `print_fmt` calls go to
+fn print_runtime(a: &mut Asm, digits: u64, buffer: u64) {
+    // unsigned decimal
+    a.label("print");
+    a.mov_imm(Rsi, 10);
+    a.alu(XOR, Rdx, Rdx);
+    a.alu(XOR, Rcx, Rcx);
+
+    // rdi = value, rsi = radix, rdx = signed flag, rcx = minimum digit count
+    a.label("print_fmt");
+    a.push(Rbx);
+    a.mov(Rax, Rdi);
+    a.alu(XOR, R9, R9);
+    // clamp the width to what fits in the buffer
+    a.mov_imm(R11, 29);
+    a.alu(CMP, Rcx, R11);
+    a.cmov(Cond::A, Rcx, R11);
+    a.alu(TEST, Rdx, Rdx);
+    a.jcc(Cond::E, "print_fmt.digits");
+    a.alu(TEST, Rax, Rax);
+    a.jcc(Cond::Ns, "print_fmt.digits");
+    a.group(0xf7, 3, Rax);
+    a.group(0xff, 0, R9);
+
+    // the string is built backwards from the newline
+    a.label("print_fmt.digits");
+    a.enc(Size::Q, &[0x8d], R8 as u8, abs(buffer + 31));
+    a.mov_mem_imm8(at(R8, 0), b'\n');
+    a.mov(Rbx, Rsi);
+    a.alu(XOR, R10, R10);
+
+    a.label("print_fmt.divide");
+    a.enc(Size::D, &[XOR], Rdx as u8, Rdx);
+    a.group(0xf7, 6, Rbx);
+    a.enc(Size::B, &[0x8a], Rdx as u8, table(digits, Rdx, 1));
+    a.group(0xff, 1, R8);
+    a.enc(Size::B, &[0x88], Rdx as u8, at(R8, 0));
+    a.group(0xff, 0, R10);
+    a.alu(TEST, Rax, Rax);
+    a.jcc(Cond::Ne, "print_fmt.divide");
+
+    a.label("print_fmt.pad");
+    a.alu(CMP, R10, Rcx);
+    a.jcc(Cond::Ae, "print_fmt.sign");
+    a.group(0xff, 1, R8);
+    a.mov_mem_imm8(at(R8, 0), b'0');
+    a.group(0xff, 0, R10);
+    a.jmp("print_fmt.pad");
+
+    a.label("print_fmt.sign");
+    a.alu(TEST, R9, R9);
+    a.jcc(Cond::E, "print_fmt.write");
+    a.group(0xff, 1, R8);
+    a.mov_mem_imm8(at(R8, 0), b'-');
+
+    a.label("print_fmt.write");
+    a.mov_imm(Rdi, 1);
+    a.mov(Rsi, R8);
+    a.enc(Size::Q, &[0x8d], Rdx as u8, abs(buffer + 32));
+    a.alu(SUB, Rdx, R8);
+    a.mov_imm(Rax, 1);
+    a.syscall();
+    a.pop(Rbx);
+    a.bytes(&[0xc3]);
+}
+
+/// In encoding order, not all of them are used
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reg {
+    Rax,
+    Rcx,
+    Rdx,
+    Rbx,
+    Rsp,
+    Rbp,
+    Rsi,
+    Rdi,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+}
+use Reg::*;
+
+impl Reg {
+    fn low(self) -> u8 {
+        self as u8 & 7
+    }
+
+    fn high(self) -> u8 {
+        self as u8 >> 3
+    }
+}
+
+/// `[base + scale * index + disp]`, without a base the displacement is an absolute address
+#[derive(Debug, Clone, Copy)]
+struct Mem {
+    base: Option<Reg>,
+    index: Option<(Reg, u8)>,
+    disp: i32,
+}
+
+fn at(base: Reg, disp: i32) -> Mem {
+    Mem {
+        base: base.some(),
+        index: None,
+        disp,
+    }
+}
+
+fn abs(addr: u64) -> Mem {
+    Mem {
+        base: None,
+        index: None,
+        disp: addr as i32,
+    }
+}
+
+fn table(addr: u64, index: Reg, scale: u8) -> Mem {
+    Mem {
+        base: None,
+        index: (index, scale).some(),
+        disp: addr as i32,
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Rm {
+    Reg(Reg),
+    Mem(Mem),
+}
+
+impl From<Reg> for Rm {
+    fn from(r: Reg) -> Self {
+        Rm::Reg(r)
+    }
+}
+
+impl From<Mem> for Rm {
+    fn from(m: Mem) -> Self {
+        Rm::Mem(m)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Size {
+    B,
+    W,
+    D,
+    Q,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Cond {
+    B = 2,
+    Ae = 3,
+    E = 4,
+    Ne = 5,
+    Be = 6,
+    A = 7,
+    Ns = 9,
+    L = 0xc,
+    Ge = 0xd,
+    Le = 0xe,
+    G = 0xf,
+}
+
+impl Cond {
+    fn of(cmp: Cmp) -> Self {
+        match cmp {
+            Cmp::Eq => Self::E,
+            Cmp::Ne => Self::Ne,
+            Cmp::Lt => Self::L,
+            Cmp::Le => Self::Le,
+            Cmp::Gt => Self::G,
+            Cmp::Ge => Self::Ge,
+            Cmp::LtU => Self::B,
+            Cmp::LeU => Self::Be,
+            Cmp::GtU => Self::A,
+            Cmp::GeU => Self::Ae,
+        }
+    }
+}
+
+/// Opcodes of `op r/m64, r64`
+const ADD: u8 = 0x01;
+const OR: u8 = 0x09;
+const AND: u8 = 0x21;
+const SUB: u8 = 0x29;
+const XOR: u8 = 0x31;
+const CMP: u8 = 0x39;
+const TEST: u8 = 0x85;
+
+/// Machine code with labels, jumps and calls are patched once every label is known
+struct Asm {
+    base: u64,
+    code: Vec<u8>,
+    labels: Vec<(String, usize)>,
+    /// Offsets of rel32 operands and the labels they point to
+    fixups: Vec<(usize, String)>,
+}
+
+impl Asm {
+    fn new(base: u64) -> Self {
+        Self {
+            base,
+            code: Vec::new(),
+            labels: Vec::new(),
+            fixups: Vec::new(),
+        }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes)
+    }
+
+    fn label(&mut self, name: &str) {
+        self.labels.push((name.to_string(), self.code.len()))
+    }
+
+    /// An instruction with a ModRM operand, `reg` is a register number or an opcode extension
+    fn enc(&mut self, size: Size, opcode: &[u8], reg: u8, rm: impl Into<Rm>) {
+        let rm = rm.into();
+        if size == Size::W {
+            self.code.push(0x66);
+        }
+        let mut rex = 0x40 | (reg >> 3) << 2;
+        if size == Size::Q {
+            rex |= 8;
+        }
+        match rm {
+            Rm::Reg(r) => rex |= r.high(),
+            Rm::Mem(m) => {
+                if let Some((index, _)) = m.index {
+                    rex |= index.high() << 1;
+                }
+                if let Some(base) = m.base {
+                    rex |= base.high();
+                }
+            }
+        }
+        if rex != 0x40 {
+            self.code.push(rex);
+        }
+        self.bytes(opcode);
+        let reg = (reg & 7) << 3;
+        let m = match rm {
+            Rm::Reg(r) => return self.code.push(0xc0 | reg | r.low()),
+            Rm::Mem(m) => m,
+        };
+        let sib = |base: u8| {
+            let (index, scale) = m.index.map_or((4, 1), |(r, s)| (r.low(), s));
+            (scale.trailing_zeros() as u8) << 6 | index << 3 | base
+        };
+        match m.base {
+            None => {
+                self.code.push(reg | 4);
+                self.code.push(sib(5));
+                self.bytes(&m.disp.to_le_bytes());
+            }
+            Some(base) => {
+                let mode = if m.disp == 0 && base.low() != 5 {
+                    0x00
+                } else if i8::try_from(m.disp).is_ok() {
+                    0x40
+                } else {
+                    0x80
+                };
+                if m.index.is_some() || base.low() == 4 {
+                    self.code.push(mode | reg | 4);
+                    self.code.push(sib(base.low()));
+                } else {
+                    self.code.push(mode | reg | base.low());
+                }
+                match mode {
+                    0x40 => self.code.push(m.disp as u8),
+                    0x80 => self.bytes(&m.disp.to_le_bytes()),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn push(&mut self, r: Reg) {
+        if r.high() != 0 {
+            self.code.push(0x41);
+        }
+        self.code.push(0x50 | r.low())
+    }
+
+    fn pop(&mut self, r: Reg) {
+        if r.high() != 0 {
+            self.code.push(0x41);
+        }
+        self.code.push(0x58 | r.low())
+    }
+
+    fn push_imm(&mut self, imm: i32) {
+        self.code.push(0x68);
+        self.bytes(&imm.to_le_bytes())
+    }
+
+    fn push_mem(&mut self, m: Mem) {
+        self.enc(Size::D, &[0xff], 6, m)
+    }
+
+    /// `mov r32, imm32` zero extends, so only wider values need the ten byte form
+    fn mov_imm(&mut self, r: Reg, imm: u64) {
+        match u32::try_from(imm) {
+            Ok(imm) => {
+                if r.high() != 0 {
+                    self.code.push(0x41);
+                }
+                self.code.push(0xb8 | r.low());
+                self.bytes(&imm.to_le_bytes())
+            }
+            Err(_) => {
+                self.code.push(0x48 | r.high());
+                self.code.push(0xb8 | r.low());
+                self.bytes(&imm.to_le_bytes())
+            }
+        }
+    }
+
+    fn mov_mem_imm(&mut self, m: Mem, imm: i32) {
+        self.enc(Size::Q, &[0xc7], 0, m);
+        self.bytes(&imm.to_le_bytes())
+    }
+
+    fn mov_mem_imm8(&mut self, m: Mem, imm: u8) {
+        self.enc(Size::B, &[0xc6], 0, m);
+        self.code.push(imm)
+    }
+
+    fn mov(&mut self, dst: Reg, src: Reg) {
+        self.enc(Size::Q, &[0x89], src as u8, dst)
+    }
+
+    fn load(&mut self, dst: Reg, m: Mem) {
+        self.enc(Size::Q, &[0x8b], dst as u8, m)
+    }
+
+    fn store(&mut self, m: Mem, src: Reg) {
+        self.enc(Size::Q, &[0x89], src as u8, m)
+    }
+
+    fn alu(&mut self, opcode: u8, dst: impl Into<Rm>, src: Reg) {
+        self.enc(Size::Q, &[opcode], src as u8, dst)
+    }
+
+    /// `op r/m64, imm32` of the 0x81 group, `extension` picks the op
+    fn alu_imm(&mut self, extension: u8, dst: impl Into<Rm>, imm: i32) {
+        self.enc(Size::Q, &[0x81], extension, dst);
+        self.bytes(&imm.to_le_bytes())
+    }
+
+    fn test_imm(&mut self, dst: impl Into<Rm>, imm: i32) {
+        self.enc(Size::Q, &[0xf7], 0, dst);
+        self.bytes(&imm.to_le_bytes())
+    }
+
+    /// One operand instructions, like `neg` and `div` of 0xf7 or `inc` and `dec` of 0xff
+    fn group(&mut self, opcode: u8, extension: u8, rm: impl Into<Rm>) {
+        self.enc(Size::Q, &[opcode], extension, rm)
+    }
+
+    fn cmov(&mut self, cond: Cond, dst: Reg, src: Reg) {
+        self.enc(Size::Q, &[0x0f, 0x40 | cond as u8], dst as u8, src)
+    }
+
+    fn setcc(&mut self, cond: Cond, dst: Reg) {
+        self.enc(Size::B, &[0x0f, 0x90 | cond as u8], 0, dst)
+    }
+
+    fn syscall(&mut self) {
+        self.bytes(&[0x0f, 0x05])
+    }
+
+    /// `movdqu xmm, [m]`, the prefix goes before any REX
+    fn movdqu_load(&mut self, xmm: u8, m: Mem) {
+        self.code.push(0xf3);
+        self.enc(Size::D, &[0x0f, 0x6f], xmm, m)
+    }
+
+    /// `movdqu [m], xmm`
+    fn movdqu_store(&mut self, m: Mem, xmm: u8) {
+        self.code.push(0xf3);
+        self.enc(Size::D, &[0x0f, 0x7f], xmm, m)
+    }
+
+    fn rel32(&mut self, opcode: &[u8], label: &str) {
+        self.bytes(opcode);
+        self.fixups.push((self.code.len(), label.to_string()));
+        self.bytes(&[0; 4])
+    }
+
+    fn jmp(&mut self, label: &str) {
+        self.rel32(&[0xe9], label)
+    }
+
+    fn jcc(&mut self, cond: Cond, label: &str) {
+        self.rel32(&[0x0f, 0x80 | cond as u8], label)
+    }
+
+    fn call(&mut self, label: &str) {
+        self.rel32(&[0xe8], label)
+    }
+
+    /// The code with every jump patched
+    fn finish(&mut self) -> std::io::Result<Vec<u8>> {
+        let labels = self
+            .labels
+            .iter()
+            .map(|(l, o)| (l.as_str(), *o))
+            .collect::<FnvHashMap<_, _>>();
+        let mut code = self.code.clone();
+        for (at, label) in &self.fixups {
+            let target = match labels.get(label.as_str()) {
+                Some(target) => *target,
+                None => {
+                    return Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Jump to undefined label `{}`", label),
+                    )
+                    .error()
+                }
+            };
+            let rel = target as i64 - (*at as i64 + 4);
+            code[*at..*at + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+        }
+        code.okay()
+    }
+}
+
+/// Named bytes or zeroed space laid out from a fixed address
+struct Section {
+    base: u64,
+    bytes: Vec<u8>,
+    size: u64,
+    symbols: Vec<(String, u64)>,
+}
+
+impl Section {
+    fn new(base: u64) -> Self {
+        Self {
+            base,
+            bytes: Vec::new(),
+            size: 0,
+            symbols: Vec::new(),
+        }
+    }
+
+    fn end(&self) -> u64 {
+        self.base + self.size
+    }
+
+    fn put(&mut self, name: impl Into<String>, bytes: &[u8], alignment: u64) -> u64 {
+        let addr = self.reserve(name, bytes.len() as u64, alignment);
+        self.bytes.resize((addr - self.base) as usize, 0);
+        self.bytes.extend_from_slice(bytes);
+        addr
+    }
+
+    fn reserve(&mut self, name: impl Into<String>, size: u64, alignment: u64) -> u64 {
+        let addr = align(self.end(), alignment);
+        self.size = addr - self.base + size;
+        self.symbols.push((name.into(), addr));
+        addr
+    }
+}
+
+fn align(offset: u64, to: u64) -> u64 {
+    offset.div_ceil(to) * to
+}
+
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+/// Section header indices
+const RODATA: u16 = 1;
+const TEXT: u16 = 2;
+const BSS_SECTION: u16 = 3;
+
+/// Name, address, section and type of a symbol
+type Symbol<'a> = (&'a str, u64, u16, u8);
+
+struct Image<'a> {
+    rodata: &'a Section,
+    text_addr: u64,
+    text: &'a [u8],
+    bss: &'a Section,
+    entry: u64,
+}
+
+impl Image<'_> {
+    /// One segment maps the file from the headers to the end of the code, the other is all zeroes.
+    /// Symbols come with section headers, which the loader ignores.
+    fn write(&self, symbols: Option<Vec<Symbol>>) -> Vec<u8> {
+        let mut out = Vec::new();
+        let loaded = self.text_addr - BASE + self.text.len() as u64;
+
+        let mut tables = Vec::new();
+        let mut shdrs = Vec::new();
+        let mut shnum = 0;
+        if let Some(mut symbols) = symbols {
+            symbols.sort_by_key(|(name, addr, ..)| (*addr, *name));
+            let symtab_offset = align(loaded, 8);
+            let mut strtab = vec![0];
+            let mut symtab = vec![0; SYM_SIZE as usize];
+            let mut locals = 1;
+            for global in [false, true] {
+                for (name, addr, section, kind) in &symbols {
+                    if (*name == "_start") != global {
+                        continue;
+                    }
+                    put(&mut symtab, strtab.len() as u64, 4);
+                    put(&mut symtab, (global as u64) << 4 | *kind as u64, 1);
+                    put(&mut symtab, 0, 1);
+                    put(&mut symtab, *section as u64, 2);
+                    put(&mut symtab, *addr, 8);
+                    put(&mut symtab, 0, 8);
+                    strtab.extend_from_slice(name.as_bytes());
+                    strtab.push(0);
+                    locals += !global as u64;
+                }
+            }
+            let shstrtab = b"\0.rodata\0.text\0.bss\0.symtab\0.strtab\0.shstrtab\0";
+            let strtab_offset = symtab_offset + symtab.len() as u64;
+            let shstrtab_offset = strtab_offset + strtab.len() as u64;
+            let shoff = align(shstrtab_offset + shstrtab.len() as u64, 8);
+
+            let rodata_offset = self.rodata.base - BASE;
+            let text_offset = self.text_addr - BASE;
+            #[rustfmt::skip]
+            let sections: [[u64; 10]; 7] = [
+                [0; 10],
+                [1, 1, 2, self.rodata.base, rodata_offset, self.rodata.size, 0, 0, 8, 0],
+                [9, 1, 6, self.text_addr, text_offset, self.text.len() as u64, 0, 0, 16, 0],
+                [15, 8, 3, self.bss.base, 0, self.bss.size, 0, 0, 8, 0],
+                [20, 2, 0, 0, symtab_offset, symtab.len() as u64, 5, locals, 8, SYM_SIZE],
+                [28, 3, 0, 0, strtab_offset, strtab.len() as u64, 0, 0, 1, 0],
+                [36, 3, 0, 0, shstrtab_offset, shstrtab.len() as u64, 0, 0, 1, 0],
+            ];
+            for [name, kind, flags, addr, offset, size, link, info, alignment, entsize] in sections
+            {
+                put(&mut shdrs, name, 4);
+                put(&mut shdrs, kind, 4);
+                put(&mut shdrs, flags, 8);
+                put(&mut shdrs, addr, 8);
+                put(&mut shdrs, offset, 8);
+                put(&mut shdrs, size, 8);
+                put(&mut shdrs, link, 4);
+                put(&mut shdrs, info, 4);
+                put(&mut shdrs, alignment, 8);
+                put(&mut shdrs, entsize, 8);
+            }
+            shnum = sections.len() as u64;
+            tables.resize((symtab_offset - loaded) as usize, 0);
+            tables.extend(symtab);
+            tables.extend(strtab);
+            tables.extend_from_slice(shstrtab);
+            tables.resize((shoff - loaded) as usize, 0);
+        }
+
+        out.extend_from_slice(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0");
+        // executable, x86-64, version 1
+        put(&mut out, 2, 2);
+        put(&mut out, 62, 2);
+        put(&mut out, 1, 4);
+        put(&mut out, self.entry, 8);
+        put(&mut out, EHDR_SIZE, 8);
+        let shoff = if shnum == 0 {
+            0
+        } else {
+            loaded + tables.len() as u64
+        };
+        put(&mut out, shoff, 8);
+        put(&mut out, 0, 4);
+        put(&mut out, EHDR_SIZE, 2);
+        put(&mut out, PHDR_SIZE, 2);
+        put(&mut out, 2, 2);
+        put(&mut out, SHDR_SIZE, 2);
+        put(&mut out, shnum, 2);
+        put(&mut out, shnum.saturating_sub(1), 2);
+
+        // PT_LOAD segments, read and execute then read and write
+        for (flags, offset, addr, filesz, memsz) in [
+            (5, 0, BASE, loaded, loaded),
+            (6, 0, self.bss.base, 0, self.bss.size),
+        ] {
+            put(&mut out, 1, 4);
+            put(&mut out, flags, 4);
+            put(&mut out, offset, 8);
+            put(&mut out, addr, 8);
+            put(&mut out, addr, 8);
+            put(&mut out, filesz, 8);
+            put(&mut out, memsz, 8);
+            put(&mut out, PAGE, 8);
+        }
+
+        out.extend_from_slice(&self.rodata.bytes);
+        out.resize((self.text_addr - BASE) as usize, 0);
+        out.extend_from_slice(self.text);
+        out.extend(tables);
+        out.extend(shdrs);
+        out
+    }
+}
+
+/// Appends the low `bytes` bytes of `value`, little endian
+fn put(out: &mut Vec<u8>, value: u64, bytes: usize) {
+    out.extend_from_slice(&value.to_le_bytes()[..bytes])
+}
+
+#[test]
+fn test_encode() {
+    let mut a = Asm::new(0);
+    a.load(Rax, at(Rsp, 8));
+    a.store(at(R8, 0), Rbx);
+    a.enc(Size::B, &[0x8a], Rdx as u8, table(0x1000, Rdx, 1));
+    a.alu(SUB, abs(0x2000), Rax);
+    a.mov_imm(R12, 1);
+    a.push(R10);
+    a.cmov(Cond::A, Rcx, R11);
+    assert_eq!(
+        a.code,
+        [
+            0x48, 0x8b, 0x44, 0x24, 0x08, // mov rax, [rsp + 8]
+            0x49, 0x89, 0x18, // mov [r8], rbx
+            0x8a, 0x14, 0x15, 0x00, 0x10, 0x00, 0x00, // mov dl, [0x1000 + rdx]
+            0x48, 0x29, 0x04, 0x25, 0x00, 0x20, 0x00, 0x00, // sub [0x2000], rax
+            0x41, 0xbc, 0x01, 0x00, 0x00, 0x00, // mov r12d, 1
+            0x41, 0x52, // push r10
+            0x49, 0x0f, 0x47, 0xcb, // cmova rcx, r11
+        ]
+    );
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+#[test]
+fn test_executable() {
+    use crate::{
+        eval::{Interpreter, Memory},
+        lir::{typecheck_source_in, Compiler},
+    };
+    use somok::Either;
+    use std::{os::unix::fs::PermissionsExt, process::Command};
+
+    let src = "proc main : u64 do
+        \"counting\\n\" 1 1 syscall3 drop
+        0 while dup 5 < do dup dup * print 1 + end
+        3 cast i64 0 cast i64 swap - print-signed
+        255 4 print-hex-pad
+        42 +
+    end";
+    let session = Session::default();
+    let (procs, _) = typecheck_source_in(src, &session).unwrap();
+    let program = Compiler::new(&session).compile(procs).unwrap();
+
+    let mut expected = Vec::new();
+    let code = Interpreter::new()
+        .mems(&program.mems)
+        .syscalls(|memory: &mut Memory, _, args: [u64; 6]| {
+            expected.extend_from_slice(memory.get(args[1], args[2]).unwrap());
+            args[2]
+        })
+        .run(&program.ops, &session.strings());
+    let code = match code {
+        Ok(Either::Left(code)) => code,
+        res => panic!("{:?}", res.map(|_| ())),
+    };
+    assert_eq!(expected, b"counting\n0\n1\n4\n9\n16\n-3\n00ff\n");
+    assert_eq!(code, 47);
+
+    let tmp = crate::build::TempDir::new().unwrap();
+    let exe = tmp.path.join("counting");
+    let sink = BufWriter::new(std::fs::File::create(&exe).unwrap());
+    compile(program, &session, sink).unwrap();
+    std::fs::set_permissions(&exe, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let output = Command::new(&exe).output().unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&expected)
+    );
+    assert_eq!(output.status.code(), Some(code as i32));
+}
@@ -0,0 +1,317 @@
+//! Symbols manifest of a program and the linker script placing its symbols.
+//! The manifest has a line for every mem, table and string, `name section size`, optionally
+//! followed by an address. Feeding a manifest with addresses back into a build puts each of those
+//! symbols in a section of its own and links it at that address.
+//! Every backend lays strings, tables and mems out in the order `data_layout` gives them.
+
+use fnv::FnvHashMap;
+use somok::Somok;
+use std::fmt::{self, Write};
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub section: String,
+    pub size: usize,
+    pub address: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub symbols: Vec<Symbol>,
+}
+
+#[derive(Debug, Error)]
+#[error("line {line}: {message}")]
+pub struct ManifestError {
+    pub line: usize,
+    pub message: String,
+}
+
+/// Section the symbol `name` is emitted in, `base` unless it has a fixed address
+pub fn section(base: &str, name: &str, placements: &FnvHashMap<String, u64>) -> String {
+    if placements.contains_key(name) {
+        format!("{}.{}", base, name)
+    } else {
+        base.to_string()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Datum {
+    /// Index of the string in `Session::strings`
+    Str(usize),
+    /// Name of the table in `Program::tables`
+    Table(String),
+    Mem(String),
+}
+
+/// A string, table or mem and where it goes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataItem {
+    pub datum: Datum,
+    pub section: String,
+    pub size: usize,
+    pub align: usize,
+    /// Bytes skipped before the item to align it, zero in a section of its own
+    pub padding: usize,
+}
+
+impl DataItem {
+    /// Label of the item in the assembly
+    pub fn name(&self) -> String {
+        match &self.datum {
+            Datum::Str(i) => format!("str_{}", i),
+            Datum::Table(name) => format!("table_{}", name),
+            Datum::Mem(name) => format!("mem_{}", name),
+        }
+    }
+}
+
+/// Alignment of a mem of `size` bytes, enough for any scalar that fits at its start
+pub fn mem_align(size: usize) -> usize {
+    match size {
+        0 => 1,
+        size => (1 << size.ilog2()).min(8),
+    }
+}
+
+/// Strings in `.data`, tables in `.rodata` then mems in `.bss`, each sorted by alignment then
+/// size, both decreasing, so that little padding is needed between them. Ties go by name, so that
+/// the layout only depends on the program. Padding is counted from the start of the items in the
+/// section, which the backends align to 8.
+pub fn data_layout(
+    mems: &FnvHashMap<String, usize>,
+    strings: &[String],
+    tables: &FnvHashMap<String, Vec<u8>>,
+    placements: &FnvHashMap<String, u64>,
+) -> Vec<DataItem> {
+    let item = |datum, base, size, align| {
+        let mut item = DataItem {
+            datum,
+            section: String::new(),
+            size,
+            align,
+            padding: 0,
+        };
+        item.section = section(base, &item.name(), placements);
+        item
+    };
+    let mut strings = strings
+        .iter()
+        .enumerate()
+        .map(|(i, s)| item(Datum::Str(i), ".data", s.len(), 1))
+        .collect::<Vec<_>>();
+    // aligned like a mem, which is enough for the elements
+    let mut tables = tables
+        .iter()
+        .map(|(name, bytes)| {
+            let size = bytes.len();
+            item(Datum::Table(name.clone()), ".rodata", size, mem_align(size))
+        })
+        .collect::<Vec<_>>();
+    let mut mems = mems
+        .iter()
+        .map(|(name, &size)| item(Datum::Mem(name.clone()), ".bss", size, mem_align(size)))
+        .collect::<Vec<_>>();
+    for items in [&mut strings, &mut tables, &mut mems] {
+        items.sort_by(|a, b| {
+            (b.align, b.size)
+                .cmp(&(a.align, a.size))
+                .then_with(|| a.name().cmp(&b.name()))
+        });
+        let mut offset = 0usize;
+        for item in items.iter_mut() {
+            if placements.contains_key(&item.name()) {
+                continue;
+            }
+            item.padding = offset.next_multiple_of(item.align) - offset;
+            offset += item.padding + item.size;
+        }
+    }
+    strings.into_iter().chain(tables).chain(mems).collect()
+}
+
+/// Bytes taken by every string, table and mem, with the padding before it, and the totals of each
+/// section
+pub fn data_report(layout: &[DataItem]) -> String {
+    let mut report = String::new();
+    let mut totals = Vec::<(&str, usize, usize)>::new();
+    let width = layout.iter().map(|i| i.name().len()).max().unwrap_or(0);
+    for item in layout {
+        writeln!(
+            report,
+            "{:<width$} {:<8} {:>10} {:>5} {:>5}",
+            item.name(),
+            item.section,
+            item.size,
+            item.align,
+            item.padding,
+        )
+        .unwrap();
+        let base = [".data", ".rodata", ".bss"]
+            .into_iter()
+            .find(|base| item.section.starts_with(base))
+            .unwrap();
+        match totals.iter_mut().find(|(s, ..)| *s == base) {
+            Some((_, size, padding)) => {
+                *size += item.size;
+                *padding += item.padding;
+            }
+            None => totals.push((base, item.size, item.padding)),
+        }
+    }
+    for (section, size, padding) in totals {
+        writeln!(
+            report,
+            "total {}: {} bytes, {} of padding",
+            section,
+            size + padding,
+            padding
+        )
+        .unwrap();
+    }
+    report
+}
+
+impl Manifest {
+    /// The strings, tables and mems of a program, in the order the backends emit them
+    pub fn new(
+        mems: &FnvHashMap<String, usize>,
+        strings: &[String],
+        tables: &FnvHashMap<String, Vec<u8>>,
+        placements: &FnvHashMap<String, u64>,
+    ) -> Self {
+        let symbols = data_layout(mems, strings, tables, placements)
+            .into_iter()
+            .map(|item| Symbol {
+                name: item.name(),
+                address: placements.get(&item.name()).copied(),
+                section: item.section,
+                size: item.size,
+            })
+            .collect();
+        Self { symbols }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, ManifestError> {
+        let mut symbols = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let error = |message: &str| ManifestError {
+                line: i + 1,
+                message: message.to_string(),
+            };
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let (name, section, size, address) = match fields[..] {
+                [name, section, size] => (name, section, size, None),
+                [name, section, size, address] => (name, section, size, address.some()),
+                _ => return error("expected `name section size [address]`").error(),
+            };
+            let size = size.parse().map_err(|_| error("invalid size"))?;
+            let address = match address {
+                Some(a) => match a.strip_prefix("0x") {
+                    Some(hex) => u64::from_str_radix(hex, 16),
+                    None => a.parse(),
+                }
+                .map_err(|_| error("invalid address"))?
+                .some(),
+                None => None,
+            };
+            symbols.push(Symbol {
+                name: name.to_string(),
+                section: section.to_string(),
+                size,
+                address,
+            });
+        }
+        Self { symbols }.okay()
+    }
+
+    /// Requested addresses by symbol name, what `Options::placements` takes
+    pub fn placements(&self) -> FnvHashMap<String, u64> {
+        self.symbols
+            .iter()
+            .filter_map(|s| (s.name.clone(), s.address?).some())
+            .collect()
+    }
+
+    /// A GNU ld script that links every symbol with an address there, on top of the default script.
+    /// The sections come from the manifest, so it has to be the one of the program being linked.
+    pub fn linker_script(&self) -> String {
+        let mut script = String::from("SECTIONS\n{\n");
+        for s in &self.symbols {
+            if let Some(address) = s.address {
+                writeln!(script, "    {0} {1:#x} : {{ *({0}) }}", s.section, address).unwrap();
+            }
+        }
+        script.push_str("}\nINSERT AFTER .bss;\n");
+        script
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for s in &self.symbols {
+            write!(f, "{} {} {}", s.name, s.section, s.size)?;
+            if let Some(address) = s.address {
+                write!(f, " {:#x}", address)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_manifest() {
+    let mems = [("buf".to_string(), 64), ("a".to_string(), 8)]
+        .into_iter()
+        .collect();
+    let strings = ["hi".to_string()];
+    let placements = [("mem_buf".to_string(), 0x800000)].into_iter().collect();
+    let manifest = Manifest::new(&mems, &strings, &Default::default(), &placements);
+    let text = manifest.to_string();
+    assert_eq!(
+        text,
+        "str_0 .data 2\nmem_buf .bss.mem_buf 64 0x800000\nmem_a .bss 8\n"
+    );
+    let parsed = Manifest::parse(&format!("# edited\n{}", text)).unwrap();
+    assert_eq!(parsed, manifest);
+    assert_eq!(parsed.placements(), placements);
+    assert!(manifest
+        .linker_script()
+        .contains(".bss.mem_buf 0x800000 : { *(.bss.mem_buf) }"));
+    let e = Manifest::parse("mem_a .bss 8 0xzz").unwrap_err();
+    assert_eq!(e.to_string(), "line 1: invalid address");
+}
+
+#[test]
+fn test_data_layout() {
+    let mems = [("a", 3), ("b", 16), ("c", 4), ("d", 9), ("e", 1)]
+        .into_iter()
+        .map(|(n, s)| (n.to_string(), s))
+        .collect();
+    let strings = ["hi".to_string(), "hello".to_string()];
+    let tables = [("sq".to_string(), vec![0, 1, 4, 9, 16, 25])]
+        .into_iter()
+        .collect();
+    let layout = data_layout(&mems, &strings, &tables, &Default::default());
+    let names = layout.iter().map(DataItem::name).collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        ["str_1", "str_0", "table_sq", "mem_b", "mem_d", "mem_c", "mem_a", "mem_e"]
+    );
+    assert_eq!(layout[2].section, ".rodata");
+    assert_eq!(layout[2].align, 4);
+    let padding = layout.iter().map(|i| i.padding).collect::<Vec<_>>();
+    assert_eq!(padding, [0, 0, 0, 0, 0, 3, 0, 0]);
+    let report = data_report(&layout);
+    assert!(report.contains("total .data: 7 bytes, 0 of padding"));
+    assert!(report.contains("total .rodata: 6 bytes, 0 of padding"));
+    assert!(report.contains("total .bss: 36 bytes, 3 of padding"));
+}
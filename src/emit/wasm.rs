@@ -0,0 +1,1122 @@
+use crate::{
+    emit::{
+        symbols::{data_layout, Datum},
+        Annotator,
+    },
+    hir::PrintFormat,
+    iconst::IConst,
+    intrinsics::{HASH_OFFSET, HASH_PRIME},
+    lir::{Cmp, Op, Program},
+    session::Session,
+};
+use fnv::FnvHashMap;
+use indoc::indoc;
+use somok::Somok;
+use std::io::{BufWriter, Error, ErrorKind, Write};
+
+/// Iovec handed to `fd_read` and `fd_write`
+const IOV: u64 = 16;
+/// Results WASI calls write through a pointer
+const RESULT: u64 = 24;
+/// `args_sizes_get` results, the argument count and the size of their strings
+const ARGS: u64 = 32;
+const DIGITS: u64 = 64;
+/// Room for `print_fmt` output, it is written backwards from the end
+const PRINT_BUFFER: u64 = 80;
+/// Linux errno of each WASI errno, indexed by the latter
+const ERRNOS: u64 = 112;
+const MISALIGNED: u64 = 192;
+/// Literals, mems and stacks are laid out from here on
+const STATIC: u64 = 256;
+const PAGE: u64 = 65536;
+/// Rights asked for files opened through `path_open`, all but the socket ones
+const RIGHTS: u64 = 0xfffffff;
+/// Bytes in the data stack, the native backend uses the process stack instead
+const DATA_STACK_BYTES: u64 = 1 << 20;
+
+/// Linux errno values, in the order of the WASI `errno` enum
+const LINUX_ERRNOS: [u8; 77] = [
+    0, 7, 13, 98, 99, 97, 11, 114, 9, 74, 16, 125, 10, 103, 111, 104, 35, 89, 33, 122, 17, 14, 27,
+    113, 43, 84, 115, 4, 22, 5, 106, 21, 40, 24, 31, 90, 72, 36, 100, 102, 101, 23, 105, 19, 2, 8,
+    37, 67, 12, 42, 92, 28, 38, 107, 20, 39, 131, 88, 95, 25, 6, 75, 130, 1, 32, 71, 93, 91, 34,
+    30, 29, 3, 116, 110, 26, 18, 1,
+];
+
+/// Syscalls `$syscall` maps to WASI calls: `read`, `write`, `open`, `close`, `lseek`,
+/// `sched_yield`, `exit`, `mkdir`, `rmdir`, `unlink` and `exit_group`
+pub const SYSCALLS: &[u64] = &[0, 1, 2, 3, 8, 24, 60, 83, 84, 87, 231];
+
+/// WASI calls the runtime is built on
+const PRELUDE: &str = indoc! {r#"
+    (module
+      (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+      (import "wasi_snapshot_preview1" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
+      (import "wasi_snapshot_preview1" "fd_close" (func $fd_close (param i32) (result i32)))
+      (import "wasi_snapshot_preview1" "fd_seek" (func $fd_seek (param i32 i64 i32 i32) (result i32)))
+      (import "wasi_snapshot_preview1" "path_open" (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+      (import "wasi_snapshot_preview1" "path_create_directory" (func $path_create_directory (param i32 i32 i32) (result i32)))
+      (import "wasi_snapshot_preview1" "path_remove_directory" (func $path_remove_directory (param i32 i32 i32) (result i32)))
+      (import "wasi_snapshot_preview1" "path_unlink_file" (func $path_unlink_file (param i32 i32 i32) (result i32)))
+      (import "wasi_snapshot_preview1" "sched_yield" (func $sched_yield (result i32)))
+      (import "wasi_snapshot_preview1" "args_sizes_get" (func $args_sizes_get (param i32 i32) (result i32)))
+      (import "wasi_snapshot_preview1" "args_get" (func $args_get (param i32 i32) (result i32)))
+      (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+"#};
+
+/// Translates the program to a WebAssembly text module for WASI runtimes like wasmtime.
+/// The operand stack lives in linear memory and every proc becomes a function,
+/// labels are reached through a `br_table` dispatch loop around the proc body.
+/// Syscalls are mapped to WASI calls where there is one, paths resolve against the first
+/// preopened directory and the others fail with `ENOSYS`.
+pub fn compile<S: Write>(
+    program: Program,
+    session: &Session,
+    mut sink: BufWriter<S>,
+) -> std::io::Result<()> {
+    let ops = program.ops;
+    let mems = &program.mems;
+    use Op::*;
+    let options = &session.options;
+    let strings = session.strings();
+    let check_alignment = options.checked && options.strict_alignment;
+    if let Some((name, _)) = program.extern_mems.iter().next() {
+        return Error::new(
+            ErrorKind::Unsupported,
+            format!("`{}` is an extern mem, which needs linking", name),
+        )
+        .error();
+    }
+
+    let mut offset = STATIC;
+    let mut literals = vec![0; strings.len()];
+    let mut addresses = FnvHashMap::default();
+    let tables = &program.tables;
+    let layout = data_layout(mems, &strings, tables, &Default::default());
+    for item in &layout {
+        offset = align(offset, item.align as u64);
+        match &item.datum {
+            Datum::Str(i) => literals[*i] = offset,
+            Datum::Table(name) | Datum::Mem(name) => {
+                addresses.insert(name.clone(), offset);
+            }
+        }
+        offset += item.size as u64;
+    }
+    // the binding stack is as big as the native return stack
+    let bindings_end = align(offset, 8) + options.stack_size;
+    let locals_end = bindings_end + options.stack_size;
+    let escaping_end = locals_end + options.stack_size;
+    let data_stack_end = escaping_end + DATA_STACK_BYTES;
+    let heap = data_stack_end;
+
+    sink.write_all(PRELUDE.as_bytes())?;
+    writeln!(
+        sink,
+        "  (memory (export \"memory\") {})",
+        align(heap, PAGE) / PAGE
+    )?;
+    // the last word stays zero, so exiting with an empty stack exits with 0
+    writeln!(
+        sink,
+        "  (global $sp (mut i32) (i32.const {}))",
+        data_stack_end - 8
+    )?;
+    writeln!(
+        sink,
+        "  (global $bp (mut i32) (i32.const {}))",
+        bindings_end
+    )?;
+    writeln!(sink, "  (global $lsp (mut i32) (i32.const {}))", locals_end)?;
+    writeln!(
+        sink,
+        "  (global $esp (mut i32) (i32.const {}))",
+        escaping_end
+    )?;
+    writeln!(sink, "  (global $argc (mut i64) (i64.const 0))")?;
+    writeln!(sink, "  (global $argv (mut i64) (i64.const 0))")?;
+    writeln!(
+        sink,
+        "  (data (i32.const {}) {})",
+        DIGITS,
+        literal(b"0123456789abcdef")
+    )?;
+    writeln!(
+        sink,
+        "  (data (i32.const {}) {})",
+        ERRNOS,
+        literal(&LINUX_ERRNOS)
+    )?;
+    writeln!(
+        sink,
+        "  (data (i32.const {}) {})",
+        MISALIGNED,
+        literal(b"Misaligned memory access\n")
+    )?;
+    for (str, address) in strings.iter().zip(&literals) {
+        writeln!(
+            sink,
+            "  (data (i32.const {}) {})",
+            address,
+            literal(str.as_bytes())
+        )?;
+    }
+    for item in &layout {
+        if let Datum::Table(name) = &item.datum {
+            writeln!(
+                sink,
+                "  (data (i32.const {}) {})",
+                addresses[name],
+                literal(&tables[name])
+            )?;
+        }
+    }
+    // procs whose address is taken, `call_indirect` reaches them by their index in the table
+    let mut table = Vec::new();
+    for op in &ops {
+        match op {
+            PushProc(p) if !table.contains(p) => table.push(p.clone()),
+            _ => (),
+        }
+    }
+    if ops.iter().any(|op| matches!(op, CallIndirect(..))) || !table.is_empty() {
+        writeln!(sink, "  (type $proc (func))")?;
+        writeln!(sink, "  (table {} funcref)", table.len())?;
+        write!(sink, "  (elem (i32.const 0)")?;
+        for p in &table {
+            write!(sink, " {}", func(p))?;
+        }
+        writeln!(sink, ")")?;
+    }
+    runtime(&mut sink, heap)?;
+
+    let starts = ops
+        .iter()
+        .enumerate()
+        .filter(|(_, op)| matches!(op, Proc(_)))
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    let mut bodies = vec![(None, &ops[..starts.first().copied().unwrap_or(ops.len())])];
+    for (i, &start) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).copied().unwrap_or(ops.len());
+        bodies.push((ops[start].clone().some(), &ops[start + 1..end]));
+    }
+
+    let mut annotator = Annotator::new(options, ";;");
+    for (proc, body) in bodies {
+        match &proc {
+            Some(op @ Proc(name)) => {
+                let comment = annotator.annotate(op, &mut sink)?;
+                if !comment.is_empty() {
+                    writeln!(sink, "  {}", comment)?;
+                }
+                writeln!(sink, "  (func {}", func(name))?;
+            }
+            _ => writeln!(sink, "  (func $_start (export \"_start\")")?,
+        }
+        writeln!(sink, "    (local $a i64) (local $b i64) (local $pc i32)")?;
+        if proc.is_none() {
+            writeln!(sink, "    call $args")?;
+        }
+        let labels = body
+            .iter()
+            .filter_map(|op| match op {
+                Label(l) => l.as_str().some(),
+                _ => None,
+            })
+            .enumerate()
+            .map(|(i, l)| (l, i + 1))
+            .collect::<FnvHashMap<_, _>>();
+        if !labels.is_empty() {
+            // `$pc` picks the block to start at, jumps set it and restart the loop
+            writeln!(sink, "    loop $dispatch")?;
+            for i in (1..=labels.len()).rev() {
+                writeln!(sink, "    block $L{}", i)?;
+            }
+            writeln!(sink, "    block $start")?;
+            write!(sink, "    local.get $pc\n    br_table $start")?;
+            for i in 1..=labels.len() {
+                write!(sink, " $L{}", i)?;
+            }
+            writeln!(sink, "\n    end")?;
+        }
+        let target = |l: &String| {
+            labels.get(l.as_str()).copied().ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Jump to `{}` outside of its proc", l),
+                )
+            })
+        };
+        for op in body {
+            let comment = annotator.annotate(op, &mut sink)?;
+            let code = match op {
+                Push(c) => match c {
+                    IConst::Bool(b) => format!("i64.const {} call $push", *b as u64),
+                    IConst::Char(c) => format!("i64.const {} call $push", *c as u64),
+                    IConst::U64(u) => format!("i64.const {} call $push", u),
+                    IConst::I64(i) => format!("i64.const {} call $push", i),
+                    IConst::Ptr(p) => format!("i64.const {} call $push", p),
+                    IConst::Int(_) | IConst::Str(_) => unreachable!(),
+                },
+                PushStr(i) => format!(
+                    "i64.const {} call $push i64.const {} call $push",
+                    strings[*i].len(),
+                    literals[*i]
+                ),
+                PushMem(nm) | PushTable(nm) => format!("i64.const {} call $push", addresses[nm]),
+                Drop => "call $pop drop".to_string(),
+                Dup => "i32.const 0 call $peek call $push".to_string(),
+                Swap => "call $pop call $pop local.set $a call $push local.get $a call $push"
+                    .to_string(),
+                Over => "i32.const 1 call $peek call $push".to_string(),
+                Pick(depth) => format!("i32.const {} call $peek call $push", depth),
+                Roll(depth) => format!("i32.const {} call $roll", depth),
+
+                Bind => "call $bind".to_string(),
+                UseBinding(offset) => {
+                    format!("global.get $bp i64.load offset={} call $push", 8 * offset)
+                }
+                Unbind => "global.get $bp i32.const 8 i32.add global.set $bp".to_string(),
+
+                ReadU64 | ReadU32 | ReadU16 | ReadU8 | WriteU64 | WriteU32 | WriteU16
+                | WriteU8 => {
+                    let (size, code) = match op {
+                        ReadU64 => (8, "call $pop i32.wrap_i64 i64.load call $push"),
+                        ReadU32 => (4, "call $pop i32.wrap_i64 i64.load32_u call $push"),
+                        ReadU16 => (2, "call $pop i32.wrap_i64 i64.load16_u call $push"),
+                        ReadU8 => (1, "call $pop i32.wrap_i64 i64.load8_u call $push"),
+                        WriteU64 => (8, "call $pop i32.wrap_i64 call $pop i64.store"),
+                        WriteU32 => (4, "call $pop i32.wrap_i64 call $pop i64.store32"),
+                        WriteU16 => (2, "call $pop i32.wrap_i64 call $pop i64.store16"),
+                        _ => (1, "call $pop i32.wrap_i64 call $pop i64.store8"),
+                    };
+                    if check_alignment && size != 1 {
+                        format!("i32.const {} call $aligned {}", size, code)
+                    } else {
+                        code.to_string()
+                    }
+                }
+
+                Bswap64 => "call $pop i32.const 8 call $bswap call $push".to_string(),
+                Bswap32 => "call $pop i32.const 4 call $bswap call $push".to_string(),
+                Bswap16 => "call $pop i32.const 2 call $bswap call $push".to_string(),
+                ZeroExtend(bits) => {
+                    format!("call $pop i64.const {} i64.and call $push", (1u64 << bits) - 1)
+                }
+                SignExtend(bits) => format!("call $pop i64.extend{}_s call $push", bits),
+
+                ReserveEscaping(n) => {
+                    format!("global.get $esp i32.const {} i32.sub global.set $esp", n)
+                }
+                PushEscaping(n) => format!(
+                    "global.get $esp i32.const {} i32.add i64.extend_i32_u call $push",
+                    n
+                ),
+                ReserveLocals(n) => {
+                    format!("global.get $lsp i32.const {} i32.sub global.set $lsp", n)
+                }
+                FreeLocals(n) => {
+                    format!("global.get $lsp i32.const {} i32.add global.set $lsp", n)
+                }
+                PushLvar(o) => format!(
+                    "global.get $lsp i32.const {} i32.add i64.extend_i32_u call $push",
+                    o
+                ),
+
+                Print(format) => format!(
+                    "call $pop i64.const {} i64.const {} i64.const 0 call $print_fmt",
+                    format.radix(),
+                    format.is_signed() as u64
+                ),
+                PrintPadded(format) => format!(
+                    "call $pop local.set $b call $pop i64.const {} i64.const {} local.get $b call $print_fmt",
+                    format.radix(),
+                    format.is_signed() as u64
+                ),
+                HashStr => "call $pop call $pop call $hash_str call $push".to_string(),
+                FindByte => concat!(
+                    "call $pop call $pop local.tee $b call $pop call $find_byte local.tee $a ",
+                    "call $push local.get $a local.get $b i64.lt_u i64.extend_i32_u call $push"
+                )
+                .to_string(),
+                Copy16 => {
+                    "call $pop i32.wrap_i64 call $pop i32.wrap_i64 v128.load v128.store".to_string()
+                }
+                Cmp16 => concat!(
+                    "call $pop i32.wrap_i64 v128.load call $pop i32.wrap_i64 v128.load ",
+                    "i8x16.eq i8x16.bitmask i64.extend_i32_u call $push"
+                )
+                .to_string(),
+                Splat16 => {
+                    "call $pop i32.wrap_i64 call $pop i32.wrap_i64 i8x16.splat v128.store".to_string()
+                }
+
+                Syscall0 => syscall(0),
+                Syscall1 => syscall(1),
+                Syscall2 => syscall(2),
+                Syscall3 => syscall(3),
+                Syscall4 => syscall(4),
+                Syscall5 => syscall(5),
+                Syscall6 => syscall(6),
+                // the kernel reports errors as -errno in the last page of the address space
+                SplitErrno => "call $pop local.tee $a i64.const -4096 i64.gt_u if i64.const 0 local.get $a i64.sub call $push i64.const 1 call $push else local.get $a call $push i64.const 0 call $push end".to_string(),
+
+                Argc => "global.get $argc call $push".to_string(),
+                Argv => "global.get $argv call $push".to_string(),
+
+                Add => "call $pop call $pop i64.add call $push".to_string(),
+                Sub => "call $pop local.set $b call $pop local.get $b i64.sub call $push".to_string(),
+                Mul => "call $pop call $pop i64.mul call $push".to_string(),
+                BitAnd => "call $pop call $pop i64.and call $push".to_string(),
+                BitOr => "call $pop call $pop i64.or call $push".to_string(),
+                BitXor => "call $pop call $pop i64.xor call $push".to_string(),
+                BitNot => "call $pop i64.const -1 i64.xor call $push".to_string(),
+                // wasm takes shift counts modulo 64 as well
+                Shl => "call $pop local.set $b call $pop local.get $b i64.shl call $push".to_string(),
+                Shr => "call $pop local.set $b call $pop local.get $b i64.shr_u call $push".to_string(),
+                Sar => "call $pop local.set $b call $pop local.get $b i64.shr_s call $push".to_string(),
+                AddImm(k) => format!("call $pop i64.const {} i64.add call $push", k),
+                SubImm(k) => format!("call $pop i64.const {} i64.sub call $push", k),
+                // division by zero traps like the `div` instruction faults
+                Divmod => "call $pop local.set $b call $pop local.tee $a local.get $b i64.div_u call $push local.get $a local.get $b i64.rem_u call $push".to_string(),
+                IDivmod => "call $pop local.set $b call $pop local.tee $a local.get $b i64.div_s call $push local.get $a local.get $b i64.rem_s call $push".to_string(),
+
+                Eq => compare("i64.eq"),
+                Ne => compare("i64.ne"),
+                Lt => compare("i64.lt_s"),
+                Le => compare("i64.le_s"),
+                Gt => compare("i64.gt_s"),
+                Ge => compare("i64.ge_s"),
+                LtU => compare("i64.lt_u"),
+                LeU => compare("i64.le_u"),
+                GtU => compare("i64.gt_u"),
+                GeU => compare("i64.ge_u"),
+
+                Label(_) => "end".to_string(),
+                Jump(l) => format!("i32.const {} local.set $pc br $dispatch", target(l)?),
+                JumpF(l) => format!(
+                    "call $pop i64.eqz if i32.const {} local.set $pc br $dispatch end",
+                    target(l)?
+                ),
+                JumpT(l) => format!(
+                    "call $pop i64.const 0 i64.ne if i32.const {} local.set $pc br $dispatch end",
+                    target(l)?
+                ),
+                CmpImmBranch(cmp, k, l) => format!(
+                    "call $pop i64.const {} {} if i32.const {} local.set $pc br $dispatch end",
+                    k,
+                    match cmp {
+                        Cmp::Eq => "i64.eq",
+                        Cmp::Ne => "i64.ne",
+                        Cmp::Lt => "i64.lt_s",
+                        Cmp::Le => "i64.le_s",
+                        Cmp::Gt => "i64.gt_s",
+                        Cmp::Ge => "i64.ge_s",
+                        Cmp::LtU => "i64.lt_u",
+                        Cmp::LeU => "i64.le_u",
+                        Cmp::GtU => "i64.gt_u",
+                        Cmp::GeU => "i64.ge_u",
+                    },
+                    target(l)?
+                ),
+                Call(p) => format!("call {}", func(p)),
+                PushProc(p) => format!(
+                    "i64.const {} call $push",
+                    table.iter().position(|t| t == p).unwrap()
+                ),
+                CallIndirect(..) => "call $pop i32.wrap_i64 call_indirect (type $proc)".to_string(),
+                Return => "return".to_string(),
+                Exit => "call $pop i32.wrap_i64 call $proc_exit unreachable".to_string(),
+
+                Loc(_) | Dump => continue,
+                CallHost(name, _, _) => {
+                    return Error::new(
+                        ErrorKind::Unsupported,
+                        format!("Host proc `{}` can only be called from the evaluator", name),
+                    )
+                    .error()
+                }
+                Proc(_) => unreachable!(),
+            };
+            if !comment.is_empty() {
+                writeln!(sink, "    {}", comment)?;
+            }
+            writeln!(sink, "    {}", code)?;
+        }
+        if !labels.is_empty() {
+            writeln!(sink, "    end")?;
+        }
+        writeln!(sink, "  )")?;
+    }
+    writeln!(sink, ")")?;
+    ().okay()
+}
+
+/// Stack helpers, `print_fmt` and the syscall translation
+fn runtime<S: Write>(sink: &mut BufWriter<S>, heap: u64) -> std::io::Result<()> {
+    write!(
+        sink,
+        indoc! {r#"
+              (func $push (param $v i64)
+                global.get $sp
+                i32.const 8
+                i32.sub
+                global.set $sp
+                global.get $sp
+                local.get $v
+                i64.store)
+              (func $pop (result i64)
+                global.get $sp
+                i64.load
+                global.get $sp
+                i32.const 8
+                i32.add
+                global.set $sp)
+              (func $peek (param $depth i32) (result i64)
+                global.get $sp
+                local.get $depth
+                i32.const 3
+                i32.shl
+                i32.add
+                i64.load)
+              (func $roll (param $depth i32)
+                (local $v i64) (local $at i32)
+                global.get $sp
+                local.get $depth
+                i32.const 3
+                i32.shl
+                i32.add
+                local.tee $at
+                i64.load
+                local.set $v
+                block $done
+                  loop $shift
+                    local.get $at
+                    global.get $sp
+                    i32.le_u
+                    br_if $done
+                    local.get $at
+                    local.get $at
+                    i32.const 8
+                    i32.sub
+                    i64.load
+                    i64.store
+                    local.get $at
+                    i32.const 8
+                    i32.sub
+                    local.set $at
+                    br $shift
+                  end
+                end
+                global.get $sp
+                local.get $v
+                i64.store)
+              (func $bind
+                global.get $bp
+                i32.const 8
+                i32.sub
+                global.set $bp
+                global.get $bp
+                call $pop
+                i64.store)
+              (func $bswap (param $v i64) (param $bytes i32) (result i64)
+                (local $r i64)
+                block $done
+                  loop $shift
+                    local.get $bytes
+                    i32.eqz
+                    br_if $done
+                    local.get $r
+                    i64.const 8
+                    i64.shl
+                    local.get $v
+                    i64.const 255
+                    i64.and
+                    i64.or
+                    local.set $r
+                    local.get $v
+                    i64.const 8
+                    i64.shr_u
+                    local.set $v
+                    local.get $bytes
+                    i32.const 1
+                    i32.sub
+                    local.set $bytes
+                    br $shift
+                  end
+                end
+                local.get $r)
+              (func $write (param $fd i32) (param $buf i32) (param $len i32) (result i32)
+                i32.const {iov}
+                local.get $buf
+                i32.store
+                i32.const {iov}
+                local.get $len
+                i32.store offset=4
+                local.get $fd
+                i32.const {iov}
+                i32.const 1
+                i32.const {result}
+                call $fd_write)
+              (func $print_fmt (param $value i64) (param $radix i64) (param $signed i64) (param $width i64)
+                (local $p i32) (local $negative i32) (local $count i64)
+                i32.const {print_end}
+                local.set $p
+                local.get $signed
+                i64.const 0
+                i64.ne
+                local.get $value
+                i64.const 0
+                i64.lt_s
+                i32.and
+                local.tee $negative
+                if
+                  i64.const 0
+                  local.get $value
+                  i64.sub
+                  local.set $value
+                end
+                local.get $width
+                i64.const {max_width}
+                i64.gt_u
+                if
+                  i64.const {max_width}
+                  local.set $width
+                end
+                local.get $p
+                i32.const 1
+                i32.sub
+                local.tee $p
+                i32.const 10
+                i32.store8
+                loop $digits
+                  local.get $p
+                  i32.const 1
+                  i32.sub
+                  local.tee $p
+                  local.get $value
+                  local.get $radix
+                  i64.rem_u
+                  i32.wrap_i64
+                  i32.load8_u offset={digits}
+                  i32.store8
+                  local.get $value
+                  local.get $radix
+                  i64.div_u
+                  local.set $value
+                  local.get $count
+                  i64.const 1
+                  i64.add
+                  local.set $count
+                  local.get $value
+                  i64.const 0
+                  i64.ne
+                  br_if $digits
+                end
+                block $done
+                  loop $pad
+                    local.get $count
+                    local.get $width
+                    i64.ge_u
+                    br_if $done
+                    local.get $p
+                    i32.const 1
+                    i32.sub
+                    local.tee $p
+                    i32.const 48
+                    i32.store8
+                    local.get $count
+                    i64.const 1
+                    i64.add
+                    local.set $count
+                    br $pad
+                  end
+                end
+                local.get $negative
+                if
+                  local.get $p
+                  i32.const 1
+                  i32.sub
+                  local.tee $p
+                  i32.const 45
+                  i32.store8
+                end
+                i32.const 1
+                local.get $p
+                i32.const {print_end}
+                local.get $p
+                i32.sub
+                call $write
+                drop)
+              (func $hash_str (param $ptr i64) (param $len i64) (result i64)
+                (local $hash i64) (local $p i32) (local $end i32)
+                i64.const {hash_offset}
+                local.set $hash
+                local.get $ptr
+                i32.wrap_i64
+                local.tee $p
+                local.get $len
+                i32.wrap_i64
+                i32.add
+                local.set $end
+                block $done
+                  loop $bytes
+                    local.get $p
+                    local.get $end
+                    i32.ge_u
+                    br_if $done
+                    local.get $hash
+                    local.get $p
+                    i64.load8_u
+                    i64.xor
+                    i64.const {hash_prime}
+                    i64.mul
+                    local.set $hash
+                    local.get $p
+                    i32.const 1
+                    i32.add
+                    local.set $p
+                    br $bytes
+                  end
+                end
+                local.get $hash)
+              (func $find_byte (param $byte i64) (param $len i64) (param $ptr i64) (result i64)
+                (local $p i32) (local $end i32) (local $v v128) (local $mask i32)
+                local.get $byte
+                i32.wrap_i64
+                i8x16.splat
+                local.set $v
+                local.get $ptr
+                i32.wrap_i64
+                local.tee $p
+                local.get $len
+                i32.wrap_i64
+                i32.add
+                local.set $end
+                block $tail
+                  loop $wide
+                    local.get $end
+                    local.get $p
+                    i32.sub
+                    i32.const 16
+                    i32.lt_u
+                    br_if $tail
+                    local.get $p
+                    v128.load
+                    local.get $v
+                    i8x16.eq
+                    i8x16.bitmask
+                    local.tee $mask
+                    if
+                      local.get $p
+                      local.get $mask
+                      i32.ctz
+                      i32.add
+                      local.set $p
+                      br $tail
+                    end
+                    local.get $p
+                    i32.const 16
+                    i32.add
+                    local.set $p
+                    br $wide
+                  end
+                end
+                block $done
+                  loop $bytes
+                    local.get $p
+                    local.get $end
+                    i32.ge_u
+                    br_if $done
+                    local.get $p
+                    i32.load8_u
+                    local.get $byte
+                    i32.wrap_i64
+                    i32.const 255
+                    i32.and
+                    i32.eq
+                    br_if $done
+                    local.get $p
+                    i32.const 1
+                    i32.add
+                    local.set $p
+                    br $bytes
+                  end
+                end
+                local.get $p
+                local.get $ptr
+                i32.wrap_i64
+                i32.sub
+                i64.extend_i32_u)
+              (func $aligned (param $size i32)
+                i32.const 0
+                call $peek
+                i32.wrap_i64
+                local.get $size
+                i32.rem_u
+                if
+                  i32.const 2
+                  i32.const {misaligned}
+                  i32.const 25
+                  call $write
+                  drop
+                  i32.const 1
+                  call $proc_exit
+                end)
+              (func $strlen (param $s i32) (result i32)
+                (local $p i32)
+                local.get $s
+                local.set $p
+                block $done
+                  loop $bytes
+                    local.get $p
+                    i32.load8_u
+                    i32.eqz
+                    br_if $done
+                    local.get $p
+                    i32.const 1
+                    i32.add
+                    local.set $p
+                    br $bytes
+                  end
+                end
+                local.get $p
+                local.get $s
+                i32.sub)
+              (func $result (param $errno i32) (param $value i64) (result i64)
+                local.get $errno
+                i32.eqz
+                if
+                  local.get $value
+                  return
+                end
+                i64.const 0
+                local.get $errno
+                i64.load8_u offset={errnos}
+                i64.sub)
+              (func $syscall (param $n i64) (param $a i64) (param $b i64) (param $c i64) (param $d i64) (param $e i64) (param $f i64) (result i64)
+                local.get $n
+                i64.eqz
+                if
+                  i32.const {iov}
+                  local.get $b
+                  i64.store32
+                  i32.const {iov}
+                  local.get $c
+                  i64.store32 offset=4
+                  local.get $a
+                  i32.wrap_i64
+                  i32.const {iov}
+                  i32.const 1
+                  i32.const {result}
+                  call $fd_read
+                  i32.const {result}
+                  i64.load32_u
+                  call $result
+                  return
+                end
+                local.get $n
+                i64.const 1
+                i64.eq
+                if
+                  local.get $a
+                  i32.wrap_i64
+                  local.get $b
+                  i32.wrap_i64
+                  local.get $c
+                  i32.wrap_i64
+                  call $write
+                  i32.const {result}
+                  i64.load32_u
+                  call $result
+                  return
+                end
+                local.get $n
+                i64.const 2
+                i64.eq
+                if
+                  i32.const 3
+                  i32.const 1
+                  local.get $a
+                  i32.wrap_i64
+                  local.get $a
+                  i32.wrap_i64
+                  call $strlen
+                  local.get $b
+                  i64.const 6
+                  i64.shr_u
+                  i64.const 1
+                  i64.and
+                  local.get $b
+                  i64.const 15
+                  i64.shr_u
+                  i64.const 2
+                  i64.and
+                  i64.or
+                  local.get $b
+                  i64.const 5
+                  i64.shr_u
+                  i64.const 4
+                  i64.and
+                  i64.or
+                  local.get $b
+                  i64.const 6
+                  i64.shr_u
+                  i64.const 8
+                  i64.and
+                  i64.or
+                  i32.wrap_i64
+                  i64.const {rights}
+                  i64.const {rights}
+                  local.get $b
+                  i64.const 10
+                  i64.shr_u
+                  i64.const 1
+                  i64.and
+                  i32.wrap_i64
+                  i32.const {result}
+                  call $path_open
+                  i32.const {result}
+                  i64.load32_u
+                  call $result
+                  return
+                end
+                local.get $n
+                i64.const 3
+                i64.eq
+                if
+                  local.get $a
+                  i32.wrap_i64
+                  call $fd_close
+                  i64.const 0
+                  call $result
+                  return
+                end
+                local.get $n
+                i64.const 8
+                i64.eq
+                if
+                  local.get $a
+                  i32.wrap_i64
+                  local.get $b
+                  local.get $c
+                  i32.wrap_i64
+                  i32.const {result}
+                  call $fd_seek
+                  i32.const {result}
+                  i64.load
+                  call $result
+                  return
+                end
+                local.get $n
+                i64.const 24
+                i64.eq
+                if
+                  call $sched_yield
+                  i64.const 0
+                  call $result
+                  return
+                end
+                local.get $n
+                i64.const 60
+                i64.eq
+                local.get $n
+                i64.const 231
+                i64.eq
+                i32.or
+                if
+                  local.get $a
+                  i32.wrap_i64
+                  call $proc_exit
+                end
+                local.get $n
+                i64.const 83
+                i64.eq
+                if
+                  i32.const 3
+                  local.get $a
+                  i32.wrap_i64
+                  local.get $a
+                  i32.wrap_i64
+                  call $strlen
+                  call $path_create_directory
+                  i64.const 0
+                  call $result
+                  return
+                end
+                local.get $n
+                i64.const 84
+                i64.eq
+                if
+                  i32.const 3
+                  local.get $a
+                  i32.wrap_i64
+                  local.get $a
+                  i32.wrap_i64
+                  call $strlen
+                  call $path_remove_directory
+                  i64.const 0
+                  call $result
+                  return
+                end
+                local.get $n
+                i64.const 87
+                i64.eq
+                if
+                  i32.const 3
+                  local.get $a
+                  i32.wrap_i64
+                  local.get $a
+                  i32.wrap_i64
+                  call $strlen
+                  call $path_unlink_file
+                  i64.const 0
+                  call $result
+                  return
+                end
+                i64.const -38)
+              (func $args
+                (local $argc i32) (local $strings i32) (local $i i32)
+                i32.const {args}
+                i32.const {args_size}
+                call $args_sizes_get
+                drop
+                i32.const {args}
+                i32.load
+                local.tee $argc
+                i64.extend_i32_u
+                global.set $argc
+                i64.const {heap}
+                global.set $argv
+                local.get $argc
+                i32.const 12
+                i32.mul
+                i32.const {heap_words}
+                i32.add
+                local.tee $strings
+                i32.const {args_size}
+                i32.load
+                i32.add
+                i32.const {page_mask}
+                i32.add
+                i32.const 16
+                i32.shr_u
+                memory.size
+                i32.sub
+                local.tee $i
+                i32.const 0
+                i32.gt_s
+                if
+                  local.get $i
+                  memory.grow
+                  drop
+                end
+                local.get $strings
+                local.get $argc
+                i32.const 4
+                i32.mul
+                i32.sub
+                local.get $strings
+                call $args_get
+                drop
+                i32.const 0
+                local.set $i
+                block $done
+                  loop $widen
+                    local.get $i
+                    local.get $argc
+                    i32.ge_u
+                    br_if $done
+                    local.get $i
+                    i32.const 3
+                    i32.shl
+                    local.get $strings
+                    local.get $argc
+                    local.get $i
+                    i32.sub
+                    i32.const 2
+                    i32.shl
+                    i32.sub
+                    i64.load32_u
+                    i64.store offset={heap}
+                    local.get $i
+                    i32.const 1
+                    i32.add
+                    local.set $i
+                    br $widen
+                  end
+                end)
+        "#},
+        iov = IOV,
+        result = RESULT,
+        args = ARGS,
+        args_size = ARGS + 4,
+        digits = DIGITS,
+        print_end = PRINT_BUFFER + 32,
+        max_width = PrintFormat::MAX_WIDTH,
+        errnos = ERRNOS,
+        misaligned = MISALIGNED,
+        hash_offset = HASH_OFFSET,
+        hash_prime = HASH_PRIME,
+        heap = heap,
+        heap_words = heap + 8,
+        page_mask = PAGE - 1,
+        rights = RIGHTS,
+    )
+}
+
+fn align(offset: u64, to: u64) -> u64 {
+    offset.div_ceil(to) * to
+}
+
+fn syscall(args: usize) -> String {
+    let mut code = "call $pop".to_string();
+    for i in 0..6 {
+        code += if i < args {
+            " call $pop"
+        } else {
+            " i64.const 0"
+        };
+    }
+    code + " call $syscall call $push"
+}
+
+fn compare(instruction: &str) -> String {
+    format!(
+        "call $pop local.set $b call $pop local.get $b {} i64.extend_i32_u call $push",
+        instruction
+    )
+}
+
+/// Function name of a proc, with what WAT does not allow in identifiers escaped
+fn func(name: &str) -> String {
+    let mut res = String::from("$rh_");
+    for c in name.chars() {
+        match c {
+            c if c.is_ascii_alphanumeric() || "_.-".contains(c) => res.push(c),
+            c => res += &format!("%{:x}", c as u32),
+        }
+    }
+    res
+}
+
+/// A WAT string literal holding exactly `bytes`
+fn literal(bytes: &[u8]) -> String {
+    let mut res = String::from("\"");
+    for &b in bytes {
+        match b {
+            b'"' | b'\\' => res += &format!("\\{:02x}", b),
+            b' '..=b'~' => res.push(b as char),
+            b => res += &format!("\\{:02x}", b),
+        }
+    }
+    res.push('"');
+    res
+}
+
+#[test]
+fn test_wat_literal() {
+    assert_eq!(literal(b"a\"b\\c\n"), r#""a\22b\5cc\0a""#);
+    assert_eq!(func("proc3_foo"), "$rh_proc3_foo");
+    assert_eq!(syscall(1), "call $pop call $pop i64.const 0 i64.const 0 i64.const 0 i64.const 0 i64.const 0 call $syscall call $push");
+}
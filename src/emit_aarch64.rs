@@ -0,0 +1,1239 @@
+use crate::{
+    emit::{
+        symbols::{data_layout, Datum},
+        Annotator,
+    },
+    hir::PrintFormat,
+    iconst::IConst,
+    intrinsics::{HASH_OFFSET, HASH_PRIME},
+    lir::{demangle, Cmp, Op, Program},
+    session::{Session, AARCH64_SYSCALLS},
+};
+use indoc::indoc;
+use somok::Somok;
+use std::io::{BufWriter, Write};
+
+/// Frames kept by the shadow call stack of checked builds, deeper calls share the last slot
+const TRACE_FRAMES: usize = 256;
+/// The hardware stack must stay 16 byte aligned, so the data stack lives in `.bss`
+const DATA_STACK_SIZE: usize = 8 << 20;
+/// What the kernel answers `ENOSYS` to, for x86-64 syscalls without an AArch64 equivalent
+const NO_SYSCALL: u64 = 0xffff;
+
+/// AArch64 number of each x86-64 syscall, the last entry catches every number past the table
+fn syscall_table() -> Vec<u64> {
+    let len = AARCH64_SYSCALLS
+        .iter()
+        .map(|&(_, x86, _)| x86)
+        .max()
+        .unwrap_or(0) as usize
+        + 2;
+    let mut table = vec![NO_SYSCALL; len];
+    for &(_, x86, aarch64) in AARCH64_SYSCALLS {
+        table[x86 as usize] = aarch64;
+    }
+    table
+}
+
+/// Loads a 64 bit immediate, with a `movk` for every nonzero upper halfword
+fn mov_imm(reg: &str, value: u64) -> String {
+    let mut res = format!("    mov {}, #{}\n", reg, value & 0xffff);
+    for shift in [16, 32, 48] {
+        let half = (value >> shift) & 0xffff;
+        if half != 0 {
+            res += &format!("    movk {}, #{}, lsl #{}\n", reg, half, shift);
+        }
+    }
+    res
+}
+
+/// `k` as an operand, an immediate if it fits in the 12 bits `add` and `cmp` take and otherwise
+/// loaded into `scratch` first, along with the code loading it
+fn imm_operand(k: u64, scratch: &str) -> (String, String) {
+    if k < 4096 {
+        (String::new(), format!("#{}", k))
+    } else {
+        (mov_imm(scratch, k), scratch.to_string())
+    }
+}
+
+/// Emits GNU assembly for AArch64 Linux.
+/// The data stack pointer lives in x28, the binding stack in x27 and the locals
+/// and escaping stacks in x26 and x25, the runtime routines leave them alone.
+pub fn compile<S: Write>(
+    program: Program,
+    session: &Session,
+    mut sink: BufWriter<S>,
+) -> std::io::Result<()> {
+    let ops = program.ops;
+    let mems = &program.mems;
+    use Op::*;
+    let options = &session.options;
+    let strings = session.strings();
+    let check_alignment = options.checked && options.strict_alignment;
+    let proc_names = ops
+        .iter()
+        .filter_map(|op| match op {
+            Proc(l) => l.clone().some(),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let hashes = ops.iter().any(|op| matches!(op, HashStr));
+    let finds = ops.iter().any(|op| matches!(op, FindByte));
+    let runtime = options.runtime;
+    let traps = options.checked && runtime.traps;
+    let syscalls = syscall_table();
+    write!(
+        sink,
+        indoc! {"
+            .macro adr_l reg, sym
+                adrp \\reg, \\sym
+                add \\reg, \\reg, :lo12:\\sym
+            .endm
+
+            .text
+            .global _start
+        "}
+    )?;
+    if options.checked && !runtime.traps {
+        // the user's trap handler gets the shadow call stack to print a backtrace
+        write!(
+            sink,
+            indoc! {"
+                .global trace_depth
+                .global trace_stack
+            "}
+        )?;
+    }
+    for name in program.extern_mems.keys() {
+        // the object defining it names it without the prefix of mem labels
+        writeln!(sink, ".set mem_{0}, {0}", name)?;
+    }
+    write!(
+        sink,
+        indoc! {"
+
+            _start:
+                adr_l x28, data_stack_end
+                adr_l x27, ret_stack_end
+                adr_l x26, locals_stack_end
+                adr_l x25, escaping_stack_end
+            // set up args
+                ldr x0, [sp]
+                adr_l x9, argc
+                str x0, [x9]
+                add x0, sp, #8
+                adr_l x9, argv
+                str x0, [x9]
+
+        "},
+    )?;
+    let mut annotator = Annotator::new(options, "//");
+    for op in ops {
+        let comment = annotator.annotate(&op, &mut sink)?;
+        match &op {
+            PushMem(nm) => write!(
+                sink,
+                indoc! {"
+                    {}
+                        adr_l x0, mem_{}
+                        str x0, [x28, #-8]!
+                    "},
+                comment, nm
+            )?,
+            PushTable(nm) => write!(
+                sink,
+                indoc! {"
+                    {}
+                        adr_l x0, table_{}
+                        str x0, [x28, #-8]!
+                    "},
+                comment, nm
+            )?,
+            PushStr(i) => write!(
+                sink,
+                indoc! {"
+                    {}
+                    {}    str x0, [x28, #-8]!
+                        adr_l x0, str_{}
+                        str x0, [x28, #-8]!
+                    "},
+                comment,
+                mov_imm("x0", strings[*i].len() as u64),
+                i
+            )?,
+            Push(c) => {
+                let value = match c {
+                    IConst::Bool(b) => *b as u64,
+                    IConst::Char(c) => *c as u64,
+                    IConst::U64(u) => *u,
+                    IConst::I64(i) => *i as u64,
+                    IConst::Ptr(p) => *p,
+                    IConst::Int(_) | IConst::Str(_) => unreachable!(),
+                };
+                write!(
+                    sink,
+                    indoc! {"
+                        {}
+                        {}    str x0, [x28, #-8]!
+                        "},
+                    comment,
+                    mov_imm("x0", value)
+                )?
+            }
+            Dup => write!(
+                sink,
+                indoc! {"
+                    {}
+                        ldr x0, [x28]
+                        str x0, [x28, #-8]!
+                    "},
+                comment
+            )?,
+            Swap => write!(
+                sink,
+                indoc! {"
+                    {}
+                        ldp x0, x1, [x28]
+                        stp x1, x0, [x28]
+                    "},
+                comment
+            )?,
+            Over => write!(
+                sink,
+                indoc! {"
+                    {}
+                        ldr x0, [x28, #8]
+                        str x0, [x28, #-8]!
+                    "},
+                comment
+            )?,
+            Pick(depth) => write!(
+                sink,
+                indoc! {"
+                    {}
+                        ldr x0, [x28, #{}]
+                        str x0, [x28, #-8]!
+                    "},
+                comment,
+                8 * depth
+            )?,
+            Roll(depth) => {
+                write!(
+                    sink,
+                    indoc! {"
+                        {}
+                            ldr x0, [x28, #{}]
+                        "},
+                    comment,
+                    8 * depth
+                )?;
+                for i in (0..*depth).rev() {
+                    writeln!(sink, "    ldr x1, [x28, #{}]", 8 * i)?;
+                    writeln!(sink, "    str x1, [x28, #{}]", 8 * (i + 1))?;
+                }
+                writeln!(sink, "    str x0, [x28]")?;
+            }
+            Drop => write!(
+                sink,
+                indoc! {"
+                    {}
+                        add x28, x28, #8
+                    "},
+                comment
+            )?,
+
+            ReserveEscaping(n) => write!(
+                sink,
+                indoc! {"
+                    {}
+                    {}    sub x25, x25, x9
+                    "},
+                comment,
+                mov_imm("x9", *n as u64)
+            )?,
+            PushEscaping(n) => write!(
+                sink,
+                indoc! {"
+                    {}
+                    {}    add x0, x25, x9
+                        str x0, [x28, #-8]!
+                    "},
+                comment,
+                mov_imm("x9", *n as u64)
+            )?,
+
+            ReserveLocals(n) => write!(
+                sink,
+                indoc! {"
+                    {}
+                    {}    sub x26, x26, x9
+                    "},
+                comment,
+                mov_imm("x9", *n as u64)
+            )?,
+            FreeLocals(n) => write!(
+                sink,
+                indoc! {"
+                    {}
+                    {}    add x26, x26, x9
+                    "},
+                comment,
+                mov_imm("x9", *n as u64)
+            )?,
+            PushLvar(o) => write!(
+                sink,
+                indoc! {"
+                    {}
+                    {}    add x0, x26, x9
+                        str x0, [x28, #-8]!
+                    "},
+                comment,
+                mov_imm("x9", *o as u64)
+            )?,
+
+            Bind => write!(
+                sink,
+                indoc! {"
+                    {}
+                        ldr x0, [x28], #8
+                        str x0, [x27, #-8]!
+                    "},
+                comment
+            )?,
+            UseBinding(offset) => write!(
+                sink,
+                indoc! {"
+                    {}
+                        ldr x0, [x27, #{}]
+                        str x0, [x28, #-8]!
+                    "},
+                comment,
+                8 * offset
+            )?,
+            Unbind => write!(
+                sink,
+                indoc! {"
+                    {}
+                        add x27, x27, #8
+                    "},
+                comment
+            )?,
+
+            ReadU64 | ReadU32 | ReadU16 | ReadU8 => {
+                let (load, reg, align) = match op {
+                    ReadU64 => ("ldr", "x1", 7),
+                    ReadU32 => ("ldr", "w1", 3),
+                    ReadU16 => ("ldrh", "w1", 1),
+                    _ => ("ldrb", "w1", 0),
+                };
+                write!(
+                    sink,
+                    indoc! {"
+                        {}
+                            ldr x0, [x28], #8
+                        "},
+                    comment
+                )?;
+                if check_alignment && align != 0 {
+                    write!(
+                        sink,
+                        indoc! {"
+                                tst x0, #{}
+                                b.ne trap_misaligned
+                            "},
+                        align
+                    )?;
+                }
+                write!(
+                    sink,
+                    indoc! {"
+                            {} {}, [x0]
+                            str x1, [x28, #-8]!
+                        "},
+                    load, reg
+                )?
+            }
+            WriteU64 | WriteU32 | WriteU16 | WriteU8 => {
+                let (store, reg, align) = match op {
+                    WriteU64 => ("str", "x1", 7),
+                    WriteU32 => ("str", "w1", 3),
+                    WriteU16 => ("strh", "w1", 1),
+                    _ => ("strb", "w1", 0),
+                };
+                write!(
+                    sink,
+                    indoc! {"
+                        {}
+                            ldr x0, [x28], #8
+                            ldr x1, [x28], #8
+                        "},
+                    comment
+                )?;
+                if check_alignment && align != 0 {
+                    write!(
+                        sink,
+                        indoc! {"
+                                tst x0, #{}
+                                b.ne trap_misaligned
+                            "},
+                        align
+                    )?;
+                }
+                write!(
+                    sink,
+                    indoc! {"
+                            {} {}, [x0]
+                        "},
+                    store, reg
+                )?
+            }
+
+            Bswap64 => write!(
+                sink,
+                indoc! {"
+                    {}
+                        ldr x0, [x28]
+                        rev x0, x0
+                        str x0, [x28]
+                    "},
+                comment
+            )?,
+            Bswap32 => write!(
+                sink,
+                indoc! {"
+                    {}
+                        ldr x0, [x28]
+                        rev w0, w0
+                        str x0, [x28]
+                    "},
+                comment
+            )?,
+            Bswap16 => write!(
+                sink,
+                indoc! {"
+                    {}
+                        ldr x0, [x28]
+                        rev16 w0, w0
+                        uxth w0, w0
+                        str x0, [x28]
+                    "},
+                comment
+            )?,
+            ZeroExtend(bits) | SignExtend(bits) => write!(
+                sink,
+                indoc! {"
+                    {}
+                        ldr x0, [x28]
+                        {}
+                        str x0, [x28]
+                    "},
+                comment,
+                match (&op, *bits) {
+                    (ZeroExtend(_), 32) => "mov w0, w0",
+                    (ZeroExtend(_), 16) => "uxth w0, w0",
+                    (ZeroExtend(_), _) => "uxtb w0, w0",
+                    (_, 32) => "sxtw x0, w0",
+                    (_, 16) => "sxth x0, w0",
+                    _ => "sxtb x0, w0",
+                }
+            )?,
+
+            Print(PrintFormat::Unsigned) => write!(
+                sink,
+                indoc! {"
+                    {}
+                        ldr x0, [x28], #8
+                        bl print
+                    "},
+                comment
+            )?,
+            Print(format) => write!(
+                sink,
+                indoc! {"
+                    {}
+                        ldr x0, [x28], #8
+                        mov x1, #{}
+                        mov x2, #{}
+                        mov x3, #0
+                        bl print_fmt
+                    "},
+                comment,
+                format.radix(),
+                format.is_signed() as u64
+            )?,
+            PrintPadded(format) => write!(
+                sink,
+                indoc! {"
+                    {}
+                        ldr x3, [x28], #8
+                        ldr x0, [x28], #8
+                        mov x1, #{}
+                        mov x2, #{}
+                        bl print_fmt
+                    "},
+                comment,
+                format.radix(),
+                format.is_signed() as u64
+            )?,
+
+            HashStr => write!(
+                sink,
+                indoc! {"
+                    {}
+                        ldr x1, [x28], #8
+                        ldr x2, [x28], #8
+                        bl hash_str
+                        str x0, [x28, #-8]!
+                    "},
+                comment
+            )?,
+
+            Copy16 => write!(
+                sink,
+                indoc! {"
+                    {}
+                        ldp x0, x1, [x28], #16
+                        ldr q0, [x1]
+                        str q0, [x0]
+                    "},
+                comment
+            )?,
+            // NEON has no pmovmskb, so each equal byte keeps the bit of its lane
+            // and the two halves are summed into the low and high byte of the mask
+            Cmp16 => write!(
+                sink,
+                indoc! {"
+                    {}
+                        ldp x0, x1, [x28], #8
+                        ldr q0, [x1]
+                        ldr q1, [x0]
+                        cmeq v0.16b, v0.16b, v1.16b
+                        movz x2, #0x0201
+                        movk x2, #0x0804, lsl #16
+                        movk x2, #0x2010, lsl #32
+                        movk x2, #0x8040, lsl #48
+                        dup v1.2d, x2
+                        and v0.16b, v0.16b, v1.16b
+                        addv b1, v0.8b
+                        ext v0.16b, v0.16b, v0.16b, #8
+                        addv b0, v0.8b
+                        umov w1, v1.b[0]
+                        umov w2, v0.b[0]
+                        orr x1, x1, x2, lsl #8
+                        str x1, [x28]
+                    "},
+                comment
+            )?,
+            Splat16 => write!(
+                sink,
+                indoc! {"
+                    {}
+                        ldp x0, x1, [x28], #16
+                        dup v0.16b, w1
+                        str q0, [x0]
+                    "},
+                comment
+            )?,
+            FindByte => write!(
+                sink,
+                indoc! {"
+                    {}
+                        ldr x3, [x28], #8
+                        ldp x2, x1, [x28], #16
+                        bl find_byte
+                        stp x1, x0, [x28, #-16]!
+                    "},
+                comment
+            )?,
+
+            Syscall0 | Syscall1 | Syscall2 | Syscall3 | Syscall4 | Syscall5 | Syscall6 => {
+                let args = match op {
+                    Syscall0 => 0,
+                    Syscall1 => 1,
+                    Syscall2 => 2,
+                    Syscall3 => 3,
+                    Syscall4 => 4,
+                    Syscall5 => 5,
+                    _ => 6,
+                };
+                write!(
+                    sink,
+                    indoc! {"
+                        {}
+                            ldr x8, [x28], #8
+                        "},
+                    comment
+                )?;
+                for reg in 0..args {
+                    writeln!(sink, "    ldr x{}, [x28], #8", reg)?;
+                }
+                write!(
+                    sink,
+                    indoc! {"
+                        // translate the x86-64 number
+                            mov x9, #{}
+                            cmp x8, x9
+                            csel x8, x8, x9, lo
+                            adr_l x9, syscall_table
+                            ldrh w8, [x9, x8, lsl #1]
+                            svc #0
+                            str x0, [x28, #-8]!
+                        "},
+                    syscalls.len() - 1
+                )?
+            }
+            SplitErrno => write!(
+                sink,
+                indoc! {"
+                    {}
+                        ldr x0, [x28], #8
+                        cmn x0, #4095
+                        neg x1, x0
+                        csel x0, x1, x0, hs
+                        cset x2, hs
+                        str x0, [x28, #-8]!
+                        str x2, [x28, #-8]!
+                    "},
+                comment
+            )?,
+
+            Argc => write!(
+                sink,
+                indoc! {"
+                    {}
+                        adr_l x9, argc
+                        ldr x0, [x9]
+                        str x0, [x28, #-8]!
+                    "},
+                comment
+            )?,
+            Argv => write!(
+                sink,
+                indoc! {"
+                    {}
+                        adr_l x9, argv
+                        ldr x0, [x9]
+                        str x0, [x28, #-8]!
+                    "},
+                comment
+            )?,
+
+            Sub | Add | Mul | BitAnd | BitOr | BitXor | Shl | Shr | Sar => write!(
+                sink,
+                indoc! {"
+                    {}
+                        ldr x0, [x28], #8
+                        ldr x1, [x28]
+                        {} x1, x1, x0
+                        str x1, [x28]
+                    "},
+                comment,
+                match op {
+                    Sub => "sub",
+                    Add => "add",
+                    Mul => "mul",
+                    BitAnd => "and",
+                    BitOr => "orr",
+                    BitXor => "eor",
+                    // register shifts take the count modulo 64, like x86
+                    Shl => "lsl",
+                    Shr => "lsr",
+                    _ => "asr",
+                }
+            )?,
+            BitNot => write!(
+                sink,
+                indoc! {"
+                    {}
+                        ldr x0, [x28]
+                        mvn x0, x0
+                        str x0, [x28]
+                    "},
+                comment
+            )?,
+            AddImm(k) | SubImm(k) => {
+                let (load, k) = imm_operand(*k, "x1");
+                write!(
+                    sink,
+                    indoc! {"
+                        {}
+                            ldr x0, [x28]
+                        {}    {} x0, x0, {}
+                            str x0, [x28]
+                        "},
+                    comment,
+                    load,
+                    if matches!(op, AddImm(_)) {
+                        "add"
+                    } else {
+                        "sub"
+                    },
+                    k
+                )?
+            }
+            Divmod | IDivmod => write!(
+                sink,
+                indoc! {"
+                    {}
+                        ldp x1, x0, [x28]
+                        {} x2, x0, x1
+                        msub x3, x2, x1, x0
+                        stp x3, x2, [x28]
+                    "},
+                comment,
+                if matches!(op, IDivmod) {
+                    "sdiv"
+                } else {
+                    "udiv"
+                }
+            )?,
+
+            Eq | Ne | Lt | Le | Gt | Ge | LtU | LeU | GtU | GeU => write!(
+                sink,
+                indoc! {"
+                    {}
+                        ldr x1, [x28], #8
+                        ldr x0, [x28]
+                        cmp x0, x1
+                        cset x2, {}
+                        str x2, [x28]
+                    "},
+                comment,
+                match op {
+                    Eq => "eq",
+                    Ne => "ne",
+                    Lt => "lt",
+                    Le => "le",
+                    Gt => "gt",
+                    Ge => "ge",
+                    LtU => "lo",
+                    LeU => "ls",
+                    GtU => "hi",
+                    _ => "hs",
+                }
+            )?,
+
+            Return if options.checked => write!(
+                sink,
+                indoc! {"
+                    // pop trace frame
+                        adr_l x9, trace_depth
+                        ldr x10, [x9]
+                        sub x10, x10, #1
+                        str x10, [x9]
+                    // load return address
+                        ldr x30, [x27], #8
+                    {}
+                        ret
+                    "},
+                comment
+            )?,
+            Return => write!(
+                sink,
+                indoc! {"
+                    // load return address
+                        ldr x30, [x27], #8
+                    {}
+                        ret
+                    "},
+                comment
+            )?,
+            Call(p) => write!(
+                sink,
+                indoc! {"
+                    {}
+                        bl {}
+                    "},
+                comment, p
+            )?,
+            PushProc(p) => write!(
+                sink,
+                indoc! {"
+                    {}
+                        adr_l x0, {}
+                        str x0, [x28, #-8]!
+                    "},
+                comment, p
+            )?,
+            CallIndirect(..) => write!(
+                sink,
+                indoc! {"
+                    {}
+                        ldr x0, [x28], #8
+                        blr x0
+                    "},
+                comment
+            )?,
+            CallHost(name, _, _) => {
+                return std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    format!("Host proc `{}` can only be called from the evaluator", name),
+                )
+                .error()
+            }
+            Exit => write!(
+                sink,
+                indoc! {"
+                    {}
+                        ldr x0, [x28], #8
+                        mov x8, #93
+                        svc #0
+                    "},
+                comment
+            )?,
+            Proc(l) if options.checked => write!(
+                sink,
+                indoc! {"
+                    {}:
+                    // save return address
+                        str x30, [x27, #-8]!
+                    // push trace frame
+                        adr_l x9, trace_depth
+                        ldr x10, [x9]
+                        mov x11, #{}
+                        cmp x10, x11
+                        csel x11, x10, x11, ls
+                        adr_l x12, trace_stack
+                    {}    str x13, [x12, x11, lsl #3]
+                        add x10, x10, #1
+                        str x10, [x9]
+                    "},
+                l,
+                TRACE_FRAMES - 1,
+                mov_imm(
+                    "x13",
+                    proc_names.iter().position(|p| p == l).unwrap() as u64
+                )
+            )?,
+            Proc(l) => write!(
+                sink,
+                indoc! {"
+                    {}:
+                    // save return address
+                        str x30, [x27, #-8]!
+                    "},
+                l
+            )?,
+            Label(l) => write!(
+                sink,
+                indoc! {"
+                    {}:
+                    "},
+                l
+            )?,
+            JumpF(l) => write!(
+                sink,
+                indoc! {"
+                    {}
+                        ldr x0, [x28], #8
+                        cbz x0, {}
+                    "},
+                comment, l
+            )?,
+            JumpT(l) => write!(
+                sink,
+                indoc! {"
+                    {}
+                        ldr x0, [x28], #8
+                        cbnz x0, {}
+                    "},
+                comment, l
+            )?,
+            CmpImmBranch(cmp, k, l) => {
+                let (load, k) = imm_operand(*k, "x1");
+                write!(
+                    sink,
+                    indoc! {"
+                        {}
+                            ldr x0, [x28], #8
+                        {}    cmp x0, {}
+                            b.{} {}
+                        "},
+                    comment,
+                    load,
+                    k,
+                    match cmp {
+                        Cmp::Eq => "eq",
+                        Cmp::Ne => "ne",
+                        Cmp::Lt => "lt",
+                        Cmp::Le => "le",
+                        Cmp::Gt => "gt",
+                        Cmp::Ge => "ge",
+                        Cmp::LtU => "lo",
+                        Cmp::LeU => "ls",
+                        Cmp::GtU => "hi",
+                        Cmp::GeU => "hs",
+                    },
+                    l
+                )?
+            }
+            Jump(l) => write!(
+                sink,
+                indoc! {"
+                    {}
+                        b {}
+                    "},
+                comment, l
+            )?,
+            Loc(_) => {}
+            Dump => {}
+        }
+    }
+    if runtime.print {
+        write!(
+            sink,
+            indoc! {"
+
+                .global print
+                .global print_fmt
+                // unsigned decimal
+                print:
+                    mov x1, #10
+                    mov x2, #0
+                    mov x3, #0
+                // x0 = value, x1 = radix, x2 = signed flag, x3 = minimum digit count
+                print_fmt:
+                    mov x9, #{}
+                    cmp x3, x9
+                    csel x3, x9, x3, hi
+                    mov x10, #0
+                    cbz x2, 1f
+                    tbz x0, #63, 1f
+                    neg x0, x0
+                    mov x10, #1
+                1:
+                // the string is built backwards from the newline
+                    adr_l x11, print_buffer
+                    add x11, x11, #31
+                    mov w12, #10
+                    strb w12, [x11]
+                    adr_l x13, print_digits
+                    mov x14, #0
+                2:
+                    udiv x15, x0, x1
+                    msub x12, x15, x1, x0
+                    ldrb w12, [x13, x12]
+                    strb w12, [x11, #-1]!
+                    add x14, x14, #1
+                    mov x0, x15
+                    cbnz x0, 2b
+                3:
+                    cmp x14, x3
+                    b.hs 4f
+                    mov w12, #48
+                    strb w12, [x11, #-1]!
+                    add x14, x14, #1
+                    b 3b
+                4:
+                    cbz x10, 5f
+                    mov w12, #45
+                    strb w12, [x11, #-1]!
+                5:
+                    mov x0, #1
+                    mov x1, x11
+                    adr_l x2, print_buffer
+                    add x2, x2, #32
+                    sub x2, x2, x11
+                    mov x8, #64
+                    svc #0
+                    ret
+            "},
+            PrintFormat::MAX_WIDTH
+        )?;
+    }
+    if traps {
+        write!(
+            sink,
+            indoc! {"
+
+                trap_misaligned:
+                    adr_l x1, trap_misaligned_msg
+                    mov x2, #{}
+                trap:
+                    mov x0, #2
+                    mov x8, #64
+                    svc #0
+                // print the shadow call stack, innermost frame first
+                    adr_l x9, trace_depth
+                    ldr x19, [x9]
+                    mov x9, #{}
+                    cmp x19, x9
+                    csel x19, x9, x19, hi
+                1:
+                    cbz x19, 2f
+                    sub x19, x19, #1
+                    adr_l x9, trace_stack
+                    ldr x20, [x9, x19, lsl #3]
+                    adr_l x9, trace_names
+                    ldr x1, [x9, x20, lsl #3]
+                    adr_l x9, trace_name_lens
+                    ldr x2, [x9, x20, lsl #3]
+                    mov x0, #2
+                    mov x8, #64
+                    svc #0
+                    b 1b
+                2:
+                    mov x0, #1
+                    mov x8, #93
+                    svc #0
+            "},
+            "Misaligned memory access\n".len(),
+            TRACE_FRAMES
+        )?;
+    }
+    if hashes {
+        // must agree with `intrinsics::hash_str`, which folds literals
+        write!(
+            sink,
+            indoc! {"
+
+                // x1 = ptr, x2 = len
+                hash_str:
+                {}{}1:
+                    cbz x2, 2f
+                    ldrb w4, [x1], #1
+                    eor x0, x0, x4
+                    mul x0, x0, x3
+                    sub x2, x2, #1
+                    b 1b
+                2:
+                    ret
+            "},
+            mov_imm("x0", HASH_OFFSET),
+            mov_imm("x3", HASH_PRIME)
+        )?;
+    }
+    if finds {
+        // a block of 16 with the byte in it is searched again one byte at a time
+        write!(
+            sink,
+            indoc! {"
+
+                // x1 = ptr, x2 = len, x3 = byte, returns the index in x0 and whether it was found in x1
+                find_byte:
+                    and w3, w3, #0xff
+                    mov x4, x1
+                    dup v1.16b, w3
+                1:
+                    cmp x2, #16
+                    b.lo 2f
+                    ldr q0, [x4]
+                    cmeq v0.16b, v0.16b, v1.16b
+                    umaxv b0, v0.16b
+                    umov w5, v0.b[0]
+                    cbnz w5, 2f
+                    add x4, x4, #16
+                    sub x2, x2, #16
+                    b 1b
+                2:
+                    cbz x2, 3f
+                    ldrb w5, [x4]
+                    cmp w5, w3
+                    b.eq 4f
+                    add x4, x4, #1
+                    sub x2, x2, #1
+                    b 2b
+                3:
+                    sub x0, x4, x1
+                    mov x1, #0
+                    ret
+                4:
+                    sub x0, x4, x1
+                    mov x1, #1
+                    ret
+            "}
+        )?;
+    }
+    write!(
+        sink,
+        indoc! {"
+
+            .section .rodata
+            .balign 2
+            syscall_table:
+        "}
+    )?;
+    for chunk in syscalls.chunks(16) {
+        writeln!(
+            sink,
+            "    .hword {}",
+            chunk
+                .iter()
+                .map(|n| n.to_string())
+                .intersperse(",".to_string())
+                .collect::<String>()
+        )?;
+    }
+    write!(
+        sink,
+        indoc! {"
+
+            .data
+        "}
+    )?;
+    if runtime.print {
+        write!(
+            sink,
+            indoc! {"
+                print_buffer:
+                    .skip 32
+                print_digits:
+                    .ascii \"0123456789abcdef\"
+            "}
+        )?;
+    }
+    if traps {
+        write!(
+            sink,
+            indoc! {"
+                trap_misaligned_msg:
+                    .ascii \"Misaligned memory access\\n\"
+            "}
+        )?;
+        for (i, name) in proc_names.iter().enumerate() {
+            write!(
+                sink,
+                indoc! {"
+                    trace_name_{}:
+                        .byte 32,32,97,116,32,{},10
+                    "},
+                i,
+                demangle(name)
+                    .bytes()
+                    .map(|b| b.to_string())
+                    .intersperse(",".to_string())
+                    .collect::<String>()
+            )?;
+        }
+        writeln!(sink, ".balign 8")?;
+        writeln!(sink, "trace_names:")?;
+        for i in 0..proc_names.len() {
+            writeln!(sink, "    .quad trace_name_{}", i)?;
+        }
+        writeln!(sink, "trace_name_lens:")?;
+        for name in &proc_names {
+            writeln!(sink, "    .quad {}", demangle(name).len() + 6)?;
+        }
+    }
+    let tables = &program.tables;
+    let layout = data_layout(mems, &strings, tables, &options.placements);
+    for item in &layout {
+        let i = match item.datum {
+            Datum::Str(i) => i,
+            _ => continue,
+        };
+        let str = &strings[i];
+        let placed = item.section != ".data";
+        if placed {
+            writeln!(sink, ".section .data.str_{}, \"aw\"", i)?;
+        }
+        writeln!(sink, ".balign {}", item.align)?;
+        writeln!(sink, "str_{}:", i)?;
+        if !str.is_empty() {
+            writeln!(
+                sink,
+                "    .byte {}",
+                str.bytes()
+                    .map(|b| b.to_string())
+                    .intersperse(",".to_string())
+                    .collect::<String>()
+            )?;
+        }
+        if placed {
+            writeln!(sink, ".data")?;
+        }
+    }
+    for item in &layout {
+        let name = match &item.datum {
+            Datum::Table(name) => name,
+            _ => continue,
+        };
+        writeln!(sink, ".section {}, \"a\"", item.section)?;
+        writeln!(sink, ".balign {}", item.align)?;
+        writeln!(sink, "table_{}:", name)?;
+        writeln!(
+            sink,
+            "    .byte {}",
+            tables[name]
+                .iter()
+                .map(|b| b.to_string())
+                .intersperse(",".to_string())
+                .collect::<String>()
+        )?;
+    }
+    write!(
+        sink,
+        indoc! {"
+
+            .bss
+            .balign 16
+                data_stack: .skip {}
+                data_stack_end:
+                ret_stack: .skip {1}
+                ret_stack_end:
+                locals_stack: .skip {1}
+                locals_stack_end:
+                escaping_stack: .skip {1}
+                escaping_stack_end:
+                argc: .skip 8
+                argv: .skip 8
+        "},
+        DATA_STACK_SIZE, options.stack_size
+    )?;
+    if options.checked {
+        write!(
+            sink,
+            indoc! {"
+                trace_depth: .skip 8
+                trace_stack: .skip {}
+            "},
+            8 * TRACE_FRAMES
+        )?;
+    }
+    for item in &layout {
+        let name = match &item.datum {
+            Datum::Mem(name) => name,
+            _ => continue,
+        };
+        let placed = item.section != ".bss";
+        if placed {
+            writeln!(sink, ".section .bss.mem_{}, \"aw\", @nobits", name)?;
+        }
+        write!(
+            sink,
+            indoc! {"
+            .balign {}
+            mem_{}:
+                .skip {}
+        "},
+            item.align, name, item.size
+        )?;
+        if placed {
+            writeln!(sink, ".bss")?;
+        }
+    }
+    ().okay()
+}
+
+#[test]
+fn test_syscall_table() {
+    let defined = include_str!("../rotth-src/syscalls.rh")
+        .lines()
+        .filter_map(|l| {
+            let (name, rest) = l.strip_prefix("const SYS_")?.split_once(": u64 do ")?;
+            (name, rest.strip_suffix(" end")?.parse::<u64>().ok()?).some()
+        })
+        .collect::<fnv::FnvHashMap<_, _>>();
+    for &(name, x86, _) in AARCH64_SYSCALLS {
+        assert_eq!(defined.get(name), Some(&x86), "{}", name);
+    }
+    let table = syscall_table();
+    assert_eq!(table[1], 64);
+    assert_eq!(table[2], NO_SYSCALL);
+    assert_eq!(*table.last().unwrap(), NO_SYSCALL);
+    assert_eq!(
+        mov_imm("x0", 0x1_0000_ffff),
+        "    mov x0, #65535\n    movk x0, #1, lsl #32\n"
+    );
+}
@@ -1,152 +1,1478 @@
-use crate::{iconst::IConst, lir::Op};
+use crate::{
+    emit::TRACE_FRAMES,
+    iconst::IConst,
+    intrinsics,
+    lir::{demangle, extend, Cmp, Op},
+    span::Span,
+};
+use fnv::FnvHashMap;
 use somok::{Either, Somok};
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    ops::Range,
+    rc::Rc,
+};
+use thiserror::Error;
 
-pub fn eval(ops: Vec<Op>, strings: &[String]) -> Result<Either<u64, Vec<u64>>, String> {
-    let labels = ops
-        .iter()
-        .enumerate()
-        .filter_map(|(i, op)| {
-            if let Op::Label(l) | Op::Proc(l) = op {
-                (l.clone(), i).some()
-            } else {
-                None
-            }
-        })
-        .collect::<HashMap<String, usize>>();
-
-    let mut call_stack = Vec::new();
-    let mut stack = Vec::new();
-    let mut i = 0;
-
-    while let Some(op) = ops.get(i) {
-        #[cfg(debug_assertions)]
-        println!("{}:\t{:?}", i, op);
-        match op {
-            Op::PushMem(_i) => {
-                todo!("Support memories in eval")
-            }
-            Op::PushStr(i) => {
-                let len = strings[*i].len() as u64;
-                stack.push(len);
-                stack.push(strings[*i].as_ptr() as u64);
-            }
-            Op::Push(c) => match c {
-                IConst::Bool(b) => stack.push(*b as u64),
-                IConst::U64(u) => stack.push(*u),
-                IConst::I64(i) => stack.push(*i as u64),
-                IConst::Ptr(p) => stack.push(*p),
-                IConst::Char(c) => stack.push(*c as u64),
-                IConst::Str(_s) => unreachable!(),
-            },
-            Op::Drop => {
-                stack.pop();
-            }
-            Op::Dup => {
-                let v = stack.last().copied().unwrap();
-                stack.push(v);
-            }
-            Op::Swap => {
-                let (a, b) = (stack.pop().unwrap(), stack.pop().unwrap());
-                stack.push(a);
-                stack.push(b);
-            }
-            Op::Over => {
-                let v = stack[stack.len() - 2];
-                stack.push(v);
-            }
+#[derive(Debug, Error)]
+pub enum EvalError {
+    /// Jumped to a label that was not compiled yet, usually a const
+    #[error("Unresolved label {0}")]
+    Unresolved(String),
+    #[error("{0}")]
+    Trap(Trap),
+}
 
-            Op::Bind => call_stack.push(stack.pop().unwrap()),
-            Op::UseBinding(offset) => stack.push(call_stack[(call_stack.len() - 1) - offset]),
-            Op::Unbind => {
-                call_stack.pop();
-            }
+/// A runtime failure, along with the procs that were executing, innermost first
+#[derive(Debug)]
+pub struct Trap {
+    pub message: String,
+    pub backtrace: Vec<String>,
+    /// Source the failure points at, with what is there
+    pub spans: Vec<(Span, String)>,
+}
 
-            Op::ReadU64 | Op::ReadU8 | Op::WriteU64 | Op::WriteU8 => {
-                panic!("Pointer operations are not supported in const eval")
-            }
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for frame in &self.backtrace {
+            write!(f, "\n  at {}", frame)?;
+        }
+        Ok(())
+    }
+}
 
-            Op::Dump => println!("{:?}", stack),
-            Op::Print => println!("{:?}", stack.pop().unwrap()),
-            Op::Syscall0
-            | Op::Syscall1
-            | Op::Syscall2
-            | Op::Syscall3
-            | Op::Syscall4
-            | Op::Syscall5
-            | Op::Syscall6
-            | Op::Argc
-            | Op::Argv => todo!("Syscalls not supported in eval"),
-
-            Op::Add => {
-                let (b, a) = (stack.pop().unwrap(), stack.pop().unwrap());
-                stack.push(a + b);
-            }
-            Op::Sub => {
-                let (b, a) = (stack.pop().unwrap(), stack.pop().unwrap());
-                stack.push(a - b);
-            }
-            Op::Divmod => {
-                let (b, a) = (stack.pop().unwrap(), stack.pop().unwrap());
-                stack.push(a / b);
-                stack.push(a % b);
-            }
-            Op::Mul => {
-                let (b, a) = (stack.pop().unwrap(), stack.pop().unwrap());
-                stack.push(a * b);
-            }
+/// `call_stack` holds the positions of the `Call`s that are executing
+fn trap(message: &str, ops: &[Op], call_stack: &[usize]) -> EvalError {
+    EvalError::Trap(Trap {
+        message: message.to_string(),
+        backtrace: call_stack
+            .iter()
+            .rev()
+            .take(TRACE_FRAMES)
+            .filter_map(|&i| match &ops[i] {
+                Op::Call(l) => demangle(l).to_string().some(),
+                _ => None,
+            })
+            .collect(),
+        spans: Vec::new(),
+    })
+}
 
-            Op::Eq => {
-                let (b, a) = (stack.pop().unwrap(), stack.pop().unwrap());
-                stack.push((a == b) as u64);
-            }
-            Op::Ne => {
-                let (b, a) = (stack.pop().unwrap(), stack.pop().unwrap());
-                stack.push((a != b) as u64);
-            }
-            Op::Lt => {
-                let (b, a) = (stack.pop().unwrap(), stack.pop().unwrap());
-                stack.push((a < b) as u64);
+/// Rust implementation of an `extern host` proc.
+/// Receives the declared inputs, bottom of the stack first, and returns the outputs in the same order.
+pub type HostFn<'h> = Box<dyn FnMut(&[u64]) -> Vec<u64> + 'h>;
+
+/// Address space of an interpreted program, one block of bytes starting at `Memory::BASE`
+#[derive(Clone)]
+pub struct Memory {
+    bytes: Vec<u8>,
+    /// Whether each byte was ever written
+    init: Vec<bool>,
+    /// Where the program was laid out up to, the least `brk` leaves
+    heap: usize,
+}
+
+impl Memory {
+    /// Lowest valid address, so that null and small numbers never point anywhere
+    pub const BASE: u64 = 0x10000;
+
+    fn range(addr: u64, len: u64) -> Option<Range<usize>> {
+        let start = addr.checked_sub(Self::BASE)?;
+        let end = start.checked_add(len)?;
+        (usize::try_from(start).ok()?..usize::try_from(end).ok()?).some()
+    }
+
+    pub fn get(&self, addr: u64, len: u64) -> Option<&[u8]> {
+        let range = Self::range(addr, len)?;
+        self.bytes.get(range)
+    }
+
+    /// Bytes at `addr`, which count as written from now on
+    pub fn get_mut(&mut self, addr: u64, len: u64) -> Option<&mut [u8]> {
+        let range = Self::range(addr, len)?;
+        self.init.get_mut(range.clone())?.fill(true);
+        self.bytes.get_mut(range)
+    }
+
+    /// Address of the first byte from `addr` on that was never written since it was reserved
+    pub fn uninit(&self, addr: u64, len: u64) -> Option<u64> {
+        let range = Self::range(addr, len)?;
+        let first = self.init.get(range)?.iter().position(|&init| !init)?;
+        (addr + first as u64).some()
+    }
+
+    /// Makes the bytes at `addr` unwritten again, like a fresh frame of locals
+    fn forget(&mut self, addr: u64, len: u64) {
+        if let Some(init) = Self::range(addr, len).and_then(|range| self.init.get_mut(range)) {
+            init.fill(false)
+        }
+    }
+
+    /// Bytes from `addr` up to the next nul
+    pub fn cstr(&self, addr: u64) -> Option<&[u8]> {
+        let rest = self.bytes.get(Self::range(addr, 0)?.start..)?;
+        let len = rest.iter().position(|&b| b == 0)?;
+        rest[..len].some()
+    }
+
+    /// Loads a little endian number `size` bytes wide
+    pub fn load(&self, addr: u64, size: u64) -> Option<u64> {
+        let mut bytes = [0; 8];
+        bytes[..size as usize].copy_from_slice(self.get(addr, size)?);
+        u64::from_le_bytes(bytes).some()
+    }
+
+    /// Stores the low `size` bytes of `value`, little endian
+    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Option<()> {
+        self.get_mut(addr, size)?
+            .copy_from_slice(&value.to_le_bytes()[..size as usize]);
+        ().some()
+    }
+
+    /// Moves the end of memory to `addr` like the `brk` syscall, returning where it ends.
+    /// It stays put when `addr` is below the heap or more than `HEAP_SIZE` bytes into it.
+    pub fn brk(&mut self, addr: u64) -> u64 {
+        let len = addr
+            .checked_sub(Self::BASE)
+            .and_then(|a| usize::try_from(a).ok());
+        if let Some(len) = len.filter(|&l| l >= self.heap && l - self.heap <= HEAP_SIZE) {
+            // what it grows by is zeroed, like the kernel's
+            self.bytes.resize(len, 0);
+            self.init.resize(len, false);
+        }
+        Self::BASE + self.bytes.len() as u64
+    }
+
+    /// Reserves `len` bytes aligned to 8, returning their address
+    fn alloc(&mut self, len: usize) -> u64 {
+        let start = (self.bytes.len() + 7) & !7;
+        self.bytes.resize(start + len, 0);
+        self.init.resize(start + len, false);
+        Self::BASE + start as u64
+    }
+}
+
+/// Services the syscalls of an interpreted program.
+/// Numbers are the x86-64 Linux ones on every target, results are `-errno` on failure like the kernel's.
+/// `exit` and `exit_group` never get here, the interpreter stops by itself.
+pub trait Syscalls {
+    fn syscall(&mut self, memory: &mut Memory, nr: u64, args: [u64; 6]) -> u64;
+}
+
+impl<F: FnMut(&mut Memory, u64, [u64; 6]) -> u64> Syscalls for F {
+    fn syscall(&mut self, memory: &mut Memory, nr: u64, args: [u64; 6]) -> u64 {
+        self(memory, nr, args)
+    }
+}
+
+const SYS_READ: u64 = 0;
+const SYS_WRITE: u64 = 1;
+const SYS_OPEN: u64 = 2;
+const SYS_CLOSE: u64 = 3;
+const SYS_BRK: u64 = 12;
+const SYS_EXIT: u64 = 60;
+const SYS_EXIT_GROUP: u64 = 231;
+
+const EBADF: u64 = 9;
+const EFAULT: u64 = 14;
+const ENOSYS: u64 = 38;
+
+enum Fd {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+/// Syscalls `HostSyscalls` services, and the ones the interpreter does by itself
+pub const HOST_SYSCALLS: &[u64] = &[
+    SYS_READ,
+    SYS_WRITE,
+    SYS_OPEN,
+    SYS_CLOSE,
+    SYS_BRK,
+    SYS_EXIT,
+    SYS_EXIT_GROUP,
+];
+
+/// File syscalls done with the standard library, so they work on any host:
+/// `read`, `write`, `open` and `close`, and `brk` growing the `Memory`.
+/// Everything else fails with `ENOSYS`.
+pub struct HostSyscalls {
+    fds: FnvHashMap<u64, Fd>,
+}
+
+impl Default for HostSyscalls {
+    fn default() -> Self {
+        let fds = [(0, Fd::Stdin), (1, Fd::Stdout), (2, Fd::Stderr)];
+        Self {
+            fds: fds.into_iter().collect(),
+        }
+    }
+}
+
+impl HostSyscalls {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn open(&mut self, path: &[u8], flags: u64) -> io::Result<u64> {
+        let path = String::from_utf8_lossy(path).into_owned();
+        let file = OpenOptions::new()
+            .read(flags & 0o3 != 0o1)
+            .write(flags & 0o3 != 0o0)
+            .create(flags & 0o100 != 0)
+            .create_new(flags & 0o300 == 0o300)
+            .truncate(flags & 0o1000 != 0)
+            .append(flags & 0o2000 != 0)
+            .open(path)?;
+        let fd = (3..).find(|fd| !self.fds.contains_key(fd)).unwrap();
+        self.fds.insert(fd, Fd::File(file));
+        fd.okay()
+    }
+}
+
+fn errno(e: io::Error) -> u64 {
+    // EIO when the host has no number for it
+    (e.raw_os_error().unwrap_or(5) as u64).wrapping_neg()
+}
+
+impl Syscalls for HostSyscalls {
+    fn syscall(&mut self, memory: &mut Memory, nr: u64, args: [u64; 6]) -> u64 {
+        let [fd, buf, count, ..] = args;
+        let res = match nr {
+            SYS_READ => {
+                let buf = match memory.get_mut(buf, count) {
+                    Some(buf) => buf,
+                    None => return EFAULT.wrapping_neg(),
+                };
+                match self.fds.get_mut(&fd) {
+                    Some(Fd::Stdin) => io::stdin().read(buf),
+                    Some(Fd::File(file)) => file.read(buf),
+                    _ => return EBADF.wrapping_neg(),
+                }
             }
-            Op::Le => {
-                let (b, a) = (stack.pop().unwrap(), stack.pop().unwrap());
-                stack.push((a <= b) as u64);
+            SYS_WRITE => {
+                let buf = match memory.get(buf, count) {
+                    Some(buf) => buf,
+                    None => return EFAULT.wrapping_neg(),
+                };
+                match self.fds.get_mut(&fd) {
+                    Some(Fd::Stdout) => io::stdout().write(buf),
+                    Some(Fd::Stderr) => io::stderr().write(buf),
+                    Some(Fd::File(file)) => file.write(buf),
+                    _ => return EBADF.wrapping_neg(),
+                }
             }
-            Op::Gt => {
-                let (b, a) = (stack.pop().unwrap(), stack.pop().unwrap());
-                stack.push((a > b) as u64);
+            SYS_OPEN => {
+                let path = match memory.cstr(args[0]) {
+                    Some(path) => path,
+                    None => return EFAULT.wrapping_neg(),
+                };
+                let path = path.to_vec();
+                return self.open(&path, args[1]).unwrap_or_else(errno);
             }
-            Op::Ge => {
-                let (b, a) = (stack.pop().unwrap(), stack.pop().unwrap());
-                stack.push((a >= b) as u64);
+            SYS_CLOSE => {
+                return match self.fds.remove(&fd) {
+                    Some(_) => 0,
+                    None => EBADF.wrapping_neg(),
+                }
             }
+            SYS_BRK => return memory.brk(args[0]),
+            _ => return ENOSYS.wrapping_neg(),
+        };
+        res.map(|n| n as u64).unwrap_or_else(errno)
+    }
+}
+
+const STACK_SIZE: usize = 65536;
+/// About as deep as the 8MiB stack of a native program lets it go
+const MAX_CALL_DEPTH: usize = 1 << 20;
+/// Room for the longest number `print` renders, and its newline
+const PRINT_BUFFER: usize = 32;
+/// Most bytes `brk` gives an interpreted program
+const HEAP_SIZE: usize = 1 << 28;
+
+/// Where everything the program can point to lives in its `Memory`
+struct Layout {
+    /// Address and length of each string
+    strings: Vec<(u64, u64)>,
+    mems: FnvHashMap<String, u64>,
+    tables: FnvHashMap<String, u64>,
+    print_buffer: u64,
+    locals: Range<u64>,
+    escaping: Range<u64>,
+    argc: u64,
+    argv: u64,
+}
+
+fn layout(
+    strings: &[String],
+    mems: &FnvHashMap<String, usize>,
+    tables: &FnvHashMap<String, Vec<u8>>,
+    args: &[String],
+) -> (Memory, Layout) {
+    let mut memory = Memory {
+        bytes: Vec::new(),
+        init: Vec::new(),
+        heap: 0,
+    };
+    let strings = strings
+        .iter()
+        .map(|s| {
+            let addr = memory.alloc(s.len());
+            memory
+                .get_mut(addr, s.len() as u64)
+                .unwrap()
+                .copy_from_slice(s.as_bytes());
+            (addr, s.len() as u64)
+        })
+        .collect();
+    let mut names = tables.keys().collect::<Vec<_>>();
+    names.sort();
+    let tables = names
+        .into_iter()
+        .map(|name| {
+            let bytes = &tables[name];
+            let addr = memory.alloc(bytes.len());
+            memory
+                .get_mut(addr, bytes.len() as u64)
+                .unwrap()
+                .copy_from_slice(bytes);
+            (name.clone(), addr)
+        })
+        .collect();
+    let mut names = mems.keys().collect::<Vec<_>>();
+    names.sort();
+    let mems = names
+        .into_iter()
+        .map(|name| (name.clone(), memory.alloc(mems[name])))
+        .collect();
+    let print_buffer = memory.alloc(PRINT_BUFFER);
+    let locals = memory.alloc(STACK_SIZE);
+    let escaping = memory.alloc(STACK_SIZE);
+    // argv is a null terminated array of pointers to nul terminated strings, like the kernel's
+    let argv = memory.alloc((args.len() + 1) * 8);
+    for (i, arg) in args.iter().enumerate() {
+        let addr = memory.alloc(arg.len() + 1);
+        memory
+            .get_mut(addr, arg.len() as u64)
+            .unwrap()
+            .copy_from_slice(arg.as_bytes());
+        memory.store(addr + arg.len() as u64, 1, 0).unwrap();
+        memory.store(argv + i as u64 * 8, 8, addr).unwrap();
+    }
+    memory.store(argv + args.len() as u64 * 8, 8, 0).unwrap();
+    // the heap starts right after, aligned like everything else
+    memory.heap = (memory.alloc(0) - Memory::BASE) as usize;
+    let stack = |start: u64| start..start + STACK_SIZE as u64;
+    let layout = Layout {
+        strings,
+        mems,
+        tables,
+        print_buffer,
+        locals: stack(locals),
+        escaping: stack(escaping),
+        argc: args.len() as u64,
+        argv,
+    };
+    (memory, layout)
+}
+
+/// Everything a running program can observe: its stacks, memory and strings.
+/// Setting up a large structure once and running many scenarios against copies of the result
+/// is a matter of taking a `snapshot` after the setup and restoring it before each scenario.
+pub struct Machine {
+    memory: Memory,
+    layout: Rc<Layout>,
+    stack: Vec<u64>,
+    call_stack: Vec<usize>,
+    bindings: Vec<u64>,
+    locals_sp: u64,
+    escaping_sp: u64,
+}
+
+impl Clone for Machine {
+    fn clone(&self) -> Self {
+        Self {
+            memory: self.memory.clone(),
+            layout: self.layout.clone(),
+            stack: self.stack.clone(),
+            call_stack: self.call_stack.clone(),
+            bindings: self.bindings.clone(),
+            locals_sp: self.locals_sp,
+            escaping_sp: self.escaping_sp,
+        }
+    }
+
+    // reuses the buffers, so restoring a snapshot doesn't allocate
+    fn clone_from(&mut self, source: &Self) {
+        self.memory.bytes.clone_from(&source.memory.bytes);
+        self.memory.init.clone_from(&source.memory.init);
+        self.memory.heap = source.memory.heap;
+        self.layout.clone_from(&source.layout);
+        self.stack.clone_from(&source.stack);
+        self.call_stack.clone_from(&source.call_stack);
+        self.bindings.clone_from(&source.bindings);
+        self.locals_sp = source.locals_sp;
+        self.escaping_sp = source.escaping_sp;
+    }
+}
+
+impl Machine {
+    /// A copy of the current state
+    pub fn snapshot(&self) -> Machine {
+        self.clone()
+    }
+
+    /// Goes back to the state `snapshot` was taken in
+    pub fn restore(&mut self, snapshot: &Machine) {
+        self.clone_from(snapshot)
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    /// Data stack, top last
+    pub fn stack(&self) -> &[u64] {
+        &self.stack
+    }
+
+    /// Data stack, for passing arguments to `Interpreter::call`
+    pub fn stack_mut(&mut self) -> &mut Vec<u64> {
+        &mut self.stack
+    }
+
+    /// Address of a `mem` or variable
+    pub fn mem(&self, name: &str) -> Option<u64> {
+        self.layout.mems.get(name).copied()
+    }
+}
+
+/// Executes compiled programs, with simulated memory for strings, `mem`s and variables,
+/// syscalls serviced by a pluggable handler and procs provided by the host application
+#[derive(Default)]
+pub struct Interpreter<'h> {
+    hosts: HashMap<String, HostFn<'h>>,
+    syscalls: Option<Box<dyn Syscalls + 'h>>,
+    mems: FnvHashMap<String, usize>,
+    tables: FnvHashMap<String, Vec<u8>>,
+    args: Vec<String>,
+    /// Where each `mem` and variable is defined, when reads of unwritten bytes trap
+    uninit: Option<FnvHashMap<String, Span>>,
+    /// Whether indirect calls trap when they leave the stack at another depth than they should
+    check_indirect: bool,
+}
+
+impl<'h> Interpreter<'h> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Provides the implementation of `extern host <name>`
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        host: impl FnMut(&[u64]) -> Vec<u64> + 'h,
+    ) -> &mut Self {
+        self.hosts.insert(name.into(), Box::new(host));
+        self
+    }
+
+    /// Services the syscalls of the program, without a handler they trap.
+    /// `print` goes through it too, as a `write` to stdout.
+    pub fn syscalls(&mut self, handler: impl Syscalls + 'h) -> &mut Self {
+        self.syscalls = Some(Box::new(handler));
+        self
+    }
+
+    /// Memories and variables of the program with their sizes, as `lir::Compiler::compile` returns them
+    pub fn mems(&mut self, mems: &FnvHashMap<String, usize>) -> &mut Self {
+        self.mems = mems.clone();
+        self
+    }
+
+    /// Bytes of the tables of the program, as `Program::tables` has them
+    pub fn tables(&mut self, tables: &FnvHashMap<String, Vec<u8>>) -> &mut Self {
+        self.tables = tables.clone();
+        self
+    }
+
+    /// What `argc` and `argv` see, the program name included
+    pub fn args(&mut self, args: impl IntoIterator<Item = impl Into<String>>) -> &mut Self {
+        self.args = args.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Traps on reading bytes of `mem`s, variables and locals that were never written, which
+    /// native programs read as zeroes or as whatever an earlier call left there.
+    /// `spans` has where each `mem` and variable is defined, as `Session::mem_spans` has them,
+    /// and the ops need `Comments::Spans` for the trap to point at the read.
+    pub fn check_uninit(&mut self, spans: &FnvHashMap<String, Span>) -> &mut Self {
+        self.uninit = Some(spans.clone());
+        self
+    }
+
+    /// Traps on indirect calls that leave the stack at another depth than the signature of their
+    /// `call-like` says, as checked builds do. Only calls through a cast `ptr(proc)` can.
+    pub fn check_indirect(&mut self) -> &mut Self {
+        self.check_indirect = true;
+        self
+    }
+
+    /// Lays out strings, tables, `mem`s, stacks and arguments of a program that hasn't run yet
+    pub fn start(&self, strings: &[String]) -> Machine {
+        let (memory, layout) = layout(strings, &self.mems, &self.tables, &self.args);
+        Machine {
+            memory,
+            locals_sp: layout.locals.end,
+            escaping_sp: layout.escaping.end,
+            layout: Rc::new(layout),
+            stack: Vec::new(),
+            call_stack: Vec::new(),
+            bindings: Vec::new(),
+        }
+    }
+
+    /// Runs `ops` to completion, returning the exit code if the program exits
+    /// and what is left on the stack if it runs off the end, as const bodies do
+    pub fn run(
+        &mut self,
+        ops: &[Op],
+        strings: &[String],
+    ) -> Result<Either<u64, Vec<u64>>, EvalError> {
+        let mut machine = self.start(strings);
+        match self.execute(&mut machine, ops, 0)? {
+            Some(code) => code.left(),
+            None => machine.stack.right(),
+        }
+        .okay()
+    }
+
+    /// Runs the proc `name`, as it is called in the source, on the state of `machine`,
+    /// returning the exit code if the program exits instead of returning.
+    /// Its inputs are taken from and its outputs are left on `machine.stack()`.
+    pub fn call(
+        &mut self,
+        machine: &mut Machine,
+        ops: &[Op],
+        name: &str,
+    ) -> Result<Option<u64>, EvalError> {
+        let entry = ops
+            .iter()
+            .position(|op| matches!(op, Op::Proc(l) if l == name || demangle(l) == name))
+            .ok_or_else(|| EvalError::Unresolved(name.to_string()))?;
+        // a program that exited can leave frames behind
+        machine.call_stack.clear();
+        self.execute(machine, ops, entry)
+    }
 
-            Op::Proc(_) => (),
-            Op::Label(_) => (),
-            Op::Jump(l) => i = labels[l],
-            Op::JumpF(l) => {
-                if stack.pop() == Some(0) {
-                    i = labels[l]
+    /// Executes from `ops[i]` until the program exits or returns from the outermost frame
+    fn execute(
+        &mut self,
+        machine: &mut Machine,
+        ops: &[Op],
+        mut i: usize,
+    ) -> Result<Option<u64>, EvalError> {
+        let labels = ops
+            .iter()
+            .enumerate()
+            .filter_map(|(i, op)| {
+                if let Op::Label(l) | Op::Proc(l) = op {
+                    (l.clone(), i).some()
+                } else {
+                    None
                 }
-            }
-            Op::JumpT(l) => {
-                if stack.pop() == Some(1) {
-                    i = labels[l]
+            })
+            .collect::<HashMap<String, usize>>();
+
+        let Machine {
+            memory,
+            layout,
+            stack,
+            call_stack,
+            bindings,
+            locals_sp,
+            escaping_sp,
+        } = machine;
+        let layout = &**layout;
+        let mut shadow = Shadow::default();
+        // frames of the checked indirect calls being executed, with the stack depth their
+        // signature leaves, as a `ptr(proc)` doesn't say what the proc takes and returns
+        let mut indirect: Vec<(usize, usize)> = Vec::new();
+
+        macro_rules! pop {
+            () => {
+                match stack.pop() {
+                    Some(v) => v,
+                    None => return trap("Stack underflow", ops, call_stack).error(),
                 }
+            };
+        }
+        macro_rules! peek {
+            ($depth:expr) => {
+                match stack.len().checked_sub($depth + 1) {
+                    Some(i) => i,
+                    None => return trap("Stack underflow", ops, call_stack).error(),
+                }
+            };
+        }
+        macro_rules! access {
+            ($res:expr, $addr:expr, $size:expr) => {
+                match $res {
+                    Some(v) => v,
+                    None => {
+                        let message = format!("Invalid access of {} bytes at {:#x}", $size, $addr);
+                        return trap(&message, ops, call_stack).error();
+                    }
+                }
+            };
+        }
+
+        macro_rules! initialized {
+            ($addr:expr, $len:expr) => {
+                if let Some(defined) = &self.uninit {
+                    if let Some(addr) = memory.uninit($addr, $len) {
+                        let (message, spans) =
+                            shadow.uninit_read(addr, layout, &self.mems, defined);
+                        let mut e = trap(&message, ops, call_stack);
+                        if let EvalError::Trap(trap) = &mut e {
+                            trap.spans = spans;
+                        }
+                        return e.error();
+                    }
+                }
+            };
+        }
+
+        while let Some(op) = ops.get(i) {
+            match op {
+                Op::PushMem(name) => match layout.mems.get(name) {
+                    Some(&addr) => stack.push(addr),
+                    // only linking defines an `extern mem`
+                    None => {
+                        let message = format!("Memory `{}` is not defined here", name);
+                        return trap(&message, ops, call_stack).error();
+                    }
+                },
+                Op::PushTable(name) => stack.push(layout.tables[name]),
+                Op::PushStr(i) => {
+                    let (addr, len) = layout.strings[*i];
+                    stack.push(len);
+                    stack.push(addr);
+                }
+                Op::Push(c) => match c {
+                    IConst::Bool(b) => stack.push(*b as u64),
+                    IConst::U64(u) => stack.push(*u),
+                    IConst::I64(i) => stack.push(*i as u64),
+                    IConst::Ptr(p) => stack.push(*p),
+                    IConst::Char(c) => stack.push(*c as u64),
+                    IConst::Int(_) | IConst::Str(_) => unreachable!(),
+                },
+                Op::Drop => {
+                    pop!();
+                }
+                Op::Dup => {
+                    let v = stack[peek!(0)];
+                    stack.push(v);
+                }
+                Op::Swap => {
+                    let (a, b) = (pop!(), pop!());
+                    stack.push(a);
+                    stack.push(b);
+                }
+                Op::Over => {
+                    let v = stack[peek!(1)];
+                    stack.push(v);
+                }
+                Op::Pick(depth) => {
+                    let v = stack[peek!(*depth)];
+                    stack.push(v);
+                }
+                Op::Roll(depth) => {
+                    let v = stack.remove(peek!(*depth));
+                    stack.push(v);
+                }
+
+                Op::Bind => bindings.push(pop!()),
+                Op::UseBinding(offset) => stack.push(bindings[(bindings.len() - 1) - offset]),
+                Op::Unbind => {
+                    bindings.pop();
+                }
+
+                Op::ReadU64 | Op::ReadU32 | Op::ReadU16 | Op::ReadU8 => {
+                    let size = match op {
+                        Op::ReadU64 => 8,
+                        Op::ReadU32 => 4,
+                        Op::ReadU16 => 2,
+                        _ => 1,
+                    };
+                    let addr = pop!();
+                    let value = access!(memory.load(addr, size), addr, size);
+                    initialized!(addr, size);
+                    stack.push(value);
+                }
+                Op::WriteU64 | Op::WriteU32 | Op::WriteU16 | Op::WriteU8 => {
+                    let size = match op {
+                        Op::WriteU64 => 8,
+                        Op::WriteU32 => 4,
+                        Op::WriteU16 => 2,
+                        _ => 1,
+                    };
+                    let (addr, value) = (pop!(), pop!());
+                    access!(memory.store(addr, size, value), addr, size);
+                }
+
+                Op::Bswap64 => {
+                    let v = pop!();
+                    stack.push(v.swap_bytes());
+                }
+                Op::Bswap32 => {
+                    let v = pop!();
+                    stack.push((v as u32).swap_bytes() as u64);
+                }
+                Op::Bswap16 => {
+                    let v = pop!();
+                    stack.push((v as u16).swap_bytes() as u64);
+                }
+                Op::ZeroExtend(bits) => {
+                    let v = pop!();
+                    stack.push(extend(v, *bits, false));
+                }
+                Op::SignExtend(bits) => {
+                    let v = pop!();
+                    stack.push(extend(v, *bits, true));
+                }
+
+                Op::Dump => println!("{:?}", stack),
+                Op::Print(_) | Op::PrintPadded(_) => {
+                    let (format, width) = match op {
+                        Op::PrintPadded(format) => (format, pop!() as usize),
+                        Op::Print(format) => (format, 0),
+                        _ => unreachable!(),
+                    };
+                    let text = format.format(pop!(), width);
+                    match &mut self.syscalls {
+                        Some(handler) => {
+                            let line = format!("{}\n", text);
+                            let buffer = layout.print_buffer;
+                            memory
+                                .get_mut(buffer, line.len() as u64)
+                                .unwrap()
+                                .copy_from_slice(line.as_bytes());
+                            let args = [1, buffer, line.len() as u64, 0, 0, 0];
+                            handler.syscall(memory, SYS_WRITE, args);
+                        }
+                        None => println!("{}", text),
+                    }
+                }
+                Op::HashStr => {
+                    let (ptr, len) = (pop!(), pop!());
+                    let bytes = access!(memory.get(ptr, len), ptr, len);
+                    initialized!(ptr, len);
+                    stack.push(intrinsics::hash_str(bytes));
+                }
+                Op::Copy16 => {
+                    let (dst, src) = (pop!(), pop!());
+                    let bytes = access!(memory.get(src, 16), src, 16).to_vec();
+                    initialized!(src, 16);
+                    access!(memory.get_mut(dst, 16), dst, 16).copy_from_slice(&bytes);
+                }
+                Op::Cmp16 => {
+                    let (b, a) = (pop!(), pop!());
+                    // out of range bytes are left for `access!` to trap on
+                    initialized!(a, 16);
+                    initialized!(b, 16);
+                    let a = access!(memory.get(a, 16), a, 16);
+                    let b = access!(memory.get(b, 16), b, 16);
+                    let mask = (0..16).filter(|&i| a[i] == b[i]).map(|i| 1 << i).sum();
+                    stack.push(mask);
+                }
+                Op::Splat16 => {
+                    let (dst, byte) = (pop!(), pop!());
+                    access!(memory.get_mut(dst, 16), dst, 16).fill(byte as u8);
+                }
+                Op::FindByte => {
+                    let (byte, len, ptr) = (pop!(), pop!(), pop!());
+                    let bytes = access!(memory.get(ptr, len), ptr, len);
+                    initialized!(ptr, len);
+                    match bytes.iter().position(|&b| b == byte as u8) {
+                        Some(i) => stack.extend([i as u64, 1]),
+                        None => stack.extend([len, 0]),
+                    }
+                }
+                Op::Syscall0
+                | Op::Syscall1
+                | Op::Syscall2
+                | Op::Syscall3
+                | Op::Syscall4
+                | Op::Syscall5
+                | Op::Syscall6 => {
+                    let count = match op {
+                        Op::Syscall0 => 0,
+                        Op::Syscall1 => 1,
+                        Op::Syscall2 => 2,
+                        Op::Syscall3 => 3,
+                        Op::Syscall4 => 4,
+                        Op::Syscall5 => 5,
+                        _ => 6,
+                    };
+                    let nr = pop!();
+                    let mut args = [0; 6];
+                    for arg in &mut args[..count] {
+                        *arg = pop!();
+                    }
+                    if nr == SYS_EXIT || nr == SYS_EXIT_GROUP {
+                        return args[0].some().okay();
+                    }
+                    let handler = self.syscalls.as_mut().ok_or_else(|| {
+                        trap(
+                            "Syscalls are not available without a handler",
+                            ops,
+                            call_stack,
+                        )
+                    })?;
+                    stack.push(handler.syscall(memory, nr, args));
+                }
+                Op::Argc => stack.push(layout.argc),
+                Op::Argv => stack.push(layout.argv),
+                Op::SplitErrno => {
+                    let v = pop!();
+                    // the kernel reports errors as -errno in the last page of the address space
+                    if v > -4096i64 as u64 {
+                        stack.push(v.wrapping_neg());
+                        stack.push(1);
+                    } else {
+                        stack.push(v);
+                        stack.push(0);
+                    }
+                }
+
+                Op::Add => {
+                    let (b, a) = (pop!(), pop!());
+                    stack.push(a.wrapping_add(b));
+                }
+                Op::Sub => {
+                    let (b, a) = (pop!(), pop!());
+                    stack.push(a.wrapping_sub(b));
+                }
+                Op::BitAnd => {
+                    let (b, a) = (pop!(), pop!());
+                    stack.push(a & b);
+                }
+                Op::BitOr => {
+                    let (b, a) = (pop!(), pop!());
+                    stack.push(a | b);
+                }
+                Op::BitXor => {
+                    let (b, a) = (pop!(), pop!());
+                    stack.push(a ^ b);
+                }
+                Op::BitNot => {
+                    let a = pop!();
+                    stack.push(!a);
+                }
+                // like the hardware, only the low 6 bits of the count are used
+                Op::Shl => {
+                    let (b, a) = (pop!(), pop!());
+                    stack.push(a.wrapping_shl(b as u32));
+                }
+                Op::Shr => {
+                    let (b, a) = (pop!(), pop!());
+                    stack.push(a.wrapping_shr(b as u32));
+                }
+                Op::Sar => {
+                    let (b, a) = (pop!(), pop!());
+                    stack.push((a as i64).wrapping_shr(b as u32) as u64);
+                }
+                Op::AddImm(k) => {
+                    let a = pop!();
+                    stack.push(a.wrapping_add(*k));
+                }
+                Op::SubImm(k) => {
+                    let a = pop!();
+                    stack.push(a.wrapping_sub(*k));
+                }
+                Op::Divmod => {
+                    let (b, a) = (pop!(), pop!());
+                    if b == 0 {
+                        return trap("Division by zero", ops, call_stack).error();
+                    }
+                    stack.push(a / b);
+                    stack.push(a % b);
+                }
+                Op::IDivmod => {
+                    let (b, a) = (pop!() as i64, pop!() as i64);
+                    if b == 0 {
+                        return trap("Division by zero", ops, call_stack).error();
+                    }
+                    stack.push(a.wrapping_div(b) as u64);
+                    stack.push(a.wrapping_rem(b) as u64);
+                }
+                Op::Mul => {
+                    let (b, a) = (pop!(), pop!());
+                    stack.push(a.wrapping_mul(b));
+                }
+
+                Op::Eq => {
+                    let (b, a) = (pop!(), pop!());
+                    stack.push((a == b) as u64);
+                }
+                Op::Ne => {
+                    let (b, a) = (pop!(), pop!());
+                    stack.push((a != b) as u64);
+                }
+                Op::Lt | Op::Le | Op::Gt | Op::Ge | Op::LtU | Op::LeU | Op::GtU | Op::GeU => {
+                    let (b, a) = (pop!(), pop!());
+                    stack.push(Cmp::of(op).unwrap().holds(a, b) as u64);
+                }
+
+                Op::Proc(_) => (),
+                Op::Label(_) => (),
+                Op::Loc(span) => shadow.loc = span.some(),
+                Op::Jump(l) => i = labels[l],
+                Op::JumpF(l) => {
+                    if pop!() == 0 {
+                        i = labels[l]
+                    }
+                }
+                Op::CmpImmBranch(cmp, k, l) => {
+                    let a = pop!();
+                    if cmp.holds(a, *k) {
+                        i = labels[l]
+                    }
+                }
+                Op::JumpT(l) => {
+                    if pop!() == 1 {
+                        i = labels[l]
+                    }
+                }
+                Op::CallHost(name, ins, outs) => {
+                    let host = self.hosts.get_mut(name).ok_or_else(|| {
+                        trap(
+                            &format!("Host proc `{}` is not registered", name),
+                            ops,
+                            call_stack,
+                        )
+                    })?;
+                    let start = match stack.len().checked_sub(*ins) {
+                        Some(start) => start,
+                        None => return trap("Stack underflow", ops, call_stack).error(),
+                    };
+                    let args = stack.split_off(start);
+                    let res = host(&args);
+                    if res.len() != *outs {
+                        return trap(
+                            &format!(
+                                "Host proc `{}` returned {} values, expected {}",
+                                name,
+                                res.len(),
+                                outs
+                            ),
+                            ops,
+                            call_stack,
+                        )
+                        .error();
+                    }
+                    stack.extend(res);
+                }
+                Op::Call(l) => {
+                    if call_stack.len() == MAX_CALL_DEPTH {
+                        return trap("Call stack overflow", ops, call_stack).error();
+                    }
+                    call_stack.push(i);
+                    i = labels
+                        .get(l)
+                        .copied()
+                        .ok_or_else(|| EvalError::Unresolved(l.clone()))?
+                }
+                Op::PushProc(l) => stack.push(labels[l] as u64),
+                Op::CallIndirect(ins, outs) => {
+                    let target = pop!() as usize;
+                    if !matches!(ops.get(target), Some(Op::Proc(_))) {
+                        return trap(
+                            "Indirect call to something that is not a proc",
+                            ops,
+                            call_stack,
+                        )
+                        .error();
+                    }
+                    if call_stack.len() == MAX_CALL_DEPTH {
+                        return trap("Call stack overflow", ops, call_stack).error();
+                    }
+                    let depth = match stack.len().checked_sub(*ins) {
+                        Some(start) => start + outs,
+                        None => return trap("Stack underflow", ops, call_stack).error(),
+                    };
+                    if self.check_indirect {
+                        indirect.push((call_stack.len(), depth));
+                    }
+                    call_stack.push(i);
+                    i = target
+                }
+                Op::Return => {
+                    if let Some(&(frame, depth)) = indirect.last() {
+                        if frame + 1 == call_stack.len() {
+                            indirect.pop();
+                            if stack.len() != depth {
+                                let message =
+                                    "Indirect call doesn't match its `call-like` signature";
+                                return trap(message, ops, call_stack).error();
+                            }
+                        }
+                    }
+                    match call_stack.pop() {
+                        Some(ret) => i = ret,
+                        None => break,
+                    }
+                }
+                Op::Exit => return pop!().some().okay(),
+                Op::ReserveLocals(n) => {
+                    *locals_sp -= *n as u64;
+                    if *locals_sp < layout.locals.start {
+                        return trap("Locals stack overflow", ops, call_stack).error();
+                    }
+                    if self.uninit.is_some() && *n > 0 {
+                        memory.forget(*locals_sp, *n as u64);
+                        shadow.locals.push((*locals_sp, shadow.loc));
+                    }
+                }
+                Op::FreeLocals(n) => {
+                    *locals_sp += *n as u64;
+                    while matches!(shadow.locals.last(), Some((sp, _)) if *sp < *locals_sp) {
+                        shadow.locals.pop();
+                    }
+                }
+                Op::PushLvar(offset) => stack.push(*locals_sp + *offset as u64),
+                Op::ReserveEscaping(n) => {
+                    *escaping_sp -= *n as u64;
+                    if *escaping_sp < layout.escaping.start {
+                        return trap("Escaping stack overflow", ops, call_stack).error();
+                    }
+                    if self.uninit.is_some() && *n > 0 {
+                        memory.forget(*escaping_sp, *n as u64);
+                        shadow.escaping.push((*escaping_sp, shadow.loc));
+                    }
+                }
+                Op::PushEscaping(offset) => stack.push(*escaping_sp + *offset as u64),
             }
-            Op::Call(l) => {
-                call_stack.push(i as u64);
-                i = labels.get(l).copied().ok_or_else(|| l.clone())?
-            }
-            Op::Return => i = call_stack.pop().unwrap() as usize,
-            Op::Exit => return stack.pop().unwrap().left().okay(),
-            Op::PushLvar(_) => todo!(),
-            Op::ReserveLocals(_) => todo!(),
-            Op::FreeLocals(_) => todo!(),
-            Op::ReserveEscaping(_) => todo!(),
-            Op::PushEscaping(_) => todo!(),
+            i += 1;
+        }
+        None.okay()
+    }
+}
+
+/// What `Interpreter::check_uninit` knows of the running program besides its `Memory`
+#[derive(Default)]
+struct Shadow<'o> {
+    /// Span of the last `Loc` executed
+    loc: Option<&'o Span>,
+    /// Locals stack pointer after each live `ReserveLocals`, outermost first, with its `Loc`
+    locals: Vec<(u64, Option<&'o Span>)>,
+    /// Same for `ReserveEscaping`, whose reservations are never freed
+    escaping: Vec<(u64, Option<&'o Span>)>,
+}
+
+impl<'o> Shadow<'o> {
+    /// Message and spans for a read of the never written byte at `addr`
+    fn uninit_read(
+        &self,
+        addr: u64,
+        layout: &Layout,
+        sizes: &FnvHashMap<String, usize>,
+        defined: &FnvHashMap<String, Span>,
+    ) -> (String, Vec<(Span, String)>) {
+        let mut message = format!("Read of uninitialized memory at {:#x}", addr);
+        let mut spans = Vec::new();
+        spans.extend(self.loc.map(|span| (span.clone(), "read here".to_string())));
+        let mem = layout
+            .mems
+            .iter()
+            .find(|(name, &start)| (start..start + sizes[*name] as u64).contains(&addr));
+        if let Some((name, start)) = mem {
+            message += &format!(", byte {} of `{}`", addr - start, name);
+            let span = defined.get(name).cloned();
+            spans.extend(span.map(|span| (span, format!("`{}` is defined here", name))));
+            return (message, spans);
+        }
+        let (frames, what) = if layout.locals.contains(&addr) {
+            (&self.locals, "locals")
+        } else {
+            (&self.escaping, "escaping variables")
+        };
+        // frames further out start higher up, so the first one starting at or below `addr` has it
+        if let Some((sp, span)) = frames.iter().find(|(sp, _)| *sp <= addr) {
+            message += &format!(", byte {} of the {} of a proc", addr - sp, what);
+            spans.extend(span.map(|span| (span.clone(), format!("{} reserved here", what))));
+        }
+        (message, spans)
+    }
+}
+
+pub fn eval(ops: Vec<Op>, strings: &[String]) -> Result<Either<u64, Vec<u64>>, EvalError> {
+    Interpreter::new().run(&ops, strings)
+}
+
+#[test]
+fn test_host_call() {
+    use std::assert_matches::assert_matches;
+    let ops = vec![
+        Op::Call("main".to_string()),
+        Op::Exit,
+        Op::Proc("main".to_string()),
+        Op::Push(IConst::U64(20)),
+        Op::CallHost("double".to_string(), 1, 1),
+        Op::Return,
+    ];
+    let mut calls = 0;
+    let res = Interpreter::new()
+        .register("double", |args| {
+            calls += 1;
+            vec![args[0] * 2]
+        })
+        .run(&ops, &[]);
+    assert_matches!(res, Ok(Either::Left(40)));
+    assert_eq!(calls, 1);
+    assert_matches!(eval(ops, &[]), Err(EvalError::Trap(_)));
+}
+
+#[test]
+fn test_hash_str() {
+    use std::assert_matches::assert_matches;
+    let strings = ["hello, world".to_string()];
+    let ops = vec![
+        Op::Call("main".to_string()),
+        Op::Exit,
+        Op::Proc("main".to_string()),
+        Op::PushStr(0),
+        Op::HashStr,
+        Op::Return,
+    ];
+    let folded = intrinsics::hash_str(strings[0].as_bytes());
+    assert_matches!(eval(ops, &strings), Ok(Either::Left(hash)) if hash == folded);
+    assert_eq!(intrinsics::hash_str(b"a"), 0xaf63dc4c8601ec8c);
+}
+
+#[test]
+fn test_hash_str_bad_pointer() {
+    use std::assert_matches::assert_matches;
+    // a pointer outside of memory traps instead of panicking
+    let ops = vec![
+        Op::Call("main".to_string()),
+        Op::Exit,
+        Op::Proc("main".to_string()),
+        Op::Push(IConst::U64(4)),
+        Op::Push(IConst::U64(8)),
+        Op::HashStr,
+        Op::Return,
+    ];
+    assert_matches!(eval(ops, &[]), Err(EvalError::Trap(_)));
+}
+
+#[test]
+fn test_16_bytes() {
+    use std::assert_matches::assert_matches;
+    let l = |s: &str| s.to_string();
+    let strings = [l("abcdefghijklmnopqrstuvwxyz")];
+    let mems = [(l("buf"), 32)].into_iter().collect();
+    // fills the second half of `buf` with `a`s, copies the alphabet in front of it and compares
+    let ops = vec![
+        Op::Call(l("main")),
+        Op::Exit,
+        Op::Proc(l("main")),
+        Op::Push(IConst::U64(b'a' as u64)),
+        Op::PushMem(l("buf")),
+        Op::AddImm(16),
+        Op::Splat16,
+        Op::PushStr(0),
+        Op::PushMem(l("buf")),
+        Op::Copy16,
+        Op::Drop,
+        Op::PushMem(l("buf")),
+        Op::Dup,
+        Op::AddImm(16),
+        Op::Cmp16,
+        Op::Return,
+    ];
+    let res = Interpreter::new().mems(&mems).run(&ops, &strings);
+    assert_matches!(res, Ok(Either::Left(1)));
+}
+
+#[test]
+fn test_endian() {
+    use crate::lir::lower_source;
+    // stores in one byte order and loads in both, the other order swaps the bytes
+    let run = |ty: &str, value: u64, write: &str, read: &str| {
+        let (ops, mems) = lower_source(&format!(
+            "mem buf do 8 end
+            proc main : u64 do
+                {value} cast {ty} buf cast &>{ty} !{ty}-{write}
+                buf cast &>{ty} @{ty}-{read} cast u64
+            end"
+        ));
+        match Interpreter::new().mems(&mems).run(&ops, &[]) {
+            Ok(Either::Left(res)) => res,
+            res => panic!("{:?}", res),
+        }
+    };
+    for (ty, value, swapped) in [
+        ("u16", 0x0102, 0x0201),
+        ("u32", 0x0102_0304, 0x0403_0201),
+        ("u64", 0x0102_0304_0506_0708, 0x0807_0605_0403_0201),
+    ] {
+        for (write, other) in [("le", "be"), ("be", "le")] {
+            assert_eq!(run(ty, value, write, write), value, "{} {}", ty, write);
+            assert_eq!(run(ty, value, write, other), swapped, "{} {}", ty, write);
         }
-        i += 1;
     }
-    stack.right().okay()
+}
+
+#[test]
+fn test_checked_syscall() {
+    use crate::lir::lower_source;
+    use std::assert_matches::assert_matches;
+    // the result and the error flag come back as `ret * 10 + err?`
+    let (ops, mems) = lower_source("proc main : u64 do 99 3 syscall1! cast u64 swap 10 * + end");
+    let res = Interpreter::new()
+        .mems(&mems)
+        .syscalls(HostSyscalls::new())
+        .run(&ops, &[]);
+    assert_matches!(res, Ok(Either::Left(n)) if n == EBADF * 10 + 1);
+
+    let (ops, mems) = lower_source("proc main : u64 do 39 syscall0! cast u64 swap 10 * + end");
+    let res = Interpreter::new()
+        .mems(&mems)
+        .syscalls(|_: &mut Memory, nr, _: [u64; 6]| match nr {
+            39 => 4242,
+            _ => ENOSYS.wrapping_neg(),
+        })
+        .run(&ops, &[]);
+    assert_matches!(res, Ok(Either::Left(42420)));
+}
+
+#[test]
+fn test_print() {
+    use crate::lir::lower_source;
+    use std::assert_matches::assert_matches;
+    let (ops, mems) = lower_source(
+        "proc main : u64 do
+            0 5 - cast i64 print-signed
+            7 cast i64 print-signed
+            255 print-hex
+            42 5 print-pad
+            0 42 - cast i64 4 print-signed-pad
+            171 4 print-hex-pad
+            0
+        end",
+    );
+    let mut out = Vec::new();
+    let res = Interpreter::new()
+        .mems(&mems)
+        .syscalls(|memory: &mut Memory, nr, args: [u64; 6]| {
+            assert_eq!((nr, args[0]), (SYS_WRITE, 1));
+            out.extend_from_slice(memory.get(args[1], args[2]).unwrap());
+            args[2]
+        })
+        .run(&ops, &[]);
+    assert_matches!(res, Ok(Either::Left(0)));
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "-5\n7\nff\n00042\n-0042\n00ab\n"
+    );
+}
+
+#[test]
+fn test_backtrace() {
+    use crate::lir::lower_source;
+    let (ops, mems) = lower_source(
+        "proc inner u64 : u64 do 0 divmod drop end
+        proc outer : u64 do 7 inner end
+        proc main : u64 do outer end",
+    );
+    match Interpreter::new().mems(&mems).run(&ops, &[]) {
+        Err(EvalError::Trap(t)) => {
+            assert_eq!(t.message, "Division by zero");
+            assert_eq!(t.backtrace, ["inner", "outer", "main"]);
+            assert!(t
+                .to_string()
+                .ends_with("\n  at inner\n  at outer\n  at main"));
+        }
+        res => panic!("{:?}", res),
+    }
+}
+
+#[test]
+fn test_interpreter() {
+    use crate::hir::PrintFormat;
+    use std::assert_matches::assert_matches;
+    let l = |s: &str| s.to_string();
+    let strings = [l("hi ")];
+    let mems = [(l("buf"), 8)].into_iter().collect();
+    // writes the string and the first byte of argv[1], then stores argc in a local
+    // and in `buf` and exits with their sum
+    let ops = vec![
+        Op::Call(l("main")),
+        Op::Exit,
+        Op::Proc(l("main")),
+        Op::ReserveLocals(8),
+        Op::PushStr(0),
+        Op::Push(IConst::U64(1)),
+        Op::Push(IConst::U64(SYS_WRITE)),
+        Op::Syscall3,
+        Op::Drop,
+        Op::Push(IConst::U64(1)),
+        Op::Argv,
+        Op::Push(IConst::U64(8)),
+        Op::Add,
+        Op::ReadU64,
+        Op::Push(IConst::U64(1)),
+        Op::Push(IConst::U64(SYS_WRITE)),
+        Op::Syscall3,
+        Op::Print(PrintFormat::Unsigned),
+        Op::Argc,
+        Op::PushLvar(0),
+        Op::WriteU64,
+        Op::Argc,
+        Op::PushMem(l("buf")),
+        Op::WriteU8,
+        Op::PushLvar(0),
+        Op::ReadU64,
+        Op::PushMem(l("buf")),
+        Op::ReadU8,
+        Op::Add,
+        Op::FreeLocals(8),
+        Op::Return,
+    ];
+    let mut out = Vec::new();
+    let res = Interpreter::new()
+        .mems(&mems)
+        .args(["prog", "xyz"])
+        .syscalls(|memory: &mut Memory, nr, args: [u64; 6]| {
+            assert_eq!((nr, args[0]), (SYS_WRITE, 1));
+            out.extend_from_slice(memory.get(args[1], args[2]).unwrap());
+            args[2]
+        })
+        .run(&ops, &strings);
+    assert_matches!(res, Ok(Either::Left(4)));
+    assert_eq!(out, b"hi x1\n");
+
+    let ops = [Op::Push(IConst::U64(8)), Op::ReadU64];
+    assert_matches!(eval(ops.to_vec(), &[]), Err(EvalError::Trap(t)) if t.message.contains("0x8"));
+    assert_matches!(eval(vec![Op::Add], &[]), Err(EvalError::Trap(_)));
+}
+
+#[test]
+fn test_snapshot() {
+    use std::assert_matches::assert_matches;
+    let l = |s: &str| s.to_string();
+    let mems = [(l("counter"), 8)].into_iter().collect();
+    // setup stores 40 in the counter, bump adds its input to it and returns the new value
+    let ops = vec![
+        Op::Proc(l("proc0_setup")),
+        Op::Push(IConst::U64(40)),
+        Op::PushMem(l("counter")),
+        Op::WriteU64,
+        Op::Return,
+        Op::Proc(l("proc1_bump")),
+        Op::PushMem(l("counter")),
+        Op::ReadU64,
+        Op::Add,
+        Op::Dup,
+        Op::PushMem(l("counter")),
+        Op::WriteU64,
+        Op::Return,
+    ];
+    let mut interpreter = Interpreter::new();
+    interpreter.mems(&mems);
+    let mut machine = interpreter.start(&[]);
+    assert_matches!(interpreter.call(&mut machine, &ops, "setup"), Ok(None));
+    let fixture = machine.snapshot();
+
+    let mut bump = |machine: &mut Machine, by| {
+        machine.stack_mut().push(by);
+        interpreter.call(machine, &ops, "bump").unwrap();
+        machine.stack_mut().pop().unwrap()
+    };
+    assert_eq!(bump(&mut machine, 1), 41);
+    assert_eq!(bump(&mut machine, 1), 42);
+    machine.restore(&fixture);
+    assert_eq!(bump(&mut machine, 2), 42);
+    let counter = fixture.mem("counter").unwrap();
+    assert_eq!(fixture.memory().load(counter, 8), Some(40));
+    assert!(fixture.stack().is_empty());
+}
+
+#[test]
+fn test_uninit() {
+    use std::assert_matches::assert_matches;
+    let l = |s: &str| s.to_string();
+    let at = |start, end| Span::new("test.rh", start, end);
+    let mems = [(l("buf"), 8)].into_iter().collect();
+    let defined = [(l("buf"), at(0, 3))].into_iter().collect();
+    // writes the low byte of `buf` and reads all of it
+    let ops = vec![
+        Op::Call(l("main")),
+        Op::Exit,
+        Op::Proc(l("main")),
+        Op::Push(IConst::U64(1)),
+        Op::PushMem(l("buf")),
+        Op::WriteU8,
+        Op::Loc(at(20, 23)),
+        Op::PushMem(l("buf")),
+        Op::ReadU64,
+        Op::Return,
+    ];
+    let res = Interpreter::new().mems(&mems).run(&ops, &[]);
+    assert_matches!(res, Ok(Either::Left(1)));
+    let res = Interpreter::new()
+        .mems(&mems)
+        .check_uninit(&defined)
+        .run(&ops, &[]);
+    let trap = match res {
+        Err(EvalError::Trap(trap)) => trap,
+        _ => panic!("{:?}", res),
+    };
+    assert!(trap.message.ends_with(", byte 1 of `buf`"), "{}", trap);
+    let expected = [
+        (at(20, 23), l("read here")),
+        (at(0, 3), l("`buf` is defined here")),
+    ];
+    assert_eq!(trap.spans, expected);
+
+    // `get` reads the local `set` left behind in the same bytes, as native code would
+    let ops = vec![
+        Op::Call(l("set")),
+        Op::Call(l("get")),
+        Op::Exit,
+        Op::Proc(l("set")),
+        Op::ReserveLocals(8),
+        Op::Push(IConst::U64(42)),
+        Op::PushLvar(0),
+        Op::WriteU64,
+        Op::FreeLocals(8),
+        Op::Return,
+        Op::Proc(l("get")),
+        Op::Loc(at(30, 40)),
+        Op::ReserveLocals(8),
+        Op::PushLvar(0),
+        Op::ReadU64,
+        Op::FreeLocals(8),
+        Op::Return,
+    ];
+    assert_matches!(eval(ops.clone(), &[]), Ok(Either::Left(42)));
+    let res = Interpreter::new().check_uninit(&defined).run(&ops, &[]);
+    assert_matches!(
+        res,
+        Err(EvalError::Trap(t)) if t.message.ends_with(", byte 0 of the locals of a proc")
+            && t.spans[1] == (at(30, 40), l("locals reserved here"))
+    );
 }
@@ -0,0 +1,369 @@
+//! Random programs for testing the typechecker and everything after it.
+//! Well-typed programs are correct by construction, near misses carry exactly one fault
+//! that the typechecker has to catch.
+
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ty {
+    U64,
+    Bool,
+}
+
+impl Ty {
+    fn name(self) -> &'static str {
+        match self {
+            Ty::U64 => "u64",
+            Ty::Bool => "bool",
+        }
+    }
+}
+
+/// What a near miss gets wrong
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Leaves an extra value on the stack
+    ExtraValue,
+    /// Consumes a value too many, underflowing or unbalancing the enclosing block
+    MissingValue,
+    /// Adds a bool to a number
+    TypeMismatch,
+}
+
+#[derive(Debug)]
+struct Signature {
+    ins: Vec<Ty>,
+    outs: Vec<Ty>,
+}
+
+/// Deterministic generator of random programs, the same seed always gives the same programs
+pub struct Generator {
+    state: u64,
+    procs: Vec<Signature>,
+    depth: usize,
+    fault: Option<(Fault, u64)>,
+}
+
+const MAX_STACK: usize = 8;
+const MAX_DEPTH: usize = 2;
+
+impl Generator {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            // xorshift gets stuck on zero
+            state: seed.wrapping_mul(0x9e3779b97f4a7c15) | 1,
+            procs: Vec::new(),
+            depth: 0,
+            fault: None,
+        }
+    }
+
+    /// A program the typechecker must accept and that runs without underflowing
+    pub fn well_typed(&mut self) -> String {
+        self.program(None)
+    }
+
+    /// A program with a single fault in `main` that the typechecker must reject
+    pub fn near_miss(&mut self) -> (String, Fault) {
+        let fault = match self.below(3) {
+            0 => Fault::ExtraValue,
+            1 => Fault::MissingValue,
+            _ => Fault::TypeMismatch,
+        };
+        (self.program(Some(fault)), fault)
+    }
+
+    fn program(&mut self, fault: Option<Fault>) -> String {
+        self.procs.clear();
+        let mut src = String::new();
+        for i in 0..self.below(4) {
+            let ins = self.types(3);
+            let outs = self.types(2);
+            let body = self.body(&ins, &outs);
+            let names = |tys: &[Ty]| {
+                tys.iter()
+                    .map(|t| format!(" {}", t.name()))
+                    .collect::<String>()
+            };
+            let outs_sig = if outs.is_empty() {
+                String::new()
+            } else {
+                format!(" :{}", names(&outs))
+            };
+            writeln!(
+                src,
+                "proc f{}{}{} do\n{}\nend\n",
+                i,
+                names(&ins),
+                outs_sig,
+                body
+            )
+            .unwrap();
+            self.procs.push(Signature { ins, outs });
+        }
+        // main is generated last so that the fault can't end up in a proc that is never called
+        self.fault = fault.map(|f| (f, self.below(12)));
+        let body = self.body(&[], &[Ty::U64]);
+        if let Some((f, _)) = self.fault.take() {
+            // the countdown outlived main, put the fault at the very end
+            let mut words = Vec::new();
+            self.inject(f, &mut words, &[Ty::U64]);
+            writeln!(src, "proc main : u64 do\n{} {}\nend", body, words.join(" ")).unwrap();
+        } else {
+            writeln!(src, "proc main : u64 do\n{}\nend", body).unwrap();
+        }
+        src
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn ty(&mut self) -> Ty {
+        if self.below(3) == 0 {
+            Ty::Bool
+        } else {
+            Ty::U64
+        }
+    }
+
+    fn types(&mut self, max: u64) -> Vec<Ty> {
+        (0..self.below(max + 1)).map(|_| self.ty()).collect()
+    }
+
+    fn body(&mut self, ins: &[Ty], outs: &[Ty]) -> String {
+        let mut words = Vec::new();
+        let mut stack = ins.to_vec();
+        self.walk(&mut words, &mut stack, 0, 8);
+        let junk = stack.len();
+        for (i, &ty) in outs.iter().enumerate() {
+            self.value(&mut words, &mut stack, ty, 2);
+            debug_assert_eq!(stack.len(), junk + i + 1);
+        }
+        // the results are on top, roll out whatever is left below them
+        for _ in 0..junk {
+            words.push(format!("{} roll drop", outs.len()));
+        }
+        words.join(" ")
+    }
+
+    /// Random stack-effect-correct words that never touch the stack below `floor`
+    fn walk(&mut self, words: &mut Vec<String>, stack: &mut Vec<Ty>, floor: usize, fuel: usize) {
+        for _ in 0..fuel {
+            self.step(words, stack, floor);
+        }
+    }
+
+    fn step(&mut self, words: &mut Vec<String>, stack: &mut Vec<Ty>, floor: usize) {
+        if let Some((fault, countdown)) = &mut self.fault {
+            if *countdown == 0 {
+                let fault = *fault;
+                self.fault = None;
+                return self.inject(fault, words, stack);
+            }
+            *countdown -= 1;
+        }
+        let avail = stack.len() - floor;
+        let numbers = avail >= 2 && stack[stack.len() - 2..] == [Ty::U64, Ty::U64];
+        let condition = avail >= 1 && stack.last() == Some(&Ty::Bool);
+        let full = stack.len() >= MAX_STACK;
+        match self.below(12) {
+            0 | 1 if !full => {
+                let ty = self.ty();
+                self.value(words, stack, ty, 2)
+            }
+            2 if avail >= 1 => {
+                words.push("drop".into());
+                stack.pop();
+            }
+            3 if avail >= 1 && !full => {
+                words.push("dup".into());
+                stack.push(stack[stack.len() - 1]);
+            }
+            4 if avail >= 2 => {
+                words.push("swap".into());
+                let len = stack.len();
+                stack.swap(len - 1, len - 2);
+            }
+            5 if avail >= 2 && !full => {
+                words.push("over".into());
+                stack.push(stack[stack.len() - 2]);
+            }
+            6 if avail >= 3 => {
+                let n = 1 + self.below(avail as u64 - 1) as usize;
+                words.push(format!("{} roll", n));
+                let v = stack.remove(stack.len() - 1 - n);
+                stack.push(v);
+            }
+            7 if numbers => {
+                words.push(["+", "-", "*"][self.below(3) as usize].into());
+                stack.pop();
+            }
+            8 if numbers => {
+                words.push(["=", "!=", "<", "<=", ">", ">="][self.below(6) as usize].into());
+                stack.pop();
+                stack.pop();
+                stack.push(Ty::Bool);
+            }
+            9 if condition && self.depth < MAX_DEPTH => {
+                stack.pop();
+                self.depth += 1;
+                words.push("if".into());
+                // both arms leave the same single value, or nothing at all
+                let result = (self.below(2) == 0 && !full).then(|| self.ty());
+                self.arm(words, stack, result);
+                if result.is_some() || self.below(2) == 0 {
+                    words.push("else".into());
+                    self.arm(words, stack, result);
+                }
+                words.push("end".into());
+                self.depth -= 1;
+                stack.extend(result);
+            }
+            10 if self.depth < MAX_DEPTH && !full => {
+                // counts down so it always terminates
+                self.depth += 1;
+                words.push(format!("{} while dup 0 > do", self.below(4)));
+                stack.push(Ty::U64);
+                let floor = stack.len();
+                self.walk(words, stack, floor, 4);
+                self.unwind(words, stack, floor);
+                words.push("1 - end drop".into());
+                stack.pop();
+                self.depth -= 1;
+            }
+            11 => {
+                let callable = (0..self.procs.len())
+                    .filter(|&i| {
+                        let sig = &self.procs[i];
+                        sig.ins.len() <= avail
+                            && stack.ends_with(&sig.ins)
+                            && stack.len() - sig.ins.len() + sig.outs.len() <= MAX_STACK
+                    })
+                    .collect::<Vec<_>>();
+                if !callable.is_empty() {
+                    let i = callable[self.below(callable.len() as u64) as usize];
+                    words.push(format!("f{}", i));
+                    let sig = &self.procs[i];
+                    stack.truncate(stack.len() - sig.ins.len());
+                    stack.extend(&sig.outs);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn arm(&mut self, words: &mut Vec<String>, stack: &mut Vec<Ty>, result: Option<Ty>) {
+        let floor = stack.len();
+        self.walk(words, stack, floor, 3);
+        self.unwind(words, stack, floor);
+        if let Some(ty) = result {
+            self.value(words, stack, ty, 1);
+            stack.pop();
+        }
+    }
+
+    /// Drops everything above `floor`
+    fn unwind(&mut self, words: &mut Vec<String>, stack: &mut Vec<Ty>, floor: usize) {
+        while stack.len() > floor {
+            words.push("drop".into());
+            stack.pop();
+        }
+    }
+
+    /// An expression leaving a single value of type `ty`, possibly copied from the stack
+    fn value(&mut self, words: &mut Vec<String>, stack: &mut Vec<Ty>, ty: Ty, depth: usize) {
+        let found = stack.iter().rev().position(|&t| t == ty);
+        match (ty, self.below(if depth == 0 { 2 } else { 4 })) {
+            (_, 0) if found.is_some() => words.push(format!("{} pick", found.unwrap())),
+            (Ty::U64, 0 | 1) => words.push(self.below(100).to_string()),
+            (Ty::Bool, 0 | 1) => words.push((self.below(2) == 0).to_string()),
+            (Ty::U64, _) => {
+                self.value(words, stack, Ty::U64, depth - 1);
+                self.value(words, stack, Ty::U64, depth - 1);
+                stack.truncate(stack.len() - 2);
+                words.push(["+", "-", "*"][self.below(3) as usize].into());
+            }
+            (Ty::Bool, _) => {
+                self.value(words, stack, Ty::U64, depth - 1);
+                self.value(words, stack, Ty::U64, depth - 1);
+                stack.truncate(stack.len() - 2);
+                words.push(["=", "!=", "<", "<=", ">", ">="][self.below(6) as usize].into());
+            }
+        }
+        stack.push(ty);
+    }
+
+    fn inject(&mut self, fault: Fault, words: &mut Vec<String>, stack: &[Ty]) {
+        // the model stack is left as if nothing happened,
+        // every later word has a fixed effect on the depth so the fault can't cancel out
+        match fault {
+            Fault::ExtraValue => words.push(self.below(100).to_string()),
+            Fault::MissingValue => words.push("drop".into()),
+            Fault::TypeMismatch => {
+                if stack.is_empty() {
+                    words.push("1 true + drop".into())
+                } else {
+                    words.push("true +".into())
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_generated_programs() {
+    use crate::{
+        ast::parse_no_include,
+        eval::eval,
+        hir::Walker,
+        lexer::lex_string,
+        lir,
+        session::{Options, Session},
+        typecheck::Typechecker,
+    };
+    use somok::Either;
+
+    let check = |src: &str, opt_level: u8| {
+        let tokens = lex_string(src.to_string(), "gen.rh".into()).unwrap();
+        let ast = parse_no_include(tokens).unwrap_or_else(|e| panic!("{:?}\n{}", e, src));
+        let options = Options::builder().opt_level(opt_level).build().unwrap();
+        let session = Session::new(Default::default(), options);
+        let items = ast.into_iter().map(|i| (i.name().unwrap(), i)).collect();
+        let hir = Walker::new(&session).walk_ast(items).unwrap();
+        Typechecker::typecheck_program(hir, &session)
+            .map(|(procs, _)| lir::Compiler::new(&session).compile(procs).unwrap().ops)
+    };
+
+    for seed in 0..200 {
+        let mut gen = Generator::new(seed);
+        let src = gen.well_typed();
+        let run = |opt_level| {
+            let ops = check(&src, opt_level)
+                .unwrap_or_else(|e| panic!("seed {}: {:?}\n{}", seed, e, src));
+            match eval(ops, &[]) {
+                Ok(Either::Left(exit)) => exit,
+                res => panic!("seed {}: {:?}\n{}", seed, res.map(|_| ()), src),
+            }
+        };
+        let exit = run(0);
+        assert_eq!(exit, run(1), "seed {}\n{}", seed, src);
+        assert_eq!(exit, run(2), "seed {}\n{}", seed, src);
+
+        let (src, fault) = gen.near_miss();
+        assert!(
+            check(&src, 0).is_err(),
+            "seed {}: {:?} accepted\n{}",
+            seed,
+            fault,
+            src
+        );
+    }
+}
@@ -1,9 +1,13 @@
 use crate::{
     ast::{self, AstKind, AstNode, Cast},
     iconst::IConst,
+    intrinsics,
+    layout::Bits,
     lexer::KeyWord,
+    session::{Session, Target},
     span::Span,
-    types::{self, StructId, StructIndex, Type},
+    types::{self, StructId, StructIndex, Type, ValueType},
+    Error,
 };
 use fnv::FnvHashMap;
 use somok::Somok;
@@ -13,6 +17,7 @@ pub enum TopLevel {
     Proc(Proc),
     Const(Const),
     Mem(Mem),
+    Table(Table),
     Var(TopLevelVar),
 }
 impl TopLevel {
@@ -49,24 +54,77 @@ pub struct TopLevelVar {
 
 #[derive(Debug, Clone)]
 pub struct Proc {
+    pub attrs: Attributes,
     pub ins: Vec<Type>,
     pub outs: Vec<Type>,
+    /// Documentation only names of the outputs, parallel to `outs`
+    pub out_names: Vec<Option<String>>,
     pub body: Vec<HirNode>,
     pub span: Span,
     pub vars: FnvHashMap<String, Var>,
+    /// Declared with `extern host`, the body is provided by the embedder
+    pub host: bool,
+}
+
+impl Proc {
+    /// Pretty signature, e.g. `U64 U64 : quotient: U64 rem: U64`
+    pub fn signature(&self) -> String {
+        let ins = self.ins.iter().map(|ty| format!("{ty:?}"));
+        let outs = self
+            .outs
+            .iter()
+            .zip(&self.out_names)
+            .map(|(ty, name)| match name {
+                Some(name) => format!("{name}: {ty:?}"),
+                None => format!("{ty:?}"),
+            });
+        if self.outs.is_empty() {
+            ins.collect::<Vec<_>>().join(" ")
+        } else {
+            ins.chain(std::iter::once(":".to_string()))
+                .chain(outs)
+                .collect::<Vec<_>>()
+                .join(" ")
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Const {
+    pub attrs: Attributes,
     pub outs: Vec<Type>,
     pub body: Vec<HirNode>,
     pub span: Span,
 }
 
+#[derive(Debug, Clone, Default)]
+pub struct Attributes {
+    /// Replacement hint from `#[deprecated("...")]`, empty if none was given
+    pub deprecated: Option<String>,
+    /// `#[cold]`, the proc is unlikely to be called, e.g. it reports an error and exits
+    pub cold: bool,
+    /// `#[pure]`, the proc only computes its outputs from its inputs, so consts can call it
+    pub pure: bool,
+    /// `#[inline]`, calls of the proc are replaced with a copy of its body
+    pub inline: bool,
+}
+
 #[derive(Debug, Clone)]
 pub struct Mem {
     pub body: Vec<HirNode>,
     pub span: Span,
+    /// Defined by a linked object, `body` only gives its size
+    pub extern_: bool,
+}
+
+/// An array in read-only memory whose elements are computed by `body` at compile time, with the
+/// index on the stack
+#[derive(Debug, Clone)]
+pub struct Table {
+    pub ty: Type,
+    pub len: usize,
+    pub body: Vec<HirNode>,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
@@ -81,12 +139,74 @@ pub enum HirKind {
     Intrinsic(Intrinsic),
     Bind(Bind),
     While(While),
+    /// Runs the body with the top element stashed away
+    Dip(Vec<HirNode>),
+    /// Runs the body, then pushes back the top element it started with
+    Keep(Vec<HirNode>),
+    /// Runs the body when the enclosing block is left, at its end or by `return`
+    Defer(Vec<HirNode>),
     If(If),
     Cond(Cond),
     Literal(IConst),
     IgnorePattern,
     Return,
+    /// Leaves the innermost loop
+    Break,
+    /// Goes on with the next check of the innermost loop's condition
+    Continue,
     FieldAccess(FieldAccess),
+    /// `&name`, the address of the proc `name`, which the typechecker makes of the word
+    ProcRef(String),
+    /// `call-like name`, calls the proc pointer on top with the signature of the proc `name`,
+    /// which the pointer's type must have. A cast `ptr(proc)` has none to check this against, so
+    /// checked builds trap when the call leaves the stack at another depth than `name` would.
+    CallLike(String),
+}
+
+impl HirNode {
+    /// The blocks nested in this node
+    pub fn blocks(&self) -> Vec<&[HirNode]> {
+        match &self.hir {
+            HirKind::Bind(b) => vec![&b.body],
+            HirKind::While(w) => vec![&w.cond, &w.body],
+            HirKind::Dip(b) | HirKind::Keep(b) | HirKind::Defer(b) => vec![b],
+            HirKind::If(i) => [&i.truth]
+                .into_iter()
+                .chain(&i.lie)
+                .map(|b| &b[..])
+                .collect(),
+            HirKind::Cond(c) => c.branches.iter().map(|b| &b.body[..]).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// The blocks nested in this node, like `blocks`
+    pub fn blocks_mut(&mut self) -> Vec<&mut Vec<HirNode>> {
+        match &mut self.hir {
+            HirKind::Bind(b) => vec![&mut b.body],
+            HirKind::While(w) => vec![&mut w.cond, &mut w.body],
+            HirKind::Dip(b) | HirKind::Keep(b) | HirKind::Defer(b) => vec![b],
+            HirKind::If(i) => [&mut i.truth].into_iter().chain(&mut i.lie).collect(),
+            HirKind::Cond(c) => c.branches.iter_mut().map(|b| &mut b.body).collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Whether `body` always ends by leaving it, with `return`, `break` or `continue`
+pub fn jumps_out(body: &[HirNode]) -> bool {
+    matches!(
+        body.last(),
+        Some(HirNode {
+            hir: HirKind::Return | HirKind::Break | HirKind::Continue,
+            ..
+        })
+    )
+}
+
+/// The source `body` spans, `None` if it is empty or its ends are in different files
+pub fn body_span(body: &[HirNode]) -> Option<Span> {
+    body.first()?.span.cover(&body.last()?.span)
 }
 #[derive(Debug, Clone)]
 pub struct FieldAccess {
@@ -119,20 +239,86 @@ pub struct Bind {
 pub struct While {
     pub cond: Vec<HirNode>,
     pub body: Vec<HirNode>,
+    /// Copies of the body per jump back from `#[unroll(n)]`
+    pub unroll: Option<usize>,
 }
 
 #[derive(Debug, Clone)]
 pub enum Binding {
     Ignore,
-    Bind { name: String, ty: Type },
+    Bind {
+        name: String,
+        ty: Type,
+    },
+    Destructure {
+        fields: Vec<String>,
+        /// Filled in by the typechecker
+        kind: Option<Destructure>,
+    },
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
+pub enum Destructure {
+    /// Pointer to a struct, fields are loaded through it
+    Struct(StructId),
+    /// `len ptr` pair, as pushed by string literals
+    Slice,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+/// How the `print` family renders a number, always followed by a newline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrintFormat {
+    Unsigned,
+    Signed,
+    /// Lowercase, without a prefix
+    Hex,
+}
+
+impl PrintFormat {
+    /// Widths past this are clamped, the runtime buffer has no room for more
+    pub const MAX_WIDTH: usize = 29;
+
+    pub fn radix(self) -> u64 {
+        match self {
+            PrintFormat::Unsigned | PrintFormat::Signed => 10,
+            PrintFormat::Hex => 16,
+        }
+    }
+
+    pub fn is_signed(self) -> bool {
+        self == PrintFormat::Signed
+    }
+
+    /// Renders `value` the way the runtime's `print_fmt` does, without the newline
+    pub fn format(self, value: u64, width: usize) -> String {
+        let width = width.min(Self::MAX_WIDTH);
+        match self {
+            PrintFormat::Unsigned => format!("{:0width$}", value),
+            PrintFormat::Signed if (value as i64) < 0 => {
+                format!("-{:0width$}", (value as i64).unsigned_abs())
+            }
+            PrintFormat::Signed => format!("{:0width$}", value),
+            PrintFormat::Hex => format!("{:0width$x}", value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Intrinsic {
     Drop,
     Dup,
     Swap,
     Over,
+    /// `n pick`, the depth is folded in from the preceding literal
+    Pick(Option<usize>),
+    /// `n roll`, the depth is folded in from the preceding literal
+    Roll(Option<usize>),
 
     Cast(Type),
 
@@ -140,10 +326,28 @@ pub enum Intrinsic {
     ReadU8,
     WriteU64,
     WriteU8,
+    /// Native loads and stores of the other integer types, `@i32` or `!u16`
+    Read(Type),
+    Write(Type),
+
+    ReadEndian(Type, Endian),
+    WriteEndian(Type, Endian),
 
     CompStop,
     Dump,
-    Print,
+    Print(PrintFormat),
+    /// `value width print-pad`, zero-padded to at least `width` digits
+    PrintPadded(PrintFormat),
+
+    /// `len ptr hash-str`, folded when the string is a literal
+    HashStr,
+
+    /// `src dst copy16`, `a b cmp16` and `byte dst splat8x16`, 16 bytes at a time
+    Copy16,
+    Cmp16,
+    Splat16,
+    /// `ptr len byte find-byte`, the index of the first `byte` and whether there is one
+    FindByte,
 
     Syscall0,
     Syscall1,
@@ -152,21 +356,40 @@ pub enum Intrinsic {
     Syscall4,
     Syscall5,
     Syscall6,
+    CheckedSyscall(u8),
 
     Argc,
     Argv,
+    /// `n argv-at`, the `n`th argument and its length, which is scanned for once
+    ArgvAt,
 
-    Add,
-    Sub,
-    Divmod,
-    Mul,
+    /// The operand type, filled in by the typechecker so results of narrow integers can wrap
+    Add(Option<Type>),
+    Sub(Option<Type>),
+    /// Typed like `Add` too, signed integers divide and compare as such
+    Divmod(Option<Type>),
+    Mul(Option<Type>),
+
+    BitAnd,
+    BitOr,
+    BitXor,
+    /// Typed like `Add`, the complement of a narrow unsigned integer and a left shift wrap and
+    /// a right shift of a signed one keeps its sign
+    BitNot(Option<Type>),
+    Shl(Option<Type>),
+    Shr(Option<Type>),
+
+    /// Both operands are evaluated, bools being 0 or 1 these are the bitwise ops
+    And,
+    Or,
+    Not,
 
     Eq,
     Ne,
-    Lt,
-    Le,
-    Gt,
-    Ge,
+    Lt(Option<Type>),
+    Le(Option<Type>),
+    Gt(Option<Type>),
+    Ge(Option<Type>),
 }
 
 #[derive(Debug, Clone)]
@@ -178,13 +401,21 @@ pub struct Var {
 pub struct Walker<'s> {
     structs: &'s StructIndex,
     proc_vars: FnvHashMap<String, Var>,
+    /// Alias names and the words they finally stand for
+    aliases: FnvHashMap<String, String>,
+    /// Names bound by the enclosing binds, these shadow aliases
+    bound: Vec<String>,
+    target: Target,
 }
 
 impl<'s> Walker<'s> {
-    pub fn new(structs: &'s StructIndex) -> Self {
+    pub fn new(session: &'s Session) -> Self {
         Self {
-            structs,
+            structs: &session.structs,
             proc_vars: Default::default(),
+            aliases: Default::default(),
+            bound: Default::default(),
+            target: session.options.target,
         }
     }
     fn intrinsic(&mut self, ast: &AstNode) -> Option<HirNode> {
@@ -197,45 +428,7 @@ impl<'s> Walker<'s> {
                         ast: AstKind::Type(ty),
                     },
             }) => Intrinsic::Cast(ty.clone().to_type(self.structs).unwrap()),
-            AstKind::Word(ref w) => match w.as_str() {
-                "drop" => Intrinsic::Drop,
-                "dup" => Intrinsic::Dup,
-                "swap" => Intrinsic::Swap,
-                "over" => Intrinsic::Over,
-
-                "@u64" => Intrinsic::ReadU64,
-                "@u8" => Intrinsic::ReadU8,
-                "!u64" => Intrinsic::WriteU64,
-                "!u8" => Intrinsic::WriteU8,
-
-                "&?&" => Intrinsic::CompStop,
-                "&?" => Intrinsic::Dump,
-                "print" => Intrinsic::Print,
-
-                "syscall0" => Intrinsic::Syscall0,
-                "syscall1" => Intrinsic::Syscall1,
-                "syscall2" => Intrinsic::Syscall2,
-                "syscall3" => Intrinsic::Syscall3,
-                "syscall4" => Intrinsic::Syscall4,
-                "syscall5" => Intrinsic::Syscall5,
-                "syscall6" => Intrinsic::Syscall6,
-
-                "argc" => Intrinsic::Argc,
-                "argv" => Intrinsic::Argv,
-
-                "+" => Intrinsic::Add,
-                "-" => Intrinsic::Sub,
-                "*" => Intrinsic::Mul,
-                "divmod" => Intrinsic::Divmod,
-
-                "=" => Intrinsic::Eq,
-                "!=" => Intrinsic::Ne,
-                "<" => Intrinsic::Lt,
-                "<=" => Intrinsic::Le,
-                ">" => Intrinsic::Gt,
-                ">=" => Intrinsic::Ge,
-                _ => return None,
-            },
+            AstKind::Word(ref w) => intrinsics::lookup(w)?.intrinsic.clone(),
             _ => return None,
         };
         HirNode {
@@ -267,6 +460,13 @@ impl<'s> Walker<'s> {
                         name,
                         ty: ty.to_type(self.structs).unwrap(),
                     }),
+                    ast::Binding::Destructure { fields } => res.push(Binding::Destructure {
+                        fields: fields
+                            .into_iter()
+                            .map(|f| coerce_ast!(f => Word || unreachable!()))
+                            .collect(),
+                        kind: None,
+                    }),
                     _ => unreachable!(),
                 }
             } else {
@@ -276,20 +476,43 @@ impl<'s> Walker<'s> {
         res
     }
 
+    /// Fails on alias cycles, which only parsing with includes rejects up front
     pub fn walk_ast(
         &mut self,
         ast: FnvHashMap<String, ast::TopLevel>,
-    ) -> FnvHashMap<String, TopLevel> {
-        ast.into_iter()
-            .map(|(name, item)| (name, self.walk_toplevel(item)))
-            .collect()
+    ) -> Result<FnvHashMap<String, TopLevel>, Error> {
+        self.aliases = ast::resolve_aliases(ast.values())?;
+        let mut items = FnvHashMap::default();
+        for (name, item) in ast {
+            match item {
+                ast::TopLevel::Alias(_) => (),
+                ast::TopLevel::Const(c) if !c.group.is_empty() => {
+                    items.extend(self.walk_const_group(c))
+                }
+                item => {
+                    items.insert(name, self.walk_toplevel(item));
+                }
+            }
+        }
+        // the program's own definitions win over the target's
+        for (i, (name, value)) in self.target.consts().into_iter().enumerate() {
+            items
+                .entry(name)
+                .or_insert_with(|| TopLevel::Const(target_const(i, value)));
+        }
+        for (name, accessor) in struct_accessors(self.structs) {
+            items.entry(name).or_insert(TopLevel::Proc(accessor));
+        }
+        items.okay()
     }
 
     fn walk_toplevel(&mut self, item: ast::TopLevel) -> TopLevel {
         match item {
             ast::TopLevel::Proc(p) => TopLevel::Proc(self.walk_proc(p)),
+            ast::TopLevel::Extern(e) => TopLevel::Proc(self.walk_extern(e)),
             ast::TopLevel::Const(c) => TopLevel::Const(self.walk_const(c)),
             ast::TopLevel::Mem(m) => TopLevel::Mem(self.walk_mem(m)),
+            ast::TopLevel::Table(t) => TopLevel::Table(self.walk_table(t)),
             ast::TopLevel::Var(v) => {
                 let ty = coerce_ast!(v.ty => Type || unreachable!())
                     .to_type(self.structs)
@@ -308,9 +531,44 @@ impl<'s> Walker<'s> {
             .into_iter()
             .map(|ast| self.walk_node(ast).unwrap())
             .collect::<Vec<_>>();
+        let body = fold_depths(body);
+        let extern_ = mem.extern_.is_some();
         Mem {
             body,
-            span: mem.mem.span.merge(mem.end.span),
+            span: mem.extern_.unwrap_or(mem.mem).span.merge(mem.end.span),
+            extern_,
+        }
+    }
+
+    fn walk_table(&mut self, table: ast::Table) -> Table {
+        let ty = coerce_ast!(table.ty => Type || unreachable!())
+            .to_type(self.structs)
+            .unwrap();
+        let len = match table.len.ast {
+            AstKind::Literal(IConst::Int(len)) => len as usize,
+            _ => unreachable!(),
+        };
+        let body = coerce_ast!(table.body => Body || unreachable!())
+            .into_iter()
+            .map(|ast| self.walk_node(ast).unwrap())
+            .collect::<Vec<_>>();
+        let span = table.table.span.merge(table.end.span);
+        // the index is the binding `i`
+        let body = vec![HirNode {
+            span: span.clone(),
+            hir: HirKind::Bind(Bind {
+                bindings: vec![Binding::Bind {
+                    name: "i".to_string(),
+                    ty: Type::U64,
+                }],
+                body: fold_depths(body),
+            }),
+        }];
+        Table {
+            ty,
+            len,
+            body,
+            span,
         }
     }
 
@@ -328,15 +586,66 @@ impl<'s> Walker<'s> {
             .into_iter()
             .map(|ast| self.walk_node(ast).unwrap())
             .collect::<Vec<_>>();
+        let body = fold_depths(body);
         Const {
+            attrs: self.walk_attributes(const_.attrs),
             outs,
             body,
             span: const_.const_.span.merge(const_.end.span),
         }
     }
 
+    /// A const group as a const of all its values, named by all its names, and a const for each
+    /// name picking its value out of that one, so that the body is evaluated once
+    fn walk_const_group(&mut self, const_: ast::Const) -> Vec<(String, TopLevel)> {
+        let names = std::iter::once(&const_.name)
+            .chain(&const_.group)
+            .map(|n| {
+                (
+                    coerce_ast!(n => REF Word || unreachable!()).clone(),
+                    n.span.clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let group = names
+            .iter()
+            .map(|(n, _)| n.as_str())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let mut all = self.walk_const(const_);
+        let attrs = std::mem::take(&mut all.attrs);
+        let count = names.len();
+        let mut items = names
+            .into_iter()
+            .enumerate()
+            .map(|(i, (name, span))| {
+                let node = |hir| HirNode {
+                    span: span.clone(),
+                    hir,
+                };
+                let mut body = vec![node(HirKind::Word(group.clone()))];
+                for _ in i + 1..count {
+                    body.push(node(HirKind::Intrinsic(Intrinsic::Drop)));
+                }
+                for _ in 0..i {
+                    body.push(node(HirKind::Intrinsic(Intrinsic::Swap)));
+                    body.push(node(HirKind::Intrinsic(Intrinsic::Drop)));
+                }
+                let const_ = Const {
+                    attrs: attrs.clone(),
+                    outs: vec![all.outs[i]],
+                    body,
+                    span,
+                };
+                (name, TopLevel::Const(const_))
+            })
+            .collect::<Vec<_>>();
+        items.push((group, TopLevel::Const(all)));
+        items
+    }
+
     fn walk_proc(&mut self, proc: ast::Proc) -> Proc {
-        let (ins, outs) = match proc.signature.ast {
+        let (ins, outs, out_names) = match proc.signature.ast {
             AstKind::ProcSignature(signature) => self.walk_proc_signature(signature),
             _ => unreachable!(),
         };
@@ -346,35 +655,88 @@ impl<'s> Walker<'s> {
         std::mem::swap(&mut vars, &mut self.proc_vars);
 
         Proc {
+            attrs: self.walk_attributes(proc.attrs),
             ins,
             outs,
+            out_names,
             body: body.unwrap(),
             vars,
             span: proc.proc.span.merge(proc.end.span),
+            host: false,
+        }
+    }
+
+    fn walk_extern(&mut self, extern_: ast::Extern) -> Proc {
+        let (ins, outs, out_names) = match extern_.signature.ast {
+            AstKind::ProcSignature(signature) => self.walk_proc_signature(signature),
+            _ => unreachable!(),
+        };
+
+        Proc {
+            attrs: Default::default(),
+            ins,
+            outs,
+            out_names,
+            body: Vec::new(),
+            vars: Default::default(),
+            span: extern_.extern_.span.merge(extern_.signature.span),
+            host: true,
         }
     }
 
+    fn walk_attributes(&mut self, attrs: Vec<AstNode>) -> Attributes {
+        let mut res = Attributes::default();
+        for attr in attrs {
+            let attr = coerce_ast!(attr => Attribute || unreachable!());
+            match attr.name.as_str() {
+                "deprecated" => res.deprecated = attr.arg.unwrap_or_default().some(),
+                "cold" => res.cold = true,
+                "pure" => res.pure = true,
+                "inline" => res.inline = true,
+                _ => (),
+            }
+        }
+        res
+    }
+
     fn try_walk_body(&mut self, node: AstNode) -> Option<Vec<HirNode>> {
         let body = coerce_ast!(node => Body || None)?;
-        body.into_iter()
+        let body = body
+            .into_iter()
             .filter_map(|ast| self.walk_node(ast))
-            .collect::<Vec<_>>()
-            .some()
+            .collect::<Vec<_>>();
+        fold_depths(body).some()
+    }
+
+    fn walk_body(&mut self, node: AstNode) -> Vec<HirNode> {
+        self.try_walk_body(node).unwrap()
     }
 
-    fn walk_node(&mut self, node: AstNode) -> Option<HirNode> {
+    fn walk_node(&mut self, mut node: AstNode) -> Option<HirNode> {
+        if let AstKind::Word(w) = &mut node.ast {
+            if let Some(target) = self.aliases.get(w) {
+                if !self.bound.contains(w) && !self.proc_vars.contains_key(w) {
+                    *w = target.clone();
+                }
+            }
+        }
         if let Some(node) = self.intrinsic(&node) {
             return node.some();
         }
         let hir = match node.ast {
             AstKind::Bind(bind) => HirKind::Bind(self.walk_bind(bind)),
             AstKind::While(while_) => HirKind::While(self.walk_while(while_)),
+            AstKind::Dip(dip) => HirKind::Dip(self.walk_body(*dip.body)),
+            AstKind::Keep(keep) => HirKind::Keep(self.walk_body(*keep.body)),
+            AstKind::Defer(defer) => HirKind::Defer(self.walk_body(*defer.body)),
             AstKind::If(if_) => HirKind::If(self.walk_if(if_)),
             AstKind::Cond(cond) => HirKind::Cond(self.walk_cond(cond)),
             AstKind::Cast(_) => unreachable!(),
             AstKind::Word(w) => HirKind::Word(w),
             AstKind::Literal(l) => HirKind::Literal(l),
             AstKind::KeyWord(KeyWord::Return) => HirKind::Return,
+            AstKind::KeyWord(KeyWord::Break) => HirKind::Break,
+            AstKind::KeyWord(KeyWord::Continue) => HirKind::Continue,
             AstKind::Var(box var) => {
                 self.walk_var(var);
                 return None;
@@ -407,69 +769,72 @@ impl<'s> Walker<'s> {
 
     fn walk_bind(&mut self, bind: ast::Bind) -> Bind {
         let bindings = self.hir_bindings(bind.bindings);
-        let body = coerce_ast!(bind.body => Body || unreachable!())
-            .into_iter()
-            .filter_map(|node| self.walk_node(node))
-            .collect();
+        let depth = self.bound.len();
+        for binding in &bindings {
+            match binding {
+                Binding::Ignore => (),
+                Binding::Bind { name, .. } => self.bound.push(name.clone()),
+                Binding::Destructure { fields, .. } => self.bound.extend(fields.iter().cloned()),
+            }
+        }
+        let body = self.walk_body(*bind.body);
+        self.bound.truncate(depth);
         Bind { bindings, body }
     }
 
     fn walk_cond(&mut self, cond: ast::Cond) -> Cond {
-        let branches = cond
-            .branches
-            .into_iter()
-            .map(|b| self.walk_cond_branch(b))
+        let first = self.walk_cond_branch(*cond.pat, *cond.body);
+        let branches = std::iter::once(first)
+            .chain(
+                cond.branches
+                    .into_iter()
+                    .map(|b| self.walk_cond_branch(*b.pat, *b.body)),
+            )
             .collect();
         Cond { branches }
     }
 
-    fn walk_cond_branch(&mut self, branch: ast::CondBranch) -> CondBranch {
-        let pattern = match branch.pat.ast {
+    fn walk_cond_branch(&mut self, pat: AstNode, body: AstNode) -> CondBranch {
+        let pattern = match pat.ast {
             AstKind::Binding(ast::Binding::Ignore) => HirNode {
-                span: branch.pat.span,
+                span: pat.span,
                 hir: HirKind::IgnorePattern,
             },
             AstKind::Literal(l) => HirNode {
-                span: branch.pat.span,
+                span: pat.span,
                 hir: HirKind::Literal(l),
             },
-            _ => unreachable!(),
+            // a const, the typechecker rejects anything else
+            _ => self.walk_node(pat).unwrap(),
         };
-        let body = coerce_ast!(branch.body => Body || unreachable!())
-            .into_iter()
-            .filter_map(|node| self.walk_node(node))
-            .collect();
+        let body = self.walk_body(body);
         CondBranch { pattern, body }
     }
 
     fn walk_while(&mut self, while_: ast::While) -> While {
-        let cond = coerce_ast!(while_.cond => Body || unreachable!())
-            .into_iter()
-            .filter_map(|node| self.walk_node(node))
-            .collect();
-        let body = coerce_ast!(while_.body => Body || unreachable!())
-            .into_iter()
-            .filter_map(|node| self.walk_node(node))
-            .collect();
-        While { cond, body }
+        let unroll = while_.attrs.into_iter().find_map(|attr| {
+            let attr = coerce_ast!(attr => Attribute || unreachable!());
+            match attr.name.as_str() {
+                "unroll" => attr.arg?.parse::<usize>().ok()?.max(1).some(),
+                _ => None,
+            }
+        });
+        let cond = self.walk_body(*while_.cond);
+        let body = self.walk_body(*while_.body);
+        While { cond, body, unroll }
     }
 
     fn walk_if(&mut self, if_: ast::If) -> If {
-        let truth = coerce_ast!(if_.truth => Body || unreachable!())
-            .into_iter()
-            .filter_map(|node| self.walk_node(node))
-            .collect();
-        let lie = if_.lie.map(|lie| {
-            coerce_ast!(lie.body => Body || unreachable!())
-                .into_iter()
-                .filter_map(|node| self.walk_node(node))
-                .collect()
-        });
+        let truth = self.walk_body(*if_.truth);
+        let lie = if_.lie.map(|lie| self.walk_body(*lie.body));
 
         If { truth, lie }
     }
 
-    fn walk_proc_signature(&mut self, signature: ast::ProcSignature) -> (Vec<Type>, Vec<Type>) {
+    fn walk_proc_signature(
+        &mut self,
+        signature: ast::ProcSignature,
+    ) -> (Vec<Type>, Vec<Type>, Vec<Option<String>>) {
         let mut ins = Vec::with_capacity(signature.ins.len());
         for ty in signature.ins {
             if let AstKind::Type(ty) = ty.ast {
@@ -478,20 +843,333 @@ impl<'s> Walker<'s> {
                 unreachable!();
             }
         }
-        let outs = if let Some(outs) = signature.outs {
-            let mut proc_outs = Vec::with_capacity(outs.len());
-            for ty in outs {
-                if let AstKind::Type(ty) = ty.ast {
-                    proc_outs.push(ty.to_type(self.structs).unwrap());
+        let mut outs = Vec::new();
+        let mut out_names = Vec::new();
+        for ty in signature.outs.unwrap_or_default() {
+            match ty.ast {
+                AstKind::Type(ty) => {
+                    outs.push(ty.to_type(self.structs).unwrap());
+                    out_names.push(None);
+                }
+                AstKind::NamedType(named) => {
+                    let name = coerce_ast!(named.name => Word || unreachable!());
+                    let ty = coerce_ast!(named.ty => Type || unreachable!());
+                    outs.push(ty.to_type(self.structs).unwrap());
+                    out_names.push(Some(name));
+                }
+                _ => unreachable!(),
+            }
+        }
+
+        (ins, outs, out_names)
+    }
+}
+
+/// File the spans of target consts point into, it does not exist
+pub const TARGET_FILE: &str = "<target>";
+
+/// A `u64` const of `Target::consts`, the `n`th one
+fn target_const(n: usize, value: u64) -> Const {
+    let span = Span::point(TARGET_FILE, n);
+    Const {
+        attrs: Default::default(),
+        outs: vec![Type::U64],
+        body: vec![HirNode {
+            span: span.clone(),
+            hir: HirKind::Literal(IConst::U64(value)),
+        }],
+        span,
+    }
+}
+
+pub const ACCESSORS_FILE: &str = "<accessors>";
+
+/// `Struct.field@ (&>Struct -- value)` and `Struct.field! (value &>Struct --)` of every field of
+/// every struct, inline procs loading and storing the field with its type
+fn struct_accessors(structs: &StructIndex) -> Vec<(String, Proc)> {
+    let mut spans = 0..;
+    let mut node = |hir: HirKind| HirNode {
+        span: Span::point(ACCESSORS_FILE, spans.next().unwrap()),
+        hir,
+    };
+    let mut res = Vec::new();
+    for (id, name) in structs.id_names() {
+        let ptr = Type::ptr_to(Type {
+            ptr_depth: 0,
+            value_type: ValueType::Struct(id),
+        });
+        let mut fields = structs[id].fields.iter().collect::<Vec<_>>();
+        fields.sort_by_key(|(field, f)| (f.offset, *field));
+        for (field, f) in fields {
+            let ty = f.ty;
+            let (read, write) = if let Some(bits) = f.bits {
+                bit_field_accessors(&mut node, structs, ty, f.offset, bits)
+            } else {
+                // the integer the field is loaded and stored as
+                let int = if ty.is_integer() {
+                    ty
+                } else if ty.is_ptr() {
+                    Type::U64
                 } else {
-                    unreachable!();
+                    Type::U8
+                };
+                let access = HirKind::FieldAccess(FieldAccess {
+                    ty: id.some(),
+                    field: field.clone(),
+                });
+                let cast = |ty| HirKind::Intrinsic(Intrinsic::Cast(ty));
+
+                let mut read = vec![node(access.clone())];
+                if int != ty {
+                    read.push(node(cast(Type::ptr_to(int))));
+                }
+                read.push(node(HirKind::Intrinsic(Intrinsic::Read(int))));
+                if ty == Type::BOOL {
+                    read.push(node(HirKind::Literal(IConst::Int(0))));
+                    read.push(node(HirKind::Intrinsic(Intrinsic::Ne)));
+                } else if int != ty {
+                    read.push(node(cast(ty)));
                 }
+
+                let mut write = vec![node(access)];
+                if int != ty {
+                    write.push(node(cast(Type::ptr_to(int))));
+                    write.push(node(HirKind::Intrinsic(Intrinsic::Swap)));
+                    write.push(node(cast(int)));
+                    write.push(node(HirKind::Intrinsic(Intrinsic::Swap)));
+                }
+                write.push(node(HirKind::Intrinsic(Intrinsic::Write(int))));
+                (read, write)
+            };
+
+            for (suffix, ins, outs, body) in [
+                ("@", vec![ptr], vec![ty], read),
+                ("!", vec![ty, ptr], vec![], write),
+            ] {
+                let proc = Proc {
+                    attrs: Attributes {
+                        inline: true,
+                        ..Default::default()
+                    },
+                    out_names: vec![None; outs.len()],
+                    ins,
+                    outs,
+                    span: body[0].span.clone(),
+                    body,
+                    vars: Default::default(),
+                    host: false,
+                };
+                res.push((format!("{}.{}{}", name, field, suffix), proc));
             }
-            proc_outs
-        } else {
-            Vec::new()
-        };
+        }
+    }
+    res
+}
 
-        (ins, outs)
+/// Bodies of `Struct.field@` and `Struct.field!` for a bit-field of type `ty`, going through the
+/// unsigned integer at `offset` it is packed in with shifts and masks
+fn bit_field_accessors(
+    node: &mut impl FnMut(HirKind) -> HirNode,
+    structs: &StructIndex,
+    ty: Type,
+    offset: usize,
+    bits: Bits,
+) -> (Vec<HirNode>, Vec<HirNode>) {
+    let (unit, unit_bits) = match ty.size(structs) {
+        1 => (Type::U8, 8),
+        2 => (Type::U16, 16),
+        4 => (Type::U32, 32),
+        _ => (Type::U64, 64),
+    };
+    let int = |k: u64| HirKind::Literal(IConst::Int(k));
+    let op = HirKind::Intrinsic;
+    let unit_ptr = [
+        op(Intrinsic::Cast(Type::U64)),
+        int(offset as u64),
+        op(Intrinsic::Add(None)),
+        op(Intrinsic::Cast(Type::ptr_to(unit))),
+    ];
+
+    let mut read = unit_ptr.to_vec();
+    read.push(op(Intrinsic::Read(unit)));
+    if ty.is_signed() {
+        // up to the top bit and back down, for the sign to be copied in
+        read.extend([
+            int((unit_bits - bits.shift - bits.width) as u64),
+            op(Intrinsic::Shl(None)),
+            op(Intrinsic::Cast(ty)),
+            int((unit_bits - bits.width) as u64),
+            op(Intrinsic::Shr(None)),
+        ]);
+    } else {
+        read.extend([
+            int(bits.shift as u64),
+            op(Intrinsic::Shr(None)),
+            int(bits.low_mask()),
+            op(Intrinsic::BitAnd),
+        ]);
+        if ty == Type::BOOL {
+            read.extend([int(0), op(Intrinsic::Ne)]);
+        }
+    }
+
+    let mut write = unit_ptr.to_vec();
+    write.push(op(Intrinsic::Swap));
+    if ty != unit {
+        write.push(op(Intrinsic::Cast(unit)));
     }
+    let others = !bits.mask() & (u64::MAX >> (64 - unit_bits));
+    write.extend([
+        int(bits.low_mask()),
+        op(Intrinsic::BitAnd),
+        int(bits.shift as u64),
+        op(Intrinsic::Shl(None)),
+        op(Intrinsic::Over),
+        op(Intrinsic::Read(unit)),
+        int(others),
+        op(Intrinsic::BitAnd),
+        op(Intrinsic::BitOr),
+        op(Intrinsic::Swap),
+        op(Intrinsic::Write(unit)),
+    ]);
+    (
+        read.into_iter().map(&mut *node).collect(),
+        write.into_iter().map(node).collect(),
+    )
+}
+
+/// Folds `n pick` and `n roll` into a single intrinsic carrying the literal depth
+fn fold_depths(body: Vec<HirNode>) -> Vec<HirNode> {
+    let mut res: Vec<HirNode> = Vec::with_capacity(body.len());
+    for mut node in body {
+        if let HirKind::Word(callee) = &node.hir {
+            if let Some(HirNode {
+                span,
+                hir: HirKind::Word(w),
+            }) = res.last()
+            {
+                if w == "call-like" {
+                    node = HirNode {
+                        span: span.clone().merge(node.span),
+                        hir: HirKind::CallLike(callee.clone()),
+                    };
+                    res.pop();
+                }
+            }
+        }
+        if let HirKind::Intrinsic(Intrinsic::Pick(depth @ None) | Intrinsic::Roll(depth @ None)) =
+            &mut node.hir
+        {
+            if let Some(HirNode {
+                span,
+                hir: HirKind::Literal(IConst::U64(n) | IConst::Int(n)),
+            }) = res.last()
+            {
+                *depth = (*n as usize).some();
+                node.span = span.clone().merge(node.span);
+                res.pop();
+            }
+        }
+        res.push(node)
+    }
+    res
+}
+
+#[test]
+fn test_struct_accessors() {
+    use crate::{
+        ast::parse_no_include, eval::eval, lexer::lex_string, lir, typecheck::Typechecker,
+        types::define_structs,
+    };
+    use somok::Either;
+
+    let src = "
+        struct Point do x: u32 y: i8 next: &>u64 ok: bool tag: char end
+        proc Point.tag@ &>Point : char do drop 'b' end
+        proc main : u64 do
+            var p : Point
+            var n : u64
+            7 n !u64
+            300 cast u32 p Point.x!
+            0 5 - cast i8 p Point.y!
+            n p Point.next!
+            true p Point.ok!
+            'a' p Point.tag!
+            p Point.x@ cast u64
+            p Point.y@ 10 + cast u64 +
+            p Point.next@ @u64 +
+            p Point.ok@ cast u64 +
+            p Point.tag@ cast u64 +
+        end";
+    let tokens = lex_string(src.to_string(), "test.rh".into()).unwrap();
+    let (structs, items) = parse_no_include(tokens)
+        .unwrap()
+        .into_iter()
+        .map(|i| (i.name().unwrap(), i))
+        .partition::<FnvHashMap<_, _>, _>(|(_, i)| matches!(i, ast::TopLevel::Struct(_)));
+    let session = Session::new(define_structs(structs), Default::default());
+    let hir = Walker::new(&session).walk_ast(items).unwrap();
+    let (procs, _) = Typechecker::typecheck_program(hir, &session).unwrap();
+    let ops = lir::Compiler::new(&session).compile(procs).unwrap().ops;
+    // the accessors are inlined, but the program's own `Point.tag@` wins over the generated one
+    let calls = ops
+        .iter()
+        .filter(|op| matches!(op, lir::Op::Call(_)))
+        .count();
+    assert_eq!(calls, 2);
+    assert!(matches!(eval(ops, &[]), Ok(Either::Left(411))));
+}
+
+#[test]
+fn test_bit_field_accessors() {
+    use crate::{
+        ast::parse_no_include, eval::eval, lexer::lex_string, lir, typecheck::Typechecker,
+        types::define_structs,
+    };
+    use somok::Either;
+
+    let src = "
+        struct Flags do mode: u8 : 3 on: bool : 1 level: i8 : 4 wide: u16 end
+        proc main : u64 do
+            var f : Flags
+            5 cast u8 f Flags.mode!
+            true f Flags.on!
+            0 3 - cast i8 f Flags.level!
+            f Flags.mode@ cast u64
+            f Flags.on@ cast u64 +
+            f Flags.level@ 10 + cast u64 +
+            f cast &>u8 @u8 cast u64 +
+            f bind { level } do level 20 + cast u64 + end
+        end";
+    let tokens = lex_string(src.to_string(), "test.rh".into()).unwrap();
+    let (structs, items) = parse_no_include(tokens)
+        .unwrap()
+        .into_iter()
+        .map(|i| (i.name().unwrap(), i))
+        .partition::<FnvHashMap<_, _>, _>(|(_, i)| matches!(i, ast::TopLevel::Struct(_)));
+    let session = Session::new(define_structs(structs), Default::default());
+    let hir = Walker::new(&session).walk_ast(items).unwrap();
+    let (procs, _) = Typechecker::typecheck_program(hir, &session).unwrap();
+    let ops = lir::Compiler::new(&session).compile(procs).unwrap().ops;
+    // 5, 1 and -3 packed into one byte as 0b1101_1_101
+    assert!(matches!(eval(ops, &[]), Ok(Either::Left(251))));
+}
+
+#[test]
+fn test_alias_cycle() {
+    use crate::{ast::parse_no_include, lexer::lex_string};
+
+    let src = "
+        alias a = b
+        alias b = a
+        proc main : u64 do 0 end";
+    let tokens = lex_string(src.to_string(), "test.rh".into()).unwrap();
+    let items = parse_no_include(tokens)
+        .unwrap()
+        .into_iter()
+        .map(|i| (i.name().unwrap(), i))
+        .collect();
+    let session = Session::default();
+    let res = Walker::new(&session).walk_ast(items);
+    assert!(matches!(res, Err(Error::AliasCycle(spans)) if spans.len() == 2));
 }
@@ -1,6 +1,8 @@
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum IConst {
     Bool(bool),
+    /// An integer literal without a suffix, the typechecker infers its type
+    Int(u64),
     U64(u64),
     I64(i64),
     Char(char),
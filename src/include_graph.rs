@@ -0,0 +1,160 @@
+use crate::{
+    ast::{parse_no_include, TopLevel},
+    lexer::lex,
+    resolver::include_path,
+    Result,
+};
+use fnv::FnvHasher;
+use somok::Somok;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    hash::Hasher,
+    path::{Path, PathBuf},
+};
+
+/// Files reachable from a root through `include`s, with the hash of their contents when read.
+/// Paths are canonical, so a file included along different relative paths is one node.
+#[derive(Debug, Clone, Default)]
+pub struct IncludeGraph {
+    root: PathBuf,
+    files: BTreeMap<PathBuf, FileNode>,
+}
+
+#[derive(Debug, Clone)]
+struct FileNode {
+    hash: u64,
+    includes: Vec<PathBuf>,
+}
+
+impl IncludeGraph {
+    pub fn new(root: &Path) -> Result<Self> {
+        let root = root.canonicalize()?;
+        let mut graph = Self {
+            root: root.clone(),
+            files: BTreeMap::new(),
+        };
+        let mut queue = vec![root];
+        while let Some(file) = queue.pop() {
+            if graph.files.contains_key(&file) {
+                continue;
+            }
+            let hash = hash_file(&file)?;
+            let includes = parse_no_include(lex(file.clone())?)?
+                .iter()
+                .filter_map(|item| match item {
+                    TopLevel::Include(i) => {
+                        let path = include_path(&file, i.path());
+                        path.canonicalize().unwrap_or(path).some()
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            queue.extend(includes.iter().cloned());
+            graph.files.insert(file, FileNode { hash, includes });
+        }
+        graph.okay()
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = &Path> {
+        self.files.keys().map(PathBuf::as_path)
+    }
+
+    /// Files `file` includes directly
+    pub fn includes(&self, file: &Path) -> &[PathBuf] {
+        self.files
+            .get(file)
+            .map_or(&[], |node| node.includes.as_slice())
+    }
+
+    /// Files that include `file` directly
+    pub fn included_by(&self, file: &Path) -> Vec<&Path> {
+        self.files
+            .iter()
+            .filter(|(_, node)| node.includes.iter().any(|i| i == file))
+            .map(|(path, _)| path.as_path())
+            .collect()
+    }
+
+    /// `file` and everything it includes transitively
+    pub fn dependencies(&self, file: &Path) -> BTreeSet<&Path> {
+        let mut seen = BTreeSet::new();
+        let mut queue = vec![file];
+        while let Some(file) = queue.pop() {
+            if let Some((path, node)) = self.files.get_key_value(file) {
+                if seen.insert(path.as_path()) {
+                    queue.extend(node.includes.iter().map(PathBuf::as_path));
+                }
+            }
+        }
+        seen
+    }
+
+    /// Cache key for the output of building `file`, changes whenever any of its dependencies do
+    pub fn key(&self, file: &Path) -> u64 {
+        let mut hasher = FnvHasher::default();
+        for dep in self.dependencies(file) {
+            hasher.write(dep.as_os_str().to_string_lossy().as_bytes());
+            hasher.write_u64(self.files[dep].hash);
+        }
+        hasher.finish()
+    }
+
+    /// Files that changed on disk since the graph was built, and every file including them
+    pub fn stale(&self) -> BTreeSet<&Path> {
+        let changed = self
+            .files
+            .iter()
+            .filter(|(path, node)| hash_file(path).ok() != Some(node.hash))
+            .map(|(path, _)| path.as_path());
+        let mut stale = BTreeSet::new();
+        let mut queue = changed.collect::<Vec<_>>();
+        while let Some(file) = queue.pop() {
+            if stale.insert(file) {
+                queue.extend(self.included_by(file));
+            }
+        }
+        stale
+    }
+
+    /// Whether the build rooted at this graph must be redone
+    pub fn is_stale(&self) -> bool {
+        self.stale().contains(self.root.as_path())
+    }
+}
+
+fn hash_file(path: &Path) -> Result<u64> {
+    let mut hasher = FnvHasher::default();
+    hasher.write(&std::fs::read(path)?);
+    hasher.finish().okay()
+}
+
+#[test]
+fn test_include_graph() {
+    let dir = std::env::temp_dir().join(format!("rotth-include-graph-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("lib")).unwrap();
+    std::fs::write(
+        dir.join("main.rh"),
+        "include \"lib/a.rh\"\nproc main do end",
+    )
+    .unwrap();
+    std::fs::write(dir.join("lib/a.rh"), "include \"b.rh\"\nproc a do end").unwrap();
+    std::fs::write(dir.join("lib/b.rh"), "proc b do end").unwrap();
+
+    let graph = IncludeGraph::new(&dir.join("main.rh")).unwrap();
+    let root = graph.root().to_path_buf();
+    assert_eq!(graph.dependencies(&root).len(), 3);
+    assert!(!graph.is_stale());
+    let key = graph.key(&root);
+
+    std::fs::write(dir.join("lib/b.rh"), "proc b do 1 drop end").unwrap();
+    assert_eq!(graph.stale().len(), 3);
+    assert!(graph.is_stale());
+    let fresh = IncludeGraph::new(&dir.join("main.rh")).unwrap();
+    assert_ne!(fresh.key(&root), key);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
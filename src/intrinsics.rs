@@ -0,0 +1,734 @@
+use crate::{
+    hir::{Endian, Intrinsic, PrintFormat},
+    lir::Op,
+    types::Type,
+};
+
+/// Everything the compiler and tooling know about one intrinsic word
+#[derive(Debug)]
+pub struct IntrinsicInfo {
+    /// Spelling in source
+    pub word: &'static str,
+    pub intrinsic: Intrinsic,
+    /// Number of stack items consumed, checked before the intrinsic's own typechecking
+    pub ins: usize,
+    /// Stack effect, e.g. `a b -- a+b`
+    pub signature: &'static str,
+    /// The op it lowers to, `None` if lowering needs more than a single op
+    pub op: Option<Op>,
+    pub doc: &'static str,
+}
+
+const fn info(
+    word: &'static str,
+    intrinsic: Intrinsic,
+    ins: usize,
+    signature: &'static str,
+    op: Option<Op>,
+    doc: &'static str,
+) -> IntrinsicInfo {
+    IntrinsicInfo {
+        word,
+        intrinsic,
+        ins,
+        signature,
+        op,
+        doc,
+    }
+}
+
+use Intrinsic as I;
+
+pub static INTRINSICS: &[IntrinsicInfo] = &[
+    info(
+        "drop",
+        I::Drop,
+        1,
+        "a --",
+        Some(Op::Drop),
+        "Discards the top of the stack",
+    ),
+    info(
+        "dup",
+        I::Dup,
+        1,
+        "a -- a a",
+        Some(Op::Dup),
+        "Duplicates the top of the stack",
+    ),
+    info(
+        "swap",
+        I::Swap,
+        2,
+        "a b -- b a",
+        Some(Op::Swap),
+        "Swaps the two topmost items",
+    ),
+    info(
+        "over",
+        I::Over,
+        2,
+        "a b -- a b a",
+        Some(Op::Over),
+        "Copies the second item to the top",
+    ),
+    info(
+        "pick",
+        I::Pick(None),
+        0,
+        "x_n .. x_0 n -- x_n .. x_0 x_n",
+        None,
+        "Copies the item `n` deep to the top, `n` must be a literal",
+    ),
+    info(
+        "roll",
+        I::Roll(None),
+        0,
+        "x_n .. x_0 n -- x_n-1 .. x_0 x_n",
+        None,
+        "Moves the item `n` deep to the top, `n` must be a literal",
+    ),
+    info(
+        "@u64",
+        I::ReadU64,
+        1,
+        "ptr -- u64",
+        Some(Op::ReadU64),
+        "Loads a u64",
+    ),
+    info(
+        "@u8",
+        I::ReadU8,
+        1,
+        "ptr -- u8",
+        Some(Op::ReadU8),
+        "Loads a u8",
+    ),
+    info(
+        "!u64",
+        I::WriteU64,
+        2,
+        "u64 ptr --",
+        Some(Op::WriteU64),
+        "Stores a u64",
+    ),
+    info(
+        "!u8",
+        I::WriteU8,
+        2,
+        "u8 ptr --",
+        Some(Op::WriteU8),
+        "Stores a u8",
+    ),
+    info(
+        "@u32",
+        I::Read(Type::U32),
+        1,
+        "ptr -- u32",
+        Some(Op::ReadU32),
+        "Loads a u32",
+    ),
+    info(
+        "@u16",
+        I::Read(Type::U16),
+        1,
+        "ptr -- u16",
+        Some(Op::ReadU16),
+        "Loads a u16",
+    ),
+    info(
+        "@i64",
+        I::Read(Type::I64),
+        1,
+        "ptr -- i64",
+        Some(Op::ReadU64),
+        "Loads an i64",
+    ),
+    info(
+        "@i32",
+        I::Read(Type::I32),
+        1,
+        "ptr -- i32",
+        None,
+        "Loads an i32, sign extended",
+    ),
+    info(
+        "@i16",
+        I::Read(Type::I16),
+        1,
+        "ptr -- i16",
+        None,
+        "Loads an i16, sign extended",
+    ),
+    info(
+        "@i8",
+        I::Read(Type::I8),
+        1,
+        "ptr -- i8",
+        None,
+        "Loads an i8, sign extended",
+    ),
+    info(
+        "!u32",
+        I::Write(Type::U32),
+        2,
+        "u32 ptr --",
+        Some(Op::WriteU32),
+        "Stores a u32",
+    ),
+    info(
+        "!u16",
+        I::Write(Type::U16),
+        2,
+        "u16 ptr --",
+        Some(Op::WriteU16),
+        "Stores a u16",
+    ),
+    info(
+        "!i64",
+        I::Write(Type::I64),
+        2,
+        "i64 ptr --",
+        Some(Op::WriteU64),
+        "Stores an i64",
+    ),
+    info(
+        "!i32",
+        I::Write(Type::I32),
+        2,
+        "i32 ptr --",
+        Some(Op::WriteU32),
+        "Stores an i32",
+    ),
+    info(
+        "!i16",
+        I::Write(Type::I16),
+        2,
+        "i16 ptr --",
+        Some(Op::WriteU16),
+        "Stores an i16",
+    ),
+    info(
+        "!i8",
+        I::Write(Type::I8),
+        2,
+        "i8 ptr --",
+        Some(Op::WriteU8),
+        "Stores an i8",
+    ),
+    info(
+        "@u16-le",
+        I::ReadEndian(Type::U16, Endian::Little),
+        1,
+        "ptr -- u16",
+        None,
+        "Loads a little endian u16",
+    ),
+    info(
+        "@u16-be",
+        I::ReadEndian(Type::U16, Endian::Big),
+        1,
+        "ptr -- u16",
+        None,
+        "Loads a big endian u16",
+    ),
+    info(
+        "@u32-le",
+        I::ReadEndian(Type::U32, Endian::Little),
+        1,
+        "ptr -- u32",
+        None,
+        "Loads a little endian u32",
+    ),
+    info(
+        "@u32-be",
+        I::ReadEndian(Type::U32, Endian::Big),
+        1,
+        "ptr -- u32",
+        None,
+        "Loads a big endian u32",
+    ),
+    info(
+        "@u64-le",
+        I::ReadEndian(Type::U64, Endian::Little),
+        1,
+        "ptr -- u64",
+        None,
+        "Loads a little endian u64",
+    ),
+    info(
+        "@u64-be",
+        I::ReadEndian(Type::U64, Endian::Big),
+        1,
+        "ptr -- u64",
+        None,
+        "Loads a big endian u64",
+    ),
+    info(
+        "!u16-le",
+        I::WriteEndian(Type::U16, Endian::Little),
+        2,
+        "u16 ptr --",
+        None,
+        "Stores a little endian u16",
+    ),
+    info(
+        "!u16-be",
+        I::WriteEndian(Type::U16, Endian::Big),
+        2,
+        "u16 ptr --",
+        None,
+        "Stores a big endian u16",
+    ),
+    info(
+        "!u32-le",
+        I::WriteEndian(Type::U32, Endian::Little),
+        2,
+        "u32 ptr --",
+        None,
+        "Stores a little endian u32",
+    ),
+    info(
+        "!u32-be",
+        I::WriteEndian(Type::U32, Endian::Big),
+        2,
+        "u32 ptr --",
+        None,
+        "Stores a big endian u32",
+    ),
+    info(
+        "!u64-le",
+        I::WriteEndian(Type::U64, Endian::Little),
+        2,
+        "u64 ptr --",
+        None,
+        "Stores a little endian u64",
+    ),
+    info(
+        "!u64-be",
+        I::WriteEndian(Type::U64, Endian::Big),
+        2,
+        "u64 ptr --",
+        None,
+        "Stores a big endian u64",
+    ),
+    info(
+        "&?&",
+        I::CompStop,
+        0,
+        "--",
+        None,
+        "Stops compilation and reports the stack",
+    ),
+    info(
+        "&?",
+        I::Dump,
+        0,
+        "--",
+        Some(Op::Dump),
+        "Prints the stack in the evaluator",
+    ),
+    info(
+        "print",
+        I::Print(PrintFormat::Unsigned),
+        1,
+        "u64 --",
+        Some(Op::Print(PrintFormat::Unsigned)),
+        "Prints an unsigned number and a newline",
+    ),
+    info(
+        "print-signed",
+        I::Print(PrintFormat::Signed),
+        1,
+        "i64 --",
+        Some(Op::Print(PrintFormat::Signed)),
+        "Prints a signed number and a newline",
+    ),
+    info(
+        "print-hex",
+        I::Print(PrintFormat::Hex),
+        1,
+        "u64 --",
+        Some(Op::Print(PrintFormat::Hex)),
+        "Prints a number in hex and a newline",
+    ),
+    info(
+        "print-pad",
+        I::PrintPadded(PrintFormat::Unsigned),
+        2,
+        "u64 width --",
+        Some(Op::PrintPadded(PrintFormat::Unsigned)),
+        "Prints an unsigned number zero-padded to `width` digits",
+    ),
+    info(
+        "print-signed-pad",
+        I::PrintPadded(PrintFormat::Signed),
+        2,
+        "i64 width --",
+        Some(Op::PrintPadded(PrintFormat::Signed)),
+        "Prints a signed number zero-padded to `width` digits",
+    ),
+    info(
+        "print-hex-pad",
+        I::PrintPadded(PrintFormat::Hex),
+        2,
+        "u64 width --",
+        Some(Op::PrintPadded(PrintFormat::Hex)),
+        "Prints a number in hex zero-padded to `width` digits",
+    ),
+    info(
+        "hash-str",
+        I::HashStr,
+        2,
+        "len ptr -- hash",
+        Some(Op::HashStr),
+        "FNV-1a hash of a string, computed at compile time for literals. Distinct strings can collide",
+    ),
+    info(
+        "copy16",
+        I::Copy16,
+        2,
+        "src dst --",
+        Some(Op::Copy16),
+        "Copies 16 bytes, the two may overlap",
+    ),
+    info(
+        "cmp16",
+        I::Cmp16,
+        2,
+        "a b -- mask",
+        Some(Op::Cmp16),
+        "Compares 16 bytes, bit `i` of the mask is set if byte `i` is the same in both",
+    ),
+    info(
+        "splat8x16",
+        I::Splat16,
+        2,
+        "byte dst --",
+        Some(Op::Splat16),
+        "Fills 16 bytes with a byte",
+    ),
+    info(
+        "find-byte",
+        I::FindByte,
+        3,
+        "ptr len byte -- idx found",
+        Some(Op::FindByte),
+        "Index of the first `byte` among `len` bytes at `ptr`, or `len` and false if there is none",
+    ),
+    info(
+        "syscall0",
+        I::Syscall0,
+        1,
+        "nr -- ret",
+        Some(Op::Syscall0),
+        "Performs a syscall without arguments",
+    ),
+    info(
+        "syscall1",
+        I::Syscall1,
+        2,
+        "a nr -- ret",
+        Some(Op::Syscall1),
+        "Performs a syscall with 1 argument",
+    ),
+    info(
+        "syscall2",
+        I::Syscall2,
+        3,
+        "a b nr -- ret",
+        Some(Op::Syscall2),
+        "Performs a syscall with 2 arguments",
+    ),
+    info(
+        "syscall3",
+        I::Syscall3,
+        4,
+        "a b c nr -- ret",
+        Some(Op::Syscall3),
+        "Performs a syscall with 3 arguments",
+    ),
+    info(
+        "syscall4",
+        I::Syscall4,
+        5,
+        "a b c d nr -- ret",
+        Some(Op::Syscall4),
+        "Performs a syscall with 4 arguments",
+    ),
+    info(
+        "syscall5",
+        I::Syscall5,
+        6,
+        "a b c d e nr -- ret",
+        Some(Op::Syscall5),
+        "Performs a syscall with 5 arguments",
+    ),
+    info(
+        "syscall6",
+        I::Syscall6,
+        7,
+        "a b c d e f nr -- ret",
+        Some(Op::Syscall6),
+        "Performs a syscall with 6 arguments",
+    ),
+    info(
+        "syscall0!",
+        I::CheckedSyscall(0),
+        1,
+        "nr -- ret err?",
+        None,
+        "Performs a syscall without arguments, splitting off the errno",
+    ),
+    info(
+        "syscall1!",
+        I::CheckedSyscall(1),
+        2,
+        "a nr -- ret err?",
+        None,
+        "Performs a syscall with 1 argument, splitting off the errno",
+    ),
+    info(
+        "syscall2!",
+        I::CheckedSyscall(2),
+        3,
+        "a b nr -- ret err?",
+        None,
+        "Performs a syscall with 2 arguments, splitting off the errno",
+    ),
+    info(
+        "syscall3!",
+        I::CheckedSyscall(3),
+        4,
+        "a b c nr -- ret err?",
+        None,
+        "Performs a syscall with 3 arguments, splitting off the errno",
+    ),
+    info(
+        "syscall4!",
+        I::CheckedSyscall(4),
+        5,
+        "a b c d nr -- ret err?",
+        None,
+        "Performs a syscall with 4 arguments, splitting off the errno",
+    ),
+    info(
+        "syscall5!",
+        I::CheckedSyscall(5),
+        6,
+        "a b c d e nr -- ret err?",
+        None,
+        "Performs a syscall with 5 arguments, splitting off the errno",
+    ),
+    info(
+        "syscall6!",
+        I::CheckedSyscall(6),
+        7,
+        "a b c d e f nr -- ret err?",
+        None,
+        "Performs a syscall with 6 arguments, splitting off the errno",
+    ),
+    info(
+        "argc",
+        I::Argc,
+        0,
+        "-- u64",
+        Some(Op::Argc),
+        "Number of command line arguments",
+    ),
+    info(
+        "argv",
+        I::Argv,
+        0,
+        "-- &>&>char",
+        Some(Op::Argv),
+        "Command line arguments",
+    ),
+    info(
+        "argv-at",
+        I::ArgvAt,
+        1,
+        "n -- ptr len",
+        None,
+        "The `n`th command line argument without its nul, `n` must be below `argc`",
+    ),
+    info(
+        "+",
+        I::Add(None),
+        2,
+        "a b -- a+b",
+        Some(Op::Add),
+        "Adds two numbers",
+    ),
+    info(
+        "-",
+        I::Sub(None),
+        2,
+        "a b -- a-b",
+        Some(Op::Sub),
+        "Subtracts the top from the second",
+    ),
+    info(
+        "*",
+        I::Mul(None),
+        2,
+        "a b -- a*b",
+        Some(Op::Mul),
+        "Multiplies two numbers",
+    ),
+    info(
+        "divmod",
+        I::Divmod(None),
+        2,
+        "a b -- a/b a%b",
+        None,
+        "Quotient and remainder",
+    ),
+    info(
+        "band",
+        I::BitAnd,
+        2,
+        "a b -- a&b",
+        Some(Op::BitAnd),
+        "Bitwise and",
+    ),
+    info(
+        "bor",
+        I::BitOr,
+        2,
+        "a b -- a|b",
+        Some(Op::BitOr),
+        "Bitwise or",
+    ),
+    info(
+        "bxor",
+        I::BitXor,
+        2,
+        "a b -- a^b",
+        Some(Op::BitXor),
+        "Bitwise exclusive or",
+    ),
+    info(
+        "bnot",
+        I::BitNot(None),
+        1,
+        "a -- ~a",
+        Some(Op::BitNot),
+        "Flips every bit",
+    ),
+    info(
+        "shl",
+        I::Shl(None),
+        2,
+        "a n -- a<<n",
+        Some(Op::Shl),
+        "Shifts left by `n` bits, only the low 6 bits of `n` count",
+    ),
+    info(
+        "shr",
+        I::Shr(None),
+        2,
+        "a n -- a>>n",
+        None,
+        "Shifts right by `n` bits, copying the sign bit in for signed integers",
+    ),
+    info(
+        "and",
+        I::And,
+        2,
+        "bool bool -- bool",
+        Some(Op::BitAnd),
+        "Logical and, both operands are evaluated",
+    ),
+    info(
+        "or",
+        I::Or,
+        2,
+        "bool bool -- bool",
+        Some(Op::BitOr),
+        "Logical or, both operands are evaluated",
+    ),
+    info("not", I::Not, 1, "bool -- bool", None, "Logical negation"),
+    info("=", I::Eq, 2, "a b -- bool", Some(Op::Eq), "Equality"),
+    info("!=", I::Ne, 2, "a b -- bool", Some(Op::Ne), "Inequality"),
+    info("<", I::Lt(None), 2, "a b -- bool", None, "Less than"),
+    info(
+        "<=",
+        I::Le(None),
+        2,
+        "a b -- bool",
+        None,
+        "Less than or equal",
+    ),
+    info(">", I::Gt(None), 2, "a b -- bool", None, "Greater than"),
+    info(
+        ">=",
+        I::Ge(None),
+        2,
+        "a b -- bool",
+        None,
+        "Greater than or equal",
+    ),
+];
+
+pub const HASH_OFFSET: u64 = 0xcbf29ce484222325;
+pub const HASH_PRIME: u64 = 0x100000001b3;
+
+/// 64 bit FNV-1a, what `hash-str` computes both at compile time and at runtime
+pub fn hash_str(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(HASH_OFFSET, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(HASH_PRIME)
+    })
+}
+
+/// The intrinsic spelled `word`
+pub fn lookup(word: &str) -> Option<&'static IntrinsicInfo> {
+    INTRINSICS.iter().find(|i| i.word == word)
+}
+
+/// The registry entry of `intrinsic`, `None` only for casts
+pub fn info_of(intrinsic: &Intrinsic) -> Option<&'static IntrinsicInfo> {
+    INTRINSICS.iter().find(|i| match (&i.intrinsic, intrinsic) {
+        // depths are folded in after parsing
+        (I::Pick(_), I::Pick(_)) | (I::Roll(_), I::Roll(_)) => true,
+        // and operand types by the typechecker
+        (I::Add(_), I::Add(_)) | (I::Sub(_), I::Sub(_)) | (I::Mul(_), I::Mul(_)) => true,
+        (I::BitNot(_), I::BitNot(_)) | (I::Shl(_), I::Shl(_)) | (I::Shr(_), I::Shr(_)) => true,
+        (I::Divmod(_), I::Divmod(_)) | (I::Lt(_), I::Lt(_)) | (I::Le(_), I::Le(_)) => true,
+        (I::Gt(_), I::Gt(_)) | (I::Ge(_), I::Ge(_)) => true,
+        (a, b) => a == b,
+    })
+}
+
+/// Intrinsic words within a small edit distance of `word`, for "did you mean" hints
+pub fn similar(word: &str) -> Vec<&'static str> {
+    INTRINSICS
+        .iter()
+        .map(|i| i.word)
+        .filter(|w| edit_distance(w, word) <= 1.max(word.len() / 4))
+        .collect()
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let cur = row[j + 1];
+            row[j + 1] = (prev + (ca != cb) as usize).min(row[j] + 1).min(cur + 1);
+            prev = cur;
+        }
+    }
+    row[b.len()]
+}
+
+#[test]
+fn test_registry() {
+    for i in INTRINSICS {
+        assert_eq!(lookup(i.word).map(|l| l.word), Some(i.word));
+        assert_eq!(info_of(&i.intrinsic).map(|l| l.word), Some(i.word));
+    }
+    assert_eq!(similar("dupp"), ["dup"]);
+}
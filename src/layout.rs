@@ -0,0 +1,185 @@
+use crate::types::{StructIndex, Type, ValueType};
+use somok::Somok;
+
+/// Size and alignment of a type, following the C rules for x86-64
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    pub size: usize,
+    pub align: usize,
+}
+
+impl Layout {
+    pub const PTR: Self = Self::scalar(8);
+
+    pub const fn scalar(size: usize) -> Self {
+        Self { size, align: size }
+    }
+
+    pub fn of(ty: Type, structs: &StructIndex) -> Self {
+        if ty.is_ptr() {
+            return Self::PTR;
+        }
+        match ty.value_type {
+            ValueType::Primitive(p) => Self::scalar(p.size()),
+            ValueType::Any => unreachable!("Naked any type"),
+            ValueType::Var(_) => unreachable!("Type variable outside a signature"),
+            ValueType::Proc(_) => Self::PTR,
+            ValueType::Struct(s) => structs[s].layout,
+        }
+    }
+
+    /// Distance between consecutive elements of this type in an array
+    pub fn stride(&self) -> usize {
+        round_up(self.size, self.align)
+    }
+
+    pub fn array(&self, len: usize) -> Self {
+        Self {
+            size: self.stride() * len,
+            align: self.align,
+        }
+    }
+
+    pub fn union(members: impl IntoIterator<Item = Self>) -> Self {
+        let (size, align) = members.into_iter().fold((0, 1), |(size, align), m| {
+            (size.max(m.size), align.max(m.align))
+        });
+        Self {
+            size: round_up(size, align),
+            align,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StructLayout {
+    pub layout: Layout,
+    /// Field offsets, in declaration order
+    pub offsets: Vec<usize>,
+    /// Where each bit-field sits in the storage unit at its offset, `None` for the other fields
+    pub bits: Vec<Option<Bits>>,
+}
+
+/// Bits of a bit-field in its storage unit, an integer of the field's type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bits {
+    pub shift: u32,
+    pub width: u32,
+}
+
+impl Bits {
+    /// The field's bits, not shifted into place
+    pub fn low_mask(&self) -> u64 {
+        u64::MAX >> (64 - self.width)
+    }
+
+    pub fn mask(&self) -> u64 {
+        self.low_mask() << self.shift
+    }
+}
+
+/// Lays fields out in declaration order, padding each to its alignment unless `packed`.
+/// Fields with a bit width share a storage unit with the bit-fields right before them if their
+/// units are the same size and there are enough bits left, the lowest bits going first.
+pub fn struct_layout(
+    fields: impl IntoIterator<Item = (Layout, Option<u32>)>,
+    packed: bool,
+) -> StructLayout {
+    let mut offsets = Vec::new();
+    let mut bits = Vec::new();
+    let mut size = 0;
+    let mut align = 1;
+    // offset, size and bits used of the unit the last bit-field went into
+    let mut unit = None::<(usize, usize, u32)>;
+    for (field, width) in fields {
+        let field_align = if packed { 1 } else { field.align };
+        align = align.max(field_align);
+        match (width, unit) {
+            (Some(width), Some((offset, unit_size, used)))
+                if unit_size == field.size && used + width <= unit_size as u32 * 8 =>
+            {
+                offsets.push(offset);
+                bits.push(Bits { shift: used, width }.some());
+                unit = (offset, unit_size, used + width).some();
+                continue;
+            }
+            _ => (),
+        }
+        size = round_up(size, field_align);
+        offsets.push(size);
+        bits.push(width.map(|width| Bits { shift: 0, width }));
+        unit = width.map(|width| (size, field.size, width));
+        size += field.size;
+    }
+    StructLayout {
+        layout: Layout {
+            size: round_up(size, align),
+            align,
+        },
+        offsets,
+        bits,
+    }
+}
+
+fn round_up(n: usize, align: usize) -> usize {
+    (n + align - 1) & !(align - 1)
+}
+
+#[test]
+fn test_struct_layout() {
+    let fields = [Layout::scalar(1), Layout::scalar(8), Layout::scalar(2)].map(|l| (l, None));
+    assert_eq!(
+        struct_layout(fields, false),
+        StructLayout {
+            layout: Layout { size: 24, align: 8 },
+            offsets: vec![0, 8, 16],
+            bits: vec![None; 3],
+        }
+    );
+    assert_eq!(
+        struct_layout(fields, true),
+        StructLayout {
+            layout: Layout { size: 11, align: 1 },
+            offsets: vec![0, 1, 9],
+            bits: vec![None; 3],
+        }
+    );
+}
+
+#[test]
+fn test_bit_fields() {
+    let (byte, half) = (Layout::scalar(1), Layout::scalar(2));
+    let fields = [
+        (byte, Some(3)),
+        (byte, Some(5)),
+        (byte, Some(1)),
+        (half, Some(12)),
+        (half, Some(4)),
+        (half, Some(1)),
+        (byte, None),
+    ];
+    let StructLayout {
+        layout,
+        offsets,
+        bits,
+    } = struct_layout(fields, false);
+    assert_eq!(layout, Layout { size: 8, align: 2 });
+    assert_eq!(offsets, [0, 0, 1, 2, 2, 4, 6]);
+    let bits = bits
+        .into_iter()
+        .map(|b| b.map(|b| (b.shift, b.width)))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        bits,
+        [
+            Some((0, 3)),
+            Some((3, 5)),
+            Some((0, 1)),
+            Some((0, 12)),
+            Some((12, 4)),
+            Some((0, 1)),
+            None
+        ]
+    );
+    assert_eq!(Bits { shift: 3, width: 5 }.mask(), 0xf8);
+}
@@ -1,21 +1,43 @@
 use std::{io::Read, path::PathBuf};
 
-use crate::{span::Span, Error, Result};
+use crate::{
+    span::{LineIndex, Span},
+    Error, Result,
+};
 use chumsky::{prelude::*, text::Character, Error as CError, Stream};
 use somok::Somok;
 
+mod fast;
+
+/// Sources at least this big are lexed by `fast::lex`
+const FAST_LEX_BYTES: usize = 64 * 1024;
+
 #[derive(Clone, Hash, PartialEq, Eq)]
 pub enum Token {
     Bool(bool),
     Word(String),
     Str(String),
     Char(char),
+    /// `'a`, a type variable of a proc signature
+    TypeVar(char),
     KeyWord(KeyWord),
     Num(String),
     Ignore,
     SigSep,
     Ptr,
     FieldAccess,
+    Attribute(String, Option<String>),
+    OpenBrace,
+    CloseBrace,
+    /// `;; text`, trivia kept for tools that want the comments of items
+    DocComment(String),
+}
+
+impl Token {
+    /// Whether the parser skips the token, see `lex_with_trivia`
+    pub fn is_trivia(&self) -> bool {
+        matches!(self, Self::DocComment(_))
+    }
 }
 
 impl std::fmt::Debug for Token {
@@ -25,12 +47,18 @@ impl std::fmt::Debug for Token {
             Self::Word(word) => write!(f, "{}", word),
             Self::Str(str) => write!(f, "{:?}", str),
             Self::Char(c) => write!(f, "{:?}", c),
+            Self::TypeVar(c) => write!(f, "'{}", c),
             Self::KeyWord(keyword) => keyword.fmt(f),
             Self::Num(num) => write!(f, "{}", num),
             Self::Ignore => write!(f, "_"),
             Self::SigSep => write!(f, ":"),
             Self::Ptr => write!(f, "&>"),
             Self::FieldAccess => write!(f, "->"),
+            Self::Attribute(name, Some(arg)) => write!(f, "#[{}({})]", name, arg),
+            Self::Attribute(name, None) => write!(f, "#[{}]", name),
+            Self::OpenBrace => write!(f, "{{"),
+            Self::CloseBrace => write!(f, "}}"),
+            Self::DocComment(text) => write!(f, ";;{}", text),
         }
     }
 }
@@ -45,6 +73,8 @@ impl std::fmt::Display for Token {
 pub enum KeyWord {
     Include,
     Return,
+    Break,
+    Continue,
     Cond,
     If,
     Else,
@@ -54,9 +84,15 @@ pub enum KeyWord {
     Bind,
     Const,
     Mem,
+    Table,
     Var,
     Struct,
     Cast,
+    Dip,
+    Keep,
+    Defer,
+    Extern,
+    Alias,
     End,
 }
 
@@ -66,9 +102,77 @@ impl std::fmt::Display for KeyWord {
     }
 }
 
+const ALLOWED_NON_ALPHA: &[u8; 24] = b"()[]<>|\\/!@#$%^&*-=+_?.,";
+
+fn keyword(word: &str) -> Option<KeyWord> {
+    match word {
+        "include" => KeyWord::Include,
+        "return" => KeyWord::Return,
+        "break" => KeyWord::Break,
+        "continue" => KeyWord::Continue,
+        "cond" => KeyWord::Cond,
+        "if" => KeyWord::If,
+        "else" => KeyWord::Else,
+        "proc" => KeyWord::Proc,
+        "while" => KeyWord::While,
+        "do" => KeyWord::Do,
+        "bind" => KeyWord::Bind,
+        "const" => KeyWord::Const,
+        "mem" => KeyWord::Mem,
+        "table" => KeyWord::Table,
+        "var" => KeyWord::Var,
+        "struct" => KeyWord::Struct,
+        "cast" => KeyWord::Cast,
+        "dip" => KeyWord::Dip,
+        "keep" => KeyWord::Keep,
+        "defer" => KeyWord::Defer,
+        "extern" => KeyWord::Extern,
+        "alias" => KeyWord::Alias,
+        "end" => KeyWord::End,
+        _ => return None,
+    }
+    .some()
+}
+
+/// Words kept for keywords to come, which can't name anything so that programs using them don't
+/// break when they become keywords
+pub const RESERVED: &[&str] = &["enum", "macro", "union"];
+
+/// The char `\c` stands for in chars and strings, unless it is `\x` or `\u`, which take digits
+fn escape(c: char) -> Option<char> {
+    match c {
+        'n' => '\n'.some(),
+        'r' => '\r'.some(),
+        't' => '\t'.some(),
+        '0' => '\0'.some(),
+        '\\' | '\'' | '"' => c.some(),
+        _ => None,
+    }
+}
+
+/// The char of the escape `\c`, with the hex `digits` of `\xNN` and `\u{...}`
+fn escape_sequence(c: char, digits: &str) -> std::result::Result<char, String> {
+    match c {
+        'x' if digits.len() == 2 => match u8::from_str_radix(digits, 16).unwrap() {
+            b @ 0..=0x7f => (b as char).okay(),
+            _ => "`\\x` escapes only go up to `\\x7f`, use `\\u{...}` for other chars"
+                .to_string()
+                .error(),
+        },
+        'x' => "`\\x` takes two hex digits".to_string().error(),
+        'u' if !digits.is_empty() => u32::from_str_radix(digits, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or_else(|| format!("`\\u{{{}}}` is not a unicode char", digits)),
+        'u' => "`\\u` takes up to six hex digits in braces"
+            .to_string()
+            .error(),
+        c => escape(c).ok_or_else(|| format!("Unknown escape `\\{}`", c)),
+    }
+}
+
 pub fn word_parser<C: Character, E: CError<C>>(
 ) -> impl Parser<C, C::Collection, Error = E> + Copy + Clone {
-    const ALLOWED_NON_ALPHA: &[u8; 26] = b"(){}[]<>|\\/!@#$%^&*-=+_?.,";
     filter(|c: &C| {
         c.to_char().is_ascii_alphabetic() || ALLOWED_NON_ALPHA.contains(&(c.to_char() as u8))
     })
@@ -85,50 +189,99 @@ pub fn word_parser<C: Character, E: CError<C>>(
 fn lexer() -> impl Parser<char, Vec<(Token, Span)>, Error = Simple<char, Span>>
 where
 {
-    let escaped = just('\\').ignore_then(any()).map(|c| match c {
-        'n' => '\n',
-        'r' => '\r',
-        't' => '\t',
-        '\\' => '\\',
-        _ => panic!("Invalid escape sequence"),
-    });
+    let hex = |min, max| {
+        filter(char::is_ascii_hexdigit)
+            .repeated()
+            .at_least(min)
+            .at_most(max)
+            .collect::<String>()
+    };
+    let escaped = just('\\')
+        .ignore_then(choice((
+            just('x').then(hex(2, 2)),
+            just('u').then(hex(1, 6).delimited_by(just('{'), just('}'))),
+            any().map(|c| (c, String::new())),
+        )))
+        .try_map(|(c, digits), span| {
+            escape_sequence(c, &digits).map_err(|e| Simple::custom(span, e))
+        });
 
     let char = just('\'')
-        .ignore_then(choice((escaped, any())))
+        .ignore_then(escaped.or(none_of(['\\'])))
         .then_ignore(just('\''))
         .map(Token::Char);
 
+    let type_var = just('\'')
+        .ignore_then(filter(char::is_ascii_lowercase))
+        .map(Token::TypeVar);
+
     let string = just('"')
-        .ignore_then(none_of(['"']).repeated().collect())
-        .then_ignore(just('"'))
-        .map(|s: String| {
-            let mut res = Vec::new();
-            let mut escape = false;
-            for b in s.into_bytes() {
-                if escape {
-                    match b {
-                        b'n' => res.push(b'\n'),
-                        b'r' => res.push(b'\r'),
-                        b't' => res.push(b'\t'),
-                        b'\\' => res.push(b'\\'),
-                        _ => panic!("Invalid escape sequence \\{}!", b as char),
-                    }
-                    escape = false;
-                } else if b == b'\\' {
-                    escape = true;
-                    continue;
-                } else {
-                    res.push(b)
-                }
-            }
-            String::from_utf8(res).unwrap()
-        })
-        .map(Token::Str);
+        .ignore_then(escaped.or(none_of(['"', '\\'])).repeated().collect())
+        .then_ignore(just('"'));
+
+    let attribute = just('#')
+        .ignore_then(just('['))
+        .ignore_then(text::ident())
+        .then(
+            string
+                .clone()
+                .or(text::int(10))
+                .delimited_by(just('('), just(')'))
+                .or_not(),
+        )
+        .then_ignore(just(']'))
+        .map(|(name, arg)| Token::Attribute(name, arg));
+
+    let string = string.map(Token::Str);
 
-    let num = text::int(10).map(Token::Num);
+    // `_` can only stand between two digits
+    let digits = |radix: u32| {
+        let digit = filter(move |c: &char| c.is_digit(radix));
+        digit
+            .chain(just('_').or_not().chain(digit).repeated().flatten())
+            .collect::<String>()
+    };
+    let radix = |prefix: char, radix: u32| {
+        just('0')
+            .ignore_then(just(prefix))
+            .ignore_then(digits(radix))
+            .map(move |digits| format!("0{prefix}{digits}"))
+    };
+    let int = choice((
+        radix('x', 16),
+        radix('b', 2),
+        radix('o', 8),
+        just('0').to("0".to_string()),
+        digits(10),
+    ));
+
+    let num = int
+        .then(one_of("uip").or_not())
+        .map(|(n, suffix): (String, _)| {
+            Token::Num(suffix.map_or(n.clone(), |s| format!("{n}{s}")))
+        });
 
     let word = word_parser().map(Token::Word);
 
+    // `ptr(proc(u64 : bool))` is one word however it is spaced, `Type::to_type` splits it again
+    let parens = recursive(|parens| {
+        none_of("()")
+            .map(String::from)
+            .or(parens
+                .delimited_by(just('('), just(')'))
+                .map(|inner| format!("({inner})")))
+            .repeated()
+            .map(|parts: Vec<String>| parts.concat())
+    });
+    let proc_ptr = just("ptr(proc(")
+        .ignore_then(parens)
+        .then_ignore(just("))"))
+        .map(|signature: String| {
+            let signature = signature.replace(':', " : ");
+            let words = signature.split_whitespace().collect::<Vec<_>>();
+            Token::Word(format!("ptr(proc({}))", words.join(" ")))
+        });
+
     let bool = word_parser().try_map(|i: String, s| {
         Token::Bool(match i.as_str() {
             "true" => true,
@@ -138,26 +291,9 @@ where
         .okay()
     });
 
-    let keyword = word_parser().try_map(|i: String, s| {
-        Token::KeyWord(match i.as_str() {
-            "include" => KeyWord::Include,
-            "return" => KeyWord::Return,
-            "cond" => KeyWord::Cond,
-            "if" => KeyWord::If,
-            "else" => KeyWord::Else,
-            "proc" => KeyWord::Proc,
-            "while" => KeyWord::While,
-            "do" => KeyWord::Do,
-            "bind" => KeyWord::Bind,
-            "const" => KeyWord::Const,
-            "mem" => KeyWord::Mem,
-            "var" => KeyWord::Var,
-            "struct" => KeyWord::Struct,
-            "cast" => KeyWord::Cast,
-            "end" => KeyWord::End,
-            _ => return Simple::custom(s, "Invalid keyword").error(),
-        })
-        .okay()
+    let keyword = word_parser().try_map(|i: String, s| match keyword(&i) {
+        Some(keyword) => Token::KeyWord(keyword).okay(),
+        None => Simple::custom(s, "Invalid keyword").error(),
     });
 
     let ignore = word_parser().try_map(|i: String, s| match i.as_str() {
@@ -169,14 +305,27 @@ where
 
     let sig_sep = just(':').to(Token::SigSep);
     let field_access = just('-').then(just('>')).to(Token::FieldAccess);
+    let open_brace = just('{').to(Token::OpenBrace);
+    let close_brace = just('}').to(Token::CloseBrace);
+    // the newline ends it without being part of it
+    let doc_comment = just(";;")
+        .ignore_then(none_of(['\n']).repeated().collect())
+        .then_ignore(just('\n').rewind())
+        .map(Token::DocComment);
 
     let token = choice((
+        doc_comment,
+        attribute,
         num,
         char,
+        type_var,
         string,
         field_access,
         ptr,
         sig_sep,
+        open_brace,
+        close_brace,
+        proc_ptr,
         ignore,
         bool,
         keyword,
@@ -184,7 +333,17 @@ where
     ))
     .recover_with(skip_then_retry_until([]));
 
-    let comment = just(";").then(take_until(just('\n'))).padded();
+    let line_comment = just(';')
+        .then(none_of([';', '(']).rewind())
+        .then(take_until(just('\n')))
+        .ignored();
+    let block_comment = recursive(|block| {
+        just(";(")
+            .then(block.or(just(");").not().ignored()).repeated())
+            .then(just(");"))
+            .ignored()
+    });
+    let comment = line_comment.or(block_comment).padded();
 
     token
         .map_with_span(|a, b| (a, b))
@@ -196,19 +355,33 @@ where
 pub fn lex(source: PathBuf) -> Result<Vec<(Token, Span)>> {
     let mut src = String::new();
     std::fs::File::open(&source)?.read_to_string(&mut src)?;
+    lex_string(src, source)
+}
 
-    match lexer().parse(Stream::from_iter(
-        Span::new(source.to_string_lossy().into_owned(), src.len(), src.len()),
-        src.chars()
-            .enumerate()
-            .map(|(i, c)| (c, Span::point(source.to_string_lossy().into_owned(), i))),
-    )) {
-        Ok(tokens) => tokens.okay(),
-        Err(es) => Error::Lexer(es).error(),
+/// The tokens of `source` for the parser, without trivia
+pub fn lex_string(source: String, file: PathBuf) -> Result<Vec<(Token, Span)>> {
+    let mut tokens = lex_with_trivia(source, file)?;
+    tokens.retain(|(token, _)| !token.is_trivia());
+    tokens.okay()
+}
+
+/// The tokens of `source` for the parser and where its lines start, for tools showing locations
+pub fn lex_with_lines(source: String, file: PathBuf) -> Result<(Vec<(Token, Span)>, LineIndex)> {
+    let lines = LineIndex::new(&source);
+    (lex_string(source, file)?, lines).okay()
+}
+
+/// Every token of `source` along with the trivia, like doc comments, that the parser doesn't see
+pub fn lex_with_trivia(source: String, file: PathBuf) -> Result<Vec<(Token, Span)>> {
+    if source.len() >= FAST_LEX_BYTES {
+        if let Some(tokens) = fast::lex(&source, &file) {
+            return tokens.okay();
+        }
     }
+    lex_combinators(&source, file)
 }
 
-pub fn lex_string(source: String, file: PathBuf) -> Result<Vec<(Token, Span)>> {
+fn lex_combinators(source: &str, file: PathBuf) -> Result<Vec<(Token, Span)>> {
     match lexer().parse(Stream::from_iter(
         Span::new(file.clone(), source.len(), source.len()),
         source
@@ -220,3 +393,60 @@ pub fn lex_string(source: String, file: PathBuf) -> Result<Vec<(Token, Span)>> {
         Err(es) => Error::Lexer(es).error(),
     }
 }
+
+#[test]
+fn test_escapes() {
+    let lex = |src: &str| lex_string(src.into(), "test.rh".into());
+    let tokens = lex(r#"'\0' '\'' '\x41' '\u{e9}' "\"\x7f\u{1F600}\\""#).unwrap();
+    let tokens = tokens.into_iter().map(|(t, _)| t).collect::<Vec<_>>();
+    assert_eq!(
+        tokens,
+        [
+            Token::Char('\0'),
+            Token::Char('\''),
+            Token::Char('A'),
+            Token::Char('é'),
+            Token::Str("\"\x7f\u{1F600}\\".into()),
+        ]
+    );
+    for (src, message, start) in [
+        (r#"1 "a\qb""#, "Unknown escape `\\q`", 4),
+        (r"'\x80'", "`\\x` escapes only go up to", 1),
+        (r"'\x4'", "`\\x` takes two hex digits", 1),
+        (r#""\u{d800}""#, "is not a unicode char", 1),
+    ] {
+        match lex(src) {
+            Err(Error::Lexer(es)) => assert!(
+                es.iter().any(
+                    |e| matches!(e.reason(), chumsky::error::SimpleReason::Custom(m)
+                    if m.starts_with(message) || m.ends_with(message))
+                        && e.span().start == start
+                ),
+                "{}: {:?}",
+                src,
+                es
+            ),
+            res => panic!("{}: {:?}", src, res),
+        }
+    }
+}
+
+#[test]
+fn test_comments() {
+    let src = ";; Adds one\n;( not ;( nested ); here );\nproc inc ; line\n;;\n";
+    let tokens = lex_with_trivia(src.into(), "test.rh".into()).unwrap();
+    let tokens = tokens.into_iter().map(|(t, _)| t).collect::<Vec<_>>();
+    assert_eq!(
+        tokens,
+        [
+            Token::DocComment(" Adds one".into()),
+            Token::KeyWord(KeyWord::Proc),
+            Token::Word("inc".into()),
+            Token::DocComment("".into()),
+        ]
+    );
+    let (tokens, lines) = lex_with_lines(src.into(), "test.rh".into()).unwrap();
+    assert_eq!(tokens.len(), 2);
+    assert_eq!(tokens[1].1.line_col(&lines), (3, 6));
+    assert!(lex_string(";( ;( ); unclosed\n".into(), "test.rh".into()).is_err());
+}
@@ -0,0 +1,275 @@
+//! Hand written lexer for big sources, like generated tables, where the combinators get slow.
+//! It gives the tokens of the combinator lexer for well formed sources only and gives up with
+//! `None` on anything else, leaving errors and their recovery to the combinators.
+
+use super::{escape, keyword, Token, ALLOWED_NON_ALPHA};
+use crate::span::Span;
+use somok::Somok;
+use std::path::Path;
+
+fn is_word_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c.is_ascii() && ALLOWED_NON_ALPHA.contains(&(c as u8))
+}
+
+fn is_word(c: char) -> bool {
+    c.is_ascii_digit() || is_word_start(c)
+}
+
+/// Length of the run of chars at the start of `chars` that `f` accepts
+fn run(chars: &[char], f: impl Fn(char) -> bool) -> usize {
+    chars.iter().position(|&c| !f(c)).unwrap_or(chars.len())
+}
+
+/// Length of the decimal integer at the start of `chars`, a lone `0` or digits not starting with
+/// one, like `text::int`
+fn int(chars: &[char]) -> usize {
+    match chars.first() {
+        Some('0') => 1,
+        Some(c) if c.is_ascii_digit() => run(chars, |c| c.is_ascii_digit()),
+        _ => 0,
+    }
+}
+
+/// Length of the digits of `radix` at the start of `chars`, with single `_`s between them
+fn digits(chars: &[char], radix: u32) -> usize {
+    if !matches!(chars.first(), Some(c) if c.is_digit(radix)) {
+        return 0;
+    }
+    let mut len = 1;
+    loop {
+        match chars[len..] {
+            [c, ..] if c.is_digit(radix) => len += 1,
+            ['_', c, ..] if c.is_digit(radix) => len += 2,
+            _ => return len,
+        }
+    }
+}
+
+/// Length of the integer literal at the start of `chars`, an `int` or digits after `0x`, `0b`
+/// or `0o`, like the combinators' `num`
+fn num(chars: &[char]) -> usize {
+    let radix = match chars {
+        ['0', 'x', ..] => 16,
+        ['0', 'b', ..] => 2,
+        ['0', 'o', ..] => 8,
+        ['0', ..] => return 1,
+        _ => return digits(chars, 10),
+    };
+    match digits(&chars[2..], radix) {
+        0 => 1,
+        len => len + 2,
+    }
+}
+
+/// Length of a string literal up to the unescaped `"` that ends it
+fn string_len(chars: &[char]) -> Option<usize> {
+    let mut escaping = false;
+    chars.iter().position(|&c| {
+        let end = !escaping && c == '"';
+        escaping = !escaping && c == '\\';
+        end
+    })
+}
+
+/// Length of the block comment at the start of `chars`, with the ones nested in it
+fn block_comment(chars: &[char]) -> Option<usize> {
+    let (mut depth, mut i) = (0, 0);
+    loop {
+        match chars.get(i..i + 2)? {
+            [';', '('] => depth += 1,
+            [')', ';'] => depth -= 1,
+            _ => {
+                i += 1;
+                continue;
+            }
+        }
+        i += 2;
+        if depth == 0 {
+            return i.some();
+        }
+    }
+}
+
+/// Contents of a string literal, `None` with `\x` and `\u` escapes, which the combinators check
+fn string(chars: &[char]) -> Option<String> {
+    let mut res = String::new();
+    let mut escaping = false;
+    for &c in chars {
+        if escaping {
+            res.push(escape(c)?);
+            escaping = false;
+        } else if c == '\\' {
+            escaping = true;
+        } else {
+            res.push(c)
+        }
+    }
+    res.some()
+}
+
+/// `#[name]`, `#[name(42)]` or `#[name("arg")]` at the start of `chars`, and its length
+fn attribute(chars: &[char]) -> Option<(Token, usize)> {
+    let mut i = 2;
+    if !matches!(chars.get(i), Some(&c) if c.is_ascii_alphabetic() || c == '_') {
+        return None;
+    }
+    i += run(&chars[i..], |c| c.is_ascii_alphanumeric() || c == '_');
+    let name = chars[2..i].iter().collect::<String>();
+    let mut arg = None;
+    if chars.get(i) == Some(&'(') {
+        i += 1;
+        let len = match chars.get(i)? {
+            '"' => {
+                let len = string_len(&chars[i + 1..])?;
+                arg = string(&chars[i + 1..i + 1 + len])?.some();
+                len + 2
+            }
+            _ => {
+                let len = int(&chars[i..]);
+                arg = chars[i..i + len].iter().collect::<String>().some();
+                len
+            }
+        };
+        if len == 0 || chars.get(i + len) != Some(&')') {
+            return None;
+        }
+        i += len + 1;
+    }
+    if chars.get(i) != Some(&']') {
+        return None;
+    }
+    (Token::Attribute(name, arg), i + 1).some()
+}
+
+pub(super) fn lex(source: &str, file: &Path) -> Option<Vec<(Token, Span)>> {
+    let chars = source.chars().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while let Some(&c) = chars.get(i) {
+        let start = i;
+        let rest = &chars[i..];
+        let next = rest.get(1).copied();
+        let (token, len) = match c {
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            ';' if next == Some(';') => {
+                let len = rest.iter().position(|&c| c == '\n')?;
+                (Token::DocComment(rest[2..len].iter().collect()), len)
+            }
+            ';' if next == Some('(') => {
+                i += block_comment(rest)?;
+                continue;
+            }
+            ';' => {
+                // a comment has to end with a newline
+                i += rest.iter().position(|&c| c == '\n')? + 1;
+                continue;
+            }
+            '0'..='9' => {
+                let len = num(rest);
+                let mut num = rest[..len].iter().collect::<String>();
+                match rest.get(len) {
+                    Some(&suffix @ ('u' | 'i' | 'p')) => {
+                        num.push(suffix);
+                        (Token::Num(num), len + 1)
+                    }
+                    _ => (Token::Num(num), len),
+                }
+            }
+            '\'' if matches!(next, Some(c) if c.is_ascii_lowercase())
+                && rest.get(2) != Some(&'\'') =>
+            {
+                (Token::TypeVar(next?), 2)
+            }
+            '\'' => {
+                let (c, len) = match *rest.get(1)? {
+                    '\\' => (escape(*rest.get(2)?)?, 2),
+                    c => (c, 1),
+                };
+                if rest.get(len + 1) != Some(&'\'') {
+                    return None;
+                }
+                (Token::Char(c), len + 2)
+            }
+            '"' => {
+                let len = string_len(&rest[1..])?;
+                (Token::Str(string(&rest[1..len + 1])?), len + 2)
+            }
+            '#' if next == Some('[') => attribute(rest)?,
+            '-' if next == Some('>') => (Token::FieldAccess, 2),
+            '&' if next == Some('>') => (Token::Ptr, 2),
+            ':' => (Token::SigSep, 1),
+            '{' => (Token::OpenBrace, 1),
+            '}' => (Token::CloseBrace, 1),
+            c if is_word_start(c) => {
+                let len = run(rest, is_word);
+                let word = rest[..len].iter().collect::<String>();
+                let token = match word.as_str() {
+                    "_" => Token::Ignore,
+                    "true" => Token::Bool(true),
+                    "false" => Token::Bool(false),
+                    w => match keyword(w) {
+                        Some(keyword) => Token::KeyWord(keyword),
+                        None => Token::Word(word),
+                    },
+                };
+                (token, len)
+            }
+            _ => return None,
+        };
+        i += len;
+        tokens.push((token, Span::new(file, start, i)));
+    }
+    // how the combinators treat a source without tokens is theirs to say
+    if tokens.is_empty() {
+        return None;
+    }
+    tokens.some()
+}
+
+#[test]
+fn test_fast_lex() {
+    use super::lex_combinators;
+
+    let mut sources = Vec::new();
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("rotth-src");
+    for dir in [dir.clone(), dir.join("examples")] {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension() == Some("rh".as_ref()) {
+                sources.push(std::fs::read_to_string(path).unwrap());
+            }
+        }
+    }
+    sources.push(
+        "#[inline] #[align(16)] #[section(\"t\\n\")] proc f u8 &>T: bool do\n\
+         ; comment ä\n;;doc ;( \n;\n ;( x ;(\n ); y );;; z\n\
+         0123 12u 7p 3i x->y ->z &>&>u8 '\\n' ''' 'ä' {}\n\
+         1_000 1__0 2_ 0_1 0xfF_0u 0x 0b102 0o78i 0b_1 0o7_7p\n\
+         _ _x true false truex -1 a(b)[c] cond-end end;tail\n\
+         \"é\\t\" \"a\\\"\" \"\\\\\" '\\0' '\\'' '\\\"' \"\\0\\'\"\n 'a &>'b 'c'\n"
+            .to_string(),
+    );
+    let table = (0..2000)
+        .map(|i| format!("const T{0} : u64 do {0} {0} * end\n", i))
+        .collect::<String>();
+    sources.push(table);
+
+    for source in sources {
+        let file = Path::new("fast.rh");
+        let fast = lex(&source, file).unwrap();
+        let combinators = lex_combinators(&source, file.to_path_buf()).unwrap();
+        assert_eq!(fast.len(), combinators.len());
+        for (a, b) in fast.iter().zip(&combinators) {
+            assert_eq!(a, b, "{:?}", source);
+        }
+    }
+    // errors are the combinators' business
+    assert!(lex("'ab'", Path::new("fast.rh")).is_none());
+    assert!(lex("foo ; no newline", Path::new("fast.rh")).is_none());
+    assert!(lex("foo ;( ;( ); unclosed", Path::new("fast.rh")).is_none());
+    assert!(lex("\"\\q\"", Path::new("fast.rh")).is_none());
+    assert!(lex("\"\\x41\"", Path::new("fast.rh")).is_none());
+}
@@ -36,13 +36,28 @@ macro_rules! coerce_ast {
 }
 
 pub mod ast;
+pub mod audit;
+pub mod backend;
+pub mod build;
+pub mod cli;
+pub mod diagnostics;
+pub mod driver;
 pub mod emit;
+pub mod emit_aarch64;
 pub mod eval;
+pub mod generate;
 pub mod hir;
 pub mod iconst;
+pub mod include_graph;
+pub mod intrinsics;
+pub mod layout;
 pub mod lexer;
 pub mod lir;
+pub mod mir;
+pub mod query;
+pub mod rename;
 pub mod resolver;
+pub mod session;
 pub mod span;
 pub mod typecheck;
 pub mod types;
@@ -51,7 +66,7 @@ use chumsky::prelude::Simple;
 use lexer::Token;
 use span::Span;
 use thiserror::Error;
-use typecheck::TypecheckError;
+use typecheck::{TypecheckError, TypecheckWarning};
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -63,8 +78,36 @@ pub enum Error {
     Parser(Vec<Simple<Token, Span>>),
     #[error("Redefinition error {0:?}")]
     Redefinition(Vec<RedefinitionError>),
+    #[error("Alias cycle {0:?}")]
+    AliasCycle(Vec<Span>),
+    #[error("Include cycle {0:?}")]
+    IncludeCycle(Vec<Span>),
     #[error("Typecheck error {0:?}")]
     Typecheck(TypecheckError),
+    #[error("`{name}` was not found, {hint}")]
+    MissingTool { name: String, hint: &'static str },
+    #[error("{command} failed:\n{stderr}")]
+    ToolFailed { command: String, stderr: String },
+    #[error("Assembler rejected the generated code")]
+    Assembler(Vec<build::AsmError>),
+    #[error("No backend for target `{0}`")]
+    UnsupportedTarget(String),
+    #[error("Backend error: {0}")]
+    Backend(String),
+    #[error("Cannot rename: {0}")]
+    Rename(#[from] rename::RenameError),
+    #[error("Invalid symbols manifest, {0}")]
+    Manifest(#[from] emit::symbols::ManifestError),
+    #[error("Invalid options, {0}")]
+    Options(#[from] session::OptionsError),
+    #[error("{} warnings with warnings denied", .0.len())]
+    DeniedWarnings(Vec<TypecheckWarning>),
+    #[error("{0}")]
+    Eval(#[from] eval::EvalError),
+    #[error("Evaluating at compile time failed, {trap}")]
+    ConstEval { span: Span, trap: eval::Trap },
+    #[error("Invalid LIR, {0}")]
+    Lir(#[from] lir::TextError),
 }
 
 impl From<TypecheckError> for Error {
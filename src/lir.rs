@@ -1,32 +1,70 @@
 use crate::{
-    eval::eval,
+    eval::{EvalError, Interpreter, Trap},
     hir::{
-        self, Bind, Binding, Cond, CondBranch, Const, HirKind, HirNode, If, Intrinsic, Mem, Proc,
-        TopLevel, While,
+        self, jumps_out, Bind, Binding, Cond, CondBranch, Const, Destructure, Endian, HirKind,
+        HirNode, If, Intrinsic, Mem, PrintFormat, Proc, Table, TopLevel, While,
     },
     iconst::IConst,
-    types::{self, StructIndex, Type},
+    intrinsics, mir,
+    session::{Comments, Session},
+    span::Span,
+    typecheck::{ErrorKind, StackSnapshots, TypecheckError},
+    types::{self, Type},
+    Error,
 };
 
-#[derive(Debug)]
+mod dce;
+mod hot_cold;
+mod inline;
+mod opt;
+mod order;
+mod pass;
+mod text;
+mod verify;
+pub use dce::{eliminate_dead_procs, reachable_procs};
+pub use hot_cold::split_hot_cold;
+pub use inline::{called_once, inline_procs, small_procs, INLINE_OPS};
+pub use opt::{optimize, OptLevel, Pipeline};
+pub use order::order_procs;
+pub use pass::{LirPass, Passes, Phase, Program};
+pub use text::{dump_data, parse, TextError};
+pub use verify::verify;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Op {
     Push(IConst),
     PushStr(usize),
     PushMem(String),
+    /// Address of a `table`, whose bytes are in `Program::tables`
+    PushTable(String),
     Drop,
     Dup,
     Swap,
     Over,
+    Pick(usize),
+    Roll(usize),
 
     Bind,
     UseBinding(usize),
     Unbind,
 
     ReadU64,
+    ReadU32,
+    ReadU16,
     ReadU8,
     WriteU64,
+    WriteU32,
+    WriteU16,
     WriteU8,
 
+    Bswap64,
+    Bswap32,
+    Bswap16,
+    /// Keeps the low bits of the top of the stack, how many given, and fills the rest with
+    /// zeroes or copies of the top kept bit. Integers narrower than 64 bits are kept this way.
+    ZeroExtend(u32),
+    SignExtend(u32),
+
     ReserveEscaping(usize),
     PushEscaping(usize),
 
@@ -35,7 +73,18 @@ pub enum Op {
     PushLvar(usize),
 
     Dump,
-    Print,
+    Print(PrintFormat),
+    PrintPadded(PrintFormat),
+
+    HashStr,
+
+    /// Loads and stores of 16 bytes as one SSE2 vector, `Cmp16` pushes the `pmovmskb` mask
+    Copy16,
+    Cmp16,
+    Splat16,
+    /// Index of the first byte equal to the top in the string under it, `len` if there is none,
+    /// and whether there is one
+    FindByte,
 
     Syscall0,
     Syscall1,
@@ -44,6 +93,7 @@ pub enum Op {
     Syscall4,
     Syscall5,
     Syscall6,
+    SplitErrno,
 
     Argc,
     Argv,
@@ -51,28 +101,128 @@ pub enum Op {
     Add,
     Sub,
     Divmod,
+    /// `Divmod` of signed integers, rounding the quotient towards zero
+    IDivmod,
     Mul,
+    /// `k +` and `k -`, folded by the MIR when the right operand is a constant
+    AddImm(u64),
+    SubImm(u64),
+
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    /// Shifts by the low 6 bits of the top, `Sar` copies the sign bit into the vacated bits
+    Shl,
+    Shr,
+    Sar,
 
     Eq,
     Ne,
+    /// Signed comparisons, the unsigned ones end with `U`
     Lt,
     Le,
     Gt,
     Ge,
+    LtU,
+    LeU,
+    GtU,
+    GeU,
 
     Proc(String),
     Label(String),
+    /// Source of the ops up to the next `Loc`, only lowered for assembly comments that show it
+    Loc(Span),
     Jump(String),
     JumpF(String),
     JumpT(String),
+    /// Pops a value and jumps if it compares to the constant that way, a comparison with a
+    /// constant and the jump on it as folded by the MIR
+    CmpImmBranch(Cmp, u64, String),
     Call(String),
+    /// Address of a proc, as an op index for the evaluator
+    PushProc(String),
+    /// Calls the proc whose address is on top, with its input and output counts
+    CallIndirect(usize, usize),
+    /// Host function name, with its input and output counts
+    CallHost(String, usize, usize),
     Return,
     Exit,
 }
-use fnv::FnvHashMap;
+
+use fnv::{FnvHashMap, FnvHashSet};
 use somok::{Either, PartitionThree, Somok, Ternary};
+use std::fmt::Write;
 use Op::*;
 
+/// Comparison of a `CmpImmBranch`, as done by the op of the same name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cmp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    LtU,
+    LeU,
+    GtU,
+    GeU,
+}
+
+impl Cmp {
+    /// The comparison `op` does, if it is one
+    pub fn of(op: &Op) -> Option<Self> {
+        match op {
+            Op::Eq => Self::Eq,
+            Op::Ne => Self::Ne,
+            Op::Lt => Self::Lt,
+            Op::Le => Self::Le,
+            Op::Gt => Self::Gt,
+            Op::Ge => Self::Ge,
+            Op::LtU => Self::LtU,
+            Op::LeU => Self::LeU,
+            Op::GtU => Self::GtU,
+            Op::GeU => Self::GeU,
+            _ => return None,
+        }
+        .some()
+    }
+
+    /// Whether `a` compares to `b` this way
+    pub fn holds(self, a: u64, b: u64) -> bool {
+        let (x, y) = (a as i64, b as i64);
+        match self {
+            Self::Eq => a == b,
+            Self::Ne => a != b,
+            Self::Lt => x < y,
+            Self::Le => x <= y,
+            Self::Gt => x > y,
+            Self::Ge => x >= y,
+            Self::LtU => a < b,
+            Self::LeU => a <= b,
+            Self::GtU => a > b,
+            Self::GeU => a >= b,
+        }
+    }
+
+    /// Holds exactly when `self` doesn't
+    pub fn negate(self) -> Self {
+        match self {
+            Self::Eq => Self::Ne,
+            Self::Ne => Self::Eq,
+            Self::Lt => Self::Ge,
+            Self::Le => Self::Gt,
+            Self::Gt => Self::Le,
+            Self::Ge => Self::Lt,
+            Self::LtU => Self::GeU,
+            Self::LeU => Self::GtU,
+            Self::GtU => Self::LeU,
+            Self::GeU => Self::LtU,
+        }
+    }
+}
+
 #[derive(Clone)]
 enum ComConst {
     Compiled(Vec<IConst>),
@@ -85,57 +235,133 @@ enum ComMem {
     NotCompiled(Mem),
 }
 
-pub struct Compiler {
+/// Where `break` and `continue` in a copy of a loop body jump, and what they unwind on the way
+struct Loop {
+    next: String,
+    end: String,
+    /// Bindings and blocks with `defer`s active outside the body
+    bindings: usize,
+    defers: usize,
+}
+
+/// Whether `body` has a `break` or `continue` of the loop it is the body of
+fn leaves_loop(body: &[HirNode]) -> bool {
+    body.iter().any(|node| match &node.hir {
+        HirKind::Break | HirKind::Continue => true,
+        HirKind::While(w) => leaves_loop(&w.cond),
+        _ => node.blocks().into_iter().any(leaves_loop),
+    })
+}
+
+/// Copies of the body per pass through a loop with a known trip count, unless `#[unroll(n)]` says
+const UNROLL: usize = 4;
+
+pub struct Compiler<'s> {
     label: usize,
     mangle_table: FnvHashMap<String, String>,
+    /// `extern host` procs, with their input and output counts
+    hosts: FnvHashMap<String, (usize, usize)>,
+    /// Input and output counts of the other procs, by mangled name
+    arities: FnvHashMap<String, (usize, usize)>,
+    /// `#[pure]` procs by mangled name, compiled again into the consts that call them
+    pure: FnvHashMap<String, Proc>,
     proc_id: usize,
     current_name: String,
     result: Vec<Op>,
     consts: FnvHashMap<String, ComConst>,
-    strings: Vec<String>,
     bindings: Vec<Vec<String>>,
+    /// `defer` bodies of the blocks being lowered, innermost block last
+    defers: Vec<Vec<Vec<HirNode>>>,
+    loops: Vec<Loop>,
     mems: FnvHashMap<String, ComMem>,
+    tables: FnvHashMap<String, Table>,
+    /// Bytes of the tables evaluated so far
+    table_bytes: FnvHashMap<String, Vec<u8>>,
+    /// Depth of every lowered proc from the snapshots of the session, by label
+    depths: FnvHashMap<String, usize>,
+    /// Where each `mem` and global `var` is defined
+    mem_spans: FnvHashMap<String, Span>,
     vars: FnvHashMap<String, types::Type>,
     local_vars: FnvHashMap<String, (usize, hir::Var)>,
     local_vars_size: usize,
     escaping_size: usize,
-    structs: StructIndex,
+    /// Span of the node being lowered, and of the last `Loc` emitted
+    span: Option<Span>,
+    loc: Option<Span>,
+    /// First failure of something evaluated at compile time, lowering goes on with zeros for it
+    failed: Option<Error>,
+    session: &'s Session,
 }
 
-impl Compiler {
-    pub fn compile(
-        mut self,
-        items: FnvHashMap<String, TopLevel>,
-    ) -> (Vec<Op>, Vec<String>, FnvHashMap<String, usize>) {
+impl<'s> Compiler<'s> {
+    pub fn compile(mut self, items: FnvHashMap<String, TopLevel>) -> Result<Program, Error> {
         let (procs, consts_mems_gvars) = items
             .into_iter()
             .partition::<Vec<_>, _>(|(_, it)| matches!(it, TopLevel::Proc(_)));
+        let mut cold = FnvHashSet::default();
+        let mut inline = FnvHashSet::default();
         let procs = procs
             .into_iter()
-            .map(|(name, proc)| {
+            .filter_map(|(name, proc)| {
                 if let TopLevel::Proc(proc) = proc {
+                    if proc.host {
+                        self.hosts.insert(name, (proc.ins.len(), proc.outs.len()));
+                        return None;
+                    }
+                    let depth = self.session.snapshots.depth(&name).unwrap_or_else(|| {
+                        panic!("No stack depth for `{}`, every checked proc has one", name)
+                    });
                     let mangled = self.mangle_name(name);
-                    (mangled, proc)
+                    self.depths.insert(mangled.clone(), depth);
+                    if proc.attrs.cold {
+                        cold.insert(mangled.clone());
+                    }
+                    if proc.attrs.inline {
+                        inline.insert(mangled.clone());
+                    }
+                    if proc.attrs.pure {
+                        self.pure.insert(mangled.clone(), proc.clone());
+                    }
+                    self.arities
+                        .insert(mangled.clone(), (proc.ins.len(), proc.outs.len()));
+                    (mangled, proc).some()
                 } else {
                     unreachable!()
                 }
             })
             .collect::<Vec<_>>();
 
+        let (tables, consts_mems_gvars) = consts_mems_gvars
+            .into_iter()
+            .partition::<Vec<_>, _>(|(_, it)| matches!(it, TopLevel::Table(_)));
+        self.tables = tables
+            .into_iter()
+            .map(|(name, table)| match table {
+                TopLevel::Table(table) => (name, table),
+                _ => unreachable!(),
+            })
+            .collect();
+
         let (consts, mems, vars) =
             consts_mems_gvars
                 .into_iter()
                 .partition_three::<Vec<_>, _>(|(_, it)| match it {
-                    TopLevel::Proc(_) => unreachable!(),
+                    TopLevel::Proc(_) | TopLevel::Table(_) => unreachable!(),
                     TopLevel::Const(_) => Ternary::First,
                     TopLevel::Mem(_) => Ternary::Second,
                     TopLevel::Var(_) => Ternary::Third,
                 });
 
+        let externs = mems
+            .iter()
+            .filter(|(_, mem)| matches!(mem, TopLevel::Mem(mem) if mem.extern_))
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
         self.mems = mems
             .into_iter()
             .map(|(name, mem)| {
                 if let TopLevel::Mem(mem) = mem {
+                    self.mem_spans.insert(name.clone(), mem.span.clone());
                     (name, ComMem::NotCompiled(mem))
                 } else {
                     unreachable!()
@@ -147,6 +373,7 @@ impl Compiler {
             .into_iter()
             .map(|(name, mem)| {
                 if let TopLevel::Var(var) = mem {
+                    self.mem_spans.insert(name.clone(), var.span);
                     (name, var.ty)
                 } else {
                     unreachable!()
@@ -166,66 +393,145 @@ impl Compiler {
             .collect::<FnvHashMap<_, _>>();
 
         self.emit(Call("main".to_string()));
-
+        // the `main` of a snippet can give nothing, see `Typechecker::typecheck_snippet`
+        if matches!(self.arities.get("main"), Some((_, 0))) {
+            self.emit(Push(IConst::U64(0)));
+        }
         self.emit(Exit);
         for (name, proc) in procs {
             self.compile_proc(name, proc)
         }
+        if let Some(e) = self.failed.take() {
+            return Err(e);
+        }
+        let pipeline = self.session.options.pipeline;
+        if pipeline.inline || pipeline.merge_once {
+            let small = small_procs(&self.result);
+            let once = called_once(&self.result);
+            inline.extend(
+                small
+                    .into_iter()
+                    .filter(|p| !cold.contains(p) && (pipeline.inline || once.contains(p))),
+            );
+        }
+        self.result = inline_procs(std::mem::take(&mut self.result), &inline, &mut self.depths);
+        if pipeline.order {
+            self.result = order_procs(std::mem::take(&mut self.result));
+        }
+        self.result = split_hot_cold(std::mem::take(&mut self.result), &cold);
+        self.result = optimize(
+            std::mem::take(&mut self.result),
+            self.session.options.peepholes,
+        );
+        self.result = eliminate_dead_procs(std::mem::take(&mut self.result));
 
         let vars = self
             .vars
             .into_iter()
-            .map(|(nm, ty)| (nm, ty.size(&self.structs)));
-        (
-            self.result,
-            self.strings,
-            self.mems
-                .into_iter()
-                .map(|(nm, sz)| {
-                    (nm, {
-                        match sz {
-                            ComMem::Compiled(sz) => sz,
-                            ComMem::NotCompiled(_) => unreachable!(),
-                        }
-                    })
+            .map(|(nm, ty)| (nm, ty.size(&self.session.structs)));
+        let mems = self
+            .mems
+            .into_iter()
+            .map(|(nm, sz)| {
+                (nm, {
+                    match sz {
+                        ComMem::Compiled(sz) => sz,
+                        ComMem::NotCompiled(_) => unreachable!(),
+                    }
                 })
-                .chain(vars)
-                .collect(),
-        )
+            })
+            .chain(vars)
+            .collect();
+        let options = &self.session.options;
+        let mut program = Program {
+            ops: self.result,
+            mems,
+            tables: self.table_bytes,
+            depths: self.depths,
+            extern_mems: Default::default(),
+            mem_spans: self.mem_spans,
+        };
+        options.passes.run(&mut program);
+        // checked builds rely on the stack depth proof instead of asserting depths at runtime
+        if cfg!(debug_assertions) || options.verify_lir || options.checked {
+            let strings = self.session.strings().len();
+            if let Err(errors) = verify(&program.ops, &program.mems, strings, &self.arities) {
+                panic!("Invalid LIR after lowering:\n{}", errors.join("\n"))
+            }
+        }
+        // the backends only make room for the mems defined in the program
+        for name in externs {
+            if let Some(size) = program.mems.remove(&name) {
+                program.extern_mems.insert(name, size);
+            }
+        }
+        program.okay()
     }
 
     fn compile_proc(&mut self, name: String, proc: Proc) {
         self.label = 0;
         self.current_name = name.clone();
+        let spans = self.session.options.comments >= Comments::Spans;
+        if spans {
+            self.span = proc.span.clone().some();
+        }
         let label = name;
+        let start = self.result.len();
+        let ins = proc.ins.len();
         self.emit(Proc(label));
 
         let mut i = 0;
-        let (local, escaping) = proc
+        let (escaping, local) = proc
             .vars
             .into_iter()
             .partition::<Vec<_>, _>(|(_, v)| v.escaping);
         for (name, var) in local {
-            let offset = var.ty.size(&self.structs);
+            // keep every local word aligned, strict alignment checks rely on it
+            let offset = (var.ty.size(&self.session.structs) + 7) & !7;
             self.local_vars.insert(name, (i, var));
             i += offset
         }
         self.local_vars_size = i;
         self.emit(ReserveLocals(i));
 
+        // escaping vars outlive the proc, they get their own stack that is never freed
+        self.escaping_size = 0;
         for (name, var) in escaping {
-            let offset = var.ty.size(&self.structs);
-            self.local_vars.insert(name, (i, var));
+            let offset = (var.ty.size(&self.session.structs) + 7) & !7;
+            self.local_vars.insert(name, (self.escaping_size, var));
             self.escaping_size += offset
         }
-        self.emit(ReserveEscaping(i));
+        self.emit(ReserveEscaping(self.escaping_size));
 
         self.compile_body(proc.body);
 
         self.local_vars = Default::default();
+        if spans {
+            // the epilogue is the `end` of the proc
+            self.span = Span::point(proc.span.file.clone(), proc.span.end - 1).some();
+        }
 
         self.emit(FreeLocals(i));
         self.emit(Return);
+
+        let pipeline = self.session.options.pipeline;
+        if pipeline.mir {
+            let ops = self.result.split_off(start);
+            let mut body = mir::Body::build(ops, ins, &self.arities);
+            body.fold();
+            body.eliminate_dead_code();
+            if pipeline.licm {
+                // the hoisted values stay on the stack while the loop runs
+                let hoisted = body.hoist_invariants();
+                if let Some(depth) = self.depths.get_mut(&self.current_name) {
+                    *depth += hoisted;
+                }
+            }
+            if pipeline.fuse {
+                body.fuse_compares();
+            }
+            self.result.extend(body.lower());
+        }
     }
 
     fn compile_const(&mut self, name: String) -> Vec<IConst> {
@@ -235,112 +541,191 @@ impl Compiler {
             None => unreachable!(),
         };
         let Const {
+            attrs: _,
             outs,
             body,
-            span: _,
+            span,
         } = const_;
-        let mut com = Self::with_consts_and_strings(self.consts.clone(), self.strings.clone());
-        com.compile_body(body.clone());
-        self.consts = com.consts;
-        self.strings = com.strings;
-        let ops = com.result;
-        let mut const_ = Vec::new();
-        match eval(ops, &self.strings) {
-            Ok(Either::Right(bytes)) => {
-                for (&ty, bytes) in outs.iter().zip(bytes) {
-                    match ty {
-                        Type::BOOL => const_.push(IConst::Bool(bytes == 1)),
-                        Type::U64 => const_.push(IConst::U64(bytes)),
-                        Type::I64 => const_.push(IConst::I64(bytes as i64)),
-                        Type::CHAR => const_.push(IConst::Char(bytes as u8 as char)),
-                        ty => unreachable!("{:?}", ty),
-                    }
-                }
-            }
-            Err(req) => {
+        let ops = self.const_program(body.clone());
+        let values = match self.const_eval(&ops) {
+            Ok(Either::Right(values)) => values,
+            Err(EvalError::Unresolved(req)) => {
                 self.compile_const(req);
-                let mut com =
-                    Self::with_consts_and_strings(self.consts.clone(), self.strings.clone());
+                let mut com = Self::with_consts(self.consts.clone(), self.session);
                 com.compile_body(body);
                 com.emit(Exit);
                 let ops = com.result;
                 self.consts = com.consts;
-                self.strings = com.strings;
-                match eval(ops, &self.strings) {
-                    Ok(Either::Right(bytes)) => {
-                        for (&ty, bytes) in outs.iter().zip(bytes) {
-                            match ty {
-                                Type::BOOL => const_.push(IConst::Bool(bytes == 1)),
-                                Type::U64 => const_.push(IConst::U64(bytes)),
-                                Type::I64 => const_.push(IConst::I64(bytes as i64)),
-                                Type::CHAR => const_.push(IConst::Char(bytes as u8 as char)),
-                                ty => unreachable!("{:?}", ty),
-                            }
-                        }
-                    }
+                self.failed = self.failed.take().or(com.failed);
+                match self.const_eval(&ops) {
+                    Ok(Either::Right(values)) => values,
                     _ => unreachable!(),
                 }
             }
+            Err(EvalError::Trap(trap)) => {
+                self.fail(span, trap);
+                vec![0; outs.len()]
+            }
             Ok(Either::Left(_)) => unreachable!(),
         };
+        let const_ = outs
+            .iter()
+            .zip(values)
+            .map(|(&ty, value)| match ty {
+                Type::BOOL => IConst::Bool(value == 1),
+                Type::U64 => IConst::U64(value),
+                Type::I64 => IConst::I64(value as i64),
+                Type::CHAR => IConst::Char(value as u8 as char),
+                ty => unreachable!("{:?}", ty),
+            })
+            .collect::<Vec<_>>();
 
         self.consts.insert(name, ComConst::Compiled(const_.clone()));
         const_
     }
 
+    /// Ops that evaluate `body` at compile time, followed by the pure procs it calls
+    fn const_program(&mut self, body: Vec<HirNode>) -> Vec<Op> {
+        let mut com = Self::with_consts(self.consts.clone(), self.session);
+        com.mangle_table = self.mangle_table.clone();
+        com.arities = self.arities.clone();
+        com.pure = self.pure.clone();
+        com.tables = self.tables.clone();
+        com.table_bytes = std::mem::take(&mut self.table_bytes);
+        com.compile_body(body);
+        // returning with nothing to return to ends evaluation with the results on the stack
+        com.emit(Return);
+        let mut compiled = FnvHashSet::default();
+        while let Some(label) = com.result.iter().find_map(|op| match op {
+            Call(l) if !compiled.contains(l) => l.clone().some(),
+            _ => None,
+        }) {
+            compiled.insert(label.clone());
+            // the typechecker only lets consts call pure procs
+            let proc = com.pure[&label].clone();
+            com.compile_proc(label, proc);
+        }
+        self.consts = com.consts;
+        self.table_bytes = com.table_bytes;
+        self.failed = self.failed.take().or(com.failed);
+        com.result
+    }
+
+    /// Records that evaluating the item at `span` trapped, unless something failed before
+    fn fail(&mut self, span: Span, trap: Trap) {
+        if self.failed.is_none() {
+            self.failed = Error::ConstEval { span, trap }.some();
+        }
+    }
+
     fn compile_mem(&mut self, name: &String) {
         let mem = match self.mems.get(name) {
             Some(ComMem::Compiled(_)) => return,
             Some(ComMem::NotCompiled(c)) => c.clone(),
             None => unreachable!(),
         };
-        let Mem { body, span: _ } = mem;
-        let mut com = Self::with_consts_and_strings(self.consts.clone(), self.strings.clone());
-        com.compile_body(body.clone());
-        self.consts = com.consts;
-        self.strings = com.strings;
-        let ops = com.result;
+        let Mem { body, span, .. } = mem;
+        let ops = self.const_program(body.clone());
         let size;
-        match eval(ops, &self.strings) {
+        match self.const_eval(&ops) {
             Ok(Either::Right(bytes)) => size = bytes[0] as usize,
-            Err(req) => {
+            Err(EvalError::Unresolved(req)) => {
                 self.compile_const(req);
-                let mut com =
-                    Self::with_consts_and_strings(self.consts.clone(), self.strings.clone());
+                let mut com = Self::with_consts(self.consts.clone(), self.session);
                 com.compile_body(body);
                 com.emit(Exit);
                 let ops = com.result;
                 self.consts = com.consts;
-                self.strings = com.strings;
-                match eval(ops, &self.strings) {
+                self.failed = self.failed.take().or(com.failed);
+                match self.const_eval(&ops) {
                     Ok(Either::Right(bytes)) => size = bytes[0] as usize,
                     _ => unreachable!(),
                 }
             }
+            Err(EvalError::Trap(trap)) => {
+                self.fail(span, trap);
+                size = 0;
+            }
             Ok(Either::Left(_)) => unreachable!(),
         };
         self.mems.insert(name.clone(), ComMem::Compiled(size));
     }
 
+    /// Runs ops evaluating something at compile time, which may read the tables evaluated so far
+    fn const_eval(&self, ops: &[Op]) -> Result<Either<u64, Vec<u64>>, EvalError> {
+        Interpreter::new()
+            .tables(&self.table_bytes)
+            .run(ops, &self.session.strings())
+    }
+
+    /// Evaluates the body of the table `name` for every index, unless it already was
+    fn compile_table(&mut self, name: &str) {
+        if self.table_bytes.contains_key(name) {
+            return;
+        }
+        let Table {
+            ty,
+            len,
+            body,
+            span,
+            ..
+        } = self.tables[name].clone();
+        let size = ty.size(&self.session.structs);
+        let ops = self.const_program(body);
+        let mut bytes = Vec::with_capacity(len * size);
+        for i in 0..len {
+            let ops = std::iter::once(Push(IConst::U64(i as u64)))
+                .chain(ops.iter().cloned())
+                .collect::<Vec<_>>();
+            match self.const_eval(&ops) {
+                Ok(Either::Right(values)) => {
+                    bytes.extend_from_slice(&values[0].to_le_bytes()[..size]);
+                }
+                Err(EvalError::Trap(trap)) => {
+                    self.fail(span, trap);
+                    break;
+                }
+                _ => unreachable!(),
+            }
+        }
+        bytes.resize(len * size, 0);
+        self.table_bytes.insert(name.to_string(), bytes);
+    }
+
+    /// Lowers a block, followed by what it deferred unless it jumps out, which already ran it
     fn compile_body(&mut self, body: Vec<HirNode>) {
+        let jumps_out = jumps_out(&body);
+        self.defers.push(Vec::new());
+        self.compile_nodes(body);
+        let deferred = self.defers.pop().unwrap();
+        if !jumps_out {
+            for body in deferred.into_iter().rev() {
+                self.compile_body(body)
+            }
+        }
+    }
+
+    fn compile_nodes(&mut self, body: Vec<HirNode>) {
+        let outer = self.span.clone();
         for node in body {
+            if self.session.options.comments >= Comments::Spans {
+                self.span = node.span.some();
+            }
             match node.hir {
                 HirKind::Cond(cond) => self.compile_cond(cond),
                 HirKind::Return => {
-                    let num_bindings = self.bindings.iter().flatten().count();
-                    for _ in 0..num_bindings {
-                        self.emit(Unbind)
-                    }
+                    self.unwind(0, 0);
                     let i = self.local_vars_size;
                     self.emit(FreeLocals(i));
                     self.emit(Return)
                 }
                 HirKind::Literal(c) => match c {
                     IConst::Str(s) => {
-                        let i = self.strings.len();
-                        self.strings.push(s);
+                        let i = self.session.intern_str(s);
                         self.emit(PushStr(i));
                     }
+                    // unsuffixed literals are the same bits whatever type they were inferred as
+                    IConst::Int(n) => self.emit(Push(IConst::U64(n))),
                     _ => self.emit(Push(c)),
                 },
                 HirKind::Word(w) if self.is_const(&w) => {
@@ -353,6 +738,10 @@ impl Compiler {
                     self.compile_mem(&w);
                     self.emit(PushMem(w))
                 }
+                HirKind::Word(w) if self.is_table(&w) => {
+                    self.compile_table(&w);
+                    self.emit(PushTable(w))
+                }
                 HirKind::Word(w) if self.is_binding(&w) => {
                     let offset = self
                         .bindings
@@ -372,63 +761,186 @@ impl Compiler {
                     }
                 }
                 HirKind::Word(w) if self.is_gvar(&w) => self.emit(PushMem(w)),
+                HirKind::Word(w) if self.hosts.contains_key(&w) => {
+                    let (ins, outs) = self.hosts[&w];
+                    self.emit(CallHost(w, ins, outs))
+                }
                 HirKind::Word(w) => {
                     let mangled = self.mangle_table.get(&w).unwrap().clone();
                     self.emit(Call(mangled))
                 }
+                HirKind::ProcRef(p) => {
+                    let mangled = self.mangle_table[&p].clone();
+                    self.emit(PushProc(mangled))
+                }
+                HirKind::CallLike(callee) => {
+                    let (ins, outs) = match self.hosts.get(&callee) {
+                        Some(&arity) => arity,
+                        None => self.arities[&self.mangle_table[&callee]],
+                    };
+                    self.emit(CallIndirect(ins, outs))
+                }
                 HirKind::Intrinsic(i) => match i {
-                    Intrinsic::Drop => self.emit(Drop),
-                    Intrinsic::Dup => self.emit(Dup),
-                    Intrinsic::Swap => self.emit(Swap),
-                    Intrinsic::Over => self.emit(Over),
-
-                    Intrinsic::Cast(_) => (), // this is a noop
-
-                    Intrinsic::ReadU64 => self.emit(ReadU64),
-                    Intrinsic::ReadU8 => self.emit(ReadU8),
-                    Intrinsic::WriteU64 => self.emit(WriteU64),
-                    Intrinsic::WriteU8 => self.emit(WriteU8),
-
-                    Intrinsic::Add => self.emit(Add),
-                    Intrinsic::Sub => self.emit(Sub),
-                    Intrinsic::Divmod => self.emit(Divmod),
-                    Intrinsic::Mul => self.emit(Mul),
-
-                    Intrinsic::Eq => self.emit(Eq),
-                    Intrinsic::Ne => self.emit(Ne),
-                    Intrinsic::Lt => self.emit(Lt),
-                    Intrinsic::Le => self.emit(Le),
-                    Intrinsic::Gt => self.emit(Gt),
-                    Intrinsic::Ge => self.emit(Ge),
-
-                    Intrinsic::Dump => self.emit(Dump),
-                    Intrinsic::Print => self.emit(Print),
-
-                    Intrinsic::Syscall0 => self.emit(Syscall0),
-                    Intrinsic::Syscall1 => self.emit(Syscall1),
-                    Intrinsic::Syscall2 => self.emit(Syscall2),
-                    Intrinsic::Syscall3 => self.emit(Syscall3),
-                    Intrinsic::Syscall4 => self.emit(Syscall4),
-                    Intrinsic::Syscall5 => self.emit(Syscall5),
-                    Intrinsic::Syscall6 => self.emit(Syscall6),
-
-                    Intrinsic::Argc => self.emit(Argc),
-                    Intrinsic::Argv => self.emit(Argv),
+                    Intrinsic::Pick(depth) => self.emit(Pick(depth.unwrap())),
+                    Intrinsic::Roll(depth) => self.emit(Roll(depth.unwrap())),
+
+                    Intrinsic::Cast(ty) => self.wrap(ty),
+                    Intrinsic::Add(ty) | Intrinsic::Sub(ty) | Intrinsic::Mul(ty) => {
+                        self.emit(intrinsics::info_of(&i).unwrap().op.clone().unwrap());
+                        self.wrap(ty.unwrap());
+                    }
+                    Intrinsic::Not => {
+                        self.emit(Push(IConst::Bool(true)));
+                        self.emit(BitXor);
+                    }
+                    Intrinsic::BitNot(ty) => {
+                        let ty = ty.unwrap();
+                        self.emit(BitNot);
+                        // the complement of a sign extended integer still is
+                        if !ty.is_signed() {
+                            self.wrap(ty);
+                        }
+                    }
+                    Intrinsic::Shl(ty) => {
+                        self.emit(Shl);
+                        self.wrap(ty.unwrap());
+                    }
+                    Intrinsic::Shr(ty) if ty.unwrap().is_signed() => self.emit(Sar),
+                    Intrinsic::Shr(_) => self.emit(Shr),
+                    Intrinsic::Divmod(ty) if ty.unwrap().is_signed() => {
+                        let ty = ty.unwrap();
+                        self.emit(IDivmod);
+                        // only the quotient of the smallest value by -1 leaves the range
+                        if ty.narrow_bits().is_some() {
+                            self.emit(Swap);
+                            self.wrap(ty);
+                            self.emit(Swap);
+                        }
+                    }
+                    Intrinsic::Divmod(_) => self.emit(Divmod),
+                    Intrinsic::Lt(ty)
+                    | Intrinsic::Le(ty)
+                    | Intrinsic::Gt(ty)
+                    | Intrinsic::Ge(ty) => {
+                        let signed = ty.unwrap().is_signed();
+                        self.emit(match (i, signed) {
+                            (Intrinsic::Lt(_), true) => Lt,
+                            (Intrinsic::Le(_), true) => Le,
+                            (Intrinsic::Gt(_), true) => Gt,
+                            (Intrinsic::Ge(_), true) => Ge,
+                            (Intrinsic::Lt(_), false) => LtU,
+                            (Intrinsic::Le(_), false) => LeU,
+                            (Intrinsic::Gt(_), false) => GtU,
+                            (Intrinsic::Ge(_), false) => GeU,
+                            _ => unreachable!(),
+                        })
+                    }
+                    Intrinsic::Read(ty) => {
+                        self.emit(sized_read(ty));
+                        if ty.is_signed() {
+                            self.wrap(ty);
+                        }
+                    }
+                    Intrinsic::Write(ty) => self.emit(sized_write(ty)),
+
+                    // x86-64 is little endian, so only big endian accesses need a byte swap
+                    Intrinsic::ReadEndian(ty, endian) => {
+                        self.emit(sized_read(ty));
+                        if endian == Endian::Big {
+                            self.emit(sized_bswap(ty));
+                        }
+                    }
+                    Intrinsic::WriteEndian(ty, endian) => {
+                        if endian == Endian::Big {
+                            self.emit(Swap);
+                            self.emit(sized_bswap(ty));
+                            self.emit(Swap);
+                        }
+                        self.emit(sized_write(ty));
+                    }
+
+                    Intrinsic::CheckedSyscall(n) => {
+                        self.emit(match n {
+                            0 => Syscall0,
+                            1 => Syscall1,
+                            2 => Syscall2,
+                            3 => Syscall3,
+                            4 => Syscall4,
+                            5 => Syscall5,
+                            6 => Syscall6,
+                            n => unreachable!("No syscall with {} arguments", n),
+                        });
+                        self.emit(SplitErrno);
+                    }
+
+                    Intrinsic::HashStr => match self.result.last() {
+                        Some(&PushStr(i)) => {
+                            let hash = intrinsics::hash_str(self.session.strings()[i].as_bytes());
+                            self.result.pop();
+                            self.emit(Push(IConst::U64(hash)))
+                        }
+                        _ => self.emit(HashStr),
+                    },
+
+                    Intrinsic::ArgvAt => {
+                        let (scan, end) = (self.gen_label(), self.gen_label());
+                        self.emit(Push(IConst::U64(8)));
+                        self.emit(Mul);
+                        self.emit(Argv);
+                        self.emit(Add);
+                        self.emit(ReadU64);
+                        // the start stays below the pointer that goes looking for the nul
+                        self.emit(Dup);
+                        self.emit(Label(scan.clone()));
+                        self.emit(Dup);
+                        self.emit(ReadU8);
+                        self.emit(JumpF(end.clone()));
+                        self.emit(AddImm(1));
+                        self.emit(Jump(scan));
+                        self.emit(Label(end));
+                        self.emit(Over);
+                        self.emit(Sub);
+                    }
 
                     Intrinsic::CompStop => return,
+
+                    i => match intrinsics::info_of(&i).and_then(|info| info.op.clone()) {
+                        Some(op) => self.emit(op),
+                        None => unreachable!("No op for intrinsic {:?}", i),
+                    },
                 },
                 HirKind::If(cond) => self.compile_if(cond),
                 HirKind::While(while_) => self.compile_while(while_),
+                HirKind::Dip(body) => {
+                    self.emit(Bind);
+                    self.compile_stashed(body);
+                }
+                HirKind::Keep(body) => {
+                    self.emit(Dup);
+                    self.emit(Bind);
+                    self.compile_stashed(body);
+                }
                 HirKind::Bind(bind) => self.compile_bind(bind),
+                HirKind::Break | HirKind::Continue => {
+                    let loop_ = self.loops.last().unwrap();
+                    let target = match node.hir {
+                        HirKind::Break => loop_.end.clone(),
+                        _ => loop_.next.clone(),
+                    };
+                    self.unwind(loop_.defers, loop_.bindings);
+                    self.emit(Jump(target))
+                }
+                HirKind::Defer(body) => self.defers.last_mut().unwrap().push(body),
                 HirKind::IgnorePattern => unreachable!(), // this is a noop
                 HirKind::FieldAccess(f) => {
-                    let struct_ = &self.structs[f.ty.unwrap()];
+                    let struct_ = &self.session.structs[f.ty.unwrap()];
                     let offset = struct_.fields[&f.field].offset;
                     self.emit(Push(IConst::U64(offset as _)));
                     self.emit(Add);
                 }
             }
         }
+        self.span = outer;
     }
 
     fn compile_bind(&mut self, bind: Bind) {
@@ -440,6 +952,13 @@ impl Compiler {
                     new_bindings.push(name.clone());
                     self.emit(Bind)
                 }
+                Binding::Destructure { fields, kind } => {
+                    self.compile_destructure(fields, kind.unwrap());
+                    for field in fields.iter().rev() {
+                        new_bindings.push(field.clone());
+                        self.emit(Bind)
+                    }
+                }
             }
         }
         self.bindings.push(new_bindings);
@@ -448,22 +967,211 @@ impl Compiler {
             match binding {
                 Binding::Ignore => (),
                 Binding::Bind { name: _, ty: _ } => self.emit(Unbind),
+                Binding::Destructure { fields, kind: _ } => {
+                    for _ in fields {
+                        self.emit(Unbind)
+                    }
+                }
             }
         }
         self.bindings.pop();
     }
 
+    /// Replaces the destructured value on the stack with its fields, in pattern order
+    fn compile_destructure(&mut self, fields: &[String], kind: Destructure) {
+        match kind {
+            Destructure::Struct(s) => {
+                for field in fields {
+                    let field = &self.session.structs[s].fields[field];
+                    let (offset, size) = (field.offset, field.ty.size(&self.session.structs));
+                    self.emit(Dup);
+                    self.emit(Push(IConst::U64(offset as _)));
+                    self.emit(Add);
+                    self.emit(read_by_size(size));
+                    if let Some(bits) = field.bits {
+                        // to the top of the word and back down, copying the sign in if it has one
+                        self.emit(Push(IConst::U64((64 - bits.shift - bits.width) as _)));
+                        self.emit(Shl);
+                        self.emit(Push(IConst::U64((64 - bits.width) as _)));
+                        self.emit(if field.ty.is_signed() { Sar } else { Shr });
+                    }
+                    self.emit(Swap);
+                }
+                self.emit(Drop);
+            }
+            Destructure::Slice => match fields {
+                [len, ptr] if len == "len" && ptr == "ptr" => (),
+                [_, _] => self.emit(Swap),
+                [f] if f == "ptr" => {
+                    self.emit(Swap);
+                    self.emit(Drop);
+                }
+                [_] => self.emit(Drop),
+                _ => unreachable!(),
+            },
+        }
+    }
+
+    /// Compiles `body` with a value stashed in an unnameable binding, pushing it back afterwards
+    /// Runs the `defer`s of all but the outermost `defers` blocks and drops all but the outermost
+    /// `bindings`, as jumping out to there leaves them
+    fn unwind(&mut self, defers: usize, bindings: usize) {
+        // innermost first, while the bindings they use are still there
+        let deferred = self.defers[defers..].iter().flatten().rev().cloned();
+        for body in deferred.collect::<Vec<_>>() {
+            self.compile_body(body)
+        }
+        for _ in bindings..self.bindings.iter().flatten().count() {
+            self.emit(Unbind)
+        }
+    }
+
+    fn compile_stashed(&mut self, body: Vec<HirNode>) {
+        self.bindings.push(vec![String::new()]);
+        self.compile_body(body);
+        self.bindings.pop();
+        self.emit(UseBinding(0));
+        self.emit(Unbind);
+    }
+
     fn compile_while(&mut self, while_: While) {
+        // a copy of the body that jumps out may not reach the end, so it can't be counted
+        let leaves = leaves_loop(&while_.body);
+        let trips = match leaves {
+            true => None,
+            false => self.trip_count(&while_),
+        };
+        let factor = while_
+            .unroll
+            .or_else(|| trips.map(|t| t.clamp(1, UNROLL)))
+            .unwrap_or(1);
+        if let Some(trips) = trips.filter(|&t| t <= factor) {
+            // every iteration fits in one pass, so there is nothing to loop or check
+            for _ in 0..trips {
+                self.compile_body(while_.body.clone());
+            }
+            return;
+        }
+        // with a whole number of passes left only the first copy needs to check
+        let exact = matches!(trips, Some(t) if t % factor == 0);
         let cond_label = self.gen_label();
         let end_label = self.gen_label();
         self.emit(Label(cond_label.clone()));
-        self.compile_body(while_.cond);
-        self.emit(JumpF(end_label.clone()));
-        self.compile_body(while_.body);
+        for i in 0..factor {
+            if i == 0 || !exact {
+                self.compile_body(while_.cond.clone());
+                self.emit(JumpF(end_label.clone()));
+            }
+            // `continue` goes on with whatever follows this copy, the next check
+            let next = match leaves {
+                true => self.gen_label(),
+                false => cond_label.clone(),
+            };
+            self.loops.push(Loop {
+                next: next.clone(),
+                end: end_label.clone(),
+                bindings: self.bindings.iter().flatten().count(),
+                defers: self.defers.len(),
+            });
+            self.compile_body(while_.body.clone());
+            self.loops.pop();
+            if leaves {
+                self.emit(Label(next));
+            }
+        }
         self.emit(Jump(cond_label));
         self.emit(Label(end_label))
     }
 
+    /// Iterations of a loop counting up by one from a constant to a constant bound,
+    /// `start while dup end < do ... 1 + end`, if that is provably what `while_` is
+    fn trip_count(&mut self, while_: &While) -> Option<usize> {
+        #[derive(Clone, Copy, PartialEq)]
+        enum Slot {
+            Counter(u64),
+            Const(u64),
+            Unknown,
+        }
+        use Slot::*;
+
+        let start = match self.result.iter().rev().find(|op| !matches!(op, Loc(_))) {
+            Some(Push(IConst::U64(start))) => *start,
+            _ => return None,
+        };
+        // lowered only to be looked at, so the labels and locations used are given back
+        let (label, loc, len) = (self.label, self.loc.clone(), self.result.len());
+        self.compile_body(while_.cond.clone());
+        let cond = self.result.split_off(len);
+        self.compile_body(while_.body.clone());
+        let body = self.result.split_off(len);
+        (self.label, self.loc) = (label, loc);
+
+        let cond = cond
+            .into_iter()
+            .filter(|op| !matches!(op, Loc(_)))
+            .collect::<Vec<_>>();
+        // signed bounds count the same when neither is negative
+        let positive = |end: u64| start.max(end) <= i64::MAX as u64;
+        let end = match &cond[..] {
+            [Dup, Push(IConst::U64(end)), LtU] => *end,
+            [Dup, Push(IConst::U64(end)), LeU] => end.checked_add(1)?,
+            [Dup, Push(IConst::U64(end)), Lt] if positive(*end) => *end,
+            [Dup, Push(IConst::U64(end)), Le] if positive(*end) => end.checked_add(1)?,
+            _ => return None,
+        };
+        // what the body leaves on the stack, starting with the counter on top
+        let mut stack = vec![Counter(0)];
+        for op in &body {
+            let needs = match op {
+                Loc(_) => continue,
+                Dup | Drop => 1,
+                Swap | Over => 2,
+                Pick(depth) | Roll(depth) => depth + 1,
+                Proc(_) | Label(_) | Jump(_) | JumpF(_) | JumpT(_) | CmpImmBranch(..) | Return
+                | Exit => return None,
+                op => mir::effect(op, &self.arities).0,
+            };
+            while stack.len() < needs {
+                stack.insert(0, Unknown)
+            }
+            let top = stack.len().wrapping_sub(1);
+            match op {
+                Dup => stack.push(stack[top]),
+                Drop => {
+                    stack.pop();
+                }
+                Swap => stack.swap(top, top - 1),
+                Over => stack.push(stack[top - 1]),
+                Pick(depth) => stack.push(stack[top - depth]),
+                Roll(depth) => {
+                    let slot = stack.remove(top - depth);
+                    stack.push(slot)
+                }
+                Push(IConst::U64(n)) => stack.push(Const(*n)),
+                Add => {
+                    let sum = match (stack[top - 1], stack[top]) {
+                        (Counter(c), Const(n)) | (Const(n), Counter(c)) => {
+                            Counter(c.wrapping_add(n))
+                        }
+                        _ => Unknown,
+                    };
+                    stack.truncate(top - 1);
+                    stack.push(sum)
+                }
+                op => {
+                    let (pops, pushes) = mir::effect(op, &self.arities);
+                    stack.truncate(stack.len() - pops);
+                    stack.extend((0..pushes).map(|_| Unknown))
+                }
+            }
+        }
+        // the condition only looks at the top, whatever else the body does
+        match stack.last() {
+            Some(Counter(1)) => end.saturating_sub(start).try_into().ok(),
+            _ => None,
+        }
+    }
+
     fn compile_if(&mut self, if_: If) {
         let lie_label = self.gen_label();
         let mut end_label = None;
@@ -488,28 +1196,40 @@ impl Compiler {
         let num_branches = cond.branches.len() - 1;
         let mut this_branch_label = self.gen_label();
         let mut next_branch_label = self.gen_label();
+        let mut matched = Vec::new();
         for (i, CondBranch { pattern, body }) in cond.branches.into_iter().enumerate() {
             if i != 0 {
                 self.emit(Label(this_branch_label));
             }
 
-            self.emit(Dup);
-            match pattern.hir {
-                HirKind::Literal(c) => self.emit(Push(c)),
-                HirKind::Word(w) if self.is_const(&w) => {
-                    let c = self.compile_const(w)[0].clone();
-                    self.emit(Push(c))
-                }
-                HirKind::Word(w) => unreachable!("Impossible non-constant: {}", w),
-                HirKind::IgnorePattern => self.emit(Dup), // todo: this is hacky
-                _ => unreachable!(),
-            }
-            self.emit(Eq);
+            // the last branch is taken whatever the value is
             if i < num_branches {
+                self.emit(Dup);
+                let value = match pattern.hir {
+                    HirKind::Literal(IConst::Int(n)) => IConst::U64(n),
+                    HirKind::Literal(c) => c,
+                    HirKind::Word(w) if self.is_const(&w) => self.compile_const(w)[0].clone(),
+                    HirKind::Word(w) => unreachable!("Impossible non-constant: {}", w),
+                    _ => unreachable!(),
+                };
+                // the typechecker only sees literals, a const's value is known once it's evaluated
+                if matched.contains(&value) && self.failed.is_none() {
+                    self.failed = Error::Typecheck(TypecheckError::new(
+                        pattern.span,
+                        ErrorKind::InvalidCond,
+                        "Value is already matched by an earlier branch",
+                    ))
+                    .some();
+                }
+                matched.push(value.clone());
+                self.emit(Push(value));
+                self.emit(Eq);
                 self.emit(JumpF(next_branch_label.clone()));
             }
             this_branch_label = next_branch_label;
             next_branch_label = self.gen_label();
+            // the matched value is consumed, as the typechecker has it
+            self.emit(Drop);
             self.compile_body(body);
             self.emit(Jump(phi_label.clone()));
         }
@@ -518,6 +1238,20 @@ impl Compiler {
     }
 
     fn emit(&mut self, op: Op) {
+        // every block starts with its own `Loc`, so it stays right when blocks are moved
+        let label = matches!(op, Proc(_) | Label(_));
+        if !label && self.span != self.loc {
+            self.loc = self.span.clone();
+            self.result.extend(self.loc.clone().map(Loc));
+        }
+        if label
+            || matches!(
+                op,
+                Jump(_) | JumpF(_) | JumpT(_) | CmpImmBranch(..) | Return | Exit
+            )
+        {
+            self.loc = None;
+        }
         self.result.push(op)
     }
 
@@ -527,40 +1261,62 @@ impl Compiler {
         res
     }
 
-    pub fn new(structs: StructIndex) -> Self {
+    pub fn new(session: &'s Session) -> Self {
         Self {
             label: 0,
             mangle_table: Default::default(),
+            hosts: Default::default(),
+            arities: Default::default(),
+            pure: Default::default(),
             proc_id: 0,
             current_name: "".to_string(),
             result: Default::default(),
             consts: Default::default(),
-            strings: Default::default(),
             bindings: Default::default(),
+            defers: Default::default(),
+            loops: Default::default(),
             mems: Default::default(),
+            tables: Default::default(),
+            table_bytes: Default::default(),
+            depths: Default::default(),
+            mem_spans: Default::default(),
             vars: Default::default(),
             local_vars: Default::default(),
             local_vars_size: Default::default(),
             escaping_size: Default::default(),
-            structs,
+            span: None,
+            loc: None,
+            failed: None,
+            session,
         }
     }
-    fn with_consts_and_strings(consts: FnvHashMap<String, ComConst>, strings: Vec<String>) -> Self {
+    fn with_consts(consts: FnvHashMap<String, ComConst>, session: &'s Session) -> Self {
         Self {
             label: 0,
             mangle_table: Default::default(),
+            hosts: Default::default(),
+            arities: Default::default(),
+            pure: Default::default(),
             proc_id: 0,
             current_name: "".to_string(),
             result: Default::default(),
             consts,
-            strings,
             bindings: Default::default(),
+            defers: Default::default(),
+            loops: Default::default(),
             mems: Default::default(),
+            tables: Default::default(),
+            table_bytes: Default::default(),
+            depths: Default::default(),
+            mem_spans: Default::default(),
             vars: Default::default(),
             local_vars: Default::default(),
             local_vars_size: Default::default(),
             escaping_size: Default::default(),
-            structs: Default::default(),
+            span: None,
+            loc: None,
+            failed: None,
+            session,
         }
     }
 
@@ -588,10 +1344,633 @@ impl Compiler {
     fn is_mem(&self, w: &str) -> bool {
         self.mems.contains_key(w)
     }
+    fn is_table(&self, w: &str) -> bool {
+        self.tables.contains_key(w)
+    }
     fn is_gvar(&self, w: &str) -> bool {
         self.vars.contains_key(w)
     }
     fn is_lvar(&self, w: &str) -> bool {
         self.local_vars.contains_key(w)
     }
+
+    /// Brings a value computed as `ty` back into the range of `ty`, for integers narrower than
+    /// a stack slot
+    fn wrap(&mut self, ty: Type) {
+        if let Some(bits) = ty.narrow_bits() {
+            self.emit(if ty.is_signed() {
+                SignExtend(bits)
+            } else {
+                ZeroExtend(bits)
+            })
+        }
+    }
+}
+
+fn read_by_size(size: usize) -> Op {
+    match size {
+        8 => ReadU64,
+        4 => ReadU32,
+        2 => ReadU16,
+        1 => ReadU8,
+        size => unreachable!("No read op for {} bytes", size),
+    }
+}
+
+/// Loads of signed integers still need a `SignExtend`
+fn sized_read(ty: Type) -> Op {
+    match ty {
+        Type::U64 | Type::I64 => ReadU64,
+        Type::U32 | Type::I32 => ReadU32,
+        Type::U16 | Type::I16 => ReadU16,
+        Type::U8 | Type::I8 => ReadU8,
+        ty => unreachable!("No read op for {:?}", ty),
+    }
+}
+
+fn sized_write(ty: Type) -> Op {
+    match ty {
+        Type::U64 | Type::I64 => WriteU64,
+        Type::U32 | Type::I32 => WriteU32,
+        Type::U16 | Type::I16 => WriteU16,
+        Type::U8 | Type::I8 => WriteU8,
+        ty => unreachable!("No write op for {:?}", ty),
+    }
+}
+
+/// `value` with only its low `bits` kept, as `ZeroExtend` and `SignExtend` leave it
+pub fn extend(value: u64, bits: u32, signed: bool) -> u64 {
+    let unused = 64 - bits;
+    if signed {
+        ((value << unused) as i64 >> unused) as u64
+    } else {
+        value << unused >> unused
+    }
+}
+
+fn sized_bswap(ty: Type) -> Op {
+    match ty {
+        Type::U64 => Bswap64,
+        Type::U32 => Bswap32,
+        Type::U16 => Bswap16,
+        ty => unreachable!("No byte swap op for {:?}", ty),
+    }
+}
+
+/// Strips the `proc<id>_` prefix added by name mangling, for display only
+pub fn demangle(label: &str) -> &str {
+    label
+        .strip_prefix("proc")
+        .and_then(|l| l.split_once('_'))
+        .filter(|(id, _)| !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()))
+        .map_or(label, |(_, name)| name)
+}
+
+/// The ops as the LIR dumps print them, one numbered line each. Source locations are followed by
+/// how their node changed the typechecker's stack, `( before -- after )` without the bottom the
+/// two share. With `only_proc`, only the ops of the proc with that name are printed.
+pub fn dump(ops: &[Op], snapshots: &StackSnapshots, only_proc: Option<&str>) -> String {
+    let mut out = String::new();
+    let mut current = None;
+    for (i, op) in ops.iter().enumerate() {
+        if let Proc(label) = op {
+            current = demangle(label).some();
+        }
+        if only_proc.is_some() && current != only_proc {
+            continue;
+        }
+        write!(out, "{}:\t{:?}", i, op).unwrap();
+        if let Some((taken, left)) = match op {
+            Loc(span) => snapshots.effect(span),
+            _ => None,
+        } {
+            let types = |ts: Vec<Type>| ts.iter().map(|t| format!(" {:?}", t)).collect::<String>();
+            write!(out, "\t({} --{} )", types(taken), types(left)).unwrap();
+        }
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+use crate::eval::eval;
+
+/// Parses `src` as `test.rh`
+#[cfg(test)]
+pub(crate) fn parse_source(src: &str) -> Vec<crate::ast::TopLevel> {
+    use crate::{ast::parse_no_include, lexer::lex_string};
+    parse_no_include(lex_string(src.to_string(), "test.rh".into()).unwrap()).unwrap()
+}
+
+#[cfg(test)]
+pub(crate) fn typecheck_source(
+    src: &str,
+) -> Result<
+    (
+        FnvHashMap<String, TopLevel>,
+        Vec<crate::typecheck::TypecheckWarning>,
+    ),
+    Error,
+> {
+    typecheck_source_in(src, &Session::default())
+}
+
+/// Typechecks `src` as `test.rh`, keeping what the passes leave in `session`
+#[cfg(test)]
+pub(crate) fn typecheck_source_in(
+    src: &str,
+    session: &Session,
+) -> Result<
+    (
+        FnvHashMap<String, TopLevel>,
+        Vec<crate::typecheck::TypecheckWarning>,
+    ),
+    Error,
+> {
+    use crate::{hir::Walker, typecheck::Typechecker};
+    let items = parse_source(src);
+    let items = items.into_iter().map(|i| (i.name().unwrap(), i)).collect();
+    let hir = Walker::new(session).walk_ast(items).unwrap();
+    Typechecker::typecheck_program(hir, session)
+}
+
+#[cfg(test)]
+pub(crate) fn lower_source(src: &str) -> (Vec<Op>, FnvHashMap<String, usize>) {
+    let session = Session::default();
+    let (procs, _) = typecheck_source_in(src, &session).unwrap();
+    let program = Compiler::new(&session).compile(procs).unwrap();
+    (program.ops, program.mems)
+}
+
+#[test]
+fn test_local_vars() {
+    use somok::Either;
+    let (ops, _) = lower_source(
+        "
+        proc bump &>u64 do dup @u64 1 + swap !u64 end
+        proc main : u64 do
+            var x : u64
+            var y : u8
+            var return z : u64
+            40 x !u64 x bump x bump
+            7 y !u8
+            2 z !u64
+            x @u64 z @u64 + y @u8 cast u64 -
+        end",
+    );
+    assert!(ops.contains(&ReserveLocals(16)));
+    assert!(ops.contains(&ReserveEscaping(8)));
+    assert!(ops.contains(&PushEscaping(0)));
+    assert!(matches!(eval(ops, &[]), Ok(Either::Left(37))));
+}
+
+#[test]
+fn test_const_group() {
+    use somok::Either;
+    let (ops, _) = lower_source(
+        "
+        const W H D : u64 u64 u64 do 80 24 2 end
+        const AREA : u64 do W H * end
+        proc main : u64 do AREA D - H + end",
+    );
+    assert!(matches!(eval(ops, &[]), Ok(Either::Left(1942))));
+}
+
+#[test]
+fn test_const_trap() {
+    // whatever is evaluated at compile time fails at the item evaluating it
+    for (item, src) in [
+        (
+            "const X",
+            "const X : u64 do 1 0 divmod drop end proc main : u64 do X end",
+        ),
+        (
+            "mem buf",
+            "mem buf do 1 0 divmod drop end proc main : u64 do buf drop 0 end",
+        ),
+        (
+            "table t",
+            "table t : u64 [4] do 3 i - 4 swap divmod drop end proc main : u64 do t drop 0 end",
+        ),
+    ] {
+        let session = Session::default();
+        let (procs, _) = typecheck_source_in(src, &session).unwrap();
+        match Compiler::new(&session).compile(procs) {
+            Err(Error::ConstEval { span, trap }) => {
+                assert_eq!(span.start, src.find(item).unwrap());
+                assert!(
+                    trap.message.contains("Division by zero"),
+                    "{}",
+                    trap.message
+                );
+            }
+            res => panic!("{:?}", res.map(|_| ())),
+        }
+    }
+}
+
+#[test]
+fn test_bind() {
+    use somok::Either;
+    // `a` is shadowed inside, `_` drops without binding, the early return unbinds both scopes
+    let (ops, _) = lower_source(
+        "
+        proc pick3 u64 u64 u64 : u64 do
+            bind a: u64 _ c: u64 do
+                c 0 = if a return end
+                a c bind a: u64 b: u64 do a b - end
+            end
+        end
+        proc main : u64 do 100 1 7 pick3 5 6 0 pick3 + end",
+    );
+    let binds = ops.iter().filter(|op| **op == Bind).count();
+    let unbinds = ops.iter().filter(|op| **op == Unbind).count();
+    assert_eq!((binds, unbinds), (4, 2 + 4));
+    assert!(matches!(eval(ops, &[]), Ok(Either::Left(98))));
+}
+
+#[test]
+fn test_destructure() {
+    use crate::{
+        ast::{parse_no_include, TopLevel::Struct},
+        hir::Walker,
+        lexer::lex_string,
+        typecheck::{ErrorKind, Typechecker},
+        types::define_structs,
+    };
+    use somok::Either;
+
+    let lower = |src: &str| {
+        let tokens = lex_string(src.to_string(), "test.rh".into()).unwrap();
+        let (structs, items) = parse_no_include(tokens)
+            .unwrap()
+            .into_iter()
+            .map(|i| (i.name().unwrap(), i))
+            .partition::<FnvHashMap<_, _>, _>(|(_, i)| matches!(i, Struct(_)));
+        let session = Session::new(define_structs(structs), Default::default());
+        let hir = Walker::new(&session).walk_ast(items).unwrap();
+        let (procs, _) = Typechecker::typecheck_program(hir, &session)?;
+        Compiler::new(&session).compile(procs)
+    };
+    // fields are bound in pattern order, whatever order the struct declares them in
+    let ops = lower(
+        "
+        struct Point do x: u64 y: u32 end
+        proc main : u64 do
+            var p : Point
+            3 p ->x !u64
+            4 cast u32 p ->y !u32
+            p bind { y x } do x 10 * y cast u64 + end
+        end",
+    )
+    .unwrap()
+    .ops;
+    assert!(matches!(eval(ops, &[]), Ok(Either::Left(34))));
+
+    let res = lower(
+        "
+        struct Point do x: u64 y: u32 end
+        proc main : u64 do var p : Point p bind { z } do end 0 end",
+    );
+    assert!(matches!(
+        res,
+        Err(Error::Typecheck(e)) if matches!(&e.kind, ErrorKind::Undefined(f) if f == "z")
+    ));
+}
+
+#[test]
+fn test_mem() {
+    use crate::eval::Interpreter;
+    use somok::Either;
+    // the size is evaluated at compile time, consts included, and the mem is zeroed
+    let (ops, mems) = lower_source(
+        "
+        const WORDS : u64 do 2 3 * end
+        mem buffer do WORDS 8 * end
+        proc main : u64 do
+            7 buffer cast u64 8 + cast &>u64 !u64
+            buffer cast u64 8 + cast &>u64 @u64 buffer @u8 cast u64 +
+        end",
+    );
+    assert_eq!(mems.get("buffer"), Some(&48));
+    let res = Interpreter::new().mems(&mems).run(&ops, &[]);
+    assert!(matches!(res, Ok(Either::Left(7))));
+}
+
+#[test]
+fn test_argv_at() {
+    use crate::eval::Interpreter;
+    use somok::Either;
+    // the length of the second argument plus its first byte, plus the length of the first
+    let (ops, mems) = lower_source(
+        "
+        proc main : u64 do
+            1 argv-at swap cast &>u8 @u8 cast u64 +
+            0 argv-at swap drop +
+        end",
+    );
+    let res = Interpreter::new()
+        .mems(&mems)
+        .args(["prog", "hello"])
+        .run(&ops, &[]);
+    assert!(matches!(res, Ok(Either::Left(113))));
+}
+
+#[test]
+fn test_extern_mem() {
+    use crate::eval::Interpreter;
+    // an extern mem gets no room of its own, linking is the only way to define it
+    let src = "
+        extern mem buf : 4 8 * end
+        extern mem unused : 8 end
+        mem local do 8 end
+        proc main : u64 do buf @u8 cast u64 local @u8 cast u64 + end";
+    let session = Session::default();
+    let (procs, _) = typecheck_source_in(src, &session).unwrap();
+    let program = Compiler::new(&session).compile(procs).unwrap();
+    assert_eq!(program.mems.keys().collect::<Vec<_>>(), ["local"]);
+    assert_eq!(
+        program.extern_mems.into_iter().collect::<Vec<_>>(),
+        [("buf".to_string(), 32)]
+    );
+    let res = Interpreter::new()
+        .mems(&program.mems)
+        .run(&program.ops, &[]);
+    assert!(
+        matches!(res, Err(EvalError::Trap(t)) if t.message == "Memory `buf` is not defined here")
+    );
+}
+
+#[test]
+fn test_tables() {
+    use crate::eval::Interpreter;
+    use somok::Either;
+    // a table can read the tables before it, whatever order they come in
+    let src = "
+        table cubes : u64 [4] do squares cast u64 i 2 * + cast &>u16 @u16 cast u64 i * end
+        table squares : u16 [16] do i i * cast u16 end
+        table hex : char [16] do
+            i 10 < if '0' cast u64 i + else 'a' cast u64 i + 10 - end cast char
+        end
+        proc main : u64 do
+            squares cast u64 14 + cast &>u16 @u16 cast u64
+            hex cast u64 11 + cast &>u8 @u8 cast u64 +
+            cubes cast u64 24 + cast &>u64 @u64 +
+        end";
+    let session = Session::default();
+    let (procs, _) = typecheck_source_in(src, &session).unwrap();
+    let Program {
+        ops, mems, tables, ..
+    } = Compiler::new(&session).compile(procs).unwrap();
+    assert_eq!(tables["squares"].len(), 32);
+    assert_eq!(tables["squares"][14..16], [49, 0]);
+    assert_eq!(tables["hex"], b"0123456789abcdef");
+    let res = Interpreter::new()
+        .mems(&mems)
+        .tables(&tables)
+        .run(&ops, &session.strings());
+    assert!(matches!(res, Ok(Either::Left(174))));
+}
+
+#[test]
+fn test_unroll() {
+    use somok::Either;
+    // 8 trips take two checked passes of 4 copies, 3 trips need no loop at all, and the
+    // countdown has no known trip count, so both of its copies check
+    let (ops, _) = lower_source(
+        "
+        proc main : u64 do
+            0 0 while dup 8 < do swap over + swap 1 + end drop
+            0 while dup 3 < do 1 + end +
+            5 #[unroll(2)] while dup 0 != do 1 - end +
+        end",
+    );
+    let count = |f: fn(&Op) -> bool| ops.iter().filter(|op| f(op)).count();
+    assert_eq!(count(|op| matches!(op, JumpF(_))), 3);
+    assert_eq!(count(|op| matches!(op, Jump(_))), 2);
+    assert_eq!(count(|op| *op == Push(IConst::U64(1))), 4 + 3 + 2);
+    assert!(matches!(eval(ops, &[]), Ok(Either::Left(31))));
+}
+
+#[test]
+fn test_return() {
+    use somok::Either;
+    // every return unwinds the bindings and locals active where it is
+    let (ops, _) = lower_source(
+        "
+        proc find u64 : u64 do
+            0 while dup 10 < do
+                over over = if drop drop 100 return end
+                1 +
+            end drop drop 0
+        end
+        proc dipret u64 u64 : u64 do
+            dip dup 5 = if drop 7 return end end +
+        end
+        proc locret : u64 do
+            var x : u64
+            3 x !u64
+            x @u64 bind n : u64 do n 3 = if 9 return end end
+            0
+        end
+        proc main : u64 do 4 find 5 1 dipret + 3 2 dipret + locret + end",
+    );
+    assert!(matches!(eval(ops, &[]), Ok(Either::Left(121))));
+}
+
+#[test]
+fn test_defer() {
+    use crate::eval::Interpreter;
+    use somok::Either;
+    // each digit is a note, the returning call runs both defers of `f` and the other one also
+    // runs the one of its `bind` first, the loop body defers once per pass
+    let (ops, mems) = lower_source(
+        "
+        mem log do 8 end
+        proc note u64 do log cast &>u64 @u64 10 * + log cast &>u64 !u64 end
+        proc f bool : u64 do
+            defer 1 note end
+            defer 2 note end
+            if 3 note 7 return end
+            5 bind x : u64 do defer x note end end
+            9
+        end
+        proc main : u64 do
+            true f false f +
+            0 while dup 2 < do defer 4 note end 1 + end drop
+            16 = if log cast &>u64 @u64 else 0 end
+        end",
+    );
+    let res = Interpreter::new().mems(&mems).run(&ops, &[]);
+    assert!(matches!(res, Ok(Either::Left(32152144))));
+}
+
+#[test]
+fn test_dump() {
+    use crate::session::Options;
+    let src = "proc sq u64 : u64 do dup * end proc main : u64 do 3 sq end";
+    let options = Options::builder()
+        .comments(Comments::Spans)
+        .build()
+        .unwrap();
+    let session = Session::new(Default::default(), options);
+    let (procs, _) = typecheck_source_in(src, &session).unwrap();
+    let ops = Compiler::new(&session).compile(procs).unwrap().ops;
+
+    let sq = dump(&ops, &session.snapshots, Some("sq"));
+    assert!(sq.contains("Dup\n"));
+    assert!(sq.contains("\t( -- Primitive(U64) )\n"));
+    assert!(sq.contains("\t( Primitive(U64) -- )\n"));
+    assert!(!sq.contains("Push"));
+    let all = dump(&ops, &session.snapshots, None);
+    assert!(all.contains("Push(U64(3))"));
+    assert_eq!(all.lines().count(), ops.len());
+}
+
+#[test]
+fn test_sized_ints() {
+    use crate::eval::Interpreter;
+    use somok::Either;
+    // 200 + 100 wraps to 44, the stored 255 loads back as -1 and 70000 narrows to 4464
+    let (ops, mems) = lower_source(
+        "
+        mem buf do 8 end
+        proc main : u64 do
+            200 cast u8 100 + cast u64
+            255 cast i8 buf cast &>i8 !i8
+            buf cast &>i8 @i8 cast i64 1 cast i64 + cast u64 +
+            70000 cast u16 3 cast u8 + cast u64 +
+        end",
+    );
+    assert!(ops.contains(&ZeroExtend(8)) && ops.contains(&SignExtend(8)));
+    let res = Interpreter::new().mems(&mems).run(&ops, &[]);
+    assert!(matches!(res, Ok(Either::Left(4511))));
+}
+
+#[test]
+fn test_bitwise() {
+    use somok::Either;
+    let (ops, _) = lower_source(
+        "
+        const MASK : u64 do 1 4 shl 1 - end
+        proc main : u64 do
+            255 MASK band 16 bor 3 bxor
+            200 cast u8 bnot cast u64 +
+            0 8 - cast i64 1 shr 0 8 - cast i64 = if 0 else 1000 end +
+        end",
+    );
+    assert!(ops.contains(&Sar) && ops.contains(&ZeroExtend(8)));
+    assert!(matches!(eval(ops, &[]), Ok(Either::Left(1083))));
+}
+
+#[test]
+fn test_logic() {
+    use somok::Either;
+    let (ops, _) = lower_source(
+        "
+        const BOTH : bool do true false or true and end
+        proc main : u64 do
+            BOTH if 1 else 0 end
+            false not false and if 10 else 0 end +
+            false false or not if 100 else 0 end +
+        end",
+    );
+    assert!(matches!(eval(ops, &[]), Ok(Either::Left(101))));
+}
+
+#[test]
+fn test_signed_ops() {
+    use somok::Either;
+    let (ops, _) = lower_source(
+        "
+        proc main : u64 do
+            0 7 - cast i64 2 divmod
+            0 1 - cast i64 = if 1 else 0 end
+            swap 0 3 - cast i64 = if 10 else 0 end +
+            0 1 - 1 > if 100 else 0 end +
+            0 1 - cast i64 1 < if 1000 else 0 end +
+            0 128 - cast i8 0 1 - cast i8 divmod drop
+            cast i64 0 128 - cast i64 = if 10000 else 0 end +
+        end",
+    );
+    assert!(ops.contains(&IDivmod) && ops.contains(&GtU) && ops.contains(&Lt));
+    assert!(matches!(eval(ops, &[]), Ok(Either::Left(11111))));
+}
+
+#[test]
+fn test_proc_pointers() {
+    use crate::{eval::Interpreter, Error};
+    use somok::Either;
+    // the pointer survives a round trip through u64, and a proc can take its own address
+    let (ops, _) = lower_source(
+        "
+        proc square u64 : u64 do dup * end
+        proc apply u64 ptr(proc(u64 : u64)) : u64 do call-like square end
+        proc down u64 : u64 do
+            dup 0 = if return end
+            1 - &down call-like down
+        end
+        proc main : u64 do
+            5 &square apply
+            3 &square cast u64 cast ptr(proc(u64:u64)) apply +
+            4 &down apply +
+        end",
+    );
+    assert!(ops.iter().any(|op| matches!(op, PushProc(_))));
+    assert!(matches!(eval(ops, &[]), Ok(Either::Left(34))));
+
+    // calling `two` like `one` is caught when its pointer has its signature, and at runtime when
+    // checking a cast `ptr(proc)`
+    let src = |call: &str| {
+        format!(
+            "
+            proc one : u64 do 1 end
+            proc two : u64 u64 do 1 2 end
+            proc main : u64 do {call} call-like one end"
+        )
+    };
+    match typecheck_source(&src("&two")) {
+        Err(Error::Typecheck(e)) => assert_eq!(
+            e.message,
+            "`call-like one` needs a `ptr(proc(: u64))`, not a `ptr(proc(: u64 u64))`"
+        ),
+        r => panic!("{:?}", r.map(|_| ())),
+    }
+    let (ops, _) = lower_source(&src("&two cast ptr(proc)"));
+    assert!(matches!(eval(ops.clone(), &[]), Ok(Either::Left(2))));
+    assert!(matches!(
+        Interpreter::new().check_indirect().run(&ops, &[]),
+        Err(EvalError::Trap(t)) if t.message.contains("`call-like` signature")
+    ));
+}
+
+#[test]
+fn test_inline() {
+    use somok::Either;
+    // inlined locals and loops stay apart per copy, and a recursive proc still calls itself
+    let (ops, _) = lower_source(
+        "
+        #[inline] proc nip u64 u64 : u64 do swap drop end
+        #[inline] proc sum-to u64 : u64 do
+            var n : u64
+            n !u64
+            0 0 while dup n @u64 <= do
+                dup 5 = if drop drop 15 return end
+                swap over + swap 1 +
+            end drop
+        end
+        #[inline] proc fact u64 : u64 do
+            dup 1 <= if return end
+            dup 1 - fact *
+        end
+        proc main : u64 do
+            1 2 nip 3 sum-to + 9 sum-to + 4 fact +
+        end",
+    );
+    let called = |name: &str| {
+        ops.iter()
+            .any(|op| matches!(op, Call(p) if p.ends_with(name)))
+    };
+    assert!(!called("nip") && !called("sumto") && called("fact"));
+    assert!(matches!(eval(ops, &[]), Ok(Either::Left(47))));
 }
@@ -0,0 +1,80 @@
+use super::Op;
+use fnv::{FnvHashMap, FnvHashSet};
+
+/// Procs reachable from the entry point, through calls and through the proc pointers pushed
+/// by reachable code
+pub fn reachable_procs(ops: &[Op]) -> FnvHashSet<&str> {
+    let mut callees = FnvHashMap::<Option<&str>, Vec<&str>>::default();
+    let mut current = None;
+    for op in ops {
+        match op {
+            Op::Proc(name) => current = Some(name.as_str()),
+            Op::Call(p) | Op::PushProc(p) => callees.entry(current).or_default().push(p),
+            _ => (),
+        }
+    }
+    let mut reachable = FnvHashSet::default();
+    let mut work = callees.get(&None).cloned().unwrap_or_default();
+    while let Some(proc) = work.pop() {
+        if reachable.insert(proc) {
+            work.extend(callees.get(&Some(proc)).into_iter().flatten());
+        }
+    }
+    reachable
+}
+
+/// Removes the procs nothing reachable calls or points to, like those only called from code
+/// that was optimized away or whose every call was inlined
+pub fn eliminate_dead_procs(ops: Vec<Op>) -> Vec<Op> {
+    let reachable = reachable_procs(&ops)
+        .into_iter()
+        .map(str::to_string)
+        .collect::<FnvHashSet<_>>();
+    let mut live = true;
+    ops.into_iter()
+        .filter(|op| {
+            if let Op::Proc(name) = op {
+                live = reachable.contains(name);
+            }
+            live
+        })
+        .collect()
+}
+
+#[test]
+fn test_eliminate_dead_procs() {
+    use crate::iconst::IConst;
+    use Op::*;
+    let label = |l: &str| l.to_string();
+    let ops = vec![
+        Call(label("main")),
+        Exit,
+        Proc(label("dead")),
+        Call(label("helper")),
+        Return,
+        Proc(label("main")),
+        PushProc(label("callback")),
+        CallIndirect(0, 1),
+        Return,
+        Proc(label("callback")),
+        Call(label("callback")),
+        Call(label("helper")),
+        Return,
+        Proc(label("helper")),
+        Push(IConst::U64(0)),
+        Return,
+        Proc(label("recursive")),
+        Call(label("recursive")),
+        Return,
+    ];
+    let ops = eliminate_dead_procs(ops);
+    let procs = ops
+        .iter()
+        .filter_map(|op| match op {
+            Proc(name) => Some(name.as_str()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(procs, ["main", "callback", "helper"]);
+    assert_eq!(ops.len(), 13);
+}
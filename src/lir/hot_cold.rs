@@ -0,0 +1,208 @@
+use super::Op;
+use crate::iconst::IConst;
+use fnv::FnvHashSet;
+
+/// Number of the `exit` syscall
+const SYS_EXIT: u64 = 60;
+
+/// Moves cold code out of the way of hot code, cold blocks go after the rest of their proc
+/// and cold procs after every other proc. Procs in `cold` are cold, and so is every block
+/// that exits or calls a cold proc, as well as every proc that does so unconditionally.
+pub fn split_hot_cold(ops: Vec<Op>, cold: &FnvHashSet<String>) -> Vec<Op> {
+    let mut entry = Vec::new();
+    let mut procs = Vec::<(String, Vec<Op>)>::new();
+    for op in ops {
+        match op {
+            Op::Proc(name) => procs.push((name, Vec::new())),
+            op => match procs.last_mut() {
+                Some((_, body)) => body.push(op),
+                None => entry.push(op),
+            },
+        }
+    }
+    let procs = procs
+        .into_iter()
+        .map(|(name, body)| (name, blocks(body)))
+        .collect::<Vec<_>>();
+
+    let mut cold = cold.clone();
+    loop {
+        let before = cold.len();
+        for (name, blocks) in &procs {
+            if blocks.first().is_some_and(|b| is_cold(b, &cold)) {
+                cold.insert(name.clone());
+            }
+        }
+        if cold.len() == before {
+            break;
+        }
+    }
+
+    let (hot_procs, cold_procs) = procs
+        .into_iter()
+        .partition::<Vec<_>, _>(|(name, _)| !cold.contains(name));
+    let mut res = entry;
+    for (name, blocks) in hot_procs.into_iter().chain(cold_procs) {
+        let blocks = reorder(&name, blocks, &cold);
+        res.push(Op::Proc(name));
+        for block in blocks {
+            res.extend(block.label.map(Op::Label));
+            res.extend(block.ops);
+        }
+    }
+    res
+}
+
+/// Straight line code, entered only at the top and left only at the bottom
+#[derive(Debug, Default)]
+struct Block {
+    label: Option<String>,
+    ops: Vec<Op>,
+}
+
+impl Block {
+    fn falls_through(&self) -> bool {
+        !matches!(self.ops.last(), Some(Op::Jump(_) | Op::Return | Op::Exit))
+    }
+}
+
+fn blocks(ops: Vec<Op>) -> Vec<Block> {
+    let mut blocks = vec![Block::default()];
+    for op in ops {
+        match op {
+            Op::Label(l) => blocks.push(Block {
+                label: Some(l),
+                ops: Vec::new(),
+            }),
+            op => {
+                let ends = matches!(
+                    op,
+                    Op::Jump(_)
+                        | Op::JumpF(_)
+                        | Op::JumpT(_)
+                        | Op::CmpImmBranch(..)
+                        | Op::Return
+                        | Op::Exit
+                );
+                blocks.last_mut().unwrap().ops.push(op);
+                if ends {
+                    blocks.push(Block::default())
+                }
+            }
+        }
+    }
+    blocks.retain(|b| b.label.is_some() || !b.ops.is_empty());
+    blocks
+}
+
+fn is_cold(block: &Block, cold: &FnvHashSet<String>) -> bool {
+    let ops = block
+        .ops
+        .iter()
+        .filter(|op| !matches!(op, Op::Loc(_)))
+        .collect::<Vec<_>>();
+    ops.iter().any(|op| match op {
+        Op::Exit => true,
+        Op::Call(p) => cold.contains(p),
+        _ => false,
+    }) || ops
+        .windows(2)
+        .any(|w| matches!(w, [Op::Push(IConst::U64(SYS_EXIT)), Op::Syscall1]))
+}
+
+/// Moves the cold blocks of a proc after its hot ones, making the jumps
+/// between blocks that are no longer next to each other explicit
+fn reorder(proc: &str, mut blocks: Vec<Block>, cold: &FnvHashSet<String>) -> Vec<Block> {
+    // the first block is where the proc is entered
+    let moved = blocks
+        .iter()
+        .enumerate()
+        .map(|(i, b)| i != 0 && is_cold(b, cold))
+        .collect::<Vec<_>>();
+    if !moved.contains(&true) {
+        return blocks;
+    }
+
+    let mut fresh = 0;
+    for i in 1..blocks.len() {
+        if moved[i - 1] == moved[i] || !blocks[i - 1].falls_through() {
+            continue;
+        }
+        let label = blocks[i]
+            .label
+            .get_or_insert_with(|| {
+                fresh += 1;
+                format!(".{}_cold_{}", proc, fresh - 1)
+            })
+            .clone();
+        blocks[i - 1].ops.push(Op::Jump(label));
+    }
+
+    let (hot, cold) = blocks
+        .into_iter()
+        .zip(moved)
+        .partition::<Vec<_>, _>(|(_, moved)| !moved);
+    let mut blocks = hot
+        .into_iter()
+        .chain(cold)
+        .map(|(b, _)| b)
+        .collect::<Vec<_>>();
+
+    // `JumpF(next) Jump(cold)` branches on the opposite condition instead
+    for i in 1..blocks.len() {
+        let next = blocks[i].label.clone();
+        let ops = &mut blocks[i - 1].ops;
+        let inverted = match &ops[..] {
+            [.., Op::JumpF(f), Op::Jump(t)] if Some(f) == next.as_ref() => Op::JumpT(t.clone()),
+            [.., Op::CmpImmBranch(cmp, k, f), Op::Jump(t)] if Some(f) == next.as_ref() => {
+                Op::CmpImmBranch(cmp.negate(), *k, t.clone())
+            }
+            _ => continue,
+        };
+        ops.truncate(ops.len() - 2);
+        ops.push(inverted);
+    }
+    blocks
+}
+
+#[test]
+fn test_split_hot_cold() {
+    use Op::*;
+    let label = |l: &str| l.to_string();
+    let ops = vec![
+        Call(label("main")),
+        Exit,
+        Proc(label("die")),
+        Push(IConst::U64(SYS_EXIT)),
+        Syscall1,
+        Return,
+        Proc(label("main")),
+        Dup,
+        JumpF(label(".main_0")),
+        Call(label("die")),
+        Label(label(".main_0")),
+        Push(IConst::U64(0)),
+        Return,
+    ];
+    let ops = split_hot_cold(ops, &Default::default());
+    assert_eq!(
+        ops,
+        vec![
+            Call(label("main")),
+            Exit,
+            Proc(label("main")),
+            Dup,
+            JumpT(label(".main_cold_0")),
+            Label(label(".main_0")),
+            Push(IConst::U64(0)),
+            Return,
+            Label(label(".main_cold_0")),
+            Call(label("die")),
+            Jump(label(".main_0")),
+            Proc(label("die")),
+            Push(IConst::U64(SYS_EXIT)),
+            Syscall1,
+            Return,
+        ]
+    );
+}
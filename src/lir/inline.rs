@@ -0,0 +1,279 @@
+use super::Op;
+use crate::span::Span;
+use fnv::{FnvHashMap, FnvHashSet};
+use somok::Somok;
+
+/// Most ops a proc can have for `small_procs`
+pub const INLINE_OPS: usize = 8;
+
+/// Procs without branches or locals of at most `INLINE_OPS` ops, not counting their return and
+/// source locations, whose calls cost about as much as their bodies. `main` is never one.
+pub fn small_procs(ops: &[Op]) -> FnvHashSet<String> {
+    let mut small = FnvHashSet::default();
+    let mut current = None::<(&str, usize)>;
+    for op in ops {
+        current = match (op, current) {
+            (Op::Proc(name), _) => (name.as_str(), 0).some(),
+            (Op::Return, Some((name, len))) => {
+                if name != "main" && len <= INLINE_OPS {
+                    small.insert(name.to_string());
+                }
+                None
+            }
+            (Op::Loc(_) | Op::ReserveLocals(0) | Op::ReserveEscaping(0) | Op::FreeLocals(0), c) => {
+                c
+            }
+            (
+                Op::Label(_)
+                | Op::Jump(_)
+                | Op::JumpF(_)
+                | Op::JumpT(_)
+                | Op::CmpImmBranch(..)
+                | Op::ReserveLocals(_)
+                | Op::ReserveEscaping(_)
+                | Op::Exit,
+                _,
+            ) => None,
+            (_, Some((name, len))) => (name, len + 1).some(),
+            (_, None) => None,
+        };
+    }
+    small
+}
+
+/// Procs with exactly one call in `ops` and no pointer taken to them, not counting calls from
+/// their own body. `main` is never one.
+pub fn called_once(ops: &[Op]) -> FnvHashSet<String> {
+    let mut calls = FnvHashMap::<&str, usize>::default();
+    let mut pointed = FnvHashSet::default();
+    let mut current = None;
+    for op in ops {
+        match op {
+            Op::Proc(name) => current = name.as_str().some(),
+            Op::Call(p) if current != Some(p.as_str()) => *calls.entry(p).or_default() += 1,
+            Op::PushProc(p) => {
+                pointed.insert(p.as_str());
+            }
+            _ => (),
+        }
+    }
+    calls
+        .into_iter()
+        .filter(|(p, n)| *n == 1 && *p != "main" && !pointed.contains(p))
+        .map(|(p, _)| p.to_string())
+        .collect()
+}
+
+/// Replaces every call of the procs in `inline` with a copy of their body, whose labels are
+/// renamed to stay unique and whose returns jump past its end. Calls of a proc from inside its
+/// own copies are left as they are. Procs in `inline` that are not called or pointed to from
+/// anywhere but their own body are removed. The values of a copy stay on the stack of its caller,
+/// whose depth in `depths` grows by that of the deepest copy in it.
+pub fn inline_procs(
+    ops: Vec<Op>,
+    inline: &FnvHashSet<String>,
+    depths: &mut FnvHashMap<String, usize>,
+) -> Vec<Op> {
+    if inline.is_empty() {
+        return ops;
+    }
+    let mut entry = Vec::new();
+    let mut procs = Vec::<(String, Vec<Op>)>::new();
+    for op in ops {
+        match op {
+            Op::Proc(name) => procs.push((name, Vec::new())),
+            op => match procs.last_mut() {
+                Some((_, body)) => body.push(op),
+                None => entry.push(op),
+            },
+        }
+    }
+    let bodies = procs
+        .iter()
+        .filter(|(name, _)| inline.contains(name))
+        .cloned()
+        .collect::<FnvHashMap<_, _>>();
+
+    let mut inliner = Inliner {
+        bodies: &bodies,
+        depths,
+        copies: 0,
+    };
+    let (entry, _) = inliner.expand(entry, &mut Vec::new());
+    let procs = procs
+        .into_iter()
+        .map(|(name, body)| {
+            let (body, deeper) = inliner.expand(body, &mut vec![name.clone()]);
+            (name, body, deeper)
+        })
+        .collect::<Vec<_>>();
+    let procs = procs
+        .into_iter()
+        .map(|(name, body, deeper)| {
+            *depths.get_mut(&name).unwrap() += deeper;
+            (name, body)
+        })
+        .collect::<Vec<_>>();
+
+    let referenced = entry
+        .iter()
+        .map(|op| (None, op))
+        .chain(
+            procs
+                .iter()
+                .flat_map(|(name, body)| body.iter().map(move |op| (Some(name), op))),
+        )
+        .filter_map(|(from, op)| match op {
+            Op::Call(p) | Op::PushProc(p) if from != Some(p) => p.clone().some(),
+            _ => None,
+        })
+        .collect::<FnvHashSet<_>>();
+    let mut res = entry;
+    for (name, body) in procs {
+        if inline.contains(&name) && !referenced.contains(&name) {
+            continue;
+        }
+        res.push(Op::Proc(name));
+        res.extend(body);
+    }
+    res
+}
+
+struct Inliner<'a> {
+    bodies: &'a FnvHashMap<String, Vec<Op>>,
+    depths: &'a FnvHashMap<String, usize>,
+    /// Copies made so far, numbering the labels of the next one
+    copies: usize,
+}
+
+impl Inliner<'_> {
+    /// `ops` with the calls of inline procs not in `open`, the procs being copied, replaced, and
+    /// the most values a copy pushes on top of its inputs
+    fn expand(&mut self, ops: Vec<Op>, open: &mut Vec<String>) -> (Vec<Op>, usize) {
+        let mut res = Vec::with_capacity(ops.len());
+        let mut deepest = 0;
+        let mut loc = None::<Span>;
+        for op in ops {
+            let callee = match op {
+                Op::Call(p) if self.bodies.contains_key(&p) && !open.contains(&p) => p,
+                Op::Loc(span) => {
+                    loc = span.clone().some();
+                    res.push(Op::Loc(span));
+                    continue;
+                }
+                op => {
+                    res.push(op);
+                    continue;
+                }
+            };
+            open.push(callee.clone());
+            let (body, deeper) = self.expand(self.bodies[&callee].clone(), open);
+            open.pop();
+            deepest = deepest.max(self.depths[&callee] + deeper);
+            let copy = self.copy(&callee, body);
+            // the ops after the copy are still those of the caller
+            let moved = copy.iter().any(|op| matches!(op, Op::Loc(_)));
+            res.extend(copy);
+            if moved {
+                res.extend(loc.clone().map(Op::Loc));
+            }
+        }
+        (res, deepest)
+    }
+
+    /// `body` of `proc` renamed for its next copy, falling through where it returned
+    fn copy(&mut self, proc: &str, mut body: Vec<Op>) -> Vec<Op> {
+        let n = self.copies;
+        self.copies += 1;
+        let rename = |label: String| format!("{}_{}", label, n);
+        let end = format!(".{}_inline_{}", proc, n);
+        if matches!(body.last(), Some(Op::Return)) {
+            body.pop();
+        }
+        let mut returns = false;
+        let mut res = body
+            .into_iter()
+            .map(|op| match op {
+                Op::Label(l) => Op::Label(rename(l)),
+                Op::Jump(l) => Op::Jump(rename(l)),
+                Op::JumpF(l) => Op::JumpF(rename(l)),
+                Op::JumpT(l) => Op::JumpT(rename(l)),
+                Op::CmpImmBranch(cmp, k, l) => Op::CmpImmBranch(cmp, k, rename(l)),
+                Op::Return => {
+                    returns = true;
+                    Op::Jump(end.clone())
+                }
+                op => op,
+            })
+            .collect::<Vec<_>>();
+        if returns {
+            res.push(Op::Label(end));
+        }
+        res
+    }
+}
+
+#[test]
+fn test_inline_procs() {
+    use crate::iconst::IConst;
+    use Op::*;
+    let label = |l: &str| l.to_string();
+    let ops = vec![
+        Call(label("main")),
+        Exit,
+        Proc(label("nip")),
+        Swap,
+        Drop,
+        Return,
+        Proc(label("pos")),
+        Dup,
+        JumpT(label(".pos_0")),
+        Drop,
+        Push(IConst::U64(1)),
+        Return,
+        Label(label(".pos_0")),
+        Return,
+        Proc(label("main")),
+        Push(IConst::U64(0)),
+        Push(IConst::U64(2)),
+        Call(label("nip")),
+        Call(label("pos")),
+        Call(label("pos")),
+        Return,
+    ];
+    assert_eq!(called_once(&ops), [label("nip")].into_iter().collect());
+    let inline = ["nip", "pos"].into_iter().map(label).collect();
+    let mut depths = [("nip", 0), ("pos", 1), ("main", 2)]
+        .into_iter()
+        .map(|(p, d)| (label(p), d))
+        .collect();
+    assert_eq!(
+        inline_procs(ops, &inline, &mut depths),
+        vec![
+            Call(label("main")),
+            Exit,
+            Proc(label("main")),
+            Push(IConst::U64(0)),
+            Push(IConst::U64(2)),
+            Swap,
+            Drop,
+            Dup,
+            JumpT(label(".pos_0_1")),
+            Drop,
+            Push(IConst::U64(1)),
+            Jump(label(".pos_inline_1")),
+            Label(label(".pos_0_1")),
+            Label(label(".pos_inline_1")),
+            Dup,
+            JumpT(label(".pos_0_2")),
+            Drop,
+            Push(IConst::U64(1)),
+            Jump(label(".pos_inline_2")),
+            Label(label(".pos_0_2")),
+            Label(label(".pos_inline_2")),
+            Return,
+        ]
+    );
+    // the `dup` of a copy of `pos` goes on top of the two values of `main`
+    assert_eq!(depths["main"], 3);
+}
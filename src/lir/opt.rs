@@ -0,0 +1,392 @@
+use super::{extend, Op};
+use crate::iconst::IConst;
+use fnv::{FnvHashMap, FnvHashSet};
+use somok::Somok;
+
+/// Peepholes run by `optimize`, each can be picked on its own
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OptLevel {
+    /// Operations on constants become their result, like `Push 1 Push 2 Add` becoming `Push 3`,
+    /// and branches on constants become jumps or nothing
+    pub fold: bool,
+    /// Values pushed only to be dropped, like `Push x Drop`, are not pushed
+    pub drops: bool,
+    /// `Swap Swap` is removed
+    pub swaps: bool,
+    /// Jumps to jumps go straight to the last target, and jumps to the next op are removed
+    pub thread_jumps: bool,
+    /// Code after a `Jump`, `Return` or `Exit` that nothing jumps to is removed
+    pub unreachable: bool,
+}
+
+impl OptLevel {
+    pub const NONE: Self = Self {
+        fold: false,
+        drops: false,
+        swaps: false,
+        thread_jumps: false,
+        unreachable: false,
+    };
+    pub const ALL: Self = Self {
+        fold: true,
+        drops: true,
+        swaps: true,
+        thread_jumps: true,
+        unreachable: true,
+    };
+
+    /// The peepholes of an `Options::opt_level`, none at 0 and all of them from 1 on
+    pub fn from_level(level: u8) -> Self {
+        if level == 0 {
+            Self::NONE
+        } else {
+            Self::ALL
+        }
+    }
+}
+
+/// The passes of an `Options::opt_level` besides its peepholes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Pipeline {
+    /// Procs are lowered through the MIR, folding constants and removing dead code
+    pub mir: bool,
+    /// Code of a loop computing the same value on every pass moves in front of it, in the MIR
+    pub licm: bool,
+    /// A comparison with a constant and the branch on it become a `CmpImmBranch`, in the MIR
+    pub fuse: bool,
+    /// Procs of at most `inline::INLINE_OPS` ops without branches are inlined like `#[inline]`
+    /// ones
+    pub inline: bool,
+    /// Procs `inline` would take that have a single call are merged into their caller
+    pub merge_once: bool,
+    /// Procs are laid out after their first caller, see `order::order_procs`
+    pub order: bool,
+    /// NASM output keeps the top of the data stack in registers
+    pub register_cache: bool,
+}
+
+impl Pipeline {
+    /// Straight lowering at 0, the MIR with its folding and dead code removal, merging of
+    /// procs called once and call order layout at 1, and everything from 2 on
+    pub fn preset(level: u8) -> Self {
+        let o1 = Self {
+            mir: true,
+            merge_once: true,
+            order: true,
+            ..Self::default()
+        };
+        match level {
+            0 => Self::default(),
+            1 => o1,
+            _ => Self {
+                licm: true,
+                fuse: true,
+                inline: true,
+                register_cache: true,
+                ..o1
+            },
+        }
+    }
+
+    /// Names of the passes this runs with `peepholes`, in the order they run
+    pub fn names(&self, peepholes: OptLevel) -> Vec<&'static str> {
+        let passes = [
+            (self.mir, "mir-fold"),
+            (self.mir, "mir-dce"),
+            (self.mir && self.licm, "licm"),
+            (self.mir && self.fuse, "fuse-compare"),
+            (self.inline, "inline-small"),
+            (self.merge_once && !self.inline, "merge-once"),
+            (true, "inline"),
+            (self.order, "order-procs"),
+            (true, "hot-cold"),
+            (peepholes.fold, "fold"),
+            (peepholes.drops, "drops"),
+            (peepholes.swaps, "swaps"),
+            (peepholes.thread_jumps, "thread-jumps"),
+            (peepholes.unreachable, "unreachable"),
+            (true, "dead-procs"),
+            (self.register_cache, "register-cache"),
+        ];
+        passes
+            .into_iter()
+            .filter(|(run, _)| *run)
+            .map(|(_, name)| name)
+            .collect()
+    }
+}
+
+/// Runs the peepholes of `level` over every proc of `ops` until they change nothing more
+pub fn optimize(ops: Vec<Op>, level: OptLevel) -> Vec<Op> {
+    if level == OptLevel::NONE {
+        return ops;
+    }
+    let mut segments = vec![Vec::new()];
+    for op in ops {
+        if matches!(op, Op::Proc(_)) {
+            segments.push(Vec::new());
+        }
+        segments.last_mut().unwrap().push(op);
+    }
+    segments
+        .into_iter()
+        .flat_map(|mut ops| loop {
+            let before = ops.len();
+            if level.fold || level.drops || level.swaps {
+                ops = peepholes(ops, level);
+            }
+            if level.thread_jumps {
+                ops = thread_jumps(ops);
+            }
+            if level.unreachable {
+                ops = remove_unreachable(ops);
+            }
+            // threading follows whole chains, so a round that removes nothing is the last
+            if ops.len() == before {
+                break ops;
+            }
+        })
+        .collect()
+}
+
+/// Folds, drops and swaps, matched against the ops kept so far so that they cascade
+fn peepholes(ops: Vec<Op>, level: OptLevel) -> Vec<Op> {
+    let mut res = Vec::<Op>::with_capacity(ops.len());
+    for op in ops {
+        if level.fold {
+            let folded = match &res[..] {
+                [.., Op::Push(a), Op::Push(b)] => fold_binary(&op, a, b).map(|c| (2, c)),
+                _ => None,
+            }
+            .or_else(|| match res.last() {
+                Some(Op::Push(a)) => fold_unary(&op, a).map(|c| (1, c)),
+                _ => None,
+            });
+            if let Some((popped, c)) = folded {
+                res.truncate(res.len() - popped);
+                res.push(Op::Push(c));
+                continue;
+            }
+            // a branch on a constant always or never jumps
+            if let ([.., Op::Push(IConst::Bool(b))], Op::JumpT(l) | Op::JumpF(l)) = (&res[..], &op)
+            {
+                let jumps = *b == matches!(op, Op::JumpT(_));
+                let l = l.clone();
+                res.pop();
+                if jumps {
+                    res.push(Op::Jump(l));
+                }
+                continue;
+            }
+        }
+        match (res.last(), &op) {
+            (
+                Some(Op::Push(_) | Op::PushStr(_) | Op::PushMem(_) | Op::PushTable(_))
+                | Some(Op::PushProc(_) | Op::Dup | Op::Over),
+                Op::Drop,
+            ) if level.drops => {
+                res.pop();
+            }
+            (Some(Op::Swap), Op::Swap) if level.swaps => {
+                res.pop();
+            }
+            _ => res.push(op),
+        }
+    }
+    res
+}
+
+fn word(c: &IConst) -> Option<u64> {
+    match c {
+        IConst::Bool(b) => (*b as u64).some(),
+        IConst::Char(c) => (*c as u64).some(),
+        IConst::U64(u) | IConst::Int(u) | IConst::Ptr(u) => (*u).some(),
+        IConst::I64(i) => (*i as u64).some(),
+        IConst::Str(_) => None,
+    }
+}
+
+/// `op` on `a` and `b`, `b` being the top, unless it is not an operation on two values
+/// or one that can fail
+fn fold_binary(op: &Op, a: &IConst, b: &IConst) -> Option<IConst> {
+    let (a, b) = (word(a)?, word(b)?);
+    let (sa, sb) = (a as i64, b as i64);
+    match op {
+        Op::Add => IConst::U64(a.wrapping_add(b)),
+        Op::Sub => IConst::U64(a.wrapping_sub(b)),
+        Op::Mul => IConst::U64(a.wrapping_mul(b)),
+        Op::BitAnd => IConst::U64(a & b),
+        Op::BitOr => IConst::U64(a | b),
+        Op::BitXor => IConst::U64(a ^ b),
+        Op::Shl => IConst::U64(a.wrapping_shl(b as u32)),
+        Op::Shr => IConst::U64(a.wrapping_shr(b as u32)),
+        Op::Sar => IConst::U64(sa.wrapping_shr(b as u32) as u64),
+        Op::Eq => IConst::Bool(a == b),
+        Op::Ne => IConst::Bool(a != b),
+        Op::Lt => IConst::Bool(sa < sb),
+        Op::Le => IConst::Bool(sa <= sb),
+        Op::Gt => IConst::Bool(sa > sb),
+        Op::Ge => IConst::Bool(sa >= sb),
+        Op::LtU => IConst::Bool(a < b),
+        Op::LeU => IConst::Bool(a <= b),
+        Op::GtU => IConst::Bool(a > b),
+        Op::GeU => IConst::Bool(a >= b),
+        _ => return None,
+    }
+    .some()
+}
+
+fn fold_unary(op: &Op, a: &IConst) -> Option<IConst> {
+    let a = word(a)?;
+    match op {
+        Op::BitNot => IConst::U64(!a),
+        Op::AddImm(k) => IConst::U64(a.wrapping_add(*k)),
+        Op::SubImm(k) => IConst::U64(a.wrapping_sub(*k)),
+        Op::ZeroExtend(bits) => IConst::U64(extend(a, *bits, false)),
+        Op::SignExtend(bits) => IConst::U64(extend(a, *bits, true)),
+        _ => return None,
+    }
+    .some()
+}
+
+/// The first op at or after `i` that does something, skipping labels and locations
+fn next_op(ops: &[Op], i: usize) -> Option<&Op> {
+    ops[i..]
+        .iter()
+        .find(|op| !matches!(op, Op::Label(_) | Op::Loc(_)))
+}
+
+fn thread_jumps(ops: Vec<Op>) -> Vec<Op> {
+    let mut leads_to = FnvHashMap::default();
+    for (i, op) in ops.iter().enumerate() {
+        if let (Op::Label(l), Some(Op::Jump(to))) = (op, next_op(&ops, i + 1)) {
+            leads_to.insert(l.clone(), to.clone());
+        }
+    }
+    let thread = |mut l: String| {
+        let mut seen = FnvHashSet::default();
+        while let Some(to) = leads_to.get(&l) {
+            // a loop of jumps has no last target
+            if !seen.insert(l) {
+                return to.clone();
+            }
+            l = to.clone();
+        }
+        l
+    };
+    let ops = ops
+        .into_iter()
+        .map(|op| match op {
+            Op::Jump(l) => Op::Jump(thread(l)),
+            Op::JumpF(l) => Op::JumpF(thread(l)),
+            Op::JumpT(l) => Op::JumpT(thread(l)),
+            Op::CmpImmBranch(cmp, k, l) => Op::CmpImmBranch(cmp, k, thread(l)),
+            op => op,
+        })
+        .collect::<Vec<_>>();
+
+    let falls_through = |i: usize, l: &String| {
+        ops[i + 1..]
+            .iter()
+            .take_while(|op| matches!(op, Op::Label(_) | Op::Loc(_)))
+            .any(|op| matches!(op, Op::Label(m) if m == l))
+    };
+    ops.iter()
+        .enumerate()
+        .filter(|(i, op)| !matches!(op, Op::Jump(l) if falls_through(*i, l)))
+        .map(|(_, op)| op.clone())
+        .collect()
+}
+
+fn remove_unreachable(ops: Vec<Op>) -> Vec<Op> {
+    let targets = ops
+        .iter()
+        .filter_map(|op| match op {
+            Op::Jump(l) | Op::JumpF(l) | Op::JumpT(l) | Op::CmpImmBranch(_, _, l) => {
+                l.clone().some()
+            }
+            _ => None,
+        })
+        .collect::<FnvHashSet<_>>();
+    let mut reachable = true;
+    ops.into_iter()
+        .filter(|op| {
+            match op {
+                Op::Proc(_) => reachable = true,
+                Op::Label(l) if targets.contains(l) => reachable = true,
+                _ => (),
+            }
+            // locations say where the following code comes from, even past dead code
+            let keep = reachable || matches!(op, Op::Loc(_));
+            if matches!(op, Op::Jump(_) | Op::Return | Op::Exit) {
+                reachable = false;
+            }
+            keep
+        })
+        .collect()
+}
+
+#[test]
+fn test_optimize() {
+    use crate::eval::eval;
+    use somok::Either;
+    use Op::*;
+    let label = |l: &str| l.to_string();
+    let ops = vec![
+        Call(label("main")),
+        Exit,
+        Proc(label("main")),
+        Push(IConst::U64(250)),
+        Push(IConst::U64(10)),
+        Add,
+        ZeroExtend(8),
+        Push(IConst::U64(7)),
+        Drop,
+        Push(IConst::U64(1)),
+        Swap,
+        Swap,
+        Drop,
+        Dup,
+        Push(IConst::U64(4)),
+        Eq,
+        JumpT(label(".main_0")),
+        Push(IConst::U64(1)),
+        Add,
+        Return,
+        Push(IConst::U64(2)),
+        Label(label(".main_0")),
+        Push(IConst::U64(3)),
+        Push(IConst::U64(3)),
+        Eq,
+        JumpT(label(".main_1")),
+        Push(IConst::U64(9)),
+        Label(label(".main_1")),
+        Return,
+    ];
+    let optimized = optimize(ops.clone(), OptLevel::ALL);
+    assert_eq!(
+        optimized,
+        vec![
+            Call(label("main")),
+            Exit,
+            Proc(label("main")),
+            Push(IConst::U64(4)),
+            Dup,
+            Push(IConst::U64(4)),
+            Eq,
+            JumpT(label(".main_1")),
+            Push(IConst::U64(1)),
+            Add,
+            Return,
+            Label(label(".main_1")),
+            Return,
+        ]
+    );
+    assert!(matches!(eval(optimized, &[]), Ok(Either::Left(4))));
+    assert_eq!(optimize(ops.clone(), OptLevel::NONE), ops);
+    let swaps = OptLevel {
+        swaps: true,
+        ..OptLevel::NONE
+    };
+    assert_eq!(optimize(ops.clone(), swaps).len(), ops.len() - 2);
+}
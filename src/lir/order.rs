@@ -0,0 +1,84 @@
+use super::Op;
+use fnv::FnvHashMap;
+
+/// Lays the procs out in the order a depth-first walk of the calls from the entry code first
+/// reaches them, so every proc comes right before the first one it calls that is not laid out
+/// yet. Procs the walk doesn't reach keep their order after the others.
+pub fn order_procs(ops: Vec<Op>) -> Vec<Op> {
+    let mut entry = Vec::new();
+    let mut procs = Vec::<(String, Vec<Op>)>::new();
+    for op in ops {
+        match op {
+            Op::Proc(name) => procs.push((name, Vec::new())),
+            op => match procs.last_mut() {
+                Some((_, body)) => body.push(op),
+                None => entry.push(op),
+            },
+        }
+    }
+    let index = procs
+        .iter()
+        .enumerate()
+        .map(|(i, (name, _))| (name.clone(), i))
+        .collect::<FnvHashMap<_, _>>();
+    let callees = |body: &[Op]| {
+        body.iter()
+            .filter_map(|op| match op {
+                Op::Call(p) | Op::PushProc(p) => index.get(p).copied(),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let mut order = Vec::with_capacity(procs.len());
+    let mut placed = vec![false; procs.len()];
+    let mut stack = callees(&entry);
+    stack.reverse();
+    while let Some(i) = stack.pop() {
+        if placed[i] {
+            continue;
+        }
+        placed[i] = true;
+        order.push(i);
+        stack.extend(callees(&procs[i].1).into_iter().rev());
+    }
+    order.extend((0..procs.len()).filter(|i| !placed[*i]));
+
+    let mut procs = procs.into_iter().map(Some).collect::<Vec<_>>();
+    let mut res = entry;
+    for i in order {
+        let (name, body) = procs[i].take().unwrap();
+        res.push(Op::Proc(name));
+        res.extend(body);
+    }
+    res
+}
+
+#[test]
+fn test_order_procs() {
+    use Op::*;
+    let label = |l: &str| l.to_string();
+    let proc = |name: &str, calls: &[&str]| {
+        let mut ops = vec![Proc(label(name))];
+        ops.extend(calls.iter().map(|c| Call(label(c))));
+        ops.push(Return);
+        ops
+    };
+    let ops = [
+        vec![Call(label("main")), Exit],
+        proc("unused", &["leaf"]),
+        proc("leaf", &[]),
+        proc("second", &["leaf"]),
+        proc("main", &["first", "second"]),
+        proc("first", &["leaf", "first"]),
+    ]
+    .concat();
+    let names = order_procs(ops)
+        .into_iter()
+        .filter_map(|op| match op {
+            Proc(name) => Some(name),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["main", "first", "leaf", "second", "unused"]);
+}
@@ -0,0 +1,151 @@
+use super::Op;
+use crate::span::Span;
+use fnv::FnvHashMap;
+use std::{collections::BTreeMap, fmt, rc::Rc};
+
+/// A lowered program, what `LirPass`es work on and the backends emit
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub ops: Vec<Op>,
+    /// Memories and variables with their sizes
+    pub mems: FnvHashMap<String, usize>,
+    /// Bytes of every `table` the program uses, by name
+    pub tables: FnvHashMap<String, Vec<u8>>,
+    /// Most values each proc pushes on top of its inputs, by label, see `StackSnapshots::depth`
+    pub depths: FnvHashMap<String, usize>,
+    /// Sizes of the `extern mem`s left for linked objects to define, these aren't in `mems`
+    pub extern_mems: BTreeMap<String, usize>,
+    /// Where each `mem` and global `var` is defined, keyed like `mems`
+    pub mem_spans: FnvHashMap<String, Span>,
+}
+
+impl Program {
+    /// Depth of the proc at `label`, lowering gives every proc one
+    pub fn depth(&self, label: &str) -> usize {
+        match self.depths.get(label) {
+            Some(&depth) => depth,
+            None => panic!("No stack depth for `{}`", label),
+        }
+    }
+}
+
+/// When a pass runs relative to the others, passes of the same phase run in registration order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    /// Only looks at the program, before anything rewrites it
+    Analysis,
+    /// Rewrites the program, e.g. peepholes
+    Optimization,
+    /// Needs the final code, after every rewrite
+    Finalization,
+}
+
+/// Analysis or rewrite of the LIR, run after lowering and before the program is verified
+pub trait LirPass {
+    fn phase(&self) -> Phase;
+
+    fn run(&self, program: &mut Program);
+
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+}
+
+/// Custom passes of a compilation, see `Options::passes`
+#[derive(Clone, Default)]
+pub struct Passes {
+    passes: Vec<Rc<dyn LirPass>>,
+}
+
+impl Passes {
+    pub fn register(&mut self, pass: impl LirPass + 'static) -> &mut Self {
+        self.passes.push(Rc::new(pass));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.passes.is_empty()
+    }
+
+    pub fn run(&self, program: &mut Program) {
+        for pass in self.ordered() {
+            pass.run(program);
+        }
+    }
+
+    /// Names of the passes in the order they run
+    pub fn names(&self) -> Vec<String> {
+        self.ordered().map(|p| p.name().to_string()).collect()
+    }
+
+    fn ordered(&self) -> impl Iterator<Item = &Rc<dyn LirPass>> {
+        let mut passes = self.passes.iter().collect::<Vec<_>>();
+        // stable, so registration order is kept within a phase
+        passes.sort_by_key(|p| p.phase());
+        passes.into_iter()
+    }
+}
+
+impl fmt::Debug for Passes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.passes.iter().map(|p| (p.phase(), p.name())))
+            .finish()
+    }
+}
+
+#[test]
+fn test_passes() {
+    use crate::{eval::eval, iconst::IConst};
+    use somok::Either;
+    use std::cell::RefCell;
+
+    struct Log(Rc<RefCell<Vec<Phase>>>, Phase);
+    impl LirPass for Log {
+        fn phase(&self) -> Phase {
+            self.1
+        }
+        fn run(&self, _: &mut Program) {
+            self.0.borrow_mut().push(self.1)
+        }
+    }
+    struct Answer;
+    impl LirPass for Answer {
+        fn phase(&self) -> Phase {
+            Phase::Optimization
+        }
+        fn run(&self, program: &mut Program) {
+            for op in &mut program.ops {
+                if *op == Op::Push(IConst::U64(41)) {
+                    *op = Op::Push(IConst::U64(42));
+                }
+            }
+        }
+    }
+
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut passes = Passes::default();
+    passes
+        .register(Log(log.clone(), Phase::Finalization))
+        .register(Answer)
+        .register(Log(log.clone(), Phase::Analysis))
+        .register(Log(log.clone(), Phase::Optimization));
+    let mut program = Program {
+        ops: vec![
+            Op::Call("main".into()),
+            Op::Exit,
+            Op::Proc("main".into()),
+            Op::Push(IConst::U64(41)),
+            Op::Return,
+        ],
+        ..Default::default()
+    };
+    passes.run(&mut program);
+    assert_eq!(
+        *log.borrow(),
+        [Phase::Analysis, Phase::Optimization, Phase::Finalization]
+    );
+    assert!(matches!(eval(program.ops, &[]), Ok(Either::Left(42))));
+    assert!(format!("{:?}", passes).contains("Answer"));
+    assert!(passes.names()[1].ends_with("Answer"));
+}
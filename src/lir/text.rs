@@ -0,0 +1,480 @@
+//! The LIR as text: the op lines `dump` prints, read back as written, and the data lines of
+//! `dump_data` that give the mems, strings and tables the ops refer to.
+//!
+//! ```text
+//! mem buf 32
+//! str 0 "Hi\n"
+//! 0:  Call("proc1_main")
+//! 1:  Exit
+//! 2:  Proc("proc1_main")
+//! 3:  Push(U64(3))  ( -- Primitive(U64) )
+//! ```
+//!
+//! Op indices and stack effects are optional, blank lines and lines starting with `;` are skipped.
+
+use super::{Cmp, Op, Program};
+use crate::{hir::PrintFormat, iconst::IConst, session::Session, span::Span};
+use somok::Somok;
+use std::{fmt::Write, path::Path};
+use thiserror::Error;
+use Op::*;
+
+#[derive(Debug, Error)]
+pub enum TextError {
+    #[error("line {line}: {message}")]
+    Syntax { line: usize, message: String },
+    #[error("{}", .0.join(", "))]
+    Invalid(Vec<String>),
+}
+
+/// Ops without fields, found by how `Debug` prints them
+const UNIT_OPS: &[Op] = &[
+    Drop, Dup, Swap, Over, Bind, Unbind, ReadU64, ReadU32, ReadU16, ReadU8, WriteU64, WriteU32,
+    WriteU16, WriteU8, Bswap64, Bswap32, Bswap16, Dump, HashStr, Copy16, Cmp16, Splat16, FindByte,
+    Syscall0, Syscall1, Syscall2, Syscall3, Syscall4, Syscall5, Syscall6, SplitErrno, Argc, Argv,
+    Add, Sub, Divmod, IDivmod, Mul, BitAnd, BitOr, BitXor, BitNot, Shl, Shr, Sar, Eq, Ne, Lt, Le,
+    Gt, Ge, LtU, LeU, GtU, GeU, Return, Exit,
+];
+
+const CMPS: &[Cmp] = &[
+    Cmp::Eq,
+    Cmp::Ne,
+    Cmp::Lt,
+    Cmp::Le,
+    Cmp::Gt,
+    Cmp::Ge,
+    Cmp::LtU,
+    Cmp::LeU,
+    Cmp::GtU,
+    Cmp::GeU,
+];
+
+/// What the ops of a program refer to besides each other, one line each for `parse` to read
+/// back: `mem`s and `extern` mems with their sizes, `str`ings by index, `table` bytes in hex and
+/// the `depth` of each proc
+pub fn dump_data(program: &Program, session: &Session) -> String {
+    let mut out = String::new();
+    let mut mems = program.mems.iter().collect::<Vec<_>>();
+    mems.sort();
+    for (name, size) in mems {
+        writeln!(out, "mem {} {}", name, size).unwrap();
+    }
+    for (name, size) in &program.extern_mems {
+        writeln!(out, "extern {} {}", name, size).unwrap();
+    }
+    for (i, s) in session.strings().iter().enumerate() {
+        writeln!(out, "str {} {:?}", i, s).unwrap();
+    }
+    let tables = &program.tables;
+    let mut names = tables.keys().collect::<Vec<_>>();
+    names.sort();
+    for name in names {
+        let hex = tables[name]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        writeln!(out, "table {} {}", name, hex).unwrap();
+    }
+    for op in &program.ops {
+        if let Proc(label) = op {
+            if let Some(depth) = program.depths.get(label) {
+                writeln!(out, "depth {} {}", label, depth).unwrap();
+            }
+        }
+    }
+    out
+}
+
+/// Reads the lines of `dump_data` and `dump` back into a program, interning its strings in
+/// `session`. `Loc`s get spans in `file`.
+pub fn parse(text: &str, file: &Path, session: &Session) -> Result<Program, TextError> {
+    let mut program = Program::default();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+        let error = |message: String| TextError::Syntax {
+            line: i + 1,
+            message,
+        };
+        let first = line.split_whitespace().next().unwrap();
+        if matches!(first, "mem" | "extern" | "str" | "table" | "depth") {
+            data(line, &mut program, session).map_err(error)?;
+        } else {
+            program.ops.push(op_line(line, file).map_err(error)?);
+        }
+    }
+    program.okay()
+}
+
+fn data(line: &str, program: &mut Program, session: &Session) -> Result<(), String> {
+    let (kind, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let (name, value) = rest
+        .trim()
+        .split_once(char::is_whitespace)
+        .unwrap_or((rest, ""));
+    let (name, value) = (name.to_string(), value.trim());
+    let number = || {
+        value
+            .parse::<usize>()
+            .map_err(|_| format!("expected a number, found `{}`", value))
+    };
+    match kind {
+        "mem" => {
+            program.mems.insert(name, number()?);
+        }
+        "extern" => {
+            program.extern_mems.insert(name, number()?);
+        }
+        "depth" => {
+            program.depths.insert(name, number()?);
+        }
+        "str" => {
+            let s = match Reader::new(value).value()? {
+                Value::Str(s) => s,
+                v => return format!("expected a string, found {:?}", v).error(),
+            };
+            if name.parse() != Ok(session.intern_str(s)) {
+                return format!("string {} is out of order or repeated", name).error();
+            }
+        }
+        "table" => {
+            let bytes = (0..value.len())
+                .step_by(2)
+                .map(|i| {
+                    value
+                        .get(i..i + 2)
+                        .and_then(|b| u8::from_str_radix(b, 16).ok())
+                })
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| format!("`{}` are not bytes in hex", value))?;
+            program.tables.insert(name, bytes);
+        }
+        _ => unreachable!(),
+    }
+    ().okay()
+}
+
+/// An op as `dump` prints it, maybe after its index and followed by its stack effect
+fn op_line(line: &str, file: &Path) -> Result<Op, String> {
+    let line = match line.split_once(':') {
+        Some((index, rest)) if index.bytes().all(|b| b.is_ascii_digit()) => rest,
+        _ => line,
+    };
+    let mut reader = Reader::new(line);
+    let value = reader.value()?;
+    reader.skip_spaces();
+    if reader.peek().is_some() && reader.peek() != Some('(') {
+        return format!("unexpected `{}` after the op", reader.rest()).error();
+    }
+    op(value, file)
+}
+
+fn op(value: Value, file: &Path) -> Result<Op, String> {
+    let (name, fields) = match value {
+        Value::Variant(name, fields) => (name, fields),
+        v => return format!("expected an op, found {:?}", v).error(),
+    };
+    let op = match (name.as_str(), &fields[..]) {
+        ("Push", [c]) => Push(iconst(c)?),
+        ("PushStr", [i]) => PushStr(num(i)?),
+        ("PushMem", [s]) => PushMem(string(s)?),
+        ("PushTable", [s]) => PushTable(string(s)?),
+        ("Pick", [n]) => Pick(num(n)?),
+        ("Roll", [n]) => Roll(num(n)?),
+        ("UseBinding", [n]) => UseBinding(num(n)?),
+        ("ZeroExtend", [n]) => ZeroExtend(num(n)?),
+        ("SignExtend", [n]) => SignExtend(num(n)?),
+        ("ReserveEscaping", [n]) => ReserveEscaping(num(n)?),
+        ("PushEscaping", [n]) => PushEscaping(num(n)?),
+        ("ReserveLocals", [n]) => ReserveLocals(num(n)?),
+        ("FreeLocals", [n]) => FreeLocals(num(n)?),
+        ("PushLvar", [n]) => PushLvar(num(n)?),
+        ("Print", [f]) => Print(print_format(f)?),
+        ("PrintPadded", [f]) => PrintPadded(print_format(f)?),
+        ("AddImm", [n]) => AddImm(num(n)?),
+        ("SubImm", [n]) => SubImm(num(n)?),
+        ("Proc", [s]) => Proc(string(s)?),
+        ("Label", [s]) => Label(string(s)?),
+        ("Loc", [Value::Span(start, end)]) => Loc(Span::new(file, *start, *end)),
+        ("Jump", [s]) => Jump(string(s)?),
+        ("JumpF", [s]) => JumpF(string(s)?),
+        ("JumpT", [s]) => JumpT(string(s)?),
+        ("CmpImmBranch", [c, n, s]) => CmpImmBranch(cmp(c)?, num(n)?, string(s)?),
+        ("Call", [s]) => Call(string(s)?),
+        ("PushProc", [s]) => PushProc(string(s)?),
+        ("CallIndirect", [i, o]) => CallIndirect(num(i)?, num(o)?),
+        ("CallHost", [s, i, o]) => CallHost(string(s)?, num(i)?, num(o)?),
+        (name, []) => match UNIT_OPS.iter().find(|op| format!("{:?}", op) == name) {
+            Some(op) => op.clone(),
+            None => return format!("unknown op `{}`", name).error(),
+        },
+        (name, _) => return format!("unknown op `{}` or wrong fields", name).error(),
+    };
+    op.okay()
+}
+
+fn iconst(value: &Value) -> Result<IConst, String> {
+    let c = match value {
+        Value::Variant(name, fields) => match (name.as_str(), &fields[..]) {
+            ("Bool", [Value::Variant(b, f)]) if f.is_empty() && (b == "true" || b == "false") => {
+                IConst::Bool(b == "true")
+            }
+            ("Int", [n]) => IConst::Int(num(n)?),
+            ("U64", [n]) => IConst::U64(num(n)?),
+            ("I64", [n]) => IConst::I64(num(n)?),
+            ("Ptr", [n]) => IConst::Ptr(num(n)?),
+            ("Char", [Value::Char(c)]) => IConst::Char(*c),
+            ("Str", [s]) => IConst::Str(string(s)?),
+            _ => return format!("expected a constant, found {:?}", value).error(),
+        },
+        _ => return format!("expected a constant, found {:?}", value).error(),
+    };
+    c.okay()
+}
+
+fn num<T: TryFrom<i128>>(value: &Value) -> Result<T, String> {
+    match value {
+        Value::Num(n) => T::try_from(*n).ok(),
+        _ => None,
+    }
+    .ok_or_else(|| format!("expected a number that fits, found {:?}", value))
+}
+
+fn string(value: &Value) -> Result<String, String> {
+    match value {
+        Value::Str(s) => s.clone().okay(),
+        _ => format!("expected a string, found {:?}", value).error(),
+    }
+}
+
+fn print_format(value: &Value) -> Result<PrintFormat, String> {
+    [PrintFormat::Unsigned, PrintFormat::Signed, PrintFormat::Hex]
+        .into_iter()
+        .find(|f| matches!(value, Value::Variant(n, _) if *n == format!("{:?}", f)))
+        .ok_or_else(|| format!("expected a print format, found {:?}", value))
+}
+
+fn cmp(value: &Value) -> Result<Cmp, String> {
+    CMPS.iter()
+        .copied()
+        .find(|c| matches!(value, Value::Variant(n, _) if *n == format!("{:?}", c)))
+        .ok_or_else(|| format!("expected a comparison, found {:?}", value))
+}
+
+/// A field of an op as `Debug` prints it
+#[derive(Debug)]
+enum Value {
+    Num(i128),
+    Str(String),
+    Char(char),
+    Span(usize, usize),
+    /// A variant with its fields, if it has any
+    Variant(String, Vec<Value>),
+}
+
+struct Reader {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Reader {
+    fn new(s: &str) -> Self {
+        Self {
+            chars: s.chars().collect(),
+            pos: 0,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn rest(&self) -> String {
+        self.chars[self.pos..].iter().collect()
+    }
+
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(c) if c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_spaces();
+        let found = self.peek() == Some(c);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.eat(c) {
+            return ().okay();
+        }
+        format!("expected `{}` at `{}`", c, self.rest()).error()
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> String {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if f(c)) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    fn number(&mut self) -> Result<i128, String> {
+        let negative = self.eat('-');
+        let digits = self.take_while(|c| c.is_ascii_digit());
+        let n = digits
+            .parse::<i128>()
+            .map_err(|_| format!("expected a number at `{}`", self.rest()))?;
+        (if negative { -n } else { n }).okay()
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_spaces();
+        let value = match self.peek() {
+            Some('"') => Value::Str(self.quoted('"')?),
+            Some('\'') => {
+                let s = self.quoted('\'')?;
+                let mut chars = s.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Value::Char(c),
+                    _ => return format!("`{}` is not a single char", s).error(),
+                }
+            }
+            Some('[') => {
+                self.pos += 1;
+                let start = self.number()?;
+                self.expect('.')?;
+                self.expect('.')?;
+                let end = self.number()?;
+                self.expect(']')?;
+                Value::Span(start as usize, end as usize)
+            }
+            Some(c) if c == '-' || c.is_ascii_digit() => Value::Num(self.number()?),
+            Some(c) if c.is_ascii_alphabetic() => {
+                let name = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_');
+                let mut fields = Vec::new();
+                if self.eat('(') {
+                    loop {
+                        fields.push(self.value()?);
+                        if !self.eat(',') {
+                            break;
+                        }
+                    }
+                    self.expect(')')?;
+                }
+                Value::Variant(name, fields)
+            }
+            _ => return format!("expected a value at `{}`", self.rest()).error(),
+        };
+        value.okay()
+    }
+
+    /// A string or char literal with the escapes of `Debug`
+    fn quoted(&mut self, quote: char) -> Result<String, String> {
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => return format!("unterminated {}", quote).error(),
+            };
+            self.pos += 1;
+            if c == quote {
+                return s.okay();
+            }
+            if c != '\\' {
+                s.push(c);
+                continue;
+            }
+            let escaped = self.peek().ok_or("unterminated escape")?;
+            self.pos += 1;
+            s.push(match escaped {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                '0' => '\0',
+                '\\' | '"' | '\'' => escaped,
+                'u' => {
+                    self.expect('{')?;
+                    let hex = self.take_while(|c| c.is_ascii_hexdigit());
+                    self.expect('}')?;
+                    u32::from_str_radix(&hex, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .ok_or_else(|| format!("`\\u{{{}}}` is not a char", hex))?
+                }
+                c => return format!("unknown escape `\\{}`", c).error(),
+            });
+        }
+    }
+}
+
+#[test]
+fn test_round_trip() {
+    use crate::{
+        eval::Interpreter,
+        lir::typecheck_source_in,
+        session::{Comments, Options},
+    };
+    use somok::Either;
+    let src = r#"
+        mem buf do 8 end
+        table sq : u8 [4] do i i * cast u8 end
+        proc main : u64 do
+            "tab\there\n" drop drop
+            'é' cast u64 buf cast &>u64 !u64
+            sq cast u64 3 + cast &>u8 @u8 cast u64 buf cast &>u64 @u64 +
+            0 while dup 3 < do 1 + end +
+        end"#;
+    let options = Options::builder()
+        .comments(Comments::Spans)
+        .build()
+        .unwrap();
+    let session = Session::new(Default::default(), options);
+    let (procs, _) = typecheck_source_in(src, &session).unwrap();
+    let lowered = super::Compiler::new(&session).compile(procs).unwrap();
+    let text = dump_data(&lowered, &session) + &super::dump(&lowered.ops, &session.snapshots, None);
+
+    let read = Session::default();
+    let program = parse(&text, Path::new("test.rh"), &read).unwrap();
+    assert_eq!(program.ops, lowered.ops);
+    assert_eq!(program.mems, lowered.mems);
+    assert_eq!(&*read.strings(), &*session.strings());
+    assert_eq!(program.tables, lowered.tables);
+    assert_eq!(program.depths, lowered.depths);
+    let res = Interpreter::new()
+        .mems(&program.mems)
+        .tables(&program.tables)
+        .run(&program.ops, &read.strings());
+    assert!(matches!(res, Ok(Either::Left(245))));
+
+    // every op with fields, as written by hand
+    let ops = [
+        CmpImmBranch(Cmp::LeU, 7, "l".into()),
+        CallHost("clock".into(), 1, 2),
+        Push(IConst::I64(-3)),
+        Push(IConst::Char('\'')),
+        Push(IConst::Str("\"\u{7f}".into())),
+        Push(IConst::Bool(false)),
+        PrintPadded(PrintFormat::Hex),
+        Loc(Span::new("f.lir", 3, 5)),
+        SignExtend(8),
+    ];
+    let text = ops
+        .iter()
+        .map(|op| format!("{:?}\n", op))
+        .collect::<String>();
+    let program = parse(&text, Path::new("f.lir"), &read).unwrap();
+    assert_eq!(program.ops, ops);
+
+    assert!(matches!(
+        parse("mem a 8\n\nNope(1)", Path::new("f.lir"), &read),
+        Err(TextError::Syntax { line: 3, .. })
+    ));
+}
@@ -0,0 +1,255 @@
+use super::Op;
+use crate::mir;
+use fnv::{FnvHashMap, FnvHashSet};
+use somok::Somok;
+
+/// Checks the invariants the emitter relies on, collecting every violation.
+/// `strings` is the number of interned string literals, `arities` the input and output counts
+/// of the procs by mangled name, procs missing from it don't get their stack depth checked.
+pub fn verify(
+    ops: &[Op],
+    mems: &FnvHashMap<String, usize>,
+    strings: usize,
+    arities: &FnvHashMap<String, (usize, usize)>,
+) -> Result<(), Vec<String>> {
+    let procs = ops
+        .iter()
+        .filter_map(|op| match op {
+            Op::Proc(l) => l.as_str().some(),
+            _ => None,
+        })
+        .collect::<FnvHashSet<_>>();
+    let mut errors = Vec::new();
+
+    let mut start = 0;
+    while start < ops.len() {
+        let end = ops[start + 1..]
+            .iter()
+            .position(|op| matches!(op, Op::Proc(_)))
+            .map_or(ops.len(), |i| start + 1 + i);
+        verify_segment(ops, start..end, &procs, mems, strings, &mut errors);
+        verify_depth(ops, start..end, arities, &mut errors);
+        start = end;
+    }
+
+    if errors.is_empty() {
+        ().okay()
+    } else {
+        errors.error()
+    }
+}
+
+/// Checks the entry point or a single proc, from its `Proc` up to the next one
+fn verify_segment(
+    ops: &[Op],
+    range: std::ops::Range<usize>,
+    procs: &FnvHashSet<&str>,
+    mems: &FnvHashMap<String, usize>,
+    strings: usize,
+    errors: &mut Vec<String>,
+) {
+    let name = match &ops[range.start] {
+        Op::Proc(name) => name.as_str(),
+        _ => "<entry>",
+    };
+    let mut labels = FnvHashSet::default();
+    for (i, op) in ops[range.clone()].iter().enumerate() {
+        if let Op::Label(l) = op {
+            if !labels.insert(l.as_str()) {
+                errors.push(format!(
+                    "{}: label `{}` defined twice in `{}`",
+                    range.start + i,
+                    l,
+                    name
+                ));
+            }
+        }
+    }
+
+    for (i, op) in ops[range.clone()]
+        .iter()
+        .enumerate()
+        .map(|(i, op)| (range.start + i, op))
+    {
+        match op {
+            Op::Jump(l) | Op::JumpF(l) | Op::JumpT(l) | Op::CmpImmBranch(_, _, l)
+                if !labels.contains(l.as_str()) =>
+            {
+                errors.push(format!(
+                    "{}: jump to `{}` which is not a label in `{}`",
+                    i, l, name
+                ))
+            }
+            Op::Call(p) if !procs.contains(p.as_str()) => {
+                errors.push(format!("{}: call to undefined proc `{}`", i, p))
+            }
+            Op::PushProc(p) if !procs.contains(p.as_str()) => {
+                errors.push(format!("{}: address of undefined proc `{}`", i, p))
+            }
+            Op::PushStr(s) if *s >= strings => errors.push(format!(
+                "{}: string {} out of range, {} were interned",
+                i, s, strings
+            )),
+            Op::PushMem(m) if !mems.contains_key(m) => {
+                errors.push(format!("{}: undefined memory `{}`", i, m))
+            }
+            Op::Return if name == "<entry>" => {
+                errors.push(format!("{}: return outside of a proc", i))
+            }
+            _ => (),
+        }
+    }
+
+    let last = range.end - 1;
+    match (&ops[last], name) {
+        (Op::Exit | Op::Jump(_), "<entry>") => (),
+        (Op::Return | Op::Jump(_), _) if name != "<entry>" => (),
+        (op, _) => errors.push(format!(
+            "{}: `{}` falls through after its last op {:?}",
+            last, name, op
+        )),
+    }
+}
+
+/// Proves that no op of the entry point or a proc pops more than there is on the stack,
+/// and that procs return exactly their outputs. As every proc then starts with its inputs,
+/// no op of the program can underflow the operand stack at runtime.
+fn verify_depth(
+    ops: &[Op],
+    range: std::ops::Range<usize>,
+    arities: &FnvHashMap<String, (usize, usize)>,
+    errors: &mut Vec<String>,
+) {
+    let (name, (ins, outs)) = match &ops[range.start] {
+        Op::Proc(name) => match arities.get(name) {
+            Some(&arity) => (name.as_str(), arity),
+            None => return,
+        },
+        _ => ("<entry>", (0, 0)),
+    };
+    let labels = ops[range.clone()]
+        .iter()
+        .enumerate()
+        .filter_map(|(i, op)| match op {
+            Op::Label(l) => (l.as_str(), range.start + i).some(),
+            _ => None,
+        })
+        .collect::<FnvHashMap<_, _>>();
+
+    let mut depths = FnvHashMap::default();
+    let mut work = vec![(range.start, ins)];
+    while let Some((i, depth)) = work.pop() {
+        if i >= range.end {
+            continue;
+        }
+        match depths.get(&i) {
+            Some(&seen) if seen != depth => {
+                errors.push(format!(
+                    "{}: reached with both {} and {} values on the stack in `{}`",
+                    i, seen, depth, name
+                ));
+                continue;
+            }
+            Some(_) => continue,
+            None => depths.insert(i, depth),
+        };
+        let op = &ops[i];
+        let (pops, pushes) = match op {
+            Op::Proc(_) | Op::Label(_) | Op::Jump(_) | Op::Return => (0, 0),
+            Op::JumpF(_) | Op::JumpT(_) | Op::CmpImmBranch(..) | Op::Exit => (1, 0),
+            Op::Drop => (1, 0),
+            Op::Dup => (1, 2),
+            Op::Swap => (2, 2),
+            Op::Over => (2, 3),
+            Op::Pick(n) => (n + 1, n + 2),
+            Op::Roll(n) => (n + 1, n + 1),
+            // undefined procs are reported on their own
+            Op::Call(p) => match arities.get(p) {
+                Some(&arity) => arity,
+                None => continue,
+            },
+            op => mir::effect(op, arities),
+        };
+        if depth < pops {
+            errors.push(format!(
+                "{}: {:?} in `{}` needs {} values but only {} are on the stack",
+                i, op, name, pops, depth
+            ));
+            continue;
+        }
+        let depth = depth - pops + pushes;
+        match op {
+            Op::Jump(l) => work.extend(labels.get(l.as_str()).map(|&l| (l, depth))),
+            Op::JumpF(l) | Op::JumpT(l) | Op::CmpImmBranch(_, _, l) => {
+                work.extend(labels.get(l.as_str()).map(|&l| (l, depth)));
+                work.push((i + 1, depth));
+            }
+            Op::Return if depth != outs => errors.push(format!(
+                "{}: `{}` returns {} values instead of {}",
+                i, name, depth, outs
+            )),
+            Op::Return | Op::Exit => (),
+            _ => work.push((i + 1, depth)),
+        }
+    }
+}
+
+#[test]
+fn test_verify() {
+    let ops = vec![
+        Op::Call("main".to_string()),
+        Op::Exit,
+        Op::Proc("main".to_string()),
+        Op::JumpF(".main_0".to_string()),
+        Op::Label(".main_1".to_string()),
+        Op::PushStr(1),
+        Op::Call("missing".to_string()),
+    ];
+    let errors = verify(&ops, &Default::default(), 1, &Default::default()).unwrap_err();
+    assert_eq!(errors.len(), 4, "{:#?}", errors);
+
+    let ops = vec![
+        Op::Call("main".to_string()),
+        Op::Exit,
+        Op::Proc("main".to_string()),
+        Op::Label(".main_0".to_string()),
+        Op::Jump(".main_0".to_string()),
+        Op::Return,
+    ];
+    assert_eq!(
+        verify(&ops, &Default::default(), 0, &Default::default()),
+        Ok(())
+    );
+}
+
+#[test]
+fn test_verify_depth() {
+    use crate::iconst::IConst;
+    let l = |s: &str| s.to_string();
+    let arities = [(l("main"), (0, 1)), (l("two"), (1, 2))]
+        .into_iter()
+        .collect();
+    let ops = vec![
+        Op::Call(l("main")),
+        Op::Exit,
+        Op::Proc(l("main")),
+        Op::Push(IConst::Bool(true)),
+        Op::JumpF(l(".main_0")),
+        Op::Push(IConst::U64(1)),
+        Op::Label(l(".main_0")),
+        Op::Call(l("two")),
+        Op::Return,
+        Op::Proc(l("two")),
+        Op::Add,
+        Op::Return,
+    ];
+    let errors = verify(&ops, &Default::default(), 0, &arities).unwrap_err();
+    assert_eq!(
+        errors,
+        [
+            "8: `main` returns 2 values instead of 1",
+            "6: reached with both 1 and 0 values on the stack in `main`",
+            "10: Add in `two` needs 2 values but only 1 are on the stack",
+        ]
+    );
+}
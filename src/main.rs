@@ -1,224 +1,274 @@
-use ariadne::{Color, FileCache, Fmt, Label, Report, ReportKind, Span};
-use chumsky::error::SimpleReason;
 use clap::Parser as ClapParser;
 use fnv::FnvHashMap;
 use rotth::{
     ast::{self, parse},
-    emit,
-    eval::eval,
+    backend::{Aarch64, Backend, Nasm, Wasm, C},
+    build::{check_asm, Builder, TempDir, Toolchain},
+    cli::{CompileArgs, ReportArgs, RunArgs},
+    diagnostics::Renderer,
+    driver::{self, BuildOptions},
+    emit::symbols::Manifest,
+    eval::{HostSyscalls, Interpreter},
     hir::Walker,
     lexer::lex,
     lir,
-    typecheck::{ErrorKind, Typechecker},
+    query::{items_json, references, stacks_json, CallGraph},
+    session::{Comments, Engine, Session, Target},
+    span::SourceMap,
+    typecheck::{TypecheckWarning, Typechecker},
     Error, Result,
 };
 use somok::Somok;
 use std::{fs::OpenOptions, io::BufWriter, path::PathBuf, time::Instant};
 
 #[derive(ClapParser)]
+#[clap(subcommand_negates_reqs = true)]
 struct Args {
+    #[clap(subcommand)]
+    query: Option<Query>,
     #[clap(short = 'k', long)]
     dump_tokens: bool,
     #[clap(short = 'a', long)]
     dump_ast: bool,
     #[clap(short = 'i', long)]
     dump_hir: bool,
+    #[clap(short = 's', long)]
+    dump_stacks: bool,
+    /// Print the lowered ops, with the stack effect of each source node
     #[clap(short = 'l', long)]
     dump_lir: bool,
+    /// Only print the ops of this proc with `--dump-lir`
+    #[clap(long)]
+    only_proc: Option<String>,
     #[clap(short = 't', long)]
     time: bool,
     #[clap(long)]
     compile: bool,
-    source: PathBuf,
+    /// Print every top-level item as JSON, with its signature, depth, attributes and references
+    #[clap(long)]
+    emit_items_json: bool,
+    /// Print the stacks before and after every typechecked word as JSON, for editor inlay hints
+    #[clap(long)]
+    emit_stacks_json: bool,
+    /// Translate to a C file next to the source, for platforms without an assembly backend
+    #[clap(long)]
+    emit_c: bool,
+    /// Translate to a WebAssembly text module next to the source, to run under WASI
+    #[clap(long)]
+    emit_wasm: bool,
+    /// Compile in memory with Cranelift and run the program
+    #[cfg(feature = "cranelift")]
+    #[clap(long)]
+    jit: bool,
+    /// Build an executable at this path instead of emitting assembly
+    #[clap(short = 'o', long)]
+    output: Option<PathBuf>,
+    /// Object to link into the executable of `--output`, defining `extern mem`s
+    #[clap(long, value_name = "OBJECT")]
+    link: Vec<PathBuf>,
+    #[clap(long)]
+    strip: bool,
+    /// Assemble the emitted code right away to catch backend bugs, if nasm is installed
+    #[clap(long)]
+    check_asm: bool,
+    #[clap(long)]
+    assembler: Option<PathBuf>,
+    #[clap(long)]
+    linker: Option<PathBuf>,
+    #[clap(long)]
+    sysroot: Option<PathBuf>,
+    /// Write the mems and strings of the program with their sections and sizes to this file
+    #[clap(long)]
+    symbols: Option<PathBuf>,
+    /// Link mems and strings at the addresses given in this symbols manifest
+    #[clap(long)]
+    layout: Option<PathBuf>,
+    #[clap(flatten)]
+    build: CompileArgs,
+    #[clap(flatten)]
+    report: ReportArgs,
+    #[clap(required = true)]
+    source: Option<PathBuf>,
+    #[clap(flatten)]
+    run: RunArgs,
+}
+
+#[derive(clap::Subcommand)]
+enum Query {
+    /// List the use sites of a proc, const or mem
+    Refs { name: String, source: PathBuf },
+    /// List the callers of a proc or const and what it calls
+    Calls { name: String, source: PathBuf },
+    /// Interpret a snippet as the body of `main` and print what it leaves on the stack
+    Eval { snippet: String },
 }
 
 fn main() -> std::result::Result<(), ()> {
-    match compiler() {
+    let args = Args::parse();
+    let mut renderer = args.report.renderer();
+    if let Some(Query::Eval { snippet }) = &args.query {
+        return eval_snippet(snippet, &mut renderer);
+    }
+    match compiler(args, &mut renderer) {
         Ok(_) => ().okay(),
         Err(e) => {
-            report_errors(e);
+            report_errors(e, &mut renderer);
             ().error()
         }
     }
 }
 
-fn report_errors(e: Error) {
-    let mut sources = FileCache::default();
-    match e {
-        Error::IO(e) => eprintln!("{}", e),
-        Error::Lexer(es) => {
-            for e in es {
-                let report = Report::build(ReportKind::Error, e.span().source(), e.span().start);
+fn report_errors(e: Error, renderer: &mut Renderer) {
+    for diagnostic in e.diagnostics() {
+        renderer.emit(&diagnostic);
+    }
+}
+
+fn report_warnings(ws: Vec<TypecheckWarning>, renderer: &mut Renderer) {
+    for w in ws {
+        renderer.emit(&w.diagnostic());
+    }
+}
 
-                let report = match e.reason() {
-                    SimpleReason::Unexpected => report
-                        .with_message(format!(
-                            "{}, expected {}",
-                            if e.found().is_some() {
-                                "Unexpected character in input"
-                            } else {
-                                "Unexpected end of input"
-                            },
-                            if e.expected().len() == 0 {
-                                "something else".to_string()
-                            } else {
-                                e.expected()
-                                    .map(|expected| match expected {
-                                        Some(expected) => expected.to_string(),
-                                        None => "end of input".to_string(),
-                                    })
-                                    .collect::<Vec<_>>()
-                                    .join(", ")
-                            }
-                        ))
-                        .with_label(
-                            Label::new(e.span())
-                                .with_message(format!(
-                                    "Unexpected character {}",
-                                    e.found()
-                                        .map(ToString::to_string)
-                                        .unwrap_or_else(|| "end of file".to_string())
-                                        .fg(Color::Red)
-                                ))
-                                .with_color(Color::Red),
-                        ),
-                    SimpleReason::Custom(msg) => report.with_message(msg).with_label(
-                        Label::new(e.span())
-                            .with_message(format!("{}", msg.fg(Color::Red)))
-                            .with_color(Color::Red),
-                    ),
-                    SimpleReason::Unclosed {
-                        span: _,
-                        delimiter: _,
-                    } => todo!(),
-                };
-                report.finish().print(&mut sources).unwrap();
+/// Interprets `snippet` as the body of `main` and prints what it leaves on the stack, one
+/// value per line with the top last
+fn eval_snippet(snippet: &str, renderer: &mut Renderer) -> std::result::Result<(), ()> {
+    let tmp = TempDir::new().map_err(|e| eprintln!("{}", e))?;
+    let source = tmp.path.join("eval.rh");
+    // errors show the snippet from its file, so they are reported while it is still there
+    let mut evaluated = std::fs::write(&source, driver::snippet_program(snippet))
+        .map_err(Error::from)
+        .and_then(|_| driver::eval(&source, &BuildOptions::default()))
+        .map_err(|e| report_errors(e, renderer))?;
+    report_warnings(std::mem::take(&mut evaluated.warnings), renderer);
+    match evaluated.exit_code {
+        Some(code) => println!("exitcode: {}", code),
+        None => {
+            for line in evaluated.describe() {
+                println!("{}", line);
             }
         }
-        Error::Parser(es) => {
-            for e in es {
-                let report = Report::build(ReportKind::Error, e.span().source(), e.span().start);
+    }
+    ().okay()
+}
 
-                let report = match e.reason() {
-                    SimpleReason::Unexpected => report
-                        .with_message(format!(
-                            "{}, expected {}",
-                            if e.found().is_some() {
-                                "Unexpected token in input"
-                            } else {
-                                "Unexpected end of input"
-                            },
-                            if e.expected().len() == 0 {
-                                "something else".to_string()
-                            } else {
-                                e.expected()
-                                    .map(|expected| match expected {
-                                        Some(expected) => expected.to_string(),
-                                        None => "end of input".to_string(),
-                                    })
-                                    .collect::<Vec<_>>()
-                                    .join(", ")
-                            }
-                        ))
-                        .with_label(
-                            Label::new(e.span())
-                                .with_message(format!(
-                                    "Unexpected token {}",
-                                    e.found()
-                                        .map(ToString::to_string)
-                                        .unwrap_or_else(|| "end of file".to_string())
-                                        .fg(Color::Red)
-                                ))
-                                .with_color(Color::Red),
-                        ),
-                    SimpleReason::Custom(msg) => report.with_message(msg).with_label(
-                        Label::new(e.span())
-                            .with_message(format!("{}", msg.fg(Color::Red)))
-                            .with_color(Color::Red),
-                    ),
-                    SimpleReason::Unclosed {
-                        span: _,
-                        delimiter: _,
-                    } => todo!(),
-                };
-                report.finish().print(&mut sources).unwrap();
+fn run_query(query: &Query) -> Result<()> {
+    let (name, source) = match query {
+        Query::Refs { name, source } | Query::Calls { name, source } => (name, source),
+        // `main` runs snippets itself, reporting their errors while their file is there
+        Query::Eval { .. } => unreachable!(),
+    };
+    let items = parse(lex(source.canonicalize()?)?)?
+        .into_values()
+        .collect::<Vec<_>>();
+    let mut sources = SourceMap::new();
+    let location = |sources: &mut SourceMap, span: &rotth::span::Span| -> Result<String> {
+        sources.load(&span.file)?;
+        sources.location(span).unwrap().okay()
+    };
+    match query {
+        Query::Refs { .. } => {
+            for span in references(&items, name) {
+                println!("{}", location(&mut sources, &span)?);
             }
         }
-        Error::Redefinition(es) => {
-            for e in es {
-                let report = Report::build(
-                    ReportKind::Error,
-                    e.redefined_item.source(),
-                    e.redefined_item.start,
-                )
-                .with_message("Duplicate word definitions")
-                .with_label(
-                    Label::new(e.redefined_item)
-                        .with_message("Word originally defined here...")
-                        .with_color(Color::Green),
-                )
-                .with_label(
-                    Label::new(e.redefining_item)
-                        .with_message("redefined here")
-                        .with_color(Color::Yellow),
-                );
-                report.finish().print(&mut sources).unwrap();
+        Query::Calls { .. } => {
+            let graph = CallGraph::new(&items);
+            println!("Called by:");
+            for (caller, sites) in graph.incoming(name) {
+                for site in sites {
+                    println!("  {caller}\t{}", location(&mut sources, site)?);
+                }
+            }
+            println!("Calls:");
+            for (callee, sites) in graph.outgoing(name) {
+                for site in sites {
+                    println!("  {callee}\t{}", location(&mut sources, site)?);
+                }
             }
         }
-        Error::Typecheck(e) => {
-            let report = Report::build(ReportKind::Error, e.span.source(), e.span.start)
-                .with_message(e.message);
-
-            let report =
-                match e.kind {
-                    ErrorKind::TypeMismatch { expected, actual } => report.with_label(
-                        Label::new(e.span).with_message(
-                            format!(
-                                "Unexpected types: {} where {} expected",
-                                format!("{:?}", actual).fg(Color::Green),
-                                format!("{:?}", expected).fg(Color::Yellow)
-                            )
-                            .fg(Color::Red),
-                        ),
-                    ),
-                    ErrorKind::NotEnoughData => report.with_label(
-                        Label::new(e.span)
-                            .with_message("Not enough data on the stack".fg(Color::Red)),
-                    ),
-
-                    ErrorKind::Undefined(w) => report.with_label(Label::new(e.span).with_message(
-                        format!("Unknown word `{}`", w.fg(Color::Yellow)).fg(Color::Red),
-                    )),
-                    ErrorKind::InvalidMain => report.with_label(
-                        Label::new(e.span).with_message(
-                            format!("Invalid type signature for `{}`", "main".fg(Color::Yellow))
-                                .fg(Color::Red),
-                        ),
-                    ),
-                    ErrorKind::InvalidWhile => report.with_label(Label::new(e.span).with_message(
-                        "While body must not alter types on the stack".fg(Color::Red),
-                    )),
-                    ErrorKind::CompStop => report
-                        .with_label(Label::new(e.span).with_message("Compilation stopped here")),
-                    ErrorKind::Unexpected => {
-                        report.with_label(Label::new(e.span).with_message("Unexpected word"))
-                    }
-                    ErrorKind::CallInConst => {
-                        report.with_label(Label::new(e.span).with_message("Procedure call here"))
-                    }
-                };
+        Query::Eval { .. } => unreachable!(),
+    }
+    ().okay()
+}
 
-            report.finish().print(&mut sources).unwrap();
-        }
+/// What runs the program, as picked by the first of the flags `compiler` looks at
+fn engine(args: &Args) -> Option<Engine> {
+    if args.output.is_some() {
+        return None;
+    }
+    #[cfg(feature = "cranelift")]
+    if args.jit {
+        return Engine::Cranelift.some();
+    }
+    if args.emit_c {
+        Engine::C.some()
+    } else if args.emit_wasm {
+        Engine::Wasm.some()
+    } else if args.compile {
+        None
+    } else {
+        Engine::Interpreter.some()
     }
 }
 
-fn compiler() -> Result<()> {
-    let args = Args::parse();
+fn compiler(args: Args, renderer: &mut Renderer) -> Result<()> {
+    if let Some(query) = &args.query {
+        return run_query(query);
+    }
+    let source = args.source.clone().unwrap();
 
     let start = Instant::now();
 
-    let source = args.source.canonicalize()?;
+    let placements = match &args.layout {
+        Some(path) => Manifest::parse(&std::fs::read_to_string(path)?)?.placements(),
+        None => Default::default(),
+    };
+    let mut builder = args
+        .build
+        .builder()?
+        .strip(args.strip)
+        .placements(placements);
+    if let Some(engine) = engine(&args) {
+        builder = builder.engine(engine);
+    }
+    let mut options = args.build.build(builder)?;
+    if args.dump_lir || args.run.check_uninit {
+        // the dump finds the nodes of ops by their spans, the uninit trap points at the read
+        options.comments = options.comments.max(Comments::Spans);
+    }
+
+    if let Some(output) = &args.output {
+        let triple = args
+            .build
+            .target
+            .clone()
+            .unwrap_or_else(|| Target::default().triple().to_string());
+        let mut builder = Builder::new();
+        if args.assembler.is_some() || args.linker.is_some() || args.sysroot.is_some() {
+            let default = Toolchain::default();
+            let toolchain = Toolchain {
+                assembler: args.assembler.clone().unwrap_or(default.assembler),
+                linker: args.linker.clone().unwrap_or(default.linker),
+                sysroot: args.sysroot.clone(),
+            };
+            builder = builder.toolchain(triple.clone(), toolchain);
+        }
+        if let Some(symbols) = &args.symbols {
+            builder = builder.symbols(symbols);
+        }
+        for object in &args.link {
+            builder = builder.object(object);
+        }
+        let warnings = builder.build(&source, &triple, output, options)?;
+        report_warnings(warnings, renderer);
+        if args.time {
+            println!("Built in:\t{:?}", start.elapsed());
+        }
+        return ().okay();
+    }
+
+    let source = source.canonicalize()?;
 
     let tokens = lex(source.clone())?;
 
@@ -232,7 +282,9 @@ fn compiler() -> Result<()> {
         println!("{tokens:?}");
     }
 
-    let ast = parse(tokens)?;
+    let ast = ast::parse_with_include_paths(tokens, &options.include_paths)?;
+    let items = (args.emit_items_json || args.emit_stacks_json)
+        .then(|| ast.values().cloned().collect::<Vec<_>>());
     let (structs, ast) = ast
         .into_iter()
         .partition::<FnvHashMap<_, _>, _>(|(_, i)| matches!(i, ast::TopLevel::Struct(_)));
@@ -247,30 +299,56 @@ fn compiler() -> Result<()> {
         println!("{ast:#?}");
     }
 
-    let struct_index = rotth::types::define_structs(structs);
+    let session = Session::new(rotth::types::define_structs(structs), options);
 
-    let mut walker = Walker::new(&struct_index);
-    let hir = walker.walk_ast(ast);
+    let mut walker = Walker::new(&session);
+    let hir = walker.walk_ast(ast)?;
 
     let lowered = Instant::now();
     if args.time {
         println!("Lowered in:\t{:?}", lowered - parsed)
     }
 
-    if args.dump_ast {
+    if args.dump_hir {
         println!("HIR:\n");
+        for (name, proc) in hir.iter().filter_map(|(n, i)| Some((n, i.as_proc()?))) {
+            println!("proc {name} {}", proc.signature());
+        }
         println!("{hir:#?}");
     }
 
-    let procs = Typechecker::typecheck_program(hir, &struct_index)?;
+    let typechecked = Typechecker::typecheck_program(hir, &session);
+    if args.dump_stacks {
+        println!("Stacks:\n");
+        for (span, stack) in session.snapshots.all() {
+            println!("{span:?}\t{stack:?}");
+        }
+        println!("\nDepths:\n");
+        for (proc, depth) in session.snapshots.depths() {
+            println!("{proc}\t{depth}");
+        }
+    }
+    if let Some(items) = items {
+        // depths are there for the procs checked before any type error
+        let mut sources = SourceMap::new();
+        sources.load_spans(&items.iter().map(|i| i.span()).collect::<Vec<_>>())?;
+        if args.emit_stacks_json {
+            println!("{}", stacks_json(&items, &sources, &session.snapshots));
+        } else {
+            println!("{}", items_json(&items, &sources, &session.snapshots));
+        }
+        return ().okay();
+    }
+    let (procs, warnings) = typechecked?;
+    report_warnings(warnings, renderer);
 
     let typechecked = Instant::now();
     if args.time {
         println!("Typechecked in:\t{:?}", typechecked - lowered)
     }
 
-    let comp = lir::Compiler::new(struct_index);
-    let (lir, strs, mems) = comp.compile(procs);
+    let comp = lir::Compiler::new(&session);
+    let lir = comp.compile(procs)?;
 
     let transpiled = Instant::now();
     if args.time {
@@ -279,23 +357,88 @@ fn compiler() -> Result<()> {
 
     if args.dump_lir {
         println!("LIR:\n");
-        for (i, op) in lir.iter().enumerate() {
-            println!("{i}:\t{op:?}");
+        print!(
+            "{}",
+            lir::dump(&lir.ops, &session.snapshots, args.only_proc.as_deref())
+        );
+    }
+    #[cfg(feature = "cranelift")]
+    if args.jit {
+        let mut program = rotth::backend::cranelift::Cranelift.compile(lir, &session)?;
+        let compiled = Instant::now();
+        if args.time {
+            println!("Compiled in:\t{:?}", compiled - transpiled);
+        }
+        let exitcode = program.run(&[source.display().to_string()]);
+        println!("exitcode: {}", exitcode);
+        if args.time {
+            println!("Ran in:\t{:?}", compiled.elapsed());
+            println!("Total:\t{:?}", start.elapsed());
+        }
+        return ().okay();
+    }
+    if args.emit_c {
+        let c = source.with_extension("c");
+        let sink = BufWriter::new(
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&c)?,
+        );
+        C::new(sink).compile(lir, &session)?;
+        if args.time {
+            let compiled = Instant::now();
+            println!("Compiled in:\t{:?}", compiled - transpiled);
+            println!("Total:\t{:?}", compiled - start);
         }
+        return ().okay();
+    }
+    if args.emit_wasm {
+        let wat = source.with_extension("wat");
+        let sink = BufWriter::new(
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&wat)?,
+        );
+        Wasm::new(sink).compile(lir, &session)?;
+        if args.time {
+            let compiled = Instant::now();
+            println!("Compiled in:\t{:?}", compiled - transpiled);
+            println!("Total:\t{:?}", compiled - start);
+        }
+        return ().okay();
     }
     if args.compile {
-        emit::compile(
-            lir,
-            &strs,
-            &mems,
-            BufWriter::new(
-                OpenOptions::new()
-                    .create(true)
-                    .write(true)
-                    .truncate(true)
-                    .open(source.with_extension("asm"))?,
-            ),
-        )?;
+        let asm = source.with_extension(match session.options.target {
+            Target::X86_64Linux => "asm",
+            Target::Aarch64Linux => "s",
+        });
+        let sink = BufWriter::new(
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&asm)?,
+        );
+        if let Some(symbols) = &args.symbols {
+            let manifest = Manifest::new(
+                &lir.mems,
+                &session.strings(),
+                &lir.tables,
+                &session.options.placements,
+            );
+            std::fs::write(symbols, manifest.to_string())?;
+        }
+        match session.options.target {
+            Target::X86_64Linux => Nasm::new(sink).compile(lir, &session)?,
+            Target::Aarch64Linux => Aarch64::new(sink).compile(lir, &session)?,
+        }
+        if args.check_asm {
+            check_asm(&asm, session.options.target)?;
+        }
 
         let compiled = Instant::now();
         if args.time {
@@ -303,7 +446,24 @@ fn compiler() -> Result<()> {
             println!("Total:\t{:?}", compiled - start);
         }
     } else {
-        println!("exitcode: {:?}", eval(lir, &strs).unwrap());
+        let program = source.display().to_string();
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .mems(&lir.mems)
+            .tables(&lir.tables)
+            .args(std::iter::once(program).chain(args.run.args))
+            .syscalls(HostSyscalls::new());
+        if args.run.check_uninit {
+            interpreter.check_uninit(&lir.mem_spans);
+        }
+        if session.options.checked {
+            interpreter.check_indirect();
+        }
+        let run = interpreter.run(&lir.ops, &session.strings());
+        match run {
+            Ok(exitcode) => println!("exitcode: {:?}", exitcode),
+            Err(e) => eprintln!("{}", e),
+        }
         let evaluated = Instant::now();
         if args.time {
             println!("Evaluated in:\t{:?}", evaluated - transpiled);
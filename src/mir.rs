@@ -0,0 +1,1119 @@
+use crate::{
+    iconst::IConst,
+    lir::{extend, Cmp, Op},
+};
+use fnv::{FnvHashMap, FnvHashSet};
+use somok::Somok;
+use std::{
+    fmt::{self, Display},
+    ops::RangeInclusive,
+};
+
+/// A stack slot, defined once by an instruction or as a block parameter
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Value(pub usize);
+
+impl Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+/// An op with the values it pops as `args`, bottom first, and the ones it pushes as `results`
+#[derive(Debug, Clone)]
+pub struct Inst {
+    pub op: Op,
+    pub args: Vec<Value>,
+    pub results: Vec<Value>,
+}
+
+/// How a block ends, targets are block indices and `args` the values handed to their parameters
+#[derive(Debug, Clone)]
+pub enum Terminator {
+    Jump(usize, Vec<Value>),
+    /// `JumpT` if `jump_if` is set and `JumpF` otherwise, falling through to `next`.
+    /// With a `compare` the condition is `cond` compared to the constant, a `CmpImmBranch`.
+    Branch {
+        cond: Value,
+        jump_if: bool,
+        target: usize,
+        next: usize,
+        args: Vec<Value>,
+        compare: Option<(Cmp, u64)>,
+    },
+    Return(Vec<Value>),
+    Exit(Value),
+}
+
+impl Terminator {
+    fn args(&self) -> Vec<Value> {
+        match self {
+            Terminator::Jump(_, args) | Terminator::Return(args) => args.clone(),
+            Terminator::Branch { cond, args, .. } => {
+                args.iter().copied().chain(std::iter::once(*cond)).collect()
+            }
+            Terminator::Exit(v) => vec![*v],
+        }
+    }
+
+    /// Values handed to the parameters of the targets
+    fn passed(&self) -> &[Value] {
+        match self {
+            Terminator::Jump(_, args) | Terminator::Branch { args, .. } => args,
+            Terminator::Return(_) | Terminator::Exit(_) => &[],
+        }
+    }
+
+    fn targets(&self) -> Vec<usize> {
+        match self {
+            Terminator::Jump(target, _) => vec![*target],
+            Terminator::Branch { target, next, .. } => vec![*target, *next],
+            Terminator::Return(_) | Terminator::Exit(_) => vec![],
+        }
+    }
+}
+
+/// Straight line code, entered with the stack holding `params`, bottom first
+#[derive(Debug, Clone)]
+pub struct Block {
+    /// The `Proc` or `Label` op starting the block, blocks that are only fallen into have none
+    pub label: Option<Op>,
+    pub params: Vec<Value>,
+    pub insts: Vec<Inst>,
+    pub terminator: Terminator,
+}
+
+/// Mid-level IR of a proc: its stack code split into blocks, with the operand stack turned into
+/// values. Stack shuffles only rename values, so they disappear here and are brought back by
+/// `lower`, which makes the values easy to fold and prune.
+/// Values never cross blocks, whatever a successor needs is passed to its parameters.
+#[derive(Debug, Clone)]
+pub struct Body {
+    pub name: String,
+    pub blocks: Vec<Block>,
+    values: usize,
+}
+
+impl Body {
+    /// Builds the MIR of one proc from its ops, `Proc` to the last `Return`.
+    /// `ins` is the number of stack slots the proc takes,
+    /// `procs` the inputs and outputs of every proc it may call.
+    pub fn build(ops: Vec<Op>, ins: usize, procs: &FnvHashMap<String, (usize, usize)>) -> Self {
+        let raw = split(ops);
+        let labels = raw
+            .iter()
+            .enumerate()
+            .filter_map(|(i, (label, ..))| match label {
+                Some(Op::Proc(l) | Op::Label(l)) => (l.clone(), i).some(),
+                _ => None,
+            })
+            .collect::<FnvHashMap<_, _>>();
+        let name = match &raw[0].0 {
+            Some(Op::Proc(name)) => name.clone(),
+            _ => String::new(),
+        };
+
+        let mut body = Self {
+            name,
+            blocks: Vec::new(),
+            values: 0,
+        };
+        let mut built = vec![None; raw.len()];
+        let mut depths = vec![None; raw.len()];
+        depths[0] = ins.some();
+        let mut work = vec![0];
+        while let Some(i) = work.pop() {
+            if built[i].is_some() {
+                continue;
+            }
+            let (label, ops, end) = &raw[i];
+            let params = (0..depths[i].unwrap())
+                .map(|_| body.value())
+                .collect::<Vec<_>>();
+            let mut stack = params.clone();
+            let mut insts = Vec::new();
+            for op in ops {
+                let top = stack.len().saturating_sub(1);
+                match op {
+                    Op::Dup => stack.push(stack[top]),
+                    Op::Drop => {
+                        stack.pop();
+                    }
+                    Op::Swap => stack.swap(top, top - 1),
+                    Op::Over => stack.push(stack[top - 1]),
+                    Op::Pick(depth) => stack.push(stack[top - depth]),
+                    Op::Roll(depth) => {
+                        let v = stack.remove(top - depth);
+                        stack.push(v)
+                    }
+                    op => {
+                        let (pops, pushes) = effect(op, procs);
+                        let args = stack.split_off(stack.len() - pops);
+                        let results = (0..pushes).map(|_| body.value()).collect::<Vec<_>>();
+                        stack.extend(&results);
+                        insts.push(Inst {
+                            op: op.clone(),
+                            args,
+                            results,
+                        })
+                    }
+                }
+            }
+            let terminator = match end {
+                Some(Op::Jump(l)) => Terminator::Jump(labels[l], stack),
+                Some(op @ (Op::JumpF(l) | Op::JumpT(l))) => Terminator::Branch {
+                    cond: stack.pop().unwrap(),
+                    jump_if: matches!(op, Op::JumpT(_)),
+                    target: labels[l],
+                    next: i + 1,
+                    args: stack,
+                    compare: None,
+                },
+                Some(Op::CmpImmBranch(cmp, k, l)) => Terminator::Branch {
+                    cond: stack.pop().unwrap(),
+                    jump_if: true,
+                    target: labels[l],
+                    next: i + 1,
+                    args: stack,
+                    compare: (*cmp, *k).some(),
+                },
+                Some(Op::Return) => Terminator::Return(stack),
+                Some(Op::Exit) => Terminator::Exit(stack.pop().unwrap()),
+                _ => Terminator::Jump(i + 1, stack),
+            };
+            let depth = match &terminator {
+                Terminator::Jump(_, args) | Terminator::Branch { args, .. } => args.len(),
+                _ => 0,
+            };
+            for target in terminator.targets() {
+                match depths[target] {
+                    Some(d) if d != depth => unreachable!(
+                        "Block {} of `{}` entered with {} and {} values",
+                        target, body.name, d, depth
+                    ),
+                    _ => depths[target] = depth.some(),
+                }
+                work.push(target);
+            }
+            built[i] = Block {
+                label: label.clone(),
+                params,
+                insts,
+                terminator,
+            }
+            .some();
+        }
+
+        // blocks nothing jumps to, like the code after an early return, are left out
+        let mut index = vec![0; raw.len()];
+        for (i, block) in built.iter().enumerate() {
+            if block.is_some() {
+                index[i] = body.blocks.len();
+                body.blocks.push(block.clone().unwrap());
+            }
+        }
+        for block in &mut body.blocks {
+            match &mut block.terminator {
+                Terminator::Jump(target, _) => *target = index[*target],
+                Terminator::Branch { target, next, .. } => {
+                    *target = index[*target];
+                    *next = index[*next];
+                }
+                _ => (),
+            }
+        }
+        body
+    }
+
+    fn value(&mut self) -> Value {
+        self.values += 1;
+        Value(self.values - 1)
+    }
+
+    /// Computes arithmetic and comparisons on constants and turns branches on them into jumps.
+    /// What is left with a constant operand takes it as an immediate, like `AddImm`.
+    pub fn fold(&mut self) {
+        for block in &mut self.blocks {
+            let mut consts = FnvHashMap::default();
+            for inst in &mut block.insts {
+                let folded = match (&inst.op, &inst.args[..]) {
+                    (Op::Push(c), _) => {
+                        consts.insert(inst.results[0], bits(c));
+                        continue;
+                    }
+                    (op, &[a, b]) => match (op, consts.get(&a), consts.get(&b)) {
+                        (Op::Add, Some(a), Some(b)) => IConst::U64(a.wrapping_add(*b)),
+                        (Op::Sub, Some(a), Some(b)) => IConst::U64(a.wrapping_sub(*b)),
+                        (Op::Mul, Some(a), Some(b)) => IConst::U64(a.wrapping_mul(*b)),
+                        (Op::BitAnd, Some(a), Some(b)) => IConst::U64(a & b),
+                        (Op::BitOr, Some(a), Some(b)) => IConst::U64(a | b),
+                        (Op::BitXor, Some(a), Some(b)) => IConst::U64(a ^ b),
+                        (Op::Shl, Some(a), Some(b)) => IConst::U64(a.wrapping_shl(*b as u32)),
+                        (Op::Shr, Some(a), Some(b)) => IConst::U64(a.wrapping_shr(*b as u32)),
+                        (Op::Sar, Some(a), Some(b)) => {
+                            IConst::U64((*a as i64).wrapping_shr(*b as u32) as u64)
+                        }
+                        (Op::Eq, Some(a), Some(b)) => IConst::Bool(a == b),
+                        (Op::Ne, Some(a), Some(b)) => IConst::Bool(a != b),
+                        (Op::Add, Some(&k), None) => {
+                            (inst.op, inst.args) = (Op::AddImm(k), vec![b]);
+                            continue;
+                        }
+                        (Op::Add, None, Some(&k)) => {
+                            (inst.op, inst.args) = (Op::AddImm(k), vec![a]);
+                            continue;
+                        }
+                        (Op::Sub, None, Some(&k)) => {
+                            (inst.op, inst.args) = (Op::SubImm(k), vec![a]);
+                            continue;
+                        }
+                        _ => continue,
+                    },
+                    (Op::BitNot, &[a]) => match consts.get(&a) {
+                        Some(&c) => IConst::U64(!c),
+                        None => continue,
+                    },
+                    (&Op::ZeroExtend(n), &[a]) | (&Op::SignExtend(n), &[a]) => {
+                        match consts.get(&a) {
+                            Some(&c) => {
+                                let signed = matches!(inst.op, Op::SignExtend(_));
+                                IConst::U64(extend(c, n, signed))
+                            }
+                            None => continue,
+                        }
+                    }
+                    _ => continue,
+                };
+                consts.insert(inst.results[0], bits(&folded));
+                inst.op = Op::Push(folded);
+                inst.args.clear();
+            }
+            if let Terminator::Branch {
+                cond,
+                jump_if,
+                target,
+                next,
+                args,
+                compare: None,
+            } = &block.terminator
+            {
+                if let Some(&c) = consts.get(cond) {
+                    let to = if (c != 0) == *jump_if { *target } else { *next };
+                    block.terminator = Terminator::Jump(to, args.clone());
+                }
+            }
+        }
+    }
+
+    /// Turns branches on the comparison of a value with a constant into `CmpImmBranch`es
+    pub fn fuse_compares(&mut self) {
+        for block in &mut self.blocks {
+            let consts = block
+                .insts
+                .iter()
+                .filter_map(|inst| match &inst.op {
+                    Op::Push(c) => (inst.results[0], bits(c)).some(),
+                    _ => None,
+                })
+                .collect();
+            block.fuse_compare(&consts);
+        }
+    }
+
+    /// Moves pure instructions of loops whose arguments are the same on every pass in front of
+    /// them, handing their results to a new parameter of every block of the loop. A loop runs
+    /// from a block to the last block jumping back to it, and is left as it is unless it is only
+    /// entered by plain jumps to its first block and only left to blocks entered from nowhere
+    /// else. Returns how many values were hoisted, which the loops keep on the stack.
+    pub fn hoist_invariants(&mut self) -> usize {
+        let mut hoisted = 0;
+        // inner loops come first, what leaves them may leave the loops around them next
+        for header in (0..self.blocks.len()).rev() {
+            let last = (header..self.blocks.len())
+                .filter(|&b| self.blocks[b].terminator.targets().contains(&header))
+                .max();
+            if let Some(last) = last {
+                hoisted += self.hoist_loop(header..=last);
+            }
+        }
+        hoisted
+    }
+
+    fn hoist_loop(&mut self, blocks: RangeInclusive<usize>) -> usize {
+        let header = *blocks.start();
+        let mut preds = vec![Vec::new(); self.blocks.len()];
+        for (i, block) in self.blocks.iter().enumerate() {
+            for target in block.terminator.targets() {
+                preds[target].push(i);
+            }
+        }
+        let inside = |b: &usize| blocks.contains(b);
+        let entries = preds[header]
+            .iter()
+            .copied()
+            .filter(|b| !inside(b))
+            .collect::<Vec<_>>();
+        let mut exits = blocks
+            .clone()
+            .flat_map(|b| self.blocks[b].terminator.targets())
+            .filter(|b| !inside(b))
+            .collect::<Vec<_>>();
+        exits.sort_unstable();
+        exits.dedup();
+        let enclosed = !entries.is_empty()
+            && entries
+                .iter()
+                .all(|&e| matches!(self.blocks[e].terminator, Terminator::Jump(..)))
+            && blocks
+                .clone()
+                .skip(1)
+                .chain(exits.iter().copied())
+                .all(|b| preds[b].iter().all(inside));
+        if !enclosed {
+            return 0;
+        }
+
+        let (mut origins, seen) = self.loop_origins(&blocks);
+        let mut hoisted = 0;
+        while let Some((b, i)) = self.invariant_inst(&blocks, &origins, &seen) {
+            let inst = self.blocks[b].insts.remove(i);
+            let k = self.blocks[header].params.len();
+            for &e in &entries {
+                let mut args = Vec::new();
+                for &a in &inst.args {
+                    let arg = match origins.get(&a) {
+                        Some(&Some(k)) => self.blocks[e].terminator.passed()[k],
+                        _ => {
+                            // a constant of the block, pushed again in front of the loop
+                            let push = self.blocks[b]
+                                .insts
+                                .iter()
+                                .find(|i| i.results == [a])
+                                .unwrap()
+                                .op
+                                .clone();
+                            let v = self.value();
+                            self.blocks[e].insts.push(Inst {
+                                op: push,
+                                args: vec![],
+                                results: vec![v],
+                            });
+                            v
+                        }
+                    };
+                    args.push(arg);
+                }
+                let result = self.value();
+                self.blocks[e].insts.push(Inst {
+                    op: inst.op.clone(),
+                    args,
+                    results: vec![result],
+                });
+                if let Terminator::Jump(_, passed) = &mut self.blocks[e].terminator {
+                    passed.push(result);
+                }
+            }
+            for x in blocks.clone().chain(exits.iter().copied()) {
+                let param = self.value();
+                self.blocks[x].params.push(param);
+                origins.insert(param, k.some());
+                if inside(&x) {
+                    match &mut self.blocks[x].terminator {
+                        Terminator::Jump(_, args) | Terminator::Branch { args, .. } => {
+                            args.push(param)
+                        }
+                        Terminator::Return(_) | Terminator::Exit(_) => (),
+                    }
+                }
+                if x == b {
+                    self.blocks[b].rename(inst.results[0], param);
+                }
+            }
+            hoisted += 1;
+        }
+        hoisted
+    }
+
+    /// Which parameter of the first block of the loop each parameter of its blocks holds on
+    /// every pass, if any, and the blocks entered from the first one
+    fn loop_origins(
+        &self,
+        blocks: &RangeInclusive<usize>,
+    ) -> (FnvHashMap<Value, Option<usize>>, FnvHashSet<usize>) {
+        let header = *blocks.start();
+        let mut invariant = vec![true; self.blocks[header].params.len()];
+        loop {
+            let mut origins = self.blocks[header]
+                .params
+                .iter()
+                .enumerate()
+                .map(|(k, &p)| (p, invariant[k].then_some(k)))
+                .collect::<FnvHashMap<_, _>>();
+            let mut seen = FnvHashSet::from_iter([header]);
+            // parameters are unknown until a jump into their block is seen, and only lose
+            // their origin after that, so this settles
+            let mut changed = true;
+            while changed {
+                changed = false;
+                for b in blocks.clone() {
+                    if !seen.contains(&b) {
+                        continue;
+                    }
+                    let terminator = &self.blocks[b].terminator;
+                    for target in terminator.targets() {
+                        if target == header || !blocks.contains(&target) {
+                            continue;
+                        }
+                        changed |= seen.insert(target);
+                        let params = &self.blocks[target].params;
+                        for (a, &p) in terminator.passed().iter().zip(params) {
+                            let from = origins.get(a).copied().flatten();
+                            let merged = match origins.get(&p) {
+                                None => from,
+                                Some(&o) if o == from => o,
+                                Some(_) => None,
+                            };
+                            changed |= origins.insert(p, merged) != merged.some();
+                        }
+                    }
+                }
+            }
+            let mut settled = true;
+            for b in blocks.clone().filter(|b| seen.contains(b)) {
+                let terminator = &self.blocks[b].terminator;
+                if !terminator.targets().contains(&header) {
+                    continue;
+                }
+                for (k, a) in terminator.passed().iter().enumerate() {
+                    if invariant[k] && origins.get(a).copied().flatten() != k.some() {
+                        invariant[k] = false;
+                        settled = false;
+                    }
+                }
+            }
+            if settled {
+                return (origins, seen);
+            }
+        }
+    }
+
+    /// The first pure instruction of the loop that computes something from the parameters
+    /// with an origin and constants alone
+    fn invariant_inst(
+        &self,
+        blocks: &RangeInclusive<usize>,
+        origins: &FnvHashMap<Value, Option<usize>>,
+        seen: &FnvHashSet<usize>,
+    ) -> Option<(usize, usize)> {
+        blocks.clone().filter(|b| seen.contains(b)).find_map(|b| {
+            let insts = &self.blocks[b].insts;
+            let consts = insts
+                .iter()
+                .filter(|i| matches!(i.op, Op::Push(_)))
+                .flat_map(|i| &i.results)
+                .collect::<FnvHashSet<_>>();
+            let i = insts.iter().position(|inst| {
+                is_pure(&inst.op)
+                    && !inst.args.is_empty()
+                    && inst
+                        .args
+                        .iter()
+                        .all(|a| consts.contains(a) || origins.get(a).copied().flatten().is_some())
+            })?;
+            (b, i).some()
+        })
+    }
+
+    /// Removes instructions without side effects whose results are never used
+    pub fn eliminate_dead_code(&mut self) {
+        for block in &mut self.blocks {
+            loop {
+                let used = block
+                    .insts
+                    .iter()
+                    .flat_map(|i| i.args.iter())
+                    .chain(block.terminator.args().iter())
+                    .copied()
+                    .collect::<FnvHashSet<_>>();
+                let before = block.insts.len();
+                block
+                    .insts
+                    .retain(|i| !is_pure(&i.op) || i.results.iter().any(|r| used.contains(r)));
+                if block.insts.len() == before {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Turns the values back into stack code, with the shuffles needed to bring
+    /// every instruction its arguments and dropping values as soon as they are dead
+    pub fn lower(&self) -> Vec<Op> {
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut work = vec![0];
+        while let Some(i) = work.pop() {
+            if !std::mem::replace(&mut reachable[i], true) {
+                work.extend(self.blocks[i].terminator.targets());
+            }
+        }
+        let order = (0..self.blocks.len())
+            .filter(|i| reachable[*i])
+            .collect::<Vec<_>>();
+        let label = |i: usize| match &self.blocks[i].label {
+            Some(Op::Proc(l) | Op::Label(l)) => l.clone(),
+            _ => format!(".{}_mir{}", self.name, i),
+        };
+        let jumped_to = order
+            .iter()
+            .flat_map(|&i| match &self.blocks[i].terminator {
+                Terminator::Branch { target, .. } => vec![*target],
+                Terminator::Jump(target, _) => vec![*target],
+                _ => vec![],
+            })
+            .collect::<FnvHashSet<_>>();
+
+        let mut ops = Vec::new();
+        for (n, &i) in order.iter().enumerate() {
+            let block = &self.blocks[i];
+            let next = order.get(n + 1).copied();
+            match &block.label {
+                Some(l) => ops.push(l.clone()),
+                None if jumped_to.contains(&i) => ops.push(Op::Label(label(i))),
+                None => (),
+            }
+
+            let end = block.insts.len();
+            let mut last_use = FnvHashMap::default();
+            for (j, inst) in block.insts.iter().enumerate() {
+                for &a in &inst.args {
+                    last_use.insert(a, j);
+                }
+            }
+            for a in block.terminator.args() {
+                last_use.insert(a, end);
+            }
+            // constants are pushed where they are used instead of being kept around
+            let consts = block
+                .insts
+                .iter()
+                .filter(|i| matches!(i.op, Op::Push(_)))
+                .map(|i| (i.results[0], i.op.clone()))
+                .collect::<FnvHashMap<_, _>>();
+            let mut stack = Shuffler {
+                stack: block.params.clone(),
+                consts: &consts,
+                ops: &mut ops,
+            };
+            let live_after = |j: Option<usize>, v: Value| match (last_use.get(&v), j) {
+                (Some(&u), Some(j)) => u > j,
+                (Some(_), None) => true,
+                (None, _) => false,
+            };
+            stack.drop_dead(|v| live_after(None, v));
+            for (j, inst) in block.insts.iter().enumerate() {
+                if matches!(inst.op, Op::Push(_)) {
+                    continue;
+                }
+                stack.place(&inst.args, |v| live_after(j.some(), v));
+                stack.ops.push(inst.op.clone());
+                let keep = stack.stack.len() - inst.args.len();
+                stack.stack.truncate(keep);
+                stack.stack.extend(&inst.results);
+                stack.drop_dead(|v| live_after(j.some(), v));
+            }
+            stack.place(&block.terminator.args(), |_| false);
+            match &block.terminator {
+                Terminator::Jump(target, _) if Some(*target) == next => (),
+                Terminator::Jump(target, _) => ops.push(Op::Jump(label(*target))),
+                Terminator::Branch {
+                    jump_if,
+                    target,
+                    next: fallthrough,
+                    compare,
+                    ..
+                } => {
+                    let l = label(*target);
+                    ops.push(match compare {
+                        Some((cmp, k)) if *jump_if => Op::CmpImmBranch(*cmp, *k, l),
+                        Some((cmp, k)) => Op::CmpImmBranch(cmp.negate(), *k, l),
+                        None if *jump_if => Op::JumpT(l),
+                        None => Op::JumpF(l),
+                    });
+                    if Some(*fallthrough) != next {
+                        ops.push(Op::Jump(label(*fallthrough)));
+                    }
+                }
+                Terminator::Return(_) => ops.push(Op::Return),
+                Terminator::Exit(_) => ops.push(Op::Exit),
+            }
+        }
+        ops
+    }
+}
+
+impl Block {
+    /// Uses of `from` become uses of `to`
+    fn rename(&mut self, from: Value, to: Value) {
+        let uses = self.insts.iter_mut().flat_map(|inst| &mut inst.args);
+        let uses = uses.chain(match &mut self.terminator {
+            Terminator::Jump(_, args) | Terminator::Return(args) => args.iter_mut().collect(),
+            Terminator::Branch { cond, args, .. } => {
+                args.iter_mut().chain(std::iter::once(cond)).collect()
+            }
+            Terminator::Exit(v) => vec![v],
+        });
+        for v in uses {
+            if *v == from {
+                *v = to;
+            }
+        }
+    }
+
+    /// Branches on the comparison of a value with a constant, when nothing else needs its result
+    fn fuse_compare(&mut self, consts: &FnvHashMap<Value, u64>) {
+        let cond = match &self.terminator {
+            Terminator::Branch {
+                cond,
+                compare: None,
+                ..
+            } => *cond,
+            _ => return,
+        };
+        let (i, cmp, a, k) = match self.insts.iter().enumerate().rev().find_map(|(i, inst)| {
+            let cmp = Cmp::of(&inst.op)?;
+            match inst.args[..] {
+                [a, b] if inst.results == [cond] => (i, cmp, a, *consts.get(&b)?).some(),
+                _ => None,
+            }
+        }) {
+            Some(fused) => fused,
+            None => return,
+        };
+        let used = self.insts[i + 1..]
+            .iter()
+            .flat_map(|inst| &inst.args)
+            .chain(match &self.terminator {
+                Terminator::Branch { args, .. } => args,
+                _ => unreachable!(),
+            })
+            .any(|&v| v == cond);
+        if used {
+            return;
+        }
+        self.insts.remove(i);
+        if let Terminator::Branch { cond, compare, .. } = &mut self.terminator {
+            *cond = a;
+            *compare = (cmp, k).some();
+        }
+    }
+}
+
+/// Keeps track of the values on the stack while stack code is written
+struct Shuffler<'o> {
+    stack: Vec<Value>,
+    consts: &'o FnvHashMap<Value, Op>,
+    ops: &'o mut Vec<Op>,
+}
+
+impl Shuffler<'_> {
+    /// Brings `args` to the top in order. A value still live after them is copied,
+    /// otherwise its last use moves it, unless it is already where it needs to be.
+    fn place(&mut self, args: &[Value], live_after: impl Fn(Value) -> bool) {
+        let len = self.stack.len();
+        let mut in_place = args.len().min(len);
+        while in_place > 0
+            && (self.stack[len - in_place..] != args[..in_place]
+                || args[..in_place].iter().any(|&v| live_after(v)))
+        {
+            in_place -= 1;
+        }
+        for (i, &v) in args.iter().enumerate().skip(in_place) {
+            if let Some(push) = self.consts.get(&v) {
+                self.ops.push(push.clone());
+                self.stack.push(v);
+                continue;
+            }
+            let consumed =
+                !live_after(v) && !args[..in_place].contains(&v) && !args[i + 1..].contains(&v);
+            // the top `i` values are the arguments placed so far
+            let below = self.stack.len() - i;
+            let position = if consumed {
+                self.stack[..below].iter().rposition(|&s| s == v)
+            } else {
+                self.stack.iter().rposition(|&s| s == v)
+            }
+            .unwrap();
+            let depth = self.stack.len() - 1 - position;
+            if consumed {
+                self.roll(depth)
+            } else {
+                self.pick(depth)
+            }
+        }
+    }
+
+    /// Drops every value on the stack that is not live anymore
+    fn drop_dead(&mut self, live: impl Fn(Value) -> bool) {
+        while let Some(position) = self.stack.iter().rposition(|&v| !live(v)) {
+            self.roll(self.stack.len() - 1 - position);
+            self.stack.pop();
+            self.ops.push(Op::Drop);
+        }
+    }
+
+    fn pick(&mut self, depth: usize) {
+        self.ops.push(match depth {
+            0 => Op::Dup,
+            1 => Op::Over,
+            depth => Op::Pick(depth),
+        });
+        self.stack.push(self.stack[self.stack.len() - 1 - depth]);
+    }
+
+    fn roll(&mut self, depth: usize) {
+        match depth {
+            0 => return,
+            1 => self.ops.push(Op::Swap),
+            depth => self.ops.push(Op::Roll(depth)),
+        }
+        let v = self.stack.remove(self.stack.len() - 1 - depth);
+        self.stack.push(v);
+    }
+}
+
+/// Splits the ops of a proc at labels and after jumps, into the label,
+/// the ops and the jump, return or exit of each block
+fn split(ops: Vec<Op>) -> Vec<(Option<Op>, Vec<Op>, Option<Op>)> {
+    let mut blocks = vec![(None, Vec::new(), None)];
+    for op in ops {
+        let (label, body, end) = blocks.last_mut().unwrap();
+        match op {
+            Op::Proc(_) | Op::Label(_) if label.is_none() && body.is_empty() && end.is_none() => {
+                *label = op.some()
+            }
+            Op::Proc(_) | Op::Label(_) => blocks.push((op.some(), Vec::new(), None)),
+            Op::Jump(_)
+            | Op::JumpF(_)
+            | Op::JumpT(_)
+            | Op::CmpImmBranch(..)
+            | Op::Return
+            | Op::Exit => {
+                *end = op.some();
+                blocks.push((None, Vec::new(), None))
+            }
+            op => body.push(op),
+        }
+    }
+    let last = blocks.last().unwrap();
+    if last.0.is_none() && last.1.is_empty() {
+        blocks.pop();
+    }
+    blocks
+}
+
+/// Values popped and pushed by an op that is not a shuffle or a jump
+pub(crate) fn effect(op: &Op, procs: &FnvHashMap<String, (usize, usize)>) -> (usize, usize) {
+    use Op::*;
+    match op {
+        Push(_) | PushMem(_) | PushTable(_) | PushProc(_) | UseBinding(_) | PushEscaping(_)
+        | PushLvar(_) | Argc | Argv => (0, 1),
+        PushStr(_) => (0, 2),
+        Unbind | ReserveEscaping(_) | ReserveLocals(_) | FreeLocals(_) | Loc(_) | Dump => (0, 0),
+        Bind | Print(_) => (1, 0),
+        ReadU64 | ReadU32 | ReadU16 | ReadU8 | Bswap64 | Bswap32 | Bswap16 => (1, 1),
+        AddImm(_) | SubImm(_) | ZeroExtend(_) | SignExtend(_) | BitNot => (1, 1),
+        WriteU64 | WriteU32 | WriteU16 | WriteU8 | PrintPadded(_) => (2, 0),
+        Copy16 | Splat16 => (2, 0),
+        HashStr | Cmp16 | Add | Sub | Mul | Eq | Ne | Lt | Le | Gt | Ge => (2, 1),
+        LtU | LeU | GtU | GeU => (2, 1),
+        BitAnd | BitOr | BitXor | Shl | Shr | Sar => (2, 1),
+        Divmod | IDivmod => (2, 2),
+        SplitErrno => (1, 2),
+        FindByte => (3, 2),
+        Syscall0 => (1, 1),
+        Syscall1 => (2, 1),
+        Syscall2 => (3, 1),
+        Syscall3 => (4, 1),
+        Syscall4 => (5, 1),
+        Syscall5 => (6, 1),
+        Syscall6 => (7, 1),
+        Call(p) => procs[p],
+        CallHost(_, ins, outs) => (*ins, *outs),
+        CallIndirect(ins, outs) => (ins + 1, *outs),
+        op => unreachable!("{:?} has no stack effect of its own", op),
+    }
+}
+
+/// Ops that only compute their results, so they can go when those are unused.
+/// Reads stay, they trap on misaligned addresses in checked builds.
+fn is_pure(op: &Op) -> bool {
+    use Op::*;
+    matches!(
+        op,
+        Push(_)
+            | PushStr(_)
+            | PushMem(_)
+            | PushTable(_)
+            | PushProc(_)
+            | PushLvar(_)
+            | PushEscaping(_)
+            | UseBinding(_)
+            | Argc
+            | Argv
+            | Add
+            | Sub
+            | AddImm(_)
+            | SubImm(_)
+            | Mul
+            | Eq
+            | Ne
+            | Lt
+            | Le
+            | Gt
+            | Ge
+            | LtU
+            | LeU
+            | GtU
+            | GeU
+            | Bswap64
+            | Bswap32
+            | Bswap16
+            | ZeroExtend(_)
+            | SignExtend(_)
+            | BitAnd
+            | BitOr
+            | BitXor
+            | BitNot
+            | Shl
+            | Shr
+            | Sar
+    )
+}
+
+fn bits(c: &IConst) -> u64 {
+    match c {
+        IConst::Bool(b) => *b as u64,
+        IConst::Char(c) => *c as u64,
+        IConst::U64(u) | IConst::Int(u) => *u,
+        IConst::I64(i) => *i as u64,
+        IConst::Ptr(p) => *p,
+        IConst::Str(_) => unreachable!(),
+    }
+}
+
+fn values(values: &[Value]) -> String {
+    values
+        .iter()
+        .map(Value::to_string)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl Display for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, block) in self.blocks.iter().enumerate() {
+            match &block.label {
+                Some(Op::Proc(l) | Op::Label(l)) => write!(f, "{}", l)?,
+                _ => write!(f, "{}", i)?,
+            }
+            writeln!(f, "({}):", values(&block.params))?;
+            for inst in &block.insts {
+                if inst.results.is_empty() {
+                    write!(f, "    ")?;
+                } else {
+                    write!(f, "    {} = ", values(&inst.results))?;
+                }
+                writeln!(f, "{:?} {}", inst.op, values(&inst.args))?;
+            }
+            match &block.terminator {
+                Terminator::Jump(target, args) => {
+                    writeln!(f, "    jump {}({})", target, values(args))?
+                }
+                Terminator::Branch {
+                    cond,
+                    jump_if,
+                    target,
+                    next,
+                    args,
+                    compare,
+                } => writeln!(
+                    f,
+                    "    if {}{} {} {} else {} ({})",
+                    cond,
+                    match compare {
+                        Some((cmp, k)) => format!(" {:?} {}", cmp, k),
+                        None => String::new(),
+                    },
+                    if *jump_if { "jump" } else { "skip" },
+                    target,
+                    next,
+                    values(args)
+                )?,
+                Terminator::Return(args) => writeln!(f, "    return {}", values(args))?,
+                Terminator::Exit(v) => writeln!(f, "    exit {}", v)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_mir_round_trip() {
+    use crate::eval::eval;
+    use somok::Either;
+    let l = |s: &str| s.to_string();
+    // sums the numbers below its input, with a dead `2 3 +` to fold and drop
+    let proc = vec![
+        Op::Proc(l("sum")),
+        Op::Push(IConst::U64(0)),
+        Op::Push(IConst::U64(2)),
+        Op::Push(IConst::U64(3)),
+        Op::Add,
+        Op::Drop,
+        Op::Label(l(".sum_0")),
+        Op::Over,
+        Op::Push(IConst::U64(0)),
+        Op::Ne,
+        Op::JumpF(l(".sum_1")),
+        Op::Over,
+        Op::Push(IConst::U64(1)),
+        Op::Sub,
+        Op::Swap,
+        Op::Pick(2),
+        Op::Add,
+        Op::Roll(2),
+        Op::Drop,
+        Op::Jump(l(".sum_0")),
+        Op::Label(l(".sum_1")),
+        Op::Swap,
+        Op::Drop,
+        Op::Return,
+        Op::Push(IConst::U64(7)),
+        Op::Return,
+    ];
+    let mut body = Body::build(proc.clone(), 1, &Default::default());
+    assert_eq!(body.blocks.len(), 4, "{}", body);
+    body.fold();
+    body.eliminate_dead_code();
+    body.fuse_compares();
+    let lowered = body.lower();
+    assert!(!lowered
+        .iter()
+        .any(|op| matches!(op, Op::Push(IConst::U64(2 | 3 | 5)))));
+    // `1 -` and the branch on `0 !=` take their constants as immediates
+    assert!(lowered.contains(&Op::SubImm(1)));
+    assert!(lowered.contains(&Op::CmpImmBranch(Cmp::Eq, 0, l(".sum_1"))));
+    let entry = [Op::Push(IConst::U64(10)), Op::Call(l("sum")), Op::Exit];
+    for ops in [proc, lowered] {
+        let ops = entry.iter().cloned().chain(ops).collect();
+        assert!(matches!(eval(ops, &[]), Ok(Either::Left(55))));
+    }
+}
+
+#[test]
+fn test_fold_immediates() {
+    use crate::eval::eval;
+    use somok::Either;
+    let l = |s: &str| s.to_string();
+    let run = |proc: &[Op], x: u64| {
+        let entry = [Op::Push(IConst::U64(x)), Op::Call(l("f")), Op::Exit];
+        match eval(entry.iter().chain(proc).cloned().collect(), &[]) {
+            Ok(Either::Left(res)) => res,
+            res => panic!("{:?}", res.map(|_| ())),
+        }
+    };
+    // `5 +` takes it as an immediate, `100 x -` can't as the constant is on the left
+    let proc = vec![
+        Op::Proc(l("f")),
+        Op::Push(IConst::U64(5)),
+        Op::Add,
+        Op::Push(IConst::U64(100)),
+        Op::Swap,
+        Op::Sub,
+        Op::Return,
+    ];
+    let mut body = Body::build(proc.clone(), 1, &Default::default());
+    body.fold();
+    body.fuse_compares();
+    let lowered = body.lower();
+    assert!(lowered.contains(&Op::AddImm(5)), "{}", body);
+    assert!(lowered.contains(&Op::Sub), "{}", body);
+    assert!(!lowered.iter().any(|op| matches!(op, Op::SubImm(_))));
+    assert_eq!((run(&proc, 3), run(&lowered, 3)), (92, 92));
+
+    // the comparison is still needed after the branch, so it isn't fused into it
+    let proc = vec![
+        Op::Proc(l("f")),
+        Op::Dup,
+        Op::Push(IConst::U64(0)),
+        Op::Eq,
+        Op::Dup,
+        Op::JumpF(l(".f_0")),
+        Op::Add,
+        Op::Return,
+        Op::Label(l(".f_0")),
+        Op::Drop,
+        Op::Drop,
+        Op::Push(IConst::U64(7)),
+        Op::Return,
+    ];
+    let mut body = Body::build(proc.clone(), 1, &Default::default());
+    body.fold();
+    body.fuse_compares();
+    let lowered = body.lower();
+    assert!(lowered.contains(&Op::Eq), "{}", body);
+    assert!(!lowered.iter().any(|op| matches!(op, Op::CmpImmBranch(..))));
+    for x in [0, 4] {
+        assert_eq!(run(&proc, x), run(&lowered, x));
+    }
+}
+
+#[test]
+fn test_hoist_invariants() {
+    use crate::eval::eval;
+    use somok::Either;
+    let l = |s: &str| s.to_string();
+    // adds `k 8 *` to a sum `n` times, `k` staying the same
+    let proc = vec![
+        Op::Proc(l("scale")),
+        Op::Push(IConst::U64(0)),
+        Op::Label(l(".scale_0")),
+        Op::Over,
+        Op::Push(IConst::U64(0)),
+        Op::Ne,
+        Op::JumpF(l(".scale_1")),
+        Op::Pick(2),
+        Op::Push(IConst::U64(8)),
+        Op::Mul,
+        Op::Add,
+        Op::Swap,
+        Op::Push(IConst::U64(1)),
+        Op::Sub,
+        Op::Swap,
+        Op::Jump(l(".scale_0")),
+        Op::Label(l(".scale_1")),
+        Op::Swap,
+        Op::Drop,
+        Op::Swap,
+        Op::Drop,
+        Op::Return,
+    ];
+    let mut body = Body::build(proc.clone(), 2, &Default::default());
+    body.fold();
+    body.eliminate_dead_code();
+    assert_eq!(body.hoist_invariants(), 1, "{}", body);
+    let lowered = body.lower();
+    let position = |op: &Op| lowered.iter().position(|o| o == op).unwrap();
+    assert!(position(&Op::Mul) < position(&Op::Label(l(".scale_0"))));
+    assert_eq!(lowered.iter().filter(|op| **op == Op::Mul).count(), 1);
+    let entry = [
+        Op::Push(IConst::U64(3)),
+        Op::Push(IConst::U64(5)),
+        Op::Call(l("scale")),
+        Op::Exit,
+    ];
+    for ops in [proc, lowered] {
+        let ops = entry.iter().cloned().chain(ops).collect();
+        assert!(matches!(eval(ops, &[]), Ok(Either::Left(120))));
+    }
+}
@@ -0,0 +1,402 @@
+use crate::{
+    ast::{resolve_aliases, AstKind, AstNode, TopLevel},
+    rename::{binding_names, children, item_nodes, word},
+    span::{SourceMap, Span},
+    typecheck::StackSnapshots,
+    types::Type,
+};
+use serde::Serialize;
+use somok::Somok;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Every use of the item `name`, leaving out words shadowed by bindings and local vars
+pub fn references(items: &[TopLevel], name: &str) -> Vec<Span> {
+    let mut res = Vec::new();
+    for item in items {
+        for node in item_nodes(item) {
+            uses(node, &mut Vec::new(), &mut |used, span| {
+                if used == name {
+                    res.push(span.clone())
+                }
+            });
+        }
+    }
+    res.sort();
+    res
+}
+
+/// Which procs, consts and mems use which procs and consts
+#[derive(Debug, Default)]
+pub struct CallGraph {
+    /// Call sites by caller and callee
+    calls: BTreeMap<(String, String), Vec<Span>>,
+}
+
+impl CallGraph {
+    pub fn new(items: &[TopLevel]) -> Self {
+        let callable = items
+            .iter()
+            .filter(|i| {
+                matches!(
+                    i,
+                    TopLevel::Proc(_) | TopLevel::Const(_) | TopLevel::Extern(_)
+                )
+            })
+            .filter_map(TopLevel::name)
+            .collect::<Vec<_>>();
+        let aliases = resolve_aliases(items).unwrap_or_default();
+        let mut calls = BTreeMap::<_, Vec<_>>::new();
+        for item in items {
+            let (caller, body) = match item {
+                TopLevel::Proc(p) => (item.name().unwrap(), &p.body),
+                TopLevel::Const(c) => (item.name().unwrap(), &c.body),
+                TopLevel::Mem(m) => (item.name().unwrap(), &m.body),
+                TopLevel::Table(t) => (item.name().unwrap(), &t.body),
+                _ => continue,
+            };
+            uses(body, &mut Vec::new(), &mut |callee, span| {
+                let callee = aliases.get(callee).map_or(callee, String::as_str);
+                if callable.iter().any(|c| c == callee) {
+                    calls
+                        .entry((caller.clone(), callee.to_string()))
+                        .or_default()
+                        .push(span.clone())
+                }
+            });
+        }
+        Self { calls }
+    }
+
+    /// Callers of `callee`, with the call sites in each
+    pub fn incoming(&self, callee: &str) -> Vec<(&str, &[Span])> {
+        self.calls
+            .iter()
+            .filter(|((_, c), _)| c == callee)
+            .map(|((caller, _), sites)| (caller.as_str(), sites.as_slice()))
+            .collect()
+    }
+
+    /// What `caller` calls, with the call sites of each
+    pub fn outgoing(&self, caller: &str) -> Vec<(&str, &[Span])> {
+        self.calls
+            .iter()
+            .filter(|((c, _), _)| c == caller)
+            .map(|((_, callee), sites)| (callee.as_str(), sites.as_slice()))
+            .collect()
+    }
+}
+
+/// Names of the items `item` uses, sorted
+pub fn item_references(items: &[TopLevel], item: &TopLevel) -> Vec<String> {
+    let names = items
+        .iter()
+        .filter_map(TopLevel::name)
+        .collect::<BTreeSet<_>>();
+    let mut res = BTreeSet::new();
+    for node in item_nodes(item) {
+        uses(node, &mut Vec::new(), &mut |used, _| {
+            if names.contains(used) {
+                res.insert(used.to_string());
+            }
+        });
+    }
+    res.into_iter().collect()
+}
+
+/// An item as listed by `items_json`
+#[derive(Serialize)]
+struct ItemJson<'a> {
+    kind: &'static str,
+    name: &'a str,
+    signature: Option<String>,
+    max_depth: Option<usize>,
+    span: SpanJson,
+    attributes: Vec<AttributeJson<'a>>,
+    references: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct AttributeJson<'a> {
+    name: &'a str,
+    arg: Option<&'a str>,
+}
+
+/// A span, with the one based line and column of its start when its file is known
+#[derive(Serialize)]
+struct SpanJson {
+    file: String,
+    start: usize,
+    end: usize,
+    line: Option<usize>,
+    column: Option<usize>,
+}
+
+impl SpanJson {
+    fn new(span: &Span, sources: &SourceMap) -> Self {
+        let (line, column) = sources.line_col(span).unzip();
+        Self {
+            file: span.file.to_string_lossy().into_owned(),
+            start: span.start,
+            end: span.end,
+            line,
+            column,
+        }
+    }
+}
+
+/// The stacks around a node as listed by `stacks_json`
+#[derive(Serialize)]
+struct StacksJson {
+    span: SpanJson,
+    before: Vec<String>,
+    after: Vec<String>,
+}
+
+/// Every item as a JSON array, sorted by name, for tools that inspect a program without linking
+/// the compiler. Spans get a one based line and column when their file is in `sources`, procs
+/// their `max_depth` when `snapshots` has it.
+pub fn items_json(items: &[TopLevel], sources: &SourceMap, snapshots: &StackSnapshots) -> String {
+    let mut sorted = items
+        .iter()
+        .filter_map(|i| (i.name()?, i).some())
+        .collect::<Vec<_>>();
+    sorted.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let entries = sorted
+        .iter()
+        .map(|(name, item)| {
+            let (kind, signature, attrs) = match item {
+                TopLevel::Proc(p) => ("proc", p.signature.to_string().some(), &p.attrs[..]),
+                TopLevel::Const(c) => ("const", signature(&c.signature).some(), &c.attrs[..]),
+                TopLevel::Mem(_) => ("mem", None, &[][..]),
+                TopLevel::Table(t) => ("table", t.ty.to_string().some(), &[][..]),
+                TopLevel::Var(v) => ("var", v.ty.to_string().some(), &[][..]),
+                TopLevel::Struct(s) => ("struct", None, &s.attrs[..]),
+                TopLevel::Extern(e) => ("extern", e.signature.to_string().some(), &[][..]),
+                TopLevel::Alias(_) => ("alias", None, &[][..]),
+                TopLevel::Include(_) => unreachable!(),
+            };
+            let attributes = attrs
+                .iter()
+                .filter_map(|a| match &a.ast {
+                    AstKind::Attribute(a) => AttributeJson {
+                        name: &a.name,
+                        arg: a.arg.as_deref(),
+                    }
+                    .some(),
+                    _ => None,
+                })
+                .collect();
+            ItemJson {
+                kind,
+                name,
+                signature,
+                max_depth: match item {
+                    TopLevel::Proc(_) => snapshots.depth(name),
+                    _ => None,
+                },
+                span: SpanJson::new(&item.span(), sources),
+                attributes,
+                references: item_references(items, item),
+            }
+        })
+        .collect::<Vec<_>>();
+    serde_json::to_string(&entries).unwrap()
+}
+
+/// An AST node with the stacks the typechecker found before and after it, `None` for the nodes
+/// it doesn't check like signatures and bodies
+#[derive(Debug)]
+pub struct Annotated<'a> {
+    pub node: &'a AstNode,
+    pub before: Option<Vec<Type>>,
+    pub after: Option<Vec<Type>>,
+    pub children: Vec<Annotated<'a>>,
+}
+
+/// The nodes of `items` annotated with their stacks in `snapshots`, one tree per signature and
+/// body
+pub fn annotate<'a>(items: &'a [TopLevel], snapshots: &StackSnapshots) -> Vec<Annotated<'a>> {
+    items
+        .iter()
+        .flat_map(item_nodes)
+        .map(|node| annotate_node(node, snapshots))
+        .collect()
+}
+
+fn annotate_node<'a>(node: &'a AstNode, snapshots: &StackSnapshots) -> Annotated<'a> {
+    Annotated {
+        node,
+        before: snapshots.before(&node.span),
+        after: snapshots.get(&node.span),
+        children: children(node)
+            .into_iter()
+            .map(|c| annotate_node(c, snapshots))
+            .collect(),
+    }
+}
+
+/// The stacks before and after every checked node of `items` as a JSON array in source order,
+/// for editors to show as inlay hints. Spans get a line and column like in `items_json`.
+pub fn stacks_json(items: &[TopLevel], sources: &SourceMap, snapshots: &StackSnapshots) -> String {
+    fn flatten<'a>(
+        node: &Annotated<'a>,
+        res: &mut Vec<(&'a Span, StacksJson)>,
+        sources: &SourceMap,
+    ) {
+        if let (Some(before), Some(after)) = (&node.before, &node.after) {
+            let stack = |stack: &[Type]| stack.iter().map(|ty| format!("{:?}", ty)).collect();
+            let entry = StacksJson {
+                span: SpanJson::new(&node.node.span, sources),
+                before: stack(before),
+                after: stack(after),
+            };
+            res.push((&node.node.span, entry));
+        }
+        for child in &node.children {
+            flatten(child, res, sources);
+        }
+    }
+    let mut entries = Vec::new();
+    for tree in annotate(items, snapshots) {
+        flatten(&tree, &mut entries, sources);
+    }
+    entries.sort_by_key(|(span, _)| *span);
+    let entries = entries
+        .into_iter()
+        .map(|(_, entry)| entry)
+        .collect::<Vec<_>>();
+    serde_json::to_string(&entries).unwrap()
+}
+
+fn signature(node: &AstNode) -> String {
+    match &node.ast {
+        AstKind::ConstSignature(s) => std::iter::once(":".to_string())
+            .chain(s.tys.iter().map(ToString::to_string))
+            .collect::<Vec<_>>()
+            .join(" "),
+        _ => node.to_string(),
+    }
+}
+
+/// Calls `f` with every word and type name in `node` that refers to an item
+fn uses<'a>(node: &'a AstNode, bound: &mut Vec<&'a str>, f: &mut impl FnMut(&str, &Span)) {
+    match &node.ast {
+        AstKind::Word(w) if !bound.contains(&w.as_str()) => f(w, &node.span),
+        AstKind::Type(ty) => f(&ty.type_name, &node.span),
+        AstKind::Body(nodes) => {
+            let depth = bound.len();
+            for node in nodes {
+                uses(node, bound, f);
+                if let AstKind::Var(v) = &node.ast {
+                    bound.push(word(&v.name));
+                }
+            }
+            bound.truncate(depth);
+        }
+        AstKind::Bind(b) => {
+            for binding in &b.bindings {
+                uses(binding, bound, f);
+            }
+            let depth = bound.len();
+            bound.extend(b.bindings.iter().flat_map(binding_names).map(word));
+            uses(&b.body, bound, f);
+            bound.truncate(depth);
+        }
+        _ => {
+            for c in children(node) {
+                uses(c, bound, f);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_call_graph() {
+    use crate::lir::parse_source;
+
+    let src = "proc inc u64 : u64 do 1 + end\n\
+               proc twice u64 : u64 do inc inc end\n\
+               proc main do bind inc: u64 do inc end 1 twice drop end";
+    let items = parse_source(src);
+    assert_eq!(references(&items, "inc").len(), 2);
+    let graph = CallGraph::new(&items);
+    let callers = graph.incoming("inc");
+    assert_eq!(callers.len(), 1);
+    assert_eq!(callers[0].0, "twice");
+    assert_eq!(callers[0].1.len(), 2);
+    assert_eq!(graph.outgoing("main")[0].0, "twice");
+}
+
+#[test]
+fn test_items_json() {
+    use crate::lir::parse_source;
+
+    let src = "mem buf do 8 end\n\
+               #[inline] proc store u64 do buf !u64 end\n\
+               const answer : u64 do 42 end\n\
+               proc main do answer store end";
+    let items = parse_source(src);
+    assert_eq!(item_references(&items, &items[3]), ["answer", "store"]);
+    let mut sources = SourceMap::new();
+    sources.insert("test.rh", src.to_string());
+    let json = items_json(&items, &sources, &Default::default());
+    let json = serde_json::from_str::<serde_json::Value>(&json).unwrap();
+    let names = json.as_array().unwrap().iter().map(|i| &i["name"]);
+    assert_eq!(
+        names.collect::<Vec<_>>(),
+        ["answer", "buf", "main", "store"]
+    );
+    assert_eq!(
+        (&json[0]["kind"], &json[0]["signature"]),
+        (&"const".into(), &": u64".into())
+    );
+    assert_eq!(json[2]["signature"], "");
+    assert!(json[2]["max_depth"].is_null());
+    let store = &json[3];
+    assert_eq!(
+        (&store["span"]["line"], &store["span"]["column"]),
+        (&2.into(), &16.into())
+    );
+    assert_eq!(
+        store["attributes"],
+        serde_json::json!([{"name": "inline", "arg": null}])
+    );
+    assert_eq!(store["references"], serde_json::json!(["buf"]));
+}
+
+#[test]
+fn test_annotate() {
+    use crate::{
+        lir::{parse_source, typecheck_source_in},
+        session::Session,
+    };
+
+    let src = "proc main : u64 do 1 2 dup * + end";
+    let items = parse_source(src);
+    let session = Session::default();
+    typecheck_source_in(src, &session).unwrap();
+    let trees = annotate(&items, &session.snapshots);
+    // the signature, then the body with its words
+    assert_eq!(trees.len(), 2);
+    assert!(trees[0].after.is_none());
+    let words = &trees[1].children;
+    assert_eq!(words.len(), 5);
+    assert_eq!(&src[words[3].node.span.start..words[3].node.span.end], "*");
+    assert_eq!(words[3].before.as_ref().map(Vec::len), Some(3));
+    assert_eq!(words[3].after.as_ref().map(Vec::len), Some(2));
+    assert_eq!(words[4].after, Some(vec![Type::U64]));
+
+    let mut sources = SourceMap::new();
+    sources.insert("test.rh", src.to_string());
+    let json = stacks_json(&items, &sources, &session.snapshots);
+    let json = serde_json::from_str::<serde_json::Value>(&json).unwrap();
+    let entries = json.as_array().unwrap();
+    assert_eq!(entries.len(), 5);
+    let last = &entries[4];
+    assert_eq!(last["span"]["column"], 30);
+    assert_eq!(
+        last["before"],
+        serde_json::json!(["Primitive(U64)", "Primitive(U64)"])
+    );
+    assert_eq!(last["after"], serde_json::json!(["Primitive(U64)"]));
+}
@@ -0,0 +1,323 @@
+use crate::{
+    ast::{parse, AstKind, AstNode, Bind, Binding, TopLevel},
+    intrinsics,
+    lexer::{lex, lex_string, Token, RESERVED},
+    span::Span,
+    Result,
+};
+use somok::Somok;
+use std::path::Path;
+use thiserror::Error;
+
+/// Replace the text at `span` with `text`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextEdit {
+    pub span: Span,
+    pub text: String,
+}
+
+#[derive(Debug, Error)]
+pub enum RenameError {
+    #[error("`{0}` is not a valid name")]
+    InvalidName(String),
+    #[error("`{0}` is an intrinsic")]
+    Intrinsic(String),
+    #[error("`{0}` is reserved for a future keyword")]
+    Reserved(String),
+    #[error("No symbol is defined at {0:?}")]
+    NotFound(Span),
+    #[error("`{name}` is already defined")]
+    Collision { name: String, existing: Span },
+    #[error("Renaming would capture a reference to `{name}` at {at:?}")]
+    Captured { name: String, at: Span },
+}
+
+/// Renames the item, binding or local var whose name is at `definition`,
+/// across `root` and every file it includes
+pub fn rename(root: &Path, definition: &Span, new_name: &str) -> Result<Vec<TextEdit>> {
+    let items = parse(lex(root.to_path_buf())?)?
+        .into_values()
+        .collect::<Vec<_>>();
+    rename_in(&items, definition, new_name)?.okay()
+}
+
+/// Renames the symbol defined at `definition` within already resolved `items`
+pub fn rename_in(
+    items: &[TopLevel],
+    definition: &Span,
+    new_name: &str,
+) -> std::result::Result<Vec<TextEdit>, RenameError> {
+    match lex_string(new_name.to_string(), "".into()).as_deref() {
+        Ok([(Token::Word(w), _)]) if w == new_name => (),
+        _ => return RenameError::InvalidName(new_name.to_string()).error(),
+    }
+    if intrinsics::lookup(new_name).is_some() {
+        return RenameError::Intrinsic(new_name.to_string()).error();
+    }
+    if RESERVED.contains(&new_name) {
+        return RenameError::Reserved(new_name.to_string()).error();
+    }
+
+    if let Some(item) = items.iter().find(|i| &i.span() == definition) {
+        if let Some(existing) = items.iter().find(|i| i.name().as_deref() == Some(new_name)) {
+            return RenameError::Collision {
+                name: new_name.to_string(),
+                existing: existing.span(),
+            }
+            .error();
+        }
+        let name = item.name().unwrap();
+        let mut renamer = Renamer::new(&name, new_name, definition);
+        for item in items {
+            for node in item_nodes(item) {
+                renamer.visit(node)?;
+            }
+        }
+        return renamer.edits.okay();
+    }
+
+    let scope = items
+        .iter()
+        .flat_map(item_nodes)
+        .find_map(|node| find_local(node, definition))
+        .ok_or_else(|| RenameError::NotFound(definition.clone()))?;
+    match scope {
+        Scope::Bind(name, bind) => {
+            if let Some(existing) = bind
+                .bindings
+                .iter()
+                .flat_map(binding_names)
+                .find(|n| word(n) == new_name)
+            {
+                return RenameError::Collision {
+                    name: new_name.to_string(),
+                    existing: existing.span.clone(),
+                }
+                .error();
+            }
+            let mut renamer = Renamer::new(name, new_name, definition);
+            renamer.visit(&bind.body)?;
+            renamer.edits.okay()
+        }
+        Scope::Body(name, rest) => {
+            let mut renamer = Renamer::new(name, new_name, definition);
+            renamer.visit_body(rest)?;
+            renamer.edits.okay()
+        }
+    }
+}
+
+/// Where a local is visible
+enum Scope<'a> {
+    Bind(&'a str, &'a Bind),
+    /// The nodes following a `var`
+    Body(&'a str, &'a [AstNode]),
+}
+
+fn find_local<'a>(node: &'a AstNode, definition: &Span) -> Option<Scope<'a>> {
+    match &node.ast {
+        AstKind::Bind(b) => {
+            if let Some(name) = b
+                .bindings
+                .iter()
+                .flat_map(binding_names)
+                .find(|n| &n.span == definition)
+            {
+                return Scope::Bind(word(name), b).some();
+            }
+        }
+        AstKind::Body(nodes) => {
+            for (i, n) in nodes.iter().enumerate() {
+                match &n.ast {
+                    AstKind::Var(v) if &v.name.span == definition => {
+                        return Scope::Body(word(&v.name), &nodes[i + 1..]).some()
+                    }
+                    _ => (),
+                }
+            }
+        }
+        _ => (),
+    }
+    children(node)
+        .into_iter()
+        .find_map(|c| find_local(c, definition))
+}
+
+struct Renamer<'a> {
+    name: &'a str,
+    new_name: &'a str,
+    /// Names bound by the enclosing binds and vars
+    bound: Vec<&'a str>,
+    edits: Vec<TextEdit>,
+}
+
+impl<'a> Renamer<'a> {
+    fn new(name: &'a str, new_name: &'a str, definition: &Span) -> Self {
+        Self {
+            name,
+            new_name,
+            bound: Vec::new(),
+            edits: vec![TextEdit {
+                span: definition.clone(),
+                text: new_name.to_string(),
+            }],
+        }
+    }
+
+    fn reference(&mut self, span: &Span, text: String) -> std::result::Result<(), RenameError> {
+        if self.bound.contains(&self.new_name) {
+            return RenameError::Captured {
+                name: self.name.to_string(),
+                at: span.clone(),
+            }
+            .error();
+        }
+        self.edits.push(TextEdit {
+            span: span.clone(),
+            text,
+        });
+        ().okay()
+    }
+
+    fn visit(&mut self, node: &'a AstNode) -> std::result::Result<(), RenameError> {
+        match &node.ast {
+            AstKind::Word(w) if w == self.name => {
+                self.reference(&node.span, self.new_name.to_string())
+            }
+            // an existing reference to `new_name` would now resolve to the renamed symbol
+            AstKind::Word(w) if w == self.new_name && !self.bound.contains(&self.new_name) => {
+                RenameError::Captured {
+                    name: w.clone(),
+                    at: node.span.clone(),
+                }
+                .error()
+            }
+            AstKind::Type(ty) if ty.type_name == self.name => self.reference(
+                &node.span,
+                format!("{}{}", "&>".repeat(ty.ptr_count), self.new_name),
+            ),
+            AstKind::Body(nodes) => self.visit_body(nodes),
+            AstKind::Bind(b) => {
+                for binding in &b.bindings {
+                    self.visit(binding)?;
+                }
+                let names = b.bindings.iter().flat_map(binding_names).map(word);
+                let depth = self.bound.len();
+                self.bound.extend(names);
+                // shadowed by the bind
+                if !self.bound[depth..].contains(&self.name) {
+                    self.visit(&b.body)?;
+                }
+                self.bound.truncate(depth);
+                ().okay()
+            }
+            _ => {
+                for c in children(node) {
+                    self.visit(c)?;
+                }
+                ().okay()
+            }
+        }
+    }
+
+    fn visit_body(&mut self, nodes: &'a [AstNode]) -> std::result::Result<(), RenameError> {
+        let depth = self.bound.len();
+        for node in nodes {
+            self.visit(node)?;
+            if let AstKind::Var(v) = &node.ast {
+                let name = word(&v.name);
+                // shadowed by the var
+                if name == self.name {
+                    break;
+                }
+                self.bound.push(name);
+            }
+        }
+        self.bound.truncate(depth);
+        ().okay()
+    }
+}
+
+pub(crate) fn word(node: &AstNode) -> &str {
+    crate::coerce_ast!(node => REF Word || unreachable!())
+}
+
+pub(crate) fn binding_names(binding: &AstNode) -> Vec<&AstNode> {
+    match &binding.ast {
+        AstKind::Binding(Binding::Bind { name, .. }) => vec![name],
+        AstKind::Binding(Binding::Destructure { fields }) => fields.iter().collect(),
+        _ => Vec::new(),
+    }
+}
+
+pub(crate) fn item_nodes(item: &TopLevel) -> Vec<&AstNode> {
+    match item {
+        TopLevel::Proc(p) => vec![&p.signature, &p.body],
+        TopLevel::Const(c) => vec![&c.signature, &c.body],
+        TopLevel::Mem(m) => vec![&m.body],
+        TopLevel::Table(t) => vec![&t.ty, &t.body],
+        TopLevel::Var(v) => vec![&v.ty],
+        TopLevel::Struct(s) => s.body.iter().collect(),
+        TopLevel::Extern(e) => vec![&e.signature],
+        TopLevel::Alias(a) => vec![&a.target],
+        TopLevel::Include(_) => Vec::new(),
+    }
+}
+
+/// Nodes that may contain references, definitions are left out
+pub(crate) fn children(node: &AstNode) -> Vec<&AstNode> {
+    match &node.ast {
+        AstKind::Body(nodes) => nodes.iter().collect(),
+        AstKind::Bind(b) => b.bindings.iter().chain([&*b.body]).collect(),
+        AstKind::Binding(Binding::Bind { ty, .. }) => vec![ty],
+        AstKind::Dip(c) | AstKind::Keep(c) | AstKind::Defer(c) => vec![&c.body],
+        AstKind::While(w) => vec![&w.cond, &w.body],
+        AstKind::If(i) => [&*i.truth]
+            .into_iter()
+            .chain(i.lie.iter().map(|e| &*e.body))
+            .collect(),
+        AstKind::Cond(c) => [&*c.pat, &*c.body]
+            .into_iter()
+            .chain(c.branches.iter().flat_map(|b| [&*b.pat, &*b.body]))
+            .collect(),
+        AstKind::Cast(c) => vec![&c.ty],
+        AstKind::Pattern(p) => vec![p],
+        AstKind::Var(v) => vec![&v.ty],
+        AstKind::NamedType(n) => vec![&n.ty],
+        AstKind::StructField(f) => vec![&f.ty],
+        AstKind::ProcSignature(s) => s.ins.iter().chain(s.outs.iter().flatten()).collect(),
+        AstKind::ConstSignature(s) => s.tys.iter().collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[test]
+fn test_rename() {
+    use crate::lir::parse_source;
+
+    let src = "proc inc u64 : u64 do 1 + end\nproc main do bind x: u64 do x inc x + drop end end";
+    let items = parse_source(src);
+    let def = items[0].span();
+    let edits = rename_in(&items, &def, "succ").unwrap();
+    assert_eq!(edits.len(), 2);
+    assert!(edits.iter().all(|e| e.text == "succ"));
+
+    let x = Span::new("test.rh", 48, 49);
+    assert_eq!(rename_in(&items, &x, "y").unwrap().len(), 3);
+    assert!(matches!(
+        rename_in(&items, &x, "inc"),
+        Err(RenameError::Captured { .. })
+    ));
+    assert!(matches!(
+        rename_in(&items, &def, "main"),
+        Err(RenameError::Collision { .. })
+    ));
+    assert!(matches!(
+        rename_in(&items, &def, "dup"),
+        Err(RenameError::Intrinsic(_))
+    ));
+    assert!(matches!(
+        rename_in(&items, &def, "enum"),
+        Err(RenameError::Reserved(_))
+    ));
+}
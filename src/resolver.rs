@@ -1,25 +1,132 @@
 use crate::{
-    ast::{parse, TopLevel},
+    ast::{parse_no_include, TopLevel},
     lexer::lex,
-    Result,
+    span::Span,
+    Error, Result,
 };
+use fnv::FnvHashSet;
 use somok::Somok;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-pub fn resolve_include(
-    included_from: &Path,
-    path: &Path,
-    existing: &mut Vec<TopLevel>,
-) -> Result<()> {
-    let source = if path.is_relative() {
+/// The file an `include` of `path` in `included_from` refers to
+pub fn include_path(included_from: &Path, path: &Path) -> PathBuf {
+    if path.is_relative() {
         included_from.parent().unwrap().join(path)
     } else {
         path.into()
-    };
-    let tokens = lex(source)?;
+    }
+}
 
-    let ast = parse(tokens)?;
+/// `include_path`, or the first of the directories in `search` that has `path` in it if there is
+/// no such file
+pub fn find_include(included_from: &Path, path: &Path, search: &[PathBuf]) -> PathBuf {
+    let next_to = include_path(included_from, path);
+    if next_to.exists() || path.is_absolute() {
+        return next_to;
+    }
+    search
+        .iter()
+        .map(|dir| dir.join(path))
+        .find(|candidate| candidate.exists())
+        .unwrap_or(next_to)
+}
 
-    existing.extend(ast.into_iter().map(|(_, i)| i));
+/// Replaces the includes in `items`, the items of `file`, with the items of the included files,
+/// looked up in `search` if they are not next to the file including them.
+/// Each file is read once however many times it is included, a file including itself through
+/// other files is an error.
+pub fn resolve_includes(
+    file: &Path,
+    items: Vec<TopLevel>,
+    search: &[PathBuf],
+) -> Result<Vec<TopLevel>> {
+    let file = file.canonicalize().unwrap_or_else(|_| file.into());
+    let mut res = Vec::new();
+    let mut seen = FnvHashSet::default();
+    seen.insert(file.clone());
+    let mut open = vec![file];
+    resolve(
+        items,
+        &mut open,
+        &mut Vec::new(),
+        search,
+        &mut seen,
+        &mut res,
+    )?;
+    res.okay()
+}
+
+/// `open` are the files being resolved, `through` the includes that opened all but the first
+fn resolve(
+    items: Vec<TopLevel>,
+    open: &mut Vec<PathBuf>,
+    through: &mut Vec<Span>,
+    search: &[PathBuf],
+    seen: &mut FnvHashSet<PathBuf>,
+    res: &mut Vec<TopLevel>,
+) -> Result<()> {
+    for item in items {
+        let include = match item {
+            TopLevel::Include(include) => include,
+            item => {
+                res.push(item);
+                continue;
+            }
+        };
+        let path = find_include(open.last().unwrap(), include.path(), search).canonicalize()?;
+        if let Some(i) = open.iter().position(|f| *f == path) {
+            let mut spans = through[i..].to_vec();
+            spans.push(include.path.span);
+            return Error::IncludeCycle(spans).error();
+        }
+        if !seen.insert(path.clone()) {
+            continue;
+        }
+        let items = parse_no_include(lex(path.clone())?)?;
+        open.push(path);
+        through.push(include.path.span);
+        resolve(items, open, through, search, seen, res)?;
+        open.pop();
+        through.pop();
+    }
     ().okay()
 }
+
+#[test]
+fn test_resolve_includes() {
+    use crate::ast::{parse, parse_with_include_paths};
+    use std::fs;
+
+    let tmp = crate::build::TempDir::new().unwrap();
+    let write = |name: &str, src: &str| {
+        let path = tmp.path.join(name);
+        fs::write(&path, src).unwrap();
+        path
+    };
+    fs::create_dir(tmp.path.join("lib")).unwrap();
+    write("lib/common.rh", "proc common do end");
+    write("lib/a.rh", "include \"common.rh\" proc a do common end");
+    write("b.rh", "include \"lib/common.rh\" proc b do end");
+    let main = write(
+        "main.rh",
+        "include \"lib/a.rh\" include \"b.rh\" include \"./b.rh\" proc main do a b end",
+    );
+    let items = parse(lex(main).unwrap()).unwrap();
+    let mut names = items.keys().cloned().collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, ["a", "b", "common", "main"]);
+    assert!(items["common"].span().file.ends_with("lib/common.rh"));
+
+    write("x.rh", "include \"y.rh\" proc x do end");
+    write("y.rh", "include \"x.rh\" proc y do end");
+    let main = write("cycle.rh", "include \"x.rh\" proc main do end");
+    assert!(matches!(
+        parse(lex(main).unwrap()),
+        Err(Error::IncludeCycle(spans)) if spans.len() == 2
+    ));
+
+    let main = write("search.rh", "include \"common.rh\" proc main do common end");
+    assert!(parse(lex(main.clone()).unwrap()).is_err());
+    let items = parse_with_include_paths(lex(main).unwrap(), &[tmp.path.join("lib")]).unwrap();
+    assert!(items["common"].span().file.ends_with("lib/common.rh"));
+}
@@ -0,0 +1,771 @@
+use crate::{
+    lir::{LirPass, OptLevel, Passes, Pipeline},
+    typecheck::StackSnapshots,
+    types::StructIndex,
+};
+use fnv::FnvHashMap;
+use somok::Somok;
+use std::{
+    cell::{Ref, RefCell},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use thiserror::Error;
+
+/// Everything shared by the compiler passes of a single compilation.
+///
+/// Struct layouts and field offsets are computed once, by `define_structs`, and looked up in
+/// `structs`, every other type is a scalar. Types aren't interned: a `Type` is a small `Copy`
+/// value, comparing two is as cheap as comparing interned ids would be.
+#[derive(Default)]
+pub struct Session {
+    pub options: Options,
+    /// Struct layouts, indexed by `StructId`
+    pub structs: StructIndex,
+    /// Type stacks recorded by the typechecker, kept even if checking fails
+    pub snapshots: StackSnapshots,
+    strings: Interner,
+}
+
+impl Session {
+    pub fn new(structs: StructIndex, options: Options) -> Self {
+        Self {
+            options,
+            structs,
+            ..Default::default()
+        }
+    }
+
+    /// Interns a string literal, returning its index in the string table
+    pub fn intern_str(&self, s: String) -> usize {
+        self.strings.intern(s)
+    }
+
+    /// All string literals interned so far
+    pub fn strings(&self) -> Ref<'_, [String]> {
+        self.strings.strings()
+    }
+}
+
+/// Everything a compilation can be asked to do differently, build it with `Options::builder`
+/// to have it validated
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub target: Target,
+    /// What runs the program, the assembly backend of `target` if `None`.
+    pub engine: Option<Engine>,
+    /// 0 lowers procs as they are, 1 lowers them through the MIR, folding constants and removing
+    /// dead code on the way, and 2 also inlines small procs, hoists loop invariants, fuses
+    /// compares with branches and keeps the top of the data stack in registers in NASM output.
+    pub opt_level: u8,
+    /// Peepholes run over the lowered program, those of the `opt_level` unless picked otherwise.
+    pub peepholes: OptLevel,
+    /// Passes besides the peepholes, those of the `opt_level` unless picked otherwise.
+    pub pipeline: Pipeline,
+    /// Emit runtime safety checks along with the trap routines they jump to.
+    pub checked: bool,
+    /// Reject provably misaligned multi-byte memory accesses and trap on the rest in checked builds.
+    pub strict_alignment: bool,
+    pub runtime: Runtime,
+    /// Verify LIR invariants after lowering, always done in debug builds of the compiler.
+    pub verify_lir: bool,
+    /// Strip symbols from executables produced by `build_executable`.
+    pub strip: bool,
+    /// What the comments in emitted assembly say about each op.
+    pub comments: Comments,
+    /// Bytes reserved for each of the return, locals and escaping stacks of compiled programs.
+    pub stack_size: u64,
+    /// What becomes of typecheck warnings.
+    pub warnings: WarningLevel,
+    /// Directories searched for included files not found next to the file including them.
+    pub include_paths: Vec<PathBuf>,
+    /// Fixed addresses of mems and strings by symbol name, see `emit::symbols`.
+    /// Each of them is emitted in a section of its own for the linker to place.
+    pub placements: FnvHashMap<String, u64>,
+    /// Prefixes of source paths to rewrite wherever they end up in the output.
+    pub remap_path_prefix: Vec<PathRemap>,
+    /// Custom LIR passes, run after lowering.
+    pub passes: Passes,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            target: Default::default(),
+            engine: None,
+            opt_level: 0,
+            peepholes: OptLevel::NONE,
+            pipeline: Pipeline::preset(0),
+            checked: false,
+            strict_alignment: false,
+            runtime: Default::default(),
+            verify_lir: false,
+            strip: false,
+            comments: Default::default(),
+            stack_size: 65536,
+            warnings: Default::default(),
+            include_paths: Vec::new(),
+            placements: Default::default(),
+            remap_path_prefix: Vec::new(),
+            passes: Default::default(),
+        }
+    }
+}
+
+impl Options {
+    pub fn builder() -> OptionsBuilder {
+        OptionsBuilder::default()
+    }
+
+    /// What runs the program
+    pub fn engine(&self) -> Engine {
+        self.engine.unwrap_or(match self.target {
+            Target::X86_64Linux => Engine::Nasm,
+            Target::Aarch64Linux => Engine::Aarch64,
+        })
+    }
+
+    /// Names of the passes a compilation runs, in order, for comparing optimization levels
+    pub fn pass_names(&self) -> Vec<String> {
+        let mut names = self
+            .pipeline
+            .names(self.peepholes)
+            .into_iter()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        // custom passes run on the final LIR, the register cache only while emitting it
+        let custom = names.len() - self.pipeline.register_cache as usize;
+        names.splice(custom..custom, self.passes.names());
+        names
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum OptionsError {
+    #[error("there is no optimization level {0}, only 0, 1 and 2")]
+    OptLevel(u8),
+    #[error("stack size {0} is not a positive multiple of 16 bytes")]
+    StackSize(u64),
+    #[error("include path `{}` is not a directory", .0.display())]
+    IncludePath(PathBuf),
+    #[error("`{0}` and `{1}` are both placed at {2:#x}")]
+    SamePlacement(String, String, u64),
+    #[error("checked builds trap, but the {0} backend has no trap routines")]
+    CheckedWithoutTraps(&'static str),
+    #[error("only linked executables can place symbols, not what the {0} backend makes")]
+    PlacementsWithoutLinker(&'static str),
+    #[error(
+        "the register cache of `-O2` is only made while emitting, past where the LIR is verified"
+    )]
+    VerifyRegisterCache,
+}
+
+/// Collects `Options`, checking them all at once in `build`
+#[derive(Debug, Clone, Default)]
+pub struct OptionsBuilder {
+    options: Options,
+}
+
+impl OptionsBuilder {
+    pub fn target(mut self, target: Target) -> Self {
+        self.options.target = target;
+        self
+    }
+
+    pub fn engine(mut self, engine: Engine) -> Self {
+        self.options.engine = engine.some();
+        self
+    }
+
+    /// Also picks the peepholes and pipeline of the level, so pick others after it
+    pub fn opt_level(mut self, level: u8) -> Self {
+        self.options.opt_level = level;
+        self.options.peepholes = OptLevel::from_level(level);
+        self.options.pipeline = Pipeline::preset(level);
+        self
+    }
+
+    pub fn peepholes(mut self, peepholes: OptLevel) -> Self {
+        self.options.peepholes = peepholes;
+        self
+    }
+
+    pub fn pipeline(mut self, pipeline: Pipeline) -> Self {
+        self.options.pipeline = pipeline;
+        self
+    }
+
+    pub fn checked(mut self, checked: bool) -> Self {
+        self.options.checked = checked;
+        self
+    }
+
+    pub fn strict_alignment(mut self, strict: bool) -> Self {
+        self.options.strict_alignment = strict;
+        self
+    }
+
+    pub fn runtime(mut self, runtime: Runtime) -> Self {
+        self.options.runtime = runtime;
+        self
+    }
+
+    pub fn verify_lir(mut self, verify: bool) -> Self {
+        self.options.verify_lir = verify;
+        self
+    }
+
+    pub fn strip(mut self, strip: bool) -> Self {
+        self.options.strip = strip;
+        self
+    }
+
+    pub fn comments(mut self, comments: Comments) -> Self {
+        self.options.comments = comments;
+        self
+    }
+
+    pub fn stack_size(mut self, bytes: u64) -> Self {
+        self.options.stack_size = bytes;
+        self
+    }
+
+    pub fn warnings(mut self, level: WarningLevel) -> Self {
+        self.options.warnings = level;
+        self
+    }
+
+    /// Searched after the ones added before it
+    pub fn include_path(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.options.include_paths.push(dir.into());
+        self
+    }
+
+    pub fn placements(mut self, placements: FnvHashMap<String, u64>) -> Self {
+        self.options.placements = placements;
+        self
+    }
+
+    /// Applied after the ones added before it
+    pub fn remap_path_prefix(mut self, remap: PathRemap) -> Self {
+        self.options.remap_path_prefix.push(remap);
+        self
+    }
+
+    pub fn pass(mut self, pass: impl LirPass + 'static) -> Self {
+        self.options.passes.register(pass);
+        self
+    }
+
+    pub fn build(self) -> Result<Options, OptionsError> {
+        let options = self.options;
+        if options.opt_level > 2 {
+            return OptionsError::OptLevel(options.opt_level).error();
+        }
+        if options.stack_size == 0 || options.stack_size & 15 != 0 {
+            return OptionsError::StackSize(options.stack_size).error();
+        }
+        if let Some(dir) = options.include_paths.iter().find(|dir| !dir.is_dir()) {
+            return OptionsError::IncludePath(dir.clone()).error();
+        }
+        let mut placed = options.placements.iter().collect::<Vec<_>>();
+        placed.sort_by(|(a, x), (b, y)| (x, a).cmp(&(y, b)));
+        if let Some(pair) = placed.windows(2).find(|pair| pair[0].1 == pair[1].1) {
+            let ((a, &address), (b, _)) = (pair[0], pair[1]);
+            return OptionsError::SamePlacement(a.clone(), b.clone(), address).error();
+        }
+        let engine = options.engine();
+        if options.checked && !engine.traps() {
+            return OptionsError::CheckedWithoutTraps(engine.name()).error();
+        }
+        if !options.placements.is_empty() && !engine.links() {
+            return OptionsError::PlacementsWithoutLinker(engine.name()).error();
+        }
+        if options.verify_lir && options.pipeline.register_cache {
+            return OptionsError::VerifyRegisterCache.error();
+        }
+        options.okay()
+    }
+}
+
+/// What typecheck warnings do to a compilation
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WarningLevel {
+    /// Dropped
+    Allow,
+    /// Returned along with the program
+    #[default]
+    Warn,
+    /// Failing it with `Error::DeniedWarnings`
+    Deny,
+}
+
+impl WarningLevel {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "allow" => WarningLevel::Allow.some(),
+            "warn" => WarningLevel::Warn.some(),
+            "deny" => WarningLevel::Deny.some(),
+            _ => None,
+        }
+    }
+}
+
+/// Rewrites paths starting with `from` to start with `to` instead, so that what is built
+/// doesn't depend on where the sources are checked out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathRemap {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+impl PathRemap {
+    /// `path` rewritten by the last of `remaps` whose prefix it starts with
+    pub fn apply(remaps: &[PathRemap], path: &Path) -> PathBuf {
+        remaps
+            .iter()
+            .rev()
+            .find_map(|r| path.strip_prefix(&r.from).ok().map(|rest| r.to.join(rest)))
+            .unwrap_or_else(|| path.to_path_buf())
+    }
+}
+
+/// `FROM=TO`, as `--remap-path-prefix` takes it
+impl FromStr for PathRemap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((from, to)) => Self {
+                from: from.into(),
+                to: to.into(),
+            }
+            .okay(),
+            None => format!("expected `FROM=TO`, found `{}`", s).error(),
+        }
+    }
+}
+
+/// Verbosity of the comments written above the instructions of each op
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Comments {
+    None,
+    /// The op itself
+    #[default]
+    Ops,
+    /// The op and where in the source it comes from
+    Spans,
+    /// The op, with each source line printed above the first op lowered from it
+    Source,
+}
+
+impl Comments {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Comments::None.some(),
+            "ops" => Comments::Ops.some(),
+            "spans" => Comments::Spans.some(),
+            "source" => Comments::Source.some(),
+            _ => None,
+        }
+    }
+}
+
+/// Runtime pieces bundled with the program, turn them off to link your own runtime object instead
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Runtime {
+    /// `print_fmt` and the `print` words using it
+    pub print: bool,
+    /// Trap routines jumped to by the checks of checked builds
+    pub traps: bool,
+}
+
+impl Default for Runtime {
+    fn default() -> Self {
+        Self {
+            print: true,
+            traps: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Target {
+    #[default]
+    X86_64Linux,
+    Aarch64Linux,
+}
+
+impl Target {
+    pub fn triple(self) -> &'static str {
+        match self {
+            Target::X86_64Linux => "x86_64-unknown-linux-gnu",
+            Target::Aarch64Linux => "aarch64-unknown-linux-gnu",
+        }
+    }
+
+    /// Backend for a target triple, `None` if there is none for its architecture or OS
+    pub fn from_triple(triple: &str) -> Option<Self> {
+        let mut parts = triple.split('-');
+        let arch = parts.next()?;
+        let linux = parts.any(|part| part == "linux");
+        match arch {
+            "x86_64" | "amd64" if linux => Target::X86_64Linux.some(),
+            "aarch64" | "arm64" if linux => Target::Aarch64Linux.some(),
+            _ => None,
+        }
+    }
+
+    /// Consts every program built for this target can use, namespaced like `sys.SYS_write`.
+    /// Syscall numbers are the x86-64 ones on every target, as the AArch64 backend translates
+    /// them, so only the syscalls it can translate are defined there.
+    pub fn consts(self) -> Vec<(String, u64)> {
+        let syscalls = x86_64_syscalls()
+            .filter(|(name, _)| match self {
+                Target::X86_64Linux => true,
+                Target::Aarch64Linux => AARCH64_SYSCALLS.iter().any(|(n, _, _)| n == name),
+            })
+            .map(|(name, nr)| (format!("SYS_{}", name), nr));
+        let open_flags = match self {
+            Target::X86_64Linux => X86_64_OPEN_FLAGS,
+            Target::Aarch64Linux => AARCH64_OPEN_FLAGS,
+        };
+        let flags = OPEN_FLAGS
+            .iter()
+            .chain(open_flags)
+            .chain(PROT_FLAGS)
+            .chain(MAP_FLAGS)
+            .map(|&(name, value)| (name.to_string(), value));
+        syscalls
+            .chain(flags)
+            .map(|(name, value)| (format!("sys.{}", name), value))
+            .collect()
+    }
+}
+
+/// What runs a program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    Interpreter,
+    /// x86-64 assembly, see `emit`
+    Nasm,
+    /// x86-64 executables written directly, see `emit::elf`
+    Elf,
+    Aarch64,
+    C,
+    Wasm,
+    /// The JIT of the `cranelift` feature
+    Cranelift,
+}
+
+impl Engine {
+    /// Every engine of this build
+    pub fn all() -> Vec<Self> {
+        let mut all = vec![
+            Engine::Interpreter,
+            Engine::Nasm,
+            Engine::Elf,
+            Engine::Aarch64,
+            Engine::C,
+            Engine::Wasm,
+        ];
+        if cfg!(feature = "cranelift") {
+            all.push(Engine::Cranelift);
+        }
+        all
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Engine::Interpreter => "interpreter",
+            Engine::Nasm => "nasm",
+            Engine::Elf => "elf",
+            Engine::Aarch64 => "aarch64",
+            Engine::C => "c",
+            Engine::Wasm => "wasm",
+            Engine::Cranelift => "cranelift",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        // the JIT can be audited without being built in
+        Self::all()
+            .into_iter()
+            .chain([Engine::Cranelift])
+            .find(|e| e.name() == name)
+    }
+
+    /// Whether checked builds have routines to trap to here
+    pub fn traps(self) -> bool {
+        self != Engine::Cranelift
+    }
+
+    /// Whether what this makes goes through the linker, which places symbols
+    pub fn links(self) -> bool {
+        matches!(self, Engine::Nasm | Engine::Elf | Engine::Aarch64)
+    }
+}
+
+/// Syscalls with their x86-64 numbers, as `syscalls.rh` defines them
+pub(crate) fn x86_64_syscalls() -> impl Iterator<Item = (&'static str, u64)> {
+    include_str!("../rotth-src/syscalls.rh")
+        .lines()
+        .filter_map(|line| {
+            let (name, rest) = line.strip_prefix("const SYS_")?.split_once(':')?;
+            let nr = rest.trim().strip_prefix("u64 do ")?.strip_suffix(" end")?;
+            (name, nr.parse().ok()?).some()
+        })
+}
+
+/// Syscalls as numbered by `syscalls.rh` and their AArch64 Linux numbers.
+/// Programs pass x86-64 numbers, the AArch64 backend translates them through a table.
+/// Legacy syscalls like `open` or `fork` only have `*at` or `clone` replacements on AArch64.
+pub const AARCH64_SYSCALLS: &[(&str, u64, u64)] = &[
+    ("read", 0, 63),
+    ("write", 1, 64),
+    ("close", 3, 57),
+    ("fstat", 5, 80),
+    ("lseek", 8, 62),
+    ("mmap", 9, 222),
+    ("mprotect", 10, 226),
+    ("munmap", 11, 215),
+    ("brk", 12, 214),
+    ("rt_sigaction", 13, 134),
+    ("rt_sigprocmask", 14, 135),
+    ("rt_sigreturn", 15, 139),
+    ("ioctl", 16, 29),
+    ("pread64", 17, 67),
+    ("pwrite64", 18, 68),
+    ("readv", 19, 65),
+    ("writev", 20, 66),
+    ("sched_yield", 24, 124),
+    ("mremap", 25, 216),
+    ("msync", 26, 227),
+    ("madvise", 28, 233),
+    ("dup", 32, 23),
+    ("nanosleep", 35, 101),
+    ("getpid", 39, 172),
+    ("sendfile", 40, 71),
+    ("socket", 41, 198),
+    ("connect", 42, 203),
+    ("accept", 43, 202),
+    ("sendto", 44, 206),
+    ("recvfrom", 45, 207),
+    ("shutdown", 48, 210),
+    ("bind", 49, 200),
+    ("listen", 50, 201),
+    ("clone", 56, 220),
+    ("execve", 59, 221),
+    ("exit", 60, 93),
+    ("wait4", 61, 260),
+    ("kill", 62, 129),
+    ("uname", 63, 160),
+    ("fcntl", 72, 25),
+    ("flock", 73, 32),
+    ("fsync", 74, 82),
+    ("fdatasync", 75, 83),
+    ("truncate", 76, 45),
+    ("ftruncate", 77, 46),
+    ("getcwd", 79, 17),
+    ("chdir", 80, 49),
+    ("fchdir", 81, 50),
+    ("fchmod", 91, 52),
+    ("fchown", 93, 55),
+    ("umask", 95, 166),
+    ("gettimeofday", 96, 169),
+    ("getrlimit", 97, 163),
+    ("getuid", 102, 174),
+    ("getgid", 104, 176),
+    ("setuid", 105, 146),
+    ("setgid", 106, 144),
+    ("geteuid", 107, 175),
+    ("getegid", 108, 177),
+    ("getppid", 110, 173),
+    ("setsid", 112, 157),
+    ("gettid", 186, 178),
+    ("futex", 202, 98),
+    ("getdents64", 217, 61),
+    ("set_tid_address", 218, 96),
+    ("clock_gettime", 228, 113),
+    ("exit_group", 231, 94),
+    ("tgkill", 234, 131),
+    ("openat", 257, 56),
+    ("mkdirat", 258, 34),
+    ("newfstatat", 262, 79),
+    ("unlinkat", 263, 35),
+    ("renameat", 264, 38),
+    ("readlinkat", 267, 78),
+    ("fchmodat", 268, 53),
+    ("faccessat", 269, 48),
+    ("dup3", 292, 24),
+    ("pipe2", 293, 59),
+    ("prlimit64", 302, 261),
+];
+
+/// `open` flags shared by every Linux architecture
+const OPEN_FLAGS: &[(&str, u64)] = &[
+    ("O_RDONLY", 0),
+    ("O_WRONLY", 0o1),
+    ("O_RDWR", 0o2),
+    ("O_CREAT", 0o100),
+    ("O_EXCL", 0o200),
+    ("O_NOCTTY", 0o400),
+    ("O_TRUNC", 0o1000),
+    ("O_APPEND", 0o2000),
+    ("O_NONBLOCK", 0o4000),
+    ("O_DSYNC", 0o10000),
+    ("O_NOATIME", 0o1000000),
+    ("O_CLOEXEC", 0o2000000),
+    ("O_SYNC", 0o4010000),
+    ("O_PATH", 0o10000000),
+];
+
+const X86_64_OPEN_FLAGS: &[(&str, u64)] = &[
+    ("O_DIRECT", 0o40000),
+    ("O_LARGEFILE", 0o100000),
+    ("O_DIRECTORY", 0o200000),
+    ("O_NOFOLLOW", 0o400000),
+];
+
+const AARCH64_OPEN_FLAGS: &[(&str, u64)] = &[
+    ("O_DIRECTORY", 0o40000),
+    ("O_NOFOLLOW", 0o100000),
+    ("O_DIRECT", 0o200000),
+    ("O_LARGEFILE", 0o400000),
+];
+
+const PROT_FLAGS: &[(&str, u64)] = &[
+    ("PROT_NONE", 0),
+    ("PROT_READ", 0x1),
+    ("PROT_WRITE", 0x2),
+    ("PROT_EXEC", 0x4),
+];
+
+const MAP_FLAGS: &[(&str, u64)] = &[
+    ("MAP_SHARED", 0x1),
+    ("MAP_PRIVATE", 0x2),
+    ("MAP_FIXED", 0x10),
+    ("MAP_ANONYMOUS", 0x20),
+    ("MAP_GROWSDOWN", 0x100),
+    ("MAP_NORESERVE", 0x4000),
+    ("MAP_POPULATE", 0x8000),
+    ("MAP_STACK", 0x20000),
+    ("MAP_FIXED_NOREPLACE", 0x100000),
+];
+
+#[derive(Default)]
+struct Interner {
+    strings: RefCell<Vec<String>>,
+    ids: RefCell<FnvHashMap<String, usize>>,
+}
+
+impl Interner {
+    fn intern(&self, s: String) -> usize {
+        if let Some(&id) = self.ids.borrow().get(&s) {
+            return id;
+        }
+        let mut strings = self.strings.borrow_mut();
+        let id = strings.len();
+        strings.push(s.clone());
+        self.ids.borrow_mut().insert(s, id);
+        id
+    }
+
+    fn strings(&self) -> Ref<'_, [String]> {
+        Ref::map(self.strings.borrow(), Vec::as_slice)
+    }
+}
+
+#[test]
+fn test_options_builder() {
+    let options = Options::builder()
+        .target(Target::Aarch64Linux)
+        .opt_level(1)
+        .stack_size(1 << 20)
+        .build()
+        .unwrap();
+    assert_eq!(options.target, Target::Aarch64Linux);
+    assert_eq!(options.stack_size, 1 << 20);
+    assert_eq!(options.peepholes, OptLevel::ALL);
+    assert_eq!(options.pipeline, Pipeline::preset(1));
+    let error = |builder: OptionsBuilder| builder.build().unwrap_err();
+    assert_eq!(
+        error(Options::builder().opt_level(3)),
+        OptionsError::OptLevel(3)
+    );
+    let names = |level| {
+        let options = Options::builder().opt_level(level).build().unwrap();
+        options.pass_names().join(" ")
+    };
+    assert_eq!(names(0), "inline hot-cold dead-procs");
+    assert!(names(1).starts_with("mir-fold mir-dce merge-once inline order-procs hot-cold"));
+    assert!(names(2).contains("licm fuse-compare inline-small"));
+    assert!(names(2).ends_with("dead-procs register-cache"));
+    assert_eq!(
+        error(Options::builder().stack_size(100)),
+        OptionsError::StackSize(100)
+    );
+    assert_eq!(
+        error(Options::builder().include_path("/nonexistent/rotth")),
+        OptionsError::IncludePath("/nonexistent/rotth".into())
+    );
+    let placements = [("a", 0x1000), ("b", 0x2000), ("c", 0x1000)]
+        .into_iter()
+        .map(|(name, address)| (name.to_string(), address))
+        .collect();
+    assert_eq!(
+        error(Options::builder().placements(placements)),
+        OptionsError::SamePlacement("a".into(), "c".into(), 0x1000)
+    );
+
+    // options each engine can't honor
+    assert_eq!(
+        error(Options::builder().engine(Engine::Cranelift).checked(true)),
+        OptionsError::CheckedWithoutTraps("cranelift")
+    );
+    assert!(Options::builder()
+        .engine(Engine::C)
+        .checked(true)
+        .build()
+        .is_ok());
+    let placements = [("mem_buf".to_string(), 0x800000)].into_iter().collect();
+    assert_eq!(
+        error(
+            Options::builder()
+                .engine(Engine::Wasm)
+                .placements(placements)
+        ),
+        OptionsError::PlacementsWithoutLinker("wasm")
+    );
+    assert_eq!(
+        error(Options::builder().opt_level(2).verify_lir(true)),
+        OptionsError::VerifyRegisterCache
+    );
+    let mut pipeline = Pipeline::preset(2);
+    pipeline.register_cache = false;
+    assert!(Options::builder()
+        .opt_level(2)
+        .pipeline(pipeline)
+        .verify_lir(true)
+        .build()
+        .is_ok());
+}
+
+#[test]
+fn test_target_consts() {
+    let x86 = Target::X86_64Linux
+        .consts()
+        .into_iter()
+        .collect::<FnvHashMap<_, _>>();
+    assert_eq!(x86["sys.SYS_write"], 1);
+    assert_eq!(x86["sys.SYS_open"], 2);
+    assert_eq!(x86["sys.O_DIRECTORY"], 0o200000);
+    let aarch64 = Target::Aarch64Linux
+        .consts()
+        .into_iter()
+        .collect::<FnvHashMap<_, _>>();
+    assert_eq!(aarch64["sys.SYS_write"], 1);
+    assert!(!aarch64.contains_key("sys.SYS_open"));
+    assert_eq!(aarch64["sys.O_DIRECTORY"], 0o40000);
+    assert_eq!(aarch64["sys.MAP_ANONYMOUS"], 0x20);
+}
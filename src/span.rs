@@ -1,9 +1,11 @@
+use fnv::FnvHashMap;
+use serde::Serialize;
 use std::{
     ops::Range,
     path::{Path, PathBuf},
 };
 
-#[derive(Clone, Hash, PartialEq, Eq)]
+#[derive(Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize)]
 pub struct Span {
     pub file: PathBuf,
     pub start: usize,
@@ -38,6 +40,45 @@ impl Span {
             end: other.end,
         }
     }
+
+    /// The smallest span covering both, `None` if they are in different files
+    pub fn cover(&self, other: &Self) -> Option<Self> {
+        if self.file != other.file {
+            return None;
+        }
+        Some(Self {
+            file: self.file.clone(),
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        })
+    }
+
+    pub fn contains(&self, other: &Self) -> bool {
+        self.file == other.file && self.start <= other.start && other.end <= self.end
+    }
+
+    pub fn contains_offset(&self, offset: usize) -> bool {
+        self.start <= offset && offset < self.end
+    }
+
+    pub fn overlaps(&self, other: &Self) -> bool {
+        self.file == other.file && self.start < other.end && other.start < self.end
+    }
+
+    /// One based line and column of the start, as `file:line:col` shows them
+    pub fn line_col(&self, lines: &LineIndex) -> (usize, usize) {
+        let (line, col) = lines.line_col(self.start);
+        (line + 1, col + 1)
+    }
+
+    /// The text covered by the span in `source`, the text of its file
+    pub fn snippet<'s>(&self, source: &'s Source) -> &'s str {
+        source.slice(self.range())
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
 }
 
 impl std::fmt::Debug for Span {
@@ -87,3 +128,214 @@ impl chumsky::Span for Span {
         self.end
     }
 }
+
+/// Source text of the files spans point into. Spans count chars, not bytes.
+#[derive(Debug, Default)]
+pub struct SourceMap {
+    files: FnvHashMap<PathBuf, Source>,
+}
+
+/// Where the lines of a file start, to find the line and column of an offset without its text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineIndex {
+    /// Char offset of the start of every line
+    starts: Vec<usize>,
+}
+
+impl LineIndex {
+    pub fn new(text: &str) -> Self {
+        let starts = [0]
+            .into_iter()
+            .chain(
+                text.chars()
+                    .enumerate()
+                    .filter(|(_, c)| *c == '\n')
+                    .map(|(i, _)| i + 1),
+            )
+            .collect();
+        Self { starts }
+    }
+
+    /// Zero based line and column of a char offset
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let line = self.starts.partition_point(|&l| l <= offset) - 1;
+        (line, offset - self.starts[line])
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.starts.len()
+    }
+}
+
+#[derive(Debug)]
+pub struct Source {
+    text: String,
+    /// Byte offset of every char, and of the end of the text
+    chars: Vec<usize>,
+    lines: LineIndex,
+}
+
+impl Source {
+    pub fn new(text: String) -> Self {
+        let chars = text
+            .char_indices()
+            .map(|(i, _)| i)
+            .chain([text.len()])
+            .collect::<Vec<_>>();
+        let lines = LineIndex::new(&text);
+        Self { text, chars, lines }
+    }
+
+    pub fn line_index(&self) -> &LineIndex {
+        &self.lines
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Text between the char offsets `range`, clamped to the end of the source
+    pub fn slice(&self, range: Range<usize>) -> &str {
+        let last = self.chars.len() - 1;
+        &self.text[self.chars[range.start.min(last)]..self.chars[range.end.min(last)]]
+    }
+
+    /// Zero based line and column of a char offset
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        self.lines.line_col(offset)
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.lines.line_count()
+    }
+
+    /// Text of a zero based line, without the line break
+    pub fn line(&self, line: usize) -> &str {
+        let start = self.lines.starts[line];
+        let end = self
+            .lines
+            .starts
+            .get(line + 1)
+            .map_or(self.chars.len() - 1, |&l| l - 1);
+        self.slice(start..end)
+    }
+}
+
+/// Lines of one file around one or more spans
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Context {
+    pub file: PathBuf,
+    /// Zero based number of the first line
+    pub first_line: usize,
+    pub lines: Vec<String>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a file that is not on disk, or not saved yet
+    pub fn insert(&mut self, file: impl Into<PathBuf>, text: String) {
+        self.files.insert(file.into(), Source::new(text));
+    }
+
+    /// Reads `file` from disk unless it is already known
+    pub fn load(&mut self, file: &Path) -> std::io::Result<&Source> {
+        if !self.files.contains_key(file) {
+            let text = std::fs::read_to_string(file)?;
+            self.insert(file, text);
+        }
+        Ok(&self.files[file])
+    }
+
+    /// Reads every file `spans` point into
+    pub fn load_spans<'s>(
+        &mut self,
+        spans: impl IntoIterator<Item = &'s Span>,
+    ) -> std::io::Result<()> {
+        for span in spans {
+            self.load(&span.file)?;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, file: &Path) -> Option<&Source> {
+        self.files.get(file)
+    }
+
+    /// The text covered by `span`
+    pub fn snippet(&self, span: &Span) -> Option<&str> {
+        self.get(&span.file).map(|s| span.snippet(s))
+    }
+
+    /// One based line and column of the start of `span`
+    pub fn line_col(&self, span: &Span) -> Option<(usize, usize)> {
+        Some(span.line_col(self.get(&span.file)?.line_index()))
+    }
+
+    /// `file:line:column` of the start of `span`
+    pub fn location(&self, span: &Span) -> Option<String> {
+        let (line, col) = self.line_col(span)?;
+        Some(format!("{}:{}:{}", span.file.display(), line, col))
+    }
+
+    /// The lines covered by `spans` with `margin` lines around them, one window per
+    /// file where the windows overlap or touch, in the order the files first appear
+    pub fn contexts<'s>(
+        &self,
+        spans: impl IntoIterator<Item = &'s Span>,
+        margin: usize,
+    ) -> Vec<Context> {
+        let mut windows: Vec<(PathBuf, Range<usize>)> = Vec::new();
+        for span in spans {
+            let source = match self.get(&span.file) {
+                Some(source) => source,
+                None => continue,
+            };
+            let (first, _) = source.line_col(span.start);
+            let (last, _) = source.line_col(span.end.saturating_sub(1).max(span.start));
+            let lines = first.saturating_sub(margin)..(last + margin + 1).min(source.line_count());
+            match windows
+                .iter_mut()
+                .find(|(f, w)| f == &span.file && w.start <= lines.end && lines.start <= w.end)
+            {
+                Some((_, w)) => *w = w.start.min(lines.start)..w.end.max(lines.end),
+                None => windows.push((span.file.clone(), lines)),
+            }
+        }
+        windows
+            .into_iter()
+            .map(|(file, lines)| {
+                let source = &self.files[&file];
+                Context {
+                    first_line: lines.start,
+                    lines: lines.map(|l| source.line(l).to_string()).collect(),
+                    file,
+                }
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_source_map() {
+    let mut map = SourceMap::new();
+    map.insert("a.rh", "proc main do\n  \"é\" drop\nend\n".to_string());
+    map.insert("b.rh", "const X: u64 do 1 end".to_string());
+    let str_ = Span::new("a.rh", 15, 18);
+    assert_eq!(map.snippet(&str_), Some("\"é\""));
+    assert_eq!(map.line_col(&str_), Some((2, 3)));
+    let lines = LineIndex::new("proc main do\n  \"é\" drop\nend\n");
+    assert_eq!((str_.line_col(&lines), lines.line_count()), ((2, 3), 4));
+    assert_eq!(str_.snippet(map.get(Path::new("a.rh")).unwrap()), "\"é\"");
+    let drop = Span::new("a.rh", 19, 23);
+    assert_eq!(str_.cover(&drop), Some(Span::new("a.rh", 15, 23)));
+    assert!(str_.cover(&Span::new("b.rh", 0, 5)).is_none());
+
+    let contexts = map.contexts([&str_, &Span::new("b.rh", 6, 7), &drop], 1);
+    assert_eq!(contexts.len(), 2);
+    assert_eq!(contexts[0].first_line, 0);
+    assert_eq!(contexts[0].lines, ["proc main do", "  \"é\" drop", "end"]);
+    assert_eq!(contexts[1].lines, ["const X: u64 do 1 end"]);
+}
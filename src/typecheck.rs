@@ -1,11 +1,20 @@
 use fnv::FnvHashMap;
 use simplearena::{Heap, Ref};
 use somok::Somok;
-use std::collections::VecDeque;
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, VecDeque},
+    path::Path,
+};
 
 use crate::{
-    hir::{self, Binding, CondBranch, HirKind, HirNode, If, Intrinsic, TopLevel},
+    hir::{
+        self, body_span, jumps_out, Binding, CondBranch, Destructure, HirKind, HirNode, If,
+        Intrinsic, TopLevel,
+    },
     iconst::IConst,
+    intrinsics::{self, info_of},
+    session::{Runtime, Session, WarningLevel},
     span::Span,
     types::{StructIndex, Type, ValueType},
     Error,
@@ -18,7 +27,7 @@ pub struct TypecheckError {
     pub message: String,
 }
 impl TypecheckError {
-    fn new(span: Span, kind: ErrorKind, message: impl ToString) -> TypecheckError {
+    pub(crate) fn new(span: Span, kind: ErrorKind, message: impl ToString) -> TypecheckError {
         TypecheckError {
             span,
             kind,
@@ -36,20 +45,380 @@ pub enum ErrorKind {
     NotEnoughData,
     Undefined(String),
     InvalidMain,
-    InvalidWhile,
+    /// A while whose condition and body together change the stack
+    InvalidWhile {
+        entry: Vec<Type>,
+        body: Box<BranchStack>,
+    },
+    /// Branches of an `if` that leave different stacks
+    BranchMismatch {
+        truth: Box<BranchStack>,
+        lie: Box<BranchStack>,
+    },
+    /// Repeated or unreachable branches, or values no branch matches
+    InvalidCond,
+    /// Deferred code that changes the stack or returns
+    InvalidDefer,
     CompStop,
     Unexpected,
     CallInConst,
+    /// Something a `#[pure]` proc can't do
+    Impure,
+    /// Needs a piece of the runtime that was opted out of
+    NoRuntime(&'static str),
+    Misaligned {
+        required: usize,
+        offset: usize,
+    },
+    DynamicDepth(&'static str),
+    InvalidCast {
+        from: Type,
+        to: Type,
+    },
+    /// A type variable of proc outputs that no input gives a type
+    UnboundTypeVar(char),
+    /// An unsuffixed literal too big for the type it settled to
+    LiteralOutOfRange(Type),
 }
 use ErrorKind::*;
+
+/// What a branch or loop body leaves on the stack
+#[derive(Debug)]
+pub struct BranchStack {
+    pub stack: Vec<Type>,
+    /// Its code, `None` if it is empty or a missing `else`
+    pub span: Option<Span>,
+}
+
+impl BranchStack {
+    fn new(stack: Vec<Type>, body: &[HirNode]) -> Box<Self> {
+        Box::new(Self {
+            stack,
+            span: body_span(body),
+        })
+    }
+}
+#[derive(Debug)]
+pub struct TypecheckWarning {
+    pub span: Span,
+    pub message: String,
+    /// What the span is, to label it with
+    pub label: &'static str,
+    /// `W` and a number, see `Diagnostic::code`
+    pub code: &'static str,
+}
+
+/// Simulated type stack before and after every checked node, keyed by the node's span, and how
+/// deep the stack of each checked proc gets
+#[derive(Debug, Default)]
+pub struct StackSnapshots {
+    before: RefCell<BTreeMap<Span, Vec<Type>>>,
+    after: RefCell<BTreeMap<Span, Vec<Type>>>,
+    depths: RefCell<BTreeMap<String, usize>>,
+}
+
+impl StackSnapshots {
+    fn record_before(&self, span: Span, stack: Vec<Type>) {
+        self.before.borrow_mut().insert(span, stack);
+    }
+
+    fn record(&self, span: Span, stack: Vec<Type>) {
+        self.after.borrow_mut().insert(span, stack);
+    }
+
+    fn record_depth(&self, proc: &str, depth: usize) {
+        self.depths.borrow_mut().insert(proc.to_string(), depth);
+    }
+
+    /// Replaces every recorded type by `f` of it
+    fn map_types(&self, f: impl Fn(Type) -> Type) {
+        for stacks in [&self.before, &self.after] {
+            for stack in stacks.borrow_mut().values_mut() {
+                stack.iter_mut().for_each(|ty| *ty = f(*ty));
+            }
+        }
+    }
+
+    pub fn get(&self, span: &Span) -> Option<Vec<Type>> {
+        self.after.borrow().get(span).cloned()
+    }
+
+    /// Stack before the node at `span`, `get` has the one after it
+    pub fn before(&self, span: &Span) -> Option<Vec<Type>> {
+        self.before.borrow().get(span).cloned()
+    }
+
+    /// The stacks before and after the node at `span`, without the bottom they have in common
+    pub fn effect(&self, span: &Span) -> Option<(Vec<Type>, Vec<Type>)> {
+        let mut before = self.before.borrow().get(span)?.clone();
+        let mut after = self.get(span)?;
+        let kept = before
+            .iter()
+            .zip(&after)
+            .take_while(|(before, after)| before == after)
+            .count();
+        (before.split_off(kept), after.split_off(kept)).some()
+    }
+
+    /// Stack after the innermost node covering `offset` in `file`
+    pub fn at(&self, file: &Path, offset: usize) -> Option<Vec<Type>> {
+        self.after
+            .borrow()
+            .iter()
+            .filter(|(span, _)| span.file == file && span.start <= offset && offset < span.end)
+            .max_by_key(|(span, _)| span.start)
+            .map(|(_, stack)| stack.clone())
+    }
+
+    /// Most values `proc` has on the stack on top of its inputs, callees aside
+    pub fn depth(&self, proc: &str) -> Option<usize> {
+        self.depths.borrow().get(proc).copied()
+    }
+
+    /// Depths of all checked procs, ordered by name
+    pub fn depths(&self) -> Vec<(String, usize)> {
+        self.depths
+            .borrow()
+            .iter()
+            .map(|(proc, depth)| (proc.clone(), *depth))
+            .collect()
+    }
+
+    /// All snapshots, ordered by file and position
+    pub fn all(&self) -> Vec<(Span, Vec<Type>)> {
+        self.after
+            .borrow()
+            .iter()
+            .map(|(span, stack)| (span.clone(), stack.clone()))
+            .collect()
+    }
+}
+
+fn deprecation_warning(span: Span, name: &str, hint: &str) -> TypecheckWarning {
+    let message = if hint.is_empty() {
+        format!("`{}` is deprecated", name)
+    } else {
+        format!("`{}` is deprecated: {}", name, hint)
+    };
+    TypecheckWarning {
+        span,
+        message,
+        label: "Used here",
+        code: "W0001",
+    }
+}
+
 fn error<T>(span: Span, kind: ErrorKind, message: impl ToString) -> Result<T> {
     Error::Typecheck(TypecheckError::new(span, kind, message)).error()
 }
 
+/// Whether a loop entered with `before` on the stack can go round with `after`, settling the
+/// literals of either to the types they meet in the other, like that of a loop counter
+fn same_loop_stack(before: &[Type], after: &[Type], literals: &mut Literals) -> bool {
+    after.len() == before.len()
+        && before
+            .iter()
+            .zip(after)
+            .all(|(&before, &after)| literals.unify(after, before))
+}
+
+/// Whether `actual` can be passed for `expected`, binding the type variable of `expected` to what
+/// it stands for. Literals settle to the types they are passed for, or meet again through a variable.
+fn unify(
+    expected: Type,
+    actual: Type,
+    bound: &mut FnvHashMap<char, Type>,
+    literals: &mut Literals,
+) -> bool {
+    match expected.type_var() {
+        Some(v) if actual.ptr_depth >= expected.ptr_depth => {
+            let ty = Type {
+                ptr_depth: actual.ptr_depth - expected.ptr_depth,
+                value_type: actual.value_type,
+            };
+            match bound.get(&v) {
+                Some(&b) => literals.unify(ty, b),
+                None => {
+                    bound.insert(v, ty);
+                    true
+                }
+            }
+        }
+        Some(_) => false,
+        None => literals.unify(actual, expected),
+    }
+}
+
+/// The unsuffixed integer literals checked so far, each typed by its own inference variable. Those
+/// used together share the variable of one of them, which settles when it meets an integer type
+/// and stands for `u64` if it never does.
+#[derive(Default)]
+struct Literals {
+    /// Value and span of the literal of each variable
+    literals: Vec<(u64, Span)>,
+    /// Variable each one was joined to, `None` for the one that stands for the others
+    joined: Vec<Option<u32>>,
+    /// Types the variables standing for others settled to
+    settled: FnvHashMap<u32, Type>,
+}
+
+impl Literals {
+    /// The type of a new literal
+    fn push(&mut self, value: u64, span: Span) -> Type {
+        let var = self.literals.len() as u32;
+        self.literals.push((value, span));
+        self.joined.push(None);
+        Type::literal(var)
+    }
+
+    fn root(&self, mut var: u32) -> u32 {
+        while let Some(next) = self.joined[var as usize] {
+            var = next;
+        }
+        var
+    }
+
+    /// `ty` as far as it is known, what its literal settled to or the variable standing for it
+    fn resolve(&self, ty: Type) -> Type {
+        match ty.literal_var() {
+            Some(var) => {
+                let root = self.root(var);
+                self.settled
+                    .get(&root)
+                    .copied()
+                    .unwrap_or_else(|| Type::literal(root))
+            }
+            None => ty,
+        }
+    }
+
+    /// Settles the literals of `a` and `b` to the type of the other, or joins them if both are
+    /// literals. Types that are not integers settle nothing.
+    fn settle(&mut self, a: Type, b: Type) {
+        let (a, b) = (self.resolve(a), self.resolve(b));
+        match (a.literal_var(), b.literal_var()) {
+            (Some(a), Some(b)) if a != b => self.joined[b as usize] = a.some(),
+            (Some(a), None) if b.is_integer() => {
+                self.settled.insert(a, b);
+            }
+            (None, Some(b)) if a.is_integer() => {
+                self.settled.insert(b, a);
+            }
+            _ => (),
+        }
+    }
+
+    /// Whether a value of type `actual` can be used as `expected`, settling their literals if so
+    fn unify(&mut self, actual: Type, expected: Type) -> bool {
+        let fits = self.resolve(actual).type_eq(&self.resolve(expected));
+        if fits {
+            self.settle(actual, expected);
+        }
+        fits
+    }
+
+    /// The type every literal finally has, the first one that doesn't fit it is an error
+    fn finish(&self) -> Result<()> {
+        for (var, (value, span)) in self.literals.iter().enumerate() {
+            let ty = self.resolve(Type::literal(var as u32));
+            let fits = match ty.narrow_bits() {
+                Some(bits) if ty.is_signed() => *value < 1 << (bits - 1),
+                Some(bits) => *value < 1 << bits,
+                None => true,
+            };
+            if !fits {
+                return error(
+                    span.clone(),
+                    LiteralOutOfRange(ty),
+                    format!("Literal `{}` is out of range for its type", value),
+                );
+            }
+        }
+        ().okay()
+    }
+
+    /// `ty` with a `u64` for the literals that never settled
+    fn finished(&self, ty: Type) -> Type {
+        match self.resolve(ty).literal_var() {
+            Some(_) => Type::U64,
+            None => self.resolve(ty),
+        }
+    }
+}
+
+/// `ty` with its type variable replaced by the type bound to it, if any
+fn substitute(ty: Type, bound: &FnvHashMap<char, Type>) -> Type {
+    match ty.type_var().and_then(|v| bound.get(&v)) {
+        Some(b) => Type {
+            ptr_depth: ty.ptr_depth + b.ptr_depth,
+            value_type: b.value_type,
+        },
+        None => ty,
+    }
+}
+
+/// Pops the inputs of a call to `name` off `stack` and pushes its outputs, with the type
+/// variables of its signature bound to the types they are called with
+fn call_proc(
+    stack: &mut TypeStack,
+    heap: &mut THeap,
+    literals: &mut Literals,
+    (ins, outs): (&[Type], &[Type]),
+    span: &Span,
+    name: &str,
+) -> Result<()> {
+    let mut bound = FnvHashMap::default();
+    for ty_expected in ins.iter().rev() {
+        let ty_actual = stack.pop(heap).ok_or_else(|| {
+            TypecheckError::new(
+                span.clone(),
+                NotEnoughData,
+                format!("Not enough data for proc invocation {}", name),
+            )
+        })?;
+        if !unify(*ty_expected, ty_actual, &mut bound, literals) {
+            return error(
+                span.clone(),
+                TypeMismatch {
+                    expected: vec![substitute(*ty_expected, &bound)],
+                    actual: vec![ty_actual],
+                },
+                format!("Wrong types for proc invocation `{}`", name),
+            );
+        }
+    }
+    for ty in outs {
+        stack.push(heap, substitute(*ty, &bound))
+    }
+    ().okay()
+}
+
+/// The first `return` in `body`, however deeply nested
+fn find_return(body: &[HirNode]) -> Option<&Span> {
+    body.iter().find_map(|node| match node.hir {
+        HirKind::Return => Some(&node.span),
+        _ => node.blocks().into_iter().find_map(find_return),
+    })
+}
+
+/// Appends the documented output names to a mismatch message, if any are named
+fn with_out_names(message: &str, out_names: &[Option<String>]) -> String {
+    if out_names.iter().all(Option::is_none) {
+        return message.to_string();
+    }
+    let names = out_names
+        .iter()
+        .map(|name| name.as_deref().unwrap_or("_"))
+        .intersperse(" ")
+        .collect::<String>();
+    format!("{message} `{names}`")
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
 enum ItemKind {
     Proc(ItemProc),
     Mem,
+    Table,
     Gvar(ItemGvar),
     Const(ItemConst),
 }
@@ -74,38 +443,164 @@ impl ItemKind {
 struct ItemProc {
     ins: Vec<Type>,
     outs: Vec<Type>,
+    out_names: Vec<Option<String>>,
     vars: FnvHashMap<String, hir::Var>,
+    deprecated: Option<String>,
+    pure: bool,
 }
 struct ItemGvar {
     ty: Type,
 }
 struct ItemConst {
     types: Vec<Type>,
+    deprecated: Option<String>,
 }
 
 pub struct Typechecker<'s> {
     structs: &'s StructIndex,
+    snapshots: &'s StackSnapshots,
+    strict_alignment: bool,
+    runtime: Runtime,
     heap: THeap,
     visited: FnvHashMap<String, ItemKind>,
     output: FnvHashMap<String, TopLevel>,
+    warnings: Vec<TypecheckWarning>,
+    /// Stack each enclosing loop was entered with, innermost last
+    loops: Vec<Vec<Type>>,
+    /// Whether `main` is a snippet, which gives whatever its body leaves
+    snippet: bool,
+    /// Most values the stack of the proc being checked has had so far, inputs included
+    depth: usize,
+    literals: Literals,
 }
 
 impl<'s> Typechecker<'s> {
     pub fn typecheck_program(
+        items: FnvHashMap<String, TopLevel>,
+        session: &'s Session,
+    ) -> Result<(FnvHashMap<String, TopLevel>, Vec<TypecheckWarning>)> {
+        Self::typecheck_main(items, session, false)
+    }
+
+    /// Typechecks a program whose `main` takes nothing and gives what its body leaves on the
+    /// stack, which become its outputs, as `driver::eval` runs snippets
+    pub fn typecheck_snippet(
+        items: FnvHashMap<String, TopLevel>,
+        session: &'s Session,
+    ) -> Result<(FnvHashMap<String, TopLevel>, Vec<TypecheckWarning>)> {
+        Self::typecheck_main(items, session, true)
+    }
+
+    fn typecheck_main(
         mut items: FnvHashMap<String, TopLevel>,
-        structs: &'s StructIndex,
-    ) -> Result<FnvHashMap<String, TopLevel>> {
+        session: &'s Session,
+        snippet: bool,
+    ) -> Result<(FnvHashMap<String, TopLevel>, Vec<TypecheckWarning>)> {
         let heap = THeap::default();
         let mut this = Self {
-            structs,
+            structs: &session.structs,
+            snapshots: &session.snapshots,
+            strict_alignment: session.options.strict_alignment,
+            runtime: session.options.runtime,
             heap,
             output: Default::default(),
             visited: Default::default(),
+            warnings: Default::default(),
+            loops: Default::default(),
+            snippet,
+            depth: 0,
+            literals: Default::default(),
         };
 
-        this.typecheck_proc("main", &mut items)?;
+        let checked = this.typecheck_proc("main", &mut items);
+        this.finish_literals();
+        checked?;
+        this.literals.finish()?;
+        this.warn_unused(&items);
+
+        match session.options.warnings {
+            WarningLevel::Allow => this.warnings.clear(),
+            WarningLevel::Warn => (),
+            WarningLevel::Deny if this.warnings.is_empty() => (),
+            WarningLevel::Deny => return Error::DeniedWarnings(this.warnings).error(),
+        }
+        (this.output, this.warnings).okay()
+    }
+
+    /// Gives the literals their final types wherever the checked items and snapshots have them
+    fn finish_literals(&mut self) {
+        fn finish_body(body: &mut [HirNode], literals: &Literals) {
+            for node in body {
+                if let HirKind::Intrinsic(
+                    Intrinsic::Add(Some(ty))
+                    | Intrinsic::Sub(Some(ty))
+                    | Intrinsic::Mul(Some(ty))
+                    | Intrinsic::Divmod(Some(ty))
+                    | Intrinsic::BitNot(Some(ty))
+                    | Intrinsic::Shl(Some(ty))
+                    | Intrinsic::Shr(Some(ty))
+                    | Intrinsic::Lt(Some(ty))
+                    | Intrinsic::Le(Some(ty))
+                    | Intrinsic::Gt(Some(ty))
+                    | Intrinsic::Ge(Some(ty)),
+                ) = &mut node.hir
+                {
+                    *ty = literals.finished(*ty);
+                }
+                for block in node.blocks_mut() {
+                    finish_body(block, literals);
+                }
+            }
+        }
+
+        let literals = &self.literals;
+        for item in self.output.values_mut() {
+            match item {
+                TopLevel::Proc(p) => {
+                    p.outs
+                        .iter_mut()
+                        .for_each(|ty| *ty = literals.finished(*ty));
+                    finish_body(&mut p.body, literals)
+                }
+                TopLevel::Const(c) => finish_body(&mut c.body, literals),
+                TopLevel::Mem(m) => finish_body(&mut m.body, literals),
+                TopLevel::Table(t) => finish_body(&mut t.body, literals),
+                TopLevel::Var(_) => (),
+            }
+        }
+        self.snapshots.map_types(|ty| literals.finished(ty));
+    }
 
-        this.output.okay()
+    /// Warns of the items of the file of `main` it never reached, which are left in `items`
+    fn warn_unused(&mut self, items: &FnvHashMap<String, TopLevel>) {
+        let file = match self.output.get("main") {
+            Some(TopLevel::Proc(main)) => main.span.file.clone(),
+            _ => return,
+        };
+        let mut unused = items
+            .iter()
+            .map(|(name, item)| match item {
+                TopLevel::Proc(p) => (&p.span, "proc", name),
+                TopLevel::Const(c) => (&c.span, "const", name),
+                TopLevel::Mem(m) => (&m.span, "mem", name),
+                TopLevel::Table(t) => (&t.span, "table", name),
+                TopLevel::Var(v) => (&v.span, "var", name),
+            })
+            .filter(|(span, _, _)| span.file == file)
+            // the const of a whole group is only used through those of its names
+            .filter(|(_, _, name)| !name.contains(' '))
+            .collect::<Vec<_>>();
+        unused.sort();
+        self.warnings.extend(
+            unused
+                .into_iter()
+                .map(|(span, kind, name)| TypecheckWarning {
+                    span: span.clone(),
+                    message: format!("{} `{}` is never used", kind, name),
+                    label: "Defined here",
+                    code: "W0002",
+                }),
+        );
     }
 
     fn typecheck_proc(
@@ -132,16 +627,39 @@ impl<'s> Typechecker<'s> {
             ItemKind::Proc(ItemProc {
                 ins: proc.ins.clone(),
                 outs: proc.outs.clone(),
+                out_names: proc.out_names.clone(),
                 vars: proc.vars.clone(),
+                deprecated: proc.attrs.deprecated.clone(),
+                pure: proc.attrs.pure && !proc.host,
             }),
         );
-        if name == "main" && (!proc.ins.is_empty() || !(proc.outs[..] == [Type::U64])) {
+        let snippet = name == "main" && self.snippet;
+        if name == "main" && !snippet && (!proc.ins.is_empty() || !(proc.outs[..] == [Type::U64])) {
             return error(
                 proc.span.clone(),
                 InvalidMain,
                 "Main must have no inputs and a single uint output",
             );
         }
+        if let Some(v) = proc
+            .outs
+            .iter()
+            .filter_map(Type::type_var)
+            .find(|v| !proc.ins.iter().any(|ty| ty.type_var() == Some(*v)))
+        {
+            return error(
+                proc.span.clone(),
+                UnboundTypeVar(v),
+                format!(
+                    "Type variable `'{}` of the outputs is not bound by any input",
+                    v
+                ),
+            );
+        }
+        if proc.host {
+            self.output.insert(name.to_string(), item);
+            return ().okay();
+        }
 
         let span = proc.span.clone();
         let mut actual = TypeStack::default();
@@ -154,6 +672,8 @@ impl<'s> Typechecker<'s> {
         }
         let mut bindings = Vec::new();
 
+        // callees checked on the way have their own depth
+        let outer = std::mem::replace(&mut self.depth, proc.ins.len());
         self.typecheck_body(
             name,
             items,
@@ -162,15 +682,29 @@ impl<'s> Typechecker<'s> {
             false,
             &mut bindings,
         )?;
+        let depth = std::mem::replace(&mut self.depth, outer);
+        self.snapshots.record_depth(name, depth - proc.ins.len());
+        if proc.attrs.pure {
+            if let Some((span, what)) = self.impure_op(name, &proc.body) {
+                return error(span, Impure, format!("Pure proc `{}` {}", name, what));
+            }
+        }
 
-        if !actual.eq(&expected, &self.heap) {
+        if snippet {
+            proc.outs = actual.into_vec(&self.heap);
+            self.output.insert(name.to_string(), item);
+            ().okay()
+        } else if !actual.eq(&expected, &self.heap, &mut self.literals) {
             error(
                 span,
                 TypeMismatch {
                     actual: actual.into_vec(&self.heap),
                     expected: expected.into_vec(&self.heap),
                 },
-                "Type mismatch: proc body does not equal proc outputs",
+                with_out_names(
+                    "Type mismatch: proc body does not equal proc outputs",
+                    &proc.out_names,
+                ),
             )
         } else {
             self.output.insert(name.to_string(), item);
@@ -178,6 +712,48 @@ impl<'s> Typechecker<'s> {
         }
     }
 
+    /// The first op in `body`, or in the body of a `#[pure]` proc `proc`, that does more than compute
+    /// outputs from inputs, and what it does. Callees are only checked for being marked pure.
+    fn impure_op(&self, proc: &str, body: &[HirNode]) -> Option<(Span, String)> {
+        use Intrinsic::*;
+        for node in body {
+            if let Some(op) = node
+                .blocks()
+                .into_iter()
+                .find_map(|b| self.impure_op(proc, b))
+            {
+                return op.some();
+            }
+            let what = match &node.hir {
+                HirKind::Intrinsic(i) => match i {
+                    Syscall0 | Syscall1 | Syscall2 | Syscall3 | Syscall4 | Syscall5 | Syscall6
+                    | CheckedSyscall(_) => "makes a syscall".to_string(),
+                    WriteU64 | WriteU8 | Write(_) | WriteEndian(..) | Copy16 | Splat16 => {
+                        "writes memory".to_string()
+                    }
+                    Dump | Print(_) | PrintPadded(_) => "prints".to_string(),
+                    Argc | Argv | ArgvAt => "reads the program arguments".to_string(),
+                    _ => continue,
+                },
+                HirKind::Word(w) => match self.visited.get(w) {
+                    Some(ItemKind::Proc(p)) if !p.pure => {
+                        format!("calls `{}`, which is not pure", w)
+                    }
+                    Some(ItemKind::Mem) => format!("uses the mem `{}`", w),
+                    _ if self.is_local_var(proc, w, &Default::default()) => continue,
+                    _ if matches!(self.output.get(w), Some(TopLevel::Var(_))) => {
+                        format!("uses the global var `{}`", w)
+                    }
+                    _ => continue,
+                },
+                HirKind::CallLike(_) => "calls through a proc pointer".to_string(),
+                _ => continue,
+            };
+            return (node.span.clone(), what).some();
+        }
+        None
+    }
+
     fn typecheck_cond(
         &mut self,
         name: &str,
@@ -194,18 +770,31 @@ impl<'s> Typechecker<'s> {
             HirKind::Cond(c) => c,
             _ => unreachable!(),
         };
-        let mut first_branch_stack = TypeStack::default();
-        let mut first_branch = true;
-        for CondBranch { pattern, body } in &mut cond.branches {
+        let last = cond.branches.len() - 1;
+        let mut covered = Vec::new();
+        let mut wildcard = false;
+        let mut out: Option<TypeStack> = None;
+        for (i, CondBranch { pattern, body }) in cond.branches.iter_mut().enumerate() {
             let pat_ty = match &pattern.hir {
-                HirKind::Literal(pat) => match pat {
-                    IConst::Bool(_) => Type::BOOL,
-                    IConst::U64(_) => Type::U64,
-                    IConst::I64(_) => Type::I64,
-                    IConst::Char(_) => Type::CHAR,
-                    IConst::Str(_) => todo!(),
-                    IConst::Ptr(_) => Type::ptr_to(Type::ANY),
-                },
+                HirKind::Literal(pat) => {
+                    if covered.contains(pat) {
+                        return error(
+                            pattern.span.clone(),
+                            InvalidCond,
+                            "Value is already matched by an earlier branch",
+                        );
+                    }
+                    covered.push(pat.clone());
+                    match pat {
+                        IConst::Bool(_) => Type::BOOL,
+                        &IConst::Int(value) => self.literals.push(value, pattern.span.clone()),
+                        IConst::U64(_) => Type::U64,
+                        IConst::I64(_) => Type::I64,
+                        IConst::Char(_) => Type::CHAR,
+                        IConst::Str(_) => todo!(),
+                        IConst::Ptr(_) => Type::ptr_to(Type::ANY),
+                    }
+                }
                 HirKind::Word(const_name) if self.is_const(const_name, items) => {
                     self.typecheck_const(const_name, items)?;
                     let const_ = self.output[const_name].as_const().ok_or_else(|| {
@@ -224,17 +813,26 @@ impl<'s> Typechecker<'s> {
                     }
                     const_.outs[0]
                 }
-                HirKind::Word(_) => {
+                HirKind::IgnorePattern if i != last => {
+                    return error(
+                        pattern.span.clone(),
+                        InvalidCond,
+                        "Branches after `_` are never taken",
+                    )
+                }
+                HirKind::IgnorePattern => {
+                    wildcard = true;
+                    Type::ANY
+                }
+                _ => {
                     return error(
                         pattern.span.clone(),
                         Unexpected,
                         "Cond only supports constant patterns",
                     )
                 }
-                HirKind::IgnorePattern => Type::ANY,
-                hir => unreachable!("{:?}", hir),
             };
-            if !ty.type_eq(&pat_ty) {
+            if !self.literals.unify(ty, pat_ty) {
                 return error(
                     pattern.span.clone(),
                     TypeMismatch {
@@ -244,42 +842,42 @@ impl<'s> Typechecker<'s> {
                     "Wrong type for cond pattern",
                 );
             }
-            if first_branch {
-                self.typecheck_body(
-                    name,
-                    items,
-                    &mut *body,
-                    &mut first_branch_stack,
-                    in_const,
-                    bindings,
-                )?;
-            } else {
-                let mut branch_stack = TypeStack::default();
-                self.typecheck_body(
-                    name,
-                    items,
-                    &mut *body,
-                    &mut branch_stack,
-                    in_const,
-                    bindings,
-                )?;
-                if !first_branch_stack.eq(&branch_stack, &self.heap) {
+            let mut branch = stack.clone();
+            self.typecheck_body(name, items, &mut *body, &mut branch, in_const, bindings)?;
+            // a branch that jumps out never reaches the end of the cond
+            if jumps_out(body) {
+                continue;
+            }
+            match &out {
+                None => out = branch.some(),
+                Some(first) if first.eq(&branch, &self.heap, &mut self.literals) => (),
+                Some(first) => {
                     return error(
                         node.span.clone(),
                         TypeMismatch {
-                            expected: first_branch_stack.into_vec(&self.heap),
-                            actual: branch_stack.into_vec(&self.heap),
+                            expected: first.clone().into_vec(&self.heap),
+                            actual: branch.into_vec(&self.heap),
                         },
                         "Type mismatch between cond branches",
-                    );
+                    )
                 }
             }
-            first_branch = false;
         }
 
-        let first_branch_stack = first_branch_stack.into_vec(&self.heap);
-        for ty in first_branch_stack.into_iter() {
-            stack.push(&mut self.heap, ty)
+        // the last branch is taken for any value that gets to it, so it has to be the only one left
+        let exhaustive = wildcard
+            || ty.type_eq(&Type::BOOL)
+                && covered.contains(&IConst::Bool(true))
+                && covered.contains(&IConst::Bool(false));
+        if !exhaustive {
+            return error(
+                node.span.clone(),
+                InvalidCond,
+                "Cond must end with a `_` branch unless its branches match every value",
+            );
+        }
+        if let Some(out) = out {
+            *stack = out;
         }
 
         ().okay()
@@ -308,6 +906,7 @@ impl<'s> Typechecker<'s> {
             const_name.to_string(),
             ItemKind::Const(ItemConst {
                 types: const_.outs.clone(),
+                deprecated: const_.attrs.deprecated.clone(),
             }),
         );
 
@@ -338,7 +937,7 @@ impl<'s> Typechecker<'s> {
             &mut bindings,
         )?;
 
-        if actual.eq(&expected, &self.heap) {
+        if actual.eq(&expected, &self.heap, &mut self.literals) {
             self.output.insert(const_name.to_string(), item.clone());
             ().okay()
         } else {
@@ -391,7 +990,7 @@ impl<'s> Typechecker<'s> {
             &mut bindings,
         )?;
 
-        if actual.eq(&expected, &self.heap) {
+        if actual.eq(&expected, &self.heap, &mut self.literals) {
             self.output.insert(mem_name.to_string(), item.clone());
             ().okay()
         } else {
@@ -406,6 +1005,61 @@ impl<'s> Typechecker<'s> {
         }
     }
 
+    fn typecheck_table(
+        &mut self,
+        table_name: &str,
+        items: &mut FnvHashMap<String, TopLevel>,
+    ) -> Result<Type> {
+        if let Some(TopLevel::Table(t)) = self.output.get(table_name) {
+            return t.ty.okay();
+        }
+        let mut item = items.remove(table_name).ok_or_else(|| {
+            TypecheckError::new(
+                Span::point("".to_string(), 0),
+                Undefined(table_name.to_string()),
+                format!("Table `{}` does not exist", table_name),
+            )
+        })?;
+        let table = match &mut item {
+            TopLevel::Table(t) => t,
+            _ => unreachable!("This can't not be a table"),
+        };
+        self.visited.insert(table_name.to_string(), ItemKind::Table);
+
+        let span = table.span.clone();
+        let mut actual = TypeStack::default();
+        let mut expected = TypeStack::default();
+        // the index, which the body binds to `i`
+        actual.push(&mut self.heap, Type::U64);
+        expected.push(&mut self.heap, table.ty);
+
+        let mut bindings = Vec::new();
+
+        self.typecheck_body(
+            table_name,
+            items,
+            &mut table.body,
+            &mut actual,
+            true,
+            &mut bindings,
+        )?;
+
+        if actual.eq(&expected, &self.heap, &mut self.literals) {
+            let ty = table.ty;
+            self.output.insert(table_name.to_string(), item);
+            ty.okay()
+        } else {
+            error(
+                span,
+                TypeMismatch {
+                    expected: expected.into_vec(&self.heap),
+                    actual: actual.into_vec(&self.heap),
+                },
+                "Table body must evaluate to one element",
+            )
+        }
+    }
+
     #[allow(clippy::too_many_arguments)]
     fn typecheck_if(
         &mut self,
@@ -419,44 +1073,57 @@ impl<'s> Typechecker<'s> {
     ) -> Result<()> {
         let (mut truth, mut lie) = (stack.clone(), stack.clone());
         self.typecheck_body(name, items, &mut if_.truth, &mut truth, in_const, bindings)?;
+        // without an else the stack has to come out of the truth branch as it went in
         if let Some(lie_body) = &mut if_.lie {
             self.typecheck_body(name, items, &mut *lie_body, &mut lie, in_const, bindings)?;
-        } else {
-            return ().okay();
         }
-        if truth.eq(&lie, &self.heap) {
+        // a branch that jumps out never reaches the end of the if
+        if jumps_out(&if_.truth) {
+            *stack = lie;
+            ().okay()
+        } else if matches!(&if_.lie, Some(body) if jumps_out(body))
+            || truth.eq(&lie, &self.heap, &mut self.literals)
+        {
             *stack = truth;
             ().okay()
         } else {
-            let (actual, expected) = (truth.into_vec(&self.heap), lie.into_vec(&self.heap));
             error(
                 span.clone(),
-                TypeMismatch { actual, expected },
+                BranchMismatch {
+                    truth: BranchStack::new(truth.into_vec(&self.heap), &if_.truth),
+                    lie: BranchStack::new(
+                        lie.into_vec(&self.heap),
+                        if_.lie.as_deref().unwrap_or(&[]),
+                    ),
+                },
                 "If branches must leave stack in the same state",
             )
         }
     }
 
-    fn typecheck_boolean(&mut self, stack: &mut TypeStack, node: &HirNode) -> Result<()> {
+    /// Checks a comparison, returning the type both operands are compared as
+    fn typecheck_boolean(&mut self, stack: &mut TypeStack, span: &Span) -> Result<Type> {
         let b = stack.pop(&self.heap).ok_or_else(|| {
             TypecheckError::new(
-                node.span.clone(),
+                span.clone(),
                 NotEnoughData,
                 "Not enough data for binary operation",
             )
         })?;
         let a = stack.pop(&self.heap).ok_or_else(|| {
             TypecheckError::new(
-                node.span.clone(),
+                span.clone(),
                 NotEnoughData,
                 "Not enough data for binary operation",
             )
         })?;
-        match (a, b) {
-            (a, b) if a.type_eq(&b) => stack.push(&mut self.heap, Type::BOOL),
-            (a, b) => {
+        let (a, b) = (self.literals.resolve(a), self.literals.resolve(b));
+        let ty = match a.widened(&b) {
+            Some(ty) => ty,
+            None if a.type_eq(&b) => a,
+            None => {
                 return error(
-                    node.span.clone(),
+                    span.clone(),
                     TypeMismatch {
                         actual: vec![b, a],
                         expected: vec![a, a],
@@ -464,48 +1131,55 @@ impl<'s> Typechecker<'s> {
                     "Wrong types for boolean operation",
                 )
             }
-        }
-        ().okay()
+        };
+        self.literals.settle(a, ty);
+        self.literals.settle(b, ty);
+        stack.push(&mut self.heap, Type::BOOL);
+        ty.okay()
     }
 
-    fn typecheck_divmod(&mut self, stack: &mut TypeStack, node: &HirNode) -> Result<()> {
-        self.typecheck_binop(stack, node)?;
-        stack.push(&mut self.heap, Type::U64);
-        ().okay()
+    fn typecheck_divmod(&mut self, stack: &mut TypeStack, span: &Span) -> Result<Type> {
+        let ty = self.typecheck_binop(stack, span)?;
+        // the remainder has the sign of the dividend
+        let rem = if ty.is_signed() { ty } else { Type::U64 };
+        stack.push(&mut self.heap, rem);
+        ty.okay()
     }
 
-    fn typecheck_binop(&mut self, stack: &mut TypeStack, node: &HirNode) -> Result<()> {
+    /// Checks an arithmetic op, returning the type both operands are used as
+    fn typecheck_binop(&mut self, stack: &mut TypeStack, span: &Span) -> Result<Type> {
         let b = stack.pop(&self.heap).ok_or_else(|| {
             TypecheckError::new(
-                node.span.clone(),
+                span.clone(),
                 NotEnoughData,
                 "Not enough data for binary operation",
             )
         })?;
         let a = stack.pop(&self.heap).ok_or_else(|| {
             TypecheckError::new(
-                node.span.clone(),
+                span.clone(),
                 NotEnoughData,
                 "Not enough data for binary operation",
             )
         })?;
 
-        if a == Type::U64 && b == Type::U64 {
-            stack.push(&mut self.heap, Type::U64)
-        } else if a == Type::I64 && b == Type::I64 {
-            stack.push(&mut self.heap, Type::I64)
+        // a literal takes the type of the other operand, a narrower integer the wider one's
+        let (a, b) = (self.literals.resolve(a), self.literals.resolve(b));
+        if let Some(ty) = a.widened(&b) {
+            self.literals.settle(a, ty);
+            self.literals.settle(b, ty);
+            stack.push(&mut self.heap, ty);
+            ty.okay()
         } else {
-            return error(
-                node.span.clone(),
+            error(
+                span.clone(),
                 TypeMismatch {
                     actual: vec![b, a],
                     expected: vec![b, b],
                 },
-                "Wrong types for binary operation, must be 2 operands of type uint|int",
-            );
+                "Wrong types for binary operation, must be 2 integers of the same signedness",
+            )
         }
-
-        ().okay()
     }
 
     fn typecheck_body(
@@ -518,9 +1192,15 @@ impl<'s> Typechecker<'s> {
         bindings: &mut Vec<Vec<(String, Type)>>,
     ) -> Result<()> {
         for node in body {
+            self.snapshots
+                .record_before(node.span.clone(), stack.clone().into_vec(&self.heap));
             match &mut node.hir {
                 HirKind::Literal(c) => match c {
                     IConst::Bool(_) => stack.push(&mut self.heap, Type::BOOL),
+                    &mut IConst::Int(value) => {
+                        let ty = self.literals.push(value, node.span.clone());
+                        stack.push(&mut self.heap, ty)
+                    }
                     IConst::U64(_) => stack.push(&mut self.heap, Type::U64),
                     IConst::I64(_) => stack.push(&mut self.heap, Type::I64),
                     IConst::Ptr(_) => stack.push(&mut self.heap, Type::ptr_to(Type::U64)),
@@ -539,14 +1219,17 @@ impl<'s> Typechecker<'s> {
                         for &ty in &p.outs {
                             expected.push(&mut self.heap, ty)
                         }
-                        if !expected.eq(stack, &self.heap) {
+                        if !expected.eq(stack, &self.heap, &mut self.literals) {
                             return error(
                                 node.span.clone(),
                                 TypeMismatch {
                                     expected: p.outs.clone(),
                                     actual: stack.clone().into_vec(&self.heap),
                                 },
-                                "Type mismatched types for early return",
+                                with_out_names(
+                                    "Type mismatched types for early return",
+                                    &p.out_names,
+                                ),
                             );
                         }
                     }
@@ -575,84 +1258,65 @@ impl<'s> Typechecker<'s> {
                                     "Recursive const definition",
                                 )
                             })?;
-                        for ty_expected in proc.ins.iter().rev() {
-                            let ty_actual = stack.pop(&self.heap).ok_or_else(|| {
-                                TypecheckError::new(
-                                    node.span.clone(),
-                                    NotEnoughData,
-                                    format!("Not enough data for proc invocation {}", rec),
-                                )
-                            })?;
-                            if !ty_expected.type_eq(&ty_actual) {
-                                return error(
-                                    node.span.clone(),
-                                    TypeMismatch {
-                                        expected: vec![*ty_expected],
-                                        actual: vec![ty_actual],
-                                    },
-                                    format!("Wrong types for proc invocation `{}`", rec),
-                                );
-                            }
-                        }
-                        for ty in &proc.outs {
-                            stack.push(&mut self.heap, *ty)
-                        }
+                        let signature = (&proc.ins[..], &proc.outs[..]);
+                        call_proc(
+                            stack,
+                            &mut self.heap,
+                            &mut self.literals,
+                            signature,
+                            &node.span,
+                            rec,
+                        )?;
                     }
                     proc_name if self.is_proc(proc_name, items) => {
-                        if in_const {
+                        self.typecheck_proc(proc_name, items)?;
+                        let proc = self.visited[proc_name].as_proc().ok_or_else(|| {
+                            TypecheckError::new(
+                                node.span.clone(),
+                                Unexpected,
+                                "Recursive const definition",
+                            )
+                        })?;
+                        if in_const && !proc.pure {
                             return error(
                                 node.span.clone(),
                                 CallInConst,
-                                "Proc calls not allowed in const context",
+                                "Only pure procs can be called in const context",
                             );
                         }
-                        self.typecheck_proc(proc_name, items)?;
-                        let proc = self.visited[proc_name].as_proc().ok_or_else(|| {
+                        if let Some(hint) = &proc.deprecated {
+                            self.warnings.push(deprecation_warning(
+                                node.span.clone(),
+                                proc_name,
+                                hint,
+                            ));
+                        }
+                        let signature = (&proc.ins[..], &proc.outs[..]);
+                        call_proc(
+                            stack,
+                            &mut self.heap,
+                            &mut self.literals,
+                            signature,
+                            &node.span,
+                            proc_name,
+                        )?;
+                    }
+                    const_name if self.is_const(const_name, items) => {
+                        self.typecheck_const(const_name, items)?;
+                        let const_ = self.visited[const_name].as_const().ok_or_else(|| {
                             TypecheckError::new(
                                 node.span.clone(),
                                 Unexpected,
                                 "Recursive const definition",
                             )
                         })?;
-                        for ty_expected in proc.ins.iter().rev() {
-                            let ty_actual = stack.pop(&self.heap).ok_or_else(|| {
-                                TypecheckError::new(
-                                    node.span.clone(),
-                                    NotEnoughData,
-                                    format!("Not enough data for proc invocation {}", proc_name),
-                                )
-                            })?;
-                            if !ty_expected.type_eq(&ty_actual) {
-                                return error(
-                                    node.span.clone(),
-                                    TypeMismatch {
-                                        expected: vec![*ty_expected],
-                                        actual: vec![ty_actual],
-                                    },
-                                    format!("Wrong types for proc invocation {}", proc_name),
-                                );
-                            }
-                        }
-                        let proc = self.output[proc_name].as_proc().ok_or_else(|| {
-                            TypecheckError::new(
+                        if let Some(hint) = &const_.deprecated {
+                            self.warnings.push(deprecation_warning(
                                 node.span.clone(),
-                                Unexpected,
-                                "Recursive const definition",
-                            )
-                        })?;
-                        for ty in &proc.outs {
-                            stack.push(&mut self.heap, *ty)
+                                const_name,
+                                hint,
+                            ));
                         }
-                    }
-                    const_name if self.is_const(const_name, items) => {
-                        self.typecheck_const(const_name, items)?;
-                        let const_ = self.visited[const_name].as_const().ok_or_else(|| {
-                            TypecheckError::new(
-                                node.span.clone(),
-                                Unexpected,
-                                "Recursive const definition",
-                            )
-                        })?;
                         for ty in &const_.types {
                             stack.push(&mut self.heap, *ty);
                         }
@@ -660,7 +1324,11 @@ impl<'s> Typechecker<'s> {
                     mem_name if self.is_mem(mem_name, items) => {
                         self.typecheck_mem(mem_name, items)?;
 
-                        stack.push(&mut self.heap, Type::ptr_to(Type::U8));
+                        stack.push_aligned(&mut self.heap, Type::ptr_to(Type::U8), Align::WORD);
+                    }
+                    table_name if self.is_table(table_name, items) => {
+                        let ty = self.typecheck_table(table_name, items)?;
+                        stack.push(&mut self.heap, Type::ptr_to(ty));
                     }
                     lvar_name if self.is_local_var(name, lvar_name, items) => {
                         let ty = items
@@ -677,13 +1345,13 @@ impl<'s> Typechecker<'s> {
                             })
                             .unwrap();
 
-                        stack.push(&mut self.heap, Type::ptr_to(ty));
+                        stack.push_aligned(&mut self.heap, Type::ptr_to(ty), Align::WORD);
                     }
                     gvar_name if self.is_global_var(gvar_name, items) => {
                         let item = &items[gvar_name];
                         let gvar = item.as_var().unwrap();
                         self.output.insert(gvar_name.to_string(), item.clone());
-                        stack.push(&mut self.heap, Type::ptr_to(gvar.ty));
+                        stack.push_aligned(&mut self.heap, Type::ptr_to(gvar.ty), Align::WORD);
                     }
                     binding_name if self.is_binding(binding_name, bindings) => {
                         let ty = bindings
@@ -701,17 +1369,130 @@ impl<'s> Typechecker<'s> {
                             .unwrap();
                         stack.push(&mut self.heap, ty);
                     }
-                    word => {
+                    proc_ref
+                        if proc_ref.starts_with('&') && self.is_proc(&proc_ref[1..], items) =>
+                    {
+                        let target = proc_ref[1..].to_string();
+                        if in_const {
+                            return error(
+                                node.span.clone(),
+                                CallInConst,
+                                "Proc pointers are not allowed in const context",
+                            );
+                        }
+                        // procs checked further up, like this one, already have their signature
+                        if items.contains_key(&target) {
+                            self.typecheck_proc(&target, items)?;
+                        }
+                        if matches!(self.output.get(&target), Some(TopLevel::Proc(p)) if p.host) {
+                            return error(
+                                node.span.clone(),
+                                Unexpected,
+                                format!("Extern host proc `{}` has no address", target),
+                            );
+                        }
+                        let ty = self.proc_ptr(&target);
+                        stack.push(&mut self.heap, ty);
+                        node.hir = HirKind::ProcRef(target);
+                    }
+                    "call-like" => {
                         return error(
                             node.span.clone(),
-                            Undefined(word.to_string()),
-                            "Encountered undefined word".to_string(),
+                            Unexpected,
+                            "`call-like` must be followed by the proc whose signature the call has",
                         )
                     }
+                    word => {
+                        let message = match intrinsics::similar(word).as_slice() {
+                            [] => "Encountered undefined word".to_string(),
+                            similar => format!(
+                                "Encountered undefined word, did you mean {}?",
+                                similar
+                                    .iter()
+                                    .map(|w| format!("`{}`", w))
+                                    .collect::<Vec<_>>()
+                                    .join(", ")
+                            ),
+                        };
+                        return error(node.span.clone(), Undefined(word.to_string()), message);
+                    }
                 },
+                HirKind::ProcRef(target) => {
+                    let ty = self.proc_ptr(target);
+                    stack.push(&mut self.heap, ty)
+                }
+                HirKind::CallLike(callee) => {
+                    let ty = stack.pop(&self.heap).ok_or_else(|| {
+                        TypecheckError::new(
+                            node.span.clone(),
+                            NotEnoughData,
+                            "Not enough data for `call-like`",
+                        )
+                    })?;
+                    if !ty.is_proc() {
+                        return error(
+                            node.span.clone(),
+                            TypeMismatch {
+                                expected: vec![Type::PROC],
+                                actual: vec![ty],
+                            },
+                            "Wrong type for `call-like`",
+                        );
+                    }
+                    if !self.is_proc(callee, items) {
+                        return error(
+                            node.span.clone(),
+                            Undefined(callee.clone()),
+                            format!(
+                                "`call-like` takes the signature of a proc, `{}` is not one",
+                                callee
+                            ),
+                        );
+                    }
+                    if items.contains_key(callee.as_str()) {
+                        self.typecheck_proc(callee, items)?;
+                    }
+                    // a `ptr(proc)` is trusted to point at such a proc, checked builds trap if not
+                    let expected = self.proc_ptr(callee);
+                    if ty != Type::PROC && ty != expected {
+                        return error(
+                            node.span.clone(),
+                            TypeMismatch {
+                                expected: vec![expected],
+                                actual: vec![ty],
+                            },
+                            format!(
+                                "`call-like {}` needs a `{}`, not a `{}`",
+                                callee,
+                                expected.name(self.structs),
+                                ty.name(self.structs)
+                            ),
+                        );
+                    }
+                    let proc = self.visited[callee.as_str()].as_proc().unwrap();
+                    let signature = (&proc.ins[..], &proc.outs[..]);
+                    call_proc(
+                        stack,
+                        &mut self.heap,
+                        &mut self.literals,
+                        signature,
+                        &node.span,
+                        callee,
+                    )?;
+                }
+                HirKind::Intrinsic(i)
+                    if !stack.has_depth(&self.heap, info_of(i).map_or(0, |info| info.ins)) =>
+                {
+                    let info = info_of(i).unwrap();
+                    return error(
+                        node.span.clone(),
+                        NotEnoughData,
+                        format!("Not enough data for `{}` ({})", info.word, info.signature),
+                    );
+                }
                 HirKind::Intrinsic(i) => match i {
                     Intrinsic::ReadU64 => {
-                        let ty = stack.pop(&self.heap).ok_or_else(|| {
+                        let (ty, align) = stack.pop_aligned(&self.heap).ok_or_else(|| {
                             TypecheckError::new(
                                 node.span.clone(),
                                 NotEnoughData,
@@ -728,6 +1509,7 @@ impl<'s> Typechecker<'s> {
                                 "Wrong types for @u64",
                             );
                         }
+                        self.check_alignment(node, align, 8)?;
                         stack.push(&mut self.heap, Type::U64)
                     }
                     Intrinsic::ReadU8 => {
@@ -751,7 +1533,7 @@ impl<'s> Typechecker<'s> {
                         stack.push(&mut self.heap, Type::U8)
                     }
                     Intrinsic::WriteU64 => {
-                        let ty = stack.pop(&self.heap).ok_or_else(|| {
+                        let (ty, align) = stack.pop_aligned(&self.heap).ok_or_else(|| {
                             TypecheckError::new(
                                 node.span.clone(),
                                 NotEnoughData,
@@ -765,7 +1547,7 @@ impl<'s> Typechecker<'s> {
                                 "Not enough data for !u64",
                             )
                         })?;
-                        if !(ty.is_ptr_to(Type::U64) && ty_store == Type::U64) {
+                        if !(ty.is_ptr_to(Type::U64) && self.literals.unify(ty_store, Type::U64)) {
                             return error(
                                 node.span.clone(),
                                 TypeMismatch {
@@ -775,6 +1557,7 @@ impl<'s> Typechecker<'s> {
                                 "Wrong types for !u8",
                             );
                         }
+                        self.check_alignment(node, align, 8)?;
                     }
                     Intrinsic::WriteU8 => {
                         let ty = stack.pop(&self.heap).ok_or_else(|| {
@@ -791,7 +1574,7 @@ impl<'s> Typechecker<'s> {
                                 "Not enough data for !u8",
                             )
                         })?;
-                        if !(ty.is_ptr_to(Type::U8) && ty_store == Type::U8) {
+                        if !(ty.is_ptr_to(Type::U8) && self.literals.unify(ty_store, Type::U8)) {
                             return error(
                                 node.span.clone(),
                                 TypeMismatch {
@@ -802,21 +1585,76 @@ impl<'s> Typechecker<'s> {
                             );
                         }
                     }
-                    &mut Intrinsic::Cast(ty) => {
-                        if !self.expect_arity(1, stack) {
+                    &mut Intrinsic::Read(ty) | &mut Intrinsic::ReadEndian(ty, _) => {
+                        let (ptr, align) = stack.pop_aligned(&self.heap).ok_or_else(|| {
+                            TypecheckError::new(
+                                node.span.clone(),
+                                NotEnoughData,
+                                format!("Not enough data for @{:?}", ty),
+                            )
+                        })?;
+                        if !ptr.is_ptr_to(ty) {
                             return error(
+                                node.span.clone(),
+                                TypeMismatch {
+                                    actual: vec![ptr],
+                                    expected: vec![Type::ptr_to(ty)],
+                                },
+                                format!("Wrong types for @{:?}", ty),
+                            );
+                        }
+                        self.check_alignment(node, align, ty.size(self.structs))?;
+                        stack.push(&mut self.heap, ty)
+                    }
+                    &mut Intrinsic::Write(ty) | &mut Intrinsic::WriteEndian(ty, _) => {
+                        let (ptr, align) = stack.pop_aligned(&self.heap).ok_or_else(|| {
+                            TypecheckError::new(
+                                node.span.clone(),
+                                NotEnoughData,
+                                format!("Not enough data for !{:?}", ty),
+                            )
+                        })?;
+                        let ty_store = stack.pop(&self.heap).ok_or_else(|| {
+                            TypecheckError::new(
+                                node.span.clone(),
+                                NotEnoughData,
+                                format!("Not enough data for !{:?}", ty),
+                            )
+                        })?;
+                        if !(ptr.is_ptr_to(ty) && self.literals.unify(ty_store, ty)) {
+                            return error(
+                                node.span.clone(),
+                                TypeMismatch {
+                                    actual: vec![ptr, ty_store],
+                                    expected: vec![Type::ptr_to(ty), ty],
+                                },
+                                format!("Wrong types for !{:?}", ty),
+                            );
+                        }
+                        self.check_alignment(node, align, ty.size(self.structs))?;
+                    }
+                    &mut Intrinsic::Cast(ty) => {
+                        let (from, align) = stack.pop_aligned(&self.heap).ok_or_else(|| {
+                            TypecheckError::new(
                                 node.span.clone(),
                                 NotEnoughData,
                                 "Not enough data on the stck for cast operation",
+                            )
+                        })?;
+                        if !from.can_cast_to(&ty) {
+                            return error(
+                                node.span.clone(),
+                                InvalidCast { from, to: ty },
+                                format!("Can't cast {:?} to {:?}", from, ty),
                             );
                         }
-                        stack.push(&mut self.heap, ty)
+                        stack.push_aligned(&mut self.heap, ty, align)
                     }
 
                     Intrinsic::CompStop => {
-                        let types: Vec<_> = stack.clone().into_vec(&self.heap);
-                        println!("{:?}", types);
-                        return error(node.span.clone(), CompStop, "");
+                        let types = stack.clone().into_vec(&self.heap);
+                        self.snapshots.record(node.span.clone(), types.clone());
+                        return error(node.span.clone(), CompStop, format!("Stack: {:?}", types));
                     }
 
                     Intrinsic::Syscall0 => {
@@ -889,15 +1727,147 @@ impl<'s> Typechecker<'s> {
                         }
                         stack.push(&mut self.heap, Type::U64);
                     }
+                    &mut Intrinsic::CheckedSyscall(n) => {
+                        if !self.expect_arity(n as usize + 1, stack) {
+                            return error(
+                                node.span.clone(),
+                                NotEnoughData,
+                                format!("Not enough data for syscall{}!", n),
+                            );
+                        }
+                        stack.push(&mut self.heap, Type::U64);
+                        stack.push(&mut self.heap, Type::BOOL);
+                    }
 
                     Intrinsic::Argc => {
                         stack.push(&mut self.heap, Type::U64);
                     }
                     Intrinsic::Argv => {
-                        stack.push(&mut self.heap, Type::ptr_to(Type::ptr_to(Type::CHAR)));
+                        stack.push_aligned(
+                            &mut self.heap,
+                            Type::ptr_to(Type::ptr_to(Type::CHAR)),
+                            Align::WORD,
+                        );
+                    }
+                    Intrinsic::ArgvAt => {
+                        let n = stack.pop(&self.heap).unwrap();
+                        if !self.literals.unify(n, Type::U64) {
+                            return error(
+                                node.span.clone(),
+                                TypeMismatch {
+                                    actual: vec![n],
+                                    expected: vec![Type::U64],
+                                },
+                                "Wrong types for argv-at",
+                            );
+                        }
+                        stack.push(&mut self.heap, Type::ptr_to(Type::CHAR));
+                        stack.push(&mut self.heap, Type::U64);
                     }
 
-                    Intrinsic::Print | Intrinsic::Drop => {
+                    Intrinsic::Print(_) | Intrinsic::PrintPadded(_) if !self.runtime.print => {
+                        return error(
+                            node.span.clone(),
+                            NoRuntime("print"),
+                            "Printing needs the print runtime, which is disabled",
+                        );
+                    }
+                    Intrinsic::PrintPadded(_) => {
+                        let width = stack.pop(&self.heap).ok_or_else(|| {
+                            TypecheckError::new(
+                                node.span.clone(),
+                                NotEnoughData,
+                                "Not enough data for padded print",
+                            )
+                        })?;
+                        let value = stack.pop(&self.heap).ok_or_else(|| {
+                            TypecheckError::new(
+                                node.span.clone(),
+                                NotEnoughData,
+                                "Not enough data for padded print",
+                            )
+                        })?;
+                        if !self.literals.unify(width, Type::U64) {
+                            return error(
+                                node.span.clone(),
+                                TypeMismatch {
+                                    actual: vec![value, width],
+                                    expected: vec![value, Type::U64],
+                                },
+                                "Print width must be a u64",
+                            );
+                        }
+                    }
+                    Intrinsic::HashStr => {
+                        let ptr = stack.pop(&self.heap).unwrap();
+                        let len = stack.pop(&self.heap).unwrap();
+                        if !ptr.is_ptr_to(Type::CHAR) || !self.literals.unify(len, Type::U64) {
+                            return error(
+                                node.span.clone(),
+                                TypeMismatch {
+                                    actual: vec![len, ptr],
+                                    expected: vec![Type::U64, Type::ptr_to(Type::CHAR)],
+                                },
+                                "Wrong types for hash-str",
+                            );
+                        }
+                        stack.push(&mut self.heap, Type::U64)
+                    }
+                    Intrinsic::Copy16 | Intrinsic::Cmp16 => {
+                        let b = stack.pop(&self.heap).unwrap();
+                        let a = stack.pop(&self.heap).unwrap();
+                        if !(a.is_ptr() && b.is_ptr()) {
+                            let word = info_of(i).unwrap().word;
+                            return error(
+                                node.span.clone(),
+                                TypeMismatch {
+                                    actual: vec![a, b],
+                                    expected: vec![Type::ptr_to(Type::U8), Type::ptr_to(Type::U8)],
+                                },
+                                format!("Wrong types for {}", word),
+                            );
+                        }
+                        if let Intrinsic::Cmp16 = i {
+                            stack.push(&mut self.heap, Type::U64)
+                        }
+                    }
+                    Intrinsic::Splat16 => {
+                        let ptr = stack.pop(&self.heap).unwrap();
+                        let byte = stack.pop(&self.heap).unwrap();
+                        if !(ptr.is_ptr()
+                            && (byte == Type::CHAR || self.literals.unify(byte, Type::U8)))
+                        {
+                            return error(
+                                node.span.clone(),
+                                TypeMismatch {
+                                    actual: vec![byte, ptr],
+                                    expected: vec![Type::U8, Type::ptr_to(Type::U8)],
+                                },
+                                "Wrong types for splat8x16",
+                            );
+                        }
+                    }
+                    Intrinsic::FindByte => {
+                        let byte = stack.pop(&self.heap).unwrap();
+                        let len = stack.pop(&self.heap).unwrap();
+                        let ptr = stack.pop(&self.heap).unwrap();
+                        if !(ptr.is_ptr()
+                            && self.literals.unify(len, Type::U64)
+                            && (byte == Type::CHAR || self.literals.unify(byte, Type::U8)))
+                        {
+                            return error(
+                                node.span.clone(),
+                                TypeMismatch {
+                                    actual: vec![ptr, len, byte],
+                                    expected: vec![Type::ptr_to(Type::U8), Type::U64, Type::U8],
+                                },
+                                "Wrong types for find-byte",
+                            );
+                        }
+                        stack.push(&mut self.heap, Type::U64);
+                        stack.push(&mut self.heap, Type::BOOL);
+                    }
+                    Intrinsic::Print(_) | Intrinsic::Drop => {
                         stack.pop(&self.heap).ok_or_else(|| {
                             TypecheckError::new(
                                 node.span.clone(),
@@ -908,63 +1878,124 @@ impl<'s> Typechecker<'s> {
                     }
 
                     Intrinsic::Dup => {
-                        let ty = stack.pop(&self.heap).ok_or_else(|| {
+                        let (ty, align) = stack.pop_aligned(&self.heap).ok_or_else(|| {
                             TypecheckError::new(
                                 node.span.clone(),
                                 NotEnoughData,
                                 "Not enough data to dup",
                             )
                         })?;
-                        stack.push(&mut self.heap, ty);
-                        stack.push(&mut self.heap, ty);
+                        stack.push_aligned(&mut self.heap, ty, align);
+                        stack.push_aligned(&mut self.heap, ty, align);
                     }
                     Intrinsic::Swap => {
-                        let a = stack.pop(&self.heap).ok_or_else(|| {
+                        let (a, a_align) = stack.pop_aligned(&self.heap).ok_or_else(|| {
                             TypecheckError::new(
                                 node.span.clone(),
                                 NotEnoughData,
                                 "Not enough data to swap",
                             )
                         })?;
-                        let b = stack.pop(&self.heap).ok_or_else(|| {
+                        let (b, b_align) = stack.pop_aligned(&self.heap).ok_or_else(|| {
                             TypecheckError::new(
                                 node.span.clone(),
                                 NotEnoughData,
                                 "Not enough data to swap",
                             )
                         })?;
-                        stack.push(&mut self.heap, a);
-                        stack.push(&mut self.heap, b);
+                        stack.push_aligned(&mut self.heap, a, a_align);
+                        stack.push_aligned(&mut self.heap, b, b_align);
                     }
                     Intrinsic::Over => {
-                        let a = stack.pop(&self.heap).ok_or_else(|| {
+                        let (a, a_align) = stack.pop_aligned(&self.heap).ok_or_else(|| {
                             TypecheckError::new(
                                 node.span.clone(),
                                 NotEnoughData,
                                 "Not enough data to over",
                             )
                         })?;
-                        let b = stack.pop(&self.heap).ok_or_else(|| {
+                        let (b, b_align) = stack.pop_aligned(&self.heap).ok_or_else(|| {
                             TypecheckError::new(
                                 node.span.clone(),
                                 NotEnoughData,
                                 "Not enough data to over",
                             )
                         })?;
-                        stack.push(&mut self.heap, b);
+                        stack.push_aligned(&mut self.heap, b, b_align);
+                        stack.push_aligned(&mut self.heap, a, a_align);
+                        stack.push_aligned(&mut self.heap, b, b_align);
+                    }
+                    Intrinsic::Pick(depth) => {
+                        self.typecheck_pick_roll(stack, node.span.clone(), *depth, false)?
+                    }
+                    Intrinsic::Roll(depth) => {
+                        self.typecheck_pick_roll(stack, node.span.clone(), *depth, true)?
+                    }
+                    Intrinsic::Add(ty) | Intrinsic::Sub(ty) | Intrinsic::Mul(ty) => {
+                        *ty = self.typecheck_binop(stack, &node.span)?.some()
+                    }
+                    Intrinsic::Divmod(ty) => *ty = self.typecheck_divmod(stack, &node.span)?.some(),
+                    Intrinsic::BitAnd | Intrinsic::BitOr | Intrinsic::BitXor => {
+                        self.typecheck_binop(stack, &node.span)?;
+                    }
+                    Intrinsic::And | Intrinsic::Or | Intrinsic::Not => {
+                        let ins = info_of(i).unwrap().ins;
+                        let args = (0..ins)
+                            .map(|_| stack.pop(&self.heap).unwrap())
+                            .collect::<Vec<_>>();
+                        if args.iter().any(|&a| a != Type::BOOL) {
+                            return error(
+                                node.span.clone(),
+                                TypeMismatch {
+                                    actual: args.into_iter().rev().collect(),
+                                    expected: vec![Type::BOOL; ins],
+                                },
+                                "Wrong types for logical op, must be bools",
+                            );
+                        }
+                        stack.push(&mut self.heap, Type::BOOL);
+                    }
+                    Intrinsic::BitNot(ty) => {
+                        let a = stack.pop(&self.heap).unwrap();
+                        if !a.is_integer() {
+                            return error(
+                                node.span.clone(),
+                                TypeMismatch {
+                                    actual: vec![a],
+                                    expected: vec![Type::U64],
+                                },
+                                "Wrong type for bnot, must be an integer",
+                            );
+                        }
                         stack.push(&mut self.heap, a);
-                        stack.push(&mut self.heap, b);
-                    }
-                    Intrinsic::Add | Intrinsic::Sub | Intrinsic::Mul => {
-                        self.typecheck_binop(stack, node)?
-                    }
-                    Intrinsic::Divmod => self.typecheck_divmod(stack, node)?,
-                    Intrinsic::Eq
-                    | Intrinsic::Ne
-                    | Intrinsic::Lt
-                    | Intrinsic::Le
-                    | Intrinsic::Gt
-                    | Intrinsic::Ge => self.typecheck_boolean(stack, node)?,
+                        *ty = a.some()
+                    }
+                    Intrinsic::Shl(ty) | Intrinsic::Shr(ty) => {
+                        let (n, a) = (
+                            stack.pop(&self.heap).unwrap(),
+                            stack.pop(&self.heap).unwrap(),
+                        );
+                        let n = self.literals.resolve(n);
+                        if !(a.is_integer() && n.is_integer() && !n.is_signed()) {
+                            return error(
+                                node.span.clone(),
+                                TypeMismatch {
+                                    actual: vec![n, a],
+                                    expected: vec![Type::U64, a],
+                                },
+                                "Wrong types for shift, must be an integer and an unsigned bit count",
+                            );
+                        }
+                        stack.push(&mut self.heap, a);
+                        *ty = a.some()
+                    }
+                    Intrinsic::Eq | Intrinsic::Ne => {
+                        self.typecheck_boolean(stack, &node.span)?;
+                    }
+                    Intrinsic::Lt(ty)
+                    | Intrinsic::Le(ty)
+                    | Intrinsic::Gt(ty)
+                    | Intrinsic::Ge(ty) => *ty = self.typecheck_boolean(stack, &node.span)?.some(),
                     Intrinsic::Dump => (),
                 },
                 HirKind::If(cond) => {
@@ -995,6 +2026,57 @@ impl<'s> Typechecker<'s> {
                         bindings,
                     )?;
                 }
+                HirKind::Dip(body) => {
+                    let (ty, align) = stack.pop_aligned(&self.heap).ok_or_else(|| {
+                        TypecheckError::new(
+                            node.span.clone(),
+                            NotEnoughData,
+                            "Not enough data for dip",
+                        )
+                    })?;
+                    self.typecheck_body(name, items, body, stack, in_const, bindings)?;
+                    stack.push_aligned(&mut self.heap, ty, align);
+                }
+                HirKind::Keep(body) => {
+                    let (ty, align) = stack.pop_aligned(&self.heap).ok_or_else(|| {
+                        TypecheckError::new(
+                            node.span.clone(),
+                            NotEnoughData,
+                            "Not enough data for keep",
+                        )
+                    })?;
+                    stack.push_aligned(&mut self.heap, ty, align);
+                    self.typecheck_body(name, items, body, stack, in_const, bindings)?;
+                    stack.push_aligned(&mut self.heap, ty, align);
+                }
+                HirKind::Defer(body) => {
+                    if in_const {
+                        return error(
+                            node.span.clone(),
+                            Unexpected,
+                            "Defer is not allowed in const",
+                        );
+                    }
+                    if let Some(span) = find_return(body) {
+                        return error(span.clone(), InvalidDefer, "Return is not allowed in defer");
+                    }
+                    // the deferred code runs wherever the block is left, so it can't rely on
+                    // anything being on the stack
+                    // nor leave a loop around it
+                    let mut deferred = TypeStack::default();
+                    let loops = std::mem::take(&mut self.loops);
+                    let res =
+                        self.typecheck_body(name, items, body, &mut deferred, in_const, bindings);
+                    self.loops = loops;
+                    res?;
+                    if deferred.top.is_some() {
+                        return error(
+                            node.span.clone(),
+                            InvalidDefer,
+                            "Deferred code must leave the stack as it was",
+                        );
+                    }
+                }
                 HirKind::While(while_) => {
                     let stack_before = stack.clone().into_vec(&self.heap);
                     self.typecheck_body(name, items, &mut while_.cond, stack, in_const, bindings)?;
@@ -1015,14 +2097,62 @@ impl<'s> Typechecker<'s> {
                             "While expects to consume a bool",
                         );
                     }
-                    self.typecheck_body(name, items, &mut while_.body, stack, in_const, bindings)?;
-                    if stack.clone().into_vec(&self.heap) != stack_before {
-                        return error(node.span.clone(), InvalidWhile, "Invalid while");
+                    self.loops.push(stack_before.clone());
+                    let res = self.typecheck_body(
+                        name,
+                        items,
+                        &mut while_.body,
+                        stack,
+                        in_const,
+                        bindings,
+                    );
+                    self.loops.pop();
+                    res?;
+                    let stack_after = stack.clone().into_vec(&self.heap);
+                    // a body that jumps out was checked where it does
+                    if !jumps_out(&while_.body)
+                        && !same_loop_stack(&stack_before, &stack_after, &mut self.literals)
+                    {
+                        return error(
+                            node.span.clone(),
+                            InvalidWhile {
+                                entry: stack_before,
+                                body: BranchStack::new(stack_after, &while_.body),
+                            },
+                            "While body must leave the stack as the loop found it",
+                        );
+                    }
+                }
+                HirKind::Break | HirKind::Continue => {
+                    let word = match node.hir {
+                        HirKind::Break => "break",
+                        _ => "continue",
+                    };
+                    let actual = stack.clone().into_vec(&self.heap);
+                    match self.loops.last() {
+                        None => {
+                            return error(
+                                node.span.clone(),
+                                Unexpected,
+                                format!("`{}` outside of a loop", word),
+                            )
+                        }
+                        Some(entry) if !same_loop_stack(entry, &actual, &mut self.literals) => {
+                            return error(
+                                node.span.clone(),
+                                TypeMismatch {
+                                    expected: entry.clone(),
+                                    actual,
+                                },
+                                format!("`{}` must leave the stack as the loop found it", word),
+                            )
+                        }
+                        Some(_) => (),
                     }
                 }
                 HirKind::Bind(bind) => {
                     let mut new_bindings = Vec::new();
-                    for binding in bind.bindings.iter().rev() {
+                    for binding in bind.bindings.iter_mut().rev() {
                         match binding {
                             Binding::Ignore => {
                                 stack.pop(&self.heap).ok_or_else(|| {
@@ -1041,7 +2171,7 @@ impl<'s> Typechecker<'s> {
                                         "Not enough data for binding",
                                     )
                                 })?;
-                                if !actual.type_eq(ty) {
+                                if !self.literals.unify(actual, *ty) {
                                     return error(
                                         node.span.clone(),
                                         TypeMismatch {
@@ -1053,6 +2183,12 @@ impl<'s> Typechecker<'s> {
                                 }
                                 new_bindings.push((name.clone(), *ty));
                             }
+                            Binding::Destructure { fields, kind } => {
+                                let destructured =
+                                    self.typecheck_destructure(node.span.clone(), fields, stack)?;
+                                *kind = destructured.0.some();
+                                new_bindings.extend(destructured.1);
+                            }
                         }
                     }
                     bindings.push(new_bindings);
@@ -1060,7 +2196,7 @@ impl<'s> Typechecker<'s> {
                 }
                 HirKind::IgnorePattern => todo!(), // noop
                 HirKind::FieldAccess(f) => {
-                    let ty = stack.pop(&self.heap).ok_or_else(|| {
+                    let (ty, align) = stack.pop_aligned(&self.heap).ok_or_else(|| {
                         TypecheckError::new(
                             node.span.clone(),
                             NotEnoughData,
@@ -1070,7 +2206,19 @@ impl<'s> Typechecker<'s> {
                     let field = {
                         if let ValueType::Struct(s) = ty.value_type {
                             f.ty = s.some();
-                            &self.structs[s].fields[&f.field]
+                            let field = &self.structs[s].fields[&f.field];
+                            if field.bits.is_some() {
+                                let name = &self.structs[s].name;
+                                return error(
+                                    node.span.clone(),
+                                    Unexpected,
+                                    format!(
+                                        "Bit-field `{}` has no address, use `{}.{}@` and `{}.{}!`",
+                                        f.field, name, f.field, name, f.field
+                                    ),
+                                );
+                            }
+                            field
                         } else {
                             return error(
                                 node.span.clone(),
@@ -1079,13 +2227,148 @@ impl<'s> Typechecker<'s> {
                             );
                         }
                     };
-                    stack.push(&mut self.heap, Type::ptr_to(field.ty))
+                    let align = align.map(|a| a.offset_by(field.offset));
+                    stack.push_aligned(&mut self.heap, Type::ptr_to(field.ty), align)
                 }
             }
+            self.snapshots
+                .record(node.span.clone(), stack.clone().into_vec(&self.heap));
+            if !in_const {
+                self.depth = self.depth.max(stack.len(&self.heap));
+            }
         }
         ().okay()
     }
 
+    fn typecheck_pick_roll(
+        &mut self,
+        stack: &mut TypeStack,
+        span: Span,
+        depth: Option<usize>,
+        roll: bool,
+    ) -> Result<()> {
+        let word = if roll { "roll" } else { "pick" };
+        let depth = depth.ok_or_else(|| {
+            TypecheckError::new(
+                span.clone(),
+                DynamicDepth(word),
+                format!("`{}` needs a literal depth, e.g. `2 {}`", word, word),
+            )
+        })?;
+        let mut taken = Vec::with_capacity(depth + 1);
+        for _ in 0..=depth {
+            taken.push(stack.pop_aligned(&self.heap).ok_or_else(|| {
+                TypecheckError::new(
+                    span.clone(),
+                    NotEnoughData,
+                    format!("Not enough data to {} from depth {}", word, depth),
+                )
+            })?);
+        }
+        let (ty, align) = taken[depth];
+        for (i, &(ty, align)) in taken.iter().enumerate().rev() {
+            if !(roll && i == depth) {
+                stack.push_aligned(&mut self.heap, ty, align);
+            }
+        }
+        stack.push_aligned(&mut self.heap, ty, align);
+        ().okay()
+    }
+
+    fn typecheck_destructure(
+        &mut self,
+        span: Span,
+        fields: &[String],
+        stack: &mut TypeStack,
+    ) -> Result<(Destructure, Vec<(String, Type)>)> {
+        for (i, field) in fields.iter().enumerate() {
+            if fields[..i].contains(field) {
+                return error(
+                    span,
+                    Unexpected,
+                    format!("Field `{}` is destructured twice", field),
+                );
+            }
+        }
+        let ptr = stack.pop(&self.heap).ok_or_else(|| {
+            TypecheckError::new(span.clone(), NotEnoughData, "Not enough data for binding")
+        })?;
+        match ptr.value_type {
+            ValueType::Struct(s) if ptr.ptr_depth == 1 => {
+                let struct_ = &self.structs[s];
+                let mut res = Vec::new();
+                for field in fields.iter().rev() {
+                    let ty = struct_
+                        .fields
+                        .get(field)
+                        .ok_or_else(|| {
+                            TypecheckError::new(
+                                span.clone(),
+                                Undefined(field.clone()),
+                                format!("Struct `{}` has no field `{}`", struct_.name, field),
+                            )
+                        })?
+                        .ty;
+                    res.push((field.clone(), ty));
+                }
+                (Destructure::Struct(s), res).okay()
+            }
+            _ if ptr.is_ptr() => {
+                let len = stack.pop(&self.heap).ok_or_else(|| {
+                    TypecheckError::new(span.clone(), NotEnoughData, "Not enough data for binding")
+                })?;
+                if !self.literals.unify(len, Type::U64) {
+                    return error(
+                        span,
+                        TypeMismatch {
+                            expected: vec![Type::U64, ptr],
+                            actual: vec![len, ptr],
+                        },
+                        "Mismatched types for slice destructuring",
+                    );
+                }
+                let mut res = Vec::new();
+                for field in fields.iter().rev() {
+                    let ty = match field.as_str() {
+                        "ptr" => ptr,
+                        "len" => Type::U64,
+                        _ => {
+                            return error(
+                                span,
+                                Undefined(field.clone()),
+                                format!("Slices only have `ptr` and `len`, not `{}`", field),
+                            )
+                        }
+                    };
+                    res.push((field.clone(), ty));
+                }
+                (Destructure::Slice, res).okay()
+            }
+            _ => error(
+                span,
+                Unexpected,
+                format!(
+                    "Can only destructure struct pointers and slices, got {:?}",
+                    ptr
+                ),
+            ),
+        }
+    }
+
+    fn check_alignment(&self, node: &HirNode, align: Option<Align>, required: usize) -> Result<()> {
+        match align {
+            Some(align) if self.strict_alignment && align.is_misaligned(required) => error(
+                node.span.clone(),
+                Misaligned {
+                    required,
+                    offset: align.offset % required,
+                },
+                format!("Misaligned {} byte memory access", required),
+            ),
+            _ => ().okay(),
+        }
+    }
+
     fn expect_arity(&self, arity: usize, stack: &mut TypeStack) -> bool {
         for _ in 0..arity {
             if stack.pop(&self.heap).is_none() {
@@ -1095,6 +2378,11 @@ impl<'s> Typechecker<'s> {
         true
     }
 
+    /// The type of `&name` for the checked proc `name`
+    fn proc_ptr(&self, name: &str) -> Type {
+        let proc = self.visited[name].as_proc().unwrap();
+        Type::proc_ptr(self.structs.signature_id(&proc.ins, &proc.outs))
+    }
     fn is_proc(&self, name: &str, items: &FnvHashMap<String, TopLevel>) -> bool {
         matches!(items.get(name), Some(TopLevel::Proc(_)))
             || matches!(self.output.get(name), Some(TopLevel::Proc(_)))
@@ -1105,6 +2393,10 @@ impl<'s> Typechecker<'s> {
             || matches!(self.output.get(name), Some(TopLevel::Mem(_)))
             || matches!(self.visited.get(name), Some(ItemKind::Mem))
     }
+    fn is_table(&self, name: &str, items: &FnvHashMap<String, TopLevel>) -> bool {
+        matches!(items.get(name), Some(TopLevel::Table(_)))
+            || matches!(self.output.get(name), Some(TopLevel::Table(_)))
+    }
     fn is_binding(&self, name: &str, bindings: &[Vec<(String, Type)>]) -> bool {
         bindings.iter().flatten().any(|b| b.0 == name)
     }
@@ -1151,25 +2443,58 @@ struct TypeStack {
 
 impl TypeStack {
     pub fn push(&mut self, heap: &mut THeap, ty: Type) {
+        self.push_aligned(heap, ty, None)
+    }
+
+    pub fn push_aligned(&mut self, heap: &mut THeap, ty: Type, align: Option<Align>) {
         let frame = TypeFrame {
             ty,
+            align,
             prev: self.top.clone(),
         };
         self.top = heap.alloc(frame).some();
     }
 
     pub fn pop(&mut self, heap: &THeap) -> Option<Type> {
+        self.pop_aligned(heap).map(|(ty, _)| ty)
+    }
+
+    pub fn pop_aligned(&mut self, heap: &THeap) -> Option<(Type, Option<Align>)> {
         if let Some(top) = self.top.clone() {
             let top = top.deref(heap).unwrap();
             let prev = top.prev.clone();
             self.top = prev;
-            top.ty.some()
+            (top.ty, top.align).some()
         } else {
             None
         }
     }
 
-    pub fn eq(&self, other: &Self, heap: &THeap) -> bool {
+    pub fn len(&self, heap: &THeap) -> usize {
+        let mut len = 0;
+        let mut next = &self.top;
+        while let Some(top) = next.as_ref().and_then(|top| top.deref(heap)) {
+            len += 1;
+            next = &top.prev;
+        }
+        len
+    }
+
+    /// Whether there are at least `depth` items on the stack
+    pub fn has_depth(&self, heap: &THeap, depth: usize) -> bool {
+        let mut next = &self.top;
+        for _ in 0..depth {
+            match next.as_ref().and_then(|top| top.deref(heap)) {
+                Some(top) => next = &top.prev,
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// Whether both stacks have the same types, settling the literals of either to the types they
+    /// meet in the other
+    pub fn eq(&self, other: &Self, heap: &THeap, literals: &mut Literals) -> bool {
         let (mut next_left, mut next_right) = (&self.top, &other.top);
         loop {
             match (next_left, next_right) {
@@ -1184,7 +2509,7 @@ impl TypeStack {
                     } else {
                         return false;
                     };
-                    if !lhs.ty.type_eq(&rhs.ty) {
+                    if !literals.unify(lhs.ty, rhs.ty) {
                         break false;
                     }
                     next_left = &lhs.prev;
@@ -1212,9 +2537,36 @@ impl TypeStack {
 #[derive(Debug, Clone)]
 struct TypeFrame {
     ty: Type,
+    align: Option<Align>,
     prev: Option<TRef>,
 }
 
+/// Statically known alignment of a pointer: its address is `offset` modulo `modulus`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Align {
+    modulus: usize,
+    offset: usize,
+}
+
+impl Align {
+    /// Mems, vars and argv are all word aligned by the emitter.
+    const WORD: Option<Self> = Some(Self {
+        modulus: 8,
+        offset: 0,
+    });
+
+    fn offset_by(self, offset: usize) -> Self {
+        Self {
+            modulus: self.modulus,
+            offset: (self.offset + offset) % self.modulus,
+        }
+    }
+
+    fn is_misaligned(&self, required: usize) -> bool {
+        self.modulus.is_multiple_of(required) && !self.offset.is_multiple_of(required)
+    }
+}
+
 type TRef = Ref<TypeFrame, 0>;
 type THeap = Heap<TypeFrame, 0>;
 
@@ -1225,20 +2577,443 @@ fn test_typecheck() {
     let procs = [(
         "main".to_string(),
         TopLevel::Proc(Proc {
+            attrs: Default::default(),
             ins: vec![],
             outs: vec![Type::U64],
+            out_names: vec![None],
             body: vec![HirNode {
                 span: Span::point("".to_string(), 0),
                 hir: HirKind::Literal(IConst::U64(1)),
             }],
             span: Span::point("".to_string(), 0),
             vars: Default::default(),
+            host: false,
         }),
     )]
     .into_iter()
     .collect();
-    assert_matches!(
-        Typechecker::typecheck_program(procs, &StructIndex::default()),
-        Ok(_)
+    let session = Session::default();
+    assert_matches!(Typechecker::typecheck_program(procs, &session), Ok(_));
+    assert_eq!(
+        session.snapshots.at(Path::new(""), 0),
+        Some(vec![Type::U64])
+    );
+}
+
+#[test]
+fn test_snapshots() {
+    use crate::lir::typecheck_source_in;
+
+    // the `+` is missing its second operand, what was checked before it is kept.
+    // Literals that never meet another integer type are u64.
+    let src = "proc main : u64 do 1 2 cast u8 drop + end";
+    let session = Session::default();
+    assert!(typecheck_source_in(src, &session).is_err());
+    let at = |word: &str| {
+        session
+            .snapshots
+            .at(Path::new("test.rh"), src.find(word).unwrap())
+    };
+    assert_eq!(at("2"), Some(vec![Type::U64, Type::U64]));
+    assert_eq!(at("u8"), Some(vec![Type::U64, Type::U8]));
+    assert_eq!(at("drop"), Some(vec![Type::U64]));
+    assert_eq!(at("+"), None);
+    let spans = session
+        .snapshots
+        .all()
+        .into_iter()
+        .map(|(span, _)| &src[span.start..span.end])
+        .collect::<Vec<_>>();
+    assert_eq!(spans, ["1", "2", "cast u8", "drop"]);
+}
+
+#[test]
+fn test_cond() {
+    use crate::{
+        eval::eval,
+        lir::{lower_source, typecheck_source, typecheck_source_in, Compiler},
+    };
+    use somok::Either;
+
+    // branches start from the stack under the matched value, and a bool cond can leave out `_`
+    let (ops, _) = lower_source(
+        "
+        const TWO : u64 do 2 end
+        proc choose u64 u64 : u64 do
+            cond 1 do 10 + else TWO do 20 + else _ do end
+        end
+        proc main : u64 do
+            5 2 choose 5 7 choose +
+            true cond true do 1 else false do 2 end +
+        end",
     );
+    assert!(matches!(eval(ops, &[]), Ok(Either::Left(31))));
+
+    let check = |body: &str| {
+        let src = format!("proc main : u64 do 1 cond {} end end", body);
+        match typecheck_source(&src) {
+            Err(Error::Typecheck(e)) => e.kind,
+            r => panic!("{:?}", r.map(|_| ())),
+        }
+    };
+    assert!(matches!(check("1 do 2 else 2 do 3"), InvalidCond));
+    assert!(matches!(check("_ do 2 else 1 do 3"), InvalidCond));
+    assert!(matches!(
+        check("1 do 2 else 1 do 3 else _ do 4"),
+        InvalidCond
+    ));
+    assert!(matches!(check("1 do 2 else _ do 3 4"), TypeMismatch { .. }));
+
+    // a const repeating a literal is only found once it's evaluated
+    let session = Session::default();
+    let src = "const ONE : u64 do 1 end proc main : u64 do 1 cond 1 do 2 else ONE do 3 else _ do 4 end end";
+    let (procs, _) = typecheck_source_in(src, &session).unwrap();
+    assert!(matches!(
+        Compiler::new(&session).compile(procs),
+        Err(Error::Typecheck(e)) if matches!(e.kind, InvalidCond)
+    ));
+}
+
+#[test]
+fn test_dip_keep() {
+    use crate::{
+        eval::eval,
+        lir::{lower_source, typecheck_source},
+    };
+    use somok::Either;
+
+    for (body, expected) in [
+        // the top value is set aside while the body runs on the rest
+        ("1 2 dip 10 * end +", 12),
+        ("1 2 3 dip dip 100 * end end + +", 105),
+        // keep runs the body on the top value and puts it back afterwards
+        ("5 keep dup * end +", 30),
+        // bindings around a dip are visible inside it
+        ("7 3 bind a: u64 do 1 dip a * end + end", 22),
+    ] {
+        let (ops, _) = lower_source(&format!("proc main : u64 do {} end", body));
+        let res = eval(ops, &[]);
+        assert!(
+            matches!(res, Ok(Either::Left(n)) if n == expected),
+            "{}",
+            body
+        );
+    }
+
+    let check = |body: &str| {
+        let src = format!("proc main : u64 do {} end", body);
+        match typecheck_source(&src) {
+            Err(Error::Typecheck(e)) => e.kind,
+            r => panic!("{:?}", r.map(|_| ())),
+        }
+    };
+    assert!(matches!(check("dip 1 end"), NotEnoughData));
+    assert!(matches!(check("1 2 dip dip drop end end"), NotEnoughData));
+    assert!(matches!(
+        check("true keep 1 + end drop"),
+        TypeMismatch { .. }
+    ));
+}
+
+#[test]
+fn test_pure() {
+    use crate::{
+        eval::eval,
+        lir::{lower_source, typecheck_source},
+    };
+    use somok::Either;
+
+    let (ops, _) = lower_source(
+        "
+        #[pure] proc square u64 : u64 do dup * end
+        #[pure] proc quad u64 : u64 do square square end
+        const SIXTEEN : u64 do 2 quad end
+        proc main : u64 do SIXTEEN 3 square + end",
+    );
+    assert!(matches!(eval(ops, &[]), Ok(Either::Left(25))));
+
+    let check = |src: &str| {
+        let src = format!(
+            "proc impure : u64 do 1 end {} proc main : u64 do f end",
+            src
+        );
+        match typecheck_source(&src) {
+            Err(Error::Typecheck(e)) => (e.kind, e.message),
+            r => panic!("{:?}", r.map(|_| ())),
+        }
+    };
+    let (kind, message) = check("#[pure] proc f : u64 do true if impure else 2 end end");
+    assert!(matches!(kind, Impure));
+    assert_eq!(message, "Pure proc `f` calls `impure`, which is not pure");
+    let (kind, message) = check("mem m do 8 end #[pure] proc f : u64 do 1 m !u8 0 end");
+    assert!(matches!(kind, Impure));
+    assert_eq!(message, "Pure proc `f` uses the mem `m`");
+    let (kind, _) = check("const C : u64 do impure end proc f : u64 do C end");
+    assert!(matches!(kind, CallInConst));
+}
+
+#[test]
+fn test_break() {
+    use crate::{
+        eval::eval,
+        lir::{lower_source, typecheck_source},
+    };
+    use somok::Either;
+
+    // odd numbers up to 15, and a `continue` out of a `bind` in an unrolled loop, which drops the
+    // binding and goes on with the next copy
+    let (ops, _) = lower_source(
+        "
+        proc odds : u64 do
+            0 0 while dup 100 < do
+                dup 15 = if break end
+                1 +
+                dup 2 divmod swap drop 0 = if continue end
+                swap over + swap
+            end drop
+        end
+        proc main : u64 do
+            odds
+            0 #[unroll(3)] while dup 10 < do
+                bind i : u64 do i 3 = if i 2 + continue end i end
+                1 +
+            end +
+        end",
+    );
+    assert!(matches!(eval(ops, &[]), Ok(Either::Left(74))));
+
+    let check = |body: &str| {
+        let src = format!("proc main : u64 do 0 {} end", body);
+        match typecheck_source(&src) {
+            Err(Error::Typecheck(e)) => e.kind,
+            r => panic!("{:?}", r.map(|_| ())),
+        }
+    };
+    assert!(matches!(check("break"), Unexpected));
+    assert!(matches!(
+        check("while dup 3 < do 1 + dup 2 = if 1 continue end end"),
+        TypeMismatch { .. }
+    ));
+    assert!(matches!(
+        check("while dup 3 < do defer break end 1 + end"),
+        Unexpected
+    ));
+}
+
+#[test]
+fn test_branch_stacks() {
+    use crate::lir::typecheck_source;
+
+    let src = "proc main : u64 do 0 dup 1 = if 1 true else 2 end while dup 3 < do 1 end drop end";
+    let check = |src: &str| match typecheck_source(src) {
+        Err(Error::Typecheck(e)) => e.kind,
+        r => panic!("{:?}", r.map(|_| ())),
+    };
+    let text = |span: Option<Span>| span.map(|s| &src[s.start..s.end]);
+    match check(src) {
+        BranchMismatch { truth, lie } => {
+            assert_eq!((truth.stack.len(), lie.stack.len()), (3, 2));
+            assert_eq!(
+                (text(truth.span), text(lie.span)),
+                (Some("1 true"), Some("2"))
+            );
+        }
+        kind => panic!("{:?}", kind),
+    }
+    let src = src.replace("true ", "");
+    match check(&src) {
+        InvalidWhile { entry, body } => {
+            assert_eq!((entry.len(), body.stack.len()), (2, 3));
+            assert_eq!(body.span.map(|s| &src[s.start..s.end]), Some("1"));
+        }
+        kind => panic!("{:?}", kind),
+    }
+}
+
+#[test]
+fn test_type_vars() {
+    use crate::{
+        eval::eval,
+        lir::{lower_source, typecheck_source},
+    };
+    use somok::Either;
+
+    let (ops, _) = lower_source(
+        "
+        proc nip 'a 'b : 'b do swap drop end
+        proc first u64 &>'a : &>'a do swap drop end
+        proc hold 'a do bind x: 'a do x drop end end
+        proc main : u64 do 1u 2 nip \"hi\" first drop true hold 7 3 cast u8 nip cast u64 + end",
+    );
+    let strings = ["hi".to_string()];
+    assert!(matches!(eval(ops, &strings), Ok(Either::Left(5))));
+
+    let check = |src: &str| match typecheck_source(src) {
+        Err(Error::Typecheck(e)) => (e.kind, e.message),
+        r => panic!("{:?}", r.map(|_| ())),
+    };
+    let (kind, _) = check("proc f 'a : 'a do 1 + end proc main : u64 do 1 f end");
+    assert!(matches!(kind, TypeMismatch { .. }));
+    let (kind, message) = check("proc f : 'a do 1 end proc main : u64 do f end");
+    assert!(matches!(kind, UnboundTypeVar('a')));
+    assert_eq!(
+        message,
+        "Type variable `'a` of the outputs is not bound by any input"
+    );
+    let (kind, _) = check("proc p 'a 'a : 'a do drop end proc main : u64 do 1u true p end");
+    match kind {
+        TypeMismatch { expected, actual } => {
+            assert_eq!((expected, actual), (vec![Type::BOOL], vec![Type::U64]))
+        }
+        kind => panic!("{:?}", kind),
+    }
+}
+
+#[test]
+fn test_literals() {
+    use crate::{
+        eval::eval,
+        lir::{lower_source, typecheck_source},
+    };
+    use somok::Either;
+
+    // the literals only learn they are u8 from `f`, after they are added
+    let (ops, _) = lower_source(
+        "
+        proc f u8 : u64 do cast u64 end
+        proc main : u64 do 200 100 + f end",
+    );
+    assert!(matches!(eval(ops, &[]), Ok(Either::Left(44))));
+
+    let check = |src: &str| match typecheck_source(src) {
+        Err(Error::Typecheck(e)) => (e.kind, e.message),
+        r => panic!("{:?}", r.map(|_| ())),
+    };
+    let (kind, message) = check("proc f u8 : u64 do cast u64 end proc main : u64 do 1 300 + f end");
+    assert!(matches!(kind, LiteralOutOfRange(ty) if ty == Type::U8));
+    assert_eq!(message, "Literal `300` is out of range for its type");
+    let (kind, _) = check("proc g i8 : u64 do drop 0 end proc main : u64 do 128 g end");
+    assert!(matches!(kind, LiteralOutOfRange(ty) if ty == Type::I8));
+    assert!(typecheck_source("proc g i8 : u64 do drop 0 end proc main : u64 do 127 g end").is_ok());
+}
+
+#[test]
+fn test_warning_levels() {
+    use crate::{lir::typecheck_source_in, session::Options};
+
+    let check = |warnings: WarningLevel| {
+        let src = "#[deprecated] proc old : u64 do 1 end proc main : u64 do old end";
+        let options = Options::builder().warnings(warnings).build().unwrap();
+        let session = Session::new(Default::default(), options);
+        typecheck_source_in(src, &session).map(|(_, warnings)| warnings)
+    };
+    assert_eq!(check(WarningLevel::Warn).unwrap().len(), 1);
+    assert!(check(WarningLevel::Allow).unwrap().is_empty());
+    assert!(matches!(check(WarningLevel::Deny), Err(Error::DeniedWarnings(ws)) if ws.len() == 1));
+}
+
+#[test]
+fn test_unused_warnings() {
+    use crate::lir::typecheck_source;
+
+    let src = "
+        proc helper : u64 do 1 end
+        proc unused : u64 do helper end
+        const K : u64 do 2 end
+        proc main : u64 do 0 end";
+    let (_, warnings) = typecheck_source(src).unwrap();
+    let messages = warnings.into_iter().map(|w| w.message).collect::<Vec<_>>();
+    assert_eq!(
+        messages,
+        [
+            "proc `helper` is never used",
+            "proc `unused` is never used",
+            "const `K` is never used"
+        ]
+    );
+}
+
+#[test]
+fn test_stack_depth() {
+    use crate::lir::typecheck_source_in;
+
+    // the values of a branch and of a const body count for the proc and the const respectively
+    let src = "
+        const K : u64 do 1 2 3 + + end
+        proc sq u64 : u64 do dup * end
+        proc main : u64 do
+            1 2 true if 3 4 drop drop end + K sq +
+        end";
+    let session = Session::default();
+    typecheck_source_in(src, &session).unwrap();
+    assert_eq!(
+        session.snapshots.depths(),
+        [("main".to_string(), 4), ("sq".to_string(), 1)]
+    );
+    assert_eq!(session.snapshots.depth("K"), None);
+}
+
+#[test]
+fn test_misaligned() {
+    use crate::{
+        ast::{parse_no_include, TopLevel::Struct},
+        hir::Walker,
+        lexer::lex_string,
+        session::Options,
+        types::define_structs,
+    };
+
+    let check = |strict_alignment: bool| {
+        let src = "
+            #[packed]
+            struct P do tag: u8 v: u64 end
+            mem buf do 16 end
+            proc main : u64 do buf cast &>P ->v @u64 end";
+        let tokens = lex_string(src.into(), "test.rh".into()).unwrap();
+        let (structs, items) = parse_no_include(tokens)
+            .unwrap()
+            .into_iter()
+            .map(|i| (i.name().unwrap(), i))
+            .partition::<FnvHashMap<_, _>, _>(|(_, i)| matches!(i, Struct(_)));
+
+        let options = Options {
+            strict_alignment,
+            ..Default::default()
+        };
+        let session = Session::new(define_structs(structs), options);
+        let hir = Walker::new(&session).walk_ast(items).unwrap();
+        Typechecker::typecheck_program(hir, &session).map(|_| ())
+    };
+    assert!(check(false).is_ok());
+    assert!(matches!(
+        check(true),
+        Err(Error::Typecheck(e)) if matches!(e.kind, Misaligned { required: 8, offset: 1 })
+    ));
+}
+
+#[test]
+fn test_pick_roll() {
+    use crate::{
+        eval::eval,
+        lir::{lower_source, typecheck_source},
+    };
+    use somok::Either;
+
+    let (ops, _) = lower_source(
+        "
+        proc main : u64 do
+            1 2 3 2 roll 100 * swap 10 * + +
+            4 5 1 pick * + +
+        end",
+    );
+    assert!(matches!(eval(ops, &[]), Ok(Either::Left(156))));
+
+    // the depth is fixed at compile time, so a const can't be one
+    let src = "
+        const ONE : u64 do 1 end
+        proc main : u64 do 1 2 ONE pick drop drop end";
+    assert!(matches!(
+        typecheck_source(src),
+        Err(Error::Typecheck(e)) if matches!(e.kind, DynamicDepth("pick"))
+    ));
 }
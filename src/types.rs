@@ -1,5 +1,11 @@
-use crate::ast::TopLevel;
+use crate::{
+    ast::{AstKind, AstNode, TopLevel},
+    iconst::IConst,
+    layout::{struct_layout, Bits, Layout, StructLayout},
+};
 use fnv::FnvHashMap;
+use somok::Somok;
+use std::cell::RefCell;
 
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub struct Type {
@@ -9,12 +15,14 @@ pub struct Type {
 
 impl std::fmt::Debug for Type {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}{:?}",
-            "&>".repeat(self.ptr_depth as usize),
-            self.value_type
-        )
+        let ptr = "&>".repeat(self.ptr_depth);
+        match self.value_type {
+            ValueType::Var(v) => write!(f, "{ptr}'{v}"),
+            ValueType::Proc(None) => write!(f, "{ptr}ptr(proc)"),
+            ValueType::Proc(Some(SignatureId(id))) => write!(f, "{ptr}ptr(proc(#{id}))"),
+            ValueType::Primitive(Primitive::Int(_)) => write!(f, "{ptr}Primitive(Int)"),
+            value_type => write!(f, "{ptr}{value_type:?}"),
+        }
     }
 }
 impl Type {
@@ -62,11 +70,40 @@ impl Type {
         value_type: ValueType::Primitive(Primitive::I8),
     };
 
+    pub const PROC: Self = Type {
+        ptr_depth: 0,
+        value_type: ValueType::Proc(None),
+    };
+
     pub const ANY: Self = Type {
         ptr_depth: 0,
         value_type: ValueType::Any,
     };
 
+    /// An unsuffixed integer literal typed by the inference variable `var`
+    pub fn literal(var: u32) -> Self {
+        Type {
+            ptr_depth: 0,
+            value_type: ValueType::Primitive(Primitive::Int(var)),
+        }
+    }
+
+    /// The inference variable of an unsuffixed literal that hasn't settled yet
+    pub fn literal_var(&self) -> Option<u32> {
+        match self.value_type {
+            ValueType::Primitive(Primitive::Int(var)) if self.ptr_depth == 0 => var.some(),
+            _ => None,
+        }
+    }
+
+    /// The type of `&name` for a proc with the signature `id`
+    pub fn proc_ptr(id: SignatureId) -> Self {
+        Self {
+            ptr_depth: 0,
+            value_type: ValueType::Proc(id.some()),
+        }
+    }
+
     pub fn ptr_to(ty: Self) -> Self {
         let ptr_depth = ty.ptr_depth + 1;
         Self {
@@ -78,13 +115,85 @@ impl Type {
     pub fn type_eq(&self, other: &Self) -> bool {
         if self.value_type == ValueType::Any || other.value_type == ValueType::Any {
             self.ptr_depth == other.ptr_depth
+        } else if self.literal_var().is_some() || other.literal_var().is_some() {
+            self.is_integer() && other.is_integer()
         } else {
             self.ptr_depth == other.ptr_depth && self.value_type == other.value_type
         }
     }
+    pub fn is_integer(&self) -> bool {
+        use Primitive::*;
+        self.ptr_depth == 0
+            && matches!(
+                self.value_type,
+                ValueType::Primitive(U64 | U32 | U16 | U8 | I64 | I32 | I16 | I8 | Int(_))
+            )
+    }
+    pub fn is_signed(&self) -> bool {
+        use Primitive::*;
+        self.ptr_depth == 0 && matches!(self.value_type, ValueType::Primitive(I64 | I32 | I16 | I8))
+    }
+    /// Bits of an integer narrower than a stack slot, what it wraps to after arithmetic
+    pub fn narrow_bits(&self) -> Option<u32> {
+        match self.value_type {
+            ValueType::Primitive(p) if self.is_integer() && p.size() < 8 => {
+                (p.size() as u32 * 8).some()
+            }
+            _ => None,
+        }
+    }
+    /// The type two integers are used as together: the other one for an unsuffixed literal and the
+    /// wider one for integers of the same signedness. Anything else needs a cast. The typechecker
+    /// settles the literals to it.
+    pub fn widened(&self, other: &Self) -> Option<Self> {
+        let size = |ty: &Self| match ty.value_type {
+            ValueType::Primitive(p) => p.size(),
+            _ => unreachable!(),
+        };
+        if !(self.is_integer() && other.is_integer()) {
+            None
+        } else if self.literal_var().is_some() {
+            (*other).some()
+        } else if other.literal_var().is_some()
+            || self.is_signed() == other.is_signed() && size(self) >= size(other)
+        {
+            (*self).some()
+        } else if self.is_signed() == other.is_signed() {
+            (*other).some()
+        } else {
+            None
+        }
+    }
+    /// Whether `cast` may reinterpret this type as `to`: integers as other integers, pointers as
+    /// other pointers or u64 and back, proc pointers as other proc pointers or u64 and back, chars
+    /// as unsigned integers and back, and bools as unsigned integers
+    pub fn can_cast_to(&self, to: &Self) -> bool {
+        use Primitive::*;
+        let unsigned = |ty: &Self| {
+            ty.ptr_depth == 0
+                && matches!(
+                    ty.value_type,
+                    ValueType::Primitive(U64 | U32 | U16 | U8 | Int(_))
+                )
+        };
+        let address = |ty: &Self| *ty == Type::U64 || ty.literal_var().is_some();
+        self.type_eq(to)
+            || self.is_integer() && to.is_integer()
+            || self.is_ptr() && (to.is_ptr() || address(to))
+            || address(self) && to.is_ptr()
+            || self.is_proc() && (to.is_proc() || address(to))
+            || address(self) && to.is_proc()
+            || *self == Type::CHAR && unsigned(to)
+            || unsigned(self) && *to == Type::CHAR
+            || *self == Type::BOOL && unsigned(to)
+    }
     pub fn is_ptr(&self) -> bool {
         self.ptr_depth > 0
     }
+    /// Whether this is a proc pointer, with a signature or without
+    pub fn is_proc(&self) -> bool {
+        self.ptr_depth == 0 && matches!(self.value_type, ValueType::Proc(_))
+    }
     pub fn is_ptr_to(&self, ty: Self) -> bool {
         if self.value_type == ValueType::Any || ty.value_type == ValueType::Any {
             self.is_ptr() && self.ptr_depth.saturating_sub(1) == ty.ptr_depth
@@ -95,16 +204,50 @@ impl Type {
         }
     }
 
+    pub fn type_var(&self) -> Option<char> {
+        match self.value_type {
+            ValueType::Var(v) => v.some(),
+            _ => None,
+        }
+    }
+
     pub fn size(&self, struct_index: &StructIndex) -> usize {
-        if self.ptr_depth > 0 {
-            8
-        } else {
-            match self.value_type {
-                ValueType::Primitive(p) => p.size(),
-                ValueType::Any => unreachable!("Naked any type"),
-                ValueType::Struct(s) => struct_index[s].size,
+        Layout::of(*self, struct_index).size
+    }
+
+    /// The type as it is written in the source, unsuffixed literals that haven't settled being `u64`s
+    pub fn name(&self, struct_index: &StructIndex) -> String {
+        use Primitive::*;
+        let name = match self.value_type {
+            ValueType::Primitive(p) => match p {
+                Bool => "bool",
+                Char => "char",
+                U64 | Int(_) => "u64",
+                U32 => "u32",
+                U16 => "u16",
+                U8 => "u8",
+                I64 => "i64",
+                I32 => "i32",
+                I16 => "i16",
+                I8 => "i8",
             }
-        }
+            .to_string(),
+            ValueType::Any => "()".to_string(),
+            ValueType::Struct(id) => struct_index[id].name.clone(),
+            ValueType::Var(v) => format!("'{}", v),
+            ValueType::Proc(None) => "ptr(proc)".to_string(),
+            ValueType::Proc(Some(id)) => {
+                let (ins, outs) = struct_index.signature(id);
+                let names = ins
+                    .iter()
+                    .map(|ty| ty.name(struct_index))
+                    .chain([":".to_string()])
+                    .chain(outs.iter().map(|ty| ty.name(struct_index)))
+                    .collect::<Vec<_>>();
+                format!("ptr(proc({}))", names.join(" "))
+            }
+        };
+        format!("{}{}", "&>".repeat(self.ptr_depth), name)
     }
 }
 
@@ -113,6 +256,12 @@ pub enum ValueType {
     Primitive(Primitive),
     Any,
     Struct(StructId),
+    /// `'a` of a proc signature, any type the caller has there. Inside the proc it only equals
+    /// itself, so the proc can only shuffle it around.
+    Var(char),
+    /// `ptr(proc(ins : outs))`, the address of a proc with that signature as pushed by `&name`.
+    /// A `ptr(proc)` without one may point at any proc, it is only made by a `cast`.
+    Proc(Option<SignatureId>),
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -129,9 +278,13 @@ pub enum Primitive {
     I32,
     I16,
     I8,
+
+    /// Unsuffixed integer literals, typed by an inference variable until they settle to the integer
+    /// type they are used as, see `Typechecker`
+    Int(u32),
 }
 impl Primitive {
-    fn size(&self) -> usize {
+    pub(crate) fn size(&self) -> usize {
         match self {
             Primitive::Bool => 1,
             Primitive::Char => 1,
@@ -145,37 +298,56 @@ impl Primitive {
             Primitive::I32 => 4,
             Primitive::I16 => 2,
             Primitive::I8 => 1,
+
+            Primitive::Int(_) => 8,
         }
     }
 }
 
 pub struct StructBuilder<'i> {
     index: &'i mut StructIndex,
-    fields: FnvHashMap<String, Type>,
+    fields: Vec<(String, Type, Option<u32>)>,
     name: String,
+    packed: bool,
 }
 
 impl<'i> StructBuilder<'i> {
     pub fn field(&mut self, name: String, ty: Type) -> &mut Self {
-        self.fields.insert(name, ty);
+        self.fields.push((name, ty, None));
+        self
+    }
+    /// A field of `width` bits, packed with the bit-fields around it
+    pub fn bit_field(&mut self, name: String, ty: Type, width: u32) -> &mut Self {
+        self.fields.push((name, ty, width.some()));
+        self
+    }
+    /// Lay fields out back to back, without alignment padding
+    pub fn packed(&mut self, packed: bool) -> &mut Self {
+        self.packed = packed;
         self
     }
     pub fn finish(self) -> StructId {
-        let mut fields = FnvHashMap::default();
-        let mut curr_offset = 0;
-        for (name, ty) in self.fields {
-            let field = Field {
-                ty,
-                offset: curr_offset,
-            };
-            curr_offset += ty.size(self.index);
-            fields.insert(name, field);
-        }
+        let StructLayout {
+            layout,
+            offsets,
+            bits,
+        } = struct_layout(
+            self.fields
+                .iter()
+                .map(|(_, ty, width)| (Layout::of(*ty, self.index), *width)),
+            self.packed,
+        );
+        let fields = self
+            .fields
+            .into_iter()
+            .zip(offsets.into_iter().zip(bits))
+            .map(|((name, ty, _), (offset, bits))| (name, Field { ty, offset, bits }))
+            .collect();
 
         let struct_ = Struct {
             name: self.name,
             fields,
-            size: curr_offset,
+            layout,
         };
         let id = self.index.structs.len();
         self.index.structs.push(struct_);
@@ -186,6 +358,8 @@ impl<'i> StructBuilder<'i> {
 #[derive(Default)]
 pub struct StructIndex {
     structs: Vec<Struct>,
+    /// Signatures of the proc pointer types, each once, added to as they are written or taken
+    signatures: RefCell<Vec<(Vec<Type>, Vec<Type>)>>,
 }
 
 impl StructIndex {
@@ -194,6 +368,7 @@ impl StructIndex {
             index: self,
             fields: Default::default(),
             name,
+            packed: false,
         }
     }
     pub fn id_names(&'_ self) -> impl Iterator<Item = (StructId, &'_ str)> {
@@ -206,6 +381,21 @@ impl StructIndex {
         self.id_names()
             .find_map(|(i, n)| if n == name { Some(i) } else { None })
     }
+    /// The id of the signature `ins : outs`, the same for every proc pointer type that has it
+    pub fn signature_id(&self, ins: &[Type], outs: &[Type]) -> SignatureId {
+        let mut signatures = self.signatures.borrow_mut();
+        let id = match signatures.iter().position(|(i, o)| i == ins && o == outs) {
+            Some(id) => id,
+            None => {
+                signatures.push((ins.to_vec(), outs.to_vec()));
+                signatures.len() - 1
+            }
+        };
+        SignatureId(id)
+    }
+    pub fn signature(&self, id: SignatureId) -> (Vec<Type>, Vec<Type>) {
+        self.signatures.borrow()[id.0].clone()
+    }
 }
 
 impl std::ops::Index<StructId> for StructIndex {
@@ -219,17 +409,22 @@ impl std::ops::Index<StructId> for StructIndex {
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct StructId(usize);
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct SignatureId(usize);
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct Struct {
     pub name: String,
     pub fields: FnvHashMap<String, Field>,
-    pub size: usize,
+    pub layout: Layout,
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct Field {
     pub ty: Type,
     pub offset: usize,
+    /// Bits of a bit-field in the integer at `offset`, such a field has no address of its own
+    pub bits: Option<Bits>,
 }
 
 pub fn define_structs(structs: FnvHashMap<String, TopLevel>) -> StructIndex {
@@ -237,6 +432,12 @@ pub fn define_structs(structs: FnvHashMap<String, TopLevel>) -> StructIndex {
     for (name, struct_) in structs {
         if let TopLevel::Struct(s) = &struct_ {
             let mut builder = index.new_struct(name);
+            for attr in &s.attrs {
+                let attr = coerce_ast!(attr => REF Attribute || unreachable!());
+                if attr.name == "packed" {
+                    builder.packed(true);
+                }
+            }
             for field in &s.body {
                 let field = coerce_ast!(field => REF StructField || unreachable!());
                 let name = coerce_ast!(field.name => REF Word || unreachable!());
@@ -244,7 +445,13 @@ pub fn define_structs(structs: FnvHashMap<String, TopLevel>) -> StructIndex {
                     .clone()
                     .to_primitive_type();
 
-                builder.field(name.clone(), ty);
+                match &field.bits {
+                    Some(box AstNode {
+                        ast: AstKind::Literal(IConst::Int(width)),
+                        ..
+                    }) => builder.bit_field(name.clone(), ty, *width as u32),
+                    _ => builder.field(name.clone(), ty),
+                };
             }
             builder.finish();
         } else {
@@ -253,3 +460,29 @@ pub fn define_structs(structs: FnvHashMap<String, TopLevel>) -> StructIndex {
     }
     index
 }
+
+#[test]
+fn test_can_cast_to() {
+    let ptr = Type::ptr_to(Type::U8);
+    assert!(Type::I8.can_cast_to(&Type::U64));
+    assert!(ptr.can_cast_to(&Type::ptr_to(Type::CHAR)));
+    assert!(ptr.can_cast_to(&Type::U64) && Type::U64.can_cast_to(&ptr));
+    assert!(Type::CHAR.can_cast_to(&Type::U8) && Type::U8.can_cast_to(&Type::CHAR));
+    assert!(Type::BOOL.can_cast_to(&Type::U64));
+    assert!(!Type::U64.can_cast_to(&Type::BOOL));
+    assert!(!ptr.can_cast_to(&Type::U8));
+    assert!(!Type::I64.can_cast_to(&ptr));
+    assert!(!Type::CHAR.can_cast_to(&Type::I8));
+}
+
+#[test]
+fn test_widened() {
+    assert_eq!(Type::U8.widened(&Type::U32), Some(Type::U32));
+    assert_eq!(Type::I64.widened(&Type::I16), Some(Type::I64));
+    assert_eq!(Type::literal(0).widened(&Type::I8), Some(Type::I8));
+    assert_eq!(Type::U16.widened(&Type::literal(0)), Some(Type::U16));
+    assert_eq!(Type::U8.widened(&Type::I64), None);
+    assert_eq!(Type::U8.widened(&Type::CHAR), None);
+    assert_eq!(Type::I32.narrow_bits(), Some(32));
+    assert_eq!(Type::U64.narrow_bits(), None);
+}